@@ -9,7 +9,6 @@ fn main() -> Result<()> {
     let mut emitter = Emitter::default();
     emitter
         .add_instructions(&cargo)?
-        .add_instructions(&git)?
         .add_instructions(&rustc)?;
 
     if let Ok(git) = GitclBuilder::default().sha(false).build() {
@@ -0,0 +1,150 @@
+//! Hints that let a load balancer send a client back to the same instance when
+//! it reconnects, so that an interrupted upload can be resumed on the instance
+//! that still has the partial file.
+//!
+//! Every instance has an identity, which is reported on the metrics server's
+//! `/instance` endpoint and in its health check headers. With the list of
+//! instances configured, `/affinity/{key}` picks the instance that a key, like
+//! a username or a client address, belongs to with rendezvous hashing, so that
+//! every instance gives the same answer and only the keys of an instance that
+//! leaves the list move. For HAProxy, instances can also answer agent checks.
+
+use std::sync::Arc;
+
+use http::{HeaderName, HeaderValue};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror_ext::AsReport;
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use tracing::{Level, event};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "affinity_config")]
+pub struct Config {
+    /// The name this instance goes by. The default value is the `HOSTNAME`
+    /// environment variable, or a random name if that isn't set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "Config::example_instance_id")]
+    pub instance_id: Option<String>,
+
+    /// The names of every instance that clients are balanced between, which
+    /// affinity hints are chosen from. It should be the same on every
+    /// instance. Without it, there are no hints.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<String>,
+
+    /// Where to answer HAProxy agent checks, if anywhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_check: Option<AgentCheckConfig>,
+}
+
+impl Config {
+    fn example_instance_id() -> &'static str {
+        "schlep-0"
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "agent_check_config")]
+pub struct AgentCheckConfig {
+    pub address: String,
+    pub port: u16,
+
+    /// The weight to report, as a percentage of the weight configured in
+    /// HAProxy. The default value is 100.
+    #[serde(
+        default = "AgentCheckConfig::default_weight",
+        skip_serializing_if = "AgentCheckConfig::is_default_weight"
+    )]
+    pub weight: u8,
+}
+
+impl AgentCheckConfig {
+    fn default_weight() -> u8 {
+        100
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_weight(weight: &u8) -> bool {
+        *weight == Self::default_weight()
+    }
+}
+
+/// This instance's identity, and the instances that keys are spread over.
+#[derive(Clone, Debug)]
+pub struct Affinity {
+    instance_id: Arc<str>,
+    instances: Arc<[String]>,
+    agent_check: Option<AgentCheckConfig>,
+}
+
+impl Affinity {
+    /// The header that carries the instance's identity in HTTP responses.
+    pub const INSTANCE_ID: HeaderName = HeaderName::from_static("instance-id");
+
+    #[must_use]
+    pub fn new(config: Config) -> Self {
+        let instance_id = config
+            .instance_id
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| format!("schlep-{:08x}", rand::random::<u32>()));
+
+        Self {
+            instance_id: instance_id.into(),
+            instances: config.instances.into(),
+            agent_check: config.agent_check,
+        }
+    }
+
+    /// The name this instance goes by.
+    #[must_use]
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// The identity of this instance as a header value, if it can be one.
+    #[must_use]
+    pub fn instance_id_header(&self) -> Option<HeaderValue> {
+        HeaderValue::from_str(&self.instance_id).ok()
+    }
+
+    /// The instance that `key` should be sent to, if any instances are
+    /// configured.
+    #[must_use]
+    pub fn instance_for(&self, key: &str) -> Option<&str> {
+        self.instances
+            .iter()
+            .max_by_key(|instance| {
+                Sha256::new()
+                    .chain_update(instance.as_bytes())
+                    .chain_update([0])
+                    .chain_update(key.as_bytes())
+                    .finalize()
+            })
+            .map(String::as_str)
+    }
+
+    /// Answers HAProxy agent checks until the listener fails, or forever if
+    /// they aren't configured.
+    pub async fn run_agent_check(&self) -> anyhow::Result<()> {
+        let Some(config) = &self.agent_check else {
+            return std::future::pending().await;
+        };
+
+        let listener = TcpListener::bind((config.address.clone(), config.port)).await?;
+        let response = format!("up ready {}%\n", config.weight);
+
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let response = response.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = stream.write_all(response.as_bytes()).await {
+                    event!(Level::DEBUG, err = %err.as_report(), "failed to answer agent check");
+                }
+            });
+        }
+    }
+}
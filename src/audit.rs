@@ -0,0 +1,88 @@
+//! The audit log records who did what to which file. Audit events are ordinary
+//! [`tracing`] events emitted with [`TARGET`] as their target, so they can be
+//! filtered, routed, and formatted separately from the rest of the log.
+//!
+//! If [`Config::log_dir`] is set, audit events are also written to daily log
+//! files in that directory, which can be mounted read-only for auditors.
+
+use camino::Utf8PathBuf;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+use crate::vfs::{self, VfsSetBuilder};
+
+/// The `tracing` target that audit events are emitted under.
+pub const TARGET: &str = "schlep::audit";
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "audit_config")]
+pub struct Config {
+    /// A directory to write the audit log to, in files that are rotated daily.
+    /// Without one, audit events only go to the regular log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub log_dir: Option<Utf8PathBuf>,
+
+    /// The DN of the LDAP group whose members can read the audit log over
+    /// SFTP. Without one, the audit log isn't exposed over SFTP at all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "Config::example_auditor_group")]
+    pub auditor_group: Option<String>,
+
+    /// Where in the virtual hierarchy the audit log is mounted for auditors.
+    /// The default value is `/.audit`.
+    #[serde(
+        default = "Config::default_mount_path",
+        skip_serializing_if = "Config::is_default_mount_path"
+    )]
+    #[schemars(with = "String")]
+    pub mount_path: Utf8PathBuf,
+}
+
+impl Config {
+    fn example_auditor_group() -> &'static str {
+        "cn=auditors,ou=groups,dc=example,dc=com"
+    }
+
+    fn default_mount_path() -> Utf8PathBuf {
+        Utf8PathBuf::from("/.audit")
+    }
+
+    fn is_default_mount_path(path: &Utf8PathBuf) -> bool {
+        *path == Self::default_mount_path()
+    }
+
+    /// A writer for the audit log files, if there are any. The log is flushed
+    /// when the returned guard is dropped, so it has to be kept alive for as
+    /// long as the program runs.
+    #[must_use]
+    pub fn writer(&self) -> Option<(NonBlocking, WorkerGuard)> {
+        let log_dir = self.log_dir.as_ref()?;
+        let appender = tracing_appender::rolling::daily(log_dir, "audit.log");
+
+        Some(tracing_appender::non_blocking(appender))
+    }
+
+    /// Mounts the audit log for auditors, if it is configured to be.
+    pub fn mount(&self, builder: VfsSetBuilder) -> Result<VfsSetBuilder, vfs::Error> {
+        match (&self.log_dir, &self.auditor_group) {
+            (Some(log_dir), Some(auditor_group)) => builder.audit_log(
+                self.mount_path.clone(),
+                log_dir.clone(),
+                auditor_group.clone(),
+            ),
+            _ => Ok(builder),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_dir: None,
+            auditor_group: None,
+            mount_path: Self::default_mount_path(),
+        }
+    }
+}
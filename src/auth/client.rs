@@ -1,9 +1,13 @@
+use std::{collections::HashSet, sync::Arc};
+
+use ahash::HashMap;
 use deadpool::{
     Runtime,
     managed::{self, PoolError},
 };
 use fred::prelude::*;
-use ldap3::{Scope, SearchEntry, ldap_escape};
+use ldap3::{Mod, Scope, SearchEntry, ldap_escape};
+use parking_lot::RwLock;
 use russh::keys::PublicKey;
 use serde::{Deserialize, Serialize};
 use tracing::{Level, event, instrument};
@@ -12,41 +16,95 @@ use super::{
     AuthError,
     Config,
     config::{LdapConfig, LdapConnectionManager},
+    key_file::KeyFile,
 };
 use crate::{
     auth::error::{IntoLdapError, IntoRedisError},
+    privacy,
     redis::RedisPool,
 };
 
 #[derive(Clone)]
 pub struct AuthClient {
     redis_pool: Option<RedisPool>,
-    ldap_config: LdapConfig,
-    ldap_pool: managed::Pool<LdapConnectionManager>,
+    ldap: Option<LdapBackend>,
+    static_users: Arc<RwLock<HashMap<String, UserInfo>>>,
+    key_file: Option<Arc<KeyFile>>,
+}
+
+#[derive(Clone)]
+struct LdapBackend {
+    config: LdapConfig,
+    pool: managed::Pool<LdapConnectionManager>,
 }
 
 pub type Result<T, E = AuthError> = std::result::Result<T, E>;
 
 impl AuthClient {
     pub fn new(config: Config, redis_pool: Option<RedisPool>) -> Result<Self> {
-        let ldap_manager = config.ldap.connection_manager();
-        let ldap_pool_timeout = config.ldap.conn_timeout;
-        let ldap_pool_max_size = config.ldap.pool_max_size;
+        let ldap = config.ldap.map(|ldap_config| {
+            let pool = managed::Pool::builder(ldap_config.connection_manager())
+                .runtime(Runtime::Tokio1)
+                .create_timeout(Some(ldap_config.conn_timeout))
+                .max_size(ldap_config.pool_max_size)
+                .build()
+                .unwrap();
 
-        let ldap_pool = managed::Pool::builder(ldap_manager)
-            .runtime(Runtime::Tokio1)
-            .create_timeout(Some(ldap_pool_timeout))
-            .max_size(ldap_pool_max_size)
-            .build()
-            .unwrap();
+            LdapBackend {
+                config: ldap_config,
+                pool,
+            }
+        });
+
+        let key_file = config.key_file.map(KeyFile::load).transpose()?;
+
+        let mut static_users = HashMap::default();
+        for user in config.users {
+            let public_keys = key_file
+                .as_ref()
+                .and_then(|key_file| key_file.get(&user.username))
+                .unwrap_or(user.public_keys)
+                .iter()
+                .map(|key| PublicKey::from_openssh(key).map_err(russh::keys::Error::from))
+                .collect::<Result<_, _>>()?;
+
+            static_users.insert(
+                user.username.clone(),
+                UserInfo {
+                    username: user.username,
+                    dn: String::new(),
+                    groups: user.groups,
+                    public_keys,
+                },
+            );
+        }
 
         Ok(Self {
             redis_pool,
-            ldap_config: config.ldap,
-            ldap_pool,
+            ldap,
+            static_users: Arc::new(RwLock::new(static_users)),
+            key_file: key_file.map(Arc::new),
         })
     }
 
+    /// A connection from the pool, bound as the bind user.
+    async fn ldap_connection(ldap: &LdapBackend) -> Result<managed::Object<LdapConnectionManager>> {
+        let mut conn = match ldap.pool.get().await {
+            Ok(conn) => Ok(conn),
+            Err(PoolError::Timeout(_)) => Err(AuthError::RedisConnectionTimeout),
+            Err(PoolError::Backend(err)) => Err(err.into_ldap_error("failed to get connection")),
+            Err(PoolError::PostCreateHook(err)) => Err(AuthError::from(err)),
+            Err(PoolError::Closed) => Err(AuthError::LdapPoolClosed),
+            Err(PoolError::NoRuntimeSpecified) => unreachable!(),
+        }?;
+
+        conn.simple_bind(&ldap.config.bind_dn, &ldap.config.bind_password)
+            .await
+            .into_ldap_error("failed to bind with provided bind credentials")?;
+
+        Ok(conn)
+    }
+
     #[instrument(skip_all, err)]
     async fn read_user_cache(&self, cache_key: &str) -> Result<Option<UserInfo>> {
         if let Some(conn) = self.redis_pool.clone() {
@@ -68,53 +126,50 @@ impl AuthClient {
 
     #[instrument(skip_all, err)]
     async fn write_user_cache(&self, cache_key: &str, user: &UserInfo) -> Result<()> {
-        if let Some(conn) = self.redis_pool.clone() {
-            if let Ok(user_json) = serde_json::to_string(user) {
-                conn.json_set::<(), _, _, _>(cache_key, "$", user_json, None)
-                    .await
-                    .into_redis_error("failed to set LDAP user cache data")?;
-                conn.expire::<(), _>(cache_key, 5 * 60, None)
-                    .await
-                    .into_redis_error("failed to set LDAP user cache expiration")?;
-            }
+        if let Some(conn) = self.redis_pool.clone()
+            && let Ok(user_json) = serde_json::to_string(user)
+        {
+            conn.json_set::<(), _, _, _>(cache_key, "$", user_json, None)
+                .await
+                .into_redis_error("failed to set LDAP user cache data")?;
+            conn.expire::<(), _>(cache_key, 5 * 60, None)
+                .await
+                .into_redis_error("failed to set LDAP user cache expiration")?;
         }
 
         Ok(())
     }
 
-    #[instrument(skip(self), err)]
+    #[instrument(skip(self, username), fields(username = %privacy::pseudonymize(username)), err)]
     async fn get_user(&self, username: &str) -> Result<Option<UserInfo>> {
-        let cache_key = format!("ldap_cache_user_{username}");
+        if let Some(user) = self.static_users.read().get(username) {
+            return Ok(Some(user.clone()));
+        }
+
+        let Some(ldap) = &self.ldap else {
+            return Ok(None);
+        };
+
+        let cache_key = user_cache_key(username);
 
         if let Some(cached_user) = self.read_user_cache(&cache_key).await? {
             return Ok(Some(cached_user));
         }
 
-        let mut conn = match self.ldap_pool.get().await {
-            Ok(conn) => Ok(conn),
-            Err(PoolError::Timeout(_)) => Err(AuthError::RedisConnectionTimeout),
-            Err(PoolError::Backend(err)) => Err(err.into_ldap_error("failed to get connection")),
-            Err(PoolError::PostCreateHook(err)) => Err(AuthError::from(err)),
-            Err(PoolError::Closed) => Err(AuthError::LdapPoolClosed),
-            Err(PoolError::NoRuntimeSpecified) => unreachable!(),
-        }?;
-
-        conn.simple_bind(&self.ldap_config.bind_dn, &self.ldap_config.bind_password)
-            .await
-            .into_ldap_error("failed to bind with provided bind credentials")?;
+        let mut conn = Self::ldap_connection(ldap).await?;
 
         let filter = format!(
             "{key}={value}",
-            key = ldap_escape(&self.ldap_config.user_attribute),
+            key = ldap_escape(&ldap.config.user_attribute),
             value = ldap_escape(username)
         );
 
         let search = conn
             .search(
-                &self.ldap_config.base_dn,
+                &ldap.config.base_dn,
                 Scope::Subtree,
                 &filter,
-                vec!["dn", "memberOf", &self.ldap_config.ssh_key_attribute],
+                vec!["dn", "memberOf", &ldap.config.ssh_key_attribute],
             )
             .await
             .into_ldap_error("failed to search for user")?;
@@ -125,17 +180,26 @@ impl AuthClient {
 
         match entries.len() {
             0 => {
-                event!(Level::DEBUG, username, "LDAP user not found");
+                event!(
+                    Level::DEBUG,
+                    username = %privacy::pseudonymize(username),
+                    "LDAP user not found"
+                );
                 Ok(None)
             }
             1 => {
-                event!(Level::DEBUG, username, "LDAP user found");
+                event!(
+                    Level::DEBUG,
+                    username = %privacy::pseudonymize(username),
+                    "LDAP user found"
+                );
                 let result = SearchEntry::construct(entries[0].clone());
 
                 let dn = result.dn;
+                let groups = result.attrs.get("memberOf").cloned().unwrap_or_default();
                 let mut public_keys = Vec::new();
 
-                if let Some(keys) = result.attrs.get(&self.ldap_config.ssh_key_attribute) {
+                if let Some(keys) = result.attrs.get(&ldap.config.ssh_key_attribute) {
                     for key in keys {
                         public_keys
                             .push(PublicKey::from_openssh(key).map_err(russh::keys::Error::from)?);
@@ -145,6 +209,7 @@ impl AuthClient {
                 let user = UserInfo {
                     username: username.to_string(),
                     dn,
+                    groups,
                     public_keys,
                 };
 
@@ -153,18 +218,22 @@ impl AuthClient {
                 Ok(Some(user))
             }
             _ => {
-                event!(Level::WARN, username, "Multiple LDAP users found");
+                event!(
+                    Level::WARN,
+                    username = %privacy::pseudonymize(username),
+                    "Multiple LDAP users found"
+                );
                 Err(AuthError::MultipleUsersFound(username.to_string()))
             }
         }
     }
 
-    #[instrument(skip(self, _password), fields(username = _username), err)]
-    pub async fn authenticate_password(&self, _username: &str, _password: &str) -> Result<bool> {
+    #[instrument(skip_all, fields(username = %privacy::pseudonymize(username)), err)]
+    pub async fn authenticate_password(&self, username: &str, _password: &str) -> Result<bool> {
         todo!()
     }
 
-    #[instrument(skip(self, key))]
+    #[instrument(skip_all, fields(username = %privacy::pseudonymize(username)))]
     pub async fn authenticate_public_key(&self, username: &str, key: &PublicKey) -> Result<bool> {
         if let Some(user) = self.get_user(username).await? {
             Ok(user
@@ -175,12 +244,201 @@ impl AuthClient {
             Ok(false)
         }
     }
+
+    /// Checks whether the user is a member of the LDAP group with the DN
+    /// `group`.
+    #[instrument(skip(self, username), fields(username = %privacy::pseudonymize(username)))]
+    pub async fn is_member_of(&self, username: &str, group: &str) -> Result<bool> {
+        if let Some(user) = self.get_user(username).await? {
+            Ok(user
+                .groups
+                .iter()
+                .any(|member_of| member_of.eq_ignore_ascii_case(group)))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// The user's SSH public keys, or none if there is no such user.
+    #[instrument(skip_all, fields(username = %privacy::pseudonymize(username)), err)]
+    pub async fn public_keys(&self, username: &str) -> Result<Vec<PublicKey>> {
+        Ok(self
+            .get_user(username)
+            .await?
+            .map(|user| user.public_keys)
+            .unwrap_or_default())
+    }
+
+    /// Replaces the user's SSH public keys with `keys`.
+    ///
+    /// Static users' keys are saved to [`Config::key_file`], and LDAP users'
+    /// keys are written back to the directory if
+    /// [`LdapConfig::write_back_keys`] is set. Fails with
+    /// [`AuthError::KeysReadOnly`] if neither applies to the user.
+    #[instrument(skip_all, fields(username = %privacy::pseudonymize(username)), err)]
+    pub async fn set_public_keys(&self, username: &str, keys: Vec<PublicKey>) -> Result<()> {
+        if keys.is_empty() {
+            return Err(AuthError::NoKeys);
+        }
+
+        let openssh_keys = keys
+            .iter()
+            .map(|key| key.to_openssh().map_err(russh::keys::Error::from))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if self.static_users.read().contains_key(username) {
+            let Some(key_file) = &self.key_file else {
+                return Err(AuthError::KeysReadOnly);
+            };
+
+            key_file.set(username, openssh_keys).await?;
+
+            if let Some(user) = self.static_users.write().get_mut(username) {
+                user.public_keys = keys;
+            }
+
+            return Ok(());
+        }
+
+        let Some(ldap) = self
+            .ldap
+            .as_ref()
+            .filter(|ldap| ldap.config.write_back_keys)
+        else {
+            return Err(AuthError::KeysReadOnly);
+        };
+        let Some(user) = self.get_user(username).await? else {
+            return Err(AuthError::KeysReadOnly);
+        };
+
+        let mut conn = Self::ldap_connection(ldap).await?;
+        conn.modify(
+            &user.dn,
+            vec![Mod::Replace(
+                ldap.config.ssh_key_attribute.clone(),
+                openssh_keys.into_iter().collect::<HashSet<_>>(),
+            )],
+        )
+        .await
+        .into_ldap_error("failed to write SSH keys")?
+        .success()
+        .into_ldap_error("failed to write SSH keys")?;
+
+        // The cached entry still has the old keys.
+        self.flush_user_cache(username).await?;
+
+        Ok(())
+    }
+
+    /// The name of the user with the numeric ID `uid`, from the system's user
+    /// database if it's there, and otherwise from the `uidNumber` of a user in
+    /// LDAP. `None` if neither knows of it.
+    #[instrument(skip(self), err)]
+    pub async fn user_name(&self, uid: u32) -> Result<Option<String>> {
+        let name = tokio::task::spawn_blocking(move || uzers::get_user_by_uid(uid))
+            .await
+            .ok()
+            .flatten()
+            .and_then(|user| user.name().to_str().map(ToString::to_string));
+        if name.is_some() {
+            return Ok(name);
+        }
+
+        let Some(ldap) = &self.ldap else {
+            return Ok(None);
+        };
+
+        Self::ldap_name(
+            ldap,
+            &format!("uidNumber={uid}"),
+            &ldap.config.user_attribute,
+        )
+        .await
+    }
+
+    /// The name of the group with the numeric ID `gid`, from the system's group
+    /// database if it's there, and otherwise from the `gidNumber` of a POSIX
+    /// group in LDAP. `None` if neither knows of it.
+    #[instrument(skip(self), err)]
+    pub async fn group_name(&self, gid: u32) -> Result<Option<String>> {
+        let name = tokio::task::spawn_blocking(move || uzers::get_group_by_gid(gid))
+            .await
+            .ok()
+            .flatten()
+            .and_then(|group| group.name().to_str().map(ToString::to_string));
+        if name.is_some() {
+            return Ok(name);
+        }
+
+        let Some(ldap) = &self.ldap else {
+            return Ok(None);
+        };
+
+        Self::ldap_name(
+            ldap,
+            &format!("(&(objectClass=posixGroup)(gidNumber={gid}))"),
+            "cn",
+        )
+        .await
+    }
+
+    /// The `attribute` of the first LDAP entry that matches `filter`.
+    async fn ldap_name(
+        ldap: &LdapBackend,
+        filter: &str,
+        attribute: &str,
+    ) -> Result<Option<String>> {
+        let mut conn = Self::ldap_connection(ldap).await?;
+
+        let search = conn
+            .search(
+                &ldap.config.base_dn,
+                Scope::Subtree,
+                filter,
+                vec![attribute],
+            )
+            .await
+            .into_ldap_error("failed to search for name")?;
+
+        let (entries, _) = search
+            .success()
+            .into_ldap_error("failed to get search results")?;
+
+        Ok(entries.into_iter().find_map(|entry| {
+            SearchEntry::construct(entry)
+                .attrs
+                .get(attribute)
+                .and_then(|values| values.first().cloned())
+        }))
+    }
+
+    /// Drops the cached directory entry for the user, so that the next lookup
+    /// goes to LDAP. Returns `false` if nothing was cached.
+    #[instrument(skip_all, fields(username = %privacy::pseudonymize(username)), err)]
+    pub async fn flush_user_cache(&self, username: &str) -> Result<bool> {
+        if let Some(conn) = self.redis_pool.clone() {
+            let removed: i64 = conn
+                .del(user_cache_key(username))
+                .await
+                .into_redis_error("failed to flush user cache")?;
+
+            Ok(removed > 0)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+fn user_cache_key(username: &str) -> String {
+    format!("ldap_cache_user_{username}")
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct UserInfo {
     username: String,
     dn: String,
+    #[serde(default)]
+    groups: Vec<String>,
     public_keys: Vec<PublicKey>,
 }
 
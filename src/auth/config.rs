@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use camino::Utf8PathBuf;
 use deadpool::managed;
 use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, LdapError};
 use schemars::JsonSchema;
@@ -10,6 +11,7 @@ use url::Url;
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct LdapConfig {
     /// LDAP URL to connect to for user backend.
+    #[schemars(example = "LdapConfig::example_url")]
     pub(super) url: Url,
 
     /// The maximum number of connections in the connection pool.
@@ -36,12 +38,14 @@ pub struct LdapConfig {
     pub(super) tls_no_verify: Option<bool>,
 
     /// Bind DN for LDAP search queries.
+    #[schemars(example = "LdapConfig::example_bind_dn")]
     pub(super) bind_dn: String,
 
     /// Password for the LDAP bind user.
     pub(super) bind_password: String,
 
     /// Base DN for LDAP searches.
+    #[schemars(example = "LdapConfig::example_base_dn")]
     pub(super) base_dn: String,
 
     /// LDAP attribute containing the username.
@@ -57,9 +61,26 @@ pub struct LdapConfig {
         skip_serializing_if = "LdapConfig::is_default_ssh_key_attribute"
     )]
     pub(super) ssh_key_attribute: String,
+
+    /// Let users change their own SSH public keys over SFTP, by writing them
+    /// back to `ssh_key_attribute`. The bind user needs write access to it.
+    #[serde(default)]
+    pub(super) write_back_keys: bool,
 }
 
 impl LdapConfig {
+    fn example_url() -> &'static str {
+        "ldaps://ldap.example.com"
+    }
+
+    fn example_bind_dn() -> &'static str {
+        "cn=schlep,ou=services,dc=example,dc=com"
+    }
+
+    fn example_base_dn() -> &'static str {
+        "ou=people,dc=example,dc=com"
+    }
+
     fn default_pool_max_size() -> usize {
         10
     }
@@ -70,7 +91,7 @@ impl LdapConfig {
     }
 
     fn default_conn_timeout() -> Duration {
-        Duration::from_secs(120)
+        Duration::from_mins(2)
     }
 
     fn is_default_conn_timeout(timeout: &Duration) -> bool {
@@ -162,6 +183,35 @@ impl managed::Manager for LdapConnectionManager {
 #[serde(rename = "auth_config")]
 pub struct Config {
     /// Configuration for Schlep's connection to the underlying LDAP
-    /// authentication directory.
-    pub(super) ldap: LdapConfig,
+    /// authentication directory. Without one, only the static `users` can log
+    /// in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) ldap: Option<LdapConfig>,
+
+    /// Users defined right in the configuration rather than in LDAP. They take
+    /// precedence over LDAP users with the same name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(super) users: Vec<StaticUser>,
+
+    /// A file to keep the SSH public keys that static users set for themselves
+    /// over SFTP, which replace the `public_keys` they have here. Without one,
+    /// static users can't change their keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub(super) key_file: Option<Utf8PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "static_user")]
+pub struct StaticUser {
+    pub username: String,
+
+    /// The user's SSH public keys, in OpenSSH format.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub public_keys: Vec<String>,
+
+    /// The groups the user is a member of, as they are named in
+    /// `visible_to_group`, `admin_group`, and the like.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
 }
@@ -1,7 +1,9 @@
+use std::io;
+
 use deadpool::managed::HookError;
 use ldap3::LdapError;
 
-use crate::redis::RedisError;
+use crate::{error_code::ErrorCode, redis::RedisError};
 
 #[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
 #[non_exhaustive]
@@ -24,4 +26,29 @@ pub enum AuthError {
     LdapConnectionTimeout,
     #[error("Redis connection timed out")]
     RedisConnectionTimeout,
+    #[error("key file error: {from}")]
+    KeyFileError { source: io::Error, from: String },
+    #[error("the user's SSH keys can't be changed here")]
+    KeysReadOnly,
+    #[error("a user has to keep at least one SSH key")]
+    NoKeys,
+}
+
+impl AuthError {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::LdapError { .. } | Self::LdapHookError(_) | Self::LdapPoolClosed => {
+                ErrorCode::DirectoryUnavailable
+            }
+            Self::RedisError { .. } | Self::NotConnected => ErrorCode::CacheUnavailable,
+            Self::SshKeyError(_) => ErrorCode::InvalidKey,
+            Self::MultipleUsersFound(_) => ErrorCode::AmbiguousUser,
+            Self::LdapConnectionTimeout | Self::RedisConnectionTimeout => ErrorCode::Timeout,
+            Self::KeyFileError { .. } => ErrorCode::Io,
+            Self::KeysReadOnly => ErrorCode::Unsupported,
+            Self::NoKeys => ErrorCode::BadRequest,
+        }
+    }
 }
@@ -0,0 +1,73 @@
+use std::{collections::BTreeMap, io};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{AuthError, error::IntoKeyFileError};
+
+/// The SSH public keys that static users have set for themselves, see
+/// [`Config::key_file`](super::Config::key_file).
+///
+/// Only the users who changed their keys are in here. Everyone else keeps the
+/// keys they have in the configuration.
+pub(super) struct KeyFile {
+    path: Utf8PathBuf,
+    keys: Mutex<BTreeMap<String, Vec<String>>>,
+    save: AsyncMutex<()>,
+}
+
+impl KeyFile {
+    pub(super) fn load(path: Utf8PathBuf) -> Result<Self, AuthError> {
+        let keys = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .into_key_file_error(format!("failed to parse key file {path}"))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err.into_key_file_error(format!("failed to read key file {path}")));
+            }
+        };
+
+        Ok(Self {
+            path,
+            keys: Mutex::new(keys),
+            save: AsyncMutex::new(()),
+        })
+    }
+
+    /// The keys `username` set for themselves, if they did.
+    pub(super) fn get(&self, username: &str) -> Option<Vec<String>> {
+        self.keys.lock().get(username).cloned()
+    }
+
+    /// Replaces the keys of `username` and saves the file.
+    pub(super) async fn set(&self, username: &str, keys: Vec<String>) -> Result<(), AuthError> {
+        // Holds the save lock across the change, so saves land in the same order
+        // as the changes they save.
+        let _guard = self.save.lock().await;
+
+        let contents = {
+            let mut all_keys = self.keys.lock();
+            all_keys.insert(username.to_string(), keys);
+
+            serde_json::to_vec_pretty(&*all_keys)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .into_key_file_error("failed to serialize keys")?
+        };
+
+        write_atomically(&self.path, contents).await
+    }
+}
+
+/// Replaces the contents of `file`, by way of a temporary file so that a
+/// crash can't leave a truncated one behind.
+async fn write_atomically(file: &Utf8Path, contents: Vec<u8>) -> Result<(), AuthError> {
+    let temp_file = file.with_extension("tmp");
+    tokio::fs::write(&temp_file, contents)
+        .await
+        .into_key_file_error(format!("failed to write key file {temp_file}"))?;
+    tokio::fs::rename(&temp_file, file)
+        .await
+        .into_key_file_error(format!("failed to replace key file {file}"))
+}
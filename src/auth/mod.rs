@@ -1,7 +1,8 @@
 mod client;
 mod config;
 mod error;
+mod key_file;
 
 pub use client::AuthClient;
-pub use config::Config;
+pub use config::{Config, StaticUser};
 pub use error::AuthError;
@@ -1,46 +1,123 @@
 #![forbid(unsafe_code)]
 
-use std::time::Duration;
+use std::{path::Path, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Result, bail};
+use camino::Utf8Path;
 use metrics_tracing_context::{MetricsLayer, TracingContextLayer};
 use metrics_util::layers::Layer as _;
 use mimalloc::MiMalloc;
+use russh::keys::PublicKey;
+use schemars::schema_for;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{Level, event};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
-    filter::{EnvFilter, LevelFilter},
+    filter::{LevelFilter, Targets},
     fmt::format::FmtSpan,
     prelude::*,
+    reload,
 };
+use url::Url;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
 use schlep::{
+    affinity::Affinity,
+    audit,
     auth::AuthClient,
+    checksums::ChecksumCache,
     config::Config,
+    conformance,
+    demo::Demo,
+    docs,
+    events::SessionEvents,
+    gateway::{Gateway, Links},
+    log_filter,
     metrics::Metrics,
-    sftp::SshServer,
-    vfs::VfsSetBuilder,
+    openssh,
+    probe::Probe,
+    purge,
+    push,
+    replication::Replicator,
+    reporting::{self, Incident},
+    sftp::{self, SshServer, load_host_keys},
+    state::StateStore,
+    supervisor,
+    transfers::Transfers,
+    upload_hooks::UploadHooks,
+    vfs::{LiveVfsSet, VfsSetBuilder},
 };
 
+const USAGE: &str = "usage: schlep [purge-user <username> | docs [markdown | html] | \
+                     import-openssh [<sshd_config>] | \
+                     conformance <sftp-url> <private-key-file> [<host-key>] | demo]";
+
 #[tokio::main]
 pub async fn main() -> Result<()> {
     LogTracer::init()?;
 
-    let env_filter = EnvFilter::builder()
-        .with_env_var("SCHLEP_LOG")
-        .with_default_directive(LevelFilter::INFO.into())
-        .from_env()?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["docs", format @ ..] => return print_docs(format),
+        ["import-openssh", sshd_config @ ..] => return import_openssh(sshd_config),
+        ["conformance", url, private_key_file, host_key @ ..] => {
+            return run_conformance(url, private_key_file, host_key).await;
+        }
+        ["demo"] => return run_demo().await,
+        _ => (),
+    }
+
+    let config = Config::load()?;
+
+    let mut log_directives = std::env::var("SCHLEP_LOG").unwrap_or_default();
+    if config.privacy.anonymize {
+        // Audit events carry raw identifiers, so in privacy mode they only go
+        // to the audit log.
+        log_directives = format!("{log_directives},{}=off", audit::TARGET);
+    }
+    let (env_filter, env_filter_handle) = reload::Layer::new(log_filter::build(&log_directives)?);
+    log_filter::init(log_directives, env_filter_handle);
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_span_events(FmtSpan::CLOSE)
         .with_filter(env_filter);
     let metrics_layer = MetricsLayer::new();
+    let (audit_layer, _audit_guard) = match config.audit.writer() {
+        Some((writer, guard)) => {
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(writer)
+                .with_filter(Targets::new().with_target(audit::TARGET, LevelFilter::TRACE));
+
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
     let subscriber = tracing_subscriber::registry()
         .with(fmt_layer)
+        .with(audit_layer)
         .with(metrics_layer);
     tracing::subscriber::set_global_default(subscriber)?;
 
+    config.privacy.init()?;
+    config.reporting.init()?;
+
+    tokio::spawn(raise_log_level_on_sigusr1());
+
+    let result = serve(config, &args).await;
+    if let Err(err) = &result {
+        reporting::report_and_wait(Incident::Fatal, format!("{err:#}")).await;
+    }
+
+    result
+}
+
+/// Sets up everything else and runs the servers until one of them fails for
+/// good.
+#[allow(clippy::too_many_lines)]
+async fn serve(config: Config, args: &[&str]) -> Result<()> {
     let metrics_recorder = metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder();
     let metrics_handle = metrics_recorder.handle();
 
@@ -59,25 +136,216 @@ pub async fn main() -> Result<()> {
     let metrics_recorder = TracingContextLayer::all().layer(metrics_recorder);
     metrics::set_global_recorder(metrics_recorder)?;
 
-    let config = Config::load()?;
-
     let redis_pool = if let Some(redis_config) = config.redis {
         Some(redis_config.get_pool()?)
     } else {
         None
     };
     let auth_client = AuthClient::new(config.auth.clone(), redis_pool.clone())?;
-    let vfs_builder = VfsSetBuilder::from_config(config.fs)?;
 
-    let metrics_server = Metrics::new(config.metrics.clone(), metrics_handle);
-    let mut ssh_server = SshServer::new(config.sftp.clone(), auth_client, vfs_builder.build());
+    match args {
+        [] => (),
+        ["purge-user", username] => {
+            return purge_user(&config.sftp, &config.audit, &auth_client, username).await;
+        }
+        _ => bail!(USAGE),
+    }
+
+    let vfs_builder = config
+        .audit
+        .mount(VfsSetBuilder::from_config(config.fs.clone())?)?;
 
-    let ssh = tokio::spawn(async move { ssh_server.run().await });
-    let metrics = tokio::spawn(async move { metrics_server.run().await });
+    let affinity = Affinity::new(config.affinity);
+    let probe = Probe::new(config.probe, &config.sftp);
+    let live_vfs_set = LiveVfsSet::new(vfs_builder.build(), config.fs);
+    tokio::spawn(reload_mounts_on_sighup(live_vfs_set.clone()));
+    let metrics_server = Metrics::new(
+        config.metrics.clone(),
+        metrics_handle,
+        affinity.clone(),
+        probe.clone(),
+        live_vfs_set.clone(),
+    );
+    let checksums = ChecksumCache::new(redis_pool.clone());
+    let transfers = Transfers::new(&config.transfers, StateStore::new(redis_pool));
+    config.events.init(&transfers);
+    push::Scheduler::new(config.push, live_vfs_set.clone(), transfers.clone())?.spawn();
+    UploadHooks::new(
+        config.upload_hooks,
+        live_vfs_set.clone(),
+        transfers.clone(),
+        checksums.clone(),
+    )?
+    .spawn();
+    Replicator::new(config.replication, live_vfs_set.clone()).spawn();
+    probe.spawn();
+    let gateway = match config.gateway {
+        Some(gateway_config) => {
+            let links = Links::new(&gateway_config)?;
+            let gateway_server = Gateway::new(
+                gateway_config,
+                links.clone(),
+                live_vfs_set.clone(),
+                transfers.clone(),
+            );
+
+            Some((gateway_server, links))
+        }
+        None => None,
+    };
+    let (gateway_server, links) = gateway.unzip();
+    let mut ssh_server = SshServer::new(
+        config.sftp.clone(),
+        auth_client,
+        live_vfs_set,
+        &transfers,
+        checksums,
+        links.as_ref(),
+    );
+    if config.events.is_enabled() {
+        ssh_server.add_lifecycle_hooks(Arc::new(SessionEvents));
+    }
+
+    let policies = config.supervisor;
+    let ssh = tokio::spawn(supervisor::supervise("ssh", policies.ssh, ssh_server));
+    let metrics = tokio::spawn(supervisor::supervise(
+        "metrics",
+        policies.metrics,
+        metrics_server,
+    ));
+    let agent_check = tokio::spawn(supervisor::supervise(
+        "agent_check",
+        policies.agent_check,
+        affinity,
+    ));
+    let gateway_policy = policies.gateway;
+    let gateway = tokio::spawn(async move {
+        match gateway_server {
+            Some(gateway_server) => {
+                supervisor::supervise("gateway", gateway_policy, gateway_server).await
+            }
+            None => std::future::pending().await,
+        }
+    });
 
     tokio::select! {
         ssh = ssh => { ssh??; }
         metrics = metrics => { metrics??; }
+        agent_check = agent_check => { agent_check??; }
+        gateway = gateway => { gateway??; }
+    }
+
+    Ok(())
+}
+
+/// Adds [`log_filter::SIGUSR1_DIRECTIVES`] to the log filter for a while
+/// whenever the process gets `SIGUSR1`.
+async fn raise_log_level_on_sigusr1() -> Result<()> {
+    let mut sigusr1 = signal(SignalKind::user_defined1())?;
+
+    while sigusr1.recv().await.is_some() {
+        if let Err(err) = log_filter::set(
+            log_filter::SIGUSR1_DIRECTIVES,
+            Some(log_filter::SIGUSR1_DURATION),
+        ) {
+            event!(Level::WARN, %err, "failed to raise log level");
+        }
+    }
+
+    Ok(())
+}
+
+/// Mounts what has been added to the `fs` configuration and unmounts what has
+/// been removed from it, for sessions that start afterwards, on `SIGHUP`.
+async fn reload_mounts_on_sighup(vfs_set: LiveVfsSet) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    while sighup.recv().await.is_some() {
+        let result = Config::load().and_then(|config| Ok(vfs_set.reload(config.fs)?));
+
+        match result {
+            Ok(()) => event!(Level::INFO, "reloaded mounts"),
+            Err(err) => event!(Level::WARN, err = %format!("{err:#}"), "failed to reload mounts"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Purges `username` and prints the signed report.
+async fn purge_user(
+    sftp_config: &sftp::Config,
+    audit_config: &audit::Config,
+    auth_client: &AuthClient,
+    username: &str,
+) -> Result<()> {
+    let host_keys = load_host_keys(sftp_config)?;
+    let Some(signing_key) = host_keys.first() else {
+        bail!("a host key is needed to sign the purge report");
+    };
+
+    let report = purge::purge_user(username, auth_client, audit_config).await?;
+    println!("{}", report.sign(signing_key)?);
+
+    Ok(())
+}
+
+/// Prints the configuration reference in the requested format, which is
+/// Markdown by default.
+fn print_docs(format: &[&str]) -> Result<()> {
+    let format = match format {
+        [] => docs::Format::Markdown,
+        [format] => format.parse().map_err(anyhow::Error::msg)?,
+        _ => bail!(USAGE),
+    };
+
+    print!("{}", docs::render(&schema_for!(Config), format));
+
+    Ok(())
+}
+
+/// Prints the configuration equivalent to an OpenSSH deployment, read from
+/// the given `sshd_config` or the system one.
+fn import_openssh(sshd_config: &[&str]) -> Result<()> {
+    let mut sources = openssh::Sources::default();
+    match sshd_config {
+        [] => (),
+        [sshd_config] => sources.sshd_config = Utf8Path::new(sshd_config),
+        _ => bail!(USAGE),
+    }
+
+    print!("{}", openssh::import(&sources)?);
+
+    Ok(())
+}
+
+/// Runs a demo server until it fails or the process is stopped, see
+/// [`schlep::demo`].
+async fn run_demo() -> Result<()> {
+    let log_filter = log_filter::build(&std::env::var("SCHLEP_LOG").unwrap_or_default())?;
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(log_filter))
+        .try_init()?;
+
+    let demo = Demo::start().await?;
+    println!("{}", demo.instructions()?);
+    demo.run().await?;
+
+    Ok(())
+}
+
+async fn run_conformance(url: &str, private_key_file: &str, host_key: &[&str]) -> Result<()> {
+    let host_key = match host_key {
+        [] => None,
+        [host_key] => Some(PublicKey::from_openssh(host_key)?),
+        _ => bail!(USAGE),
+    };
+
+    let report = conformance::run(&Url::parse(url)?, Path::new(private_key_file), host_key).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if !report.passed() {
+        bail!("the server failed some conformance checks");
     }
 
     Ok(())
@@ -0,0 +1,126 @@
+//! A cache of the checksums of whole files, so that asking for the checksum
+//! of a file that hasn't changed doesn't hash it all over again.
+//!
+//! Checksums are keyed by the mount, the path, and the file's
+//! [change token](crate::vfs::Metadata::change_token), so one that's changed in
+//! any way the backend can tell is hashed again. They are kept in Redis when
+//! one is configured, so that every instance shares them, and in memory
+//! otherwise.
+
+use std::{future::Future, num::NonZeroUsize, sync::Arc};
+
+use camino::Utf8Path;
+use fred::{prelude::*, types::Expiration};
+use lru::LruCache;
+use parking_lot::Mutex;
+use thiserror_ext::AsReport;
+use tracing::{Level, event};
+
+use crate::{
+    redis::{RedisError, RedisPool},
+    vfs::{self, HashAlgorithm, Mount, Vfs},
+};
+
+/// How many checksums are kept at most when they're kept in memory.
+const MEMORY_ENTRIES: usize = 10_000;
+
+/// How long checksums are kept in Redis, in seconds.
+const REDIS_TTL: i64 = 7 * 24 * 60 * 60;
+
+/// The checksums of files that have been hashed before.
+#[derive(Clone)]
+pub struct ChecksumCache(Backend);
+
+#[derive(Clone)]
+enum Backend {
+    Redis(RedisPool),
+    Memory(Arc<Mutex<LruCache<String, String>>>),
+}
+
+impl ChecksumCache {
+    /// A cache kept in Redis through `redis_pool`, or in memory if there is
+    /// no pool.
+    #[must_use]
+    pub fn new(redis_pool: Option<RedisPool>) -> Self {
+        match redis_pool {
+            Some(pool) => Self(Backend::Redis(pool)),
+            None => Self(Backend::Memory(Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(MEMORY_ENTRIES).unwrap(),
+            ))))),
+        }
+    }
+
+    /// The hex-encoded `algorithm` checksum of the file at `path` in `vfs`.
+    pub async fn checksum(
+        &self,
+        algorithm: HashAlgorithm,
+        vfs: &Mount,
+        path: &Utf8Path,
+    ) -> Result<String, vfs::Error> {
+        self.get_or_hash(algorithm, vfs, path, async {
+            let digests = vfs.hash_range(path, algorithm, 0, 0, 0).await?;
+
+            Ok(vfs::to_hex(&digests.concat()))
+        })
+        .await
+    }
+
+    /// The cached checksum of the file at `path`, or the one `hash` works out
+    /// if there isn't one yet. A cache that can't be reached is only logged,
+    /// since the file can still be hashed without it.
+    async fn get_or_hash<F>(
+        &self,
+        algorithm: HashAlgorithm,
+        vfs: &Mount,
+        path: &Utf8Path,
+        hash: F,
+    ) -> Result<String, vfs::Error>
+    where
+        F: Future<Output = Result<String, vfs::Error>>,
+    {
+        let metadata = vfs.stat(path).await?;
+        let key = format!(
+            "schlep:checksum:{}:{}:{path}:{}",
+            algorithm.name(),
+            vfs.vfs_root(),
+            metadata.change_token()
+        );
+
+        match self.get(&key).await {
+            Ok(Some(checksum)) => return Ok(checksum),
+            Ok(None) => {}
+            Err(err) => {
+                event!(Level::WARN, err = %err.as_report(), "failed to read checksum cache");
+            }
+        }
+
+        let checksum = hash.await?;
+
+        if let Err(err) = self.set(&key, &checksum).await {
+            event!(Level::WARN, err = %err.as_report(), "failed to write checksum cache");
+        }
+
+        Ok(checksum)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<String>, RedisError> {
+        match &self.0 {
+            Backend::Redis(pool) => pool.get(key).await,
+            Backend::Memory(cache) => Ok(cache.lock().get(key).cloned()),
+        }
+    }
+
+    async fn set(&self, key: &str, checksum: &str) -> Result<(), RedisError> {
+        match &self.0 {
+            Backend::Redis(pool) => {
+                pool.set::<(), _, _>(key, checksum, Some(Expiration::EX(REDIS_TTL)), None, false)
+                    .await
+            }
+            Backend::Memory(cache) => {
+                cache.lock().put(key.to_owned(), checksum.to_owned());
+
+                Ok(())
+            }
+        }
+    }
+}
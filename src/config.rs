@@ -6,7 +6,25 @@ use figment::{
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{auth, metrics, redis, sftp, vfs};
+use crate::{
+    affinity,
+    audit,
+    auth,
+    events,
+    gateway,
+    metrics,
+    privacy,
+    probe,
+    push,
+    redis,
+    replication,
+    reporting,
+    sftp,
+    supervisor,
+    transfers,
+    upload_hooks,
+    vfs,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
@@ -24,6 +42,58 @@ pub struct Config {
     pub redis: Option<redis::Config>,
 
     pub metrics: metrics::Config,
+
+    /// Configuration for the audit log.
+    #[serde(default)]
+    pub audit: audit::Config,
+
+    /// Configuration for keeping identifiers out of the logs and metrics.
+    #[serde(default)]
+    pub privacy: privacy::Config,
+
+    /// Configuration for the transfer history.
+    #[serde(default)]
+    pub transfers: transfers::Config,
+
+    /// Configuration for pushing files to remote destinations.
+    #[serde(default)]
+    pub push: push::Config,
+
+    /// Configuration for the hooks that run when an upload finishes.
+    #[serde(default)]
+    pub upload_hooks: upload_hooks::Config,
+
+    /// Configuration for publishing what clients do to a message bus.
+    #[serde(default)]
+    pub events: events::Config,
+
+    /// Configuration for replicating files between mounts.
+    #[serde(default)]
+    pub replication: replication::Config,
+
+    /// Configuration for the HTTP gateway that upload and download links are
+    /// served from. Without it, links can't be created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<gateway::Config>,
+
+    /// Configuration for checking that the SFTP server still answers
+    /// connections, which `/readyz` reports. Without it, nothing is checked
+    /// and `/readyz` always reports the server as ready.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<probe::Config>,
+
+    /// Configuration for helping load balancers send reconnecting clients back
+    /// to the same instance.
+    #[serde(default)]
+    pub affinity: affinity::Config,
+
+    /// Configuration for reporting panics and fatal errors.
+    #[serde(default)]
+    pub reporting: reporting::Config,
+
+    /// Configuration for restarting servers that stop.
+    #[serde(default)]
+    pub supervisor: supervisor::Config,
 }
 
 impl Config {
@@ -0,0 +1,789 @@
+//! Protocol-level conformance checks against a running SFTP server, for making
+//! sure a deployment still behaves the way SFTP clients expect it to.
+//!
+//! The checks connect like any other client would, and cover request ordering,
+//! end-of-file semantics, attribute round-trips, and the behavior of every
+//! advertised extension. Everything they create goes in a scratch directory
+//! that is removed afterwards. The outcome is a [`ConformanceReport`], which is
+//! meant to be read by machines as much as by people.
+
+use std::{
+    future::Future,
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use ahash::HashSet;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use humantime_serde::re::humantime;
+use russh::keys::PublicKey;
+use russh_sftp::{
+    client::{RawSftpSession, error::Error as SftpError},
+    protocol::{File, FileAttributes, OpenFlags, Packet, StatusCode, Version},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use tokio::task::JoinSet;
+use url::Url;
+
+use crate::{
+    sftp::extensions::{
+        self,
+        ChangeTokenExtension,
+        ChangeTokenReply,
+        ExpectSha256Extension,
+        FstatvfsExtension,
+        Statvfs,
+        WriteIfMatchExtension,
+    },
+    ssh_client::SshClient,
+};
+
+/// How much to ask for in each read.
+const READ_LEN: u32 = 32 * 1024;
+
+/// What a conformance run found.
+#[derive(Debug, Serialize)]
+pub struct ConformanceReport {
+    pub server: String,
+    pub started_at: String,
+    pub checks: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+    /// Whether no check failed. Skipped checks don't count against the server.
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.outcome != Outcome::Fail)
+    }
+
+    async fn run(&mut self, name: impl Into<String>, check: impl Future<Output = Check>) {
+        let started = Instant::now();
+        let (outcome, message) = match check.await {
+            Ok(()) => (Outcome::Pass, None),
+            Err(Failure::Fail(message)) => (Outcome::Fail, Some(message)),
+            Err(Failure::Skip(message)) => (Outcome::Skip, Some(message)),
+        };
+
+        self.checks.push(CheckResult {
+            name: name.into(),
+            outcome,
+            message,
+            duration_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+        });
+    }
+}
+
+/// The result of a single check.
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub outcome: Outcome,
+    /// Why the check failed or was skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// The server doesn't claim to support what the check needs.
+    Skip,
+}
+
+/// Why a check didn't pass.
+enum Failure {
+    Fail(String),
+    Skip(String),
+}
+
+impl From<SftpError> for Failure {
+    fn from(err: SftpError) -> Self {
+        Self::Fail(err.to_string())
+    }
+}
+
+type Check = Result<(), Failure>;
+
+/// Runs every check against the server at `url`, logging in with the key in
+/// `private_key_file`. The scratch directory is created in the directory that
+/// `url` points to.
+///
+/// Only problems with getting to the point where checks can run are returned
+/// as errors; failed checks are recorded in the report.
+pub async fn run(
+    url: &Url,
+    private_key_file: &Path,
+    host_key: Option<PublicKey>,
+) -> Result<ConformanceReport> {
+    let mut report = ConformanceReport {
+        server: url.to_string(),
+        started_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        checks: Vec::new(),
+    };
+
+    let client = SshClient::connect(url, private_key_file, host_key)
+        .await
+        .context("failed to connect")?;
+    let session = RawSftpSession::new(client.sftp().await.context("failed to start SFTP")?);
+    let version = session
+        .init()
+        .await
+        .context("failed to start SFTP session")?;
+
+    let root = match url.path() {
+        "" => ".",
+        path => path.trim_end_matches('/'),
+    };
+    let dir = format!("{root}/schlep-conformance-{:08x}", rand::random::<u32>());
+    session
+        .mkdir(&dir, FileAttributes::empty())
+        .await
+        .with_context(|| format!("failed to create scratch directory {dir}"))?;
+
+    let checker = Checker {
+        session: Arc::new(session),
+        version,
+        dir,
+    };
+
+    report.run("version", checker.check_version()).await;
+    report.run("realpath", checker.check_realpath()).await;
+    report
+        .run("out_of_order_writes", checker.check_out_of_order_writes())
+        .await;
+    report
+        .run("pipelined_writes", checker.check_pipelined_writes())
+        .await;
+    report.run("read_eof", checker.check_read_eof()).await;
+    report.run("readdir_eof", checker.check_readdir_eof()).await;
+    report.run("attributes", checker.check_attributes()).await;
+    report
+        .run("missing_files", checker.check_missing_files())
+        .await;
+    report.run("directories", checker.check_directories()).await;
+    report
+        .run("rename_and_remove", checker.check_rename_and_remove())
+        .await;
+
+    let mut advertised = checker.version.extensions.keys().collect::<Vec<_>>();
+    advertised.sort();
+    for name in advertised {
+        report
+            .run(format!("extension {name}"), checker.check_extension(name))
+            .await;
+    }
+    report
+        .run("unknown_extension", checker.check_unknown_extension())
+        .await;
+
+    checker.clean_up().await;
+
+    Ok(report)
+}
+
+struct Checker {
+    session: Arc<RawSftpSession>,
+    version: Version,
+    dir: String,
+}
+
+impl Checker {
+    /// The path of `name` in the scratch directory.
+    fn path(&self, name: &str) -> String {
+        format!("{}/{name}", self.dir)
+    }
+
+    /// Creates `name` in the scratch directory with `contents`, returning its
+    /// path.
+    async fn create(&self, name: &str, contents: &[u8]) -> Result<String, Failure> {
+        let path = self.path(name);
+        let handle = self
+            .session
+            .open(
+                &path,
+                OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
+                FileAttributes::empty(),
+            )
+            .await?
+            .handle;
+        self.session.write(&handle, 0, contents.to_vec()).await?;
+        self.session.close(handle).await?;
+
+        Ok(path)
+    }
+
+    async fn read_to_end(&self, path: &str) -> Result<Vec<u8>, Failure> {
+        let handle = self
+            .session
+            .open(path, OpenFlags::READ, FileAttributes::empty())
+            .await?
+            .handle;
+        let mut contents = Vec::new();
+
+        loop {
+            match self
+                .session
+                .read(&handle, contents.len() as u64, READ_LEN)
+                .await
+            {
+                Ok(data) => contents.extend(data.data),
+                Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        self.session.close(handle).await?;
+
+        Ok(contents)
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn check_version(&self) -> Check {
+        ensure(self.version.version == 3, || {
+            format!("server negotiated version {}", self.version.version)
+        })
+    }
+
+    async fn check_realpath(&self) -> Check {
+        let canonical = single_name(self.session.realpath(&self.dir).await?.files)?;
+        ensure(canonical.starts_with('/'), || {
+            format!("{canonical} isn't an absolute path")
+        })?;
+
+        let dotted = single_name(self.session.realpath(self.path(".")).await?.files)?;
+        ensure(dotted == canonical, || {
+            format!(
+                "{}/. resolved to {dotted} rather than {canonical}",
+                self.dir
+            )
+        })
+    }
+
+    /// Writes the second half of a file before the first.
+    async fn check_out_of_order_writes(&self) -> Check {
+        let contents = pattern(64 * 1024);
+        let half = contents.len() / 2;
+        let path = self.path("out-of-order");
+
+        let handle = self
+            .session
+            .open(
+                &path,
+                OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
+                FileAttributes::empty(),
+            )
+            .await?
+            .handle;
+        self.session
+            .write(&handle, half as u64, contents[half..].to_vec())
+            .await?;
+        self.session
+            .write(&handle, 0, contents[..half].to_vec())
+            .await?;
+        self.session.close(handle).await?;
+
+        ensure(self.read_to_end(&path).await? == contents, || {
+            "file doesn't match what was written".to_string()
+        })
+    }
+
+    /// Sends every write before waiting for any reply, so that the server sees
+    /// them back to back and may finish them in any order.
+    async fn check_pipelined_writes(&self) -> Check {
+        const CHUNK_SIZE: usize = 4 * 1024;
+
+        let contents = pattern(32 * CHUNK_SIZE);
+        let path = self.path("pipelined");
+
+        let handle = self
+            .session
+            .open(
+                &path,
+                OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
+                FileAttributes::empty(),
+            )
+            .await?
+            .handle;
+
+        let mut writes = JoinSet::new();
+        for (index, chunk) in contents.chunks(CHUNK_SIZE).enumerate() {
+            let session = self.session.clone();
+            let handle = handle.clone();
+            let chunk = chunk.to_vec();
+
+            writes.spawn(async move {
+                session
+                    .write(handle, (index * CHUNK_SIZE) as u64, chunk)
+                    .await
+            });
+        }
+        while let Some(result) = writes.join_next().await {
+            result.map_err(|err| Failure::Fail(err.to_string()))??;
+        }
+        self.session.close(handle).await?;
+
+        ensure(self.read_to_end(&path).await? == contents, || {
+            "file doesn't match what was written".to_string()
+        })
+    }
+
+    /// A read that runs into the end of the file comes back short, and one
+    /// that starts at or past it gets `SSH_FX_EOF`.
+    async fn check_read_eof(&self) -> Check {
+        let contents = pattern(1000);
+        let path = self.create("eof", &contents).await?;
+
+        let handle = self
+            .session
+            .open(&path, OpenFlags::READ, FileAttributes::empty())
+            .await?
+            .handle;
+        let tail = self.session.read(&handle, 990, 100).await?.data;
+        let at_end = self.session.read(&handle, 1000, 100).await;
+        let past_end = self.session.read(&handle, 1_000_000, 100).await;
+        self.session.close(handle).await?;
+
+        ensure(
+            !tail.is_empty() && contents[990..].starts_with(&tail),
+            || format!("short read returned {} wrong bytes", tail.len()),
+        )?;
+        expect_status(at_end, StatusCode::Eof, "read at end of file")?;
+        expect_status(past_end, StatusCode::Eof, "read past end of file")
+    }
+
+    /// Every entry is listed before `SSH_FX_EOF`, and reading on after that
+    /// keeps getting `SSH_FX_EOF`.
+    async fn check_readdir_eof(&self) -> Check {
+        const NAMES: [&str; 3] = ["a", "b", "c"];
+
+        let dir = self.path("listing");
+        self.session.mkdir(&dir, FileAttributes::empty()).await?;
+        for name in NAMES {
+            self.create(&format!("listing/{name}"), b"").await?;
+        }
+
+        let handle = self.session.opendir(&dir).await?.handle;
+        let mut listed = HashSet::default();
+        loop {
+            match self.session.readdir(&handle).await {
+                Ok(name) => listed.extend(name.files.into_iter().map(|file| file.filename)),
+                Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let after_end = self.session.readdir(&handle).await;
+        self.session.close(handle).await?;
+
+        for name in NAMES {
+            ensure(listed.contains(name), || format!("{name} wasn't listed"))?;
+        }
+        expect_status(after_end, StatusCode::Eof, "readdir after end of directory")
+    }
+
+    /// Times set with `SSH_FXP_SETSTAT` come back from `SSH_FXP_STAT`, and
+    /// `SSH_FXP_FSTAT` agrees with it.
+    async fn check_attributes(&self) -> Check {
+        const MTIME: u32 = 1_700_000_000;
+
+        let path = self.create("attributes", &pattern(123)).await?;
+        self.session
+            .setstat(
+                &path,
+                FileAttributes {
+                    atime: Some(MTIME),
+                    mtime: Some(MTIME),
+                    ..FileAttributes::empty()
+                },
+            )
+            .await?;
+
+        let attrs = self.session.stat(&path).await?.attrs;
+        ensure(attrs.size == Some(123), || {
+            format!("size is {:?} rather than 123", attrs.size)
+        })?;
+        ensure(attrs.mtime == Some(MTIME), || {
+            format!("mtime is {:?} rather than {MTIME}", attrs.mtime)
+        })?;
+
+        let handle = self
+            .session
+            .open(&path, OpenFlags::READ, FileAttributes::empty())
+            .await?
+            .handle;
+        let fattrs = self.session.fstat(&handle).await?.attrs;
+        self.session.close(handle).await?;
+
+        ensure(
+            fattrs.size == attrs.size && fattrs.mtime == attrs.mtime,
+            || "fstat disagrees with stat".to_string(),
+        )
+    }
+
+    async fn check_missing_files(&self) -> Check {
+        let path = self.path("missing");
+
+        expect_status(
+            self.session.stat(&path).await,
+            StatusCode::NoSuchFile,
+            "stat",
+        )?;
+        expect_status(
+            self.session
+                .open(&path, OpenFlags::READ, FileAttributes::empty())
+                .await,
+            StatusCode::NoSuchFile,
+            "open",
+        )?;
+        expect_status(
+            self.session.opendir(&path).await,
+            StatusCode::NoSuchFile,
+            "opendir",
+        )?;
+        expect_status(
+            self.session.remove(&path).await,
+            StatusCode::NoSuchFile,
+            "remove",
+        )
+    }
+
+    async fn check_directories(&self) -> Check {
+        let path = self.path("directory");
+
+        self.session.mkdir(&path, FileAttributes::empty()).await?;
+        let attrs = self.session.stat(&path).await?.attrs;
+        ensure(attrs.is_dir(), || {
+            "new directory isn't a directory".to_string()
+        })?;
+        ensure(
+            self.session
+                .mkdir(&path, FileAttributes::empty())
+                .await
+                .is_err(),
+            || "creating an existing directory succeeded".to_string(),
+        )?;
+
+        self.session.rmdir(&path).await?;
+        expect_status(
+            self.session.stat(&path).await,
+            StatusCode::NoSuchFile,
+            "stat after rmdir",
+        )
+    }
+
+    async fn check_rename_and_remove(&self) -> Check {
+        let from = self.create("rename-from", b"renamed").await?;
+        let to = self.path("rename-to");
+
+        self.session.rename(&from, &to).await?;
+        expect_status(
+            self.session.stat(&from).await,
+            StatusCode::NoSuchFile,
+            "stat of rename source",
+        )?;
+        ensure(self.read_to_end(&to).await? == b"renamed", || {
+            "renamed file doesn't match the original".to_string()
+        })?;
+
+        self.session.remove(&to).await?;
+        expect_status(
+            self.session.stat(&to).await,
+            StatusCode::NoSuchFile,
+            "stat after remove",
+        )
+    }
+
+    async fn check_extension(&self, name: &str) -> Check {
+        match name {
+            "limits@openssh.com" => {
+                let limits = self.session.limits().await?;
+                ensure(limits.max_read_len > 0 && limits.max_write_len > 0, || {
+                    "limits don't allow reading or writing".to_string()
+                })
+            }
+            "hardlink@openssh.com" => {
+                let from = self.create("hardlink-from", b"linked").await?;
+                let to = self.path("hardlink-to");
+
+                self.session.hardlink(&from, &to).await?;
+                ensure(self.read_to_end(&to).await? == b"linked", || {
+                    "link doesn't match the original".to_string()
+                })
+            }
+            "fsync@openssh.com" => {
+                let path = self.create("fsync", b"").await?;
+                let handle = self
+                    .session
+                    .open(&path, OpenFlags::WRITE, FileAttributes::empty())
+                    .await?
+                    .handle;
+                self.session.write(&handle, 0, b"synced".to_vec()).await?;
+                let result = self.session.fsync(&handle).await;
+                self.session.close(handle).await?;
+
+                result.map(drop).map_err(Failure::from)
+            }
+            "statvfs@openssh.com" => self
+                .session
+                .statvfs(&self.dir)
+                .await
+                .map(drop)
+                .map_err(Failure::from),
+            extensions::CHANGE_TOKEN => self.check_change_token().await,
+            extensions::WRITE_IF_MATCH => self.check_write_if_match().await,
+            extensions::EXPECT_SHA256 => self.check_expect_sha256().await,
+            extensions::FSTATVFS => self.check_fstatvfs().await,
+            _ => Err(Failure::Skip("no check for this extension".to_string())),
+        }
+    }
+
+    async fn change_token(&self, path: &str) -> Result<String, Failure> {
+        let request = ChangeTokenExtension {
+            path: path.to_string(),
+        };
+        let reply: ChangeTokenReply = self.extended(extensions::CHANGE_TOKEN, &request).await?;
+
+        Ok(reply.token)
+    }
+
+    /// The token changes when the file does.
+    async fn check_change_token(&self) -> Check {
+        let path = self.create("change-token", b"before").await?;
+        let before = self.change_token(&path).await?;
+        ensure(before == self.change_token(&path).await?, || {
+            "token changed while the file didn't".to_string()
+        })?;
+
+        self.create("change-token", b"after!").await?;
+        ensure(before != self.change_token(&path).await?, || {
+            "token didn't change with the file".to_string()
+        })
+    }
+
+    /// A write with the current token goes through, and one with a stale token
+    /// is refused.
+    async fn check_write_if_match(&self) -> Check {
+        let path = self.create("write-if-match", b"original").await?;
+        let token = self.change_token(&path).await?;
+
+        let handle = self
+            .session
+            .open(&path, OpenFlags::WRITE, FileAttributes::empty())
+            .await?
+            .handle;
+        let mut request = WriteIfMatchExtension {
+            handle: handle.clone(),
+            token,
+            offset: 0,
+            data: b"replaced".to_vec(),
+        };
+        let matched = self
+            .extended::<_, ChangeTokenReply>(extensions::WRITE_IF_MATCH, &request)
+            .await;
+        request.data = b"conflict".to_vec();
+        let stale = self
+            .extended::<_, ChangeTokenReply>(extensions::WRITE_IF_MATCH, &request)
+            .await;
+        self.session.close(handle).await?;
+
+        let matched = matched?;
+        ensure(matched.token == self.change_token(&path).await?, || {
+            "returned token isn't the file's new token".to_string()
+        })?;
+        ensure(stale.is_err(), || {
+            "write with a stale token succeeded".to_string()
+        })?;
+        ensure(self.read_to_end(&path).await? == b"replaced", || {
+            "file doesn't match the matching write".to_string()
+        })
+    }
+
+    /// An upload that matches the SHA-256 declared for it is kept, and one
+    /// that doesn't is refused when it's closed.
+    async fn check_expect_sha256(&self) -> Check {
+        let contents = b"checksummed";
+        let sha256 = format!("{:x}", Sha256::digest(contents));
+
+        let matching = self
+            .upload_expecting("expect-sha256-match", contents, &sha256)
+            .await?;
+        ensure(matching.is_ok(), || {
+            "upload matching its checksum was refused".to_string()
+        })?;
+        ensure(
+            self.read_to_end(&self.path("expect-sha256-match")).await? == contents,
+            || "kept upload doesn't match what was written".to_string(),
+        )?;
+
+        let mismatched = self
+            .upload_expecting("expect-sha256-mismatch", b"tampered", &sha256)
+            .await?;
+        expect_status(
+            mismatched,
+            StatusCode::Failure,
+            "close of mismatched upload",
+        )
+    }
+
+    /// Reporting on the filesystem works through a handle, like it does for
+    /// a path with `statvfs@openssh.com`.
+    async fn check_fstatvfs(&self) -> Check {
+        let handle = self.session.opendir(&self.dir).await?.handle;
+        let request = FstatvfsExtension {
+            handle: handle.clone(),
+        };
+        let result = self
+            .extended::<_, Statvfs>(extensions::FSTATVFS, &request)
+            .await;
+        self.session.close(handle).await?;
+
+        result.map(drop).map_err(Failure::from)
+    }
+
+    /// Uploads `contents` to `name` after declaring that it has the SHA-256
+    /// `sha256`, and returns what closing the file did.
+    async fn upload_expecting(
+        &self,
+        name: &str,
+        contents: &[u8],
+        sha256: &str,
+    ) -> Result<Result<(), SftpError>, Failure> {
+        let path = self.path(name);
+        let handle = self
+            .session
+            .open(
+                &path,
+                OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE,
+                FileAttributes::empty(),
+            )
+            .await?
+            .handle;
+        let request = ExpectSha256Extension {
+            handle: handle.clone(),
+            sha256: sha256.to_string(),
+        };
+        let data = russh_sftp::ser::to_bytes(&request)
+            .map_err(SftpError::from)?
+            .to_vec();
+
+        let declared = self.session.extended(extensions::EXPECT_SHA256, data).await;
+        self.session.write(&handle, 0, contents.to_vec()).await?;
+        let closed = self.session.close(handle).await.map(drop);
+
+        match declared? {
+            Packet::Status(status) if status.status_code == StatusCode::Ok => Ok(closed),
+            Packet::Status(status) => Err(SftpError::Status(status).into()),
+            _ => Err(SftpError::UnexpectedPacket.into()),
+        }
+    }
+
+    async fn check_unknown_extension(&self) -> Check {
+        let result = self
+            .session
+            .extended("conformance-check@schlep.invalid", Vec::new())
+            .await;
+
+        match result {
+            Ok(Packet::Status(status)) => expect_status(
+                Err::<(), _>(SftpError::Status(status)),
+                StatusCode::OpUnsupported,
+                "unknown extension",
+            ),
+            result => expect_status(result, StatusCode::OpUnsupported, "unknown extension"),
+        }
+    }
+
+    /// Sends an extended request and decodes the reply.
+    async fn extended<T: Serialize, R: DeserializeOwned>(
+        &self,
+        name: &str,
+        request: &T,
+    ) -> Result<R, SftpError> {
+        let data = russh_sftp::ser::to_bytes(request)?.to_vec();
+
+        match self.session.extended(name, data).await? {
+            Packet::ExtendedReply(reply) => {
+                Ok(russh_sftp::de::from_bytes(&mut Bytes::from(reply.data))?)
+            }
+            Packet::Status(status) => Err(SftpError::Status(status)),
+            _ => Err(SftpError::UnexpectedPacket),
+        }
+    }
+
+    /// Removes the scratch directory and everything in it. Failures are
+    /// ignored, since the report is what matters.
+    async fn clean_up(&self) {
+        let mut dirs = vec![self.dir.clone()];
+        let mut index = 0;
+
+        while let Some(dir) = dirs.get(index).cloned() {
+            index += 1;
+
+            let Ok(handle) = self.session.opendir(&dir).await.map(|reply| reply.handle) else {
+                continue;
+            };
+            while let Ok(name) = self.session.readdir(&handle).await {
+                for file in name.files {
+                    if file.filename == "." || file.filename == ".." {
+                        continue;
+                    }
+
+                    let path = format!("{dir}/{}", file.filename);
+                    if file.attrs.is_dir() {
+                        dirs.push(path);
+                    } else {
+                        let _ = self.session.remove(path).await;
+                    }
+                }
+            }
+            let _ = self.session.close(handle).await;
+        }
+
+        for dir in dirs.into_iter().rev() {
+            let _ = self.session.rmdir(dir).await;
+        }
+    }
+}
+
+fn ensure(condition: bool, message: impl FnOnce() -> String) -> Check {
+    if condition {
+        Ok(())
+    } else {
+        Err(Failure::Fail(message()))
+    }
+}
+
+/// Checks that `result` is the status `expected`.
+fn expect_status<T>(result: Result<T, SftpError>, expected: StatusCode, what: &str) -> Check {
+    match result {
+        Err(SftpError::Status(status)) if status.status_code == expected => Ok(()),
+        Err(err) => Err(Failure::Fail(format!(
+            "{what}: expected {expected}, got {err}"
+        ))),
+        Ok(_) => Err(Failure::Fail(format!(
+            "{what}: expected {expected}, but it succeeded"
+        ))),
+    }
+}
+
+fn single_name(files: Vec<File>) -> Result<String, Failure> {
+    match <[_; 1]>::try_from(files) {
+        Ok([file]) => Ok(file.filename),
+        Err(files) => Err(Failure::Fail(format!(
+            "expected one name, got {}",
+            files.len()
+        ))),
+    }
+}
+
+/// Recognizable file contents that don't repeat on any power-of-two boundary,
+/// so that misplaced chunks show up.
+fn pattern(len: usize) -> Vec<u8> {
+    (0..=250).cycle().take(len).collect()
+}
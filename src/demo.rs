@@ -0,0 +1,153 @@
+//! A throwaway server for trying Schlep out, which needs no configuration at
+//! all: its files are kept in memory, its host key and the key of its only
+//! user are generated when it starts, and it listens on a port the OS picks on
+//! the loopback interface. Nothing it does outlives it.
+//!
+//! `schlep demo` runs one and prints how to connect to it. Tests can start one
+//! with [`Demo::start`] and connect to it the same way.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use camino::Utf8PathBuf;
+use rand::rngs::OsRng;
+use russh::keys::ssh_key::{Algorithm, LineEnding, PrivateKey, PublicKey};
+use serde_json::json;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+use crate::{
+    auth::AuthClient,
+    checksums::ChecksumCache,
+    sftp::{self, SshServer},
+    state::StateStore,
+    transfers::{self, Transfers},
+    vfs::{self, LiveVfsSet, MountOptions, VfsSetBuilder},
+};
+
+/// The name of the demo user.
+pub const USERNAME: &str = "demo";
+
+/// A running demo server, see the [module documentation](self).
+pub struct Demo {
+    server: SshServer,
+    listener: TcpListener,
+    host_key: PublicKey,
+    /// Holds the demo user's private key and a `known_hosts` file with the
+    /// host key in it, for clients to use. It's removed along with the demo.
+    dir: TempDir,
+}
+
+impl Demo {
+    /// Sets up a demo server and starts listening, so that clients can connect
+    /// as soon as it's [run](Self::run).
+    pub async fn start() -> Result<Self> {
+        let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+        let user_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+
+        let auth_config = serde_json::from_value(json!({
+            "users": [{
+                "username": USERNAME,
+                "public_keys": [user_key.public_key().to_openssh()?],
+            }],
+        }))?;
+        let sftp_config: sftp::Config = serde_json::from_value(json!({
+            "address": [Ipv4Addr::LOCALHOST],
+            "port": 0,
+        }))?;
+
+        let vfs_set = VfsSetBuilder::new()
+            .memory(Utf8PathBuf::from("/"), MountOptions::default())?
+            .build();
+        let transfers = Transfers::new(&transfers::Config::default(), StateStore::new(None));
+        let auth_client = AuthClient::new(auth_config, None)?;
+
+        let mut server = SshServer::new(
+            sftp_config,
+            auth_client,
+            LiveVfsSet::new(vfs_set, vfs::Config::default()),
+            &transfers,
+            ChecksumCache::new(None),
+            None,
+        );
+        server.add_host_key(host_key.clone());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+
+        let dir = tempfile::Builder::new().prefix("schlep-demo-").tempdir()?;
+        user_key.write_openssh_file(&dir.path().join("id_ed25519"), LineEnding::LF)?;
+        std::fs::write(
+            dir.path().join("known_hosts"),
+            format!(
+                "[{}]:{} {}\n",
+                addr.ip(),
+                addr.port(),
+                host_key.public_key().to_openssh()?
+            ),
+        )?;
+
+        Ok(Self {
+            server,
+            listener,
+            host_key: host_key.public_key().clone(),
+            dir,
+        })
+    }
+
+    /// The address the demo is listening on.
+    pub fn addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The public host key the demo identifies itself with.
+    #[must_use]
+    pub fn host_key(&self) -> &PublicKey {
+        &self.host_key
+    }
+
+    /// The file with the private key of the demo user, in OpenSSH format.
+    #[must_use]
+    pub fn private_key_file(&self) -> PathBuf {
+        self.dir.path().join("id_ed25519")
+    }
+
+    /// A `known_hosts` file that vouches for the demo's host key.
+    #[must_use]
+    pub fn known_hosts_file(&self) -> PathBuf {
+        self.dir.path().join("known_hosts")
+    }
+
+    /// How to connect to the demo, for people.
+    pub fn instructions(&self) -> io::Result<String> {
+        let addr = self.addr()?;
+
+        Ok(format!(
+            "\
+A Schlep demo server is listening on {addr}. Connect to it with:
+
+    sftp -P {port} -i {private_key_file} -o UserKnownHostsFile={known_hosts_file} {USERNAME}@{ip}
+
+or check how it holds up against SFTP clients' expectations with:
+
+    schlep conformance sftp://{USERNAME}@{addr}/ {private_key_file} '{host_key}'
+
+Files are kept in memory and are gone once the demo stops.
+",
+            port = addr.port(),
+            ip = addr.ip(),
+            private_key_file = self.private_key_file().display(),
+            known_hosts_file = self.known_hosts_file().display(),
+            host_key = self.host_key.to_openssh().map_err(io::Error::other)?,
+        ))
+    }
+
+    /// Serves clients until the server fails.
+    pub async fn run(mut self) -> io::Result<()> {
+        self.server.run_on_listener(&self.listener).await
+    }
+}
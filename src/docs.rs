@@ -0,0 +1,502 @@
+//! The configuration reference, rendered from the same JSON schema that
+//! `schlep-schema` prints. Since the schema is derived from the config structs
+//! themselves, the reference can't drift from what the server actually
+//! accepts: descriptions come from the doc comments, and defaults, examples,
+//! and deprecations from the serde and schemars attributes.
+
+use std::{
+    fmt::{self, Write as _},
+    str::FromStr,
+};
+
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use serde_json::Value;
+
+/// The heading of the section for the top level of the configuration file.
+const ROOT: &str = "schlep.toml";
+
+const TITLE: &str = "Configuration reference";
+
+const COLUMNS: [&str; 5] = ["Key", "Type", "Required", "Default", "Description"];
+
+/// The formats the reference can be rendered in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" => Ok(Self::Html),
+            _ => Err(format!("unknown docs format `{s}`")),
+        }
+    }
+}
+
+/// Renders the reference for the configuration described by `schema`, with a
+/// section for the top level followed by one for each definition.
+#[must_use]
+pub fn render(schema: &RootSchema, format: Format) -> String {
+    let mut sections = vec![Section::new(ROOT, &schema.schema)];
+    sections.extend(
+        schema
+            .definitions
+            .iter()
+            .filter_map(|(name, schema)| Some(Section::new(name, as_object(schema)?))),
+    );
+
+    let mut out = String::new();
+
+    // Writing to a `String` can't fail.
+    let _ = match format {
+        Format::Markdown => write_markdown(&mut out, &sections),
+        Format::Html => write_html(&mut out, &sections),
+    };
+
+    out
+}
+
+fn write_markdown(out: &mut String, sections: &[Section<'_>]) -> fmt::Result {
+    let link = |name: &str| format!("[`{name}`](#{})", anchor(name));
+
+    writeln!(out, "# {TITLE}")?;
+
+    for section in sections {
+        writeln!(out, "\n## {}\n", section.name)?;
+
+        if let Some(description) = section.description {
+            writeln!(out, "{description}\n")?;
+        }
+
+        if let Some(ty) = &section.ty {
+            writeln!(out, "Type: {}\n", ty.render(&link))?;
+        }
+
+        if !section.fields.is_empty() {
+            writeln!(out, "| {} |", COLUMNS.join(" | "))?;
+            writeln!(out, "|{}", " --- |".repeat(COLUMNS.len()))?;
+
+            for field in &section.fields {
+                let mut description = String::new();
+
+                if field.deprecated {
+                    description.push_str("**Deprecated.** ");
+                }
+                description.push_str(field.description.unwrap_or_default());
+                for example in field.examples {
+                    write!(description, "<br>Example: `{example}`")?;
+                }
+
+                writeln!(
+                    out,
+                    "| `{}` | {} | {} | {} | {} |",
+                    field.name,
+                    field.ty.render(&link),
+                    field.presence,
+                    field
+                        .default
+                        .map(|default| format!("`{default}`"))
+                        .unwrap_or_default(),
+                    markdown_cell(&description),
+                )?;
+            }
+
+            writeln!(out)?;
+        }
+
+        for variant in &section.variants {
+            match variant.description {
+                Some(description) => writeln!(out, "- `{}`: {description}", variant.value)?,
+                None => writeln!(out, "- `{}`", variant.value)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn write_html(out: &mut String, sections: &[Section<'_>]) -> fmt::Result {
+    let link = |name: &str| {
+        format!(
+            "<a href=\"#{}\"><code>{}</code></a>",
+            anchor(name),
+            html_escape(name)
+        )
+    };
+
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>")?;
+    writeln!(
+        out,
+        "<head><meta charset=\"utf-8\"><title>{TITLE}</title></head>"
+    )?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>{TITLE}</h1>")?;
+
+    for section in sections {
+        writeln!(out, "<section id=\"{}\">", anchor(section.name))?;
+        writeln!(out, "<h2>{}</h2>", html_escape(section.name))?;
+
+        if let Some(description) = section.description {
+            writeln!(out, "<p>{}</p>", html_escape(description))?;
+        }
+
+        if let Some(ty) = &section.ty {
+            writeln!(out, "<p>Type: {}</p>", ty.render(&link))?;
+        }
+
+        if !section.fields.is_empty() {
+            writeln!(out, "<table>")?;
+            writeln!(
+                out,
+                "<tr>{}</tr>",
+                COLUMNS.map(|column| format!("<th>{column}</th>")).concat()
+            )?;
+
+            for field in &section.fields {
+                write!(
+                    out,
+                    "<tr><td><code>{}</code></td><td>{}</td><td>{}</td><td>",
+                    html_escape(field.name),
+                    field.ty.render(&link),
+                    field.presence,
+                )?;
+                if let Some(default) = field.default {
+                    write!(out, "<code>{}</code>", html_escape(&default.to_string()))?;
+                }
+                write!(out, "</td><td>")?;
+                if field.deprecated {
+                    write!(out, "<strong>Deprecated.</strong> ")?;
+                }
+                write!(
+                    out,
+                    "{}",
+                    html_escape(field.description.unwrap_or_default())
+                )?;
+                for example in field.examples {
+                    write!(
+                        out,
+                        "<br>Example: <code>{}</code>",
+                        html_escape(&example.to_string())
+                    )?;
+                }
+                writeln!(out, "</td></tr>")?;
+            }
+
+            writeln!(out, "</table>")?;
+        }
+
+        if !section.variants.is_empty() {
+            writeln!(out, "<ul>")?;
+
+            for variant in &section.variants {
+                write!(
+                    out,
+                    "<li><code>{}</code>",
+                    html_escape(&variant.value.to_string())
+                )?;
+                if let Some(description) = variant.description {
+                    write!(out, ": {}", html_escape(description))?;
+                }
+                writeln!(out, "</li>")?;
+            }
+
+            writeln!(out, "</ul>")?;
+        }
+
+        writeln!(out, "</section>")?;
+    }
+
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")
+}
+
+/// A section of the reference, for either the top level of the file or one
+/// of the definitions in the schema.
+struct Section<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    /// The type of a definition that isn't an object or an enum.
+    ty: Option<Type>,
+    fields: Vec<Field<'a>>,
+    variants: Vec<Variant<'a>>,
+}
+
+impl<'a> Section<'a> {
+    fn new(name: &'a str, schema: &'a SchemaObject) -> Self {
+        let mut section = Self {
+            name,
+            description: description(schema),
+            ty: None,
+            fields: Vec::new(),
+            variants: Vec::new(),
+        };
+
+        section.collect_fields(schema, false);
+        section.collect_variants(schema);
+
+        if section.fields.is_empty() && section.variants.is_empty() {
+            section.ty = Some(Type::of(schema));
+        }
+
+        section
+    }
+
+    /// Collects the properties of `schema`, including the ones flattened into
+    /// it. Properties of one of several alternatives, like the backend of a
+    /// mount, are marked as such rather than as required.
+    fn collect_fields(&mut self, schema: &'a SchemaObject, alternative: bool) {
+        if let Some(object) = &schema.object {
+            for (name, property) in &object.properties {
+                let Some(property) = as_object(property) else {
+                    continue;
+                };
+                let metadata = property.metadata.as_deref();
+
+                let presence = if alternative {
+                    Presence::OneOf
+                } else if object.required.contains(name) {
+                    Presence::Required
+                } else {
+                    Presence::Optional
+                };
+
+                self.fields.push(Field {
+                    name,
+                    ty: Type::of(property),
+                    presence,
+                    default: metadata.and_then(|metadata| metadata.default.as_ref()),
+                    description: description(property),
+                    examples: metadata.map_or(&[][..], |metadata| metadata.examples.as_slice()),
+                    deprecated: metadata.is_some_and(|metadata| metadata.deprecated),
+                });
+            }
+        }
+
+        if let Some(subschemas) = &schema.subschemas {
+            for subschema in subschemas.all_of.iter().flatten().filter_map(as_object) {
+                self.collect_fields(subschema, alternative);
+            }
+
+            for subschema in subschemas
+                .any_of
+                .iter()
+                .chain(&subschemas.one_of)
+                .flatten()
+                .filter_map(as_object)
+                .filter(|subschema| subschema.object.is_some())
+            {
+                self.collect_fields(subschema, true);
+            }
+        }
+    }
+
+    /// Collects the values of an enum, with the descriptions of the variants
+    /// if they have any.
+    fn collect_variants(&mut self, schema: &'a SchemaObject) {
+        for value in schema.enum_values.iter().flatten() {
+            self.variants.push(Variant {
+                value,
+                description: None,
+            });
+        }
+
+        let one_of = schema
+            .subschemas
+            .as_ref()
+            .and_then(|subschemas| subschemas.one_of.as_ref());
+
+        for variant in one_of.into_iter().flatten().filter_map(as_object) {
+            for value in variant
+                .enum_values
+                .iter()
+                .flatten()
+                .chain(&variant.const_value)
+            {
+                self.variants.push(Variant {
+                    value,
+                    description: description(variant),
+                });
+            }
+        }
+    }
+}
+
+struct Field<'a> {
+    name: &'a str,
+    ty: Type,
+    presence: Presence,
+    default: Option<&'a Value>,
+    description: Option<&'a str>,
+    examples: &'a [Value],
+    deprecated: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Presence {
+    Required,
+    Optional,
+    /// Exactly one of the fields marked like this has to be present.
+    OneOf,
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Required => "yes",
+            Self::Optional => "no",
+            Self::OneOf => "one of",
+        })
+    }
+}
+
+struct Variant<'a> {
+    value: &'a Value,
+    description: Option<&'a str>,
+}
+
+/// A human-readable version of the type a schema describes.
+enum Type {
+    Plain(&'static str),
+    Ref(String),
+    Array(Box<Type>),
+    Map(Box<Type>),
+    Optional(Box<Type>),
+    Union(Vec<Type>),
+    Any,
+}
+
+impl Type {
+    fn of(schema: &SchemaObject) -> Self {
+        if let Some(reference) = &schema.reference {
+            return Self::Ref(reference.trim_start_matches("#/definitions/").to_string());
+        }
+
+        if let Some(subschemas) = &schema.subschemas {
+            if let Some([single]) = subschemas.all_of.as_deref() {
+                return as_object(single).map_or(Self::Any, Self::of);
+            }
+
+            if let Some(alternatives) = subschemas.any_of.as_ref().or(subschemas.one_of.as_ref()) {
+                return Self::union(
+                    alternatives
+                        .iter()
+                        .filter_map(as_object)
+                        .map(Self::of)
+                        .collect(),
+                );
+            }
+        }
+
+        match &schema.instance_type {
+            Some(SingleOrVec::Single(ty)) => Self::instance(schema, **ty),
+            Some(SingleOrVec::Vec(types)) => {
+                Self::union(types.iter().map(|ty| Self::instance(schema, *ty)).collect())
+            }
+            None => Self::Any,
+        }
+    }
+
+    fn instance(schema: &SchemaObject, ty: InstanceType) -> Self {
+        match ty {
+            InstanceType::Null => Self::Plain("null"),
+            InstanceType::Boolean => Self::Plain("boolean"),
+            InstanceType::Number => Self::Plain("number"),
+            InstanceType::Integer => Self::Plain("integer"),
+            InstanceType::String => Self::Plain("string"),
+            InstanceType::Array => {
+                let items = match schema.array.as_ref().and_then(|array| array.items.as_ref()) {
+                    Some(SingleOrVec::Single(items)) => {
+                        as_object(items).map_or(Self::Any, Self::of)
+                    }
+                    _ => Self::Any,
+                };
+
+                Self::Array(Box::new(items))
+            }
+            InstanceType::Object => {
+                let values = schema
+                    .object
+                    .as_ref()
+                    .and_then(|object| object.additional_properties.as_deref())
+                    .and_then(as_object);
+
+                match values {
+                    Some(values) => Self::Map(Box::new(Self::of(values))),
+                    None => Self::Plain("object"),
+                }
+            }
+        }
+    }
+
+    /// Folds `null` in a list of alternatives into an optional type.
+    fn union(mut types: Vec<Self>) -> Self {
+        let nullable = types.iter().any(|ty| matches!(ty, Self::Plain("null")));
+        types.retain(|ty| !matches!(ty, Self::Plain("null")));
+
+        let ty = if types.len() == 1 {
+            types.remove(0)
+        } else {
+            Self::Union(types)
+        };
+
+        if nullable {
+            Self::Optional(Box::new(ty))
+        } else {
+            ty
+        }
+    }
+
+    /// Renders the type, using `link` to render references to definitions.
+    fn render(&self, link: &impl Fn(&str) -> String) -> String {
+        match self {
+            Self::Plain(name) => (*name).to_string(),
+            Self::Ref(name) => link(name),
+            Self::Array(items) => format!("array of {}", items.render(link)),
+            Self::Map(values) => format!("map of {}", values.render(link)),
+            Self::Optional(ty) => format!("{} (optional)", ty.render(link)),
+            Self::Union(types) => types
+                .iter()
+                .map(|ty| ty.render(link))
+                .collect::<Vec<_>>()
+                .join(" or "),
+            Self::Any => "any".to_string(),
+        }
+    }
+}
+
+fn as_object(schema: &Schema) -> Option<&SchemaObject> {
+    match schema {
+        Schema::Object(object) => Some(object),
+        Schema::Bool(_) => None,
+    }
+}
+
+fn description(schema: &SchemaObject) -> Option<&str> {
+    schema.metadata.as_ref()?.description.as_deref()
+}
+
+/// The anchor for a section, which is what GitHub generates for a heading
+/// with the section's name.
+fn anchor(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', "<br>")
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,154 @@
+//! Stable, machine-readable codes for the errors Schlep reports.
+//!
+//! Error messages are meant for people and can change between releases, so
+//! every error that leaves the server also carries an [`ErrorCode`]: at the
+//! end of SFTP status messages, in audit events, in admin command replies, and
+//! as a metrics label. Integrators should branch on the code rather than on the
+//! message.
+
+use std::fmt;
+
+use russh_sftp::protocol::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// A stable code for a kind of error.
+///
+/// Codes are only ever added, never renamed or removed, so they're safe to
+/// match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The request was malformed or had the wrong arguments.
+    BadRequest,
+    /// The operation isn't supported here.
+    Unsupported,
+    /// The operation failed for a reason that has no more specific code.
+    Failure,
+    /// The operation didn't finish in time.
+    Timeout,
+    /// The operation was abandoned before it finished.
+    Cancelled,
+    /// Something went wrong inside the server.
+    Internal,
+    /// Reading from or writing to the underlying storage or connection
+    /// failed.
+    Io,
+    /// The path isn't valid.
+    InvalidPath,
+    /// The file or directory doesn't exist.
+    NotFound,
+    /// The path is a directory where a file was expected.
+    NotAFile,
+    /// The path is a file where a directory was expected.
+    NotADirectory,
+    /// The client isn't allowed to do this.
+    PermissionDenied,
+    /// The path leads outside of the mount it's in.
+    OutsideRoot,
+    /// The file is already open for writing, or locked, by someone else.
+    Locked,
+    /// The file was changed since the client last looked at it.
+    Conflict,
+    /// The mount is read-only.
+    ReadOnly,
+    /// The file is under legal hold.
+    LegalHold,
+    /// The file is write-once and has already been written.
+    Immutable,
+    /// The file is still within its retention period.
+    Retained,
+    /// The user directory couldn't be reached.
+    DirectoryUnavailable,
+    /// The cache server couldn't be reached.
+    CacheUnavailable,
+    /// More than one user matched the username.
+    AmbiguousUser,
+    /// An SSH key couldn't be parsed.
+    InvalidKey,
+    /// The SSH connection failed.
+    Ssh,
+    /// The SSH channel went away.
+    ChannelLost,
+    /// A remote server or store that files are pushed to couldn't be reached,
+    /// or refused a file.
+    DestinationUnavailable,
+    /// A link or other credential was valid once, but has run out.
+    Expired,
+    /// An uploaded file didn't match the checksum its client declared for it.
+    ChecksumMismatch,
+    /// There isn't enough free space left to store the file.
+    NoSpace,
+    /// The client, or everyone together, did too much of this recently. It may
+    /// work if tried again later.
+    Throttled,
+    /// The file would be bigger than it's allowed to be.
+    TooLarge,
+    /// An uploaded file was found to be infected, and was quarantined.
+    Infected,
+    /// The session already has as many files and directories open as it may.
+    TooManyHandles,
+}
+
+impl ErrorCode {
+    /// The code as it appears in messages, logs, and metrics labels.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::BadRequest => "bad_request",
+            Self::Unsupported => "unsupported",
+            Self::Failure => "failure",
+            Self::Timeout => "timeout",
+            Self::Cancelled => "cancelled",
+            Self::Internal => "internal",
+            Self::Io => "io",
+            Self::InvalidPath => "invalid_path",
+            Self::NotFound => "not_found",
+            Self::NotAFile => "not_a_file",
+            Self::NotADirectory => "not_a_directory",
+            Self::PermissionDenied => "permission_denied",
+            Self::OutsideRoot => "outside_root",
+            Self::Locked => "locked",
+            Self::Conflict => "conflict",
+            Self::ReadOnly => "read_only",
+            Self::LegalHold => "legal_hold",
+            Self::Immutable => "immutable",
+            Self::Retained => "retained",
+            Self::DirectoryUnavailable => "directory_unavailable",
+            Self::CacheUnavailable => "cache_unavailable",
+            Self::AmbiguousUser => "ambiguous_user",
+            Self::InvalidKey => "invalid_key",
+            Self::Ssh => "ssh",
+            Self::ChannelLost => "channel_lost",
+            Self::DestinationUnavailable => "destination_unavailable",
+            Self::Expired => "expired",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::NoSpace => "no_space",
+            Self::Throttled => "throttled",
+            Self::TooLarge => "too_large",
+            Self::Infected => "infected",
+            Self::TooManyHandles => "too_many_handles",
+        }
+    }
+
+    /// The code for a failure that is only known by its SFTP status, or
+    /// `None` if the status doesn't mean that anything failed.
+    #[must_use]
+    pub const fn for_status(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::Ok | StatusCode::Eof => None,
+            StatusCode::NoSuchFile => Some(Self::NotFound),
+            StatusCode::PermissionDenied => Some(Self::PermissionDenied),
+            StatusCode::BadMessage => Some(Self::BadRequest),
+            StatusCode::NoConnection | StatusCode::ConnectionLost => Some(Self::Ssh),
+            StatusCode::OpUnsupported => Some(Self::Unsupported),
+            StatusCode::Failure => Some(Self::Failure),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
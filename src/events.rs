@@ -0,0 +1,571 @@
+//! Publishes what clients do — logging in and out, uploading, removing, and
+//! renaming files — to NATS or Kafka as JSON, so that other systems can react
+//! to it without tailing the logs.
+//!
+//! Publishing is set up once for the whole process with [`Config::init`], and
+//! after that anything can call [`publish`]. Events are queued in memory and
+//! sent in the background. Delivery is at least once: events only leave the
+//! queue once the bus has acknowledged them, and are sent again after a
+//! failure, so consumers should use the `id` of each event to drop the
+//! duplicates. While the bus can't be reached, up to `buffer_size` events wait
+//! for it, and the oldest are dropped beyond that.
+
+use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use camino::Utf8PathBuf;
+use humantime_serde::re::humantime;
+use metrics::counter;
+use parking_lot::Mutex;
+use rskafka::{
+    client::{
+        ClientBuilder,
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+    },
+    record::Record,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror_ext::AsReport;
+use tokio::sync::{Notify, broadcast::error::RecvError};
+use tracing::{Level, event};
+
+use crate::{
+    error_code::ErrorCode,
+    metrics::Metrics,
+    sftp::{LifecycleHooks, SessionInfo},
+    transfers::{self, Direction, Transfers},
+};
+
+/// How many events are sent to the bus at once, at most.
+const BATCH_SIZE: usize = 100;
+
+static PUBLISHER: OnceLock<Publisher> = OnceLock::new();
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "events_config")]
+pub struct Config {
+    /// The message bus to publish events to. Without one, nothing is
+    /// published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bus: Option<BusConfig>,
+
+    /// How many events to hold on to while they can't be published. Once
+    /// there are more, the oldest are dropped. The default value is 10000.
+    #[serde(
+        default = "Config::default_buffer_size",
+        skip_serializing_if = "Config::is_default_buffer_size"
+    )]
+    pub buffer_size: usize,
+
+    /// How long to wait before trying again after publishing failed. The
+    /// default value is 5 seconds.
+    #[serde(
+        default = "Config::default_retry_delay",
+        skip_serializing_if = "Config::is_default_retry_delay",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub retry_delay: Duration,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "event_bus_config", tag = "type", rename_all = "snake_case")]
+pub enum BusConfig {
+    /// A NATS server with JetStream. Each event is published to the subject
+    /// `{subject_prefix}.{type}`, like `schlep.file_uploaded`, which a stream
+    /// has to be set up for, since events only count as delivered once a
+    /// stream has stored them.
+    Nats {
+        #[schemars(example = "BusConfig::example_nats_url")]
+        url: String,
+
+        /// The default value is `schlep`.
+        #[serde(
+            default = "BusConfig::default_subject_prefix",
+            skip_serializing_if = "BusConfig::is_default_subject_prefix"
+        )]
+        subject_prefix: String,
+
+        /// A NATS credentials file to connect with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credentials_file: Option<PathBuf>,
+    },
+    /// A Kafka cluster. Events are keyed by the user they're about, and every
+    /// user's events go to the same partition, so that they stay in order.
+    Kafka {
+        /// The brokers to find the cluster through, as `host:port`.
+        #[schemars(example = "BusConfig::example_kafka_brokers")]
+        brokers: Vec<String>,
+
+        topic: String,
+    },
+}
+
+impl Config {
+    fn default_buffer_size() -> usize {
+        10_000
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_buffer_size(size: &usize) -> bool {
+        *size == Self::default_buffer_size()
+    }
+
+    fn default_retry_delay() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn is_default_retry_delay(delay: &Duration) -> bool {
+        *delay == Self::default_retry_delay()
+    }
+
+    /// Starts publishing events for the whole process if a bus is configured.
+    /// Uploads are watched for in `transfers`.
+    pub fn init(&self, transfers: &Transfers) {
+        let Some(bus) = &self.bus else {
+            return;
+        };
+
+        let publisher = Publisher {
+            queue: Mutex::new(VecDeque::new()),
+            queued: Notify::new(),
+            next_seq: AtomicU64::new(1),
+            buffer_size: self.buffer_size.max(1),
+            dropping: AtomicBool::new(false),
+        };
+
+        if PUBLISHER.set(publisher).is_err() {
+            event!(Level::WARN, "event publishing was already initialized");
+            return;
+        }
+
+        tokio::spawn(deliver(bus.clone(), self.retry_delay));
+        tokio::spawn(watch_uploads(transfers.clone()));
+    }
+
+    /// Whether events are published at all.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.bus.is_some()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bus: None,
+            buffer_size: Self::default_buffer_size(),
+            retry_delay: Self::default_retry_delay(),
+        }
+    }
+}
+
+impl BusConfig {
+    fn example_nats_url() -> &'static str {
+        "nats://nats.example.com:4222"
+    }
+
+    fn example_kafka_brokers() -> Vec<&'static str> {
+        vec!["kafka-1.example.com:9092", "kafka-2.example.com:9092"]
+    }
+
+    fn default_subject_prefix() -> String {
+        "schlep".to_owned()
+    }
+
+    fn is_default_subject_prefix(prefix: &String) -> bool {
+        *prefix == Self::default_subject_prefix()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("NATS error")]
+    NatsError(#[from] async_nats::Error),
+    #[error("Kafka error")]
+    KafkaError(#[from] rskafka::client::error::Error),
+    #[error("Kafka topic {0} doesn't exist")]
+    NoSuchTopic(String),
+    #[error("invalid event")]
+    JsonError(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NatsError(_) | Self::KafkaError(_) => ErrorCode::DestinationUnavailable,
+            Self::NoSuchTopic(_) => ErrorCode::NotFound,
+            Self::JsonError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// Something a client did, as it's published.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event {
+    /// A client logged in.
+    SessionStarted {
+        session_id: u64,
+        username: String,
+        peer_addr: Option<SocketAddr>,
+    },
+    /// A client that had logged in disconnected.
+    SessionEnded { session_id: u64, username: String },
+    /// A client finished uploading a file.
+    FileUploaded {
+        username: String,
+        /// The path of the file in the virtual hierarchy.
+        path: Utf8PathBuf,
+        size: u64,
+        /// The hex-encoded SHA-256 of the file, if it was written in order.
+        checksum: Option<String>,
+    },
+    /// A client removed a file.
+    FileDeleted { username: String, path: Utf8PathBuf },
+    /// A client renamed a file or directory.
+    Renamed {
+        username: String,
+        from: Utf8PathBuf,
+        to: Utf8PathBuf,
+    },
+}
+
+impl Event {
+    /// The `type` of the event, as it's serialized.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::SessionStarted { .. } => "session_started",
+            Self::SessionEnded { .. } => "session_ended",
+            Self::FileUploaded { .. } => "file_uploaded",
+            Self::FileDeleted { .. } => "file_deleted",
+            Self::Renamed { .. } => "renamed",
+        }
+    }
+
+    fn username(&self) -> &str {
+        match self {
+            Self::SessionStarted { username, .. }
+            | Self::SessionEnded { username, .. }
+            | Self::FileUploaded { username, .. }
+            | Self::FileDeleted { username, .. }
+            | Self::Renamed { username, .. } => username,
+        }
+    }
+}
+
+/// An event as it's sent to the bus.
+#[derive(Clone, Debug, Serialize)]
+struct Envelope {
+    /// Identifies the event, so that consumers can tell when they've been sent
+    /// it more than once.
+    id: String,
+    timestamp: String,
+    #[serde(flatten)]
+    event: Event,
+    /// Where the event is in the queue, which only matters here.
+    #[serde(skip)]
+    seq: u64,
+}
+
+struct Publisher {
+    queue: Mutex<VecDeque<Envelope>>,
+    queued: Notify,
+    next_seq: AtomicU64,
+    buffer_size: usize,
+    /// Whether events are being dropped for lack of room, so that the warning
+    /// is only logged when that starts.
+    dropping: AtomicBool,
+}
+
+/// Queues `event` to be published, if publishing has been set up.
+pub fn publish(event: Event) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+
+    let envelope = Envelope {
+        id: format!("{:032x}", rand::random::<u128>()),
+        timestamp: humantime::format_rfc3339_micros(SystemTime::now()).to_string(),
+        event,
+        seq: publisher.next_seq.fetch_add(1, Ordering::Relaxed),
+    };
+
+    let dropped = {
+        let mut queue = publisher.queue.lock();
+        queue.push_back(envelope);
+
+        queue.len() > publisher.buffer_size && queue.pop_front().is_some()
+    };
+
+    if dropped {
+        counter!(Metrics::EVENTS_DROPPED).increment(1);
+
+        if !publisher.dropping.swap(true, Ordering::Relaxed) {
+            event!(
+                Level::WARN,
+                buffer_size = publisher.buffer_size,
+                "event buffer is full, dropping the oldest events"
+            );
+        }
+    }
+
+    publisher.queued.notify_one();
+}
+
+/// Publishes [`Event::SessionStarted`] and [`Event::SessionEnded`], when
+/// registered with an [`SshServer`](crate::sftp::SshServer).
+pub struct SessionEvents;
+
+#[async_trait]
+impl LifecycleHooks for SessionEvents {
+    async fn on_session_open(&self, session: &SessionInfo) -> anyhow::Result<()> {
+        publish(Event::SessionStarted {
+            session_id: session.id,
+            username: session.username.clone().unwrap_or_default(),
+            peer_addr: session.peer_addr,
+        });
+
+        Ok(())
+    }
+
+    async fn on_session_close(&self, session: &SessionInfo) {
+        publish(Event::SessionEnded {
+            session_id: session.id,
+            username: session.username.clone().unwrap_or_default(),
+        });
+    }
+}
+
+/// Publishes [`Event::FileUploaded`] for every upload that's recorded in
+/// `transfers`.
+async fn watch_uploads(transfers: Transfers) {
+    let mut finished = transfers.subscribe();
+
+    loop {
+        let record = match finished.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(skipped)) => {
+                event!(Level::WARN, skipped, "event publishing missed uploads");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if record.direction == Direction::Upload && record.status == transfers::Status::Completed {
+            publish(Event::FileUploaded {
+                username: record.username,
+                path: record.path,
+                size: record.size,
+                checksum: record.checksum,
+            });
+        }
+    }
+}
+
+/// Sends queued events to `bus` for as long as the process runs, connecting
+/// again whenever sending fails.
+async fn deliver(bus: BusConfig, retry_delay: Duration) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+
+    let mut connection = None;
+
+    loop {
+        let batch = loop {
+            let notified = publisher.queued.notified();
+            let batch = publisher
+                .queue
+                .lock()
+                .iter()
+                .take(BATCH_SIZE)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if !batch.is_empty() {
+                break batch;
+            }
+
+            notified.await;
+        };
+
+        let result = async {
+            let bus = match &mut connection {
+                Some(bus) => bus,
+                None => connection.insert(Bus::connect(&bus).await?),
+            };
+
+            bus.send(&batch).await
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                // Events that were dropped for room while the batch was being
+                // sent have already left the front of the queue.
+                let last = batch.last().map_or(0, |envelope| envelope.seq);
+                let mut queue = publisher.queue.lock();
+                while queue.front().is_some_and(|envelope| envelope.seq <= last) {
+                    queue.pop_front();
+                }
+                drop(queue);
+
+                counter!(Metrics::EVENTS_PUBLISHED).increment(batch.len() as u64);
+                publisher.dropping.store(false, Ordering::Relaxed);
+            }
+            Err(err) => {
+                event!(
+                    Level::WARN,
+                    err = %err.as_report(),
+                    code = %err.code(),
+                    queued = publisher.queue.lock().len(),
+                    "failed to publish events, retrying"
+                );
+
+                connection = None;
+                tokio::time::sleep(retry_delay).await;
+            }
+        }
+    }
+}
+
+/// A connection to the bus that events are published to.
+enum Bus {
+    Nats {
+        jetstream: async_nats::jetstream::Context,
+        subject_prefix: String,
+    },
+    Kafka {
+        partitions: Vec<PartitionClient>,
+    },
+}
+
+impl Bus {
+    async fn connect(config: &BusConfig) -> Result<Self, Error> {
+        match config {
+            BusConfig::Nats {
+                url,
+                subject_prefix,
+                credentials_file,
+            } => {
+                let options = match credentials_file {
+                    Some(credentials_file) => {
+                        async_nats::ConnectOptions::with_credentials_file(credentials_file)
+                            .await
+                            .map_err(|err| Error::NatsError(err.into()))?
+                    }
+                    None => async_nats::ConnectOptions::new(),
+                };
+                let client = options
+                    .connect(url.as_str())
+                    .await
+                    .map_err(|err| Error::NatsError(err.into()))?;
+
+                Ok(Self::Nats {
+                    jetstream: async_nats::jetstream::new(client),
+                    subject_prefix: subject_prefix.clone(),
+                })
+            }
+            BusConfig::Kafka { brokers, topic } => {
+                let client = ClientBuilder::new(brokers.clone()).build().await?;
+                let partition_ids = client
+                    .list_topics()
+                    .await?
+                    .into_iter()
+                    .find(|found| found.name == *topic)
+                    .ok_or_else(|| Error::NoSuchTopic(topic.clone()))?
+                    .partitions;
+
+                let mut partitions = Vec::with_capacity(partition_ids.len());
+                for partition in partition_ids {
+                    partitions.push(
+                        client
+                            .partition_client(topic.clone(), partition, UnknownTopicHandling::Error)
+                            .await?,
+                    );
+                }
+
+                if partitions.is_empty() {
+                    return Err(Error::NoSuchTopic(topic.clone()));
+                }
+
+                Ok(Self::Kafka { partitions })
+            }
+        }
+    }
+
+    /// Sends `batch`, only returning once the bus has acknowledged all of it.
+    async fn send(&self, batch: &[Envelope]) -> Result<(), Error> {
+        match self {
+            Self::Nats {
+                jetstream,
+                subject_prefix,
+            } => {
+                let mut acks = Vec::with_capacity(batch.len());
+
+                for envelope in batch {
+                    let subject = format!("{subject_prefix}.{}", envelope.event.kind());
+                    let payload = serde_json::to_vec(envelope)?;
+
+                    acks.push(
+                        jetstream
+                            .publish(subject, payload.into())
+                            .await
+                            .map_err(|err| Error::NatsError(err.into()))?,
+                    );
+                }
+
+                for ack in acks {
+                    ack.await.map_err(|err| Error::NatsError(err.into()))?;
+                }
+
+                Ok(())
+            }
+            Self::Kafka { partitions } => {
+                let mut records = vec![Vec::new(); partitions.len()];
+
+                for envelope in batch {
+                    let username = envelope.event.username();
+                    let mut hasher = DefaultHasher::new();
+                    username.hash(&mut hasher);
+                    let partition = usize::try_from(hasher.finish() % partitions.len() as u64)
+                        .unwrap_or_default();
+
+                    records[partition].push(Record {
+                        key: Some(username.as_bytes().to_vec()),
+                        value: Some(serde_json::to_vec(envelope)?),
+                        headers: [("type".to_owned(), envelope.event.kind().as_bytes().to_vec())]
+                            .into(),
+                        timestamp: chrono::Utc::now(),
+                    });
+                }
+
+                for (partition, records) in partitions.iter().zip(records) {
+                    if !records.is_empty() {
+                        partition
+                            .produce(records, Compression::NoCompression)
+                            .await?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
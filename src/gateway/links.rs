@@ -0,0 +1,139 @@
+use std::{
+    io,
+    time::{Duration, SystemTime},
+};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use camino::{Utf8Path, Utf8PathBuf};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{Level, event};
+use url::Url;
+
+use super::{Config, Error};
+use crate::transfers::Direction;
+
+type Key = Hmac<Sha256>;
+
+/// What a link lets whoever has it do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    /// A random identifier, so that transfers through the link can be told
+    /// apart in the transfer history.
+    pub id: String,
+    /// The path in the virtual hierarchy that the link is for.
+    pub path: Utf8PathBuf,
+    pub direction: Direction,
+    /// When the link stops working.
+    #[serde(with = "humantime_serde")]
+    pub expires_at: SystemTime,
+}
+
+impl Link {
+    /// The name that transfers through this link are recorded under.
+    #[must_use]
+    pub fn username(&self) -> String {
+        format!("link:{}", self.id)
+    }
+}
+
+/// Creates and checks links.
+#[derive(Clone)]
+pub struct Links {
+    key: Key,
+    public_url: Url,
+    max_lifetime: Duration,
+}
+
+impl Links {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let key = if let Some(key_file) = &config.key_file {
+            std::fs::read(key_file)?
+        } else {
+            event!(
+                Level::WARN,
+                "no gateway key file is configured, links will stop working on restart"
+            );
+            rand::random::<[u8; 32]>().to_vec()
+        };
+
+        let key = Key::new_from_slice(&key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+        Ok(Self {
+            key,
+            public_url: config.public_url.clone(),
+            max_lifetime: config.max_link_lifetime,
+        })
+    }
+
+    /// Creates a link for `direction` on the file at `path`, valid for
+    /// `lifetime`, and returns it along with its URL.
+    pub fn create(
+        &self,
+        path: &Utf8Path,
+        direction: Direction,
+        lifetime: Duration,
+    ) -> Result<(Link, Url), Error> {
+        if lifetime > self.max_lifetime {
+            return Err(Error::LifetimeTooLong {
+                max: self.max_lifetime,
+            });
+        }
+
+        let link = Link {
+            id: format!("{:016x}", rand::random::<u64>()),
+            path: path.to_owned(),
+            direction,
+            expires_at: SystemTime::now() + lifetime,
+        };
+
+        // A link is nothing but strings and numbers, which always serialize.
+        let payload = Base64UrlUnpadded::encode_string(&serde_json::to_vec(&link).unwrap());
+        let signature = Base64UrlUnpadded::encode_string(&self.sign(&payload));
+        let token = format!("{payload}.{signature}");
+
+        let mut url = self.public_url.clone();
+        url.path_segments_mut()
+            .map_err(|()| Error::InvalidLink)?
+            .pop_if_empty()
+            .extend(["links", token.as_str()]);
+
+        Ok((link, url))
+    }
+
+    /// Checks that `token`, the last part of a link's URL, was made by this
+    /// gateway for `direction` and hasn't expired, and returns the link.
+    pub fn verify(&self, token: &str, direction: Direction) -> Result<Link, Error> {
+        let Some((payload, signature)) = token.split_once('.') else {
+            return Err(Error::InvalidLink);
+        };
+
+        let signature = Base64UrlUnpadded::decode_vec(signature).map_err(|_| Error::InvalidLink)?;
+        let mut mac = self.key.clone();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| Error::InvalidLink)?;
+
+        let payload = Base64UrlUnpadded::decode_vec(payload).map_err(|_| Error::InvalidLink)?;
+        let link: Link = serde_json::from_slice(&payload).map_err(|_| Error::InvalidLink)?;
+
+        if link.direction != direction {
+            return Err(Error::InvalidLink);
+        }
+
+        if link.expires_at < SystemTime::now() {
+            return Err(Error::LinkExpired);
+        }
+
+        Ok(link)
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        let mut mac = self.key.clone();
+        mac.update(payload.as_bytes());
+
+        mac.finalize().into_bytes().to_vec()
+    }
+}
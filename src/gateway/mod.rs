@@ -0,0 +1,120 @@
+//! The HTTP gateway, which lets people without SSH credentials upload or
+//! download single files through signed, time-limited links.
+//!
+//! Links are created by administrators with the `link` admin command, and each
+//! one is good for either uploading to or downloading from one path in the
+//! virtual hierarchy until it expires. Everything needed to check a link is in
+//! the link itself, signed with the gateway's key, so nothing has to be stored
+//! for them. Transfers through the gateway are recorded in the transfer history
+//! like any other, so push jobs and anything else that watches for finished
+//! uploads sees them too.
+
+use std::{path::PathBuf, time::Duration};
+
+use camino::Utf8PathBuf;
+use humantime_serde::re::humantime;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{error_code::ErrorCode, vfs};
+
+mod links;
+mod server;
+
+pub use links::{Link, Links};
+pub use server::Gateway;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "gateway_config")]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+
+    /// The URL that the gateway can be reached at from outside, which links
+    /// are made relative to.
+    #[schemars(example = "Config::example_public_url")]
+    pub public_url: Url,
+
+    /// A file holding the key that links are signed with. Without one, a
+    /// random key is generated every time the server starts, so links stop
+    /// working on restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+
+    /// The longest a link can be valid for. The default value is 7 days.
+    #[serde(
+        default = "Config::default_max_link_lifetime",
+        skip_serializing_if = "Config::is_default_max_link_lifetime",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub max_link_lifetime: Duration,
+
+    /// How many bytes can be uploaded through a link at most. A mount's
+    /// `max_file_size` still applies if it's smaller. The default value is 1
+    /// GiB.
+    #[serde(
+        default = "Config::default_max_upload_size",
+        skip_serializing_if = "Config::is_default_max_upload_size"
+    )]
+    pub max_upload_size: u64,
+}
+
+impl Config {
+    fn example_public_url() -> &'static str {
+        "https://files.example.com/"
+    }
+
+    fn default_max_link_lifetime() -> Duration {
+        Duration::from_hours(168)
+    }
+
+    fn is_default_max_link_lifetime(lifetime: &Duration) -> bool {
+        *lifetime == Self::default_max_link_lifetime()
+    }
+
+    fn default_max_upload_size() -> u64 {
+        1024 * 1024 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_upload_size(size: &u64) -> bool {
+        *size == Self::default_max_upload_size()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid link")]
+    InvalidLink,
+    #[error("link has expired")]
+    LinkExpired,
+    #[error("links can be valid for at most {}", humantime::format_duration(*.max))]
+    LifetimeTooLong { max: Duration },
+    #[error("{path} isn't in any mount")]
+    NotMounted { path: Utf8PathBuf },
+    #[error("files can't be bigger than {max} bytes here")]
+    TooLarge { max: u64 },
+    #[error("VFS error")]
+    VfsError(#[from] vfs::Error),
+    #[error("failed to receive upload")]
+    BodyError(#[from] axum::Error),
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidLink => ErrorCode::PermissionDenied,
+            Self::LinkExpired => ErrorCode::Expired,
+            Self::LifetimeTooLong { .. } => ErrorCode::BadRequest,
+            Self::NotMounted { .. } => ErrorCode::NotFound,
+            Self::TooLarge { .. } => ErrorCode::TooLarge,
+            Self::VfsError(err) => err.code(),
+            Self::BodyError(_) => ErrorCode::Io,
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::sync::Arc;
+
+use axum::{
+    Router,
+    body::{Body, Bytes},
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing,
+};
+use camino::Utf8PathBuf;
+use futures_util::{StreamExt, stream};
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use thiserror_ext::AsReport;
+use tokio::net::TcpListener;
+use tracing::{Level, event};
+
+use super::{Config, Error, Link, Links};
+use crate::{
+    audit,
+    error_code::ErrorCode,
+    transfers::{self, Direction, Transfer, Transfers},
+    vfs::{self, Handle, LiveVfsSet, Mount, OpenFlags, PathMatch, Vfs},
+};
+
+/// How much of a file is sent at a time.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// The HTTP server that links point to.
+pub struct Gateway {
+    config: Config,
+    state: Arc<GatewayState>,
+}
+
+struct GatewayState {
+    links: Links,
+    vfs_set: LiveVfsSet,
+    transfers: Transfers,
+    max_upload_size: u64,
+}
+
+impl Gateway {
+    #[must_use]
+    pub fn new(config: Config, links: Links, vfs_set: LiveVfsSet, transfers: Transfers) -> Self {
+        Self {
+            state: Arc::new(GatewayState {
+                links,
+                vfs_set,
+                transfers,
+                max_upload_size: config.max_upload_size,
+            }),
+            config,
+        }
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let app = Router::new()
+            .route(
+                "/links/{token}",
+                routing::get(download_handler).put(upload_handler),
+            )
+            .with_state(self.state.clone());
+
+        let listener = TcpListener::bind((self.config.address.clone(), self.config.port)).await?;
+        axum::serve(listener, app).await?;
+
+        Ok(())
+    }
+}
+
+impl GatewayState {
+    /// Checks the link in `token`, and finds the mount that its path is in.
+    fn open_link(
+        &self,
+        token: &str,
+        direction: Direction,
+    ) -> Result<(Link, Arc<Mount>, Utf8PathBuf), Error> {
+        let link = match self.links.verify(token, direction) {
+            Ok(link) => link,
+            Err(err) => {
+                event!(
+                    target: audit::TARGET,
+                    Level::WARN,
+                    %direction,
+                    err = %err.as_report(),
+                    "link refused"
+                );
+
+                return Err(err);
+            }
+        };
+
+        let Some(PathMatch { vfs, relative_path }) = self.vfs_set.resolve_path(&link.path) else {
+            return Err(Error::NotMounted { path: link.path });
+        };
+
+        Ok((link, vfs, relative_path))
+    }
+
+    /// How many bytes can be uploaded to a file in `mount` at most.
+    fn max_upload_size(&self, mount: &Mount) -> u64 {
+        mount
+            .options()
+            .max_file_size
+            .map_or(self.max_upload_size, |max| max.min(self.max_upload_size))
+    }
+}
+
+async fn download_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(token): Path<String>,
+) -> Result<Response, Error> {
+    let (link, mount, path) = state.open_link(&token, Direction::Download)?;
+
+    let metadata = mount.stat(&path).await?;
+    if metadata.is_directory() {
+        return Err(vfs::Error::NotAFile.into());
+    }
+
+    let handle = mount.open(&path, OpenFlags::READ).await?;
+    let file = LinkedFile::new(&link, mount, handle, state.transfers.clone());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    if let Some(size) = metadata.size() {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    if let Some(disposition) = link
+        .path
+        .file_name()
+        .and_then(|name| HeaderValue::from_str(&format!("attachment; filename=\"{name}\"")).ok())
+    {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    let body = Body::from_stream(stream::try_unfold(file, LinkedFile::next_chunk));
+
+    Ok((headers, body).into_response())
+}
+
+async fn upload_handler(
+    State(state): State<Arc<GatewayState>>,
+    Path(token): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, Error> {
+    let (link, mount, path) = state.open_link(&token, Direction::Upload)?;
+
+    if let Err(err) = mount.check_file_name(&path) {
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            username = link.username(),
+            path = %link.path,
+            "file name refused"
+        );
+
+        return Err(err.into());
+    }
+
+    // Uploads that say up front they're too big are refused before anything
+    // is written, and the rest once they get there.
+    let max_size = state.max_upload_size(&mount);
+    let announced_size = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if announced_size.is_some_and(|size| size > max_size) {
+        return Err(Error::TooLarge { max: max_size });
+    }
+
+    let handle = mount
+        .open(
+            &path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        )
+        .await?;
+    let mut file = LinkedFile::new(&link, mount, handle, state.transfers.clone());
+
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if file.offset + chunk.len() as u64 > max_size {
+            return Err(Error::TooLarge { max: max_size });
+        }
+
+        file.write(&chunk).await?;
+    }
+    file.finish().await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// A file opened through a link. If it's dropped before it's finished, like
+/// when the other end goes away halfway through, it's aborted in the
+/// background, which throws away an upload the mount was holding back, and the
+/// transfer is recorded as interrupted.
+struct LinkedFile {
+    mount: Arc<Mount>,
+    handle: Option<Handle>,
+    transfer: Option<Transfer>,
+    transfers: Transfers,
+    offset: u64,
+}
+
+impl LinkedFile {
+    fn new(link: &Link, mount: Arc<Mount>, handle: Handle, transfers: Transfers) -> Self {
+        Self {
+            mount,
+            handle: Some(handle),
+            transfer: Some(Transfer::start(
+                &link.username(),
+                &link.path,
+                link.direction,
+            )),
+            transfers,
+            offset: 0,
+        }
+    }
+
+    fn handle(&self) -> Result<&Handle, Error> {
+        self.handle
+            .as_ref()
+            .ok_or(Error::VfsError(vfs::Error::Cancelled))
+    }
+
+    async fn next_chunk(mut self) -> Result<Option<(Bytes, Self)>, Error> {
+        let chunk = self
+            .mount
+            .read(self.handle()?, self.offset, CHUNK_SIZE)
+            .await?;
+
+        if let Some(chunk) = chunk {
+            self.transferred(&chunk);
+            self.mount.transferred(chunk.len() as u64).await;
+            Ok(Some((Bytes::from(chunk), self)))
+        } else {
+            self.finish().await?;
+            Ok(None)
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.mount.write(self.handle()?, self.offset, data).await?;
+        self.transferred(data);
+        self.mount.transferred(data.len() as u64).await;
+
+        Ok(())
+    }
+
+    fn transferred(&mut self, data: &[u8]) {
+        if let Some(transfer) = &mut self.transfer {
+            transfer.transferred(self.offset, data);
+        }
+        self.offset += data.len() as u64;
+    }
+
+    /// Closes the file, and records the transfer as completed if that worked.
+    async fn finish(mut self) -> Result<(), Error> {
+        let (Some(handle), Some(transfer)) = (self.handle.take(), self.transfer.take()) else {
+            return Ok(());
+        };
+
+        let result = self.mount.close(handle).await;
+        let status = if result.is_ok() {
+            transfers::Status::Completed
+        } else {
+            transfers::Status::Failed
+        };
+        record_transfer(&self.transfers, transfer, status).await;
+
+        result.map_err(Error::from)
+    }
+}
+
+impl Drop for LinkedFile {
+    fn drop(&mut self) {
+        let (Some(handle), Some(transfer)) = (self.handle.take(), self.transfer.take()) else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let mount = self.mount.clone();
+        let history = self.transfers.clone();
+        runtime.spawn(async move {
+            if let Err(err) = mount.abort(handle).await {
+                event!(Level::WARN, err = %err.as_report(), "failed to abort linked file");
+            }
+            record_transfer(&history, transfer, transfers::Status::Interrupted).await;
+        });
+    }
+}
+
+async fn record_transfer(transfers: &Transfers, transfer: Transfer, status: transfers::Status) {
+    if let Err(err) = transfers.record(&transfer.finish(status)).await {
+        event!(Level::WARN, err = %err.as_report(), "failed to record transfer");
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let status = match code {
+            ErrorCode::BadRequest | ErrorCode::InvalidPath => StatusCode::BAD_REQUEST,
+            ErrorCode::PermissionDenied | ErrorCode::OutsideRoot => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound | ErrorCode::NotAFile | ErrorCode::NotADirectory => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::Expired => StatusCode::GONE,
+            ErrorCode::NoSpace => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Locked
+            | ErrorCode::Conflict
+            | ErrorCode::ReadOnly
+            | ErrorCode::LegalHold
+            | ErrorCode::Immutable
+            | ErrorCode::Retained => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        (status, format!("error [{code}]: {}\n", self.as_report())).into_response()
+    }
+}
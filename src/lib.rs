@@ -1,9 +1,32 @@
 #![forbid(unsafe_code)]
 
+pub mod affinity;
+pub mod audit;
 pub mod auth;
+pub mod checksums;
 pub mod config;
+pub mod conformance;
+pub mod demo;
+pub mod docs;
+pub mod error_code;
+pub mod events;
+pub mod gateway;
+pub mod log_filter;
 pub mod metrics;
+pub mod openssh;
+pub mod privacy;
+pub mod probe;
+pub mod purge;
+pub mod push;
 pub mod redis;
+pub mod replication;
+pub mod reporting;
 pub mod sftp;
+pub mod ssh_client;
+pub mod state;
+pub mod supervisor;
+pub mod throttle;
+pub mod transfers;
+pub mod upload_hooks;
 pub mod version;
 pub mod vfs;
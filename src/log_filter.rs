@@ -0,0 +1,330 @@
+//! Changing what gets logged while the server is running, since restarting it
+//! to turn up the verbosity would drop every connected session.
+//!
+//! The filter set at startup from `SCHLEP_LOG` is the base. Directives can be
+//! added on top of it, like `schlep::sftp=debug`, either until they're reset or
+//! for a limited time, from the `log-level` admin command or by sending the
+//! process `SIGUSR1`.
+//!
+//! Separately, the SFTP requests from a single user or address can be traced in
+//! full detail for a while with [`trace`], without turning up the verbosity for
+//! everyone else.
+
+use std::{
+    fmt::{self, Write as _},
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use ahash::HashMap;
+use parking_lot::Mutex;
+use tracing::{Level, event};
+use tracing_subscriber::{
+    EnvFilter,
+    Registry,
+    filter::{LevelFilter, ParseError},
+    reload,
+};
+
+use crate::error_code::ErrorCode;
+
+/// The directives that `SIGUSR1` adds.
+pub const SIGUSR1_DIRECTIVES: &str = "schlep=debug";
+
+/// How long the directives that `SIGUSR1` adds last.
+pub const SIGUSR1_DURATION: Duration = Duration::from_mins(10);
+
+/// The target of the spans that traced SFTP requests are wrapped in.
+pub const TRACE_TARGET: &str = "schlep::trace";
+
+/// The name of the spans that traced SFTP requests are wrapped in. Everything
+/// logged inside one of them by [`TRACED_MODULES`] is let through, down to
+/// `TRACE`.
+pub const TRACE_SPAN: &str = "traced_sftp_request";
+
+/// The modules whose events are let through inside a [`TRACE_SPAN`]. This
+/// can't just be everything, since span directives override target ones, and
+/// audit events would end up in the regular log even with privacy mode on.
+const TRACED_MODULES: [&str; 2] = ["schlep::sftp", "schlep::vfs"];
+
+/// How long a trace lasts unless it's given a duration.
+pub const DEFAULT_TRACE_DURATION: Duration = Duration::from_mins(10);
+
+/// The longest a trace can last, so that one that's forgotten about doesn't
+/// fill up the disk.
+pub const MAX_TRACE_DURATION: Duration = Duration::from_hours(1);
+
+static FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid log directives: {0}")]
+    InvalidDirectives(#[from] ParseError),
+    #[error("failed to change the log filter: {0}")]
+    ReloadFailed(#[from] reload::Error),
+    #[error("the log filter can't be changed in this process")]
+    NotInitialized,
+    #[error("invalid trace subject {0:?}, expected user=<name> or ip=<address>")]
+    InvalidTraceSubject(String),
+    #[error("traces can last at most an hour")]
+    TraceTooLong,
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidDirectives(_) | Self::InvalidTraceSubject(_) | Self::TraceTooLong => {
+                ErrorCode::BadRequest
+            }
+            Self::ReloadFailed(_) => ErrorCode::Internal,
+            Self::NotInitialized => ErrorCode::Unsupported,
+        }
+    }
+}
+
+/// Whose SFTP requests to trace, see [`trace`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TraceSubject {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl FromStr for TraceSubject {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("user", user)) if !user.is_empty() => Ok(Self::User(user.to_owned())),
+            Some(("ip", ip)) => ip
+                .parse()
+                .map(Self::Ip)
+                .map_err(|_| Error::InvalidTraceSubject(s.to_owned())),
+            _ => Err(Error::InvalidTraceSubject(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for TraceSubject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User(user) => write!(f, "user={user}"),
+            Self::Ip(ip) => write!(f, "ip={ip}"),
+        }
+    }
+}
+
+struct LogFilter {
+    base: String,
+    extra: Mutex<Option<String>>,
+    /// Bumped on every change, so that a timed change only resets the filter
+    /// if nothing else has changed it since.
+    generation: AtomicU64,
+    /// The subjects being traced, with an ID that's unique to each trace and
+    /// when the trace ends.
+    traces: Mutex<HashMap<TraceSubject, (u64, SystemTime)>>,
+    /// Whether `traces` is non-empty, so that checking whether a request is
+    /// traced doesn't need a lock when nothing is.
+    tracing: AtomicBool,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    /// Reloads the filter with `extra` added on top of the base, and with
+    /// traced requests let through if there are any traces.
+    fn reload(&self, extra: Option<&str>) -> Result<(), Error> {
+        let mut directives = self.base.clone();
+        if let Some(extra) = extra {
+            directives.push(',');
+            directives.push_str(extra);
+        }
+        if self.tracing.load(Ordering::SeqCst) {
+            let _ = write!(directives, ",{TRACE_TARGET}=trace");
+            for module in TRACED_MODULES {
+                let _ = write!(directives, ",{module}[{TRACE_SPAN}]=trace");
+            }
+        }
+
+        self.handle.reload(build(&directives)?)?;
+
+        Ok(())
+    }
+
+    fn apply(&self, extra: Option<&str>) -> Result<u64, Error> {
+        let mut current = self.extra.lock();
+        self.reload(extra)?;
+        *current = extra.map(str::to_owned);
+
+        Ok(self.generation.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn update_traces(
+        &self,
+        f: impl FnOnce(&mut HashMap<TraceSubject, (u64, SystemTime)>),
+    ) -> Result<(), Error> {
+        let extra = self.extra.lock();
+        let mut traces = self.traces.lock();
+        f(&mut traces);
+
+        let tracing = !traces.is_empty();
+        if self.tracing.swap(tracing, Ordering::SeqCst) != tracing {
+            self.reload(extra.as_deref())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Builds the filter for `directives`, which logs at `INFO` by default.
+pub fn build(directives: &str) -> Result<EnvFilter, Error> {
+    Ok(EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .parse(directives)?)
+}
+
+/// Makes the filter behind `handle`, built from `base`, changeable with the
+/// other functions in this module.
+pub fn init(base: String, handle: reload::Handle<EnvFilter, Registry>) {
+    let filter = LogFilter {
+        base,
+        extra: Mutex::default(),
+        generation: AtomicU64::new(0),
+        traces: Mutex::default(),
+        tracing: AtomicBool::new(false),
+        handle,
+    };
+
+    if FILTER.set(filter).is_err() {
+        event!(Level::WARN, "the log filter was already initialized");
+    }
+}
+
+/// Adds `directives` on top of the base filter, replacing any that were added
+/// before. With a `duration`, the filter goes back to the base afterwards.
+pub fn set(directives: &str, duration: Option<Duration>) -> Result<(), Error> {
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+    let generation = filter.apply(Some(directives))?;
+
+    event!(Level::INFO, directives, ?duration, "changed log filter");
+
+    if let Some(duration) = duration {
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+
+            if filter.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+
+            match filter.apply(None) {
+                Ok(_) => event!(Level::INFO, "reset log filter"),
+                Err(err) => event!(Level::WARN, %err, "failed to reset log filter"),
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Goes back to the base filter.
+pub fn reset() -> Result<(), Error> {
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+    filter.apply(None)?;
+
+    event!(Level::INFO, "reset log filter");
+
+    Ok(())
+}
+
+/// The base filter, and the directives added on top of it, if any.
+pub fn current() -> Result<(String, Option<String>), Error> {
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+
+    Ok((filter.base.clone(), filter.extra.lock().clone()))
+}
+
+/// Logs every SFTP request from `subject` in full detail for `duration`,
+/// replacing any trace of `subject` that's already running. Returns when the
+/// trace ends.
+pub fn trace(subject: TraceSubject, duration: Duration) -> Result<SystemTime, Error> {
+    if duration > MAX_TRACE_DURATION {
+        return Err(Error::TraceTooLong);
+    }
+
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+    let id = rand::random::<u64>();
+    let ends_at = SystemTime::now() + duration;
+
+    filter.update_traces(|traces| {
+        traces.insert(subject.clone(), (id, ends_at));
+    })?;
+
+    event!(Level::INFO, ?duration, "started SFTP trace");
+
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+
+        let result = filter.update_traces(|traces| {
+            if traces
+                .get(&subject)
+                .is_some_and(|(trace_id, _)| *trace_id == id)
+            {
+                traces.remove(&subject);
+            }
+        });
+
+        match result {
+            Ok(()) => event!(Level::INFO, "ended SFTP trace"),
+            Err(err) => event!(Level::WARN, %err, "failed to end SFTP trace"),
+        }
+    });
+
+    Ok(ends_at)
+}
+
+/// Stops tracing `subject`. Returns whether it was being traced.
+pub fn untrace(subject: &TraceSubject) -> Result<bool, Error> {
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+    let mut removed = false;
+
+    filter.update_traces(|traces| removed = traces.remove(subject).is_some())?;
+
+    Ok(removed)
+}
+
+/// The subjects being traced, and when their traces end.
+pub fn traces() -> Result<Vec<(TraceSubject, SystemTime)>, Error> {
+    let filter = FILTER.get().ok_or(Error::NotInitialized)?;
+    let mut traces = filter
+        .traces
+        .lock()
+        .iter()
+        .map(|(subject, (_, ends_at))| (subject.clone(), *ends_at))
+        .collect::<Vec<_>>();
+    traces.sort_by_key(|(_, ends_at)| *ends_at);
+
+    Ok(traces)
+}
+
+/// Whether the requests of a session for `username`, connected from `ip`,
+/// are being traced.
+#[must_use]
+pub fn is_traced(username: &str, ip: Option<IpAddr>) -> bool {
+    let Some(filter) = FILTER.get() else {
+        return false;
+    };
+    if !filter.tracing.load(Ordering::Relaxed) {
+        return false;
+    }
+
+    filter.traces.lock().keys().any(|subject| match subject {
+        TraceSubject::User(user) => user == username,
+        TraceSubject::Ip(traced) => ip.is_some_and(|ip| ip == *traced),
+    })
+}
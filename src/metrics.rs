@@ -1,16 +1,35 @@
-use std::sync::Arc;
+use std::{fs, io, sync::Arc, time::Duration};
 
-use axum::{Router, extract::State, response::IntoResponse, routing};
+use axum::{
+    Json,
+    Router,
+    extract::{Path, State},
+    response::IntoResponse,
+    routing,
+};
+use futures_util::future;
 use http::{HeaderMap, StatusCode};
-use metrics::{describe_gauge, describe_histogram};
+use metrics::{describe_counter, describe_gauge, describe_histogram, gauge};
 use metrics_exporter_prometheus::PrometheusHandle;
 use parking_lot::Once;
+use rustix::process::{Resource, getrlimit};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_inline_default::serde_inline_default;
+use thiserror_ext::AsReport;
 use tokio::net::TcpListener;
+use tracing::{Level, event};
 
-use crate::version::VERSION_INFO;
+use crate::{
+    affinity::Affinity,
+    probe::Probe,
+    version::VERSION_INFO,
+    vfs::{LiveVfsSet, Vfs, VfsSet},
+};
+
+/// How long a mount's backend may take to answer a readiness check before it
+/// counts as down.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde_inline_default]
@@ -28,19 +47,60 @@ pub struct Config {
 pub struct Metrics {
     config: Arc<Config>,
     handle: Arc<PrometheusHandle>,
+    affinity: Affinity,
+    probe: Probe,
+    vfs_set: LiveVfsSet,
 }
 
 #[allow(clippy::unused_async)]
 impl Metrics {
+    pub const EVENTS_DROPPED: &'static str = "schlep_events_dropped";
+    pub const EVENTS_PUBLISHED: &'static str = "schlep_events_published";
+    pub const MOUNT_FREE_BYTES: &'static str = "schlep_mount_free_bytes";
+    pub const MOUNT_LOW_SPACE: &'static str = "schlep_mount_low_space";
+    pub const PROCESS_MAX_FDS: &'static str = "schlep_process_max_fds";
+    pub const PROCESS_OPEN_FDS: &'static str = "schlep_process_open_fds";
+    pub const PROCESS_RESIDENT_MEMORY: &'static str = "schlep_process_resident_memory_bytes";
+    pub const PUSHED_FILES: &'static str = "schlep_pushed_files";
+    pub const PUSH_FAILURES: &'static str = "schlep_push_failures";
+    pub const REPLICATED_FILES: &'static str = "schlep_replicated_files";
+    pub const SERVICE_RESTARTS: &'static str = "schlep_service_restarts";
     pub const SFTP_CLIENTS: &'static str = "schlep_sftp_clients";
+    pub const SFTP_ERRORS: &'static str = "schlep_sftp_errors";
+    pub const SFTP_PROBE_UP: &'static str = "schlep_sftp_probe_up";
+    pub const SFTP_PROTOCOL_VIOLATIONS: &'static str = "schlep_sftp_protocol_violations";
     pub const SFTP_READ_DURATION: &'static str = "schlep_sftp_read_duration";
+    pub const SFTP_RECONNECTS: &'static str = "schlep_sftp_reconnects";
+    pub const SFTP_RECONNECT_STORMS: &'static str = "schlep_sftp_reconnect_storms";
+    pub const SFTP_THROTTLED: &'static str = "schlep_sftp_throttled";
     pub const SFTP_WRITE_DURATION: &'static str = "schlep_sftp_write_duration";
+    pub const TOKIO_ALIVE_TASKS: &'static str = "schlep_tokio_alive_tasks";
+    pub const TOKIO_GLOBAL_QUEUE_DEPTH: &'static str = "schlep_tokio_global_queue_depth";
+    pub const TOKIO_WORKERS: &'static str = "schlep_tokio_workers";
+    pub const UPLOAD_HOOK_FAILURES: &'static str = "schlep_upload_hook_failures";
+    pub const VFS_BLOCKING_TASKS: &'static str = "schlep_vfs_blocking_tasks";
+    pub const VFS_CACHE_HITS: &'static str = "schlep_vfs_cache_hits";
+    pub const VFS_CACHE_MISSES: &'static str = "schlep_vfs_cache_misses";
 
+    #[allow(clippy::too_many_lines)]
     fn register_metrics() {
         static REGISTER_METRICS: Once = Once::new();
 
         REGISTER_METRICS.call_once(|| {
             describe_gauge!(Self::SFTP_CLIENTS, "active SFTP connections");
+            describe_gauge!(
+                Self::SFTP_PROBE_UP,
+                "1 if the SFTP server answered the last probes, 0 if too many in a row failed"
+            );
+            describe_gauge!(
+                Self::MOUNT_FREE_BYTES,
+                metrics::Unit::Bytes,
+                "free space on a mount's backend, as of the last upload to it"
+            );
+            describe_gauge!(
+                Self::MOUNT_LOW_SPACE,
+                "1 if a mount is below its free space warning watermark, 0 otherwise"
+            );
 
             describe_histogram!(
                 Self::SFTP_READ_DURATION,
@@ -52,22 +112,130 @@ impl Metrics {
                 metrics::Unit::Seconds,
                 "duration per write operation"
             );
+
+            describe_counter!(
+                Self::SFTP_ERRORS,
+                "failed SFTP requests, by operation and error code"
+            );
+            describe_counter!(
+                Self::SFTP_PROTOCOL_VIOLATIONS,
+                "malformed or out-of-place SFTP requests, which are answered with an error"
+            );
+            describe_counter!(
+                Self::SFTP_RECONNECTS,
+                "connections from a client that already connected within the last minute"
+            );
+            describe_counter!(
+                Self::SFTP_RECONNECT_STORMS,
+                "times a client exceeded the reconnect threshold"
+            );
+            describe_counter!(
+                Self::SFTP_THROTTLED,
+                "expensive operations refused for being over their limits, by operation"
+            );
+
+            describe_counter!(
+                Self::EVENTS_PUBLISHED,
+                "events published to the message bus"
+            );
+            describe_counter!(
+                Self::EVENTS_DROPPED,
+                "events dropped because the buffer was full"
+            );
+
+            describe_counter!(Self::PUSHED_FILES, "files pushed, by push job");
+            describe_counter!(
+                Self::PUSH_FAILURES,
+                "pushes that failed after their last retry, by push job"
+            );
+
+            describe_counter!(
+                Self::REPLICATED_FILES,
+                "files copied to a replica because they changed"
+            );
+
+            describe_counter!(
+                Self::UPLOAD_HOOK_FAILURES,
+                "upload hooks that failed, by hook"
+            );
+
+            describe_counter!(
+                Self::SERVICE_RESTARTS,
+                "times a server was restarted after it stopped, by server"
+            );
+
+            describe_gauge!(
+                Self::VFS_BLOCKING_TASKS,
+                "VFS operations currently running on the blocking thread pool"
+            );
+            describe_counter!(
+                Self::VFS_CACHE_HITS,
+                "lookups answered from a mount's cache, by mount and kind"
+            );
+            describe_counter!(
+                Self::VFS_CACHE_MISSES,
+                "lookups a mount's cache had to pass on to its backend, by mount and kind"
+            );
+
+            describe_gauge!(
+                Self::PROCESS_RESIDENT_MEMORY,
+                metrics::Unit::Bytes,
+                "resident memory of the process"
+            );
+            describe_gauge!(
+                Self::PROCESS_OPEN_FDS,
+                "file descriptors open in the process"
+            );
+            describe_gauge!(
+                Self::PROCESS_MAX_FDS,
+                "the most file descriptors the process may have open"
+            );
+
+            describe_gauge!(Self::TOKIO_WORKERS, "Tokio worker threads");
+            describe_gauge!(Self::TOKIO_ALIVE_TASKS, "Tokio tasks that haven't finished");
+            describe_gauge!(
+                Self::TOKIO_GLOBAL_QUEUE_DEPTH,
+                "Tokio tasks waiting in the global run queue"
+            );
         });
     }
 
     #[must_use]
-    pub fn new(config: Config, handle: PrometheusHandle) -> Self {
+    pub fn new(
+        config: Config,
+        handle: PrometheusHandle,
+        affinity: Affinity,
+        probe: Probe,
+        vfs_set: LiveVfsSet,
+    ) -> Self {
         Self::register_metrics();
 
         Self {
             config: Arc::new(config),
             handle: Arc::new(handle),
+            affinity,
+            probe,
+            vfs_set,
         }
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
         let app = Router::new()
-            .route("/healthz", routing::get(Self::healthz_handler))
+            .route(
+                "/healthz",
+                routing::get({
+                    let affinity = self.affinity.clone();
+                    move |config| Self::healthz_handler(config, affinity)
+                }),
+            )
+            .route(
+                "/readyz",
+                routing::get({
+                    let probe = self.probe.clone();
+                    let vfs_set = self.vfs_set.clone();
+                    move |config| Self::readyz_handler(config, probe, vfs_set)
+                }),
+            )
             .route(
                 "/metrics",
                 routing::get({
@@ -75,6 +243,20 @@ impl Metrics {
                     move |config| Self::prometheus_handler(config, handle)
                 }),
             )
+            .route(
+                "/instance",
+                routing::get({
+                    let affinity = self.affinity.clone();
+                    move || Self::instance_handler(affinity)
+                }),
+            )
+            .route(
+                "/affinity/{key}",
+                routing::get({
+                    let affinity = self.affinity.clone();
+                    move |key| Self::affinity_handler(key, affinity)
+                }),
+            )
             .with_state(self.config.clone());
 
         let listener = TcpListener::bind((self.config.address.clone(), self.config.port)).await?;
@@ -83,23 +265,136 @@ impl Metrics {
         Ok(())
     }
 
-    async fn healthz_handler(State(config): State<Arc<Config>>) -> impl IntoResponse {
+    async fn healthz_handler(
+        State(config): State<Arc<Config>>,
+        affinity: Affinity,
+    ) -> impl IntoResponse {
         if config.enable_health_check {
-            (StatusCode::OK, VERSION_INFO.as_headers())
+            let mut headers = VERSION_INFO.as_headers();
+            if let Some(instance_id) = affinity.instance_id_header() {
+                headers.insert(Affinity::INSTANCE_ID, instance_id);
+            }
+
+            (StatusCode::OK, headers)
         } else {
             (StatusCode::NOT_FOUND, HeaderMap::default())
         }
     }
 
+    async fn readyz_handler(
+        State(config): State<Arc<Config>>,
+        probe: Probe,
+        vfs_set: LiveVfsSet,
+    ) -> StatusCode {
+        if !config.enable_health_check {
+            StatusCode::NOT_FOUND
+        } else if probe.is_ready() && Self::mounts_healthy(&vfs_set.snapshot()).await {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+
+    /// Whether every mount's backend passes its health check. Mounts that are
+    /// only made for each user as they log in aren't checked.
+    async fn mounts_healthy(vfs_set: &VfsSet) -> bool {
+        let checks = vfs_set.mounts().map(|(root, mount)| async move {
+            let err = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, mount.health_check()).await {
+                Ok(Ok(())) => return true,
+                Ok(Err(err)) => err.as_report().to_string(),
+                Err(_) => "timed out".to_owned(),
+            };
+
+            event!(Level::WARN, mount = %root, err = %err, "mount failed health check");
+            false
+        });
+
+        future::join_all(checks)
+            .await
+            .into_iter()
+            .all(|healthy| healthy)
+    }
+
     async fn prometheus_handler(
         State(config): State<Arc<Config>>,
         handle: Arc<PrometheusHandle>,
     ) -> impl IntoResponse {
         if config.enable_metrics_export {
+            Self::collect_process_metrics();
             let metrics = handle.render();
             (StatusCode::OK, metrics)
         } else {
             (StatusCode::NOT_FOUND, String::new())
         }
     }
+
+    /// Updates the gauges that describe the process itself, so that they're
+    /// current as of each scrape.
+    #[allow(clippy::cast_precision_loss)]
+    fn collect_process_metrics() {
+        match resident_memory() {
+            Ok(bytes) => gauge!(Self::PROCESS_RESIDENT_MEMORY).set(bytes as f64),
+            Err(err) => event!(Level::DEBUG, %err, "couldn't read resident memory"),
+        }
+
+        match fs::read_dir("/proc/self/fd") {
+            Ok(fds) => gauge!(Self::PROCESS_OPEN_FDS).set(fds.count() as f64),
+            Err(err) => event!(Level::DEBUG, %err, "couldn't count open file descriptors"),
+        }
+
+        if let Some(max_fds) = getrlimit(Resource::Nofile).current {
+            gauge!(Self::PROCESS_MAX_FDS).set(max_fds as f64);
+        }
+
+        let runtime = tokio::runtime::Handle::current().metrics();
+        gauge!(Self::TOKIO_WORKERS).set(runtime.num_workers() as f64);
+        gauge!(Self::TOKIO_ALIVE_TASKS).set(runtime.num_alive_tasks() as f64);
+        gauge!(Self::TOKIO_GLOBAL_QUEUE_DEPTH).set(runtime.global_queue_depth() as f64);
+    }
+
+    async fn instance_handler(affinity: Affinity) -> impl IntoResponse {
+        Json(InstanceInfo {
+            instance_id: affinity.instance_id().to_owned(),
+            version: VERSION_INFO.version,
+        })
+    }
+
+    async fn affinity_handler(Path(key): Path<String>, affinity: Affinity) -> impl IntoResponse {
+        match affinity.instance_for(&key) {
+            Some(instance_id) => (
+                StatusCode::OK,
+                Json(Some(AffinityHint {
+                    instance_id: instance_id.to_owned(),
+                    key,
+                })),
+            ),
+            None => (StatusCode::NOT_FOUND, Json(None)),
+        }
+    }
+}
+
+/// The resident memory of the process, in bytes, from `/proc/self/statm`.
+fn resident_memory() -> io::Result<u64> {
+    let statm = fs::read_to_string("/proc/self/statm")?;
+    let pages = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse::<u64>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed statm"))?;
+
+    Ok(pages * rustix::param::page_size() as u64)
+}
+
+/// The body of a response from `/instance`.
+#[derive(Serialize)]
+struct InstanceInfo {
+    instance_id: String,
+    version: &'static str,
+}
+
+/// The body of a response from `/affinity/{key}`.
+#[derive(Serialize)]
+struct AffinityHint {
+    key: String,
+    instance_id: String,
 }
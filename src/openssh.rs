@@ -0,0 +1,669 @@
+//! Migrating from an OpenSSH `internal-sftp` deployment.
+//!
+//! [`import`] reads an `sshd_config`, along with the system's users and groups
+//! and their `authorized_keys` files, and works out the equivalent Schlep
+//! configuration:
+//!
+//! - `Port`, `ListenAddress`, `HostKey`, `PasswordAuthentication`, and
+//!   `PubkeyAuthentication` carry over to the `sftp` section.
+//! - Every user with an authorized key becomes a static user.
+//! - Every directory that users are chrooted into, globally or in a `Match`
+//!   block, becomes a mount that only the users chrooted there can see. Users
+//!   who aren't chrooted get their home directory instead.
+//!
+//! Anything that doesn't carry over is listed in comments at the top of the
+//! generated configuration, for an administrator to review.
+
+use std::{collections::BTreeMap, fs, io};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use russh::keys::PublicKey;
+use toml::{Table, Value};
+
+use crate::auth::StaticUser;
+
+/// Where OpenSSH reads authorized keys from unless it's told otherwise.
+const DEFAULT_AUTHORIZED_KEYS_FILES: &[&str] = &[".ssh/authorized_keys", ".ssh/authorized_keys2"];
+
+/// The host keys OpenSSH uses unless it's told otherwise.
+const DEFAULT_HOST_KEYS: &[&str] = &[
+    "/etc/ssh/ssh_host_ecdsa_key",
+    "/etc/ssh/ssh_host_ed25519_key",
+    "/etc/ssh/ssh_host_rsa_key",
+];
+
+#[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("I/O error: {from}")]
+    IoError { source: io::Error, from: String },
+    #[error("{file}:{line}: {message}")]
+    SyntaxError {
+        file: Utf8PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+/// Where to find the deployment to import.
+pub struct Sources<'a> {
+    pub sshd_config: &'a Utf8Path,
+    pub passwd: &'a Utf8Path,
+    pub group: &'a Utf8Path,
+}
+
+impl Default for Sources<'_> {
+    fn default() -> Self {
+        Self {
+            sshd_config: Utf8Path::new("/etc/ssh/sshd_config"),
+            passwd: Utf8Path::new("/etc/passwd"),
+            group: Utf8Path::new("/etc/group"),
+        }
+    }
+}
+
+/// Reads the deployment in `sources`, and renders the equivalent Schlep
+/// configuration as TOML.
+pub fn import(sources: &Sources) -> Result<String, Error> {
+    let sshd_config = SshdConfig::parse(sources.sshd_config, &read(sources.sshd_config)?)?;
+    let users = parse_passwd(&read(sources.passwd)?, &read(sources.group)?);
+
+    let mut import = Import::default();
+    let mut config = Table::new();
+
+    config.insert("sftp".to_string(), import.sftp(&sshd_config).into());
+
+    let mut static_users = Vec::new();
+    for user in &users {
+        if let Some(static_user) = import.user(&sshd_config, user) {
+            static_users.push(static_user);
+        }
+    }
+
+    let mut auth = Table::new();
+    auth.insert(
+        "users".to_string(),
+        Value::try_from(static_users).expect("static users are serializable"),
+    );
+    config.insert("auth".to_string(), auth.into());
+    config.insert("fs".to_string(), import.mounts().into());
+
+    // The metrics section has no defaults, so the configuration wouldn't load
+    // without one.
+    let mut metrics = Table::new();
+    metrics.insert("address".to_string(), "127.0.0.1".into());
+    metrics.insert("port".to_string(), 9100_i64.into());
+    config.insert("metrics".to_string(), metrics.into());
+    import.note("the metrics endpoint listens on 127.0.0.1:9100, change it if needed");
+
+    let mut out = format!(
+        "# Generated by `schlep import-openssh` from {}.\n",
+        sources.sshd_config
+    );
+    for note in &import.notes {
+        out.push_str("# note: ");
+        out.push_str(note);
+        out.push('\n');
+    }
+    out.push('\n');
+    out.push_str(&toml::to_string_pretty(&config).expect("TOML tables are serializable"));
+
+    Ok(out)
+}
+
+fn read(path: &Utf8Path) -> Result<String, Error> {
+    fs::read_to_string(path).into_io_error(format!("failed to read {path}"))
+}
+
+#[derive(Default)]
+struct Import {
+    /// The mounts to generate, keyed by the directory they expose, with the
+    /// root they're mounted at.
+    mounts: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+    notes: Vec<String>,
+}
+
+impl Import {
+    fn note(&mut self, note: impl Into<String>) {
+        let note = note.into();
+
+        if !self.notes.contains(&note) {
+            self.notes.push(note);
+        }
+    }
+
+    fn sftp(&mut self, sshd_config: &SshdConfig) -> Table {
+        let mut sftp = Table::new();
+
+        if let Some(port) = sshd_config.global("port").and_then(|args| args.first()) {
+            match port.parse::<u16>() {
+                Ok(port) => {
+                    sftp.insert("port".to_string(), i64::from(port).into());
+                }
+                Err(_) => self.note(format!("couldn't parse Port {port}")),
+            }
+        }
+
+        let addresses = sshd_config
+            .all_global("listenaddress")
+            .filter_map(|args| args.first())
+            .map(|address| listen_address(address).to_string())
+            .collect::<Vec<_>>();
+        if !addresses.is_empty() {
+            sftp.insert("address".to_string(), addresses.into());
+        }
+
+        let mut host_keys = sshd_config
+            .all_global("hostkey")
+            .filter_map(|args| args.first().cloned())
+            .collect::<Vec<_>>();
+        if host_keys.is_empty() {
+            host_keys = DEFAULT_HOST_KEYS.iter().map(ToString::to_string).collect();
+        }
+        sftp.insert(
+            "private_host_key_file".to_string(),
+            host_keys[0].clone().into(),
+        );
+        if host_keys.len() > 1 {
+            self.note(format!(
+                "only the host key {} is used; concatenate {} into it, or move them into a \
+                 directory and set private_host_key_dir, to keep the others",
+                host_keys[0],
+                host_keys[1..].join(", ")
+            ));
+        }
+
+        if let Some(enabled) = sshd_config.global_flag("passwordauthentication") {
+            sftp.insert("allow_password".to_string(), enabled.into());
+        }
+        if let Some(enabled) = sshd_config.global_flag("pubkeyauthentication") {
+            sftp.insert("allow_publickey".to_string(), enabled.into());
+        }
+
+        for keyword in [
+            "allowtcpforwarding",
+            "x11forwarding",
+            "permittunnel",
+            "banner",
+        ] {
+            if sshd_config.global(keyword).is_some() {
+                self.note(format!("{keyword} has no equivalent and was skipped"));
+            }
+        }
+
+        sftp
+    }
+
+    /// The static user for `user`, or `None` if they can't log in over SFTP.
+    fn user(&mut self, sshd_config: &SshdConfig, user: &User) -> Option<StaticUser> {
+        if !sshd_config.allows(user) {
+            return None;
+        }
+
+        let settings = sshd_config.settings_for(user, &mut self.notes);
+
+        let key_files = match settings.get("authorizedkeysfile") {
+            Some(files) => files.clone(),
+            None => DEFAULT_AUTHORIZED_KEYS_FILES
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        };
+
+        let mut public_keys = Vec::new();
+        for file in key_files {
+            if file.eq_ignore_ascii_case("none") {
+                continue;
+            }
+
+            let path = user.home.join(expand_tokens(&file, user));
+            if let Ok(contents) = fs::read_to_string(&path) {
+                for line in contents.lines() {
+                    match authorized_key(line) {
+                        Some((key, had_options)) => {
+                            if had_options {
+                                self.note(format!(
+                                    "options on keys in {path} have no equivalent and were \
+                                     dropped"
+                                ));
+                            }
+                            public_keys.push(key);
+                        }
+                        None => {
+                            if !line.trim().is_empty() && !line.trim_start().starts_with('#') {
+                                self.note(format!("skipped a key in {path} that couldn't be read"));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if public_keys.is_empty() {
+            return None;
+        }
+
+        if settings
+            .get("forcecommand")
+            .and_then(|args| args.first())
+            .is_some_and(|command| *command != "internal-sftp")
+        {
+            self.note(format!(
+                "{} has a ForceCommand other than internal-sftp, which was dropped",
+                user.name
+            ));
+        }
+
+        let dir = match settings
+            .get("chrootdirectory")
+            .and_then(|args| args.first())
+        {
+            Some(dir) if !dir.eq_ignore_ascii_case("none") => {
+                Utf8PathBuf::from(expand_tokens(dir, user))
+            }
+            _ => {
+                self.note(format!(
+                    "{} isn't chrooted, so they only get their home directory",
+                    user.name
+                ));
+                user.home.clone()
+            }
+        };
+
+        let root = self.mount(&dir);
+        let mut groups = user.groups.clone();
+        groups.push(mount_group(&root));
+
+        Some(StaticUser {
+            username: user.name.clone(),
+            public_keys,
+            groups,
+        })
+    }
+
+    /// The root of the mount for `dir`, adding a mount if there isn't one yet.
+    fn mount(&mut self, dir: &Utf8Path) -> Utf8PathBuf {
+        if let Some(root) = self.mounts.get(dir) {
+            return root.clone();
+        }
+
+        let name = dir.file_name().unwrap_or("root");
+        let mut root = Utf8PathBuf::from(format!("/{name}"));
+        let mut suffix = 2;
+        while self.mounts.values().any(|existing| *existing == root) {
+            root = Utf8PathBuf::from(format!("/{name}-{suffix}"));
+            suffix += 1;
+        }
+
+        self.mounts.insert(dir.to_owned(), root.clone());
+
+        root
+    }
+
+    fn mounts(&self) -> Vec<Value> {
+        self.mounts
+            .iter()
+            .map(|(dir, root)| {
+                let mut mount = Table::new();
+                mount.insert("vfs_root".to_string(), root.to_string().into());
+                mount.insert("local_dir".to_string(), dir.to_string().into());
+                mount.insert("visible_to_group".to_string(), mount_group(root).into());
+
+                mount.into()
+            })
+            .collect()
+    }
+}
+
+/// The group that the users who can see the mount at `root` are put in.
+fn mount_group(root: &Utf8Path) -> String {
+    format!("openssh:{root}")
+}
+
+/// The address part of a `ListenAddress`, which may also have a port.
+fn listen_address(address: &str) -> &str {
+    if let Some(address) = address.strip_prefix('[') {
+        return address
+            .split_once(']')
+            .map_or(address, |(address, _)| address);
+    }
+
+    match address.split_once(':') {
+        Some((host, port)) if !port.contains(':') => host,
+        _ => address,
+    }
+}
+
+/// Expands the `%` tokens that OpenSSH allows in paths.
+fn expand_tokens(value: &str, user: &User) -> String {
+    let mut out = String::new();
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('%') | None => out.push('%'),
+            Some('h') => out.push_str(user.home.as_str()),
+            Some('u') => out.push_str(&user.name),
+            Some('U') => out.push_str(&user.uid.to_string()),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+        }
+    }
+
+    out
+}
+
+/// The key on a line of an `authorized_keys` file, and whether it had options
+/// in front of it.
+fn authorized_key(line: &str) -> Option<(String, bool)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    (0..words.len()).find_map(|start| {
+        let key = words[start..].join(" ");
+
+        PublicKey::from_openssh(&key)
+            .is_ok()
+            .then_some((key, start > 0))
+    })
+}
+
+struct User {
+    name: String,
+    uid: u32,
+    home: Utf8PathBuf,
+    groups: Vec<String>,
+}
+
+/// The users in a `passwd` file, with the groups they're in according to a
+/// `group` file.
+fn parse_passwd(passwd: &str, group: &str) -> Vec<User> {
+    let groups = group
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(':').collect::<Vec<_>>();
+            let &[name, _, gid, members] = fields.as_slice() else {
+                return None;
+            };
+
+            Some((name, gid, members.split(',').collect::<Vec<_>>()))
+        })
+        .collect::<Vec<_>>();
+
+    passwd
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(':').collect::<Vec<_>>();
+            let &[name, _, uid, gid, _, home, _] = fields.as_slice() else {
+                return None;
+            };
+
+            let user_groups = groups
+                .iter()
+                .filter(|(_, group_gid, members)| *group_gid == gid || members.contains(&name))
+                .map(|(group_name, ..)| (*group_name).to_string())
+                .collect();
+
+            Some(User {
+                name: name.to_string(),
+                uid: uid.parse().ok()?,
+                home: Utf8PathBuf::from(home),
+                groups: user_groups,
+            })
+        })
+        .collect()
+}
+
+struct Directive {
+    keyword: String,
+    args: Vec<String>,
+}
+
+struct MatchBlock {
+    criteria: Vec<(String, String)>,
+    directives: Vec<Directive>,
+}
+
+/// The parts of an `sshd_config` that matter for the import.
+struct SshdConfig {
+    global: Vec<Directive>,
+    matches: Vec<MatchBlock>,
+}
+
+impl SshdConfig {
+    fn parse(file: &Utf8Path, contents: &str) -> Result<Self, Error> {
+        let mut config = Self {
+            global: Vec::new(),
+            matches: Vec::new(),
+        };
+
+        for (index, line) in contents.lines().enumerate() {
+            let syntax_error = |message: &str| Error::SyntaxError {
+                file: file.to_owned(),
+                line: index + 1,
+                message: message.to_string(),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some(mut words) = shlex::split(line) else {
+                return Err(syntax_error("unbalanced quotes"));
+            };
+
+            // `Keyword=value` is as good as `Keyword value`.
+            if let Some((keyword, value)) = words[0]
+                .split_once('=')
+                .map(|(keyword, value)| (keyword.to_string(), value.to_string()))
+            {
+                words[0] = keyword;
+                if !value.is_empty() {
+                    words.insert(1, value);
+                }
+            }
+
+            let keyword = words.remove(0).to_ascii_lowercase();
+
+            if keyword == "match" {
+                let criteria = if words.len() == 1 && words[0].eq_ignore_ascii_case("all") {
+                    Vec::new()
+                } else if words.len() % 2 == 0 {
+                    words
+                        .chunks(2)
+                        .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].clone()))
+                        .collect()
+                } else {
+                    return Err(syntax_error("Match criteria need a value each"));
+                };
+
+                config.matches.push(MatchBlock {
+                    criteria,
+                    directives: Vec::new(),
+                });
+            } else {
+                let directive = Directive {
+                    keyword,
+                    args: words,
+                };
+
+                match config.matches.last_mut() {
+                    Some(block) => block.directives.push(directive),
+                    None => config.global.push(directive),
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The first global value of `keyword`, which is the one OpenSSH uses.
+    fn global(&self, keyword: &str) -> Option<&[String]> {
+        self.all_global(keyword).next()
+    }
+
+    fn all_global(&self, keyword: &str) -> impl Iterator<Item = &[String]> {
+        self.global
+            .iter()
+            .filter(move |directive| directive.keyword == keyword)
+            .map(|directive| directive.args.as_slice())
+    }
+
+    fn global_flag(&self, keyword: &str) -> Option<bool> {
+        self.global(keyword)
+            .and_then(|args| args.first())
+            .map(|value| value.eq_ignore_ascii_case("yes"))
+    }
+
+    /// Whether `AllowUsers`, `DenyUsers`, `AllowGroups`, and `DenyGroups` let
+    /// `user` log in.
+    fn allows(&self, user: &User) -> bool {
+        let user_patterns = |keyword| {
+            self.all_global(keyword)
+                .flatten()
+                .map(|pattern| {
+                    pattern
+                        .split_once('@')
+                        .map_or(pattern.as_str(), |(user, _)| user)
+                })
+                .collect::<Vec<_>>()
+        };
+        let group_patterns = |keyword| self.all_global(keyword).flatten().collect::<Vec<_>>();
+
+        let allow_users = user_patterns("allowusers");
+        let deny_users = user_patterns("denyusers");
+        let allow_groups = group_patterns("allowgroups");
+        let deny_groups = group_patterns("denygroups");
+
+        let in_groups = |patterns: &[&String]| {
+            user.groups.iter().any(|group| {
+                patterns
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, group))
+            })
+        };
+
+        !deny_users
+            .iter()
+            .any(|pattern| wildcard_match(pattern, &user.name))
+            && !in_groups(&deny_groups)
+            && (allow_users.is_empty()
+                || allow_users
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, &user.name)))
+            && (allow_groups.is_empty() || in_groups(&allow_groups))
+    }
+
+    /// The settings that apply to `user`: the first value of each keyword in
+    /// a `Match` block that applies to them, or else the global one.
+    fn settings_for(&self, user: &User, notes: &mut Vec<String>) -> BTreeMap<String, Vec<String>> {
+        let mut settings = BTreeMap::new();
+
+        for block in &self.matches {
+            if !block_matches(block, user, notes) {
+                continue;
+            }
+
+            for directive in &block.directives {
+                settings
+                    .entry(directive.keyword.clone())
+                    .or_insert_with(|| directive.args.clone());
+            }
+        }
+
+        for directive in &self.global {
+            settings
+                .entry(directive.keyword.clone())
+                .or_insert_with(|| directive.args.clone());
+        }
+
+        settings
+    }
+}
+
+/// Whether every criterion of `block` matches `user`. Criteria that depend on
+/// the connection can't be evaluated ahead of time, so blocks that have them
+/// are skipped.
+fn block_matches(block: &MatchBlock, user: &User, notes: &mut Vec<String>) -> bool {
+    block
+        .criteria
+        .iter()
+        .all(|(criterion, patterns)| match criterion.as_str() {
+            "user" => pattern_list_matches(patterns, &user.name),
+            "group" => user
+                .groups
+                .iter()
+                .any(|group| pattern_list_matches(patterns, group)),
+            _ => {
+                let note =
+                    format!("Match blocks on {criterion} can't be imported and were skipped");
+                if !notes.contains(&note) {
+                    notes.push(note);
+                }
+
+                false
+            }
+        })
+}
+
+/// Matches `value` against a comma-separated list of patterns, any of which
+/// may be negated with `!`. A negated match always wins.
+fn pattern_list_matches(patterns: &str, value: &str) -> bool {
+    let mut matched = false;
+
+    for pattern in patterns.split(',') {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if wildcard_match(pattern, value) {
+                return false;
+            }
+        } else if wildcard_match(pattern, value) {
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+/// Matches `value` against an OpenSSH pattern, where `*` matches any run of
+/// characters and `?` any single character.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,102 @@
+//! Privacy mode keeps raw usernames and client addresses out of everything but
+//! the audit log. With it turned on, the identifiers that end up in the
+//! regular log and in metrics labels are replaced by keyed hashes: the same
+//! user always maps to the same pseudonym, so they can still be correlated,
+//! but the pseudonym can't be turned back into a username without the key.
+//!
+//! Audit events are left alone, since the audit log is exactly where the raw
+//! identifiers are supposed to be kept.
+
+use std::{fmt, io, path::PathBuf, sync::OnceLock};
+
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{Level, event};
+
+type Key = Hmac<Sha256>;
+
+static KEY: OnceLock<Key> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "privacy_config")]
+pub struct Config {
+    /// Replace usernames and client addresses with keyed hashes in the regular
+    /// log and in metrics labels. Audit events record them as-is, so they're
+    /// left out of the regular log and only written to the audit log.
+    #[serde(default)]
+    pub anonymize: bool,
+
+    /// A file holding the key for the hashes. Without one, a random key is
+    /// generated every time the server starts, so pseudonyms can't be
+    /// correlated across restarts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Turns privacy mode on for the whole process if it's enabled. This has
+    /// to happen before anything is logged for it to be effective.
+    pub fn init(&self) -> io::Result<()> {
+        if !self.anonymize {
+            return Ok(());
+        }
+
+        let key = if let Some(key_file) = &self.key_file {
+            std::fs::read(key_file)?
+        } else {
+            event!(
+                Level::WARN,
+                "no privacy key file is configured, pseudonyms will change on restart"
+            );
+            rand::random::<[u8; 32]>().to_vec()
+        };
+
+        let key = Key::new_from_slice(&key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+        if KEY.set(key).is_err() {
+            event!(Level::WARN, "privacy mode was already initialized");
+        }
+
+        Ok(())
+    }
+}
+
+/// Wraps an identifier so that it's displayed as its pseudonym when privacy
+/// mode is on, and as itself otherwise.
+pub fn pseudonymize<T: fmt::Display>(value: T) -> Pseudonymized<T> {
+    Pseudonymized(value)
+}
+
+/// An identifier that's displayed as its pseudonym when privacy mode is on.
+/// See [`pseudonymize`].
+#[derive(Clone, Copy)]
+pub struct Pseudonymized<T>(T);
+
+impl<T: fmt::Display> fmt::Display for Pseudonymized<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match KEY.get() {
+            Some(key) => {
+                let mut mac = key.clone();
+                mac.update(self.0.to_string().as_bytes());
+                let digest = mac.finalize().into_bytes();
+
+                f.write_str("anon-")?;
+                for byte in &digest[..8] {
+                    write!(f, "{byte:02x}")?;
+                }
+
+                Ok(())
+            }
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Debug for Pseudonymized<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,205 @@
+//! A self-check that connects to the SFTP server on an interval and waits for
+//! its SSH banner, to catch an accept loop that has wedged while the rest of
+//! the process carries on. The result is reported on the metrics server's
+//! `/readyz` endpoint and in the `schlep_sftp_probe_up` gauge.
+//!
+//! The probe hangs up as soon as it has the banner, before any key exchange,
+//! but the server still counts it as a connection from the loopback address.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use metrics::gauge;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror_ext::AsReport;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::TcpStream,
+    time::MissedTickBehavior,
+};
+use tracing::{Level, event};
+
+use crate::{metrics::Metrics, sftp};
+
+/// The longest banner line the probe reads. RFC 4253 allows 255 bytes.
+const MAX_BANNER_LEN: u64 = 255;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "probe_config")]
+pub struct Config {
+    /// How long to wait between probes. The default value is 10 seconds.
+    #[serde(
+        default = "Config::default_interval",
+        skip_serializing_if = "Config::is_default_interval",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub interval: Duration,
+
+    /// How long a probe may take to get the banner before it fails. The
+    /// default value is 5 seconds.
+    #[serde(
+        default = "Config::default_timeout",
+        skip_serializing_if = "Config::is_default_timeout",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub timeout: Duration,
+
+    /// How many probes in a row have to fail before the server is reported as
+    /// not ready. The default value is 3.
+    #[serde(
+        default = "Config::default_failure_threshold",
+        skip_serializing_if = "Config::is_default_failure_threshold"
+    )]
+    pub failure_threshold: u32,
+}
+
+impl Config {
+    fn default_interval() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn is_default_interval(interval: &Duration) -> bool {
+        *interval == Self::default_interval()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn is_default_timeout(timeout: &Duration) -> bool {
+        *timeout == Self::default_timeout()
+    }
+
+    fn default_failure_threshold() -> u32 {
+        3
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_failure_threshold(failure_threshold: &u32) -> bool {
+        *failure_threshold == Self::default_failure_threshold()
+    }
+}
+
+/// Whether the SFTP server is answering, as far as the probe can tell.
+#[derive(Clone)]
+pub struct Probe {
+    config: Option<Config>,
+    target: SocketAddr,
+    ready: Arc<AtomicBool>,
+}
+
+impl Probe {
+    /// A probe of the server configured by `sftp_config`. Without a `config`,
+    /// nothing is probed and the server is always reported as ready.
+    #[must_use]
+    pub fn new(config: Option<Config>, sftp_config: &sftp::Config) -> Self {
+        let ip = match sftp_config.address.first() {
+            Some(IpAddr::V4(ip)) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Some(IpAddr::V6(ip)) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            Some(ip) => *ip,
+            None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+
+        Self {
+            ready: Arc::new(AtomicBool::new(config.is_none())),
+            config,
+            target: SocketAddr::new(ip, sftp_config.port),
+        }
+    }
+
+    /// Whether the last probes got a banner. Until the first one does, the
+    /// server isn't ready.
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Starts probing on the configured interval, if probes are configured.
+    pub fn spawn(&self) {
+        let Some(config) = self.config.clone() else {
+            return;
+        };
+        let probe = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(config.interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut failures = 0u32;
+
+            loop {
+                interval.tick().await;
+
+                match tokio::time::timeout(config.timeout, probe.check()).await {
+                    Ok(Ok(())) => {
+                        failures = 0;
+                        probe.set_ready(true);
+                    }
+                    Ok(Err(err)) => {
+                        failures = failures.saturating_add(1);
+                        event!(
+                            Level::WARN,
+                            addr = %probe.target,
+                            failures,
+                            err = %err.as_report(),
+                            "SFTP probe failed"
+                        );
+                    }
+                    Err(_) => {
+                        failures = failures.saturating_add(1);
+                        event!(
+                            Level::WARN,
+                            addr = %probe.target,
+                            failures,
+                            "SFTP probe timed out"
+                        );
+                    }
+                }
+
+                if failures >= config.failure_threshold {
+                    probe.set_ready(false);
+                }
+            }
+        });
+    }
+
+    fn set_ready(&self, ready: bool) {
+        if self.ready.swap(ready, Ordering::Relaxed) != ready {
+            event!(Level::INFO, ready, "SFTP probe changed readiness");
+        }
+
+        gauge!(Metrics::SFTP_PROBE_UP).set(if ready { 1.0 } else { 0.0 });
+    }
+
+    /// Connects to the server and reads lines until its identification
+    /// string, which servers may send other lines before.
+    async fn check(&self) -> io::Result<()> {
+        let stream = TcpStream::connect(self.target).await?;
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            (&mut reader)
+                .take(MAX_BANNER_LEN)
+                .read_until(b'\n', &mut line)
+                .await?;
+
+            if line.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            if line.starts_with(b"SSH-") {
+                return Ok(());
+            }
+        }
+    }
+}
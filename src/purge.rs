@@ -0,0 +1,158 @@
+//! Purging what Schlep knows about a user, for data protection requests.
+//!
+//! A purge drops the user's cached directory entry and pseudonymizes the user
+//! in the audit log, so that the log still shows what happened but no longer
+//! who did it. It produces a [`PurgeReport`] that can be signed with a host key
+//! as evidence of what was done.
+
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use humantime_serde::re::humantime;
+use russh::keys::ssh_key::{HashAlg, LineEnding, PrivateKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{Level, event};
+
+use crate::{audit, auth::AuthClient, vfs};
+
+/// The namespace purge reports are signed in, for `ssh-keygen -Y verify -n`.
+pub const SIGNATURE_NAMESPACE: &str = "purge-report@schlep";
+
+/// What a purge did.
+#[derive(Debug, Serialize)]
+pub struct PurgeReport {
+    pub username: String,
+    /// What the user was replaced with in the audit log.
+    pub pseudonym: String,
+    pub purged_at: String,
+    /// A description of each thing that was removed or rewritten.
+    pub actions: Vec<String>,
+}
+
+impl PurgeReport {
+    /// Renders the report as JSON, followed by an SSH signature over that JSON
+    /// made with `key`.
+    pub fn sign(&self, key: &PrivateKey) -> Result<String> {
+        let report = serde_json::to_string_pretty(self)?;
+        let signature = key
+            .sign(SIGNATURE_NAMESPACE, HashAlg::Sha512, report.as_bytes())
+            .context("failed to sign purge report")?
+            .to_pem(LineEnding::LF)
+            .context("failed to encode purge report signature")?;
+
+        Ok(format!("{report}\n{signature}"))
+    }
+}
+
+/// Purges `username`.
+pub async fn purge_user(
+    username: &str,
+    auth_client: &AuthClient,
+    audit_config: &audit::Config,
+) -> Result<PurgeReport> {
+    let pseudonym = pseudonym(username);
+    let mut actions = Vec::new();
+
+    if auth_client.flush_user_cache(username).await? {
+        actions.push("removed cached directory entry".to_string());
+    }
+
+    if let Some(log_dir) = &audit_config.log_dir {
+        for file in pseudonymize_audit_log(log_dir, username, &pseudonym).await? {
+            actions.push(format!("pseudonymized audit log file {file}"));
+        }
+    }
+
+    event!(
+        target: audit::TARGET,
+        Level::INFO,
+        pseudonym,
+        actions = actions.len(),
+        "purged user"
+    );
+
+    Ok(PurgeReport {
+        username: username.to_string(),
+        pseudonym,
+        purged_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        actions,
+    })
+}
+
+/// A stable stand-in for `username` that can't be turned back into it without
+/// already knowing it.
+fn pseudonym(username: &str) -> String {
+    let digest = Sha256::digest(username.as_bytes());
+
+    format!("user-{}", vfs::to_hex(&digest[..8]))
+}
+
+/// Replaces `username` with `pseudonym` in every audit log file in `log_dir`,
+/// returning the names of the files that were changed.
+///
+/// Files are rewritten in place rather than replaced, since the server may be
+/// appending to the current one.
+async fn pseudonymize_audit_log(
+    log_dir: &Utf8Path,
+    username: &str,
+    pseudonym: &str,
+) -> Result<Vec<String>> {
+    let mut changed = Vec::new();
+    let mut entries = tokio::fs::read_dir(log_dir)
+        .await
+        .with_context(|| format!("failed to read audit log directory {log_dir}"))?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let contents = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("failed to read audit log file {}", path.display()))?;
+
+        let rewritten = replace_word(&contents, username, pseudonym);
+        if rewritten != contents {
+            tokio::fs::write(&path, rewritten)
+                .await
+                .with_context(|| format!("failed to rewrite audit log file {}", path.display()))?;
+
+            changed.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    changed.sort();
+
+    Ok(changed)
+}
+
+/// Replaces every occurrence of `word` in `text` that isn't part of a longer
+/// word, so that purging `al` leaves `alice` alone.
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find(word) {
+        let before = rest[..index].chars().next_back();
+        let after = rest[index + word.len()..].chars().next();
+
+        out.push_str(&rest[..index]);
+
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            out.push_str(word);
+        } else {
+            out.push_str(replacement);
+        }
+
+        rest = &rest[index + word.len()..];
+    }
+
+    out.push_str(rest);
+
+    out
+}
@@ -0,0 +1,133 @@
+use std::{pin::Pin, sync::Arc};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use object_store::{ObjectStore, aws::AmazonS3Builder, buffered::BufWriter};
+use russh::keys::PublicKey;
+use russh_sftp::client::SftpSession;
+use tokio::io::AsyncWrite;
+
+use super::{DestinationConfig, Error, IntoKeyError, IntoS3Error, IntoSftpError};
+use crate::ssh_client::SshClient;
+
+/// Where a pushed file is written to. The file is only complete once the
+/// writer has been shut down.
+pub(super) type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// An open connection to the destination of a push job.
+pub(super) enum Connection {
+    Sftp {
+        session: SftpSession,
+        root: Utf8PathBuf,
+        // Dropping the client would close the connection out from under the
+        // session.
+        _client: SshClient,
+    },
+    S3 {
+        store: Arc<dyn ObjectStore>,
+        prefix: String,
+    },
+}
+
+impl Connection {
+    pub(super) async fn open(config: &DestinationConfig) -> Result<Self, Error> {
+        match config {
+            DestinationConfig::Sftp {
+                url,
+                private_key_file,
+                host_key,
+            } => {
+                let root = Utf8PathBuf::from(url.path());
+                let host_key = PublicKey::from_openssh(host_key)
+                    .map_err(russh::keys::Error::from)
+                    .into_key_error("failed to parse host key")?;
+
+                let client = SshClient::connect(url, private_key_file, Some(host_key)).await?;
+                let session = SftpSession::new(client.sftp().await?)
+                    .await
+                    .into_sftp_error("failed to start SFTP session")?;
+
+                Ok(Self::Sftp {
+                    session,
+                    root,
+                    _client: client,
+                })
+            }
+            DestinationConfig::S3 {
+                bucket,
+                prefix,
+                region,
+                endpoint,
+            } => {
+                let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+                if let Some(region) = region {
+                    builder = builder.with_region(region);
+                }
+                if let Some(endpoint) = endpoint {
+                    builder = builder.with_endpoint(endpoint.as_str());
+                }
+
+                let store = builder
+                    .build()
+                    .into_s3_error("failed to configure bucket")?;
+
+                Ok(Self::S3 {
+                    store: Arc::new(store),
+                    prefix: prefix.clone(),
+                })
+            }
+        }
+    }
+
+    /// Creates the file at `path`, relative to the root of the destination,
+    /// replacing it if it already exists.
+    pub(super) async fn create(&self, path: &Utf8Path) -> Result<Writer, Error> {
+        match self {
+            Self::Sftp { session, root, .. } => {
+                let path = root.join(path);
+
+                if let Some(parent) = path.parent() {
+                    create_dirs(session, parent).await?;
+                }
+
+                let file = session
+                    .create(path.as_str())
+                    .await
+                    .into_sftp_error("failed to create file")?;
+
+                Ok(Box::pin(file))
+            }
+            Self::S3 { store, prefix } => {
+                let key = object_store::path::Path::from(format!("{prefix}{path}"));
+
+                Ok(Box::pin(BufWriter::new(store.clone(), key)))
+            }
+        }
+    }
+}
+
+/// Creates `path` and any of its parents that don't exist yet.
+async fn create_dirs(session: &SftpSession, path: &Utf8Path) -> Result<(), Error> {
+    let mut missing = Vec::new();
+
+    for ancestor in path.ancestors() {
+        if ancestor.as_str().is_empty()
+            || session
+                .try_exists(ancestor.as_str())
+                .await
+                .into_sftp_error("failed to check directory")?
+        {
+            break;
+        }
+
+        missing.push(ancestor);
+    }
+
+    for dir in missing.into_iter().rev() {
+        session
+            .create_dir(dir.as_str())
+            .await
+            .into_sftp_error("failed to create directory")?;
+    }
+
+    Ok(())
+}
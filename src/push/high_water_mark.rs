@@ -0,0 +1,88 @@
+use std::{io, time::SystemTime};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{Error, IntoIoError};
+
+/// When a push job last saw an upload, kept in
+/// [`JobConfig::high_water_mark_file`](super::JobConfig::high_water_mark_file)
+/// so that it survives restarts.
+pub(super) struct HighWaterMark {
+    file: Utf8PathBuf,
+    mark: Mutex<Option<SystemTime>>,
+    save: AsyncMutex<()>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Contents {
+    #[serde(with = "humantime_serde")]
+    high_water_mark: SystemTime,
+}
+
+impl HighWaterMark {
+    pub(super) fn new(file: Utf8PathBuf) -> Self {
+        Self {
+            file,
+            mark: Mutex::new(None),
+            save: AsyncMutex::new(()),
+        }
+    }
+
+    /// Reads the mark back from the file, if there is one yet.
+    pub(super) async fn load(&self) -> Result<Option<SystemTime>, Error> {
+        let contents = match tokio::fs::read(&self.file).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err.into_io_error(format!("failed to read {}", self.file)));
+            }
+        };
+
+        let Contents { high_water_mark } = serde_json::from_slice(&contents)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .into_io_error(format!("failed to parse {}", self.file))?;
+
+        let mut mark = self.mark.lock();
+        *mark = mark.max(Some(high_water_mark));
+
+        Ok(*mark)
+    }
+
+    /// Moves the mark up to `time` and saves it, unless it's already past it.
+    pub(super) async fn advance(&self, time: SystemTime) -> Result<(), Error> {
+        // Holds the save lock across the change, so saves land in the same order
+        // as the changes they save.
+        let _guard = self.save.lock().await;
+
+        {
+            let mut mark = self.mark.lock();
+            if mark.is_some_and(|mark| mark >= time) {
+                return Ok(());
+            }
+            *mark = Some(time);
+        }
+
+        let contents = serde_json::to_vec_pretty(&Contents {
+            high_water_mark: time,
+        })
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        .into_io_error("failed to serialize high-water mark")?;
+
+        write_atomically(&self.file, contents).await
+    }
+}
+
+/// Replaces the contents of `file`, by way of a temporary file so that a
+/// crash can't leave a truncated one behind.
+async fn write_atomically(file: &Utf8Path, contents: Vec<u8>) -> Result<(), Error> {
+    let temp_file = file.with_extension("tmp");
+    tokio::fs::write(&temp_file, contents)
+        .await
+        .into_io_error(format!("failed to write {temp_file}"))?;
+    tokio::fs::rename(&temp_file, file)
+        .await
+        .into_io_error(format!("failed to replace {file}"))
+}
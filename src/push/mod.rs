@@ -0,0 +1,208 @@
+//! Push jobs, which send files from the virtual hierarchy to a remote SFTP
+//! server or S3 bucket on the server's own initiative, rather than waiting for
+//! someone to come and download them.
+//!
+//! A job pushes everything under its source path on a cron schedule, and can
+//! also push each file under it as soon as a client finishes uploading it.
+//! Failed pushes are retried a configurable number of times, and every pushed
+//! file and every push that gave up is recorded in the audit log. Jobs that
+//! push on upload can also catch up on what they missed while Schlep wasn't
+//! running.
+
+use std::{io, path::PathBuf, time::Duration};
+
+use camino::Utf8PathBuf;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{error_code::ErrorCode, ssh_client, vfs};
+
+mod destination;
+mod high_water_mark;
+mod scheduler;
+
+pub use scheduler::Scheduler;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "push_config")]
+pub struct Config {
+    /// The push jobs to run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<JobConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "push_job_config")]
+pub struct JobConfig {
+    /// The name of the job, as it appears in logs and audit events.
+    pub name: String,
+
+    /// The path in the virtual hierarchy to push. If it is a directory, every
+    /// file under it is pushed, keeping the layout below it.
+    #[schemars(with = "String", example = "JobConfig::example_source")]
+    pub source: Utf8PathBuf,
+
+    /// Where to push the files to.
+    pub destination: DestinationConfig,
+
+    /// A cron expression, with a leading seconds field, for when to push
+    /// every file under `source`. Times are in UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "JobConfig::example_schedule")]
+    pub schedule: Option<String>,
+
+    /// Whether to push each file under `source` as soon as a client finishes
+    /// uploading it.
+    #[serde(default)]
+    pub on_upload: bool,
+
+    /// How many times to retry a push that failed before giving up on it. The
+    /// default value is 3.
+    #[serde(
+        default = "JobConfig::default_retries",
+        skip_serializing_if = "JobConfig::is_default_retries"
+    )]
+    pub retries: u32,
+
+    /// How long to wait before retrying a push that failed. The default value
+    /// is 30 seconds.
+    #[serde(
+        default = "JobConfig::default_retry_delay",
+        skip_serializing_if = "JobConfig::is_default_retry_delay",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub retry_delay: Duration,
+
+    /// A file to keep track of when the job last saw an upload in, for jobs
+    /// that push on upload. When Schlep starts, the job pushes the files under
+    /// `source` that changed since then, so that uploads that happened while
+    /// it was down, or that it didn't get around to pushing before it stopped,
+    /// aren't missed. Without it, those files wait for the next scheduled run,
+    /// if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub high_water_mark_file: Option<Utf8PathBuf>,
+}
+
+impl JobConfig {
+    fn example_source() -> &'static str {
+        "/shared/outbound"
+    }
+
+    fn example_schedule() -> &'static str {
+        "0 0 2 * * *"
+    }
+
+    fn default_retries() -> u32 {
+        3
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_retries(retries: &u32) -> bool {
+        *retries == Self::default_retries()
+    }
+
+    fn default_retry_delay() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn is_default_retry_delay(delay: &Duration) -> bool {
+        *delay == Self::default_retry_delay()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(
+    rename = "push_destination_config",
+    tag = "type",
+    rename_all = "snake_case"
+)]
+pub enum DestinationConfig {
+    /// A directory on another SFTP server.
+    Sftp {
+        /// The server, user, and directory to push to.
+        #[schemars(example = "DestinationConfig::example_sftp_url")]
+        url: Url,
+
+        /// The private key to log in with.
+        private_key_file: PathBuf,
+
+        /// The server's public host key, in OpenSSH format. Servers that
+        /// present any other key are refused.
+        host_key: String,
+    },
+    /// An S3 bucket, or a bucket on an S3-compatible store. Credentials are
+    /// taken from the usual `AWS_*` environment variables.
+    S3 {
+        bucket: String,
+
+        /// The prefix to put in front of the key of every pushed file.
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        prefix: String,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+
+        /// The endpoint of an S3-compatible store, if the bucket isn't on S3
+        /// itself.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<Url>,
+    },
+}
+
+impl DestinationConfig {
+    fn example_sftp_url() -> &'static str {
+        "sftp://schlep@partner.example.com:22/inbound"
+    }
+}
+
+#[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid schedule for push job {job}")]
+    InvalidSchedule {
+        source: cron::error::Error,
+        job: String,
+    },
+    #[error("VFS error")]
+    VfsError(#[from] vfs::Error),
+    #[error("{path} isn't in any mount")]
+    NotMounted { path: Utf8PathBuf },
+    #[error("invalid key: {from}")]
+    KeyError {
+        source: russh::keys::Error,
+        from: String,
+    },
+    #[error("SSH error")]
+    SshClientError(#[from] ssh_client::Error),
+    #[error("SFTP error: {from}")]
+    SftpError {
+        source: russh_sftp::client::error::Error,
+        from: String,
+    },
+    #[error("S3 error: {from}")]
+    S3Error {
+        source: object_store::Error,
+        from: String,
+    },
+    #[error("I/O error: {from}")]
+    IoError { source: io::Error, from: String },
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidSchedule { .. } => ErrorCode::BadRequest,
+            Self::VfsError(err) => err.code(),
+            Self::NotMounted { .. } => ErrorCode::NotFound,
+            Self::KeyError { .. } => ErrorCode::InvalidKey,
+            Self::SshClientError(err) => err.code(),
+            Self::SftpError { .. } | Self::S3Error { .. } => ErrorCode::DestinationUnavailable,
+            Self::IoError { .. } => ErrorCode::Io,
+        }
+    }
+}
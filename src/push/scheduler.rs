@@ -0,0 +1,385 @@
+use std::{str::FromStr, sync::Arc, time::SystemTime};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use metrics::counter;
+use thiserror_ext::AsReport;
+use tokio::{io::AsyncWriteExt, sync::broadcast::error::RecvError};
+use tracing::{Level, event};
+
+use super::{
+    Config,
+    Error,
+    IntoInvalidSchedule,
+    IntoIoError,
+    JobConfig,
+    destination::Connection,
+    high_water_mark::HighWaterMark,
+};
+use crate::{
+    audit,
+    metrics::Metrics,
+    transfers::{self, Direction, Transfers},
+    vfs::{self, LiveVfsSet, PathMatch, Vfs},
+};
+
+/// How much of a file is read from the VFS at a time while pushing it.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Runs the configured push jobs in the background.
+pub struct Scheduler {
+    jobs: Vec<Arc<Job>>,
+    vfs_set: LiveVfsSet,
+    transfers: Transfers,
+}
+
+struct Job {
+    config: JobConfig,
+    schedule: Option<cron::Schedule>,
+    high_water_mark: Option<HighWaterMark>,
+}
+
+impl Scheduler {
+    /// Checks the jobs in `config`. Files are read from `vfs_set`, and uploads
+    /// are watched for in `transfers`.
+    pub fn new(config: Config, vfs_set: LiveVfsSet, transfers: Transfers) -> Result<Self, Error> {
+        let jobs = config
+            .jobs
+            .into_iter()
+            .map(|config| {
+                let schedule = config
+                    .schedule
+                    .as_deref()
+                    .map(cron::Schedule::from_str)
+                    .transpose()
+                    .into_invalid_schedule(config.name.clone())?;
+
+                let high_water_mark = config
+                    .high_water_mark_file
+                    .clone()
+                    .filter(|_| config.on_upload)
+                    .map(HighWaterMark::new);
+
+                Ok(Arc::new(Job {
+                    config,
+                    schedule,
+                    high_water_mark,
+                }))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            jobs,
+            vfs_set,
+            transfers,
+        })
+    }
+
+    /// Starts running the jobs on their schedules and watching for uploads.
+    pub fn spawn(self) {
+        for job in &self.jobs {
+            if let Some(schedule) = job.schedule.clone() {
+                tokio::spawn(run_scheduled(job.clone(), schedule, self.vfs_set.clone()));
+            }
+        }
+
+        let upload_jobs = self
+            .jobs
+            .into_iter()
+            .filter(|job| job.config.on_upload)
+            .collect::<Vec<_>>();
+
+        for job in &upload_jobs {
+            if job.high_water_mark.is_some() {
+                tokio::spawn(catch_up(job.clone(), self.vfs_set.clone()));
+            }
+        }
+
+        if !upload_jobs.is_empty() {
+            tokio::spawn(run_on_upload(upload_jobs, self.vfs_set, self.transfers));
+        }
+    }
+}
+
+/// Pushes everything under the source of `job` every time `schedule` comes
+/// around.
+async fn run_scheduled(job: Arc<Job>, schedule: cron::Schedule, vfs_set: LiveVfsSet) {
+    while let Some(next) = schedule.upcoming(Utc).next() {
+        let delay = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        match files_under(&vfs_set, &job.config.source).await {
+            Ok(files) => job.push(&vfs_set, files).await,
+            Err(err) => event!(
+                target: audit::TARGET,
+                Level::WARN,
+                job = job.config.name,
+                err = %err.as_report(),
+                code = %err.code(),
+                "push failed"
+            ),
+        }
+    }
+}
+
+/// Pushes the files under the source of `job` that changed since its
+/// high-water mark, which it missed while Schlep wasn't running. A job that has
+/// no mark yet starts keeping one, without pushing anything.
+async fn catch_up(job: Arc<Job>, vfs_set: LiveVfsSet) {
+    let Some(high_water_mark) = &job.high_water_mark else {
+        return;
+    };
+
+    // Anything that changes from here on is an upload the job sees as it
+    // happens, so this is as far as catching up has to reach.
+    let started = SystemTime::now();
+
+    let result = async {
+        let Some(since) = high_water_mark.load().await? else {
+            return Ok(());
+        };
+
+        let files = files_changed_since(&vfs_set, &job.config.source, since).await?;
+        if !files.is_empty() {
+            event!(
+                Level::INFO,
+                job = job.config.name,
+                files = files.len(),
+                since = %humantime_serde::re::humantime::format_rfc3339_seconds(since),
+                "pushing files that changed while Schlep wasn't running"
+            );
+            job.push(&vfs_set, files).await;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            job = job.config.name,
+            err = %err.as_report(),
+            code = %err.code(),
+            "push failed"
+        );
+    }
+
+    job.advance_high_water_mark(started).await;
+}
+
+/// Pushes each file that is uploaded under the source of one of `jobs`.
+async fn run_on_upload(jobs: Vec<Arc<Job>>, vfs_set: LiveVfsSet, transfers: Transfers) {
+    let mut finished = transfers.subscribe();
+
+    loop {
+        let record = match finished.recv().await {
+            Ok(record) => record,
+            Err(RecvError::Lagged(skipped)) => {
+                event!(Level::WARN, skipped, "push jobs missed uploads");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if record.direction != Direction::Upload || record.status != transfers::Status::Completed {
+            continue;
+        }
+
+        for job in &jobs {
+            if record.path.starts_with(&job.config.source) {
+                let job = job.clone();
+                let vfs_set = vfs_set.clone();
+                let path = record.path.clone();
+
+                tokio::spawn(async move {
+                    job.advance_high_water_mark(SystemTime::now()).await;
+                    job.push(&vfs_set, vec![path]).await;
+                });
+            }
+        }
+    }
+}
+
+impl Job {
+    /// Moves the job's high-water mark up to `time`, if it keeps one.
+    async fn advance_high_water_mark(&self, time: SystemTime) {
+        let Some(high_water_mark) = &self.high_water_mark else {
+            return;
+        };
+
+        if let Err(err) = high_water_mark.advance(time).await {
+            event!(
+                Level::WARN,
+                job = self.config.name,
+                err = %err.as_report(),
+                "failed to save high-water mark"
+            );
+        }
+    }
+
+    /// Pushes `files`, retrying the ones that are left whenever a push fails,
+    /// until they're all done or the job runs out of retries.
+    async fn push(&self, vfs_set: &LiveVfsSet, mut files: Vec<Utf8PathBuf>) {
+        let mut attempt = 0;
+
+        loop {
+            let Err(err) = self.try_push(vfs_set, &mut files).await else {
+                return;
+            };
+
+            if attempt >= self.config.retries {
+                counter!(Metrics::PUSH_FAILURES, "job" => self.config.name.clone()).increment(1);
+                event!(
+                    target: audit::TARGET,
+                    Level::WARN,
+                    job = self.config.name,
+                    remaining = files.len(),
+                    err = %err.as_report(),
+                    code = %err.code(),
+                    "push failed"
+                );
+
+                return;
+            }
+
+            attempt += 1;
+            event!(
+                Level::INFO,
+                job = self.config.name,
+                attempt,
+                err = %err.as_report(),
+                "push failed, retrying"
+            );
+            tokio::time::sleep(self.config.retry_delay).await;
+        }
+    }
+
+    /// Pushes `files` over one connection, removing each of them from the list
+    /// once it's been pushed.
+    async fn try_push(
+        &self,
+        vfs_set: &LiveVfsSet,
+        files: &mut Vec<Utf8PathBuf>,
+    ) -> Result<(), Error> {
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        let connection = Connection::open(&self.config.destination).await?;
+
+        while let Some(path) = files.last() {
+            let size = self.push_file(vfs_set, &connection, path).await?;
+
+            counter!(Metrics::PUSHED_FILES, "job" => self.config.name.clone()).increment(1);
+            event!(
+                target: audit::TARGET,
+                Level::INFO,
+                job = self.config.name,
+                %path,
+                size,
+                "file pushed"
+            );
+
+            files.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Copies the file at the absolute virtual path `path` to the
+    /// destination, returning its size.
+    async fn push_file(
+        &self,
+        vfs_set: &LiveVfsSet,
+        connection: &Connection,
+        path: &Utf8Path,
+    ) -> Result<u64, Error> {
+        let PathMatch { vfs, relative_path } = resolve(vfs_set, path)?;
+
+        // Files keep their place below the source; a source that is a file
+        // itself lands at the root of the destination.
+        let destination_path = match path.strip_prefix(&self.config.source) {
+            Ok(rest) if !rest.as_str().is_empty() => rest,
+            _ => Utf8Path::new(path.file_name().unwrap_or_default()),
+        };
+
+        let mut writer = connection.create(destination_path).await?;
+        let handle = vfs.open(&relative_path, vfs::OpenFlags::READ).await?;
+
+        let result = async {
+            let mut offset = 0;
+
+            while let Some(chunk) = vfs.read(&handle, offset, CHUNK_SIZE).await? {
+                writer
+                    .write_all(&chunk)
+                    .await
+                    .into_io_error("failed to write to destination")?;
+                offset += chunk.len() as u64;
+
+                vfs.transferred(chunk.len() as u64).await;
+            }
+
+            writer
+                .shutdown()
+                .await
+                .into_io_error("failed to finish file at destination")?;
+
+            Ok(offset)
+        }
+        .await;
+
+        vfs.close(handle).await?;
+
+        result
+    }
+}
+
+/// Every file at or below the absolute virtual path `root`.
+async fn files_under(vfs_set: &LiveVfsSet, root: &Utf8Path) -> Result<Vec<Utf8PathBuf>, Error> {
+    let PathMatch { vfs, relative_path } = resolve(vfs_set, root)?;
+
+    Ok(vfs
+        .files_under(&relative_path)
+        .await?
+        .into_iter()
+        .map(|(path, _)| {
+            if path.as_str().is_empty() {
+                root.to_owned()
+            } else {
+                root.join(path)
+            }
+        })
+        .collect())
+}
+
+/// Every file at or below the absolute virtual path `root` that was modified
+/// after `since`.
+async fn files_changed_since(
+    vfs_set: &LiveVfsSet,
+    root: &Utf8Path,
+    since: SystemTime,
+) -> Result<Vec<Utf8PathBuf>, Error> {
+    let PathMatch { vfs, relative_path } = resolve(vfs_set, root)?;
+
+    Ok(vfs
+        .files_under(&relative_path)
+        .await?
+        .into_iter()
+        .filter(|(_, metadata)| metadata.mtime().is_some_and(|mtime| mtime > since))
+        .map(|(path, _)| {
+            if path.as_str().is_empty() {
+                root.to_owned()
+            } else {
+                root.join(path)
+            }
+        })
+        .collect())
+}
+
+fn resolve(vfs_set: &LiveVfsSet, path: &Utf8Path) -> Result<PathMatch, Error> {
+    vfs_set.resolve_path(path).ok_or_else(|| Error::NotMounted {
+        path: path.to_owned(),
+    })
+}
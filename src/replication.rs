@@ -0,0 +1,391 @@
+//! One-way replication between two places in the virtual hierarchy, for
+//! keeping a warm standby copy of a mount on secondary storage.
+//!
+//! Each configured pair is synced on an interval. Files that are missing from
+//! the destination or differ from the source in size or modification time are
+//! copied over, at no more than the pair's bandwidth limit, and the copy is
+//! only put in place once its checksum matches the source's. Files are never
+//! copied the other way, and files that only exist at the destination are left
+//! alone unless the pair is configured to delete them.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use metrics::counter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror_ext::AsReport;
+use tokio::time::MissedTickBehavior;
+use tracing::{Level, event};
+
+use crate::{
+    audit,
+    error_code::ErrorCode,
+    metrics::Metrics,
+    vfs::{self, HashAlgorithm, LiveVfsSet, Metadata, Mount, OpenFlags, PathMatch, Vfs},
+};
+
+/// How much of a file is copied at a time.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// The suffix of the temporary file a copy is written to before it's verified
+/// and renamed into place.
+const PARTIAL_SUFFIX: &str = ".schlep-partial";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "replication_config")]
+pub struct Config {
+    /// The pairs of paths to keep in sync.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pairs: Vec<PairConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "replication_pair_config")]
+pub struct PairConfig {
+    /// The directory in the virtual hierarchy to copy from.
+    #[schemars(with = "String", example = "PairConfig::example_source")]
+    pub source: Utf8PathBuf,
+
+    /// The directory in the virtual hierarchy to copy to. It should be on a
+    /// different mount than `source`, and nothing else should write to it.
+    #[schemars(with = "String", example = "PairConfig::example_destination")]
+    pub destination: Utf8PathBuf,
+
+    /// How long to wait between syncs. The default value is 5 minutes.
+    #[serde(
+        default = "PairConfig::default_interval",
+        skip_serializing_if = "PairConfig::is_default_interval",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub interval: Duration,
+
+    /// The most bytes per second to copy. Without a limit, files are copied as
+    /// fast as the storage allows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_limit: Option<u64>,
+
+    /// Whether to delete files from `destination` that no longer exist in
+    /// `source`.
+    #[serde(default)]
+    pub delete: bool,
+}
+
+impl PairConfig {
+    fn example_source() -> &'static str {
+        "/shared"
+    }
+
+    fn example_destination() -> &'static str {
+        "/standby/shared"
+    }
+
+    fn default_interval() -> Duration {
+        Duration::from_mins(5)
+    }
+
+    fn is_default_interval(interval: &Duration) -> bool {
+        *interval == Self::default_interval()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("VFS error")]
+    VfsError(#[from] vfs::Error),
+    #[error("{path} isn't in any mount")]
+    NotMounted { path: Utf8PathBuf },
+    #[error("the copy of {path} doesn't match the original")]
+    ChecksumMismatch { path: Utf8PathBuf },
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::VfsError(err) => err.code(),
+            Self::NotMounted { .. } => ErrorCode::NotFound,
+            Self::ChecksumMismatch { .. } => ErrorCode::Conflict,
+        }
+    }
+}
+
+/// Runs the configured replication pairs in the background.
+pub struct Replicator {
+    pairs: Vec<PairConfig>,
+    vfs_set: LiveVfsSet,
+}
+
+impl Replicator {
+    #[must_use]
+    pub fn new(config: Config, vfs_set: LiveVfsSet) -> Self {
+        Self {
+            pairs: config.pairs,
+            vfs_set,
+        }
+    }
+
+    /// Starts syncing every pair on its interval.
+    pub fn spawn(self) {
+        for pair in self.pairs {
+            let vfs_set = self.vfs_set.clone();
+
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(pair.interval);
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                loop {
+                    interval.tick().await;
+
+                    if let Err(err) = sync(&pair, &vfs_set).await {
+                        event!(
+                            target: audit::TARGET,
+                            Level::WARN,
+                            source = %pair.source,
+                            destination = %pair.destination,
+                            err = %err.as_report(),
+                            code = %err.code(),
+                            "replication failed"
+                        );
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// One end of a pair: the mount it's in, and where in that mount it is.
+struct Side {
+    mount: Arc<Mount>,
+    path: Utf8PathBuf,
+}
+
+impl Side {
+    fn resolve(vfs_set: &LiveVfsSet, path: &Utf8Path) -> Result<Self, Error> {
+        let Some(PathMatch { vfs, relative_path }) = vfs_set.resolve_path(path) else {
+            return Err(Error::NotMounted {
+                path: path.to_owned(),
+            });
+        };
+
+        Ok(Self {
+            mount: vfs,
+            path: relative_path,
+        })
+    }
+
+    /// The path in the mount of the file at `path` below this side.
+    fn join(&self, path: &Utf8Path) -> Utf8PathBuf {
+        self.path.join(path)
+    }
+}
+
+/// Brings the destination of `pair` in line with its source.
+async fn sync(pair: &PairConfig, vfs_set: &LiveVfsSet) -> Result<(), Error> {
+    let source = Side::resolve(vfs_set, &pair.source)?;
+    let destination = Side::resolve(vfs_set, &pair.destination)?;
+
+    let mut existing = match destination.mount.files_under(&destination.path).await {
+        Ok(files) => files.into_iter().collect::<HashMap<_, _>>(),
+        Err(vfs::Error::FileNotFound) => {
+            destination.mount.mkdir(&destination.path).await?;
+            HashMap::default()
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut limiter = pair.bandwidth_limit.map(RateLimiter::new);
+
+    for (path, metadata) in source.mount.files_under(&source.path).await? {
+        let up_to_date = existing.remove(&path).is_some_and(|existing| {
+            existing.size() == metadata.size() && existing.mtime() == metadata.mtime()
+        });
+
+        if !up_to_date {
+            copy(&source, &destination, &path, &metadata, limiter.as_mut()).await?;
+
+            counter!(Metrics::REPLICATED_FILES).increment(1);
+            event!(
+                target: audit::TARGET,
+                Level::INFO,
+                source = %pair.source.join(&path),
+                destination = %pair.destination.join(&path),
+                size = metadata.size(),
+                "file replicated"
+            );
+        }
+    }
+
+    if pair.delete {
+        for path in existing.into_keys() {
+            destination
+                .mount
+                .remove_file(&destination.join(&path))
+                .await?;
+
+            event!(
+                target: audit::TARGET,
+                Level::INFO,
+                destination = %pair.destination.join(&path),
+                "replica removed"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the file at `path` from `source` to `destination`, making sure the
+/// copy matches before putting it in place.
+async fn copy(
+    source: &Side,
+    destination: &Side,
+    path: &Utf8Path,
+    metadata: &Metadata,
+    limiter: Option<&mut RateLimiter>,
+) -> Result<(), Error> {
+    let from = source.join(path);
+    let to = destination.join(path);
+    let partial = Utf8PathBuf::from(format!("{to}{PARTIAL_SUFFIX}"));
+
+    if let Some(parent) = to.parent() {
+        create_dirs(&destination.mount, parent).await?;
+    }
+
+    copy_contents(&source.mount, &from, &destination.mount, &partial, limiter).await?;
+
+    let source_digest = source
+        .mount
+        .hash_range(&from, HashAlgorithm::Sha1, 0, 0, 0)
+        .await?;
+    let destination_digest = destination
+        .mount
+        .hash_range(&partial, HashAlgorithm::Sha1, 0, 0, 0)
+        .await?;
+    if source_digest != destination_digest {
+        destination.mount.remove_file(&partial).await?;
+
+        return Err(Error::ChecksumMismatch { path: from });
+    }
+
+    // The modification time is what tells the next sync that the copy is
+    // up to date.
+    destination
+        .mount
+        .set_times(&partial, metadata.atime(), metadata.mtime())
+        .await?;
+
+    match destination.mount.remove_file(&to).await {
+        Ok(()) | Err(vfs::Error::FileNotFound) => (),
+        Err(err) => return Err(err.into()),
+    }
+    destination.mount.rename(&partial, &to).await?;
+
+    Ok(())
+}
+
+async fn copy_contents(
+    from_mount: &Mount,
+    from: &Utf8Path,
+    to_mount: &Mount,
+    to: &Utf8Path,
+    mut limiter: Option<&mut RateLimiter>,
+) -> Result<(), Error> {
+    let reader = from_mount.open(from, OpenFlags::READ).await?;
+    let writer = match to_mount
+        .open(
+            to,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        )
+        .await
+    {
+        Ok(writer) => writer,
+        Err(err) => {
+            from_mount.close(reader).await?;
+            return Err(err.into());
+        }
+    };
+
+    let result = async {
+        let mut offset = 0;
+
+        while let Some(chunk) = from_mount.read(&reader, offset, CHUNK_SIZE).await? {
+            to_mount.write(&writer, offset, &chunk).await?;
+            offset += chunk.len() as u64;
+
+            from_mount.transferred(chunk.len() as u64).await;
+            to_mount.transferred(chunk.len() as u64).await;
+
+            if let Some(limiter) = limiter.as_deref_mut() {
+                limiter.consume(chunk.len() as u64).await;
+            }
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    from_mount.close(reader).await?;
+    to_mount.close(writer).await?;
+
+    result
+}
+
+/// Creates `path` and any of its parents that don't exist yet.
+async fn create_dirs(mount: &Mount, path: &Utf8Path) -> Result<(), Error> {
+    let mut missing = Vec::new();
+
+    for ancestor in path.ancestors() {
+        if ancestor.as_str().is_empty() {
+            break;
+        }
+
+        match mount.stat(ancestor).await {
+            Ok(_) => break,
+            Err(vfs::Error::FileNotFound) => missing.push(ancestor),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    for dir in missing.into_iter().rev() {
+        mount.mkdir(dir).await?;
+    }
+
+    Ok(())
+}
+
+/// Keeps a sync under its bandwidth limit by sleeping whenever it gets ahead
+/// of it.
+struct RateLimiter {
+    bytes_per_second: u64,
+    started_at: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            started_at: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+
+        #[allow(clippy::cast_precision_loss)]
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_second as f64);
+
+        if let Some(ahead) = due.checked_sub(self.started_at.elapsed()) {
+            tokio::time::sleep(ahead).await;
+        }
+    }
+}
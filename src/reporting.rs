@@ -0,0 +1,287 @@
+//! Reports incidents that need someone's attention, like panics, to Sentry or
+//! to a webhook, so that they don't go unnoticed in the logs.
+//!
+//! Reporting is set up once for the whole process with [`Config::init`], which
+//! also installs a panic hook, and after that anything can call [`report`].
+//! Reports are tagged with the version that Schlep was built from.
+
+use std::{panic::PanicHookInfo, sync::OnceLock, time::SystemTime};
+
+use humantime_serde::re::humantime;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use thiserror_ext::AsReport;
+use tracing::{Level, event};
+use url::Url;
+
+use crate::{error_code::ErrorCode, version::VERSION_INFO};
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "reporting_config")]
+pub struct Config {
+    /// The DSN of a Sentry project to send reports to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "Config::example_sentry_dsn")]
+    pub sentry_dsn: Option<Url>,
+
+    /// A URL to `POST` each report to, as JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<Url>,
+
+    /// The environment to tag reports with, like `production`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+}
+
+impl Config {
+    fn example_sentry_dsn() -> &'static str {
+        "https://0123456789abcdef@o0.ingest.sentry.io/0"
+    }
+
+    /// Turns reporting on for the whole process if anywhere to send reports
+    /// is configured, and starts reporting panics.
+    pub fn init(&self) -> Result<(), Error> {
+        if self.sentry_dsn.is_none() && self.webhook_url.is_none() {
+            return Ok(());
+        }
+
+        let reporter = Reporter {
+            client: reqwest::Client::builder()
+                .user_agent(format!("{}/{}", VERSION_INFO.name, VERSION_INFO.version))
+                .build()?,
+            sentry: self.sentry_dsn.as_ref().map(Sentry::new).transpose()?,
+            webhook_url: self.webhook_url.clone(),
+            environment: self.environment.clone(),
+        };
+
+        if REPORTER.set(reporter).is_err() {
+            event!(Level::WARN, "reporting was already initialized");
+            return Ok(());
+        }
+
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            report(Incident::Panic, panic_message(info));
+            previous_hook(info);
+        }));
+
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid Sentry DSN: {0}")]
+    InvalidSentryDsn(Url),
+    #[error("HTTP error")]
+    HttpError(#[from] reqwest::Error),
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidSentryDsn(_) => ErrorCode::BadRequest,
+            Self::HttpError(_) => ErrorCode::Io,
+        }
+    }
+}
+
+/// The kinds of things that get reported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Incident {
+    /// A task panicked.
+    Panic,
+    /// The server failed to start, or stopped because of an error.
+    Fatal,
+    /// A client crossed the reconnect threshold, and is probably stuck
+    /// reconnecting over and over.
+    ReconnectStorm,
+}
+
+impl Incident {
+    /// How bad this kind of incident is, as a Sentry level.
+    #[must_use]
+    pub fn level(self) -> &'static str {
+        match self {
+            Self::Panic => "error",
+            Self::Fatal => "fatal",
+            Self::ReconnectStorm => "warning",
+        }
+    }
+}
+
+/// Reports `message` in the background, if reporting is turned on.
+pub fn report(incident: Incident, message: impl Into<String>) {
+    let Some(reporter) = REPORTER.get() else {
+        return;
+    };
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+
+    let message = message.into();
+    runtime.spawn(reporter.send(incident, message));
+}
+
+/// Reports `message` and waits for it to be sent, for when the process is
+/// about to exit.
+pub async fn report_and_wait(incident: Incident, message: impl Into<String>) {
+    if let Some(reporter) = REPORTER.get() {
+        reporter.send(incident, message.into()).await;
+    }
+}
+
+struct Reporter {
+    client: reqwest::Client,
+    sentry: Option<Sentry>,
+    webhook_url: Option<Url>,
+    environment: Option<String>,
+}
+
+impl Reporter {
+    async fn send(&self, incident: Incident, message: String) {
+        let report = Report {
+            event_id: format!("{:032x}", rand::random::<u128>()),
+            timestamp: humantime::format_rfc3339(SystemTime::now()).to_string(),
+            incident,
+            level: incident.level(),
+            message,
+            release: format!("{}@{}", VERSION_INFO.name, VERSION_INFO.version),
+            git_commit_sha: VERSION_INFO.git_commit_sha,
+            environment: self.environment.clone(),
+        };
+
+        if let Some(sentry) = &self.sentry
+            && let Err(err) = sentry.send(&self.client, &report).await
+        {
+            event!(Level::WARN, err = %err.as_report(), "failed to report to Sentry");
+        }
+
+        if let Some(webhook_url) = &self.webhook_url {
+            let result = self
+                .client
+                .post(webhook_url.clone())
+                .json(&report)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+
+            if let Err(err) = result {
+                event!(Level::WARN, err = %err.as_report(), "failed to report to webhook");
+            }
+        }
+    }
+}
+
+/// A report, as it's sent to webhooks.
+#[derive(Serialize)]
+struct Report {
+    event_id: String,
+    timestamp: String,
+    incident: Incident,
+    level: &'static str,
+    message: String,
+    release: String,
+    git_commit_sha: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    environment: Option<String>,
+}
+
+/// Where to send reports in Sentry, from its DSN.
+struct Sentry {
+    envelope_url: Url,
+    public_key: String,
+}
+
+impl Sentry {
+    fn new(dsn: &Url) -> Result<Self, Error> {
+        let invalid = || Error::InvalidSentryDsn(dsn.clone());
+
+        let public_key = dsn.username();
+        let (prefix, project_id) = dsn
+            .path()
+            .trim_end_matches('/')
+            .rsplit_once('/')
+            .ok_or_else(invalid)?;
+        if public_key.is_empty() || project_id.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut envelope_url = dsn.clone();
+        envelope_url.set_username("").map_err(|()| invalid())?;
+        envelope_url.set_password(None).map_err(|()| invalid())?;
+        envelope_url.set_path(&format!("{prefix}/api/{project_id}/envelope/"));
+
+        Ok(Self {
+            envelope_url,
+            public_key: public_key.to_owned(),
+        })
+    }
+
+    async fn send(&self, client: &reqwest::Client, report: &Report) -> Result<(), Error> {
+        let header = json!({
+            "event_id": report.event_id,
+            "sent_at": report.timestamp,
+        });
+        let event = json!({
+            "event_id": report.event_id,
+            "timestamp": report.timestamp,
+            "level": report.level,
+            "platform": "other",
+            "logger": "schlep",
+            "release": report.release,
+            "environment": report.environment,
+            "message": { "formatted": report.message },
+            "tags": {
+                "incident": report.incident,
+                "git_commit_sha": report.git_commit_sha,
+                "rustc_version": VERSION_INFO.rustc.version,
+                "build_target": VERSION_INFO.build.target,
+            },
+        });
+        let envelope = format!("{header}\n{{\"type\":\"event\"}}\n{event}\n");
+
+        client
+            .post(self.envelope_url.clone())
+            .header(
+                "X-Sentry-Auth",
+                format!(
+                    "Sentry sentry_version=7, sentry_key={}, sentry_client={}/{}",
+                    self.public_key, VERSION_INFO.name, VERSION_INFO.version
+                ),
+            )
+            .header(http::header::CONTENT_TYPE, "application/x-sentry-envelope")
+            .body(envelope)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+fn panic_message(info: &PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        *message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.as_str()
+    } else {
+        "unknown panic"
+    };
+
+    let thread = std::thread::current();
+    let thread = thread.name().unwrap_or("<unnamed>");
+
+    match info.location() {
+        Some(location) => format!("thread '{thread}' panicked at {location}: {message}"),
+        None => format!("thread '{thread}' panicked: {message}"),
+    }
+}
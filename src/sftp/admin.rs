@@ -0,0 +1,508 @@
+//! The `schlep-admin` subsystem, a small line-based command interface for
+//! administering a running server over SSH itself. It is only served if it is
+//! listed in [`Config::subsystems`], and only to members of
+//! [`Config::admin_group`].
+//!
+//! Every command is answered with its output, followed by a line reading
+//! either `ok` or `error [<code>]: ` and a description of what went wrong,
+//! where `<code>` is the [`ErrorCode`] of the failure.
+
+use std::{fmt::Write as _, time::Duration};
+
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use humantime_serde::re::humantime;
+use russh::{ChannelStream, server::Msg};
+use thiserror_ext::AsReport;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::{Level, event};
+
+use super::{Config, Error, Subsystem, SubsystemContext, error::IntoIoError, sessions::Sessions};
+use crate::{
+    audit,
+    auth::AuthClient,
+    error_code::ErrorCode,
+    gateway::Links,
+    log_filter,
+    transfers::{TransferFilter, Transfers},
+    vfs::PathMatch,
+};
+
+const HELP: &str = "\
+help                 show this message
+sessions             list connected sessions, with how many protocol
+                     violations each has made
+mounts               list mounted VFS roots
+flush-cache <user>   drop the cached directory entry for a user
+holds                list paths under legal hold
+hold <path>          place a path under legal hold
+release <path>       release the legal hold on a path
+transfers [<filter>=<value>...]
+                     list past transfers, newest first, filtered by user=,
+                     path=, direction=, or status=, and paged with limit=
+                     (default 50) and offset=
+link <upload|download> <path> [<lifetime>]
+                     create an HTTP gateway link for uploading to or
+                     downloading from a path, valid for a day by default
+log-level [<directives> [<duration>] | reset]
+                     show the log filter, add directives like
+                     schlep::sftp=debug to it, for a duration if one is given,
+                     or reset it to what it was at startup
+trace [user=<name>|ip=<address> [<duration>]]
+                     list traced users and addresses, or log every SFTP
+                     request from one in full detail, for 10 minutes by
+                     default and at most an hour
+untrace user=<name>|ip=<address>
+                     stop tracing a user or address
+quit                 end the admin session
+";
+
+pub(super) struct AdminSubsystem {
+    pub(super) config: Config,
+    pub(super) auth_client: AuthClient,
+    pub(super) sessions: Sessions,
+    pub(super) transfers: Transfers,
+    pub(super) links: Option<Links>,
+}
+
+impl AdminSubsystem {
+    pub(super) const NAME: &str = "schlep-admin";
+
+    async fn is_admin(&self, username: &str) -> Result<bool, Error> {
+        match &self.config.admin_group {
+            Some(group) => Ok(self.auth_client.is_member_of(username, group).await?),
+            None => Ok(false),
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn execute(
+        &self,
+        context: &SubsystemContext,
+        command: &str,
+        args: &[&str],
+    ) -> Result<String, CommandError> {
+        match (command, args) {
+            ("help", []) => Ok(HELP.to_string()),
+            ("sessions", []) => {
+                let mut out = String::new();
+
+                for session in self.sessions.list() {
+                    let _ = writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}\t{}",
+                        session.id,
+                        session.username.as_deref().unwrap_or("-"),
+                        session
+                            .peer_addr
+                            .map_or_else(|| "-".to_string(), |addr| addr.to_string()),
+                        humantime::format_rfc3339_seconds(session.connected_at),
+                        session.protocol_violations,
+                    );
+                }
+
+                Ok(out)
+            }
+            ("mounts", []) => {
+                let mut roots = context
+                    .vfs_set
+                    .mounts()
+                    .map(|(root, mount)| (root.to_owned(), mount.options().exclusive_writes))
+                    .collect::<Vec<_>>();
+                roots.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+                let mut out = String::new();
+                for (root, exclusive_writes) in roots {
+                    let _ = writeln!(out, "{root}\texclusive_writes={exclusive_writes:?}");
+                }
+
+                Ok(out)
+            }
+            ("flush-cache", [username]) => {
+                match self.auth_client.flush_user_cache(username).await {
+                    Ok(true) => Ok(format!("flushed cache for {username}\n")),
+                    Ok(false) => Ok(format!("nothing cached for {username}\n")),
+                    Err(err) => Err(CommandError::new(err.code(), err.to_report_string())),
+                }
+            }
+            ("holds", []) => {
+                let mut holds = context
+                    .vfs_set
+                    .mounts()
+                    .flat_map(|(root, mount)| {
+                        mount
+                            .holds()
+                            .into_iter()
+                            .map(|path| root.join(path))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+                holds.sort();
+
+                let mut out = String::new();
+                for path in holds {
+                    let _ = writeln!(out, "{path}");
+                }
+
+                Ok(out)
+            }
+            ("hold", [path]) => {
+                let path_match = resolve(context, path)?;
+
+                match path_match.vfs.hold(&path_match.relative_path).await {
+                    Ok(true) => Ok(format!("placed {path} under legal hold\n")),
+                    Ok(false) => Ok(format!("{path} was already under legal hold\n")),
+                    Err(err) => Err(CommandError::new(err.code(), err.to_report_string())),
+                }
+            }
+            ("release", [path]) => {
+                let path_match = resolve(context, path)?;
+
+                match path_match.vfs.release_hold(&path_match.relative_path).await {
+                    Ok(true) => Ok(format!("released legal hold on {path}\n")),
+                    Ok(false) => Ok(format!("{path} wasn't under legal hold\n")),
+                    Err(err) => Err(CommandError::new(err.code(), err.to_report_string())),
+                }
+            }
+            ("transfers", args) => {
+                let (filter, offset, limit) = parse_transfer_query(args)?;
+                let records = self
+                    .transfers
+                    .list(&filter, offset, limit)
+                    .await
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                let mut out = String::new();
+                for record in records {
+                    // Nobody needs a transfer's duration to the nanosecond.
+                    let duration = Duration::from_millis(
+                        record.duration.as_millis().try_into().unwrap_or(u64::MAX),
+                    );
+
+                    let _ = writeln!(
+                        out,
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        record.id,
+                        humantime::format_rfc3339_seconds(record.started_at),
+                        record.username,
+                        record.direction,
+                        record.path,
+                        record.size,
+                        humantime::format_duration(duration),
+                        record.status,
+                        record.checksum.as_deref().unwrap_or("-"),
+                    );
+                }
+
+                Ok(out)
+            }
+            ("link", [direction, path, lifetime @ ..]) => {
+                let Some(links) = &self.links else {
+                    return Err(CommandError::new(
+                        ErrorCode::Unsupported,
+                        "the HTTP gateway isn't configured".to_string(),
+                    ));
+                };
+
+                let bad_request =
+                    |message: String| CommandError::new(ErrorCode::BadRequest, message);
+                let direction = direction.parse().map_err(bad_request)?;
+                let lifetime = match lifetime {
+                    [] => DEFAULT_LINK_LIFETIME,
+                    [lifetime] => humantime::parse_duration(lifetime)
+                        .map_err(|err| bad_request(format!("invalid lifetime: {err}")))?,
+                    _ => {
+                        return Err(bad_request(format!(
+                            "wrong arguments for {command}, see help"
+                        )));
+                    }
+                };
+
+                resolve(context, path)?;
+                let (link, url) = links
+                    .create(Utf8Path::new(path), direction, lifetime)
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                event!(
+                    target: audit::TARGET,
+                    Level::INFO,
+                    username = context.username,
+                    link = link.id,
+                    path = %link.path,
+                    %direction,
+                    expires_at = %humantime::format_rfc3339_seconds(link.expires_at),
+                    "link created"
+                );
+
+                Ok(format!(
+                    "{url}\t{}\n",
+                    humantime::format_rfc3339_seconds(link.expires_at)
+                ))
+            }
+            ("log-level", []) => {
+                let (base, extra) = log_filter::current()
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                Ok(format!(
+                    "base\t{base}\nadded\t{}\n",
+                    extra.as_deref().unwrap_or("-")
+                ))
+            }
+            ("log-level", ["reset"]) => {
+                log_filter::reset()
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                Ok("reset log filter\n".to_string())
+            }
+            ("log-level", [directives, duration @ ..]) => {
+                let duration = match duration {
+                    [] => None,
+                    [duration] => Some(humantime::parse_duration(duration).map_err(|err| {
+                        CommandError::new(ErrorCode::BadRequest, format!("invalid duration: {err}"))
+                    })?),
+                    _ => {
+                        return Err(CommandError::new(
+                            ErrorCode::BadRequest,
+                            format!("wrong arguments for {command}, see help"),
+                        ));
+                    }
+                };
+
+                log_filter::set(directives, duration)
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                Ok(format!("added {directives} to log filter\n"))
+            }
+            ("trace", []) => {
+                let traces = log_filter::traces()
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                let mut out = String::new();
+                for (subject, ends_at) in traces {
+                    let _ = writeln!(
+                        out,
+                        "{subject}\t{}",
+                        humantime::format_rfc3339_seconds(ends_at)
+                    );
+                }
+
+                Ok(out)
+            }
+            ("trace", [subject, duration @ ..]) => {
+                let subject = subject
+                    .parse::<log_filter::TraceSubject>()
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+                let duration = match duration {
+                    [] => log_filter::DEFAULT_TRACE_DURATION,
+                    [duration] => humantime::parse_duration(duration).map_err(|err| {
+                        CommandError::new(ErrorCode::BadRequest, format!("invalid duration: {err}"))
+                    })?,
+                    _ => {
+                        return Err(CommandError::new(
+                            ErrorCode::BadRequest,
+                            format!("wrong arguments for {command}, see help"),
+                        ));
+                    }
+                };
+
+                let ends_at = log_filter::trace(subject.clone(), duration)
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                event!(
+                    target: audit::TARGET,
+                    Level::INFO,
+                    username = context.username,
+                    %subject,
+                    ends_at = %humantime::format_rfc3339_seconds(ends_at),
+                    "trace started"
+                );
+
+                Ok(format!(
+                    "tracing {subject} until {}\n",
+                    humantime::format_rfc3339_seconds(ends_at)
+                ))
+            }
+            ("untrace", [subject]) => {
+                let subject = subject
+                    .parse::<log_filter::TraceSubject>()
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?;
+
+                if log_filter::untrace(&subject)
+                    .map_err(|err| CommandError::new(err.code(), err.to_report_string()))?
+                {
+                    Ok(format!("stopped tracing {subject}\n"))
+                } else {
+                    Ok(format!("{subject} wasn't being traced\n"))
+                }
+            }
+            (
+                "help" | "sessions" | "mounts" | "flush-cache" | "holds" | "hold" | "release"
+                | "link" | "untrace",
+                _,
+            ) => Err(CommandError::new(
+                ErrorCode::BadRequest,
+                format!("wrong arguments for {command}, see help"),
+            )),
+            _ => Err(CommandError::new(
+                ErrorCode::Unsupported,
+                format!("unknown command {command}, see help"),
+            )),
+        }
+    }
+}
+
+/// Why an admin command failed.
+struct CommandError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl CommandError {
+    fn new(code: ErrorCode, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+/// How many transfers the `transfers` command lists unless it's given a limit.
+const DEFAULT_TRANSFERS_LIMIT: usize = 50;
+
+/// How long links made with the `link` command are valid for unless they're
+/// given a lifetime.
+const DEFAULT_LINK_LIFETIME: Duration = Duration::from_hours(24);
+
+/// Parses the `<filter>=<value>` arguments of the `transfers` command into a
+/// filter, an offset, and a limit.
+fn parse_transfer_query(args: &[&str]) -> Result<(TransferFilter, usize, usize), CommandError> {
+    let mut filter = TransferFilter::default();
+    let mut offset = 0;
+    let mut limit = DEFAULT_TRANSFERS_LIMIT;
+
+    for arg in args {
+        let bad_request = |message: String| CommandError::new(ErrorCode::BadRequest, message);
+
+        let Some((key, value)) = arg.split_once('=') else {
+            return Err(bad_request(format!("expected <filter>=<value>, got {arg}")));
+        };
+
+        match key {
+            "user" => filter.username = Some(value.to_string()),
+            "path" => filter.path = Some(Utf8PathBuf::from(value)),
+            "direction" => filter.direction = Some(value.parse().map_err(bad_request)?),
+            "status" => filter.status = Some(value.parse().map_err(bad_request)?),
+            "offset" | "limit" => {
+                let value = value
+                    .parse()
+                    .map_err(|_| bad_request(format!("{key} must be a number, got {value}")))?;
+
+                if key == "offset" {
+                    offset = value;
+                } else {
+                    limit = value;
+                }
+            }
+            _ => return Err(bad_request(format!("unknown filter {key}, see help"))),
+        }
+    }
+
+    Ok((filter, offset, limit))
+}
+
+/// Finds the mount that the absolute virtual path `path` is in.
+fn resolve(context: &SubsystemContext, path: &str) -> Result<PathMatch, CommandError> {
+    let path = Utf8Path::new(path);
+
+    if !path.is_absolute() {
+        return Err(CommandError::new(
+            ErrorCode::InvalidPath,
+            format!("{path} isn't an absolute path"),
+        ));
+    }
+
+    context
+        .vfs_set
+        .resolve_path(path)
+        .ok_or_else(|| CommandError::new(ErrorCode::NotFound, format!("{path} isn't in any mount")))
+}
+
+#[async_trait]
+impl Subsystem for AdminSubsystem {
+    async fn run(
+        &self,
+        context: SubsystemContext,
+        stream: ChannelStream<Msg>,
+    ) -> Result<(), Error> {
+        let (reader, mut writer) = tokio::io::split(stream);
+
+        if !self.is_admin(&context.username).await? {
+            event!(
+                target: audit::TARGET,
+                Level::WARN,
+                username = context.username,
+                "admin subsystem refused"
+            );
+
+            writer
+                .write_all(b"error [permission_denied]: permission denied\n")
+                .await
+                .into_io_error("failed to write admin reply")?;
+
+            return writer
+                .shutdown()
+                .await
+                .into_io_error("failed to close admin session");
+        }
+
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .into_io_error("failed to read admin command")?
+        {
+            let mut words = line.split_whitespace();
+            let Some(command) = words.next() else {
+                continue;
+            };
+            let args = words.collect::<Vec<_>>();
+
+            if command == "quit" {
+                break;
+            }
+
+            event!(
+                target: audit::TARGET,
+                Level::INFO,
+                username = context.username,
+                command,
+                ?args,
+                "admin command"
+            );
+
+            let reply = match self.execute(&context, command, &args).await {
+                Ok(output) => format!("{output}ok\n"),
+                Err(CommandError { code, message }) => {
+                    event!(
+                        target: audit::TARGET,
+                        Level::INFO,
+                        username = context.username,
+                        command,
+                        %code,
+                        "admin command failed"
+                    );
+
+                    format!("error [{code}]: {message}\n")
+                }
+            };
+
+            writer
+                .write_all(reply.as_bytes())
+                .await
+                .into_io_error("failed to write admin reply")?;
+        }
+
+        writer
+            .shutdown()
+            .await
+            .into_io_error("failed to close admin session")
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::PathBuf,
+    time::Duration,
 };
 
 use schemars::JsonSchema;
@@ -21,7 +22,28 @@ pub struct Config {
 
     /// Path to a directory containing OpenSSH-formatted private keys for the
     /// host to advertise to clients.
-    pub private_host_key_dir: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_host_key_dir: Option<PathBuf>,
+
+    /// Path to a single file containing one or more OpenSSH-formatted private
+    /// keys for the host, one after the other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_host_key_file: Option<PathBuf>,
+
+    /// Names of environment variables that each contain one or more
+    /// OpenSSH-formatted private keys for the host.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub private_host_key_env: Vec<String>,
+
+    /// The passphrase that encrypted host keys are protected with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_host_key_passphrase: Option<String>,
+
+    /// Path to a file containing the passphrase that encrypted host keys are
+    /// protected with, such as a mounted secret. Ignored if
+    /// `private_host_key_passphrase` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_host_key_passphrase_file: Option<PathBuf>,
 
     /// Allow clients to authenticate with their passwords.
     #[serde_inline_default(false)]
@@ -31,18 +53,364 @@ pub struct Config {
     #[serde_inline_default(true)]
     pub allow_publickey: bool,
 
+    /// The permissions shown for files whose backend doesn't keep any.
     #[serde_inline_default(0o666)]
     pub default_file_mode: u32,
 
+    /// The permissions shown for directories whose backend doesn't keep any.
     #[serde_inline_default(0o777)]
     pub default_dir_mode: u32,
+
+    /// The longest a single filesystem operation may take before it is
+    /// abandoned and the client gets an error instead. A handle whose operation
+    /// was abandoned can only be closed afterwards. By default, operations may
+    /// take as long as they need.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub op_timeout: Option<Duration>,
+
+    /// A message to show to clients that try to open an interactive shell,
+    /// which isn't supported, before the request is refused. Without one, the
+    /// request is refused silently and the client shows its own error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_interactive_message: Option<String>,
+
+    /// The SSH subsystems clients may request. Besides the built-in `sftp`
+    /// subsystem, this can name subsystems registered by an embedder.
+    #[serde(default = "Config::default_subsystems")]
+    pub subsystems: Vec<String>,
+
+    /// The DN of the LDAP group whose members may use the `schlep-admin`
+    /// subsystem. Without one, nobody may use it, even if it is listed in
+    /// `subsystems`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_group: Option<String>,
+
+    /// Destinations that clients may open `direct-tcpip` channels (`ssh -L`)
+    /// to. Any other port forwarding, and agent forwarding, is always refused.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_direct_tcpip: Vec<ForwardDestination>,
+
+    /// How many times a client may connect from the same address, or as the
+    /// same user, within a minute before an audit warning is logged. By
+    /// default, no warning is ever logged, but reconnects are still counted
+    /// in the metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_storm_threshold: Option<u32>,
+
+    /// What to do with an uploaded file that doesn't match the SHA-256 its
+    /// client declared for it with the `expect-sha256@schlep` extension.
+    #[serde(default)]
+    pub checksum_mismatch: ChecksumMismatch,
+
+    /// Limits on how many sessions a user may have at once. The first policy
+    /// that applies to a user is the one enforced when they log in, and users
+    /// that none applies to may have any number of sessions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub concurrent_logins: Vec<ConcurrentLoginPolicy>,
+
+    /// Limits on operations that are expensive for the server, so that one
+    /// client can't keep the disks busy for everyone else. By default, nothing
+    /// is limited.
+    #[serde(default)]
+    pub expensive_operations: ExpensiveOperationLimits,
+
+    /// Limits on how much each session may have going on at once, so that one
+    /// client can't take up an unbounded amount of the server's memory.
+    #[serde(default)]
+    pub session_limits: SessionLimits,
+
+    /// How many bytes per second each session may read and write. Transfers
+    /// over the limit are slowed down rather than refused. By default, sessions
+    /// aren't limited on their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_bandwidth_limit: Option<u64>,
+
+    /// How many bytes per second all of a user's sessions together may read
+    /// and write. By default, users aren't limited on their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_bandwidth_limit: Option<u64>,
+
+    /// Limits on how big the files users upload may get. The first limit that
+    /// applies to a user is the one enforced, on top of the `max_file_size` of
+    /// the mount they upload to. Users that none applies to are only held to
+    /// the mounts' limits.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_size_limits: Vec<FileSizeLimit>,
+
+    /// A user for black-box monitors to log in as. It's checked against its
+    /// own keys, without asking LDAP or Redis, and only sees an empty
+    /// filesystem in memory. Its sessions aren't held to the concurrent login
+    /// policies and aren't counted as reconnects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe_user: Option<ProbeUser>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "probe_user")]
+pub struct ProbeUser {
+    /// The name the monitor logs in with. It shadows any other user of the
+    /// same name.
+    pub username: String,
+
+    /// The SSH public keys the monitor may log in with, in OpenSSH format.
+    pub public_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "expensive_operation_limits")]
+pub struct ExpensiveOperationLimits {
+    /// Hashing a whole file, for the `md5sum`, `sha1sum`, `sha256sum`, and
+    /// `sha512sum` commands and to check an upload against the SHA-256 its
+    /// client declared for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<OperationLimit>,
+
+    /// Listing a directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_dir: Option<OperationLimit>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "session_limits")]
+pub struct SessionLimits {
+    /// How many requests a session may have in flight at once. Once it has
+    /// this many, nothing more is read from it until one of them is answered,
+    /// which holds the client up. The default value is 128.
+    #[serde(
+        default = "SessionLimits::default_max_requests",
+        skip_serializing_if = "SessionLimits::is_default_max_requests"
+    )]
+    pub max_requests: usize,
+
+    /// How many bytes of writes a session may have in flight at once, before
+    /// nothing more is read from it. The default value is 33554432 (32 MiB).
+    #[serde(
+        default = "SessionLimits::default_max_write_bytes",
+        skip_serializing_if = "SessionLimits::is_default_max_write_bytes"
+    )]
+    pub max_write_bytes: u64,
+
+    /// How many files and directories a session may have open at once.
+    /// Opening any more fails until some of them are closed. The default value
+    /// is 1024.
+    #[serde(
+        default = "SessionLimits::default_max_open_handles",
+        skip_serializing_if = "SessionLimits::is_default_max_open_handles"
+    )]
+    pub max_open_handles: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "operation_limit")]
+pub struct OperationLimit {
+    /// How many of these operations each session may start per `period`.
+    /// Without one, sessions aren't limited on their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_rate: Option<u32>,
+
+    /// How many of these operations all sessions together may start per
+    /// `period`. Without one, there is no limit across sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub global_rate: Option<u32>,
+
+    /// The period the rates are over. A whole period's worth of operations may
+    /// start at once, and after that they start evenly spread out over the
+    /// period. The default value is 1 second.
+    #[serde(
+        default = "OperationLimit::default_period",
+        skip_serializing_if = "OperationLimit::is_default_period",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub period: Duration,
+
+    /// How long an operation over a limit may wait for its turn. Operations
+    /// whose turn is further off are refused instead. The default value is 5
+    /// seconds.
+    #[serde(
+        default = "OperationLimit::default_max_wait",
+        skip_serializing_if = "OperationLimit::is_default_max_wait",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub max_wait: Duration,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "concurrent_login_policy")]
+pub struct ConcurrentLoginPolicy {
+    /// The users the policy applies to. A policy without `users` or `group`
+    /// applies to everyone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+
+    /// The DN of an LDAP group whose members the policy also applies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "ConcurrentLoginPolicy::example_group")]
+    pub group: Option<String>,
+
+    /// How many sessions a user may have at once. The default value is 1.
+    #[serde(
+        default = "ConcurrentLoginPolicy::default_max_sessions",
+        skip_serializing_if = "ConcurrentLoginPolicy::is_default_max_sessions"
+    )]
+    pub max_sessions: usize,
+
+    /// What happens when a user who already has `max_sessions` sessions logs
+    /// in again.
+    pub action: ConcurrentLoginAction,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "file_size_limit")]
+pub struct FileSizeLimit {
+    /// The users the limit applies to. A limit without `users` or `group`
+    /// applies to everyone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+
+    /// The DN of an LDAP group whose members the limit also applies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "FileSizeLimit::example_group")]
+    pub group: Option<String>,
+
+    /// How many bytes a file may hold at most.
+    pub max_file_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "concurrent_login_action", rename_all = "snake_case")]
+pub enum ConcurrentLoginAction {
+    /// The new session is let in anyway, which exempts the users from any
+    /// policy further down the list.
+    Allow,
+    /// The new session is refused.
+    Reject,
+    /// The user's oldest sessions are disconnected to make room for the new
+    /// one.
+    Takeover,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "checksum_mismatch", rename_all = "snake_case")]
+pub enum ChecksumMismatch {
+    /// The file is removed.
+    #[default]
+    Reject,
+    /// The file is renamed to its name followed by `.quarantined`, next to
+    /// where it was uploaded, for someone to look at.
+    Quarantine,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "forward_destination")]
+pub struct ForwardDestination {
+    /// The host name or address, exactly as the client asks for it.
+    pub host: String,
+
+    /// The port on that host.
+    pub port: u16,
+}
+
+impl ForwardDestination {
+    #[must_use]
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.eq_ignore_ascii_case(host) && self.port == port
+    }
+}
+
+impl ConcurrentLoginPolicy {
+    fn example_group() -> &'static str {
+        "cn=partner-appliances,ou=groups,dc=example,dc=com"
+    }
+
+    fn default_max_sessions() -> usize {
+        1
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_sessions(max_sessions: &usize) -> bool {
+        *max_sessions == Self::default_max_sessions()
+    }
+}
+
+impl FileSizeLimit {
+    fn example_group() -> &'static str {
+        "cn=bulk-uploaders,ou=groups,dc=example,dc=com"
+    }
+}
+
+impl SessionLimits {
+    fn default_max_requests() -> usize {
+        128
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_requests(max_requests: &usize) -> bool {
+        *max_requests == Self::default_max_requests()
+    }
+
+    fn default_max_write_bytes() -> u64 {
+        32 * 1024 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_write_bytes(max_write_bytes: &u64) -> bool {
+        *max_write_bytes == Self::default_max_write_bytes()
+    }
+
+    fn default_max_open_handles() -> usize {
+        1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_open_handles(max_open_handles: &usize) -> bool {
+        *max_open_handles == Self::default_max_open_handles()
+    }
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            max_requests: Self::default_max_requests(),
+            max_write_bytes: Self::default_max_write_bytes(),
+            max_open_handles: Self::default_max_open_handles(),
+        }
+    }
+}
+
+impl OperationLimit {
+    fn default_period() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn is_default_period(period: &Duration) -> bool {
+        *period == Self::default_period()
+    }
+
+    fn default_max_wait() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn is_default_max_wait(max_wait: &Duration) -> bool {
+        *max_wait == Self::default_max_wait()
+    }
 }
 
 impl Config {
     fn default_address() -> Vec<IpAddr> {
         vec![
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
         ]
     }
+
+    fn default_subsystems() -> Vec<String> {
+        vec!["sftp".to_string()]
+    }
 }
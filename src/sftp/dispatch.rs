@@ -0,0 +1,644 @@
+//! The SFTP packet loop. This takes the place of [`russh_sftp::server::run`]
+//! so that we get to see every request and reply on their way through, which
+//! is where request IDs are assigned and attached to errors, along with the
+//! [`ErrorCode`] of the failure.
+//!
+//! It's also where requests are traced, if the session is the subject of a
+//! [`log_filter::trace`].
+//!
+//! Requests are handled concurrently, so that a slow one doesn't hold up the
+//! rest of the session. Requests on the same handle are still handled in the
+//! order they were sent, and those that change what's at a path wait for
+//! everything before them, see [`Order`]. Once a session has as much in flight
+//! as its [`SessionLimits`] allow, nothing more is read from it until some of
+//! that is done.
+//!
+//! Clients that negotiated a version of the protocol later than 3 have their
+//! packets translated by [`versions`] on the way in and out.
+//!
+//! Client mistakes, like packets that can't be parsed, are too big, or aren't
+//! requests, are answered with an error status and counted as protocol
+//! violations against the session. Only losing the stream itself ends it.
+
+use std::{
+    fmt,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use ahash::HashMap;
+use bytes::Bytes;
+use metrics::counter;
+use russh_sftp::{
+    protocol::{Packet, StatusCode},
+    server::Handler,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot},
+    task::{self, JoinError, JoinSet},
+};
+use tracing::{Instrument, Level, Span, event, field, info_span, span};
+
+use super::{SessionLimits, SubsystemContext, server::Failure, sessions::Sessions, versions};
+use crate::{audit, error_code::ErrorCode, log_filter, metrics::Metrics, privacy};
+
+/// The largest packet a client may send. Anything bigger is skipped over
+/// without being read into memory. This leaves room for a 256 KiB write, which
+/// is the most any common client sends.
+pub(super) const MAX_PACKET_LENGTH: u32 = 256 * 1024 + 1024;
+
+/// A short, random identifier for a single SFTP request.
+///
+/// It shows up in the tracing span for the request, in the audit log, and at
+/// the end of the error message of every failed request, so that a failure a
+/// client reports can be tracked down in the logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(u32);
+
+impl RequestId {
+    fn generate() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// What became of a request.
+enum Processed {
+    Answered,
+    /// The request was answered, but broke the protocol.
+    Violation,
+}
+
+/// A packet as it was read from the client.
+struct Incoming {
+    /// The request ID of the packet, for when it can't be decoded.
+    id: u32,
+    request: Result<Packet, versions::Error>,
+    request_id: RequestId,
+    /// What the request is handled in.
+    span: Span,
+}
+
+/// What a request has to wait for before it's handled.
+enum Order {
+    /// Nothing, so it's handled alongside whatever else is in flight.
+    Any,
+    /// The requests before it on the same handle.
+    Handle(String),
+    /// Every request before it, and nothing after it is started until it's
+    /// done.
+    Alone,
+}
+
+impl Order {
+    fn of(request: &Packet) -> Self {
+        match request {
+            Packet::Read(read) => Self::Handle(read.handle.clone()),
+            Packet::Write(write) => Self::Handle(write.handle.clone()),
+            Packet::Close(close) => Self::Handle(close.handle.clone()),
+            Packet::Fstat(fstat) => Self::Handle(fstat.handle.clone()),
+            Packet::FSetStat(fsetstat) => Self::Handle(fsetstat.handle.clone()),
+            Packet::ReadDir(readdir) => Self::Handle(readdir.handle.clone()),
+            Packet::Open(_)
+            | Packet::OpenDir(_)
+            | Packet::Lstat(_)
+            | Packet::Stat(_)
+            | Packet::RealPath(_)
+            | Packet::ReadLink(_) => Self::Any,
+            // The handshake, anything that changes what's at a path, and
+            // extensions, which can do anything to anything.
+            _ => Self::Alone,
+        }
+    }
+}
+
+/// A request that is being handled in a task of its own, for answering it if
+/// the task panics.
+struct Pending {
+    id: u32,
+    request_id: RequestId,
+    /// The version of the protocol it's answered in.
+    version: u32,
+    /// How many bytes it has to write, if it's a write.
+    write_bytes: u64,
+}
+
+/// The requests being handled, each in a task of its own.
+#[derive(Default)]
+struct InFlight {
+    tasks: JoinSet<Result<Processed, io::Error>>,
+    requests: HashMap<task::Id, Pending>,
+    /// How many bytes the writes among them have to write.
+    write_bytes: Arc<AtomicU64>,
+    /// What each handle's last request in flight drops when it's done, for the
+    /// next request on the handle to wait for.
+    handles: HashMap<String, oneshot::Receiver<()>>,
+}
+
+impl InFlight {
+    /// Whether the session has as much in flight as `limits` allow.
+    fn is_full(&self, limits: &SessionLimits) -> bool {
+        !self.tasks.is_empty()
+            && (self.tasks.len() >= limits.max_requests
+                || self.write_bytes.load(Ordering::Relaxed) >= limits.max_write_bytes)
+    }
+
+    /// What became of a request that was handled in a task of its own. A
+    /// request that panicked is answered with a failure, and the rest of the
+    /// session carries on.
+    fn joined(
+        &mut self,
+        finished: Result<(task::Id, Result<Processed, io::Error>), JoinError>,
+        replies: &mpsc::UnboundedSender<Bytes>,
+    ) -> Result<Processed, io::Error> {
+        let err = match finished {
+            Ok((task, processed)) => {
+                self.requests.remove(&task);
+                return processed;
+            }
+            Err(err) => err,
+        };
+
+        event!(Level::ERROR, %err, "SFTP request failed to finish");
+        let Some(pending) = self.requests.remove(&err.id()) else {
+            return Ok(Processed::Answered);
+        };
+
+        self.write_bytes
+            .fetch_sub(pending.write_bytes, Ordering::Relaxed);
+
+        let failure = tag_error(
+            Packet::error(pending.id, StatusCode::Failure),
+            Some(ErrorCode::Internal),
+            pending.request_id,
+        );
+        let packet = versions::encode(pending.version, failure, Some(ErrorCode::Internal))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        let _ = replies.send(packet);
+
+        Ok(Processed::Answered)
+    }
+}
+
+/// Processes SFTP packets from `stream` with `handler` until the stream is
+/// closed. The session in `context` decides whether requests are traced, and
+/// protocol violations are counted against it in `sessions`.
+///
+/// Each request is handled in a task of its own, with a clone of `handler`, and
+/// no more are read while the session has as many in flight as `limits` allow.
+pub async fn run<S, H>(
+    stream: S,
+    handler: H,
+    limits: &SessionLimits,
+    context: &SubsystemContext,
+    sessions: &Sessions,
+) where
+    S: AsyncRead + AsyncWrite,
+    H: Handler<Error = Failure> + Clone + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let (replies, outgoing) = mpsc::unbounded_channel();
+    let mut violations = 0_u64;
+
+    let result = tokio::try_join!(
+        read_requests(
+            reader,
+            handler,
+            replies,
+            limits,
+            context,
+            sessions,
+            &mut violations
+        ),
+        write_replies(writer, outgoing),
+    );
+
+    if let Err(err) = result {
+        event!(Level::WARN, %err, "SFTP stream failed");
+    }
+
+    if violations > 0 {
+        event!(
+            Level::INFO,
+            username = context.username,
+            violations,
+            "SFTP client broke the protocol during the session"
+        );
+    }
+
+    event!(Level::DEBUG, "SFTP stream ended");
+}
+
+/// Reads packets from `reader` until the stream is closed, and handles them in
+/// the `version` of the protocol that was negotiated, which the handshake sets.
+/// Their replies are sent to `replies`, and the protocol violations among them
+/// are counted in `violations`.
+///
+/// With a traced session, each request is handled in a
+/// [`log_filter::TRACE_SPAN`] that records the sizes of the request and
+/// response, and everything logged while handling it is let through.
+#[allow(clippy::too_many_lines)]
+async fn read_requests<R, H>(
+    mut reader: R,
+    mut handler: H,
+    replies: mpsc::UnboundedSender<Bytes>,
+    limits: &SessionLimits,
+    context: &SubsystemContext,
+    sessions: &Sessions,
+    violations: &mut u64,
+) -> Result<(), io::Error>
+where
+    R: AsyncRead + Unpin,
+    H: Handler<Error = Failure> + Clone + Send + 'static,
+{
+    let username = context.username.as_str();
+    let ip = context.peer_addr.map(|addr| addr.ip());
+    // Everything up to the handshake is in version 3, which is also what
+    // clients that never finish it are answered in.
+    let mut version = versions::MIN_VERSION;
+    let mut in_flight = InFlight::default();
+
+    let mut record = |processed: Processed| {
+        if let Processed::Violation = processed {
+            *violations += 1;
+            sessions.record_protocol_violation(context.session_id);
+            counter!(Metrics::SFTP_PROTOCOL_VIOLATIONS).increment(1);
+        }
+    };
+
+    loop {
+        while let Some(finished) = in_flight.tasks.try_join_next_with_id() {
+            record(in_flight.joined(finished, &replies)?);
+        }
+
+        // Not reading from the stream holds the client up through the
+        // channel's flow control, until some of what it has in flight is done.
+        while in_flight.is_full(limits) {
+            if let Some(finished) = in_flight.tasks.join_next_with_id().await {
+                record(in_flight.joined(finished, &replies)?);
+            }
+        }
+
+        if in_flight.tasks.is_empty() {
+            in_flight.handles.clear();
+        }
+
+        let length = match reader.read_u32().await {
+            Ok(length) => length,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+
+        if length > MAX_PACKET_LENGTH {
+            record(skip_packet(&mut reader, length, version, &replies).await?);
+            continue;
+        }
+
+        let mut buf = vec![0; length as usize];
+        reader.read_exact(&mut buf).await?;
+
+        let request_id = RequestId::generate();
+        let span = if log_filter::is_traced(username, ip) {
+            span!(
+                target: log_filter::TRACE_TARGET,
+                Level::TRACE,
+                log_filter::TRACE_SPAN,
+                req = %request_id,
+                username = %privacy::pseudonymize(username),
+                operation = field::Empty,
+                request_size = length,
+                response_size = field::Empty,
+            )
+        } else {
+            info_span!("sftp_request", req = %request_id)
+        };
+
+        // Replies don't say which request they answer, so the ID is taken from
+        // the request, or straight from the packet if it can't be parsed.
+        let incoming = Incoming {
+            id: raw_request_id(&buf),
+            request: versions::decode(version, Bytes::from(buf)),
+            request_id,
+            span,
+        };
+
+        let order = incoming.request.as_ref().map_or(Order::Any, Order::of);
+        let (previous, done) = match order {
+            Order::Any => (None, None),
+            Order::Handle(handle) => {
+                let (done, finished) = oneshot::channel::<()>();
+                (in_flight.handles.insert(handle, finished), Some(done))
+            }
+            Order::Alone => {
+                while let Some(finished) = in_flight.tasks.join_next_with_id().await {
+                    record(in_flight.joined(finished, &replies)?);
+                }
+
+                record(answer(incoming, &mut handler, &mut version, &replies).await?);
+                continue;
+            }
+        };
+
+        let write_bytes = match &incoming.request {
+            Ok(Packet::Write(write)) => write.data.len() as u64,
+            _ => 0,
+        };
+        in_flight
+            .write_bytes
+            .fetch_add(write_bytes, Ordering::Relaxed);
+
+        let pending = Pending {
+            id: incoming
+                .request
+                .as_ref()
+                .map_or(incoming.id, Packet::get_request_id),
+            request_id: incoming.request_id,
+            version,
+            write_bytes,
+        };
+
+        let mut handler = handler.clone();
+        let replies = replies.clone();
+        let queued = in_flight.write_bytes.clone();
+        let task = in_flight.tasks.spawn(async move {
+            let _done = done;
+            // Whether the request before it succeeded or not doesn't matter,
+            // only that it's over.
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            let mut version = version;
+            let processed = answer(incoming, &mut handler, &mut version, &replies).await;
+            queued.fetch_sub(write_bytes, Ordering::Relaxed);
+
+            processed
+        });
+        in_flight.requests.insert(task.id(), pending);
+    }
+
+    while let Some(finished) = in_flight.tasks.join_next_with_id().await {
+        record(in_flight.joined(finished, &replies)?);
+    }
+
+    Ok(())
+}
+
+/// Writes the replies from `outgoing` to `writer` until every request has been
+/// answered. The stream is only flushed once there are no more replies waiting.
+async fn write_replies<W>(
+    mut writer: W,
+    mut outgoing: mpsc::UnboundedReceiver<Bytes>,
+) -> Result<(), io::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(packet) = outgoing.recv().await {
+        writer.write_all(&packet).await?;
+
+        if outgoing.is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles an `incoming` request and sends its reply to `replies`, in the
+/// `version` of the protocol that was negotiated, which the handshake sets.
+async fn answer<H>(
+    incoming: Incoming,
+    handler: &mut H,
+    version: &mut u32,
+    replies: &mpsc::UnboundedSender<Bytes>,
+) -> Result<Processed, io::Error>
+where
+    H: Handler<Error = Failure> + Send,
+{
+    let Incoming {
+        mut id,
+        request,
+        request_id,
+        span,
+    } = incoming;
+
+    let (response, code) = async {
+        let (response, code) = match request {
+            Ok(request) => {
+                id = request.get_request_id();
+                let operation = operation(&request);
+                Span::current().record("operation", operation);
+
+                let (response, code) = match process_request(request, handler).await {
+                    Ok(response) => (response, None),
+                    Err(failure) => {
+                        let code = failure.code;
+                        (failure.into_packet(id), code)
+                    }
+                };
+
+                if let Packet::Version(reply) = &response {
+                    *version = reply.version;
+                }
+
+                event!(
+                    target: audit::TARGET,
+                    Level::INFO,
+                    req = %request_id,
+                    operation,
+                    status = ?response_status(&response),
+                    code = code.map(ErrorCode::as_str),
+                    "SFTP request"
+                );
+
+                if let Some(code) = code {
+                    let labels = [("operation", operation), ("code", code.as_str())];
+                    counter!(Metrics::SFTP_ERRORS, &labels).increment(1);
+                }
+
+                (response, code)
+            }
+            Err(err) => {
+                event!(Level::DEBUG, %err, "malformed SFTP packet");
+                (
+                    Packet::error(id, StatusCode::BadMessage),
+                    Some(ErrorCode::BadRequest),
+                )
+            }
+        };
+
+        (tag_error(response, code, request_id), code)
+    }
+    .instrument(span.clone())
+    .await;
+    let violation = code == Some(ErrorCode::BadRequest);
+
+    let packet = match versions::encode(*version, response, code) {
+        Ok(packet) => packet,
+        Err(err) => {
+            event!(Level::ERROR, %err, req = %request_id, "failed to encode SFTP reply");
+
+            let failure = Packet::error(id, StatusCode::Failure);
+            let failure = tag_error(failure, Some(ErrorCode::Internal), request_id);
+            versions::encode(*version, failure, Some(ErrorCode::Internal))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+        }
+    };
+    span.record("response_size", packet.len());
+    // If the stream has failed, so has the session, which will find out for
+    // itself.
+    let _ = replies.send(packet);
+
+    Ok(if violation {
+        Processed::Violation
+    } else {
+        Processed::Answered
+    })
+}
+
+/// Skips over a packet of `length` bytes that is too big to handle, and
+/// answers it with a failure. Its request ID is all that's read of it.
+async fn skip_packet<R>(
+    reader: &mut R,
+    length: u32,
+    version: u32,
+    replies: &mpsc::UnboundedSender<Bytes>,
+) -> Result<Processed, io::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; 5];
+    reader.read_exact(&mut header).await?;
+    let id = raw_request_id(&header);
+
+    let remaining = u64::from(length) - header.len() as u64;
+    tokio::io::copy(&mut (&mut *reader).take(remaining), &mut tokio::io::sink()).await?;
+
+    event!(Level::DEBUG, length, "skipped oversized SFTP packet");
+
+    let request_id = RequestId::generate();
+    let failure = tag_error(
+        Packet::error(id, StatusCode::Failure),
+        Some(ErrorCode::BadRequest),
+        request_id,
+    );
+    let packet = versions::encode(version, failure, Some(ErrorCode::BadRequest))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let _ = replies.send(packet);
+
+    Ok(Processed::Violation)
+}
+
+/// The request ID of a raw packet, which follows its type, so that a packet
+/// that can't be parsed can still be answered in a way the client can match
+/// up with what it sent. It's 0 if the packet is too short to have one.
+fn raw_request_id(packet: &[u8]) -> u32 {
+    packet
+        .get(1..5)
+        .and_then(|id| id.try_into().ok())
+        .map_or(0, u32::from_be_bytes)
+}
+
+macro_rules! into_wrap {
+    ($handler:expr, $var:ident; $($arg:ident),*) => {
+        $handler.$var($($var.$arg),*).await.map(Into::into)
+    };
+}
+
+async fn process_request<H>(packet: Packet, handler: &mut H) -> Result<Packet, Failure>
+where
+    H: Handler<Error = Failure> + Send,
+{
+    match packet {
+        Packet::Init(init) => into_wrap!(handler, init; version, extensions),
+        Packet::Open(open) => into_wrap!(handler, open; id, filename, pflags, attrs),
+        Packet::Close(close) => into_wrap!(handler, close; id, handle),
+        Packet::Read(read) => into_wrap!(handler, read; id, handle, offset, len),
+        Packet::Write(write) => into_wrap!(handler, write; id, handle, offset, data),
+        Packet::Lstat(lstat) => into_wrap!(handler, lstat; id, path),
+        Packet::Fstat(fstat) => into_wrap!(handler, fstat; id, handle),
+        Packet::SetStat(setstat) => into_wrap!(handler, setstat; id, path, attrs),
+        Packet::FSetStat(fsetstat) => into_wrap!(handler, fsetstat; id, handle, attrs),
+        Packet::OpenDir(opendir) => into_wrap!(handler, opendir; id, path),
+        Packet::ReadDir(readdir) => into_wrap!(handler, readdir; id, handle),
+        Packet::Remove(remove) => into_wrap!(handler, remove; id, filename),
+        Packet::MkDir(mkdir) => into_wrap!(handler, mkdir; id, path, attrs),
+        Packet::RmDir(rmdir) => into_wrap!(handler, rmdir; id, path),
+        Packet::RealPath(realpath) => into_wrap!(handler, realpath; id, path),
+        Packet::Stat(stat) => into_wrap!(handler, stat; id, path),
+        Packet::Rename(rename) => into_wrap!(handler, rename; id, oldpath, newpath),
+        Packet::ReadLink(readlink) => into_wrap!(handler, readlink; id, path),
+        Packet::Symlink(symlink) => into_wrap!(handler, symlink; id, linkpath, targetpath),
+        Packet::Extended(extended) => into_wrap!(handler, extended; id, request, data),
+        // Replies and the like are well-formed, but nothing a client should
+        // send.
+        _ => Err(Failure::new(
+            StatusCode::OpUnsupported,
+            ErrorCode::BadRequest,
+        )),
+    }
+}
+
+/// The name of the SFTP operation a request packet performs, for logging.
+fn operation(packet: &Packet) -> &'static str {
+    match packet {
+        Packet::Init(_) => "init",
+        Packet::Open(_) => "open",
+        Packet::Close(_) => "close",
+        Packet::Read(_) => "read",
+        Packet::Write(_) => "write",
+        Packet::Lstat(_) => "lstat",
+        Packet::Fstat(_) => "fstat",
+        Packet::SetStat(_) => "setstat",
+        Packet::FSetStat(_) => "fsetstat",
+        Packet::OpenDir(_) => "opendir",
+        Packet::ReadDir(_) => "readdir",
+        Packet::Remove(_) => "remove",
+        Packet::MkDir(_) => "mkdir",
+        Packet::RmDir(_) => "rmdir",
+        Packet::RealPath(_) => "realpath",
+        Packet::Stat(_) => "stat",
+        Packet::Rename(_) => "rename",
+        Packet::ReadLink(_) => "readlink",
+        Packet::Symlink(_) => "symlink",
+        Packet::Extended(_) => "extended",
+        _ => "unknown",
+    }
+}
+
+/// The status a reply carries, which is [`StatusCode::Ok`] for anything that
+/// isn't a status reply.
+fn response_status(packet: &Packet) -> StatusCode {
+    match packet {
+        Packet::Status(status) => status.status_code,
+        _ => StatusCode::Ok,
+    }
+}
+
+/// Appends the error code and the request ID to the error message of a failed
+/// request.
+///
+/// `SSH_FX_EOF` isn't really a failure, so it's left alone.
+fn tag_error(mut packet: Packet, code: Option<ErrorCode>, request_id: RequestId) -> Packet {
+    if let Packet::Status(status) = &mut packet
+        && !matches!(status.status_code, StatusCode::Ok | StatusCode::Eof)
+    {
+        let code = code.or_else(|| ErrorCode::for_status(status.status_code));
+
+        status.error_message = match code {
+            Some(code) => format!("{} [{code}] [req {request_id}]", status.error_message),
+            None => format!("{} [req {request_id}]", status.error_message),
+        };
+    }
+
+    packet
+}
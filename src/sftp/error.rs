@@ -2,7 +2,7 @@ use std::io;
 
 use camino::FromPathError;
 
-use crate::auth;
+use crate::{auth, error_code::ErrorCode, vfs};
 
 #[derive(Debug, thiserror::Error, thiserror_ext::ContextInto)]
 pub enum Error {
@@ -16,4 +16,21 @@ pub enum Error {
     FromPathError(#[from] FromPathError),
     #[error("couldn't find channel")]
     LostChannel,
+    #[error("VFS error")]
+    VfsError(#[from] vfs::Error),
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::AuthError(err) => err.code(),
+            Self::RusshError(_) => ErrorCode::Ssh,
+            Self::IoError { .. } => ErrorCode::Io,
+            Self::FromPathError(_) => ErrorCode::InvalidPath,
+            Self::LostChannel => ErrorCode::ChannelLost,
+            Self::VfsError(err) => err.code(),
+        }
+    }
 }
@@ -0,0 +1,55 @@
+//! Running the commands clients send in `exec` requests.
+//!
+//! A command runs on its own task rather than inside the session handler: the
+//! handler is also what processes the client's window adjustments, so a
+//! command that blocked it would stall as soon as its output filled the
+//! channel window. Output goes through a buffered writer on the channel that
+//! waits for the window to open instead, and once the command is done the exit
+//! status, EOF, and close are sent strictly after the last of its output.
+
+use std::{future::Future, pin::Pin};
+
+use russh::{Channel, server::Msg};
+use tokio::io::{AsyncWrite, BufWriter};
+use tracing::{Level, event};
+
+/// How much command output is buffered before it's written to the channel,
+/// so that commands writing a line at a time still send full packets.
+const OUTPUT_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Where a command writes its output. Writes wait for the client to make room
+/// in the channel window, and the command has to flush it before it returns.
+pub(super) type Output = BufWriter<Pin<Box<dyn AsyncWrite + Send>>>;
+
+/// A command that is ready to run.
+pub(super) type Command = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// The writer for the output of a command run on `channel`.
+pub(super) fn output(channel: &Channel<Msg>) -> Output {
+    BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, Box::pin(channel.make_writer()))
+}
+
+/// Runs `command` to completion on its own task, then reports its exit status
+/// and closes `channel`.
+pub(super) fn spawn(channel: Channel<Msg>, command: Command) {
+    tokio::spawn(async move {
+        let exit_status = match command.await {
+            Ok(()) => 0,
+            Err(err) => {
+                event!(Level::INFO, %err, "exec command failed");
+                1
+            }
+        };
+
+        if let Err(err) = finish(&channel, exit_status).await {
+            event!(Level::DEBUG, %err, "couldn't close exec channel");
+        }
+    });
+}
+
+/// Sends the exit status, EOF, and close, in the order clients expect them.
+async fn finish(channel: &Channel<Msg>, exit_status: u32) -> Result<(), russh::Error> {
+    channel.exit_status(exit_status).await?;
+    channel.eof().await?;
+    channel.close().await
+}
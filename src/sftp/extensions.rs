@@ -0,0 +1,412 @@
+//! Request and reply payloads for the `SSH_FXP_EXTENDED` requests that Schlep
+//! understands, along with the names they are advertised under.
+
+use std::{collections::HashMap, ops::Range};
+
+use bytes::Bytes;
+pub use russh_sftp::extensions::{
+    HARDLINK,
+    HardlinkExtension,
+    LIMITS,
+    LimitsExtension,
+    STATVFS,
+    Statvfs,
+    StatvfsExtension,
+};
+use russh_sftp::protocol::{ExtendedReply, Packet, StatusCode};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tracing::{Level, event};
+
+use super::dispatch::MAX_PACKET_LENGTH;
+use crate::vfs::{Capabilities, FsMetadata, HashAlgorithm};
+
+/// The hash algorithms [`CHECK_FILE_HANDLE`] and [`CHECK_FILE_NAME`] can use.
+const CHECK_FILE_ALGORITHMS: [HashAlgorithm; 4] = [
+    HashAlgorithm::Md5,
+    HashAlgorithm::Sha1,
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Sha512,
+];
+
+/// The smallest block a [`CHECK_FILE_HANDLE`] or [`CHECK_FILE_NAME`] request
+/// can ask for a digest of each of, other than 0 for the whole range.
+const MIN_CHECK_FILE_BLOCK_SIZE: u32 = 256;
+
+/// The most a single read returns. Longer reads are cut short, which clients
+/// have to expect near the end of a file anyway.
+pub const MAX_READ_LENGTH: u32 = 256 * 1024;
+
+/// The most a single write can carry while leaving room in the packet for the
+/// rest of the request.
+pub const MAX_WRITE_LENGTH: u32 = 256 * 1024;
+
+/// Reports on the filesystem an open file or directory is on, like
+/// `statvfs@openssh.com` does for a path.
+pub const FSTATVFS: &str = "fstatvfs@openssh.com";
+/// Renames a file, replacing whatever is already at the new path in one step,
+/// like POSIX `rename(2)`.
+pub const POSIX_RENAME: &str = "posix-rename@openssh.com";
+/// Copies part of one open file into another without the data passing
+/// through the client. A length of 0 copies everything from the offset on.
+pub const COPY_DATA: &str = "copy-data";
+/// Copies a file to a new path without the data passing through the client,
+/// replacing whatever is already there only if the client asks to.
+pub const COPY_FILE: &str = "copy-file@openssh.com";
+/// Hashes a range of an open file, with a digest for every block of it or one
+/// for the whole range, so that clients can check a transfer or see where to
+/// resume it without downloading the file.
+pub const CHECK_FILE_HANDLE: &str = "check-file-handle";
+/// Like [`CHECK_FILE_HANDLE`], for the file at a path.
+pub const CHECK_FILE_NAME: &str = "check-file-name";
+/// What replies to [`CHECK_FILE_HANDLE`] and [`CHECK_FILE_NAME`] are called,
+/// and what's advertised with the hash algorithms they support.
+pub const CHECK_FILE: &str = "check-file";
+/// Resolves a path like `realpath` does, after expanding a leading `~` or
+/// `~user` to the user's home directory.
+pub const EXPAND_PATH: &str = "expand-path@openssh.com";
+/// Resolves a path like `realpath` does, but only fails for a path that
+/// doesn't exist if the client asks it to, like `SSH_FXP_REALPATH` can in
+/// later versions of the protocol. Paths that don't exist come back without
+/// attributes.
+pub const REALPATH: &str = "realpath@schlep";
+/// Looks up the names of numeric user and group IDs, so that clients can show
+/// who owns a file. IDs that nothing is known about get empty names.
+pub const USERS_GROUPS_BY_ID: &str = "users-groups-by-id@openssh.com";
+/// Returns the change token of the file at `path`, see
+/// [`Metadata::change_token`](crate::vfs::Metadata::change_token).
+pub const CHANGE_TOKEN: &str = "change-token@schlep";
+/// Writes to an open file only if its change token still matches the one the
+/// client provides, and returns the new change token.
+pub const WRITE_IF_MATCH: &str = "write-if-match@schlep";
+/// Declares the hex-encoded SHA-256 that a file being uploaded should have.
+/// It can be sent at any point before the file is closed, and closing the file
+/// fails if it doesn't match.
+pub const EXPECT_SHA256: &str = "expect-sha256@schlep";
+/// Returns the SSH public keys the user can log in with, in OpenSSH format.
+pub const PUBLIC_KEYS: &str = "public-keys@schlep";
+/// Replaces the SSH public keys the user can log in with. Whether this works
+/// depends on where the user's keys are kept, see
+/// [`AuthClient::set_public_keys`](crate::auth::AuthClient::set_public_keys).
+pub const SET_PUBLIC_KEYS: &str = "set-public-keys@schlep";
+/// Takes an advisory lock on a byte range of an open file, like
+/// `SSH_FXP_BLOCK` in later versions of the protocol. A length of 0 locks
+/// everything from the offset on. Other clients can't write to a locked range,
+/// nor read it if the lock is exclusive, until the lock is released or the
+/// file is closed.
+pub const LOCK: &str = "lock@schlep";
+/// Releases a lock taken with [`LOCK`], which must match its range exactly.
+pub const UNLOCK: &str = "unlock@schlep";
+
+/// The extensions advertised to the client in `SSH_FXP_VERSION`, given what
+/// the mounts the client can see are capable of between them.
+pub fn advertised(capabilities: Capabilities) -> HashMap<String, String> {
+    let optional = [
+        (HARDLINK, Capabilities::HARDLINKS),
+        (STATVFS, Capabilities::STATVFS),
+        (FSTATVFS, Capabilities::STATVFS),
+        (POSIX_RENAME, Capabilities::ATOMIC_RENAME),
+    ]
+    .into_iter()
+    .filter(|(_, needed)| capabilities.contains(*needed))
+    .map(|(name, _)| name);
+
+    [
+        LIMITS,
+        COPY_DATA,
+        COPY_FILE,
+        CHECK_FILE_HANDLE,
+        CHECK_FILE_NAME,
+        EXPAND_PATH,
+        REALPATH,
+        USERS_GROUPS_BY_ID,
+        CHANGE_TOKEN,
+        WRITE_IF_MATCH,
+        EXPECT_SHA256,
+        PUBLIC_KEYS,
+        SET_PUBLIC_KEYS,
+        LOCK,
+        UNLOCK,
+    ]
+    .into_iter()
+    .chain(optional)
+    .map(|name| (name.to_string(), "1".to_string()))
+    .chain([(CHECK_FILE.to_string(), check_file_algorithms())])
+    .collect()
+}
+
+/// The names of [`CHECK_FILE_ALGORITHMS`], as a comma-separated list.
+fn check_file_algorithms() -> String {
+    CHECK_FILE_ALGORITHMS.map(HashAlgorithm::name).join(",")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FstatvfsExtension {
+    pub handle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PosixRenameExtension {
+    pub oldpath: String,
+    pub newpath: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyDataExtension {
+    pub read_from_handle: String,
+    pub read_from_offset: u64,
+    pub read_data_length: u64,
+    pub write_to_handle: String,
+    pub write_to_offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyFileExtension {
+    pub source: String,
+    pub destination: String,
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileHandleExtension {
+    pub handle: String,
+    pub hash_algorithms: String,
+    pub start_offset: u64,
+    pub length: u64,
+    pub block_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileNameExtension {
+    pub filename: String,
+    pub hash_algorithms: String,
+    pub start_offset: u64,
+    pub length: u64,
+    pub block_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExpandPathExtension {
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RealpathExtension {
+    pub path: String,
+    #[serde(with = "flag")]
+    pub must_exist: bool,
+}
+
+/// The IDs are packed together as big-endian `uint32`s, see [`unpack_ids`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsersGroupsByIdExtension {
+    pub uids: Vec<u8>,
+    pub gids: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeTokenExtension {
+    pub path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteIfMatchExtension {
+    pub handle: String,
+    pub token: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExpectSha256Extension {
+    pub handle: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPublicKeysExtension {
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockExtension {
+    pub handle: String,
+    pub offset: u64,
+    pub length: u64,
+    pub exclusive: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnlockExtension {
+    pub handle: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangeTokenReply {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeysReply {
+    pub keys: Vec<String>,
+}
+
+/// The names are packed together in the order of the IDs they're for, see
+/// [`pack_names`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsersGroupsByIdReply {
+    pub usernames: Vec<u8>,
+    pub groupnames: Vec<u8>,
+}
+
+/// The reply to a [`CHECK_FILE_HANDLE`] or [`CHECK_FILE_NAME`] request, up to
+/// the digests, which follow it without a length.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckFileReply {
+    name: String,
+    hash_algorithm: String,
+}
+
+/// The reply to a [`LIMITS`] request.
+pub fn limits() -> LimitsExtension {
+    LimitsExtension {
+        max_packet_len: u64::from(MAX_PACKET_LENGTH),
+        max_read_len: u64::from(MAX_READ_LENGTH),
+        max_write_len: u64::from(MAX_WRITE_LENGTH),
+        // Open handles aren't limited, which is what 0 stands for.
+        max_open_handles: 0,
+    }
+}
+
+/// The reply to a [`STATVFS`] or [`FSTATVFS`] request, for a backend's
+/// `metadata`.
+pub fn statvfs(metadata: &FsMetadata) -> Statvfs {
+    /// `SSH_FXE_STATVFS_ST_RDONLY`.
+    const READ_ONLY: u64 = 0x1;
+
+    Statvfs {
+        block_size: metadata.block_size,
+        fragment_size: metadata.block_size,
+        blocks: metadata.num_blocks,
+        blocks_free: metadata.free_blocks,
+        blocks_avail: metadata.free_blocks,
+        inodes: metadata.num_files,
+        inodes_free: metadata.free_files,
+        inodes_avail: metadata.free_files,
+        fs_id: 0,
+        flags: if metadata.read_only { READ_ONLY } else { 0 },
+        name_max: metadata.max_length,
+    }
+}
+
+/// Decodes the payload of an extended request.
+pub fn parse<T: DeserializeOwned>(data: Vec<u8>) -> Result<T, StatusCode> {
+    russh_sftp::de::from_bytes(&mut Bytes::from(data)).map_err(|err| {
+        event!(Level::DEBUG, %err, "malformed extended request");
+        StatusCode::BadMessage
+    })
+}
+
+/// The first of the comma-separated `hash_algorithms` from a
+/// [`CHECK_FILE_HANDLE`] or [`CHECK_FILE_NAME`] request that can be used, which
+/// the client lists in the order it prefers them, and the block size it asked
+/// for.
+pub fn check_file_params(
+    hash_algorithms: &str,
+    block_size: u32,
+) -> Result<(HashAlgorithm, u64), StatusCode> {
+    if block_size != 0 && block_size < MIN_CHECK_FILE_BLOCK_SIZE {
+        return Err(StatusCode::BadMessage);
+    }
+
+    let algorithm = hash_algorithms
+        .split(',')
+        .find_map(|name| HashAlgorithm::for_name(name.trim()))
+        .ok_or(StatusCode::OpUnsupported)?;
+
+    Ok((algorithm, u64::from(block_size)))
+}
+
+/// The reply to a [`CHECK_FILE_HANDLE`] or [`CHECK_FILE_NAME`] request that
+/// was hashed with `algorithm`.
+pub fn check_file_reply(
+    id: u32,
+    algorithm: HashAlgorithm,
+    digests: &[Vec<u8>],
+) -> Result<Packet, StatusCode> {
+    let reply = CheckFileReply {
+        name: CHECK_FILE.to_string(),
+        hash_algorithm: algorithm.name().to_string(),
+    };
+
+    let mut data = encode(&reply)?;
+    data.extend(digests.concat());
+
+    Ok(Packet::ExtendedReply(ExtendedReply { id, data }))
+}
+
+/// The IDs in the `uids` or `gids` of a [`USERS_GROUPS_BY_ID`] request.
+pub fn unpack_ids(data: &[u8]) -> Result<Vec<u32>, StatusCode> {
+    if !data.len().is_multiple_of(4) {
+        return Err(StatusCode::BadMessage);
+    }
+
+    Ok(data
+        .chunks_exact(4)
+        .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+        .collect())
+}
+
+/// Packs `names` for the reply to a [`USERS_GROUPS_BY_ID`] request, as SSH
+/// strings one after another.
+pub fn pack_names(names: &[String]) -> Vec<u8> {
+    names.iter().fold(Vec::new(), |mut data, name| {
+        // Names come from the user database, so they're nowhere near 4 GiB.
+        data.extend(u32::try_from(name.len()).unwrap().to_be_bytes());
+        data.extend(name.as_bytes());
+        data
+    })
+}
+
+/// The byte range an offset and length from a [`LOCK`] or [`UNLOCK`] request
+/// stand for.
+pub fn lock_range(offset: u64, length: u64) -> Range<u64> {
+    if length == 0 {
+        offset..u64::MAX
+    } else {
+        offset..offset.saturating_add(length)
+    }
+}
+
+/// Encodes the payload of an extended reply.
+pub fn reply<T: Serialize>(id: u32, reply: &T) -> Result<Packet, StatusCode> {
+    Ok(Packet::ExtendedReply(ExtendedReply {
+        id,
+        data: encode(reply)?,
+    }))
+}
+
+fn encode<T: Serialize>(reply: &T) -> Result<Vec<u8>, StatusCode> {
+    let data = russh_sftp::ser::to_bytes(reply).map_err(|err| {
+        event!(Level::ERROR, %err, "failed to encode extended reply");
+        StatusCode::Failure
+    })?;
+
+    Ok(data.to_vec())
+}
+
+/// A `bool` in an extended request, which the SFTP encoding only has room for
+/// as a single byte.
+mod flag {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub fn serialize<S: Serializer>(value: &bool, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(u8::from(*value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+        Ok(u8::deserialize(deserializer)? != 0)
+    }
+}
@@ -0,0 +1,88 @@
+//! Ties the handles given to SFTP clients to the session that opened them.
+//!
+//! VFS handles are resolved by the mount they name, so on their own they'd
+//! work from any session that learned or guessed one. Every handle sent to a
+//! client is therefore sealed with a MAC over the session's ID, and a handle
+//! that comes back with a MAC from another session is refused before it gets
+//! anywhere near the VFS. The MAC also keeps clients from pointing a handle
+//! at another mount.
+
+use std::{str::FromStr, sync::LazyLock};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use hmac::{Hmac, Mac};
+use russh_sftp::protocol::StatusCode;
+use sha2::Sha256;
+use tracing::{Level, event};
+
+use super::server::Failure;
+use crate::{error_code::ErrorCode, vfs};
+
+type Key = Hmac<Sha256>;
+
+/// The key for the MACs. Handles don't outlive the process, so neither does
+/// the key.
+static KEY: LazyLock<Key> = LazyLock::new(|| {
+    Key::new_from_slice(&rand::random::<[u8; 32]>()).expect("HMAC takes keys of any length")
+});
+
+/// How many bytes of the MAC a handle carries.
+const TAG_LEN: usize = 16;
+
+/// Seals and unseals the handles of one session.
+pub(super) struct HandleSeal {
+    session_id: u64,
+}
+
+impl HandleSeal {
+    pub(super) fn new(session_id: u64) -> Self {
+        Self { session_id }
+    }
+
+    /// The handle to give the client for `handle`.
+    pub(super) fn seal(&self, handle: &vfs::Handle) -> String {
+        let handle = handle.to_string();
+        let tag = self.mac(&handle).finalize().into_bytes();
+
+        format!(
+            "{handle}.{}",
+            Base64UrlUnpadded::encode_string(&tag[..TAG_LEN])
+        )
+    }
+
+    /// The VFS handle that `handle`, as the client sent it, stands for.
+    ///
+    /// A handle that isn't sealed at all is the client's mistake, but one that
+    /// was sealed for another session is refused with
+    /// [`StatusCode::PermissionDenied`].
+    pub(super) fn unseal(&self, handle: &str) -> Result<vfs::Handle, Failure> {
+        let malformed = || Failure::new(StatusCode::Failure, ErrorCode::BadRequest);
+
+        let (handle, tag) = handle.rsplit_once('.').ok_or_else(malformed)?;
+        let tag = Base64UrlUnpadded::decode_vec(tag).map_err(|_| malformed())?;
+
+        if tag.len() != TAG_LEN || self.mac(handle).verify_truncated_left(&tag).is_err() {
+            event!(
+                Level::WARN,
+                session = self.session_id,
+                "refused a handle that this session didn't open"
+            );
+
+            return Err(Failure::new(
+                StatusCode::PermissionDenied,
+                ErrorCode::PermissionDenied,
+            ));
+        }
+
+        vfs::Handle::from_str(handle).map_err(|_| malformed())
+    }
+
+    /// The MAC of `handle` in this session.
+    fn mac(&self, handle: &str) -> Key {
+        let mut mac = KEY.clone();
+        mac.update(&self.session_id.to_be_bytes());
+        mac.update(handle.as_bytes());
+
+        mac
+    }
+}
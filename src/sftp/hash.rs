@@ -1,19 +1,28 @@
-use std::{ffi::OsString, path::Path};
+use std::{ffi::OsString, path::Path, sync::Arc};
 
 use camino::{Utf8Path, Utf8PathBuf};
 use path_absolutize::Absolutize;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-
-use crate::vfs::{PathMatch, VfsSet};
-
-pub async fn exec_sha1sum<S>(
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use super::limits::{Limiter, Operation};
+use crate::{
+    checksums::ChecksumCache,
+    vfs::{HashAlgorithm, PathMatch, VfsSet},
+};
+
+/// Prints the `algorithm` checksum of every file in `arguments`, like
+/// `sha256sum` and friends do.
+pub async fn exec_checksum<W>(
+    algorithm: HashAlgorithm,
     vfs_set: VfsSet,
     cwd: Utf8PathBuf,
-    mut stream: S,
+    limiter: Arc<Limiter>,
+    checksums: ChecksumCache,
+    mut output: W,
     arguments: Vec<OsString>,
 ) -> anyhow::Result<()>
 where
-    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin,
 {
     for argument in arguments {
         let path = Path::new(&argument);
@@ -22,38 +31,15 @@ where
             Utf8Path::from_path(&absolute_path).ok_or(anyhow::anyhow!("invalid path"))?;
 
         if let Some(PathMatch { vfs, relative_path }) = vfs_set.resolve_path(absolute_path) {
-            let digest = vfs.sha1sum(&relative_path).await?;
-            let output_line = format!("{digest:x}  {}\n", path.display());
+            limiter.acquire(Operation::Hash).await?;
+            let digest = checksums.checksum(algorithm, &vfs, &relative_path).await?;
+            let output_line = format!("{digest}  {}\n", path.display());
 
-            stream.write_all(output_line.as_bytes()).await?;
+            output.write_all(output_line.as_bytes()).await?;
         }
     }
 
-    Ok(())
-}
-
-pub async fn exec_md5sum<S>(
-    vfs_set: VfsSet,
-    cwd: Utf8PathBuf,
-    mut stream: S,
-    arguments: Vec<OsString>,
-) -> anyhow::Result<()>
-where
-    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-{
-    for argument in arguments {
-        let path = Path::new(&argument);
-        let absolute_path = path.absolutize_from(cwd.as_path())?;
-        let absolute_path =
-            Utf8Path::from_path(&absolute_path).ok_or(anyhow::anyhow!("invalid path"))?;
-
-        if let Some(PathMatch { vfs, relative_path }) = vfs_set.resolve_path(absolute_path) {
-            let digest = vfs.md5sum(&relative_path).await?;
-            let output_line = format!("{digest:x}  {}\n", path.display());
-
-            stream.write_all(output_line.as_bytes()).await?;
-        }
-    }
+    output.flush().await?;
 
     Ok(())
 }
@@ -0,0 +1,159 @@
+//! Loading the host keys the server identifies itself with. Keys can come from
+//! a directory of key files, a single file with several keys concatenated, or
+//! environment variables, so that containerized deployments can inject them
+//! as secrets without writing them to disk first.
+//!
+//! The keys always end up in memory: `russh` signs the key exchange with the
+//! [`PrivateKey`]s in its server config itself, and has no hook for handing
+//! that signature off to an external signer such as a PKCS#11 token or a
+//! cloud KMS. Keeping host keys in a KMS needs that hook upstream first.
+
+use std::{
+    env,
+    io::{self, read_to_string},
+};
+
+use camino::Utf8Path;
+use cap_primitives::ambient_authority;
+use cap_std::fs_utf8::Dir;
+use russh::keys::{decode_secret_key, ssh_key::PrivateKey};
+use tracing::{Level, event, instrument};
+
+use super::Config;
+
+/// Loads the host keys from every source in the configuration.
+#[instrument(
+    skip_all,
+    fields(
+        config.private_host_key_dir,
+        config.private_host_key_file,
+        config.private_host_key_env
+    )
+)]
+pub fn load(config: &Config) -> io::Result<Vec<PrivateKey>> {
+    let passphrase = passphrase(config)?;
+    let mut keys = KeyLoader {
+        passphrase: passphrase.as_deref(),
+        keys: Vec::new(),
+    };
+
+    if let Some(key_dir) = &config.private_host_key_dir {
+        keys.load_dir(key_dir.as_path())?;
+    }
+
+    if let Some(key_file) = &config.private_host_key_file {
+        let contents = std::fs::read_to_string(key_file)?;
+        keys.parse(&contents, &key_file.display().to_string())?;
+    }
+
+    for var in &config.private_host_key_env {
+        match env::var(var) {
+            Ok(contents) => keys.parse(&contents, &format!("${var}"))?,
+            Err(err) => event!(Level::WARN, %var, %err, "couldn't read host key variable"),
+        }
+    }
+
+    Ok(keys.keys)
+}
+
+/// The passphrase for encrypted host keys, either straight from the config
+/// or from the secret file it points to.
+fn passphrase(config: &Config) -> io::Result<Option<String>> {
+    if let Some(passphrase) = &config.private_host_key_passphrase {
+        return Ok(Some(passphrase.clone()));
+    }
+
+    if let Some(passphrase_file) = &config.private_host_key_passphrase_file {
+        let passphrase = std::fs::read_to_string(passphrase_file)?;
+
+        return Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_string()));
+    }
+
+    Ok(None)
+}
+
+struct KeyLoader<'a> {
+    passphrase: Option<&'a str>,
+    keys: Vec<PrivateKey>,
+}
+
+impl KeyLoader<'_> {
+    fn load_dir(&mut self, key_dir: &std::path::Path) -> io::Result<()> {
+        if let Some(key_dir) = Utf8Path::from_path(key_dir) {
+            let dir = Dir::open_ambient_dir(key_dir, ambient_authority())?;
+
+            for entry in dir.entries()? {
+                let entry = entry?;
+                let file_name = entry.file_name()?;
+
+                if Utf8Path::new(&file_name)
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("pub"))
+                {
+                    continue;
+                }
+
+                let contents = read_to_string(entry.open()?)?;
+                self.parse(&contents, &file_name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses every OpenSSH private key in `contents`, which may hold several
+    /// of them one after the other. `source` describes where they came from
+    /// for the log.
+    ///
+    /// A key that can't be parsed (or decrypted) is an error rather than being
+    /// skipped, since a server that silently comes up without the host key
+    /// its clients expect is worse than one that doesn't come up at all.
+    fn parse(&mut self, contents: &str, source: &str) -> io::Result<()> {
+        for pem in split_pem(contents) {
+            match decode_secret_key(&pem, self.passphrase) {
+                Ok(private_key) => {
+                    let algorithm = private_key.algorithm();
+                    event!(Level::INFO, %source, %algorithm, "imported private key");
+
+                    self.keys.push(private_key);
+                }
+                Err(err) => {
+                    event!(Level::ERROR, %source, %err, "couldn't load private key");
+
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("couldn't load private key from {source}: {err}"),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits concatenated PEM documents into the individual documents, dropping
+/// anything outside of a `-----BEGIN`/`-----END` pair.
+fn split_pem(contents: &str) -> Vec<String> {
+    let mut documents = Vec::new();
+    let mut current: Option<String> = None;
+
+    for line in contents.lines() {
+        let line = line.trim();
+
+        if line.starts_with("-----BEGIN ") {
+            current = Some(String::new());
+        }
+
+        if let Some(document) = &mut current {
+            document.push_str(line);
+            document.push('\n');
+
+            if line.starts_with("-----END ") {
+                documents.extend(current.take());
+            }
+        }
+    }
+
+    documents
+}
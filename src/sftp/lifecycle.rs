@@ -0,0 +1,141 @@
+//! Hooks that embedders can register with
+//! [`SshServer::add_lifecycle_hooks`](super::SshServer::add_lifecycle_hooks)
+//! to be told when the server starts and stops and when sessions come and go,
+//! so that they can set things up for them and tear them down again without
+//! running the server loop themselves.
+
+use std::{io, sync::Arc};
+
+use async_trait::async_trait;
+use tracing::{Level, event};
+
+use super::SessionInfo;
+use crate::vfs::VfsSet;
+
+/// Called at points in the life of an [`SshServer`](super::SshServer). Every
+/// method does nothing by default, so implementations only need to provide
+/// the ones they care about.
+///
+/// When several hooks are registered, they're called in the order they were
+/// added.
+#[async_trait]
+pub trait LifecycleHooks: Send + Sync {
+    /// Called before the server starts listening. If it fails, the server
+    /// doesn't start, and [`SshServer::run`](super::SshServer::run) returns the
+    /// error.
+    ///
+    /// A supervised server calls it again each time it's restarted.
+    async fn on_start(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once a client has proven who it is, before its files are set up
+    /// for it. If it fails, the client is refused as though the login had been
+    /// rejected.
+    async fn on_session_open(&self, _session: &SessionInfo) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the mounts a session gets have been worked out, after the
+    /// `{username}` templates, `visible_to_users` and `visible_to_group` have
+    /// been applied, with the chance to narrow them down or replace them, like
+    /// with [`VfsSet::without`]. If it fails, the client is refused as though
+    /// the login had been rejected.
+    async fn session_mounts(
+        &self,
+        _session: &SessionInfo,
+        vfs_set: VfsSet,
+    ) -> anyhow::Result<VfsSet> {
+        Ok(vfs_set)
+    }
+
+    /// Called after a session whose [`on_session_open`](Self::on_session_open)
+    /// succeeded has disconnected.
+    async fn on_session_close(&self, _session: &SessionInfo) {}
+
+    /// Called after the server has stopped listening, whether it stopped
+    /// because it failed or not. Sessions that are still connected may
+    /// outlive it.
+    async fn on_shutdown(&self) {}
+}
+
+/// The [`LifecycleHooks`] registered with an [`SshServer`](super::SshServer).
+#[derive(Clone, Default)]
+pub(super) struct Hooks(Arc<Vec<Arc<dyn LifecycleHooks>>>);
+
+impl Hooks {
+    pub(super) fn add(&mut self, hooks: Arc<dyn LifecycleHooks>) {
+        Arc::make_mut(&mut self.0).push(hooks);
+    }
+
+    pub(super) async fn start(&self) -> io::Result<()> {
+        for hooks in self.0.iter() {
+            hooks.on_start().await.map_err(io::Error::other)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether every hook let the session go ahead.
+    pub(super) async fn session_open(&self, session: &SessionInfo) -> bool {
+        for hooks in self.0.iter() {
+            if let Err(err) = hooks.on_session_open(session).await {
+                event!(
+                    Level::WARN,
+                    session_id = session.id,
+                    err = %format!("{err:#}"),
+                    "session refused by lifecycle hook"
+                );
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// The mounts that every hook has had its say on, or `None` if one of
+    /// them refused the session.
+    pub(super) async fn session_mounts(
+        &self,
+        session: &SessionInfo,
+        mut vfs_set: VfsSet,
+    ) -> Option<VfsSet> {
+        for hooks in self.0.iter() {
+            vfs_set = match hooks.session_mounts(session, vfs_set).await {
+                Ok(vfs_set) => vfs_set,
+                Err(err) => {
+                    event!(
+                        Level::WARN,
+                        session_id = session.id,
+                        err = %format!("{err:#}"),
+                        "session refused by lifecycle hook"
+                    );
+                    return None;
+                }
+            };
+        }
+
+        Some(vfs_set)
+    }
+
+    /// Calls the hooks in the background, since sessions are closed from
+    /// where nothing can wait for them.
+    pub(super) fn session_close(&self, session: SessionInfo) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        let hooks = self.clone();
+        tokio::spawn(async move {
+            for hooks in hooks.0.iter() {
+                hooks.on_session_close(&session).await;
+            }
+        });
+    }
+
+    pub(super) async fn shutdown(&self) {
+        for hooks in self.0.iter() {
+            hooks.on_shutdown().await;
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Limits on the operations that are expensive for the server, like hashing
+//! whole files or listing large directories.
+//!
+//! Each kind of operation can be limited per session and across all sessions
+//! with token buckets, which let a burst of a period's worth of operations
+//! through at once and then one at a time as the bucket refills. An operation
+//! that is over a limit waits for its turn, unless its turn is further off
+//! than the configured `max_wait`, in which case it's refused.
+//!
+//! The bandwidth of each session, and of all of a user's sessions together, is
+//! limited here as well. Transfers over those limits are never refused, only
+//! slowed down. So is how big a user's uploads may get, which is looked up
+//! once when they log in.
+
+use std::{
+    fmt,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use ahash::HashMap;
+use metrics::counter;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use tracing::{Level, event};
+
+use super::{Config, ExpensiveOperationLimits, OperationLimit};
+use crate::{metrics::Metrics, throttle::Throttle};
+
+/// A kind of expensive operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Operation {
+    /// Hashing a whole file.
+    Hash,
+    /// Listing a directory.
+    ReadDir,
+}
+
+impl Operation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hash => "hash",
+            Self::ReadDir => "read_dir",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An operation was refused because it would have had to wait too long for
+/// its turn.
+#[derive(thiserror::Error, Debug)]
+#[error("too many {operation} operations, try again later")]
+pub(super) struct Throttled {
+    operation: Operation,
+}
+
+/// A token bucket, kept as the time at which it will be full again.
+struct Bucket {
+    /// How long the bucket takes to refill by one operation.
+    interval: Duration,
+    /// How much earlier than `interval` apart operations may start while the
+    /// bucket has some left.
+    burst: Duration,
+    full_at: Mutex<Instant>,
+}
+
+impl Bucket {
+    fn new(rate: u32, period: Duration) -> Self {
+        let rate = rate.max(1);
+        let interval = period / rate;
+
+        Self {
+            interval,
+            burst: interval * (rate - 1),
+            full_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// How long after `now` the next operation may start, given that the
+    /// bucket is full at `full_at`.
+    fn delay(&self, full_at: Instant, now: Instant) -> Duration {
+        full_at
+            .saturating_duration_since(now)
+            .saturating_sub(self.burst)
+    }
+}
+
+/// The limit on one kind of operation for one session.
+struct Limit {
+    operation: Operation,
+    session: Option<Bucket>,
+    global: Option<Arc<Bucket>>,
+    max_wait: Duration,
+}
+
+impl Limit {
+    async fn acquire(&self) -> Result<(), Throttled> {
+        let buckets = self
+            .session
+            .iter()
+            .chain(self.global.as_deref())
+            .collect::<Vec<_>>();
+
+        let delay = {
+            let now = Instant::now();
+            let mut full_at = buckets
+                .iter()
+                .map(|bucket| bucket.full_at.lock())
+                .collect::<Vec<_>>();
+
+            let delay = buckets
+                .iter()
+                .zip(&full_at)
+                .map(|(bucket, full_at)| bucket.delay(**full_at, now))
+                .max()
+                .unwrap_or_default();
+
+            if delay > self.max_wait {
+                None
+            } else {
+                // The operation takes its turn in every bucket at once, so
+                // that waiting on one doesn't let it skip ahead in another.
+                let start = now + delay;
+                for (bucket, full_at) in buckets.iter().zip(&mut full_at) {
+                    **full_at = (**full_at).max(start) + bucket.interval;
+                }
+
+                Some(delay)
+            }
+        };
+
+        let Some(delay) = delay else {
+            event!(Level::DEBUG, operation = %self.operation, "refused throttled operation");
+            counter!(Metrics::SFTP_THROTTLED, "operation" => self.operation.as_str()).increment(1);
+
+            return Err(Throttled {
+                operation: self.operation,
+            });
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// The limits that all sessions on a server share.
+#[derive(Clone)]
+pub(super) struct Limits {
+    config: ExpensiveOperationLimits,
+    hash: Option<Arc<Bucket>>,
+    read_dir: Option<Arc<Bucket>>,
+    session_bandwidth_limit: Option<u64>,
+    user_bandwidth_limit: Option<u64>,
+    /// The bandwidth limits of the users who are logged in, shared by all of
+    /// their sessions.
+    users: Arc<Mutex<HashMap<String, Weak<Throttle>>>>,
+}
+
+impl Limits {
+    pub(super) fn new(config: &Config) -> Self {
+        let global = |limit: &Option<OperationLimit>| {
+            let limit = limit.as_ref()?;
+
+            Some(Arc::new(Bucket::new(limit.global_rate?, limit.period)))
+        };
+
+        Self {
+            config: config.expensive_operations.clone(),
+            hash: global(&config.expensive_operations.hash),
+            read_dir: global(&config.expensive_operations.read_dir),
+            session_bandwidth_limit: config.session_bandwidth_limit,
+            user_bandwidth_limit: config.user_bandwidth_limit,
+            users: Arc::default(),
+        }
+    }
+
+    /// The bandwidth limit shared by `username`'s sessions.
+    fn user_bandwidth(&self, username: &str) -> Option<Arc<Throttle>> {
+        let limit = self.user_bandwidth_limit?;
+        let mut users = self.users.lock();
+        users.retain(|_, throttle| throttle.strong_count() > 0);
+
+        if let Some(throttle) = users.get(username).and_then(Weak::upgrade) {
+            return Some(throttle);
+        }
+
+        let throttle = Arc::new(Throttle::new(limit));
+        users.insert(username.to_owned(), Arc::downgrade(&throttle));
+
+        Some(throttle)
+    }
+
+    /// The limiter for a new session of `username`.
+    pub(super) fn session(&self, username: &str, max_file_size: Option<u64>) -> Limiter {
+        let limit =
+            |operation, config: &Option<OperationLimit>, global: &Option<Arc<Bucket>>| Limit {
+                operation,
+                session: config
+                    .as_ref()
+                    .and_then(|config| Some(Bucket::new(config.session_rate?, config.period))),
+                global: global.clone(),
+                max_wait: config
+                    .as_ref()
+                    .map_or(Duration::ZERO, |config| config.max_wait),
+            };
+
+        Limiter {
+            hash: limit(Operation::Hash, &self.config.hash, &self.hash),
+            read_dir: limit(Operation::ReadDir, &self.config.read_dir, &self.read_dir),
+            session_bandwidth: self.session_bandwidth_limit.map(Throttle::new),
+            user_bandwidth: self.user_bandwidth(username),
+            max_file_size,
+        }
+    }
+}
+
+/// Limits the expensive operations and the bandwidth of one session.
+pub(super) struct Limiter {
+    hash: Limit,
+    read_dir: Limit,
+    session_bandwidth: Option<Throttle>,
+    user_bandwidth: Option<Arc<Throttle>>,
+    max_file_size: Option<u64>,
+}
+
+impl Limiter {
+    /// How many bytes a file the user uploads may hold at most, from the
+    /// first of [`Config::file_size_limits`] that applies to them.
+    pub(super) fn max_file_size(&self) -> Option<u64> {
+        self.max_file_size
+    }
+
+    /// Waits until `operation` may start, or fails if it would have to wait
+    /// too long.
+    pub(super) async fn acquire(&self, operation: Operation) -> Result<(), Throttled> {
+        match operation {
+            Operation::Hash => self.hash.acquire().await,
+            Operation::ReadDir => self.read_dir.acquire().await,
+        }
+    }
+
+    /// Accounts for `bytes` having been read or written, and waits until the
+    /// session and its user are back under their bandwidth limits.
+    pub(super) async fn transferred(&self, bytes: u64) {
+        let delay = self
+            .session_bandwidth
+            .iter()
+            .chain(self.user_bandwidth.as_deref())
+            .map(|throttle| throttle.reserve(bytes))
+            .max()
+            .unwrap_or_default();
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
@@ -1,9 +1,36 @@
+mod admin;
 mod config;
+mod dispatch;
 mod error;
+mod exec;
+pub(crate) mod extensions;
+mod handles;
 mod hash;
+mod host_keys;
+mod lifecycle;
+mod limits;
+mod reconnects;
 mod server;
+mod sessions;
 mod ssh;
+mod subsystem;
+mod versions;
 
-pub use config::Config;
+pub use config::{
+    ChecksumMismatch,
+    ConcurrentLoginAction,
+    ConcurrentLoginPolicy,
+    Config,
+    ExpensiveOperationLimits,
+    FileSizeLimit,
+    ForwardDestination,
+    OperationLimit,
+    ProbeUser,
+    SessionLimits,
+};
 pub use error::Error;
+pub use host_keys::load as load_host_keys;
+pub use lifecycle::LifecycleHooks;
+pub use sessions::{SessionInfo, Sessions};
 pub use ssh::SshServer;
+pub use subsystem::{Subsystem, SubsystemContext};
@@ -0,0 +1,99 @@
+//! Tracks how often clients reconnect, per source address and per user, to
+//! spot clients that are stuck reconnecting over and over (usually a broken
+//! script on the other end).
+
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use ahash::HashMap;
+use metrics::counter;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use tracing::{Level, event};
+
+use crate::{
+    audit,
+    metrics::Metrics,
+    privacy::pseudonymize,
+    reporting::{self, Incident},
+};
+
+/// The window that reconnects are counted over.
+const WINDOW: Duration = Duration::from_mins(1);
+
+/// How many clients are tracked before clients that haven't connected within
+/// [`WINDOW`] are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+#[derive(Clone)]
+pub(super) struct Reconnects {
+    threshold: Option<u32>,
+    connections: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+}
+
+impl Reconnects {
+    pub(super) fn new(threshold: Option<u32>) -> Self {
+        Self {
+            threshold,
+            connections: Arc::default(),
+        }
+    }
+
+    /// Records a connection from `addr`.
+    pub(super) fn record_addr(&self, addr: &impl ToString) {
+        self.record("addr", &addr.to_string());
+    }
+
+    /// Records a connection that authenticated as `username`.
+    pub(super) fn record_user(&self, username: &str) {
+        self.record("user", username);
+    }
+
+    fn record(&self, kind: &'static str, client: &str) {
+        let now = Instant::now();
+        let key = format!("{kind}:{client}");
+
+        let count = {
+            let mut connections = self.connections.lock();
+
+            if connections.len() > PRUNE_THRESHOLD {
+                connections.retain(|_, times| times.back().is_some_and(|t| now - *t < WINDOW));
+            }
+
+            let times = connections.entry(key).or_default();
+            while times.front().is_some_and(|t| now - *t >= WINDOW) {
+                times.pop_front();
+            }
+            times.push_back(now);
+
+            times.len()
+        };
+
+        if count > 1 {
+            counter!(Metrics::SFTP_RECONNECTS, "kind" => kind).increment(1);
+        }
+
+        // Only warn when the threshold is crossed, not on every connection after.
+        if let Some(threshold) = self.threshold
+            && count == threshold as usize + 1
+        {
+            counter!(Metrics::SFTP_RECONNECT_STORMS, "kind" => kind).increment(1);
+
+            event!(
+                target: audit::TARGET,
+                Level::WARN,
+                kind,
+                client,
+                count,
+                "client is reconnecting repeatedly"
+            );
+            reporting::report(
+                Incident::ReconnectStorm,
+                format!(
+                    "{kind} {} reconnected {count} times within {}s",
+                    pseudonymize(client),
+                    WINDOW.as_secs()
+                ),
+            );
+        }
+    }
+}
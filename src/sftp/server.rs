@@ -1,73 +1,699 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    future::Future,
+    ops::Deref,
     path::Path,
     result::Result,
-    str::FromStr,
     string::ToString,
-    sync::{Arc, LazyLock},
+    sync::{
+        Arc,
+        LazyLock,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::{Duration, SystemTime},
 };
 
-use ahash::RandomState;
-use async_trait::async_trait;
-use camino::{Utf8Path, Utf8PathBuf};
+use ahash::{HashSet, RandomState};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use metrics::histogram;
+use parking_lot::Mutex;
 use path_absolutize::Absolutize;
-use russh_sftp::protocol::{
-    Attrs,
-    Data,
-    File,
-    FileAttributes,
-    Handle,
-    Name,
-    OpenFlags,
-    Packet,
-    Status,
-    StatusCode,
-    Version,
+use russh::keys::{HashAlg, PublicKey};
+use russh_sftp::{
+    protocol::{
+        Attrs,
+        Data,
+        File,
+        FileAttributes,
+        Handle,
+        Name,
+        OpenFlags,
+        Packet,
+        Status,
+        StatusCode,
+        Version,
+    },
+    server::StatusReply,
 };
+use sha2::{Digest, Sha256};
 use thiserror_ext::AsReport;
 use tracing::{Level, event, instrument};
-use whirlwind::ShardSet;
+use whirlwind::ShardMap;
 
-use super::Config;
+use super::{
+    ChecksumMismatch,
+    Config,
+    extensions::{
+        self,
+        ChangeTokenExtension,
+        ChangeTokenReply,
+        CheckFileHandleExtension,
+        CheckFileNameExtension,
+        CopyDataExtension,
+        CopyFileExtension,
+        ExpandPathExtension,
+        ExpectSha256Extension,
+        FstatvfsExtension,
+        HardlinkExtension,
+        LockExtension,
+        PosixRenameExtension,
+        PublicKeysReply,
+        RealpathExtension,
+        SetPublicKeysExtension,
+        StatvfsExtension,
+        UnlockExtension,
+        UsersGroupsByIdExtension,
+        UsersGroupsByIdReply,
+        WriteIfMatchExtension,
+    },
+    handles::HandleSeal,
+    limits::{Limiter, Operation, Throttled},
+    versions,
+};
 use crate::{
+    audit,
+    auth::{AuthClient, AuthError},
+    error_code::ErrorCode,
+    events::{self, Event},
     metrics::Metrics,
+    transfers::{self, Direction, Transfer, Transfers},
     vfs,
-    vfs::{PathMatch, VfsInstance, VfsSet},
+    vfs::{Capabilities, Mount, PathMatch, Vfs, VfsSet},
 };
 
+/// How much of a file to read at a time when checking its checksum.
+const HASH_CHUNK_SIZE: usize = 256 * 1024;
+
+/// The most directory entries sent in reply to a single `SSH_FXP_READDIR`,
+/// which is as many as OpenSSH sends. Bigger directories take more than one,
+/// so that no reply gets too big for clients to take.
+const READDIR_BATCH_SIZE: usize = 100;
+
+/// The most symlinks followed while resolving a path, past which it's taken to
+/// be a loop, as Linux does.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// What is appended to the name of an upload that doesn't match its declared
+/// checksum, when it's quarantined.
+const QUARANTINE_SUFFIX: &str = ".quarantined";
+
+/// How far along the listing of a directory is.
+enum Listing {
+    /// The backend lists the directory a batch at a time, and carries on from
+    /// where it stopped on its own.
+    Paged,
+    /// The backend listed the whole directory at once, and this is what's left
+    /// to send of it.
+    Read(VecDeque<File>),
+}
+
 pub struct SftpSession {
     config: Config,
     cwd_path: Utf8PathBuf,
     vfs_set: VfsSet,
-    version: Option<u32>,
-    readdir_performed: ShardSet<vfs::Handle, RandomState>,
+    version: Mutex<Option<u32>>,
+    /// How far along the directories being listed are, keyed by the handle
+    /// they're being listed on.
+    listings: ShardMap<vfs::Handle, Listing, RandomState>,
+    open_handles: Mutex<HashSet<vfs::Handle>>,
+    /// How many handles are open or being opened, which is what
+    /// [`SessionLimits::max_open_handles`](super::SessionLimits::max_open_handles)
+    /// is checked against.
+    handle_slots: AtomicUsize,
+    deadline: Deadline,
+    username: String,
+    transfers: Transfers,
+    /// The transfers in progress, keyed by the handle they're happening on.
+    active_transfers: Mutex<HashMap<String, Transfer>>,
+    handles: HandleSeal,
+    limiter: Arc<Limiter>,
+    auth_client: AuthClient,
 }
 
 impl SftpSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Config,
-        _authenticated_username: String,
+        authenticated_username: String,
         cwd_path: Utf8PathBuf,
         vfs_set: VfsSet,
+        transfers: Transfers,
+        session_id: u64,
+        limiter: Arc<Limiter>,
+        auth_client: AuthClient,
     ) -> Self {
+        let deadline = Deadline::new(config.op_timeout);
+
         Self {
             config,
             cwd_path,
             vfs_set,
-            version: None,
-            readdir_performed: ShardSet::new_with_hasher(RandomState::default()),
+            version: Mutex::new(None),
+            listings: ShardMap::with_hasher(RandomState::default()),
+            open_handles: Mutex::default(),
+            handle_slots: AtomicUsize::new(0),
+            deadline,
+            username: authenticated_username,
+            transfers,
+            active_transfers: Mutex::default(),
+            handles: HandleSeal::new(session_id),
+            limiter,
+            auth_client,
+        }
+    }
+
+    async fn record_transfer(&self, transfer: Transfer, status: transfers::Status) {
+        if let Err(err) = self.transfers.record(&transfer.finish(status)).await {
+            event!(Level::WARN, err = %err.as_report(), "failed to record transfer");
+        }
+    }
+
+    /// Checks an uploaded file against the SHA-256 its client declared for
+    /// it, and removes or quarantines the file if it doesn't match.
+    ///
+    /// The checksum of the upload itself is used if the client wrote the
+    /// whole file in order, and otherwise the file is read back.
+    async fn verify_checksum(&self, transfer: &Transfer, expected: &str) -> Result<(), Failure> {
+        let Some(PathMatch { vfs, relative_path }) = self.vfs_set.resolve_path(transfer.path())
+        else {
+            return Err(StatusCode::NoSuchFile.into());
+        };
+
+        let actual = self
+            .deadline
+            .run(None, async {
+                let metadata = vfs.stat(&relative_path).await?;
+
+                match transfer.checksum() {
+                    Some(checksum) if metadata.size() == Some(transfer.size()) => Ok(checksum),
+                    _ => {
+                        self.limiter.acquire(Operation::Hash).await?;
+
+                        sha256(&vfs, &relative_path).await.map_err(Failure::from)
+                    }
+                }
+            })
+            .await?;
+
+        if actual == expected {
+            event!(
+                target: audit::TARGET,
+                Level::INFO,
+                username = self.username,
+                path = %transfer.path(),
+                checksum = actual,
+                "upload checksum verified"
+            );
+
+            return Ok(());
+        }
+
+        let quarantined = self.config.checksum_mismatch == ChecksumMismatch::Quarantine;
+        let result = if quarantined {
+            let quarantine_path = Utf8PathBuf::from(format!("{relative_path}{QUARANTINE_SUFFIX}"));
+
+            vfs.rename(&relative_path, &quarantine_path).await
+        } else {
+            vfs.remove_file(&relative_path).await
+        };
+
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            username = self.username,
+            path = %transfer.path(),
+            expected,
+            actual,
+            quarantined,
+            "upload checksum mismatch"
+        );
+
+        if let Err(err) = result {
+            event!(
+                Level::ERROR,
+                err = %err.as_report(),
+                path = %transfer.path(),
+                "failed to remove or quarantine mismatched upload"
+            );
+        }
+
+        Err(Failure {
+            status: StatusCode::Failure,
+            code: Some(ErrorCode::ChecksumMismatch),
+            message: Some(format!(
+                "file has SHA-256 {actual}, but {expected} was expected"
+            )),
+        })
+    }
+
+    /// Refuses to let a file in `vfs` get to `size` bytes if that's more than
+    /// the mount's or the user's `max_file_size`, whichever is smaller.
+    fn check_file_size(&self, vfs: &Mount, size: u64) -> Result<(), Failure> {
+        let max_file_size = [self.limiter.max_file_size(), vfs.options().max_file_size]
+            .into_iter()
+            .flatten()
+            .min();
+
+        match max_file_size {
+            Some(max_file_size) if size > max_file_size => Err(Failure {
+                status: StatusCode::Failure,
+                code: Some(ErrorCode::TooLarge),
+                message: Some(format!(
+                    "files can't be bigger than {max_file_size} bytes here"
+                )),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes a slot for a handle that's about to be opened, or refuses to
+    /// open anything else if the session already has as many handles open as
+    /// [`SessionLimits::max_open_handles`](super::SessionLimits::max_open_handles)
+    /// allows. Opens that are still going on count too, so that ones that
+    /// arrive together can't all get past the limit.
+    fn reserve_handle(&self) -> Result<HandleSlot<'_>, Failure> {
+        let max_open_handles = self.config.session_limits.max_open_handles;
+
+        let reserved =
+            self.handle_slots
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slots| {
+                    (slots < max_open_handles).then_some(slots + 1)
+                });
+
+        match reserved {
+            Ok(_) => Ok(HandleSlot(&self.handle_slots)),
+            Err(_) => Err(Failure {
+                status: StatusCode::Failure,
+                code: Some(ErrorCode::TooManyHandles),
+                message: Some(format!(
+                    "sessions can't have more than {max_open_handles} handles open"
+                )),
+            }),
+        }
+    }
+
+    /// Records that `handle` was opened in the slot [`Self::reserve_handle`]
+    /// took for it.
+    fn opened(&self, handle: &vfs::Handle, slot: HandleSlot<'_>) {
+        self.open_handles.lock().insert(handle.clone());
+        std::mem::forget(slot);
+    }
+
+    /// Gives back the slot of `handle` once it's closed.
+    fn closed(&self, handle: &vfs::Handle) {
+        if self.open_handles.lock().remove(handle) {
+            self.handle_slots.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Turns directory entries from a backend into what's sent to the client.
+    fn files(&self, entries: Vec<(Utf8PathBuf, vfs::Metadata)>) -> Vec<File> {
+        entries
+            .into_iter()
+            .map(|(path, metadata)| {
+                File::new(
+                    path.as_str(),
+                    metadata
+                        .file_attrs(self.config.default_file_mode, self.config.default_dir_mode),
+                )
+            })
+            .collect()
+    }
+
+    /// Refuses to create a file, or a directory if `is_dir`, at
+    /// `relative_path` if the filename filter of `vfs` doesn't allow its name,
+    /// and records the refusal in the audit log.
+    fn check_name(
+        &self,
+        vfs: &Mount,
+        relative_path: &Utf8Path,
+        is_dir: bool,
+    ) -> Result<(), Failure> {
+        let result = if is_dir {
+            vfs.check_dir_name(relative_path)
+        } else {
+            vfs.check_file_name(relative_path)
+        };
+
+        if let Err(err) = result {
+            event!(
+                target: audit::TARGET,
+                Level::WARN,
+                username = self.username,
+                path = %vfs.vfs_root().join(relative_path),
+                "file name refused"
+            );
+
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Renames `old_path` to `new_path`, replacing anything already there,
+    /// once the mount they're in turns out to have the `needed` capabilities.
+    async fn rename_path(
+        &self,
+        old_path: &str,
+        new_path: &str,
+        needed: Capabilities,
+    ) -> Result<(), Failure> {
+        path_match2(
+            &self.vfs_set,
+            &self.deadline,
+            &self.cwd_path,
+            old_path,
+            new_path,
+            async |vfs, path1, path2| {
+                require(&vfs, needed)?;
+
+                let is_dir = vfs
+                    .stat(path1)
+                    .await
+                    .is_ok_and(|metadata| metadata.is_directory());
+                self.check_name(&vfs, path2, is_dir)?;
+
+                vfs.rename(path1, path2).await?;
+
+                if let (Some(from), Some(to)) = (
+                    absolute_path(&self.cwd_path, old_path),
+                    absolute_path(&self.cwd_path, new_path),
+                ) {
+                    events::publish(Event::Renamed {
+                        username: self.username.clone(),
+                        from,
+                        to,
+                    });
+                }
+
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// The user's home directory, which is where sessions start out.
+    fn home_path(&self) -> &Utf8Path {
+        &self.cwd_path
+    }
+
+    /// Resolves `path` against the working directory, after expanding a
+    /// leading `~` to the user's home directory. `~user` only expands for the
+    /// user themselves, since nobody else's home is in their view.
+    fn expand_path(&self, path: &str) -> Result<String, Failure> {
+        let path = match path.strip_prefix('~') {
+            Some(rest) => {
+                let (user, rest) = rest.split_once('/').unwrap_or((rest, ""));
+                if !user.is_empty() && user != self.username {
+                    return Err(StatusCode::NoSuchFile.into());
+                }
+
+                self.home_path().join(rest).into_string()
+            }
+            None => path.to_owned(),
+        };
+
+        absolute_path(&self.cwd_path, &path)
+            .map(Utf8PathBuf::into_string)
+            .ok_or_else(|| StatusCode::Failure.into())
+    }
+
+    /// Resolves `path` to the canonical path of what's there, following the
+    /// symlinks on the way wherever they lead, into other mounts too, and
+    /// returns it along with its attributes. The directories that mounts are
+    /// in exist, but nothing else outside of the mounts does.
+    ///
+    /// Unless `must_exist` is set, a path that doesn't exist is resolved as far
+    /// as it can be, and comes back without attributes.
+    async fn canonicalize(
+        &self,
+        path: &str,
+        must_exist: bool,
+    ) -> Result<(Utf8PathBuf, FileAttributes), Failure> {
+        let path = self.expand_path(path)?;
+
+        // What's left of the path to resolve, last component first.
+        let mut pending = names(Utf8Path::new(&path));
+        let mut resolved = Utf8PathBuf::from("/");
+        let mut hops = 0;
+
+        while let Some(component) = pending.pop() {
+            let candidate = resolved.join(&component);
+
+            let Some(PathMatch { vfs, relative_path }) = self.vfs_set.resolve_path(&candidate)
+            else {
+                resolved = candidate;
+                continue;
+            };
+
+            if !vfs.capabilities().contains(Capabilities::SYMLINKS) {
+                resolved = candidate;
+                continue;
+            }
+
+            match vfs.readlink(&relative_path).await {
+                Ok(target) => {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        return Err(Failure::new(StatusCode::Failure, ErrorCode::InvalidPath));
+                    }
+
+                    // Links are either relative to the directory they're in,
+                    // or absolute in the paths that clients see.
+                    let target = absolute_path(&resolved, target.as_str())
+                        .ok_or_else(|| Failure::new(StatusCode::Failure, ErrorCode::InvalidPath))?;
+                    pending.extend(names(&target));
+                    resolved = Utf8PathBuf::from("/");
+                }
+                Err(err) if err.is_not_found() && !must_exist => resolved = candidate,
+                Err(err) if err.is_not_found() || matches!(err, vfs::Error::WouldEscape) => {
+                    return Err(err.into());
+                }
+                // Anything else there isn't a symlink.
+                Err(_) => resolved = candidate,
+            }
+        }
+
+        let attrs = match self.vfs_set.resolve_path(&resolved) {
+            Some(PathMatch { vfs, relative_path }) => match vfs.stat(&relative_path).await {
+                Ok(metadata) => {
+                    metadata.file_attrs(self.config.default_file_mode, self.config.default_dir_mode)
+                }
+                Err(err) if err.is_not_found() && !must_exist => FileAttributes::empty(),
+                Err(err) => return Err(err.into()),
+            },
+            None if self
+                .vfs_set
+                .mounts()
+                .any(|(root, _)| root.starts_with(&resolved)) =>
+            {
+                FileAttributes {
+                    permissions: Some((0o004 << 12) | self.config.default_dir_mode),
+                    ..FileAttributes::default()
+                }
+            }
+            None if !must_exist => FileAttributes::empty(),
+            None => return Err(StatusCode::NoSuchFile.into()),
+        };
+
+        Ok((resolved, attrs))
+    }
+
+    /// Copies up to `len` bytes from `src_offset` in `src` to `dst_offset` in
+    /// `dst`, stopping early if `src` ends first, and returns how many were
+    /// copied. The backend does the copying if it can, and otherwise it's read
+    /// and written a chunk at a time, like between mounts.
+    ///
+    /// The copy isn't held up by the mounts' bandwidth limits, which is left to
+    /// [`Self::copied`] once it's done.
+    async fn copy_data(
+        &self,
+        (src_vfs, src, src_offset): (&Mount, &vfs::Handle, u64),
+        (dst_vfs, dst, dst_offset): (&Mount, &vfs::Handle, u64),
+        len: u64,
+    ) -> Result<u64, Failure> {
+        let len = match src_vfs.stat_fd(src).await?.size() {
+            Some(size) => len.min(size.saturating_sub(src_offset)),
+            None => len,
+        };
+        let end = dst_vfs.write_offset(dst, dst_offset).await?;
+        self.check_file_size(dst_vfs, end.saturating_add(len))?;
+
+        match dst_vfs
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+        {
+            Ok(copied) => return Ok(copied),
+            Err(vfs::Error::UnsupportedMethod) => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut copied = 0;
+        while copied < len {
+            let max = extensions::MAX_READ_LENGTH as usize;
+            let chunk = usize::try_from(len - copied).map_or(max, |left| left.min(max));
+            let Some(data) = src_vfs.read(src, src_offset + copied, chunk).await? else {
+                break;
+            };
+            if data.is_empty() {
+                break;
+            }
+
+            dst_vfs.write(dst, dst_offset + copied, &data).await?;
+            copied += data.len() as u64;
+        }
+
+        Ok(copied)
+    }
+
+    /// Waits until the mounts that `bytes` were copied between are back under
+    /// their bandwidth limits. A copy within one mount only counts once.
+    async fn copied(src_vfs: &Arc<Mount>, dst_vfs: &Arc<Mount>, bytes: u64) {
+        src_vfs.transferred(bytes).await;
+
+        if !Arc::ptr_eq(src_vfs, dst_vfs) {
+            dst_vfs.transferred(bytes).await;
+        }
+    }
+
+    /// Copies the file at `source` to `destination`, which can be in another
+    /// mount. Anything already at `destination` is only replaced if
+    /// `overwrite`.
+    async fn copy_file(
+        &self,
+        source: &str,
+        destination: &str,
+        overwrite: bool,
+    ) -> Result<(), Failure> {
+        let resolve = |path: &str| {
+            let absolute_path = absolute_path(&self.cwd_path, path)
+                .ok_or_else(|| Failure::new(StatusCode::Failure, ErrorCode::InvalidPath))?;
+
+            self.vfs_set
+                .resolve_path(&absolute_path)
+                .ok_or_else(|| Failure::from(StatusCode::NoSuchFile))
+        };
+
+        let PathMatch {
+            vfs: src_vfs,
+            relative_path: src_path,
+        } = resolve(source)?;
+        let PathMatch {
+            vfs: dst_vfs,
+            relative_path: dst_path,
+        } = resolve(destination)?;
+
+        // Opening the destination would truncate the source before it's read.
+        if Arc::ptr_eq(&src_vfs, &dst_vfs) && src_path == dst_path {
+            return Err(Failure::new(StatusCode::Failure, ErrorCode::BadRequest));
+        }
+
+        self.check_name(&dst_vfs, &dst_path, false)?;
+
+        let mut flags = vfs::OpenFlags::WRITE | vfs::OpenFlags::CREATE | vfs::OpenFlags::TRUNCATE;
+        if !overwrite {
+            flags |= vfs::OpenFlags::EXCLUDE;
+        }
+
+        let copied = self
+            .deadline
+            .run(None, async {
+                let src = src_vfs.open(&src_path, vfs::OpenFlags::READ).await?;
+                let dst = match dst_vfs.open(&dst_path, flags).await {
+                    Ok(dst) => dst,
+                    Err(err) => {
+                        let _ = src_vfs.close(src).await;
+                        return Err(err.into());
+                    }
+                };
+
+                let copied = self
+                    .copy_data((&src_vfs, &src, 0), (&dst_vfs, &dst, 0), u64::MAX)
+                    .await;
+                let closed = dst_vfs.close(dst).await;
+                let _ = src_vfs.close(src).await;
+
+                let copied = copied?;
+                closed?;
+
+                Ok(copied)
+            })
+            .await?;
+
+        Self::copied(&src_vfs, &dst_vfs, copied).await;
+
+        Ok(())
+    }
+}
+
+/// An [`SftpSession`] shared between the requests being handled on it, so that
+/// they can run concurrently. The session is dropped once the stream has ended
+/// and the last of them is done with it.
+#[derive(Clone)]
+pub(super) struct SharedSession(Arc<SftpSession>);
+
+impl From<SftpSession> for SharedSession {
+    fn from(session: SftpSession) -> Self {
+        Self(Arc::new(session))
+    }
+}
+
+impl Deref for SharedSession {
+    type Target = SftpSession;
+
+    fn deref(&self) -> &SftpSession {
+        &self.0
+    }
+}
+
+impl Drop for SftpSession {
+    /// Closes any handles the client left open, so that they don't outlive the
+    /// session and keep holding on to files (or write claims on them), and
+    /// records the transfers on them as interrupted. Uploads that were being
+    /// held back until they were closed are thrown away.
+    fn drop(&mut self) {
+        let open_handles = std::mem::take(self.open_handles.get_mut());
+        let active_transfers = std::mem::take(self.active_transfers.get_mut());
+
+        if open_handles.is_empty() {
+            return;
         }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let vfs_set = self.vfs_set.clone();
+        let transfers = self.transfers.clone();
+        runtime.spawn(async move {
+            for transfer in active_transfers.into_values() {
+                let record = transfer.finish(transfers::Status::Interrupted);
+
+                if let Err(err) = transfers.record(&record).await {
+                    event!(Level::WARN, err = %err.as_report(), "failed to record transfer");
+                }
+            }
+
+            for handle in open_handles {
+                if let Some(vfs) = vfs_set.resolve_handle(&handle).await
+                    && let Err(err) = vfs.abort(handle).await
+                {
+                    event!(
+                        Level::WARN,
+                        err = %err.as_report(),
+                        "failed to close leftover handle"
+                    );
+                }
+            }
+        });
     }
 }
 
-#[async_trait]
-impl russh_sftp::server::Handler for SftpSession {
-    type Error = StatusCode;
+impl russh_sftp::server::Handler for SharedSession {
+    type Error = Failure;
 
     fn unimplemented(&self) -> Self::Error {
-        StatusCode::OpUnsupported
+        StatusCode::OpUnsupported.into()
     }
 
     async fn init(
@@ -75,20 +701,23 @@ impl russh_sftp::server::Handler for SftpSession {
         version: u32,
         _extensions: HashMap<String, String>,
     ) -> Result<Version, Self::Error> {
-        if self.version.is_some() {
+        let mut negotiated = self.version.lock();
+
+        if negotiated.is_some() {
             event!(
                 Level::ERROR,
                 new_version = version,
-                old_version = self.version,
+                old_version = *negotiated,
                 "Tried to negotiate version after initial handshake"
             );
-            Err(StatusCode::BadMessage)
+            Err(Failure::new(StatusCode::Failure, ErrorCode::BadRequest))
         } else {
-            self.version = Some(version);
+            let version = versions::negotiate(version);
+            *negotiated = Some(version);
 
             Ok(Version {
                 version,
-                extensions: HashMap::new(),
+                extensions: extensions::advertised(self.vfs_set.capabilities()),
             })
         }
     }
@@ -98,42 +727,134 @@ impl russh_sftp::server::Handler for SftpSession {
         id: u32,
         path: String,
         pflags: OpenFlags,
-        _attrs: FileAttributes,
+        attrs: FileAttributes,
     ) -> Result<Handle, Self::Error> {
-        path_match(
+        let slot = self.reserve_handle()?;
+
+        // Waiting for another writer to close the file isn't counted against
+        // the time the open is given.
+        if pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            let resolved = absolute_path(&self.cwd_path, &path)
+                .and_then(|path| self.vfs_set.resolve_path(&path));
+
+            if let Some(PathMatch { vfs, relative_path }) = resolved {
+                vfs.wait_for_writer(&relative_path).await?;
+            }
+        }
+
+        let handle = path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| {
-                if let Ok(handle) = vfs.open(relative_path, vfs::OpenFlags::from(pflags)).await {
-                    Ok(Handle {
-                        id,
-                        handle: handle.to_string(),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
+                if pflags.contains(OpenFlags::CREATE) {
+                    self.check_name(&vfs, relative_path, false)?;
+                }
+                if let Some(size) = attrs.size.filter(|_| pflags.contains(OpenFlags::WRITE)) {
+                    self.check_file_size(&vfs, size)?;
+                }
+
+                let handle = vfs
+                    .open(relative_path, vfs::OpenFlags::from(pflags))
+                    .await
+                    .map_err(Failure::from)?;
+
+                // Clients that say how big an upload will be get the space for
+                // it up front, so that it can't run out halfway through.
+                if let Some(size) = attrs.size.filter(|_| pflags.contains(OpenFlags::WRITE)) {
+                    match vfs.allocate(&handle, 0, size).await {
+                        Ok(()) => {}
+                        Err(err @ vfs::Error::NoSpace) => {
+                            let _ = vfs.close(handle).await;
+                            return Err(Failure::from(err));
+                        }
+                        Err(err) => {
+                            event!(
+                                Level::DEBUG,
+                                %path,
+                                err = %err.as_report(),
+                                "couldn't allocate space for upload"
+                            );
+                        }
+                    }
                 }
+
+                Ok(handle)
             },
         )
-        .await
+        .await?;
+
+        self.opened(&handle, slot);
+        let handle = self.handles.seal(&handle);
+
+        if let Some(path) = absolute_path(&self.cwd_path, &path) {
+            let direction = if pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+                Direction::Upload
+            } else {
+                Direction::Download
+            };
+
+            self.active_transfers.lock().insert(
+                handle.clone(),
+                Transfer::start(&self.username, &path, direction),
+            );
+        }
+
+        Ok(Handle { id, handle })
     }
 
     async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
-        handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            self.readdir_performed.remove(&handle).await;
+        // Closing is the one thing a poisoned handle is still good for.
+        if let Ok(handle) = self.handles.unseal(&handle) {
+            self.deadline.forget(&handle);
+        }
+
+        let transfer = self.active_transfers.lock().remove(&handle);
+
+        let result = handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle,
+            async |vfs, handle| {
+                self.listings.remove(&handle).await;
+                self.closed(&handle);
+
+                vfs.close(handle).await?;
 
-            if let Ok(()) = vfs.close(handle).await {
                 Ok(Status {
                     id,
                     status_code: StatusCode::Ok,
                     error_message: String::new(),
                     language_tag: String::new(),
                 })
-            } else {
-                Err(StatusCode::Failure)
+            },
+        )
+        .await;
+
+        let Some(transfer) = transfer else {
+            return result;
+        };
+
+        let result = match (result, transfer.expected_checksum()) {
+            (Ok(status), Some(expected)) => self
+                .verify_checksum(&transfer, expected)
+                .await
+                .map(|()| status),
+            (result, _) => result,
+        };
+
+        let status = match &result {
+            Ok(_) => transfers::Status::Completed,
+            Err(failure) if failure.code == Some(ErrorCode::ChecksumMismatch) => {
+                transfers::Status::Rejected
             }
-        })
-        .await
+            Err(_) => transfers::Status::Failed,
+        };
+        self.record_transfer(transfer, status).await;
+
+        result
     }
 
     #[instrument(skip_all, fields(size = len, vfs))]
@@ -146,22 +867,37 @@ impl russh_sftp::server::Handler for SftpSession {
     ) -> Result<Data, Self::Error> {
         let start_time = SystemTime::now();
 
-        let data = handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            tracing::Span::current().record("vfs", vfs.vfs_root().as_str());
+        let (data, vfs) = handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle.clone(),
+            async |vfs, handle| {
+                tracing::Span::current().record("vfs", vfs.vfs_root().as_str());
+
+                let len = len.min(extensions::MAX_READ_LENGTH);
 
-            match vfs.read(&handle, offset, len as usize).await {
-                Ok(Some(data)) => Ok(Data { id, data }),
-                Ok(None) => Err(StatusCode::Eof),
-                Err(_) => Err(StatusCode::Failure),
-            }
-        })
+                match vfs.read(&handle, offset, len as usize).await {
+                    Ok(Some(data)) => Ok((Data { id, data }, vfs)),
+                    Ok(None) => Err(StatusCode::Eof.into()),
+                    Err(err) => Err(err.into()),
+                }
+            },
+        )
         .await?;
 
+        if let Some(transfer) = self.active_transfers.lock().get_mut(&handle) {
+            transfer.transferred(offset, &data.data);
+        }
+
         let end_time = SystemTime::now();
         if let Ok(duration) = end_time.duration_since(start_time) {
             histogram!(Metrics::SFTP_READ_DURATION).record(duration);
         }
 
+        vfs.transferred(data.data.len() as u64).await;
+        self.limiter.transferred(data.data.len() as u64).await;
+
         Ok(data)
     }
 
@@ -175,63 +911,80 @@ impl russh_sftp::server::Handler for SftpSession {
     ) -> Result<Status, Self::Error> {
         let start_time = SystemTime::now();
 
-        let status = handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            tracing::Span::current().record("vfs", vfs.vfs_root().as_str());
+        let (status, vfs) = handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle.clone(),
+            async |vfs, handle| {
+                tracing::Span::current().record("vfs", vfs.vfs_root().as_str());
+
+                // Appended writes go to the end of the file, not to `offset`.
+                let end = vfs.write_offset(&handle, offset).await?;
+                self.check_file_size(&vfs, end.saturating_add(data.len() as u64))?;
+                vfs.write(&handle, offset, data.as_slice()).await?;
 
-            if let Ok(()) = vfs.write(&handle, offset, data.as_slice()).await {
-                Ok(Status {
+                let status = Status {
                     id,
                     status_code: StatusCode::Ok,
                     error_message: String::new(),
                     language_tag: String::new(),
-                })
-            } else {
-                Err(StatusCode::Failure)
-            }
-        })
+                };
+
+                Ok((status, vfs))
+            },
+        )
         .await?;
 
+        if let Some(transfer) = self.active_transfers.lock().get_mut(&handle) {
+            transfer.transferred(offset, &data);
+        }
+
         let end_time = SystemTime::now();
         if let Ok(duration) = end_time.duration_since(start_time) {
             histogram!(Metrics::SFTP_WRITE_DURATION).record(duration);
         }
 
+        vfs.transferred(data.len() as u64).await;
+        self.limiter.transferred(data.len() as u64).await;
+
         Ok(status)
     }
     async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| {
-                if let Ok(metadata) = vfs.stat_link(relative_path).await {
-                    Ok(Attrs {
-                        id,
-                        attrs: metadata.file_attrs(
-                            self.config.default_file_mode,
-                            self.config.default_dir_mode,
-                        ),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
+                let metadata = vfs.stat_link(relative_path).await?;
+
+                Ok(Attrs {
+                    id,
+                    attrs: metadata
+                        .file_attrs(self.config.default_file_mode, self.config.default_dir_mode),
+                })
             },
         )
         .await
     }
 
     async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
-        handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            if let Ok(metadata) = vfs.stat_fd(&handle).await {
+        handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle,
+            async |vfs, handle| {
+                let metadata = vfs.stat_fd(&handle).await?;
+
                 Ok(Attrs {
                     id,
                     attrs: metadata
                         .file_attrs(self.config.default_file_mode, self.config.default_dir_mode),
                 })
-            } else {
-                Err(StatusCode::Failure)
-            }
-        })
+            },
+        )
         .await
     }
 
@@ -243,15 +996,25 @@ impl russh_sftp::server::Handler for SftpSession {
     ) -> Result<Status, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| {
+                if let Some(size) = attrs.size {
+                    self.check_file_size(&vfs, size)?;
+                    vfs.truncate(relative_path, size).await?;
+                }
+
+                if let Some(mode) = permissions(&vfs, &attrs) {
+                    vfs.set_permissions(relative_path, mode).await?;
+                }
+
                 let atime = attrs.atime.map(to_system_time);
                 let mtime = attrs.mtime.map(to_system_time);
 
                 vfs.set_times(relative_path, atime, mtime)
                     .await
-                    .map_err(|_| StatusCode::Failure)
+                    .map_err(Failure::from)
             },
         )
         .await
@@ -269,14 +1032,29 @@ impl russh_sftp::server::Handler for SftpSession {
         handle: String,
         attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
-        handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            let atime = attrs.atime.map(to_system_time);
-            let mtime = attrs.mtime.map(to_system_time);
+        handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle,
+            async |vfs, handle| {
+                if let Some(size) = attrs.size {
+                    self.check_file_size(&vfs, size)?;
+                    vfs.truncate_fd(&handle, size).await?;
+                }
 
-            vfs.set_times_fd(&handle, atime, mtime)
-                .await
-                .map_err(|_| StatusCode::Failure)
-        })
+                if let Some(mode) = permissions(&vfs, &attrs) {
+                    vfs.set_permissions_fd(&handle, mode).await?;
+                }
+
+                let atime = attrs.atime.map(to_system_time);
+                let mtime = attrs.mtime.map(to_system_time);
+
+                vfs.set_times_fd(&handle, atime, mtime)
+                    .await
+                    .map_err(Failure::from)
+            },
+        )
         .await
         .map(|()| Status {
             id,
@@ -287,74 +1065,99 @@ impl russh_sftp::server::Handler for SftpSession {
     }
 
     async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
-        path_match(
+        let slot = self.reserve_handle()?;
+
+        let dir_handle = path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
-            async |vfs, relative_path| {
-                if let Ok(dir_handle) = vfs.open_dir(relative_path).await {
-                    Ok(Handle {
-                        id,
-                        handle: dir_handle.to_string(),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
-            },
+            async |vfs, relative_path| vfs.open_dir(relative_path).await.map_err(Failure::from),
         )
-        .await
+        .await?;
+
+        self.opened(&dir_handle, slot);
+
+        Ok(Handle {
+            id,
+            handle: self.handles.seal(&dir_handle),
+        })
     }
 
     async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
-        handle_match(&self.vfs_set, handle, async |vfs, handle| {
-            let readdir_performed = self.readdir_performed.contains(&handle).await;
-
-            if !readdir_performed {
-                if let Ok(dirs) = vfs.read_dir(&handle).await {
-                    let dirs = dirs
-                        .iter()
-                        .map(|(path, metadata)| {
-                            File::new(
-                                path.as_str(),
-                                metadata.file_attrs(
-                                    self.config.default_file_mode,
-                                    self.config.default_dir_mode,
-                                ),
-                            )
-                        })
-                        .collect();
+        handle_match(
+            &self.vfs_set,
+            &self.deadline,
+            &self.handles,
+            handle,
+            async |vfs, handle| {
+                // Backends that can list a directory a batch at a time do so.
+                // The others list it in full the first time, and it's sent a
+                // batch at a time from then on.
+                let (listing, files) = match self.listings.remove(&handle).await {
+                    Some(Listing::Paged) => {
+                        let entries = vfs.read_dir_next(&handle, READDIR_BATCH_SIZE).await?;
+                        (Listing::Paged, self.files(entries))
+                    }
+                    Some(Listing::Read(mut listing)) => {
+                        let files = listing
+                            .drain(..listing.len().min(READDIR_BATCH_SIZE))
+                            .collect();
+                        (Listing::Read(listing), files)
+                    }
+                    None => {
+                        self.limiter.acquire(Operation::ReadDir).await?;
 
-                    self.readdir_performed.insert(handle).await;
+                        match vfs.read_dir_next(&handle, READDIR_BATCH_SIZE).await {
+                            Ok(entries) => (Listing::Paged, self.files(entries)),
+                            Err(vfs::Error::UnsupportedMethod) => {
+                                let mut listing: VecDeque<_> =
+                                    self.files(vfs.read_dir(&handle).await?).into();
+                                let files = listing
+                                    .drain(..listing.len().min(READDIR_BATCH_SIZE))
+                                    .collect();
+                                (Listing::Read(listing), files)
+                            }
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                };
+                // The listing is kept even once it's done, so that reading on
+                // keeps hitting the end rather than starting over.
+                self.listings.insert(handle, listing).await;
 
-                    Ok(Name { id, files: dirs })
-                } else {
-                    Err(StatusCode::Failure)
+                if files.is_empty() {
+                    return Err(StatusCode::Eof.into());
                 }
-            } else {
-                Err(StatusCode::Eof)
-            }
-        })
+
+                Ok(Name { id, files })
+            },
+        )
         .await
     }
 
     async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &filename,
-            async |vfs, relative_path| match vfs.remove_file(relative_path).await {
-                Ok(()) => Ok(Status {
+            async |vfs, relative_path| {
+                vfs.remove_file(relative_path).await?;
+
+                if let Some(path) = absolute_path(&self.cwd_path, &filename) {
+                    events::publish(Event::FileDeleted {
+                        username: self.username.clone(),
+                        path,
+                    });
+                }
+
+                Ok(Status {
                     id,
                     status_code: StatusCode::Ok,
                     error_message: String::new(),
                     language_tag: String::new(),
-                }),
-                Err(err) => Ok(Status {
-                    id,
-                    status_code: StatusCode::Failure,
-                    error_message: err.as_report().to_string(),
-                    language_tag: LANGUAGE_TAG.clone(),
-                }),
+                })
             },
         )
         .await
@@ -368,21 +1171,21 @@ impl russh_sftp::server::Handler for SftpSession {
     ) -> Result<Status, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
-            async |vfs, relative_path| match vfs.mkdir(relative_path).await {
-                Ok(()) => Ok(Status {
-                    id,
-                    status_code: StatusCode::Ok,
-                    error_message: String::new(),
-                    language_tag: String::new(),
-                }),
-                Err(err) => Ok(Status {
-                    id,
-                    status_code: StatusCode::Failure,
-                    error_message: err.as_report().to_string(),
-                    language_tag: LANGUAGE_TAG.clone(),
-                }),
+            async |vfs, relative_path| {
+                self.check_name(&vfs, relative_path, true)?;
+
+                match vfs.mkdir(relative_path).await {
+                    Ok(()) => Ok(Status {
+                        id,
+                        status_code: StatusCode::Ok,
+                        error_message: String::new(),
+                        language_tag: String::new(),
+                    }),
+                    Err(err) => Err(err.into()),
+                }
             },
         )
         .await
@@ -391,6 +1194,7 @@ impl russh_sftp::server::Handler for SftpSession {
     async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| match vfs.remove_dir(relative_path).await {
@@ -400,50 +1204,38 @@ impl russh_sftp::server::Handler for SftpSession {
                     error_message: String::new(),
                     language_tag: String::new(),
                 }),
-                Err(err) => Ok(Status {
-                    id,
-                    status_code: StatusCode::Failure,
-                    error_message: err.as_report().to_string(),
-                    language_tag: LANGUAGE_TAG.clone(),
-                }),
+                Err(err) => Err(err.into()),
             },
         )
         .await
     }
 
     async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
-        use path_absolutize::Absolutize;
-
-        let path = Path::new(&path)
-            .absolutize_from(self.cwd_path.as_std_path())
-            .map_err(|_| StatusCode::Failure)?
-            .to_str()
-            .map(ToString::to_string)
-            .ok_or(StatusCode::Failure)?;
+        let (path, attrs) = self
+            .deadline
+            .run(None, self.canonicalize(&path, true))
+            .await?;
 
         Ok(Name {
             id,
-            files: vec![File::dummy(path)],
+            files: vec![File::new(path, attrs)],
         })
     }
 
     async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| {
-                if let Ok(metadata) = vfs.stat(relative_path).await {
-                    Ok(Attrs {
-                        id,
-                        attrs: metadata.file_attrs(
-                            self.config.default_file_mode,
-                            self.config.default_dir_mode,
-                        ),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
+                let metadata = vfs.stat(relative_path).await?;
+
+                Ok(Attrs {
+                    id,
+                    attrs: metadata
+                        .file_attrs(self.config.default_file_mode, self.config.default_dir_mode),
+                })
             },
         )
         .await
@@ -455,41 +1247,31 @@ impl russh_sftp::server::Handler for SftpSession {
         old_path: String,
         new_path: String,
     ) -> Result<Status, Self::Error> {
-        path_match2(
-            &self.vfs_set,
-            &self.cwd_path,
-            &old_path,
-            &new_path,
-            async move |vfs, path1, path2| {
-                if vfs.rename(path1, path2).await.is_ok() {
-                    Ok(Status {
-                        id,
-                        status_code: StatusCode::Ok,
-                        error_message: String::new(),
-                        language_tag: String::new(),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
-            },
-        )
-        .await
+        self.rename_path(&old_path, &new_path, Capabilities::empty())
+            .await?;
+
+        Ok(Status {
+            id,
+            status_code: StatusCode::Ok,
+            error_message: String::new(),
+            language_tag: String::new(),
+        })
     }
 
     async fn readlink(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
         path_match(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &path,
             async |vfs, relative_path| {
-                if let Ok(link_contents) = vfs.readlink(relative_path).await {
-                    Ok(Name {
-                        id,
-                        files: vec![File::dummy(link_contents)],
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
+                require(&vfs, Capabilities::SYMLINKS)?;
+                let link_contents = vfs.readlink(relative_path).await?;
+
+                Ok(Name {
+                    id,
+                    files: vec![File::dummy(link_contents)],
+                })
             },
         )
         .await
@@ -498,102 +1280,616 @@ impl russh_sftp::server::Handler for SftpSession {
     async fn symlink(
         &mut self,
         id: u32,
-        link_path: String,
-        target_path: String,
+        first_path: String,
+        second_path: String,
     ) -> Result<Status, Self::Error> {
+        let (link_path, target_path) = symlink_paths(&first_path, &second_path);
+
         path_match2(
             &self.vfs_set,
+            &self.deadline,
             &self.cwd_path,
             &link_path,
             &target_path,
-            async move |vfs, path1, path2| {
-                if vfs.symlink(path1, path2).await.is_ok() {
-                    Ok(Status {
-                        id,
-                        status_code: StatusCode::Ok,
-                        error_message: String::new(),
-                        language_tag: String::new(),
-                    })
-                } else {
-                    Err(StatusCode::Failure)
-                }
+            async |vfs, path1, path2| {
+                require(&vfs, Capabilities::SYMLINKS)?;
+                self.check_name(&vfs, path1, false)?;
+                vfs.symlink(path1, path2).await?;
+
+                Ok(Status {
+                    id,
+                    status_code: StatusCode::Ok,
+                    error_message: String::new(),
+                    language_tag: String::new(),
+                })
             },
         )
         .await
     }
 
+    #[allow(clippy::too_many_lines)]
     async fn extended(
         &mut self,
-        _id: u32,
-        _request: String,
-        _data: Vec<u8>,
+        id: u32,
+        request: String,
+        data: Vec<u8>,
     ) -> Result<Packet, Self::Error> {
-        Err(StatusCode::OpUnsupported)
+        match request.as_str() {
+            extensions::CHANGE_TOKEN => {
+                let ChangeTokenExtension { path } = extensions::parse(data)?;
+
+                let metadata = path_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.cwd_path,
+                    &path,
+                    async |vfs, relative_path| vfs.stat(relative_path).await.map_err(Failure::from),
+                )
+                .await?;
+
+                extensions::reply(
+                    id,
+                    &ChangeTokenReply {
+                        token: metadata.change_token(),
+                    },
+                )
+                .map_err(Failure::from)
+            }
+            extensions::WRITE_IF_MATCH => {
+                let WriteIfMatchExtension {
+                    handle,
+                    token,
+                    offset,
+                    data,
+                } = extensions::parse(data)?;
+
+                let (metadata, vfs) = handle_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    handle,
+                    async |vfs, handle| {
+                        let end = vfs.write_offset(&handle, offset).await?;
+                        self.check_file_size(&vfs, end.saturating_add(data.len() as u64))?;
+                        vfs.write_if_match(&handle, offset, &data, &token).await?;
+                        let metadata = vfs.stat_fd(&handle).await?;
+
+                        Ok((metadata, vfs))
+                    },
+                )
+                .await?;
+                vfs.transferred(data.len() as u64).await;
+
+                extensions::reply(
+                    id,
+                    &ChangeTokenReply {
+                        token: metadata.change_token(),
+                    },
+                )
+                .map_err(Failure::from)
+            }
+            extensions::EXPECT_SHA256 => {
+                let ExpectSha256Extension { handle, sha256 } = extensions::parse(data)?;
+
+                if sha256.len() != 64 || !sha256.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                    return Err(Failure::new(StatusCode::Failure, ErrorCode::BadRequest));
+                }
+
+                match self.active_transfers.lock().get_mut(&handle) {
+                    Some(transfer) if transfer.direction() == Direction::Upload => {
+                        transfer.expect_checksum(&sha256);
+
+                        Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+                    }
+                    Some(_) => Err(Failure::new(StatusCode::Failure, ErrorCode::BadRequest)),
+                    None => Err(StatusCode::NoSuchFile.into()),
+                }
+            }
+            extensions::USERS_GROUPS_BY_ID => {
+                let UsersGroupsByIdExtension { uids, gids } = extensions::parse(data)?;
+
+                let mut usernames = Vec::new();
+                for uid in extensions::unpack_ids(&uids)? {
+                    usernames.push(self.auth_client.user_name(uid).await?.unwrap_or_default());
+                }
+
+                let mut groupnames = Vec::new();
+                for gid in extensions::unpack_ids(&gids)? {
+                    groupnames.push(self.auth_client.group_name(gid).await?.unwrap_or_default());
+                }
+
+                extensions::reply(
+                    id,
+                    &UsersGroupsByIdReply {
+                        usernames: extensions::pack_names(&usernames),
+                        groupnames: extensions::pack_names(&groupnames),
+                    },
+                )
+                .map_err(Failure::from)
+            }
+            extensions::PUBLIC_KEYS => {
+                let keys = self.auth_client.public_keys(&self.username).await?;
+
+                extensions::reply(
+                    id,
+                    &PublicKeysReply {
+                        keys: keys
+                            .iter()
+                            .filter_map(|key| key.to_openssh().ok())
+                            .collect(),
+                    },
+                )
+                .map_err(Failure::from)
+            }
+            extensions::SET_PUBLIC_KEYS => {
+                let SetPublicKeysExtension { keys } = extensions::parse(data)?;
+
+                let keys = keys
+                    .iter()
+                    .map(|key| PublicKey::from_openssh(key))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| Failure::new(StatusCode::Failure, ErrorCode::InvalidKey))?;
+
+                let old_keys = self.auth_client.public_keys(&self.username).await?;
+                let result = self
+                    .auth_client
+                    .set_public_keys(&self.username, keys.clone())
+                    .await;
+
+                match &result {
+                    Ok(()) => event!(
+                        target: audit::TARGET,
+                        Level::INFO,
+                        username = self.username,
+                        old_keys = ?fingerprints(&old_keys),
+                        new_keys = ?fingerprints(&keys),
+                        "SSH public keys changed"
+                    ),
+                    Err(err) => event!(
+                        target: audit::TARGET,
+                        Level::WARN,
+                        username = self.username,
+                        code = %err.code(),
+                        "SSH public key change refused"
+                    ),
+                }
+
+                result?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::LIMITS => {
+                extensions::reply(id, &extensions::limits()).map_err(Failure::from)
+            }
+            extensions::COPY_DATA => {
+                let CopyDataExtension {
+                    read_from_handle,
+                    read_from_offset,
+                    read_data_length,
+                    write_to_handle,
+                    write_to_offset,
+                } = extensions::parse(data)?;
+
+                let len = if read_data_length == 0 {
+                    u64::MAX
+                } else {
+                    read_data_length
+                };
+
+                // A file can only be copied onto itself where the two ranges
+                // don't overlap.
+                if read_from_handle == write_to_handle
+                    && read_from_offset < write_to_offset.saturating_add(len)
+                    && write_to_offset < read_from_offset.saturating_add(len)
+                {
+                    return Err(Failure::new(StatusCode::Failure, ErrorCode::BadRequest));
+                }
+
+                let (copied, src_vfs, dst_vfs) = handle_match2(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    read_from_handle,
+                    write_to_handle,
+                    async |src_vfs, src, dst_vfs, dst| {
+                        let copied = self
+                            .copy_data(
+                                (&src_vfs, &src, read_from_offset),
+                                (&dst_vfs, &dst, write_to_offset),
+                                len,
+                            )
+                            .await?;
+
+                        Ok((copied, src_vfs, dst_vfs))
+                    },
+                )
+                .await?;
+                SftpSession::copied(&src_vfs, &dst_vfs, copied).await;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::COPY_FILE => {
+                let CopyFileExtension {
+                    source,
+                    destination,
+                    overwrite,
+                } = extensions::parse(data)?;
+
+                self.copy_file(&source, &destination, overwrite).await?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::CHECK_FILE_HANDLE => {
+                let CheckFileHandleExtension {
+                    handle,
+                    hash_algorithms,
+                    start_offset,
+                    length,
+                    block_size,
+                } = extensions::parse(data)?;
+
+                let (algorithm, block_size) =
+                    extensions::check_file_params(&hash_algorithms, block_size)?;
+                self.limiter.acquire(Operation::Hash).await?;
+
+                let digests = handle_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    handle,
+                    async |vfs, handle| {
+                        vfs::hash_handle(
+                            &*vfs,
+                            &handle,
+                            algorithm,
+                            start_offset,
+                            length,
+                            block_size,
+                        )
+                        .await
+                        .map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                extensions::check_file_reply(id, algorithm, &digests).map_err(Failure::from)
+            }
+            extensions::CHECK_FILE_NAME => {
+                let CheckFileNameExtension {
+                    filename,
+                    hash_algorithms,
+                    start_offset,
+                    length,
+                    block_size,
+                } = extensions::parse(data)?;
+
+                let (algorithm, block_size) =
+                    extensions::check_file_params(&hash_algorithms, block_size)?;
+                self.limiter.acquire(Operation::Hash).await?;
+
+                let digests = path_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.cwd_path,
+                    &filename,
+                    async |vfs, relative_path| {
+                        vfs.hash_range(relative_path, algorithm, start_offset, length, block_size)
+                            .await
+                            .map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                extensions::check_file_reply(id, algorithm, &digests).map_err(Failure::from)
+            }
+            extensions::REALPATH => {
+                let RealpathExtension { path, must_exist } = extensions::parse(data)?;
+                let (path, attrs) = self
+                    .deadline
+                    .run(None, self.canonicalize(&path, must_exist))
+                    .await?;
+
+                Ok(Packet::Name(Name {
+                    id,
+                    files: vec![File::new(path, attrs)],
+                }))
+            }
+            extensions::EXPAND_PATH => {
+                let ExpandPathExtension { path } = extensions::parse(data)?;
+
+                Ok(Packet::Name(Name {
+                    id,
+                    files: vec![File::dummy(self.expand_path(&path)?)],
+                }))
+            }
+            extensions::HARDLINK => {
+                let HardlinkExtension { oldpath, newpath } = extensions::parse(data)?;
+
+                path_match2(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.cwd_path,
+                    &oldpath,
+                    &newpath,
+                    async |vfs, oldpath, newpath| {
+                        require(&vfs, Capabilities::HARDLINKS)?;
+                        self.check_name(&vfs, newpath, false)?;
+
+                        vfs.hardlink(oldpath, newpath).await.map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::POSIX_RENAME => {
+                let PosixRenameExtension { oldpath, newpath } = extensions::parse(data)?;
+
+                self.rename_path(&oldpath, &newpath, Capabilities::ATOMIC_RENAME)
+                    .await?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::STATVFS => {
+                let StatvfsExtension { path } = extensions::parse(data)?;
+
+                let metadata = path_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.cwd_path,
+                    &path,
+                    async |vfs, relative_path| {
+                        require(&vfs, Capabilities::STATVFS)?;
+
+                        vfs.statvfs(relative_path).await.map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                extensions::reply(id, &extensions::statvfs(&metadata)).map_err(Failure::from)
+            }
+            extensions::FSTATVFS => {
+                let FstatvfsExtension { handle } = extensions::parse(data)?;
+
+                let metadata = handle_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    handle,
+                    async |vfs, handle| {
+                        require(&vfs, Capabilities::STATVFS)?;
+
+                        vfs.statvfs_fd(&handle).await.map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                extensions::reply(id, &extensions::statvfs(&metadata)).map_err(Failure::from)
+            }
+            extensions::LOCK => {
+                let LockExtension {
+                    handle,
+                    offset,
+                    length,
+                    exclusive,
+                } = extensions::parse(data)?;
+
+                let mode = if exclusive {
+                    vfs::LockMode::Exclusive
+                } else {
+                    vfs::LockMode::Shared
+                };
+
+                handle_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    handle,
+                    async |vfs, handle| {
+                        vfs.lock(&handle, extensions::lock_range(offset, length), mode)
+                            .await
+                            .map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            extensions::UNLOCK => {
+                let UnlockExtension {
+                    handle,
+                    offset,
+                    length,
+                } = extensions::parse(data)?;
+
+                handle_match(
+                    &self.vfs_set,
+                    &self.deadline,
+                    &self.handles,
+                    handle,
+                    async |vfs, handle| {
+                        vfs.unlock(&handle, extensions::lock_range(offset, length))
+                            .await
+                            .map_err(Failure::from)
+                    },
+                )
+                .await?;
+
+                Ok(Packet::status(id, StatusCode::Ok, "", &LANGUAGE_TAG))
+            }
+            _ => Err(StatusCode::OpUnsupported.into()),
+        }
     }
 }
 
-async fn handle_match<T, F>(vfs_set: &VfsSet, handle: String, fun: F) -> Result<T, StatusCode>
+/// Enforces [`Config::op_timeout`] on the VFS operations performed on behalf of
+/// a session.
+struct Deadline {
+    timeout: Option<Duration>,
+    /// Handles that had an operation abandoned because it timed out. The
+    /// abandoned operation may still be running in the background, so these
+    /// handles can't safely be used for anything but closing them.
+    poisoned: Mutex<HashSet<vfs::Handle>>,
+}
+
+impl Deadline {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            poisoned: Mutex::new(HashSet::default()),
+        }
+    }
+
+    async fn run<T>(
+        &self,
+        handle: Option<&vfs::Handle>,
+        op: impl Future<Output = Result<T, Failure>>,
+    ) -> Result<T, Failure> {
+        let Some(timeout) = self.timeout else {
+            return op.await;
+        };
+
+        if let Ok(result) = tokio::time::timeout(timeout, op).await {
+            result
+        } else {
+            event!(Level::WARN, ?timeout, "VFS operation timed out");
+
+            if let Some(handle) = handle {
+                self.poisoned.lock().insert(handle.clone());
+            }
+
+            Err(Failure::new(StatusCode::Failure, ErrorCode::Timeout))
+        }
+    }
+
+    fn is_poisoned(&self, handle: &vfs::Handle) -> bool {
+        self.poisoned.lock().contains(handle)
+    }
+
+    fn forget(&self, handle: &vfs::Handle) {
+        self.poisoned.lock().remove(handle);
+    }
+}
+
+async fn handle_match<T, F>(
+    vfs_set: &VfsSet,
+    deadline: &Deadline,
+    handles: &HandleSeal,
+    handle: String,
+    fun: F,
+) -> Result<T, Failure>
 where
-    F: AsyncFnOnce(Arc<VfsInstance>, vfs::Handle) -> Result<T, StatusCode>,
+    F: AsyncFnOnce(Arc<Mount>, vfs::Handle) -> Result<T, Failure>,
 {
-    let handle = vfs::Handle::from_str(&handle).map_err(|_| StatusCode::BadMessage)?;
+    // A malformed handle can't be anything the client has open, which is the
+    // client's mistake rather than a reason to give up on the session.
+    let handle = handles.unseal(&handle)?;
+
+    if deadline.is_poisoned(&handle) {
+        return Err(Failure::new(StatusCode::Failure, ErrorCode::Timeout));
+    }
 
     if let Some(vfs) = vfs_set.resolve_handle(&handle).await {
-        fun(vfs, handle).await
+        let poison = handle.clone();
+        deadline.run(Some(&poison), fun(vfs, handle)).await
     } else {
-        Err(StatusCode::NoSuchFile)
+        Err(StatusCode::NoSuchFile.into())
+    }
+}
+
+/// Like [`handle_match`], for requests that use two handles, which can be open
+/// on different mounts. Only `destination` is poisoned if `fun` takes too long,
+/// since it's the one left half-written.
+async fn handle_match2<T, F>(
+    vfs_set: &VfsSet,
+    deadline: &Deadline,
+    handles: &HandleSeal,
+    source: String,
+    destination: String,
+    fun: F,
+) -> Result<T, Failure>
+where
+    F: AsyncFnOnce(Arc<Mount>, vfs::Handle, Arc<Mount>, vfs::Handle) -> Result<T, Failure>,
+{
+    let source = handles.unseal(&source)?;
+    let destination = handles.unseal(&destination)?;
+
+    if deadline.is_poisoned(&source) || deadline.is_poisoned(&destination) {
+        return Err(Failure::new(StatusCode::Failure, ErrorCode::Timeout));
     }
+
+    let (Some(source_vfs), Some(destination_vfs)) = (
+        vfs_set.resolve_handle(&source).await,
+        vfs_set.resolve_handle(&destination).await,
+    ) else {
+        return Err(StatusCode::NoSuchFile.into());
+    };
+
+    let poison = destination.clone();
+    deadline
+        .run(
+            Some(&poison),
+            fun(source_vfs, source, destination_vfs, destination),
+        )
+        .await
+}
+
+/// Resolves the path a client sent against its working directory.
+fn absolute_path(cwd: &Utf8Path, path: &str) -> Option<Utf8PathBuf> {
+    let path = Path::new(path).absolutize_from(cwd.as_std_path()).ok()?;
+
+    Utf8Path::from_path(&path).map(Utf8Path::to_path_buf)
+}
+
+/// The names in `path`, which must be absolute and normalized, last first.
+fn names(path: &Utf8Path) -> Vec<String> {
+    path.components()
+        .rev()
+        .filter_map(|component| match component {
+            Utf8Component::Normal(name) => Some(name.to_owned()),
+            _ => None,
+        })
+        .collect()
 }
 
 async fn path_match<T, F>(
     vfs_set: &VfsSet,
+    deadline: &Deadline,
     cwd: &Utf8Path,
     path: &str,
     fun: F,
-) -> Result<T, StatusCode>
+) -> Result<T, Failure>
 where
-    F: AsyncFnOnce(Arc<VfsInstance>, &Utf8Path) -> Result<T, StatusCode>,
+    F: AsyncFnOnce(Arc<Mount>, &Utf8Path) -> Result<T, Failure>,
 {
-    let path = Path::new(path);
-    if let Ok(Some(absolute_path)) = path
-        .absolutize_from(cwd.as_std_path())
-        .map(|p| Utf8Path::from_path(&p).map(Utf8Path::to_path_buf))
-    {
+    if let Some(absolute_path) = absolute_path(cwd, path) {
         if let Some(PathMatch { vfs, relative_path }) = vfs_set.resolve_path(&absolute_path) {
-            fun(vfs, relative_path.as_path()).await
+            deadline.run(None, fun(vfs, relative_path.as_path())).await
         } else {
-            Err(StatusCode::NoSuchFile)
+            Err(StatusCode::NoSuchFile.into())
         }
     } else {
-        Err(StatusCode::Failure)
+        Err(Failure::new(StatusCode::Failure, ErrorCode::InvalidPath))
     }
 }
 
 async fn path_match2<T, F>(
     vfs_set: &VfsSet,
+    deadline: &Deadline,
     cwd: &Utf8Path,
     path1: &str,
     path2: &str,
     fun: F,
-) -> Result<T, StatusCode>
+) -> Result<T, Failure>
 where
-    F: AsyncFnOnce(Arc<VfsInstance>, &Utf8Path, &Utf8Path) -> Result<T, StatusCode>,
+    F: AsyncFnOnce(Arc<Mount>, &Utf8Path, &Utf8Path) -> Result<T, Failure>,
 {
-    let path1 = Path::new(path1);
-    let path2 = Path::new(path2);
-
-    let Ok(Some(absolute_path1)) = path1
-        .absolutize_from(cwd.as_std_path())
-        .map(|p| Utf8Path::from_path(&p).map(Utf8Path::to_path_buf))
-    else {
-        return Err(StatusCode::Failure);
+    let Some(absolute_path1) = absolute_path(cwd, path1) else {
+        return Err(Failure::new(StatusCode::Failure, ErrorCode::InvalidPath));
     };
 
-    let Ok(Some(absolute_path2)) = path2
-        .absolutize_from(cwd.as_std_path())
-        .map(|p| Utf8Path::from_path(&p).map(Utf8Path::to_path_buf))
-    else {
-        return Err(StatusCode::Failure);
+    let Some(absolute_path2) = absolute_path(cwd, path2) else {
+        return Err(Failure::new(StatusCode::Failure, ErrorCode::InvalidPath));
     };
 
     let path_match1 = vfs_set.resolve_path(&absolute_path1);
@@ -611,17 +1907,243 @@ where
             }),
         ) => {
             if Arc::ptr_eq(&vfs1, &vfs2) {
-                fun(vfs1, relative_path1.as_path(), relative_path2.as_path()).await
+                deadline
+                    .run(
+                        None,
+                        fun(vfs1, relative_path1.as_path(), relative_path2.as_path()),
+                    )
+                    .await
             } else {
-                Err(StatusCode::Failure)
+                Err(Failure::new(StatusCode::Failure, ErrorCode::Unsupported))
             }
         }
-        _ => Err(StatusCode::NoSuchFile),
+        _ => Err(StatusCode::NoSuchFile.into()),
+    }
+}
+
+/// The path of the link and the path of its target for an `SSH_FXP_SYMLINK`
+/// request that came with `first` and `second`.
+///
+/// OpenSSH's sftp-server has always taken these the other way around from the
+/// draft, with the target first, and clients send them the way it expects. A
+/// relative target is relative to the directory the link is in, not to the
+/// working directory.
+fn symlink_paths(first: &str, second: &str) -> (String, String) {
+    let (target, link) = (first, second);
+
+    if target.starts_with('/') {
+        return (link.to_owned(), target.to_owned());
+    }
+
+    let link_dir = Utf8Path::new(link).parent().unwrap_or(Utf8Path::new(""));
+
+    (link.to_owned(), link_dir.join(target).into_string())
+}
+
+/// A session's slot for a handle it's opening, taken by
+/// [`SftpSession::reserve_handle`]. The slot is given back if it's dropped
+/// before the handle has been [opened](SftpSession::opened).
+struct HandleSlot<'a>(&'a AtomicUsize);
+
+impl Drop for HandleSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Why an SFTP request failed: the status the client gets, the stable code for
+/// the failure, and a more specific message than the status's own, if there is
+/// one.
+#[derive(Debug, Clone)]
+pub(super) struct Failure {
+    pub(super) status: StatusCode,
+    pub(super) code: Option<ErrorCode>,
+    message: Option<String>,
+}
+
+impl Failure {
+    pub(super) fn new(status: StatusCode, code: ErrorCode) -> Self {
+        Self {
+            status,
+            code: Some(code),
+            message: None,
+        }
+    }
+
+    /// The status reply to request `id`.
+    pub(super) fn into_packet(self, id: u32) -> Packet {
+        let message = self.message.unwrap_or_else(|| self.status.to_string());
+
+        Packet::status(id, self.status, &message, &LANGUAGE_TAG)
     }
 }
 
+impl From<StatusCode> for Failure {
+    fn from(status: StatusCode) -> Self {
+        Self {
+            status,
+            code: ErrorCode::for_status(status),
+            message: None,
+        }
+    }
+}
+
+impl From<vfs::Error> for Failure {
+    fn from(err: vfs::Error) -> Self {
+        Self {
+            status: status_code(&err),
+            code: Some(err.code()),
+            message: Some(err.to_report_string()),
+        }
+    }
+}
+
+impl From<Throttled> for Failure {
+    fn from(err: Throttled) -> Self {
+        Self {
+            status: StatusCode::Failure,
+            code: Some(ErrorCode::Throttled),
+            message: Some(err.to_report_string()),
+        }
+    }
+}
+
+impl From<AuthError> for Failure {
+    fn from(err: AuthError) -> Self {
+        let status = match err {
+            AuthError::KeysReadOnly => StatusCode::OpUnsupported,
+            _ => StatusCode::Failure,
+        };
+
+        Self {
+            status,
+            code: Some(err.code()),
+            message: Some(err.to_report_string()),
+        }
+    }
+}
+
+impl From<Failure> for StatusCode {
+    fn from(failure: Failure) -> Self {
+        failure.status
+    }
+}
+
+impl From<Failure> for StatusReply {
+    fn from(failure: Failure) -> Self {
+        let reply = StatusReply::new(failure.status);
+
+        match failure.message {
+            Some(message) => reply.with_message(message),
+            None => reply,
+        }
+    }
+}
+
+/// Picks the closest SFTP status code for a VFS error.
+fn status_code(err: &vfs::Error) -> StatusCode {
+    match err {
+        vfs::Error::FileNotFound => StatusCode::NoSuchFile,
+        vfs::Error::WouldEscape
+        | vfs::Error::OpenForWrite
+        | vfs::Error::RangeLocked
+        | vfs::Error::ReadOnly
+        | vfs::Error::LegalHold
+        | vfs::Error::Immutable
+        | vfs::Error::Retained
+        | vfs::Error::SymlinkDenied
+        | vfs::Error::FilenameDenied
+        | vfs::Error::Quarantined => StatusCode::PermissionDenied,
+        vfs::Error::UnsupportedMethod => StatusCode::OpUnsupported,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// Refuses an operation up front if the backend of `vfs` can't do it, rather
+/// than leaving it to fail however the backend fails.
+fn require(vfs: &Mount, capability: Capabilities) -> Result<(), Failure> {
+    if vfs.capabilities().contains(capability) {
+        Ok(())
+    } else {
+        Err(Failure::new(
+            StatusCode::OpUnsupported,
+            ErrorCode::Unsupported,
+        ))
+    }
+}
+
+/// The permissions `attrs` asks for, if the backend of `vfs` keeps any. Other
+/// backends ignore them, as clients routinely send permissions along with
+/// times that should still be set.
+fn permissions(vfs: &Mount, attrs: &FileAttributes) -> Option<u32> {
+    attrs
+        .permissions
+        .filter(|_| vfs.capabilities().contains(Capabilities::PERMISSIONS))
+        .map(|permissions| permissions & vfs::PERMISSION_BITS)
+}
+
+/// The SHA-256 fingerprints of `keys`, for the audit log.
+fn fingerprints(keys: &[PublicKey]) -> Vec<String> {
+    keys.iter()
+        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+        .collect()
+}
+
+/// The hex-encoded SHA-256 of the file at `path`, read back from `vfs`.
+async fn sha256(vfs: &Mount, path: &Utf8Path) -> Result<String, vfs::Error> {
+    let handle = vfs.open(path, vfs::OpenFlags::READ).await?;
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+
+    let result = loop {
+        match vfs.read(&handle, offset, HASH_CHUNK_SIZE).await {
+            Ok(Some(data)) => {
+                hasher.update(&data);
+                offset += data.len() as u64;
+            }
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+
+    vfs.close(handle).await?;
+    result?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 fn to_system_time(epoch_secs: u32) -> SystemTime {
     SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(epoch_secs))
 }
 
 static LANGUAGE_TAG: LazyLock<String> = LazyLock::new(|| "en".to_string());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ln -s` in OpenSSH's `sftp` sends the target first and the link second.
+    #[test]
+    fn symlink_paths_in_openssh_order() {
+        assert_eq!(
+            symlink_paths("/data/file", "/data/link"),
+            ("/data/link".to_owned(), "/data/file".to_owned())
+        );
+    }
+
+    #[test]
+    fn symlink_paths_relative_to_link() {
+        assert_eq!(
+            symlink_paths("file", "/data/dir/link"),
+            ("/data/dir/link".to_owned(), "/data/dir/file".to_owned())
+        );
+        assert_eq!(
+            symlink_paths("../file", "dir/link"),
+            ("dir/link".to_owned(), "dir/../file".to_owned())
+        );
+        assert_eq!(
+            symlink_paths("file", "link"),
+            ("link".to_owned(), "file".to_owned())
+        );
+    }
+}
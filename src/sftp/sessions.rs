@@ -0,0 +1,175 @@
+//! A registry of the SSH sessions that are currently connected, so that they
+//! can be listed and inspected while the server is running.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+
+use ahash::HashMap;
+use parking_lot::Mutex;
+use russh::{Disconnect, server::Handle};
+
+/// What is known about a connected session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Identifies the session for as long as the server is running.
+    pub id: u64,
+    /// The address the client connected from, if known.
+    pub peer_addr: Option<SocketAddr>,
+    /// The user the client authenticated as, once it has.
+    pub username: Option<String>,
+    /// When the client connected.
+    pub connected_at: SystemTime,
+    /// How many SFTP requests the client has sent that broke the protocol,
+    /// like unparseable packets or malformed handles. They're answered with
+    /// an error rather than ending the session.
+    pub protocol_violations: u64,
+}
+
+/// The sessions connected to an [`SshServer`](super::SshServer).
+#[derive(Clone, Default)]
+pub struct Sessions {
+    inner: Arc<SessionsInner>,
+}
+
+#[derive(Default)]
+struct SessionsInner {
+    next_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, SessionInfo>>,
+    /// What each session can be disconnected with, once it has authenticated.
+    handles: Mutex<HashMap<u64, Handle>>,
+}
+
+impl Sessions {
+    /// Adds a new session to the registry. It stays registered until the
+    /// returned [`SessionRegistration`] is dropped.
+    pub(super) fn register(&self, peer_addr: Option<SocketAddr>) -> SessionRegistration {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+
+        self.inner.sessions.lock().insert(
+            id,
+            SessionInfo {
+                id,
+                peer_addr,
+                username: None,
+                connected_at: SystemTime::now(),
+                protocol_violations: 0,
+            },
+        );
+
+        SessionRegistration {
+            sessions: self.clone(),
+            id,
+            peer_addr,
+        }
+    }
+
+    /// The sessions that are currently connected, oldest first.
+    #[must_use]
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .inner
+            .sessions
+            .lock()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+
+        sessions
+    }
+
+    /// The sessions that have authenticated as `username`, oldest first.
+    #[must_use]
+    pub fn for_user(&self, username: &str) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .inner
+            .sessions
+            .lock()
+            .values()
+            .filter(|session| session.username.as_deref() == Some(username))
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| session.id);
+
+        sessions
+    }
+
+    /// Counts a protocol violation against the session `id`.
+    pub(super) fn record_protocol_violation(&self, id: u64) {
+        if let Some(session) = self.inner.sessions.lock().get_mut(&id) {
+            session.protocol_violations += 1;
+        }
+    }
+
+    /// Disconnects the session `id`, telling the client `description`.
+    /// Returns whether the session was still connected.
+    pub(super) async fn disconnect(&self, id: u64, description: &str) -> bool {
+        let Some(handle) = self.inner.handles.lock().get(&id).cloned() else {
+            return false;
+        };
+
+        handle
+            .disconnect(
+                Disconnect::ByApplication,
+                description.to_owned(),
+                "en".to_owned(),
+            )
+            .await
+            .is_ok()
+    }
+}
+
+/// Keeps a session in its [`Sessions`] registry.
+pub(super) struct SessionRegistration {
+    sessions: Sessions,
+    id: u64,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl SessionRegistration {
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(super) fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub(super) fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    /// What is known about the session so far.
+    pub(super) fn info(&self) -> SessionInfo {
+        self.sessions
+            .inner
+            .sessions
+            .lock()
+            .get(&self.id)
+            .cloned()
+            .expect("sessions stay registered until their registration is dropped")
+    }
+
+    pub(super) fn set_username(&self, username: &str) {
+        if let Some(session) = self.sessions.inner.sessions.lock().get_mut(&self.id) {
+            session.username = Some(username.to_owned());
+        }
+    }
+
+    pub(super) fn set_handle(&self, handle: Handle) {
+        self.sessions.inner.handles.lock().insert(self.id, handle);
+    }
+}
+
+impl Drop for SessionRegistration {
+    fn drop(&mut self) {
+        self.sessions.inner.sessions.lock().remove(&self.id);
+        self.sessions.inner.handles.lock().remove(&self.id);
+    }
+}
@@ -1,48 +1,123 @@
 use std::{
     ffi::OsString,
-    future::Future,
-    io::{self, ErrorKind, read_to_string},
+    io::{self, ErrorKind},
     net::SocketAddr,
     os::unix::prelude::OsStringExt,
-    pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
-use ahash::RandomState;
-use camino::{Utf8Path, Utf8PathBuf};
-use cap_primitives::ambient_authority;
-use cap_std::fs_utf8::Dir;
+use ahash::{HashSet, RandomState};
+use camino::Utf8PathBuf;
 use metrics::gauge;
 use russh::{
     Channel,
     ChannelId,
+    CryptoVec,
     MethodKind,
     MethodSet,
     Pty,
-    keys::ssh_key::{self, PrivateKey},
+    keys::{PrivateKey, ssh_key},
     server::{Auth, Msg, Server, Session},
 };
 use shlex::bytes::Shlex;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{Level, event, info, instrument};
+use thiserror_ext::AsReport;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{Level, event, info};
 use vec_string::VecString;
 use whirlwind::ShardMap;
 
-use super::{Config, Error, hash, server::SftpSession};
-use crate::{auth::AuthClient, metrics::Metrics, vfs::VfsSet};
+use super::{
+    ConcurrentLoginAction,
+    ConcurrentLoginPolicy,
+    Config,
+    Error,
+    ProbeUser,
+    Subsystem,
+    exec,
+    hash,
+    host_keys,
+    lifecycle::{Hooks, LifecycleHooks},
+    limits::{Limiter, Limits},
+    reconnects::Reconnects,
+    sessions::{SessionRegistration, Sessions},
+    subsystem::{SubsystemContext, Subsystems},
+};
+use crate::{
+    audit,
+    auth::AuthClient,
+    checksums::ChecksumCache,
+    gateway::Links,
+    metrics::Metrics,
+    privacy,
+    transfers::Transfers,
+    vfs::{HashAlgorithm, LiveVfsSet, MountOptions, VfsSet, VfsSetBuilder},
+};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// How long connecting to the destination of a `direct-tcpip` channel can take
+/// before the channel is refused.
+const DIRECT_TCPIP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct SshServer {
     config: Config,
     methods: MethodSet,
     auth_client: AuthClient,
-    vfs_set: VfsSet,
+    vfs_set: LiveVfsSet,
+    subsystems: Subsystems,
+    sessions: Sessions,
+    reconnects: Reconnects,
+    limits: Limits,
+    host_keys: Vec<PrivateKey>,
+    hooks: Hooks,
+    probe_user: Option<Arc<ProbeLogin>>,
+    checksums: ChecksumCache,
+}
+
+/// [`Config::probe_user`], with its keys parsed.
+struct ProbeLogin {
+    username: String,
+    public_keys: Vec<ssh_key::PublicKey>,
+}
+
+impl ProbeLogin {
+    fn new(probe_user: &ProbeUser) -> Self {
+        let public_keys = probe_user
+            .public_keys
+            .iter()
+            .filter_map(|key| match ssh_key::PublicKey::from_openssh(key) {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    event!(Level::WARN, err = %err.as_report(), "ignoring invalid probe user key");
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            username: probe_user.username.clone(),
+            public_keys,
+        }
+    }
+
+    fn accepts(&self, public_key: &ssh_key::PublicKey) -> bool {
+        self.public_keys
+            .iter()
+            .any(|key| key.key_data() == public_key.key_data())
+    }
 }
 
 impl SshServer {
     #[must_use]
-    pub fn new(config: Config, auth_client: AuthClient, vfs_set: VfsSet) -> Self {
+    pub fn new(
+        config: Config,
+        auth_client: AuthClient,
+        vfs_set: LiveVfsSet,
+        transfers: &Transfers,
+        checksums: ChecksumCache,
+        links: Option<&Links>,
+    ) -> Self {
         let mut methods = MethodSet::empty();
 
         if config.allow_password {
@@ -53,23 +128,58 @@ impl SshServer {
             methods.push(MethodKind::PublicKey);
         }
 
+        let sessions = Sessions::default();
+        let subsystems = Subsystems::new(&config, &auth_client, &sessions, transfers, links);
+        let reconnects = Reconnects::new(config.reconnect_storm_threshold);
+        let limits = Limits::new(&config);
+        let probe_user = config
+            .probe_user
+            .as_ref()
+            .map(|probe_user| Arc::new(ProbeLogin::new(probe_user)));
+
         Self {
             config,
             methods,
             auth_client,
             vfs_set,
+            subsystems,
+            sessions,
+            reconnects,
+            limits,
+            host_keys: Vec::new(),
+            hooks: Hooks::default(),
+            probe_user,
+            checksums,
         }
     }
 
-    pub async fn run(&mut self) -> io::Result<()> {
-        let host_keys = get_host_keys(&self.config)?;
+    /// The sessions currently connected to this server.
+    #[must_use]
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
 
-        let russh_config = russh::server::Config {
-            methods: self.methods.clone(),
-            keys: host_keys,
-            window_size: 16 * 1024 * 1024,
-            ..Default::default()
-        };
+    /// Makes `subsystem` available to clients under `name`, replacing any
+    /// subsystem previously registered under the same name. It is only served
+    /// if `name` is also listed in [`Config::subsystems`].
+    pub fn register_subsystem(&mut self, name: impl Into<String>, subsystem: Arc<dyn Subsystem>) {
+        self.subsystems.register(name.into(), subsystem);
+    }
+
+    /// Identifies the server with `key`, in addition to the host keys from
+    /// the configuration.
+    pub fn add_host_key(&mut self, key: PrivateKey) {
+        self.host_keys.push(key);
+    }
+
+    /// Calls `hooks` as the server starts and stops and as sessions open and
+    /// close, after any hooks added before them.
+    pub fn add_lifecycle_hooks(&mut self, hooks: Arc<dyn LifecycleHooks>) {
+        self.hooks.add(hooks);
+    }
+
+    pub async fn run(&mut self) -> io::Result<()> {
+        let russh_config = self.russh_config()?;
 
         let socket_addrs = self
             .config
@@ -83,43 +193,54 @@ impl SshServer {
             "Listening for SFTP connections"
         );
 
-        self.run_on_address(Arc::new(russh_config), socket_addrs.as_slice())
-            .await?;
+        self.hooks.start().await?;
+        let result = self
+            .run_on_address(Arc::new(russh_config), socket_addrs.as_slice())
+            .await;
+        self.hooks.shutdown().await;
 
-        Ok(())
+        result
     }
-}
 
-#[instrument(skip_all, fields(config.private_host_key_dir))]
-fn get_host_keys(config: &Config) -> io::Result<Vec<PrivateKey>> {
-    let mut keys = Vec::new();
-    let key_dir = config.private_host_key_dir.as_path();
+    /// Serves the clients that connect to `listener`, rather than listening on
+    /// the configured address and port. This is how to run the server on a port
+    /// the OS picks.
+    pub async fn run_on_listener(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let russh_config = self.russh_config()?;
 
-    if let Some(key_dir) = Utf8Path::from_path(key_dir) {
-        let dir = Dir::open_ambient_dir(key_dir, ambient_authority())?;
+        info!(
+            socket_addr = %listener.local_addr()?,
+            "Listening for SFTP connections"
+        );
 
-        for entry in dir.entries()? {
-            let entry = entry?;
-            let file_name = entry.file_name()?;
+        self.hooks.start().await?;
+        let result = self.run_on_socket(Arc::new(russh_config), listener).await;
+        self.hooks.shutdown().await;
 
-            if Utf8Path::new(&file_name)
-                .extension()
-                .is_some_and(|ext| ext.eq_ignore_ascii_case("pub"))
-            {
-                continue;
-            }
+        result
+    }
 
-            let contents = read_to_string(entry.open()?)?;
-            if let Ok(private_key) = PrivateKey::from_openssh(contents) {
-                let algorithm = private_key.algorithm();
-                event!(Level::INFO, %file_name, %algorithm, "imported private key");
+    fn russh_config(&self) -> io::Result<russh::server::Config> {
+        let mut host_keys = host_keys::load(&self.config)?;
+        host_keys.extend(self.host_keys.iter().cloned());
+
+        if host_keys.is_empty() {
+            event!(Level::WARN, "no host keys were loaded");
+        }
 
-                keys.push(private_key);
+        for name in &self.config.subsystems {
+            if self.subsystems.get(name).is_none() {
+                event!(Level::WARN, %name, "allowed subsystem isn't registered");
             }
         }
-    }
 
-    Ok(keys)
+        Ok(russh::server::Config {
+            methods: self.methods.clone(),
+            keys: host_keys,
+            window_size: 16 * 1024 * 1024,
+            ..Default::default()
+        })
+    }
 }
 
 impl Server for SshServer {
@@ -127,7 +248,13 @@ impl Server for SshServer {
 
     fn new_client(&mut self, sock_addr: Option<SocketAddr>) -> Self::Handler {
         if let Some(sock_addr) = sock_addr {
-            event!(Level::INFO, ?sock_addr, "Client connected");
+            event!(
+                Level::INFO,
+                ip = %privacy::pseudonymize(sock_addr.ip()),
+                port = sock_addr.port(),
+                "Client connected"
+            );
+            self.reconnects.record_addr(&sock_addr.ip());
         }
 
         gauge!(Metrics::SFTP_CLIENTS).increment(1);
@@ -136,7 +263,14 @@ impl Server for SshServer {
             self.config.clone(),
             self.methods.clone(),
             self.auth_client.clone(),
-            self.vfs_set.clone(),
+            self.vfs_set.snapshot(),
+            self.subsystems.clone(),
+            self.sessions.register(sock_addr),
+            self.reconnects.clone(),
+            self.limits.clone(),
+            self.hooks.clone(),
+            self.probe_user.clone(),
+            self.checksums.clone(),
         )
     }
 
@@ -164,14 +298,35 @@ pub struct SshSession {
     cwd: Utf8PathBuf,
     authenticated_username: Option<String>,
     clients: ShardMap<ChannelId, Channel<Msg>, RandomState>,
+    interactive_denied: HashSet<ChannelId>,
+    subsystems: Subsystems,
+    registration: SessionRegistration,
+    reconnects: Reconnects,
+    limits: Limits,
+    /// The limits of the session, once it knows whose it is.
+    limiter: Option<Arc<Limiter>>,
+    hooks: Hooks,
+    probe_user: Option<Arc<ProbeLogin>>,
+    /// Whether the lifecycle hooks have let the session open, and so need to
+    /// hear about it closing.
+    opened: bool,
+    checksums: ChecksumCache,
 }
 
 impl SshSession {
-    pub fn new(
+    #[allow(clippy::too_many_arguments)]
+    fn new(
         config: Config,
         methods: MethodSet,
         auth_client: AuthClient,
         vfs_set: VfsSet,
+        subsystems: Subsystems,
+        registration: SessionRegistration,
+        reconnects: Reconnects,
+        limits: Limits,
+        hooks: Hooks,
+        probe_user: Option<Arc<ProbeLogin>>,
+        checksums: ChecksumCache,
     ) -> Self {
         let cwd: Utf8PathBuf = Utf8PathBuf::from("/");
 
@@ -183,9 +338,206 @@ impl SshSession {
             cwd,
             authenticated_username: None,
             clients: ShardMap::with_hasher(RandomState::default()),
+            interactive_denied: HashSet::default(),
+            subsystems,
+            registration,
+            reconnects,
+            limits,
+            limiter: None,
+            hooks,
+            probe_user,
+            opened: false,
+            checksums,
+        }
+    }
+
+    /// The probe user, if `user` is it.
+    fn probe_user(&self, user: &str) -> Option<Arc<ProbeLogin>> {
+        self.probe_user
+            .clone()
+            .filter(|probe_user| probe_user.username == user)
+    }
+
+    /// Sets the session up for the probe user, which gets an empty filesystem
+    /// of its own and skips everything that would count it as a real user.
+    fn probe_authenticated(&mut self, user: &str) -> Result<()> {
+        self.registration.set_username(user);
+        self.authenticated_username = Some(user.to_owned());
+        self.limiter = Some(Arc::new(self.limits.session(user, None)));
+        self.vfs_set = VfsSetBuilder::new()
+            .memory(Utf8PathBuf::from("/"), MountOptions::default())?
+            .build();
+
+        Ok(())
+    }
+
+    /// Sets the session up for `user`, who has just been admitted. Returns
+    /// whether the lifecycle hooks let it go ahead.
+    async fn authenticated(&mut self, user: &str) -> Result<bool> {
+        let mut info = self.registration.info();
+        info.username = Some(user.to_owned());
+        if !self.hooks.session_open(&info).await {
+            return Ok(false);
+        }
+        self.opened = true;
+
+        self.registration.set_username(user);
+        self.reconnects.record_user(user);
+        self.authenticated_username = Some(user.to_owned());
+        let max_file_size = self.file_size_limit(user).await?;
+        self.limiter = Some(Arc::new(self.limits.session(user, max_file_size)));
+        self.vfs_set = self.vfs_set.for_user(user).await?;
+
+        let mut hidden = Vec::new();
+        for (root, mount) in self.vfs_set.mounts() {
+            if !self.can_see(user, mount.options()).await? {
+                hidden.push(root.to_owned());
+            }
+        }
+
+        if !hidden.is_empty() {
+            self.vfs_set = self.vfs_set.without(&hidden);
+        }
+
+        match self.hooks.session_mounts(&info, self.vfs_set.clone()).await {
+            Some(vfs_set) => self.vfs_set = vfs_set,
+            None => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Whether `user` can see a mount with `options`, going by its
+    /// [`MountOptions::visible_to_users`] and
+    /// [`MountOptions::visible_to_group`].
+    async fn can_see(&self, user: &str, options: &MountOptions) -> Result<bool> {
+        Ok(
+            (options.visible_to_users.is_empty() && options.visible_to_group.is_none())
+                || options.visible_to_users.iter().any(|name| name == user)
+                || match &options.visible_to_group {
+                    Some(group) => self.auth_client.is_member_of(user, group).await?,
+                    None => false,
+                },
+        )
+    }
+
+    /// The first of [`Config::concurrent_logins`] that applies to `user`.
+    async fn concurrent_login_policy(&self, user: &str) -> Result<Option<&ConcurrentLoginPolicy>> {
+        for policy in &self.config.concurrent_logins {
+            let applies = (policy.users.is_empty() && policy.group.is_none())
+                || policy.users.iter().any(|name| name == user)
+                || match &policy.group {
+                    Some(group) => self.auth_client.is_member_of(user, group).await?,
+                    None => false,
+                };
+
+            if applies {
+                return Ok(Some(policy));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The `max_file_size` of the first of [`Config::file_size_limits`] that
+    /// applies to `user`.
+    async fn file_size_limit(&self, user: &str) -> Result<Option<u64>> {
+        for limit in &self.config.file_size_limits {
+            let applies = (limit.users.is_empty() && limit.group.is_none())
+                || limit.users.iter().any(|name| name == user)
+                || match &limit.group {
+                    Some(group) => self.auth_client.is_member_of(user, group).await?,
+                    None => false,
+                };
+
+            if applies {
+                return Ok(Some(limit.max_file_size));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Enforces [`Config::concurrent_logins`] on `user`, who has just proven
+    /// who they are, disconnecting their oldest sessions if the policy says
+    /// to. Returns whether the new session may go ahead.
+    async fn admit(&self, user: &str) -> Result<bool> {
+        let Some(policy) = self.concurrent_login_policy(user).await? else {
+            return Ok(true);
+        };
+
+        let sessions = self.registration.sessions().for_user(user);
+        if sessions.len() < policy.max_sessions {
+            return Ok(true);
+        }
+
+        match policy.action {
+            ConcurrentLoginAction::Allow => Ok(true),
+            ConcurrentLoginAction::Reject => {
+                event!(
+                    target: audit::TARGET,
+                    Level::WARN,
+                    username = user,
+                    sessions = sessions.len(),
+                    max_sessions = policy.max_sessions,
+                    "login refused, too many sessions"
+                );
+
+                Ok(false)
+            }
+            ConcurrentLoginAction::Takeover => {
+                let excess = sessions.len() + 1 - policy.max_sessions.max(1);
+
+                for session in &sessions[..excess] {
+                    let disconnected = self
+                        .registration
+                        .sessions()
+                        .disconnect(session.id, "Taken over by a newer session")
+                        .await;
+
+                    event!(
+                        target: audit::TARGET,
+                        Level::INFO,
+                        username = user,
+                        session = session.id,
+                        new_session = self.registration.id(),
+                        disconnected,
+                        "session taken over"
+                    );
+                }
+
+                Ok(true)
+            }
         }
     }
 
+    /// Refuses a request for an interactive session on `channel`, first
+    /// sending [`Config::no_interactive_message`] to the client if there is
+    /// one. The message is only sent once per channel, since clients usually
+    /// ask for a PTY and a shell one after the other.
+    fn deny_interactive(&mut self, channel: ChannelId, session: &mut Session) -> Result<()> {
+        if let Some(message) = &self.config.no_interactive_message
+            && self.interactive_denied.insert(channel)
+        {
+            let message = format!("{}\r\n", message.trim_end());
+
+            if session
+                .data(channel, CryptoVec::from(message.into_bytes()))
+                .is_err()
+            {
+                event!(
+                    Level::DEBUG,
+                    ?channel,
+                    "couldn't send no-interactive message"
+                );
+            }
+        }
+
+        session.channel_failure(channel)?;
+
+        Ok(())
+    }
+
     pub async fn get_channel(&mut self, channel_id: ChannelId) -> Result<Channel<Msg>> {
         if let Some(channel) = self.clients.remove(&channel_id).await {
             Ok(channel)
@@ -194,48 +546,55 @@ impl SshSession {
         }
     }
 
-    fn exec_command<S>(
-        &self,
-        stream: S,
-        data: &[u8],
-    ) -> Option<Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>>
-    where
-        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    {
-        const MD5SUM: &str = "md5sum";
-        const SHA1SUM: &str = "sha1sum";
-
+    fn exec_command(&self, output: exec::Output, data: &[u8]) -> Option<exec::Command> {
         let mut shell_parts = Shlex::new(data).map(OsString::from_vec);
 
         if let Some(command) = shell_parts.next() {
             let vfs_set = self.vfs_set.clone();
             let cwd = self.cwd.clone();
+            let limiter = self.limiter.clone()?;
+            let checksums = self.checksums.clone();
             let arguments = shell_parts.collect::<Vec<_>>();
 
-            if command == MD5SUM {
-                return Some(Box::pin(hash::exec_md5sum(vfs_set, cwd, stream, arguments)));
-            } else if command == SHA1SUM {
-                return Some(Box::pin(hash::exec_sha1sum(
-                    vfs_set, cwd, stream, arguments,
+            if let Some(algorithm) = command.to_str().and_then(HashAlgorithm::for_command) {
+                return Some(Box::pin(hash::exec_checksum(
+                    algorithm, vfs_set, cwd, limiter, checksums, output, arguments,
                 )));
             }
-        };
+        }
 
         None
     }
 }
 
+impl Drop for SshSession {
+    fn drop(&mut self) {
+        // Counted here rather than when a channel closes, so that clients that
+        // hang up without opening one, like the probe, aren't counted forever.
+        gauge!(Metrics::SFTP_CLIENTS).decrement(1);
+
+        if self.opened {
+            self.hooks.session_close(self.registration.info());
+        }
+    }
+}
+
 impl russh::server::Handler for SshSession {
     type Error = Error;
 
     async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth> {
         if self.config.allow_password
+            && self.probe_user(user).is_none()
             && self
                 .auth_client
                 .authenticate_password(user, password)
                 .await?
         {
-            self.authenticated_username = Some(user.to_owned());
+            if !self.admit(user).await? || !self.authenticated(user).await? {
+                return Ok(Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::empty()),
+                });
+            }
 
             Ok(Auth::Accept)
         } else {
@@ -252,13 +611,31 @@ impl russh::server::Handler for SshSession {
         user: &str,
         public_key: &ssh_key::PublicKey,
     ) -> Result<Auth> {
+        if let Some(probe_user) = self.probe_user(user) {
+            if self.config.allow_publickey && probe_user.accepts(public_key) {
+                self.probe_authenticated(user)?;
+
+                return Ok(Auth::Accept);
+            }
+
+            self.methods.remove(MethodKind::PublicKey);
+
+            return Ok(Auth::Reject {
+                proceed_with_methods: Some(self.methods.clone()),
+            });
+        }
+
         if self.config.allow_publickey
             && self
                 .auth_client
                 .authenticate_public_key(user, public_key)
                 .await?
         {
-            self.authenticated_username = Some(user.to_owned());
+            if !self.admit(user).await? || !self.authenticated(user).await? {
+                return Ok(Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::empty()),
+                });
+            }
 
             Ok(Auth::Accept)
         } else {
@@ -270,10 +647,18 @@ impl russh::server::Handler for SshSession {
         }
     }
 
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<()> {
+        // Kept so that a newer session of the same user can take this one
+        // over.
+        self.registration.set_handle(session.handle());
+
+        Ok(())
+    }
+
     async fn channel_eof(&mut self, channel: ChannelId, session: &mut Session) -> Result<()> {
         session.close(channel)?;
         self.clients.remove(&channel).await;
-        gauge!(Metrics::SFTP_CLIENTS).decrement(1);
+        self.interactive_denied.remove(&channel);
 
         Ok(())
     }
@@ -289,6 +674,114 @@ impl russh::server::Handler for SshSession {
         Ok(true)
     }
 
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool> {
+        let username = self.authenticated_username.as_deref().unwrap_or_default();
+        let port = u16::try_from(port_to_connect).ok().filter(|port| {
+            self.config
+                .allowed_direct_tcpip
+                .iter()
+                .any(|destination| destination.matches(host_to_connect, *port))
+        });
+
+        let Some(port) = port else {
+            event!(
+                target: audit::TARGET,
+                Level::WARN,
+                username,
+                host_to_connect,
+                port_to_connect,
+                originator_address,
+                originator_port,
+                "direct-tcpip refused"
+            );
+
+            return Ok(false);
+        };
+
+        // The connection is made before the channel is accepted, so that the
+        // client finds out if it can't be, and to the address the host name
+        // was resolved to just now rather than whatever it resolves to later.
+        let (mut tcp_stream, address) = match connect_direct_tcpip(host_to_connect, port).await {
+            Ok(connected) => connected,
+            Err(err) => {
+                event!(
+                    target: audit::TARGET,
+                    Level::WARN,
+                    username,
+                    host_to_connect,
+                    port_to_connect,
+                    originator_address,
+                    originator_port,
+                    err = %err.as_report(),
+                    "direct-tcpip connection failed"
+                );
+
+                return Ok(false);
+            }
+        };
+
+        event!(
+            target: audit::TARGET,
+            Level::INFO,
+            username,
+            host_to_connect,
+            port_to_connect,
+            %address,
+            originator_address,
+            originator_port,
+            "direct-tcpip opened"
+        );
+
+        tokio::spawn(async move {
+            let mut channel_stream = channel.into_stream();
+
+            let result = tokio::io::copy_bidirectional(&mut channel_stream, &mut tcp_stream).await;
+            if let Err(err) = result {
+                event!(Level::INFO, %address, %err, "direct-tcpip connection closed with an error");
+            }
+        });
+
+        Ok(true)
+    }
+
+    async fn tcpip_forward(
+        &mut self,
+        address: &str,
+        port: &mut u32,
+        _session: &mut Session,
+    ) -> Result<bool> {
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            username = self.authenticated_username.as_deref().unwrap_or_default(),
+            address,
+            port = *port,
+            "tcpip-forward refused"
+        );
+
+        Ok(false)
+    }
+
+    async fn agent_request(&mut self, channel: ChannelId, _session: &mut Session) -> Result<bool> {
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            username = self.authenticated_username.as_deref().unwrap_or_default(),
+            ?channel,
+            "agent forwarding refused"
+        );
+
+        Ok(false)
+    }
+
     async fn pty_request(
         &mut self,
         channel: ChannelId,
@@ -300,14 +793,11 @@ impl russh::server::Handler for SshSession {
         _modes: &[(Pty, u32)],
         session: &mut Session,
     ) -> Result<()> {
-        session.channel_failure(channel)?;
-
-        Ok(())
+        self.deny_interactive(channel, session)
     }
-    async fn shell_request(&mut self, channel: ChannelId, session: &mut Session) -> Result<()> {
-        session.channel_failure(channel)?;
 
-        Ok(())
+    async fn shell_request(&mut self, channel: ChannelId, session: &mut Session) -> Result<()> {
+        self.deny_interactive(channel, session)
     }
 
     async fn exec_request(
@@ -317,16 +807,10 @@ impl russh::server::Handler for SshSession {
         session: &mut Session,
     ) -> Result<()> {
         let channel = self.get_channel(channel_id).await?;
-        let channel_stream = channel.into_stream();
 
-        if let Some(result_future) = self.exec_command(channel_stream, data) {
+        if let Some(command) = self.exec_command(exec::output(&channel), data) {
             session.channel_success(channel_id)?;
-
-            if result_future.await.is_ok() {
-                session.exit_status_request(channel_id, 0)?;
-            } else {
-                session.exit_status_request(channel_id, 1)?;
-            }
+            exec::spawn(channel, command);
         } else {
             session.channel_failure(channel_id)?;
         }
@@ -340,25 +824,68 @@ impl russh::server::Handler for SshSession {
         name: &str,
         session: &mut Session,
     ) -> Result<()> {
-        let authenticated_username = self.authenticated_username.as_ref().unwrap().clone();
-
-        if name == "sftp" {
-            event!(Level::INFO, ?channel_id, "SFTP session started");
-            let channel = self.get_channel(channel_id).await?;
-            session.channel_success(channel_id)?;
-
-            let sftp = SftpSession::new(
-                self.config.clone(),
-                authenticated_username,
-                self.cwd.clone(),
-                self.vfs_set.clone(),
-            );
-            let channel_stream = channel.into_stream();
-            russh_sftp::server::run(channel_stream, sftp).await;
+        let subsystem = if self.config.subsystems.iter().any(|allowed| allowed == name) {
+            self.subsystems.get(name)
         } else {
+            None
+        };
+
+        let Some(subsystem) = subsystem else {
+            event!(Level::INFO, ?channel_id, %name, "refused unknown subsystem");
             session.channel_failure(channel_id)?;
-        }
+
+            return Ok(());
+        };
+
+        event!(Level::INFO, ?channel_id, %name, "subsystem started");
+        let channel = self.get_channel(channel_id).await?;
+        session.channel_success(channel_id)?;
+
+        let context = SubsystemContext {
+            session_id: self.registration.id(),
+            username: self.authenticated_username.as_ref().unwrap().clone(),
+            peer_addr: self.registration.peer_addr(),
+            channel_id,
+            cwd: self.cwd.clone(),
+            vfs_set: self.vfs_set.clone(),
+            limiter: self.limiter.clone().unwrap(),
+        };
+        let name = name.to_string();
+
+        tokio::spawn(async move {
+            if let Err(err) = subsystem.run(context, channel.into_stream()).await {
+                event!(
+                    Level::WARN,
+                    ?channel_id,
+                    %name,
+                    code = %err.code(),
+                    err = %err.as_report(),
+                    "subsystem failed"
+                );
+            }
+        });
 
         Ok(())
     }
 }
+
+/// Connects to `host` on `port` for a `direct-tcpip` channel, trying each of
+/// the addresses the host resolves to in turn, and returns the connection
+/// along with the address it was made to.
+async fn connect_direct_tcpip(host: &str, port: u16) -> io::Result<(TcpStream, SocketAddr)> {
+    let connect = async {
+        let mut last_err = None;
+        for address in tokio::net::lookup_host((host, port)).await? {
+            match TcpStream::connect(address).await {
+                Ok(stream) => return Ok((stream, address)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| io::Error::from(ErrorKind::NotFound)))
+    };
+
+    tokio::time::timeout(DIRECT_TCPIP_CONNECT_TIMEOUT, connect)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut)))
+}
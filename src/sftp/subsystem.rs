@@ -0,0 +1,132 @@
+//! SSH subsystems that clients can request on a session channel. SFTP is one
+//! of them, and embedders can register their own with
+//! [`SshServer::register_subsystem`](super::SshServer::register_subsystem).
+//! Only the subsystems listed in [`Config::subsystems`] are served, whether
+//! they are built in or not.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::Utf8PathBuf;
+use russh::{ChannelId, ChannelStream, server::Msg};
+
+use super::{
+    Config,
+    Error,
+    admin::AdminSubsystem,
+    dispatch,
+    limits::Limiter,
+    server::{SftpSession, SharedSession},
+    sessions::Sessions,
+};
+use crate::{auth::AuthClient, gateway::Links, transfers::Transfers, vfs::VfsSet};
+
+/// What a subsystem knows about the session it is running in.
+#[derive(Clone)]
+pub struct SubsystemContext {
+    /// The [`SessionInfo::id`](super::SessionInfo::id) of the session.
+    pub session_id: u64,
+    pub username: String,
+    /// The address the client connected from, if known.
+    pub peer_addr: Option<SocketAddr>,
+    pub channel_id: ChannelId,
+    pub cwd: Utf8PathBuf,
+    pub vfs_set: VfsSet,
+    pub(super) limiter: Arc<Limiter>,
+}
+
+#[async_trait]
+pub trait Subsystem: Send + Sync {
+    /// Serves the subsystem over `stream` until the client is done with it.
+    async fn run(&self, context: SubsystemContext, stream: ChannelStream<Msg>)
+    -> Result<(), Error>;
+}
+
+/// The subsystems an [`SshServer`](super::SshServer) knows how to serve,
+/// keyed by the name clients request them by.
+#[derive(Clone)]
+pub(super) struct Subsystems(Arc<HashMap<String, Arc<dyn Subsystem>>>);
+
+impl Subsystems {
+    /// The built-in subsystems.
+    pub(super) fn new(
+        config: &Config,
+        auth_client: &AuthClient,
+        sessions: &Sessions,
+        transfers: &Transfers,
+        links: Option<&Links>,
+    ) -> Self {
+        let mut subsystems = HashMap::<String, Arc<dyn Subsystem>>::default();
+        subsystems.insert(
+            SftpSubsystem::NAME.to_string(),
+            Arc::new(SftpSubsystem {
+                config: config.clone(),
+                auth_client: auth_client.clone(),
+                sessions: sessions.clone(),
+                transfers: transfers.clone(),
+            }),
+        );
+        subsystems.insert(
+            AdminSubsystem::NAME.to_string(),
+            Arc::new(AdminSubsystem {
+                config: config.clone(),
+                auth_client: auth_client.clone(),
+                sessions: sessions.clone(),
+                transfers: transfers.clone(),
+                links: links.cloned(),
+            }),
+        );
+
+        Self(Arc::new(subsystems))
+    }
+
+    pub(super) fn register(&mut self, name: String, subsystem: Arc<dyn Subsystem>) {
+        Arc::make_mut(&mut self.0).insert(name, subsystem);
+    }
+
+    pub(super) fn get(&self, name: &str) -> Option<Arc<dyn Subsystem>> {
+        self.0.get(name).cloned()
+    }
+}
+
+struct SftpSubsystem {
+    config: Config,
+    auth_client: AuthClient,
+    sessions: Sessions,
+    transfers: Transfers,
+}
+
+impl SftpSubsystem {
+    const NAME: &str = "sftp";
+}
+
+#[async_trait]
+impl Subsystem for SftpSubsystem {
+    async fn run(
+        &self,
+        context: SubsystemContext,
+        stream: ChannelStream<Msg>,
+    ) -> Result<(), Error> {
+        let sftp = SftpSession::new(
+            self.config.clone(),
+            context.username.clone(),
+            context.cwd.clone(),
+            context.vfs_set.clone(),
+            self.transfers.clone(),
+            context.session_id,
+            context.limiter.clone(),
+            self.auth_client.clone(),
+        );
+        dispatch::run(
+            stream,
+            SharedSession::from(sftp),
+            &self.config.session_limits,
+            &context,
+            &self.sessions,
+        )
+        .await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,827 @@
+//! Versions 4 to 6 of the SFTP protocol.
+//!
+//! The handler only speaks version 3, which is what `russh_sftp` implements,
+//! so the packets of clients that negotiated a later version are translated
+//! into version 3 ones on the way in, and the replies are translated back on
+//! the way out. Most packets are laid out the same in every version. The ones
+//! that aren't are those with file attributes in them, `OPEN`, `RENAME`,
+//! `REALPATH` and the stat requests, along with `LINK`, `BLOCK` and `UNBLOCK`,
+//! which version 6 adds. Those, and version 6's `REALPATH`, are answered with
+//! the extensions that do the same in version 3.
+//!
+//! See `draft-ietf-secsh-filexfer-04`, `-05` and `-13`.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use russh_sftp::protocol::{
+    Extended,
+    FSetStat,
+    FileAttributes,
+    FileMode,
+    Fstat,
+    Lstat,
+    MkDir,
+    Open,
+    OpenFlags,
+    Packet,
+    RealPath,
+    Rename,
+    SetStat,
+    Stat,
+    StatusCode,
+    Symlink,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::extensions::{
+    self,
+    HardlinkExtension,
+    LockExtension,
+    RealpathExtension,
+    UnlockExtension,
+};
+use crate::error_code::ErrorCode;
+
+/// The oldest version that's spoken. Older clients are answered in it anyway,
+/// since they can't be answered in anything they'd understand better.
+pub(super) const MIN_VERSION: u32 = 3;
+/// The newest version that's spoken.
+pub(super) const MAX_VERSION: u32 = 6;
+
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_FSETSTAT: u8 = 10;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_LINK: u8 = 21;
+const SSH_FXP_BLOCK: u8 = 22;
+const SSH_FXP_UNBLOCK: u8 = 23;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x0000_0001;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x0000_0004;
+const SSH_FILEXFER_ATTR_ACCESSTIME: u32 = 0x0000_0008;
+const SSH_FILEXFER_ATTR_CREATETIME: u32 = 0x0000_0010;
+const SSH_FILEXFER_ATTR_MODIFYTIME: u32 = 0x0000_0020;
+const SSH_FILEXFER_ATTR_ACL: u32 = 0x0000_0040;
+const SSH_FILEXFER_ATTR_OWNERGROUP: u32 = 0x0000_0080;
+const SSH_FILEXFER_ATTR_SUBSECOND_TIMES: u32 = 0x0000_0100;
+const SSH_FILEXFER_ATTR_BITS: u32 = 0x0000_0200;
+const SSH_FILEXFER_ATTR_ALLOCATION_SIZE: u32 = 0x0000_0400;
+const SSH_FILEXFER_ATTR_TEXT_HINT: u32 = 0x0000_0800;
+const SSH_FILEXFER_ATTR_MIME_TYPE: u32 = 0x0000_1000;
+const SSH_FILEXFER_ATTR_LINK_COUNT: u32 = 0x0000_2000;
+const SSH_FILEXFER_ATTR_UNTRANSLATED_NAME: u32 = 0x0000_4000;
+const SSH_FILEXFER_ATTR_CTIME: u32 = 0x0000_8000;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FILEXFER_TYPE_REGULAR: u8 = 1;
+const SSH_FILEXFER_TYPE_DIRECTORY: u8 = 2;
+const SSH_FILEXFER_TYPE_SYMLINK: u8 = 3;
+const SSH_FILEXFER_TYPE_SPECIAL: u8 = 4;
+const SSH_FILEXFER_TYPE_UNKNOWN: u8 = 5;
+const SSH_FILEXFER_TYPE_SOCKET: u8 = 6;
+const SSH_FILEXFER_TYPE_CHAR_DEVICE: u8 = 7;
+const SSH_FILEXFER_TYPE_BLOCK_DEVICE: u8 = 8;
+const SSH_FILEXFER_TYPE_FIFO: u8 = 9;
+
+const ACE4_READ_DATA: u32 = 0x0000_0001;
+const ACE4_WRITE_DATA: u32 = 0x0000_0002;
+const ACE4_APPEND_DATA: u32 = 0x0000_0004;
+
+const SSH_FXF_ACCESS_DISPOSITION: u32 = 0x0000_0007;
+const SSH_FXF_CREATE_NEW: u32 = 0x0000_0000;
+const SSH_FXF_CREATE_TRUNCATE: u32 = 0x0000_0001;
+const SSH_FXF_OPEN_OR_CREATE: u32 = 0x0000_0003;
+const SSH_FXF_TRUNCATE_EXISTING: u32 = 0x0000_0004;
+const SSH_FXF_APPEND_DATA: u32 = 0x0000_0008;
+const SSH_FXF_APPEND_DATA_ATOMIC: u32 = 0x0000_0010;
+
+const SSH_FXF_BLOCK_READ: u32 = 0x0000_0040;
+
+const SSH_FXP_REALPATH_NO_CHECK: u8 = 0x01;
+const SSH_FXP_REALPATH_STAT_IF: u8 = 0x02;
+const SSH_FXP_REALPATH_STAT_ALWAYS: u8 = 0x03;
+
+/// A packet that couldn't be translated.
+#[derive(Debug, Error)]
+pub(super) enum Error {
+    #[error("packet ends early")]
+    Truncated,
+    #[error("string isn't UTF-8")]
+    NotUtf8,
+    #[error("{0}")]
+    Malformed(String),
+}
+
+/// The version to speak with a client that asked for `client_version`, which
+/// is the newest one both sides know.
+pub(super) fn negotiate(client_version: u32) -> u32 {
+    client_version.clamp(MIN_VERSION, MAX_VERSION)
+}
+
+/// Parses a request `packet` from a client that speaks `version`, without its
+/// length, into the version 3 request that does the same.
+#[allow(clippy::too_many_lines)]
+pub(super) fn decode(version: u32, mut packet: Bytes) -> Result<Packet, Error> {
+    if version <= MIN_VERSION {
+        return Packet::try_from(&mut packet).map_err(|err| Error::Malformed(err.to_string()));
+    }
+
+    let mut reader = Reader(packet.clone());
+    let kind = reader.u8()?;
+
+    let request = match kind {
+        SSH_FXP_OPEN => {
+            let id = reader.u32()?;
+            let filename = reader.string()?;
+            let pflags = if version >= 5 {
+                let desired_access = reader.u32()?;
+                open_flags(desired_access, reader.u32()?)
+            } else {
+                // Version 4 only adds a text mode flag, which is ignored like
+                // the rest of text mode, since lines end with `\n` here.
+                OpenFlags::from_bits_truncate(reader.u32()?)
+            };
+            let attrs = reader.attrs(version)?;
+
+            Packet::Open(Open {
+                id,
+                filename,
+                pflags,
+                attrs,
+            })
+        }
+        // Which attributes the client wants is only a hint, and all of them
+        // are returned anyway.
+        SSH_FXP_STAT | SSH_FXP_LSTAT => {
+            let id = reader.u32()?;
+            let path = reader.string()?;
+
+            if kind == SSH_FXP_STAT {
+                Packet::Stat(Stat { id, path })
+            } else {
+                Packet::Lstat(Lstat { id, path })
+            }
+        }
+        SSH_FXP_FSTAT => Packet::Fstat(Fstat {
+            id: reader.u32()?,
+            handle: reader.string()?,
+        }),
+        SSH_FXP_SETSTAT => Packet::SetStat(SetStat {
+            id: reader.u32()?,
+            path: reader.string()?,
+            attrs: reader.attrs(version)?,
+        }),
+        SSH_FXP_FSETSTAT => Packet::FSetStat(FSetStat {
+            id: reader.u32()?,
+            handle: reader.string()?,
+            attrs: reader.attrs(version)?,
+        }),
+        SSH_FXP_MKDIR => Packet::MkDir(MkDir {
+            id: reader.u32()?,
+            path: reader.string()?,
+            attrs: reader.attrs(version)?,
+        }),
+        SSH_FXP_REALPATH if version >= 6 => {
+            let id = reader.u32()?;
+            let mut path = reader.string()?;
+
+            // Version 6 says whether the path has to exist, and can ask for
+            // more paths to be resolved against the first one. Without being
+            // asked to, it doesn't.
+            let mut must_exist = false;
+            if reader.has_more() {
+                must_exist = match reader.u8()? {
+                    SSH_FXP_REALPATH_NO_CHECK | SSH_FXP_REALPATH_STAT_IF => false,
+                    SSH_FXP_REALPATH_STAT_ALWAYS => true,
+                    control => {
+                        return Err(Error::Malformed(format!(
+                            "unknown SSH_FXP_REALPATH control byte {control}"
+                        )));
+                    }
+                };
+
+                while reader.has_more() {
+                    let compose_path = reader.string()?;
+                    path = if compose_path.starts_with('/') {
+                        compose_path
+                    } else {
+                        format!("{}/{compose_path}", path.trim_end_matches('/'))
+                    };
+                }
+            }
+
+            extended(
+                id,
+                extensions::REALPATH,
+                &RealpathExtension { path, must_exist },
+            )?
+        }
+        SSH_FXP_REALPATH => Packet::RealPath(RealPath {
+            id: reader.u32()?,
+            path: reader.string()?,
+        }),
+        // Renames replace whatever is at the new path, as they do in version
+        // 3, so the flags of version 5 have nothing to add.
+        SSH_FXP_RENAME => Packet::Rename(Rename {
+            id: reader.u32()?,
+            oldpath: reader.string()?,
+            newpath: reader.string()?,
+        }),
+        SSH_FXP_SYMLINK if version >= 6 => {
+            return Err(Error::Malformed(
+                "SSH_FXP_SYMLINK was replaced by SSH_FXP_LINK".to_string(),
+            ));
+        }
+        SSH_FXP_LINK if version >= 6 => {
+            let id = reader.u32()?;
+            let new_link_path = reader.string()?;
+            let existing_path = reader.string()?;
+
+            if reader.bool()? {
+                Packet::Symlink(Symlink {
+                    id,
+                    linkpath: new_link_path,
+                    targetpath: existing_path,
+                })
+            } else {
+                extended(
+                    id,
+                    extensions::HARDLINK,
+                    &HardlinkExtension {
+                        oldpath: existing_path,
+                        newpath: new_link_path,
+                    },
+                )?
+            }
+        }
+        SSH_FXP_BLOCK if version >= 6 => {
+            let id = reader.u32()?;
+            let handle = reader.string()?;
+            let offset = reader.u64()?;
+            let length = reader.u64()?;
+            // Keeping others from reading a range takes an exclusive lock,
+            // while keeping them from writing to it only takes a shared one.
+            let exclusive = reader.u32()? & SSH_FXF_BLOCK_READ != 0;
+
+            extended(
+                id,
+                extensions::LOCK,
+                &LockExtension {
+                    handle,
+                    offset,
+                    length,
+                    exclusive,
+                },
+            )?
+        }
+        SSH_FXP_UNBLOCK if version >= 6 => {
+            let id = reader.u32()?;
+
+            extended(
+                id,
+                extensions::UNLOCK,
+                &UnlockExtension {
+                    handle: reader.string()?,
+                    offset: reader.u64()?,
+                    length: reader.u64()?,
+                },
+            )?
+        }
+        _ => Packet::try_from(&mut packet).map_err(|err| Error::Malformed(err.to_string()))?,
+    };
+
+    Ok(request)
+}
+
+/// Encodes a version 3 `reply`, with its length, for a client that speaks
+/// `version`. The [`ErrorCode`] of a failure picks a more specific status
+/// where the version has one.
+pub(super) fn encode(version: u32, reply: Packet, code: Option<ErrorCode>) -> Result<Bytes, Error> {
+    if version <= MIN_VERSION {
+        return Bytes::try_from(reply).map_err(|err| Error::Malformed(err.to_string()));
+    }
+
+    let mut payload = BytesMut::new();
+
+    match reply {
+        Packet::Status(status) => {
+            payload.put_u8(SSH_FXP_STATUS);
+            payload.put_u32(status.id);
+            payload.put_u32(status_code(version, status.status_code, code));
+            put_string(&mut payload, status.error_message.as_bytes());
+            put_string(&mut payload, status.language_tag.as_bytes());
+        }
+        // Names don't come with a long name any more, since clients are
+        // expected to format the attributes themselves.
+        Packet::Name(name) => {
+            payload.put_u8(SSH_FXP_NAME);
+            payload.put_u32(name.id);
+            payload.put_u32(u32::try_from(name.files.len()).unwrap_or(u32::MAX));
+
+            for file in name.files {
+                put_string(&mut payload, file.filename.as_bytes());
+                put_attrs(&mut payload, version, &file.attrs);
+            }
+        }
+        Packet::Attrs(attrs) => {
+            payload.put_u8(SSH_FXP_ATTRS);
+            payload.put_u32(attrs.id);
+            put_attrs(&mut payload, version, &attrs.attrs);
+        }
+        reply => {
+            return Bytes::try_from(reply).map_err(|err| Error::Malformed(err.to_string()));
+        }
+    }
+
+    let mut packet = BytesMut::with_capacity(payload.len() + 4);
+    packet.put_u32(u32::try_from(payload.len()).unwrap_or(u32::MAX));
+    packet.put_slice(&payload);
+
+    Ok(packet.freeze())
+}
+
+/// The version 3 flags for a version 5 `OPEN` with `desired_access` and
+/// `flags`.
+fn open_flags(desired_access: u32, flags: u32) -> OpenFlags {
+    let mut pflags = OpenFlags::empty();
+
+    if desired_access & ACE4_READ_DATA != 0 {
+        pflags |= OpenFlags::READ;
+    }
+    if desired_access & (ACE4_WRITE_DATA | ACE4_APPEND_DATA) != 0 {
+        pflags |= OpenFlags::WRITE;
+    }
+    if flags & (SSH_FXF_APPEND_DATA | SSH_FXF_APPEND_DATA_ATOMIC) != 0 {
+        pflags |= OpenFlags::APPEND;
+    }
+
+    pflags
+        | match flags & SSH_FXF_ACCESS_DISPOSITION {
+            SSH_FXF_CREATE_NEW => OpenFlags::CREATE | OpenFlags::EXCLUDE,
+            SSH_FXF_CREATE_TRUNCATE => OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            SSH_FXF_OPEN_OR_CREATE => OpenFlags::CREATE,
+            SSH_FXF_TRUNCATE_EXISTING => OpenFlags::TRUNCATE,
+            _ => OpenFlags::empty(),
+        }
+}
+
+/// An extended request for `name` with `data`.
+fn extended<T: Serialize>(id: u32, name: &str, data: &T) -> Result<Packet, Error> {
+    let data = russh_sftp::ser::to_bytes(data).map_err(|err| Error::Malformed(err.to_string()))?;
+
+    Ok(Packet::Extended(Extended {
+        id,
+        request: name.to_string(),
+        data: data.to_vec(),
+    }))
+}
+
+/// The status to send a client that speaks `version` for a failure with
+/// `status` and `code`.
+fn status_code(version: u32, status: StatusCode, code: Option<ErrorCode>) -> u32 {
+    /// `SSH_FX_WRITE_PROTECT`.
+    const WRITE_PROTECT: u32 = 12;
+    /// `SSH_FX_NO_SPACE_ON_FILESYSTEM`.
+    const NO_SPACE_ON_FILESYSTEM: u32 = 14;
+    /// `SSH_FX_LOCK_CONFLICT`.
+    const LOCK_CONFLICT: u32 = 17;
+    /// `SSH_FX_NOT_A_DIRECTORY`.
+    const NOT_A_DIRECTORY: u32 = 19;
+    /// `SSH_FX_INVALID_FILENAME`.
+    const INVALID_FILENAME: u32 = 20;
+    /// `SSH_FX_FILE_IS_A_DIRECTORY`.
+    const FILE_IS_A_DIRECTORY: u32 = 24;
+
+    if status != StatusCode::Failure {
+        return status as u32;
+    }
+
+    let specific = match code {
+        Some(
+            ErrorCode::ReadOnly | ErrorCode::LegalHold | ErrorCode::Immutable | ErrorCode::Retained,
+        ) => Some((4, WRITE_PROTECT)),
+        Some(ErrorCode::NoSpace) => Some((5, NO_SPACE_ON_FILESYSTEM)),
+        Some(ErrorCode::Locked) => Some((5, LOCK_CONFLICT)),
+        Some(ErrorCode::NotADirectory) => Some((6, NOT_A_DIRECTORY)),
+        Some(ErrorCode::InvalidPath) => Some((6, INVALID_FILENAME)),
+        Some(ErrorCode::NotAFile) => Some((6, FILE_IS_A_DIRECTORY)),
+        _ => None,
+    };
+
+    match specific {
+        Some((since, specific)) if version >= since => specific,
+        _ => status as u32,
+    }
+}
+
+/// Writes `attrs` the way `version` lays them out. Every version from 4 on
+/// puts the attributes that are known here in the same order.
+fn put_attrs(buf: &mut BytesMut, version: u32, attrs: &FileAttributes) {
+    let owner = attrs
+        .user
+        .clone()
+        .or_else(|| attrs.uid.map(|uid| uid.to_string()));
+    let group = attrs
+        .group
+        .clone()
+        .or_else(|| attrs.gid.map(|gid| gid.to_string()));
+    let owner_group = owner.zip(group);
+
+    let mut flags = 0;
+    if attrs.size.is_some() {
+        flags |= SSH_FILEXFER_ATTR_SIZE;
+    }
+    if owner_group.is_some() {
+        flags |= SSH_FILEXFER_ATTR_OWNERGROUP;
+    }
+    if attrs.permissions.is_some() {
+        flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+    }
+    if attrs.atime.is_some() {
+        flags |= SSH_FILEXFER_ATTR_ACCESSTIME;
+    }
+    if attrs.mtime.is_some() {
+        flags |= SSH_FILEXFER_ATTR_MODIFYTIME;
+    }
+
+    buf.put_u32(flags);
+    buf.put_u8(file_type(version, attrs.permissions));
+
+    if let Some(size) = attrs.size {
+        buf.put_u64(size);
+    }
+    if let Some((owner, group)) = owner_group {
+        put_string(buf, owner.as_bytes());
+        put_string(buf, group.as_bytes());
+    }
+    if let Some(permissions) = attrs.permissions {
+        buf.put_u32(permissions & 0o7777);
+    }
+    if let Some(atime) = attrs.atime {
+        buf.put_i64(i64::from(atime));
+    }
+    if let Some(mtime) = attrs.mtime {
+        buf.put_i64(i64::from(mtime));
+    }
+}
+
+/// The type of a file with POSIX `permissions`, which version 4 only has the
+/// first five of.
+fn file_type(version: u32, permissions: Option<u32>) -> u8 {
+    const FORMAT: u32 = 0o170_000;
+
+    let Some(permissions) = permissions else {
+        return SSH_FILEXFER_TYPE_UNKNOWN;
+    };
+
+    let mode = FileMode::from_bits_truncate(permissions & FORMAT);
+    let file_type = [
+        (FileMode::REG, SSH_FILEXFER_TYPE_REGULAR),
+        (FileMode::DIR, SSH_FILEXFER_TYPE_DIRECTORY),
+        (FileMode::LNK, SSH_FILEXFER_TYPE_SYMLINK),
+        (FileMode::SOCK, SSH_FILEXFER_TYPE_SOCKET),
+        (FileMode::CHR, SSH_FILEXFER_TYPE_CHAR_DEVICE),
+        (FileMode::BLK, SSH_FILEXFER_TYPE_BLOCK_DEVICE),
+        (FileMode::FIFO, SSH_FILEXFER_TYPE_FIFO),
+    ]
+    .into_iter()
+    .find(|(known, _)| *known == mode)
+    .map_or(SSH_FILEXFER_TYPE_UNKNOWN, |(_, file_type)| file_type);
+
+    if version < 5 && file_type > SSH_FILEXFER_TYPE_UNKNOWN {
+        SSH_FILEXFER_TYPE_SPECIAL
+    } else {
+        file_type
+    }
+}
+
+fn put_string(buf: &mut BytesMut, data: &[u8]) {
+    buf.put_u32(u32::try_from(data.len()).unwrap_or(u32::MAX));
+    buf.put_slice(data);
+}
+
+/// Reads the fields of a packet one at a time.
+struct Reader(Bytes);
+
+impl Reader {
+    fn need(&self, len: usize) -> Result<(), Error> {
+        if self.0.remaining() < len {
+            return Err(Error::Truncated);
+        }
+
+        Ok(())
+    }
+
+    fn has_more(&self) -> bool {
+        self.0.has_remaining()
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        self.need(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.need(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        self.need(8)?;
+        Ok(self.0.get_u64())
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        self.need(8)?;
+        Ok(self.0.get_i64())
+    }
+
+    fn bytes(&mut self) -> Result<Bytes, Error> {
+        let len = self.u32()? as usize;
+        self.need(len)?;
+        Ok(self.0.split_to(len))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| Error::NotUtf8)
+    }
+
+    /// Reads a time, and the nanoseconds that go with it if there are any,
+    /// to the second.
+    fn time(&mut self, flags: u32) -> Result<Option<u32>, Error> {
+        let seconds = self.i64()?;
+        if flags & SSH_FILEXFER_ATTR_SUBSECOND_TIMES != 0 {
+            self.u32()?;
+        }
+
+        Ok(u32::try_from(seconds).ok())
+    }
+
+    /// Reads file attributes the way `version` lays them out, keeping the
+    /// ones that version 3 has too.
+    fn attrs(&mut self, version: u32) -> Result<FileAttributes, Error> {
+        let flags = self.u32()?;
+        // The type of a file can't be changed, nor picked when creating one,
+        // since the request already says what it's creating.
+        self.u8()?;
+
+        let mut attrs = FileAttributes::empty();
+
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if version >= 6 && flags & SSH_FILEXFER_ATTR_ALLOCATION_SIZE != 0 {
+            self.u64()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_OWNERGROUP != 0 {
+            attrs.user = Some(self.string()?);
+            attrs.group = Some(self.string()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACCESSTIME != 0 {
+            attrs.atime = self.time(flags)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_CREATETIME != 0 {
+            self.time(flags)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_MODIFYTIME != 0 {
+            attrs.mtime = self.time(flags)?;
+        }
+        if version >= 6 && flags & SSH_FILEXFER_ATTR_CTIME != 0 {
+            self.time(flags)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_ACL != 0 {
+            self.bytes()?;
+        }
+        if version >= 5 && flags & SSH_FILEXFER_ATTR_BITS != 0 {
+            self.u32()?;
+            if version >= 6 {
+                self.u32()?;
+            }
+        }
+        if version >= 6 {
+            if flags & SSH_FILEXFER_ATTR_TEXT_HINT != 0 {
+                self.u8()?;
+            }
+            if flags & SSH_FILEXFER_ATTR_MIME_TYPE != 0 {
+                self.bytes()?;
+            }
+            if flags & SSH_FILEXFER_ATTR_LINK_COUNT != 0 {
+                self.u32()?;
+            }
+            if flags & SSH_FILEXFER_ATTR_UNTRANSLATED_NAME != 0 {
+                self.bytes()?;
+            }
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+
+        Ok(attrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attrs() -> FileAttributes {
+        FileAttributes {
+            size: Some(1234),
+            user: Some("alice".to_string()),
+            group: Some("staff".to_string()),
+            permissions: Some(0o100_644),
+            atime: Some(1_700_000_000),
+            mtime: Some(1_700_000_100),
+            ..FileAttributes::empty()
+        }
+    }
+
+    /// The attributes from [`attrs`], laid out the way every version from 4
+    /// on lays them out.
+    fn attrs_bytes() -> Vec<u8> {
+        let flags = SSH_FILEXFER_ATTR_SIZE
+            | SSH_FILEXFER_ATTR_OWNERGROUP
+            | SSH_FILEXFER_ATTR_PERMISSIONS
+            | SSH_FILEXFER_ATTR_ACCESSTIME
+            | SSH_FILEXFER_ATTR_MODIFYTIME;
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.push(SSH_FILEXFER_TYPE_REGULAR);
+        bytes.extend_from_slice(&1234_u64.to_be_bytes());
+        bytes.extend_from_slice(b"\0\0\0\x05alice\0\0\0\x05staff");
+        bytes.extend_from_slice(&0o644_u32.to_be_bytes());
+        bytes.extend_from_slice(&1_700_000_000_i64.to_be_bytes());
+        bytes.extend_from_slice(&1_700_000_100_i64.to_be_bytes());
+
+        bytes
+    }
+
+    fn assert_same(decoded: &FileAttributes, expected: &FileAttributes) {
+        assert_eq!(decoded.size, expected.size);
+        assert_eq!(decoded.user, expected.user);
+        assert_eq!(decoded.group, expected.group);
+        assert_eq!(decoded.permissions, expected.permissions);
+        assert_eq!(decoded.atime, expected.atime);
+        assert_eq!(decoded.mtime, expected.mtime);
+    }
+
+    #[test]
+    fn attrs_round_trip() {
+        for version in 4..=MAX_VERSION {
+            let mut buf = BytesMut::new();
+            put_attrs(&mut buf, version, &attrs());
+            assert_eq!(buf[..], attrs_bytes()[..], "version {version}");
+
+            let mut reader = Reader(buf.freeze());
+            let decoded = reader.attrs(version).unwrap();
+            assert!(!reader.has_more(), "version {version}");
+
+            // Only the permission bits are sent, since the type has a field of
+            // its own.
+            let expected = FileAttributes {
+                permissions: Some(0o644),
+                ..attrs()
+            };
+            assert_same(&decoded, &expected);
+        }
+    }
+
+    #[test]
+    fn attrs_without_fields() {
+        for version in 4..=MAX_VERSION {
+            let mut buf = BytesMut::new();
+            put_attrs(&mut buf, version, &FileAttributes::empty());
+            assert_eq!(buf[..], [0, 0, 0, 0, SSH_FILEXFER_TYPE_UNKNOWN]);
+
+            let decoded = Reader(buf.freeze()).attrs(version).unwrap();
+            assert_same(&decoded, &FileAttributes::empty());
+        }
+    }
+
+    /// Special files only have types of their own from version 5 on.
+    #[test]
+    fn attrs_special_file_type() {
+        let socket = FileAttributes {
+            permissions: Some(0o140_755),
+            ..FileAttributes::empty()
+        };
+
+        for (version, file_type) in [
+            (4, SSH_FILEXFER_TYPE_SPECIAL),
+            (5, SSH_FILEXFER_TYPE_SOCKET),
+            (6, SSH_FILEXFER_TYPE_SOCKET),
+        ] {
+            let mut buf = BytesMut::new();
+            put_attrs(&mut buf, version, &socket);
+            assert_eq!(buf[4], file_type, "version {version}");
+        }
+    }
+
+    /// The fields that version 3 has no room for are read past.
+    #[test]
+    fn attrs_skip_later_fields() {
+        let flags = SSH_FILEXFER_ATTR_SIZE
+            | SSH_FILEXFER_ATTR_ALLOCATION_SIZE
+            | SSH_FILEXFER_ATTR_MODIFYTIME
+            | SSH_FILEXFER_ATTR_SUBSECOND_TIMES
+            | SSH_FILEXFER_ATTR_CTIME
+            | SSH_FILEXFER_ATTR_BITS
+            | SSH_FILEXFER_ATTR_LINK_COUNT
+            | SSH_FILEXFER_ATTR_EXTENDED;
+
+        let mut buf = BytesMut::new();
+        buf.put_u32(flags);
+        buf.put_u8(SSH_FILEXFER_TYPE_REGULAR);
+        buf.put_u64(1234);
+        buf.put_u64(4096);
+        buf.put_i64(1_700_000_100);
+        buf.put_u32(500);
+        buf.put_i64(1_700_000_200);
+        buf.put_u32(600);
+        buf.put_u32(0);
+        buf.put_u32(0);
+        buf.put_u32(2);
+        buf.put_u32(1);
+        put_string(&mut buf, b"name@example.com");
+        put_string(&mut buf, b"value");
+
+        let mut reader = Reader(buf.freeze());
+        let decoded = reader.attrs(6).unwrap();
+        assert!(!reader.has_more());
+
+        let expected = FileAttributes {
+            size: Some(1234),
+            mtime: Some(1_700_000_100),
+            ..FileAttributes::empty()
+        };
+        assert_same(&decoded, &expected);
+    }
+
+    fn realpath(control: Option<u8>, compose: &[&str]) -> Result<Packet, Error> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(SSH_FXP_REALPATH);
+        buf.put_u32(7);
+        put_string(&mut buf, b"/home/alice");
+        if let Some(control) = control {
+            buf.put_u8(control);
+        }
+        for path in compose {
+            put_string(&mut buf, path.as_bytes());
+        }
+
+        decode(6, buf.freeze())
+    }
+
+    fn realpath_extension(packet: Packet) -> RealpathExtension {
+        let Packet::Extended(Extended { id, request, data }) = packet else {
+            panic!("expected an extended request");
+        };
+        assert_eq!(id, 7);
+        assert_eq!(request, extensions::REALPATH);
+
+        extensions::parse(data).unwrap()
+    }
+
+    #[test]
+    fn realpath_control_byte() {
+        for (control, must_exist) in [
+            (None, false),
+            (Some(SSH_FXP_REALPATH_NO_CHECK), false),
+            (Some(SSH_FXP_REALPATH_STAT_IF), false),
+            (Some(SSH_FXP_REALPATH_STAT_ALWAYS), true),
+        ] {
+            let realpath = realpath_extension(realpath(control, &[]).unwrap());
+            assert_eq!(realpath.path, "/home/alice");
+            assert_eq!(realpath.must_exist, must_exist, "control {control:?}");
+        }
+
+        assert!(matches!(realpath(Some(4), &[]), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn realpath_compose_paths() {
+        let packet = realpath(Some(SSH_FXP_REALPATH_NO_CHECK), &["docs", "/srv", "data"]);
+
+        assert_eq!(realpath_extension(packet.unwrap()).path, "/srv/data");
+    }
+}
@@ -0,0 +1,137 @@
+//! An SSH client for when Schlep is the one connecting to an SFTP server,
+//! such as for push jobs and conformance checks.
+
+use std::{path::Path, sync::Arc};
+
+use russh::{
+    ChannelStream,
+    client::{self, Msg},
+    keys::{PrivateKeyWithHashAlg, PublicKey, load_secret_key},
+};
+use url::Url;
+
+use crate::error_code::ErrorCode;
+
+#[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("invalid key: {from}")]
+    KeyError {
+        source: russh::keys::Error,
+        from: String,
+    },
+    #[error("SSH error: {from}")]
+    SshError { source: russh::Error, from: String },
+    #[error("couldn't log in to {host}")]
+    AuthenticationFailed { host: String },
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidUrl(_) => ErrorCode::BadRequest,
+            Self::KeyError { .. } => ErrorCode::InvalidKey,
+            Self::SshError { .. } => ErrorCode::Ssh,
+            Self::AuthenticationFailed { .. } => ErrorCode::PermissionDenied,
+        }
+    }
+}
+
+/// A logged-in SSH connection. The connection is closed when it's dropped.
+pub struct SshClient {
+    handle: client::Handle<HostKeyCheck>,
+}
+
+impl SshClient {
+    /// Connects to the server at `url`, which looks like
+    /// `sftp://user@host:port/...`, and logs in with the key in
+    /// `private_key_file`.
+    ///
+    /// The server has to present `host_key`. Without one, any host key is
+    /// accepted, which is only fit for talking to a server under test.
+    pub async fn connect(
+        url: &Url,
+        private_key_file: &Path,
+        host_key: Option<PublicKey>,
+    ) -> Result<Self, Error> {
+        let Some(host) = url.host_str() else {
+            return Err(Error::InvalidUrl(format!("{url} has no host")));
+        };
+        let port = url.port().unwrap_or(22);
+
+        let key =
+            load_secret_key(private_key_file, None).into_key_error("failed to load private key")?;
+
+        let mut handle = client::connect(
+            Arc::new(client::Config::default()),
+            (host, port),
+            HostKeyCheck(host_key),
+        )
+        .await
+        .into_ssh_error("failed to connect")?;
+
+        let hash_alg = handle
+            .best_supported_rsa_hash()
+            .await
+            .into_ssh_error("failed to negotiate key type")?
+            .flatten();
+        let auth = handle
+            .authenticate_publickey(
+                url.username(),
+                PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg),
+            )
+            .await
+            .into_ssh_error("failed to authenticate")?;
+
+        if !auth.success() {
+            return Err(Error::AuthenticationFailed {
+                host: host.to_string(),
+            });
+        }
+
+        Ok(Self { handle })
+    }
+
+    /// Opens a channel running the `sftp` subsystem.
+    pub async fn sftp(&self) -> Result<ChannelStream<Msg>, Error> {
+        let channel = self
+            .handle
+            .channel_open_session()
+            .await
+            .into_ssh_error("failed to open channel")?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .into_ssh_error("failed to request SFTP")?;
+
+        Ok(channel.into_stream())
+    }
+
+    /// Whether the connection has been closed, by either end.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.handle.is_closed()
+    }
+}
+
+/// Only accepts the host key the client was told to expect, if it was told
+/// to expect one.
+struct HostKeyCheck(Option<PublicKey>);
+
+impl client::Handler for HostKeyCheck {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKey,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .0
+            .as_ref()
+            .is_none_or(|host_key| host_key == server_public_key))
+    }
+}
@@ -0,0 +1,111 @@
+//! Shared state that has to outlive a single session, like the history of
+//! transfers. It is kept in Redis when one is configured, so that it survives
+//! restarts and is shared by every instance behind a load balancer, and in
+//! memory otherwise.
+
+use std::{collections::VecDeque, sync::Arc};
+
+use ahash::HashMap;
+use fred::prelude::*;
+use parking_lot::Mutex;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    error_code::ErrorCode,
+    redis::{RedisError, RedisPool},
+};
+
+#[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("redis error: {from}")]
+    RedisError { source: RedisError, from: String },
+    #[error("couldn't encode or decode state")]
+    JsonError(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::RedisError { .. } => ErrorCode::CacheUnavailable,
+            Self::JsonError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+/// A store for shared state, made of named lists of JSON values.
+#[derive(Clone)]
+pub struct StateStore(Backend);
+
+#[derive(Clone)]
+enum Backend {
+    Redis(RedisPool),
+    Memory(Arc<Mutex<HashMap<String, VecDeque<String>>>>),
+}
+
+impl StateStore {
+    /// A store kept in Redis through `redis_pool`, or in memory if there is
+    /// no pool.
+    #[must_use]
+    pub fn new(redis_pool: Option<RedisPool>) -> Self {
+        match redis_pool {
+            Some(pool) => Self(Backend::Redis(pool)),
+            None => Self(Backend::Memory(Arc::default())),
+        }
+    }
+
+    /// Adds `value` to the front of the list `key`, dropping the oldest
+    /// entries beyond the first `max_len`.
+    pub async fn push<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        max_len: usize,
+    ) -> Result<(), Error> {
+        let value = serde_json::to_string(value)?;
+
+        match &self.0 {
+            Backend::Redis(pool) => {
+                let stop = i64::try_from(max_len).unwrap_or(i64::MAX).saturating_sub(1);
+
+                pool.lpush::<(), _, _>(key, value)
+                    .await
+                    .into_redis_error("failed to add state")?;
+                pool.ltrim::<(), _>(key, 0, stop)
+                    .await
+                    .into_redis_error("failed to trim state")?;
+            }
+            Backend::Memory(lists) => {
+                let mut lists = lists.lock();
+                let list = lists.entry(key.to_string()).or_default();
+
+                list.push_front(value);
+                list.truncate(max_len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every entry in the list `key`, newest first.
+    pub async fn list<T: DeserializeOwned>(&self, key: &str) -> Result<Vec<T>, Error> {
+        let values: Vec<String> = match &self.0 {
+            Backend::Redis(pool) => pool
+                .lrange(key, 0, -1)
+                .await
+                .into_redis_error("failed to read state")?,
+            Backend::Memory(lists) => lists
+                .lock()
+                .get(key)
+                .map(|list| list.iter().cloned().collect())
+                .unwrap_or_default(),
+        };
+
+        values
+            .iter()
+            .map(|value| serde_json::from_str(value).map_err(Error::from))
+            .collect()
+    }
+}
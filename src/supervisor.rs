@@ -0,0 +1,200 @@
+//! Keeps Schlep's long-running servers running. When one of them stops, it's
+//! restarted after a delay that doubles each time it stops again, so that a
+//! transient failure, like a port that's briefly still in use, doesn't take the
+//! whole process down with it, and with it every active SFTP session.
+//!
+//! Each server has its own [`RestartPolicy`]. A server that keeps stopping is
+//! eventually given up on, which stops the process.
+
+use std::{panic::AssertUnwindSafe, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures_util::FutureExt as _;
+use metrics::counter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{Level, event};
+
+use crate::{affinity::Affinity, gateway::Gateway, metrics::Metrics, sftp::SshServer};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "supervisor_config")]
+pub struct Config {
+    /// How to restart the SFTP server.
+    #[serde(default)]
+    pub ssh: RestartPolicy,
+
+    /// How to restart the metrics server.
+    #[serde(default)]
+    pub metrics: RestartPolicy,
+
+    /// How to restart the HAProxy agent-check responder.
+    #[serde(default)]
+    pub agent_check: RestartPolicy,
+
+    /// How to restart the HTTP gateway.
+    #[serde(default)]
+    pub gateway: RestartPolicy,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "restart_policy")]
+pub struct RestartPolicy {
+    /// How many times in a row the server may be restarted before it's given
+    /// up on. Zero means that it's never restarted. The default value is 10.
+    #[serde(
+        default = "RestartPolicy::default_max_restarts",
+        skip_serializing_if = "RestartPolicy::is_default_max_restarts"
+    )]
+    pub max_restarts: u32,
+
+    /// How long to wait before the first restart. The default value is 1
+    /// second.
+    #[serde(
+        default = "RestartPolicy::default_initial_backoff",
+        skip_serializing_if = "RestartPolicy::is_default_initial_backoff",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub initial_backoff: Duration,
+
+    /// The longest to wait before a restart. A server that ran for at least
+    /// this long before it stopped has its restarts reset, as if it had never
+    /// stopped before. The default value is 1 minute.
+    #[serde(
+        default = "RestartPolicy::default_max_backoff",
+        skip_serializing_if = "RestartPolicy::is_default_max_backoff",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    fn default_max_restarts() -> u32 {
+        10
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_restarts(max_restarts: &u32) -> bool {
+        *max_restarts == Self::default_max_restarts()
+    }
+
+    fn default_initial_backoff() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn is_default_initial_backoff(initial_backoff: &Duration) -> bool {
+        *initial_backoff == Self::default_initial_backoff()
+    }
+
+    fn default_max_backoff() -> Duration {
+        Duration::from_mins(1)
+    }
+
+    fn is_default_max_backoff(max_backoff: &Duration) -> bool {
+        *max_backoff == Self::default_max_backoff()
+    }
+
+    /// How long to wait before the `restarts`th restart in a row.
+    fn backoff(&self, restarts: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: Self::default_max_restarts(),
+            initial_backoff: Self::default_initial_backoff(),
+            max_backoff: Self::default_max_backoff(),
+        }
+    }
+}
+
+/// A server that can be supervised. It's run again each time it stops.
+#[async_trait]
+pub trait Service: Send {
+    /// Runs the server until it stops.
+    async fn serve(&mut self) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Service for SshServer {
+    async fn serve(&mut self) -> anyhow::Result<()> {
+        Ok(self.run().await?)
+    }
+}
+
+#[async_trait]
+impl Service for Metrics {
+    async fn serve(&mut self) -> anyhow::Result<()> {
+        self.run().await
+    }
+}
+
+#[async_trait]
+impl Service for Affinity {
+    async fn serve(&mut self) -> anyhow::Result<()> {
+        self.run_agent_check().await
+    }
+}
+
+#[async_trait]
+impl Service for Gateway {
+    async fn serve(&mut self) -> anyhow::Result<()> {
+        self.run().await
+    }
+}
+
+/// Runs `service` under `policy`, restarting it whenever it stops or panics,
+/// until it has to be given up on. `name` identifies it in the logs and in the
+/// restart metric.
+pub async fn supervise(
+    name: &'static str,
+    policy: RestartPolicy,
+    mut service: impl Service,
+) -> anyhow::Result<()> {
+    let mut restarts = 0;
+
+    loop {
+        let started_at = Instant::now();
+        let err = match AssertUnwindSafe(service.serve()).catch_unwind().await {
+            Ok(Ok(())) => anyhow!("{name} stopped unexpectedly"),
+            Ok(Err(err)) => err,
+            Err(_) => anyhow!("{name} panicked"),
+        };
+
+        if started_at.elapsed() >= policy.max_backoff {
+            restarts = 0;
+        }
+
+        if restarts >= policy.max_restarts {
+            event!(
+                Level::ERROR,
+                service = name,
+                restarts,
+                "giving up on restarting"
+            );
+            return Err(err.context(format!("{name} stopped too many times")));
+        }
+
+        restarts += 1;
+        let backoff = policy.backoff(restarts);
+        event!(
+            Level::WARN,
+            service = name,
+            err = %format!("{err:#}"),
+            restarts,
+            ?backoff,
+            "restarting after failure"
+        );
+        counter!(Metrics::SERVICE_RESTARTS, "service" => name).increment(1);
+
+        tokio::time::sleep(backoff).await;
+    }
+}
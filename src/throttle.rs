@@ -0,0 +1,55 @@
+//! Bandwidth limits for file transfers.
+//!
+//! A [`Throttle`] is a token bucket of bytes: transfers may run a second ahead
+//! of the limit in a burst, and after that they're slowed down to it. It never
+//! refuses anything, it only makes the transfer wait, so whoever is using it
+//! can simply hold on to their reply for a while.
+
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// How far ahead of its limit a transfer may get in a burst.
+const BURST: Duration = Duration::from_secs(1);
+
+/// Keeps the transfers that share it under a bandwidth limit.
+pub struct Throttle {
+    bytes_per_second: u64,
+    /// When the bucket will be full again.
+    full_at: Mutex<Instant>,
+}
+
+impl Throttle {
+    #[must_use]
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            full_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Accounts for `bytes` being transferred, and returns how long the
+    /// transfer has to wait before it's back under the limit.
+    #[must_use]
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        #[allow(clippy::cast_precision_loss)]
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+
+        let now = Instant::now();
+        let mut full_at = self.full_at.lock();
+        *full_at = (*full_at).max(now) + cost;
+
+        full_at.saturating_duration_since(now).saturating_sub(BURST)
+    }
+
+    /// Accounts for `bytes` being transferred, and waits until the transfer is
+    /// back under the limit.
+    pub async fn consume(&self, bytes: u64) {
+        let delay = self.reserve(bytes);
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
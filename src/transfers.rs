@@ -0,0 +1,344 @@
+//! The history of file transfers. Every upload and download over SFTP is
+//! recorded when its handle is closed: who transferred which file, how much of
+//! it, its checksum, how long it took, and whether it went through. The
+//! history is kept in the [`StateStore`], and can be queried through the admin
+//! subsystem for reporting.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::broadcast;
+use tracing::{Level, event};
+
+use crate::{
+    audit,
+    state::{self, StateStore},
+};
+
+/// The list in the state store that the history is kept in.
+const HISTORY_KEY: &str = "schlep:transfers";
+
+/// How many finished transfers can be waiting for a slow subscriber before it
+/// starts missing them.
+const FINISHED_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "transfers_config")]
+pub struct Config {
+    /// How many transfers to keep in the history. Once it is full, the oldest
+    /// transfers are dropped first. The default value is 10000.
+    #[serde(
+        default = "Config::default_history_size",
+        skip_serializing_if = "Config::is_default_history_size"
+    )]
+    pub history_size: usize,
+}
+
+impl Config {
+    fn default_history_size() -> usize {
+        10_000
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_history_size(size: &usize) -> bool {
+        *size == Self::default_history_size()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            history_size: Self::default_history_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The handle was closed normally.
+    Completed,
+    /// Closing the handle failed, so the file may not have been written out.
+    Failed,
+    /// The session ended with the handle still open.
+    Interrupted,
+    /// The file didn't match the checksum its client declared for it, so it
+    /// was removed or quarantined.
+    Rejected,
+}
+
+/// A finished transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub id: String,
+    pub username: String,
+    /// The path of the file in the virtual hierarchy.
+    pub path: Utf8PathBuf,
+    pub direction: Direction,
+    /// How many bytes were transferred.
+    pub size: u64,
+    /// The hex-encoded SHA-256 of the bytes transferred, if they were
+    /// transferred in order from the start of the file. Clients that skip
+    /// around in the file don't get one.
+    pub checksum: Option<String>,
+    /// The hex-encoded SHA-256 that the client declared the file should have,
+    /// if it declared one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_checksum: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub started_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub duration: Duration,
+    pub status: Status,
+}
+
+/// Which transfers to list. Every criterion that is set has to match.
+#[derive(Debug, Clone, Default)]
+pub struct TransferFilter {
+    pub username: Option<String>,
+    /// Only transfers of files in this directory, or of this file.
+    pub path: Option<Utf8PathBuf>,
+    pub direction: Option<Direction>,
+    pub status: Option<Status>,
+}
+
+impl TransferFilter {
+    fn matches(&self, record: &TransferRecord) -> bool {
+        self.username
+            .as_ref()
+            .is_none_or(|username| *username == record.username)
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|path| record.path.starts_with(path))
+            && self
+                .direction
+                .is_none_or(|direction| direction == record.direction)
+            && self.status.is_none_or(|status| status == record.status)
+    }
+}
+
+/// The transfer history.
+#[derive(Clone)]
+pub struct Transfers {
+    store: StateStore,
+    history_size: usize,
+    finished: broadcast::Sender<TransferRecord>,
+}
+
+impl Transfers {
+    #[must_use]
+    pub fn new(config: &Config, store: StateStore) -> Self {
+        Self {
+            store,
+            history_size: config.history_size,
+            finished: broadcast::channel(FINISHED_CAPACITY).0,
+        }
+    }
+
+    /// Subscribes to the transfers that finish on this server from now on.
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<TransferRecord> {
+        self.finished.subscribe()
+    }
+
+    /// Adds a finished transfer to the history, and tells the subscribers
+    /// about it.
+    pub async fn record(&self, record: &TransferRecord) -> Result<(), state::Error> {
+        event!(
+            target: audit::TARGET,
+            Level::INFO,
+            id = record.id,
+            username = record.username,
+            path = %record.path,
+            direction = %record.direction,
+            size = record.size,
+            status = %record.status,
+            "transfer finished"
+        );
+
+        // Nobody listening is fine.
+        let _ = self.finished.send(record.clone());
+
+        self.store
+            .push(HISTORY_KEY, record, self.history_size)
+            .await
+    }
+
+    /// The transfers matching `filter`, newest first, skipping the first
+    /// `offset` of them and returning at most `limit`.
+    pub async fn list(
+        &self,
+        filter: &TransferFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<TransferRecord>, state::Error> {
+        Ok(self
+            .store
+            .list::<TransferRecord>(HISTORY_KEY)
+            .await?
+            .into_iter()
+            .filter(|record| filter.matches(record))
+            .skip(offset)
+            .take(limit)
+            .collect())
+    }
+}
+
+/// A transfer that is still in progress.
+pub struct Transfer {
+    username: String,
+    path: Utf8PathBuf,
+    direction: Direction,
+    started_at: SystemTime,
+    size: u64,
+    /// The running checksum, until the transfer stops being sequential.
+    hasher: Option<Sha256>,
+    next_offset: u64,
+    expected_checksum: Option<String>,
+}
+
+impl Transfer {
+    #[must_use]
+    pub fn start(username: &str, path: &Utf8Path, direction: Direction) -> Self {
+        Self {
+            username: username.to_string(),
+            path: path.to_owned(),
+            direction,
+            started_at: SystemTime::now(),
+            size: 0,
+            hasher: Some(Sha256::new()),
+            next_offset: 0,
+            expected_checksum: None,
+        }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// How many bytes have been transferred so far.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The hex-encoded SHA-256 of the bytes transferred so far, if they were
+    /// transferred in order from the start of the file.
+    #[must_use]
+    pub fn checksum(&self) -> Option<String> {
+        self.hasher
+            .clone()
+            .map(|hasher| format!("{:x}", hasher.finalize()))
+    }
+
+    /// The hex-encoded SHA-256 that the client declared the file should have.
+    #[must_use]
+    pub fn expected_checksum(&self) -> Option<&str> {
+        self.expected_checksum.as_deref()
+    }
+
+    /// Records the SHA-256 that the client declared the file should have,
+    /// replacing any it declared before.
+    pub fn expect_checksum(&mut self, checksum: &str) {
+        self.expected_checksum = Some(checksum.to_ascii_lowercase());
+    }
+
+    /// Accounts for `data` having been read or written at `offset`.
+    pub fn transferred(&mut self, offset: u64, data: &[u8]) {
+        let len = data.len() as u64;
+
+        if offset == self.next_offset {
+            if let Some(hasher) = &mut self.hasher {
+                hasher.update(data);
+            }
+        } else {
+            self.hasher = None;
+        }
+
+        self.size += len;
+        self.next_offset = offset.saturating_add(len);
+    }
+
+    #[must_use]
+    pub fn finish(self, status: Status) -> TransferRecord {
+        TransferRecord {
+            id: format!("{:016x}", rand::random::<u64>()),
+            username: self.username,
+            path: self.path,
+            direction: self.direction,
+            size: self.size,
+            checksum: self.hasher.map(|hasher| format!("{:x}", hasher.finalize())),
+            expected_checksum: self.expected_checksum,
+            started_at: self.started_at,
+            duration: self.started_at.elapsed().unwrap_or_default(),
+            status,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Upload => "upload",
+            Self::Download => "download",
+        })
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upload" => Ok(Self::Upload),
+            "download" => Ok(Self::Download),
+            _ => Err(format!("unknown transfer direction `{s}`")),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Completed => "completed",
+            Self::Failed => "failed",
+            Self::Interrupted => "interrupted",
+            Self::Rejected => "rejected",
+        })
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "completed" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            "interrupted" => Ok(Self::Interrupted),
+            "rejected" => Ok(Self::Rejected),
+            _ => Err(format!("unknown transfer status `{s}`")),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Hooks that run as soon as a client finishes uploading a file, so that
+//! whatever processes uploads downstream can start on them right away rather
+//! than polling for new files.
+//!
+//! A hook can `POST` the upload to a webhook as JSON, run a local command with
+//! the upload in its environment, or both. Every upload is reported with the
+//! user that uploaded it, its path and size, and its SHA-256, which is worked
+//! out from the file if the transfer couldn't hash it as it went. Hooks that
+//! fail are recorded in the audit log.
+
+use std::{io, process::Stdio, time::Duration};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use metrics::counter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror_ext::AsReport;
+use tokio::{process::Command, sync::broadcast::error::RecvError};
+use tracing::{Level, event};
+use url::Url;
+
+use crate::{
+    audit,
+    checksums::ChecksumCache,
+    error_code::ErrorCode,
+    metrics::Metrics,
+    transfers::{self, Direction, TransferRecord, Transfers},
+    version::VERSION_INFO,
+    vfs::{HashAlgorithm, LiveVfsSet, PathMatch},
+};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "upload_hooks_config")]
+pub struct Config {
+    /// The hooks to run when an upload finishes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "upload_hook_config")]
+pub struct HookConfig {
+    /// The name of the hook, as it appears in logs and audit events.
+    pub name: String,
+
+    /// Only uploads of files in this directory in the virtual hierarchy, or of
+    /// this file, run the hook. By default, every upload does.
+    #[serde(
+        default = "HookConfig::default_path",
+        skip_serializing_if = "HookConfig::is_default_path"
+    )]
+    #[schemars(with = "String", example = "HookConfig::example_path")]
+    pub path: Utf8PathBuf,
+
+    /// A URL to `POST` each upload to, as JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "HookConfig::example_webhook_url")]
+    pub webhook_url: Option<Url>,
+
+    /// A command to run for each upload, as the program followed by its
+    /// arguments. The upload is passed in the `SCHLEP_TRANSFER_ID`,
+    /// `SCHLEP_USERNAME`, `SCHLEP_PATH`, `SCHLEP_SIZE` and `SCHLEP_CHECKSUM`
+    /// environment variables, and the command has to exit successfully for the
+    /// hook to succeed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(example = "HookConfig::example_command")]
+    pub command: Vec<String>,
+
+    /// How long the webhook and the command each have to finish before the
+    /// hook is counted as failed. Commands that run over are killed. The
+    /// default value is 30 seconds.
+    #[serde(
+        default = "HookConfig::default_timeout",
+        skip_serializing_if = "HookConfig::is_default_timeout",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub timeout: Duration,
+}
+
+impl HookConfig {
+    fn example_path() -> &'static str {
+        "/shared/inbound"
+    }
+
+    fn example_webhook_url() -> &'static str {
+        "https://etl.example.com/hooks/upload"
+    }
+
+    fn example_command() -> Vec<&'static str> {
+        vec!["/usr/local/bin/ingest", "--quiet"]
+    }
+
+    fn default_path() -> Utf8PathBuf {
+        Utf8PathBuf::from("/")
+    }
+
+    fn is_default_path(path: &Utf8PathBuf) -> bool {
+        *path == Self::default_path()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn is_default_timeout(timeout: &Duration) -> bool {
+        *timeout == Self::default_timeout()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("upload hook {0} has neither a webhook_url nor a command")]
+    NothingToRun(String),
+    #[error("HTTP error")]
+    HttpError(#[from] reqwest::Error),
+    #[error("failed to run command")]
+    IoError(#[from] io::Error),
+    #[error("command exited with {0}")]
+    CommandFailed(std::process::ExitStatus),
+    #[error("hook timed out")]
+    TimedOut,
+}
+
+impl Error {
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::NothingToRun(_) => ErrorCode::BadRequest,
+            Self::HttpError(_) => ErrorCode::DestinationUnavailable,
+            Self::IoError(_) => ErrorCode::Io,
+            Self::CommandFailed(_) => ErrorCode::Failure,
+            Self::TimedOut => ErrorCode::Timeout,
+        }
+    }
+}
+
+/// What a hook is told about an upload, as JSON for webhooks.
+#[derive(Clone, Debug, Serialize)]
+struct Upload {
+    id: String,
+    username: String,
+    /// The path of the file in the virtual hierarchy.
+    path: Utf8PathBuf,
+    size: u64,
+    /// The hex-encoded SHA-256 of the file, if it could be worked out.
+    checksum: Option<String>,
+}
+
+/// Runs the configured hooks whenever an upload finishes.
+pub struct UploadHooks {
+    hooks: Vec<HookConfig>,
+    client: reqwest::Client,
+    vfs_set: LiveVfsSet,
+    transfers: Transfers,
+    checksums: ChecksumCache,
+}
+
+impl UploadHooks {
+    /// Checks the hooks in `config`. Uploads are watched for in `transfers`,
+    /// and files that weren't hashed as they were uploaded are hashed through
+    /// `vfs_set` and `checksums`.
+    pub fn new(
+        config: Config,
+        vfs_set: LiveVfsSet,
+        transfers: Transfers,
+        checksums: ChecksumCache,
+    ) -> Result<Self, Error> {
+        if let Some(hook) = config
+            .hooks
+            .iter()
+            .find(|hook| hook.webhook_url.is_none() && hook.command.is_empty())
+        {
+            return Err(Error::NothingToRun(hook.name.clone()));
+        }
+
+        Ok(Self {
+            hooks: config.hooks,
+            client: reqwest::Client::builder()
+                .user_agent(format!("{}/{}", VERSION_INFO.name, VERSION_INFO.version))
+                .build()?,
+            vfs_set,
+            transfers,
+            checksums,
+        })
+    }
+
+    /// Starts watching for uploads, if there are any hooks to run.
+    pub fn spawn(self) {
+        if !self.hooks.is_empty() {
+            tokio::spawn(self.run());
+        }
+    }
+
+    async fn run(self) {
+        let mut finished = self.transfers.subscribe();
+
+        loop {
+            let record = match finished.recv().await {
+                Ok(record) => record,
+                Err(RecvError::Lagged(skipped)) => {
+                    event!(Level::WARN, skipped, "upload hooks missed uploads");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if record.direction != Direction::Upload
+                || record.status != transfers::Status::Completed
+            {
+                continue;
+            }
+
+            let hooks = self
+                .hooks
+                .iter()
+                .filter(|hook| record.path.starts_with(&hook.path))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if hooks.is_empty() {
+                continue;
+            }
+
+            let client = self.client.clone();
+            let vfs_set = self.vfs_set.clone();
+            let checksums = self.checksums.clone();
+
+            tokio::spawn(async move {
+                let upload = Upload::from_record(record, &vfs_set, &checksums).await;
+
+                for hook in &hooks {
+                    run_hook(hook, &client, &upload).await;
+                }
+            });
+        }
+    }
+}
+
+impl Upload {
+    /// The upload that `record` is about, hashing the file if the transfer
+    /// didn't.
+    async fn from_record(
+        record: TransferRecord,
+        vfs_set: &LiveVfsSet,
+        checksums: &ChecksumCache,
+    ) -> Self {
+        let checksum = match record.checksum {
+            Some(checksum) => Some(checksum),
+            None => hash(vfs_set, checksums, &record.path).await,
+        };
+
+        Self {
+            id: record.id,
+            username: record.username,
+            path: record.path,
+            size: record.size,
+            checksum,
+        }
+    }
+}
+
+/// The SHA-256 of the file at the absolute virtual path `path`, or `None` if
+/// it can't be read.
+async fn hash(vfs_set: &LiveVfsSet, checksums: &ChecksumCache, path: &Utf8Path) -> Option<String> {
+    let PathMatch { vfs, relative_path } = vfs_set.resolve_path(path)?;
+
+    match checksums
+        .checksum(HashAlgorithm::Sha256, &vfs, &relative_path)
+        .await
+    {
+        Ok(checksum) => Some(checksum),
+        Err(err) => {
+            event!(
+                Level::WARN,
+                %path,
+                err = %err.as_report(),
+                "failed to hash upload for upload hooks"
+            );
+            None
+        }
+    }
+}
+
+/// Runs `hook` for `upload`, recording it in the audit log if it fails.
+async fn run_hook(hook: &HookConfig, client: &reqwest::Client, upload: &Upload) {
+    let result = async {
+        if let Some(webhook_url) = &hook.webhook_url {
+            tokio::time::timeout(hook.timeout, send_webhook(client, webhook_url, upload))
+                .await
+                .map_err(|_| Error::TimedOut)??;
+        }
+
+        if !hook.command.is_empty() {
+            tokio::time::timeout(hook.timeout, run_command(&hook.command, upload))
+                .await
+                .map_err(|_| Error::TimedOut)??;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        counter!(Metrics::UPLOAD_HOOK_FAILURES, "hook" => hook.name.clone()).increment(1);
+        event!(
+            target: audit::TARGET,
+            Level::WARN,
+            hook = hook.name,
+            path = %upload.path,
+            err = %err.as_report(),
+            code = %err.code(),
+            "upload hook failed"
+        );
+    }
+}
+
+async fn send_webhook(client: &reqwest::Client, url: &Url, upload: &Upload) -> Result<(), Error> {
+    client
+        .post(url.clone())
+        .json(upload)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)?;
+
+    Ok(())
+}
+
+/// Runs `command` with `upload` in its environment. The command is killed if
+/// it's dropped before it exits, which is how it's stopped when it times out.
+async fn run_command(command: &[String], upload: &Upload) -> Result<(), Error> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    let status = Command::new(program)
+        .args(args)
+        .env("SCHLEP_TRANSFER_ID", &upload.id)
+        .env("SCHLEP_USERNAME", &upload.username)
+        .env("SCHLEP_PATH", &upload.path)
+        .env("SCHLEP_SIZE", upload.size.to_string())
+        .env(
+            "SCHLEP_CHECKSUM",
+            upload.checksum.as_deref().unwrap_or_default(),
+        )
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::CommandFailed(status))
+    }
+}
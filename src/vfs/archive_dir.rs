@@ -0,0 +1,752 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use flate2::read::DeflateDecoder;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use zip::{CompressionMethod, ZipArchive};
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    blocking,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// How much of a file is read at a time while hashing it.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How much of a file one blocking task hashes before handing the rest on to
+/// the next.
+const HASH_SLICE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// A read-only backend that serves the files in a `.zip` or `.tar.zst`
+/// archive, so that bundles can be shared without unpacking them.
+///
+/// The archive is indexed once, when it's mounted, so listing directories
+/// and getting metadata never touch it. Files are decompressed as they're
+/// read. Reading a file in order is cheap, but every jump backwards starts
+/// decompressing again from the beginning of the file, and in a `.tar.zst`,
+/// from the beginning of the archive.
+pub struct ArchiveDir {
+    vfs_path: Utf8PathBuf,
+    archive_path: Arc<Utf8PathBuf>,
+    entries: HashMap<Utf8PathBuf, Entry>,
+    children: HashMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    open_files: Mutex<HashMap<String, Arc<Mutex<OpenEntry>>>>,
+    open_dirs: Mutex<HashMap<String, Utf8PathBuf>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    TarZstd,
+}
+
+impl ArchiveKind {
+    fn of(path: &Utf8Path) -> Option<Self> {
+        let name = path.file_name()?.to_ascii_lowercase();
+        let extension = path.extension()?;
+
+        if extension.eq_ignore_ascii_case("zip") {
+            Some(Self::Zip)
+        } else if extension.eq_ignore_ascii_case("tzst") || name.ends_with(".tar.zst") {
+            Some(Self::TarZstd)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    metadata: Metadata,
+    data: Option<EntryData>,
+}
+
+/// Where the contents of a file are in the archive.
+#[derive(Clone, Copy)]
+enum EntryData {
+    Zip {
+        start: u64,
+        compressed_size: u64,
+        compression: ZipCompression,
+    },
+    /// `start` is an offset into the decompressed archive.
+    TarZstd { start: u64 },
+}
+
+#[derive(Clone, Copy)]
+enum ZipCompression {
+    Stored,
+    Deflated,
+    Zstd,
+}
+
+struct OpenEntry {
+    entry: Entry,
+    /// A reader for the file's contents, and how far into them it is.
+    reader: Option<(Box<dyn Read + Send>, u64)>,
+}
+
+impl ArchiveDir {
+    pub fn new(vfs_path: Utf8PathBuf, archive_path: Utf8PathBuf) -> Result<Self, Error> {
+        let Some(kind) = ArchiveKind::of(&archive_path) else {
+            return Err(Error::InvalidPath(archive_path.into()));
+        };
+
+        let file = File::open(&archive_path).into_io_error("failed to open archive")?;
+        let archive_mtime = file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let files = match kind {
+            ArchiveKind::Zip => index_zip(file)?,
+            ArchiveKind::TarZstd => index_tar_zstd(file)?,
+        };
+
+        let directory = Entry {
+            metadata: Metadata {
+                mtime: archive_mtime,
+                is_directory: true,
+                ..Metadata::default()
+            },
+            data: None,
+        };
+
+        let mut entries = HashMap::default();
+        let mut children = HashMap::<Utf8PathBuf, Vec<Utf8PathBuf>>::default();
+        entries.insert(Utf8PathBuf::new(), directory);
+
+        for (path, entry) in files {
+            // Archives don't have to list every directory, so the ones that
+            // files are in are added as they're found.
+            for ancestor in path.ancestors().skip(1) {
+                if entries.contains_key(ancestor) {
+                    break;
+                }
+
+                entries.insert(ancestor.to_owned(), directory);
+            }
+
+            entries.insert(path, entry);
+        }
+
+        for path in entries.keys() {
+            if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                children
+                    .entry(parent.to_owned())
+                    .or_default()
+                    .push(Utf8PathBuf::from(name));
+            }
+        }
+
+        Ok(Self {
+            vfs_path,
+            archive_path: Arc::new(archive_path),
+            entries,
+            children,
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        })
+    }
+
+    fn entry(&self, path: &Utf8Path) -> Result<Entry, Error> {
+        self.entries
+            .get(&normalize(path)?)
+            .copied()
+            .ok_or(Error::FileNotFound)
+    }
+
+    fn get_file(&self, handle: &Handle) -> Result<Arc<Mutex<OpenEntry>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    /// Hashes the range a slice at a time, each on its own blocking task, so
+    /// that a huge file doesn't hold a thread until it's done.
+    async fn hash_slices(
+        &self,
+        path: &Utf8Path,
+        offset: u64,
+        mut hasher: BlockHasher,
+    ) -> Result<BlockHasher, Error> {
+        let entry = self.entry(path)?;
+        let Some(data) = entry.data else {
+            return Err(Error::NotAFile);
+        };
+        let archive_path = self.archive_path.clone();
+        let size = entry.metadata.size.unwrap_or(0);
+
+        let mut reader = blocking::run(move |_| {
+            let mut reader = open_reader(&archive_path, data, size)?;
+            io::copy(&mut (&mut reader).take(offset), &mut io::sink())
+                .into_io_error("failed to hash file")?;
+
+            Ok(reader)
+        })
+        .await?;
+
+        loop {
+            let done;
+            (reader, hasher, done) = blocking::run(move |cancel| {
+                let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+                let mut sliced = 0;
+
+                while sliced < HASH_SLICE_SIZE {
+                    if cancel.is_cancelled() {
+                        return Err(Error::Cancelled);
+                    }
+
+                    let wanted = hasher.wanted(buf.len());
+                    if wanted == 0 {
+                        return Ok((reader, hasher, true));
+                    }
+
+                    let bytes_read = reader
+                        .read(&mut buf[..wanted])
+                        .into_io_error("failed to hash file")?;
+                    if bytes_read == 0 {
+                        return Ok((reader, hasher, true));
+                    }
+
+                    hasher.update(&buf[..bytes_read]);
+                    sliced += bytes_read as u64;
+                }
+
+                Ok((reader, hasher, false))
+            })
+            .await?;
+
+            if done {
+                return Ok(hasher);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Vfs for ArchiveDir {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if flags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        ) {
+            return Err(Error::ReadOnly);
+        }
+
+        let entry = self.entry(path)?;
+        if entry.data.is_none() {
+            return Err(Error::NotAFile);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_files.lock().insert(
+            vfs_handle.clone(),
+            Arc::new(Mutex::new(OpenEntry {
+                entry,
+                reader: None,
+            })),
+        );
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let path = normalize(path)?;
+        if !self.entry(&path)?.metadata.is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs.lock().insert(vfs_handle.clone(), path);
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().remove(handle.vfs_handle()).map(drop),
+            HandleType::Dir => self.open_dirs.lock().remove(handle.vfs_handle()).map(drop),
+        };
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::STATVFS
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = self.get_file(handle)?;
+        let archive_path = self.archive_path.clone();
+
+        blocking::run(move |_| {
+            let mut file = file.lock();
+            let entry = file.entry;
+            let size = entry.metadata.size.unwrap_or(0);
+            let Some(data) = entry.data else {
+                return Err(Error::NotAFile);
+            };
+
+            if offset >= size {
+                return Ok(None);
+            }
+
+            // Readers can only go forwards, so going backwards means starting
+            // over.
+            if file
+                .reader
+                .as_ref()
+                .is_none_or(|(_, position)| *position > offset)
+            {
+                file.reader = Some((open_reader(&archive_path, data, size)?, 0));
+            }
+            let (reader, position) = file.reader.as_mut().unwrap();
+
+            io::copy(
+                &mut reader.by_ref().take(offset - *position),
+                &mut io::sink(),
+            )
+            .into_io_error("failed to read archive")?;
+            *position = offset;
+
+            let mut buf = Vec::with_capacity(len);
+            reader
+                .by_ref()
+                .take(len as u64)
+                .read_to_end(&mut buf)
+                .into_io_error("failed to read archive")?;
+            *position += buf.len() as u64;
+
+            Ok((!buf.is_empty() || len == 0).then_some(buf))
+        })
+        .await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self
+            .open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)?;
+
+        Ok(self
+            .children
+            .get(&path)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| {
+                let entry = self.entries.get(&path.join(name))?;
+                Some((name.clone(), entry.metadata))
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, _handle: &Handle, _offset: u64, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        match handle.handle_type() {
+            HandleType::File => Ok(self.get_file(handle)?.lock().entry.metadata),
+            HandleType::Dir => {
+                let path = self
+                    .open_dirs
+                    .lock()
+                    .get(handle.vfs_handle())
+                    .cloned()
+                    .ok_or(Error::FileNotFound)?;
+
+                self.stat(&path).await
+            }
+        }
+    }
+
+    async fn sync_fd(&self, _handle: &Handle) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn rename(&self, _from: &Utf8Path, _to: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        Ok(self.entry(path)?.metadata)
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // Links in archives aren't served, so nothing needs to be followed.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, _path: &Utf8Path) -> Result<FsMetadata, Error> {
+        let size = self
+            .entries
+            .values()
+            .filter_map(|entry| entry.metadata.size)
+            .sum();
+
+        Ok(FsMetadata {
+            block_size: 1,
+            num_blocks: size,
+            free_blocks: 0,
+            num_files: self.entries.len() as u64,
+            free_files: 0,
+            read_only: true,
+            max_length: 255,
+        })
+    }
+
+    async fn statvfs_fd(&self, _handle: &Handle) -> Result<FsMetadata, Error> {
+        self.statvfs(Utf8Path::new("")).await
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let hasher = BlockHasher::new(algorithm, len, block_size);
+
+        Ok(self.hash_slices(path, offset, hasher).await?.finish())
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn remove_file(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn remove_dir(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_times(
+        &self,
+        _path: &Utf8Path,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_times_fd(
+        &self,
+        _handle: &Handle,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn truncate(&self, _path: &Utf8Path, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn truncate_fd(&self, _handle: &Handle, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        let archive_path = self.archive_path.clone();
+
+        blocking::run(move |_| {
+            std::fs::metadata(archive_path.as_path())
+                .map(drop)
+                .into_io_error("failed to find archive")
+        })
+        .await
+    }
+}
+
+/// `path` as it's keyed in the index, which is relative and has no `.`s.
+fn normalize(path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+    let mut normalized = Utf8PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(name) => normalized.push(name),
+            Utf8Component::CurDir | Utf8Component::RootDir => (),
+            _ => return Err(Error::InvalidPath(path.into())),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Lists the files and directories in a zip archive.
+fn index_zip(file: File) -> Result<Vec<(Utf8PathBuf, Entry)>, Error> {
+    let mut archive = ZipArchive::new(BufReader::new(file))
+        .map_err(io::Error::other)
+        .into_io_error("failed to read zip archive")?;
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for index in 0..archive.len() {
+        let file = archive
+            .by_index_raw(index)
+            .map_err(io::Error::other)
+            .into_io_error("failed to read zip archive")?;
+        // Names that would escape the archive are left out.
+        let Some(path) = file
+            .enclosed_name()
+            .and_then(|path| Utf8PathBuf::from_path_buf(path).ok())
+            .and_then(|path| normalize(&path).ok())
+        else {
+            continue;
+        };
+        if path.as_str().is_empty() {
+            continue;
+        }
+
+        let mtime = file.last_modified().and_then(|time| {
+            let date = chrono::NaiveDate::from_ymd_opt(
+                i32::from(time.year()),
+                u32::from(time.month()),
+                u32::from(time.day()),
+            )?;
+            let time = date.and_hms_opt(
+                u32::from(time.hour()),
+                u32::from(time.minute()),
+                u32::from(time.second()),
+            )?;
+
+            u64::try_from(time.and_utc().timestamp())
+                .ok()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        });
+
+        let data = if file.is_dir() {
+            None
+        } else {
+            let compression = match file.compression() {
+                CompressionMethod::Stored => ZipCompression::Stored,
+                CompressionMethod::Deflated => ZipCompression::Deflated,
+                CompressionMethod::Zstd => ZipCompression::Zstd,
+                // Files that can't be decompressed are left out.
+                _ => continue,
+            };
+
+            Some(EntryData::Zip {
+                start: file.data_start(),
+                compressed_size: file.compressed_size(),
+                compression,
+            })
+        };
+
+        entries.push((
+            path,
+            Entry {
+                metadata: Metadata {
+                    size: data.map(|_| file.size()),
+                    mtime,
+                    is_directory: data.is_none(),
+                    ..Metadata::default()
+                },
+                data,
+            },
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// Lists the files and directories in a zstd-compressed tarball, which means
+/// decompressing all of it.
+fn index_tar_zstd(file: File) -> Result<Vec<(Utf8PathBuf, Entry)>, Error> {
+    let decoder = zstd::Decoder::new(file).into_io_error("failed to read tar archive")?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut entries = Vec::new();
+
+    for entry in archive
+        .entries()
+        .into_io_error("failed to read tar archive")?
+    {
+        let entry = entry.into_io_error("failed to read tar archive")?;
+        let header = entry.header();
+        let is_directory = header.entry_type().is_dir();
+        // Links and special files are left out.
+        if !is_directory && !header.entry_type().is_file() {
+            continue;
+        }
+
+        let Some(path) = entry
+            .path()
+            .ok()
+            .and_then(|path| Utf8PathBuf::from_path_buf(path.into_owned()).ok())
+            .and_then(|path| normalize(&path).ok())
+        else {
+            continue;
+        };
+        if path.as_str().is_empty() {
+            continue;
+        }
+
+        let mtime = header
+            .mtime()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+
+        entries.push((
+            path,
+            Entry {
+                metadata: Metadata {
+                    size: (!is_directory).then(|| entry.size()),
+                    mtime,
+                    is_directory,
+                    ..Metadata::default()
+                },
+                data: (!is_directory).then(|| EntryData::TarZstd {
+                    start: entry.raw_file_position(),
+                }),
+            },
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// A reader for the `size` bytes of a file's contents, from the start.
+fn open_reader(
+    archive_path: &Utf8Path,
+    data: EntryData,
+    size: u64,
+) -> Result<Box<dyn Read + Send>, Error> {
+    let mut file = File::open(archive_path).into_io_error("failed to open archive")?;
+
+    let reader: Box<dyn Read + Send> = match data {
+        EntryData::Zip {
+            start,
+            compressed_size,
+            compression,
+        } => {
+            file.seek(SeekFrom::Start(start))
+                .into_io_error("failed to read archive")?;
+            let compressed = BufReader::new(file.take(compressed_size));
+
+            match compression {
+                ZipCompression::Stored => Box::new(compressed),
+                ZipCompression::Deflated => Box::new(DeflateDecoder::new(compressed)),
+                ZipCompression::Zstd => Box::new(
+                    zstd::Decoder::with_buffer(compressed)
+                        .into_io_error("failed to read archive")?,
+                ),
+            }
+        }
+        EntryData::TarZstd { start } => {
+            let mut decoder = zstd::Decoder::new(file).into_io_error("failed to read archive")?;
+            io::copy(&mut (&mut decoder).take(start), &mut io::sink())
+                .into_io_error("failed to read archive")?;
+
+            Box::new(decoder)
+        }
+    };
+
+    Ok(Box::new(reader.take(size)))
+}
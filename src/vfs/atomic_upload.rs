@@ -0,0 +1,104 @@
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use thiserror_ext::AsReport;
+use tracing::{Level, event};
+
+use super::{Error, Handle, VfsInstance};
+
+/// Enforces
+/// [`MountOptions::atomic_uploads`](super::MountOptions::atomic_uploads) for a
+/// mount.
+///
+/// Each upload is written under a hidden name in the directory it's going
+/// to, so that renaming it into place can't cross filesystems.
+#[derive(Default)]
+pub(super) struct AtomicUploads {
+    uploads: Mutex<HashMap<Handle, Upload>>,
+}
+
+/// An upload that's being written under a hidden name.
+pub(super) struct Upload {
+    temp_path: Utf8PathBuf,
+    path: Utf8PathBuf,
+}
+
+impl AtomicUploads {
+    /// A hidden name in the same directory to write an upload to `path`
+    /// under.
+    pub(super) fn temp_path(path: &Utf8Path) -> Utf8PathBuf {
+        path.with_file_name(format!(
+            ".{}.{:016x}.upload",
+            path.file_name().unwrap_or_default(),
+            rand::random::<u64>()
+        ))
+    }
+
+    /// Whether `name` is one that [`AtomicUploads::temp_path`] makes, which
+    /// are left out of directory listings.
+    pub(super) fn is_temp_name(name: &str) -> bool {
+        let Some(name) = name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(".upload"))
+        else {
+            return false;
+        };
+
+        name.rsplit_once('.').is_some_and(|(file_name, suffix)| {
+            !file_name.is_empty()
+                && suffix.len() == 16
+                && suffix.bytes().all(|byte| byte.is_ascii_hexdigit())
+        })
+    }
+
+    /// Remembers that `handle` is writing the upload to `path` under
+    /// `temp_path`.
+    pub(super) fn started(&self, handle: &Handle, temp_path: Utf8PathBuf, path: &Utf8Path) {
+        self.uploads.lock().insert(
+            handle.clone(),
+            Upload {
+                temp_path,
+                path: path.to_owned(),
+            },
+        );
+    }
+
+    /// The upload `handle` was writing, if it was writing one.
+    pub(super) fn take(&self, handle: &Handle) -> Option<Upload> {
+        self.uploads.lock().remove(handle)
+    }
+}
+
+impl Upload {
+    /// Where the upload is being written.
+    pub(super) fn temp_path(&self) -> &Utf8Path {
+        &self.temp_path
+    }
+
+    /// Renames the upload into place, replacing whatever was there. If that
+    /// fails, the upload is removed, so that it doesn't linger out of sight.
+    pub(super) async fn commit(self, vfs: &VfsInstance) -> Result<(), Error> {
+        match vfs.rename(&self.temp_path, &self.path).await {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.discard(vfs).await;
+
+                Err(err)
+            }
+        }
+    }
+
+    /// Removes the upload without putting it in place, unless it's already
+    /// gone, like when it was quarantined.
+    pub(super) async fn discard(self, vfs: &VfsInstance) {
+        match vfs.remove_file(&self.temp_path).await {
+            Err(err) if !err.is_not_found() => event!(
+                Level::WARN,
+                err = %err.as_report(),
+                path = %self.temp_path,
+                "failed to remove abandoned upload"
+            ),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use std::{ops::Range, time::SystemTime};
+
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::sync::broadcast;
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LocalDirOptions,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    Vfs,
+    local_dir::LocalDir,
+};
+
+/// Exposes the directory that the audit log is written to, so that auditors
+/// can fetch it over SFTP. Everything that would change the log is refused.
+pub struct AuditLog {
+    log_dir: LocalDir,
+}
+
+impl AuditLog {
+    pub fn new(vfs_path: Utf8PathBuf, log_dir: Utf8PathBuf) -> Result<Self, Error> {
+        Ok(Self {
+            log_dir: LocalDir::new(vfs_path, log_dir, &LocalDirOptions::default())?,
+        })
+    }
+}
+
+#[async_trait]
+impl Vfs for AuditLog {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if flags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        ) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.log_dir.open(path, flags).await
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        self.log_dir.open_dir(path).await
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        self.log_dir.close(handle).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.log_dir.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.log_dir.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.log_dir.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.log_dir.read(handle, offset, len).await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.log_dir.read_dir(handle).await
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.log_dir.read_dir_next(handle, max).await
+    }
+
+    async fn write(&self, _handle: &Handle, _offset: u64, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.log_dir.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, _handle: &Handle) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn rename(&self, _from: &Utf8Path, _to: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.log_dir.stat(path).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.log_dir.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.log_dir.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.log_dir.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.log_dir
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.log_dir.readlink(path).await
+    }
+
+    async fn mkdir(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn remove_file(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn remove_dir(&self, _path: &Utf8Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_times(
+        &self,
+        _path: &Utf8Path,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_times_fd(
+        &self,
+        _handle: &Handle,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn truncate(&self, _path: &Utf8Path, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn truncate_fd(&self, _handle: &Handle, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.log_dir.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.log_dir.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.log_dir.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.log_dir.health_check().await
+    }
+}
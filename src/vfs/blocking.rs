@@ -0,0 +1,79 @@
+//! Most filesystem APIs are blocking, so backends hand them off to Tokio's
+//! blocking thread pool. [`run`] wraps [`tokio::task::spawn_blocking`] so that
+//! the work is cancelled when nobody is waiting for it anymore and a panic in
+//! the work turns into an [`Error`] instead of taking the session down with
+//! it.
+
+use std::any::Any;
+
+use metrics::gauge;
+use tokio_util::sync::CancellationToken;
+use tracing::{Level, event};
+
+use super::Error;
+use crate::metrics::Metrics;
+
+/// Runs `f` on the blocking thread pool and waits for its result.
+///
+/// If the returned future is dropped before `f` completes (for example,
+/// because the session went away or the operation timed out), the
+/// [`CancellationToken`] passed to `f` is cancelled. Long-running work should
+/// check it periodically and bail out with [`Error::Cancelled`]. Work that
+/// hasn't started yet when the token is cancelled is skipped entirely.
+pub(super) async fn run<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce(&CancellationToken) -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let token = CancellationToken::new();
+    let task_token = token.clone();
+    let _cancel_on_drop = token.drop_guard();
+
+    let result = tokio::task::spawn_blocking(move || {
+        if task_token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let _in_flight = InFlight::new();
+        f(&task_token)
+    })
+    .await;
+
+    match result {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => {
+            let message = panic_message(err.into_panic().as_ref());
+            event!(Level::ERROR, %message, "blocking VFS task panicked");
+
+            Err(Error::TaskPanicked(message))
+        }
+        Err(_) => Err(Error::Cancelled),
+    }
+}
+
+/// Counts a blocking task in [`Metrics::VFS_BLOCKING_TASKS`] for as long as it
+/// is alive, including when it unwinds.
+struct InFlight;
+
+impl InFlight {
+    fn new() -> Self {
+        gauge!(Metrics::VFS_BLOCKING_TASKS).increment(1);
+        Self
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        gauge!(Metrics::VFS_BLOCKING_TASKS).decrement(1);
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
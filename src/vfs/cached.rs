@@ -0,0 +1,657 @@
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use camino::{Utf8Path, Utf8PathBuf};
+use metrics::counter;
+use parking_lot::Mutex;
+use tokio::{sync::broadcast, time::Instant};
+
+use super::{
+    CacheOptions,
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    Vfs,
+    VfsInstance,
+    state_file::normalize,
+};
+use crate::metrics::Metrics;
+
+/// Roughly what an entry costs on top of the data in it, for keeping the cache
+/// under [`CacheOptions::max_size`].
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// Keeps recently read blocks of files, metadata, and directory listings from
+/// the backend it wraps in memory, for [`CacheOptions::ttl`].
+///
+/// Whatever is changed through the wrapper is dropped from the cache right
+/// away. Files that are open for writing are read from the backend directly,
+/// and are dropped from the cache when they are opened and when they are
+/// closed, so writes in between don't have to touch the cache.
+pub struct Cached {
+    inner: Box<VfsInstance>,
+    cache: Mutex<Cache>,
+    block_size: u64,
+    whole_files_below: Option<u64>,
+    open_files: Mutex<HashMap<Handle, OpenFile>>,
+    open_dirs: Mutex<HashMap<Handle, Utf8PathBuf>>,
+}
+
+struct OpenFile {
+    path: Utf8PathBuf,
+    writing: bool,
+    /// The size of the file when it was opened, if it's small enough to be
+    /// cached whole.
+    whole_size: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Stat(Utf8PathBuf),
+    Listing(Utf8PathBuf),
+    Block(Utf8PathBuf, u64),
+}
+
+impl Key {
+    fn path(&self) -> &Utf8Path {
+        match self {
+            Self::Stat(path) | Self::Listing(path) | Self::Block(path, _) => path,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Stat(_) => "stat",
+            Self::Listing(_) => "listing",
+            Self::Block(..) => "block",
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Stat(Metadata),
+    Listing(Arc<Vec<(Utf8PathBuf, Metadata)>>),
+    Block(Bytes),
+}
+
+impl Value {
+    fn size(&self) -> u64 {
+        match self {
+            Self::Stat(_) => 0,
+            Self::Listing(entries) => entries
+                .iter()
+                .map(|(name, _)| name.as_str().len() as u64 + ENTRY_OVERHEAD)
+                .sum(),
+            Self::Block(data) => data.len() as u64,
+        }
+    }
+}
+
+struct Entry {
+    value: Value,
+    size: u64,
+    cached_at: Instant,
+    /// When the entry was last used, as a key into [`Cache::lru`].
+    used: u64,
+}
+
+/// The cached entries, and the order they were last used in.
+struct Cache {
+    ttl: Duration,
+    max_size: u64,
+    size: u64,
+    clock: u64,
+    entries: HashMap<Key, Entry>,
+    lru: BTreeMap<u64, Key>,
+}
+
+impl Cache {
+    fn get(&mut self, key: &Key) -> Option<Value> {
+        let entry = self.entries.get_mut(key)?;
+
+        if entry.cached_at.elapsed() > self.ttl {
+            self.remove(key);
+            return None;
+        }
+
+        self.clock += 1;
+        self.lru.remove(&entry.used);
+        self.lru.insert(self.clock, key.clone());
+        entry.used = self.clock;
+
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        let size = value.size() + key.path().as_str().len() as u64 + ENTRY_OVERHEAD;
+        if size > self.max_size {
+            return;
+        }
+
+        self.remove(&key);
+        while self.size + size > self.max_size {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.size;
+            }
+        }
+
+        self.clock += 1;
+        self.size += size;
+        self.lru.insert(self.clock, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                size,
+                cached_at: Instant::now(),
+                used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Drops everything cached about `path` and anything below it, and the
+    /// listing of the directory it's in.
+    fn invalidate(&mut self, path: &Utf8Path) {
+        let stale = self
+            .entries
+            .keys()
+            .filter(|key| key.path().starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for key in stale {
+            self.remove(&key);
+        }
+
+        if let Some(parent) = path.parent() {
+            self.remove(&Key::Listing(parent.to_owned()));
+        }
+    }
+}
+
+impl Cached {
+    pub fn new(inner: VfsInstance, options: &CacheOptions) -> Self {
+        Self {
+            inner: Box::new(inner),
+            cache: Mutex::new(Cache {
+                ttl: options.ttl,
+                max_size: options.max_size,
+                size: 0,
+                clock: 0,
+                entries: HashMap::default(),
+                lru: BTreeMap::new(),
+            }),
+            block_size: u64::from(options.block_size.max(1)),
+            whole_files_below: options.whole_files_below,
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        }
+    }
+
+    /// Looks `key` up, and counts whether it was found in the metrics.
+    fn lookup(&self, key: &Key) -> Option<Value> {
+        let value = self.cache.lock().get(key);
+        let metric = if value.is_some() {
+            Metrics::VFS_CACHE_HITS
+        } else {
+            Metrics::VFS_CACHE_MISSES
+        };
+        counter!(metric, "mount" => self.vfs_root().to_string(), "kind" => key.kind()).increment(1);
+
+        value
+    }
+
+    fn invalidate(&self, path: &Utf8Path) {
+        self.cache.lock().invalidate(&normalize(path));
+    }
+
+    /// Block `index` of the file open at `handle`, from the cache if it's
+    /// there. Only the last block of a file is shorter than the block size.
+    async fn block(&self, handle: &Handle, file: &OpenFile, index: u64) -> Result<Bytes, Error> {
+        let key = Key::Block(file.path.clone(), index);
+        if let Some(Value::Block(data)) = self.lookup(&key) {
+            return Ok(data);
+        }
+
+        if let Some(size) = file.whole_size {
+            return self.read_whole(handle, file, size, index).await;
+        }
+
+        let data = self
+            .inner
+            .read(handle, index * self.block_size, block_len(self.block_size))
+            .await?
+            .map(Bytes::from)
+            .unwrap_or_default();
+        self.cache.lock().insert(key, Value::Block(data.clone()));
+
+        Ok(data)
+    }
+
+    /// Reads the file open at `handle` in one go and caches all of its blocks,
+    /// returning block `index`.
+    async fn read_whole(
+        &self,
+        handle: &Handle,
+        file: &OpenFile,
+        size: u64,
+        index: u64,
+    ) -> Result<Bytes, Error> {
+        let data = self
+            .inner
+            .read(handle, 0, block_len(size))
+            .await?
+            .map(Bytes::from)
+            .unwrap_or_default();
+
+        let mut cache = self.cache.lock();
+        let mut wanted = Bytes::new();
+        let mut start = 0;
+        let mut block = 0;
+
+        // Even an empty file has a first block, so that it's cached too.
+        loop {
+            let end = data.len().min(start + block_len(self.block_size));
+            let block_data = data.slice(start..end);
+            if block == index {
+                wanted = block_data.clone();
+            }
+            cache.insert(
+                Key::Block(file.path.clone(), block),
+                Value::Block(block_data),
+            );
+
+            if end == data.len() {
+                break;
+            }
+            start = end;
+            block += 1;
+        }
+
+        Ok(wanted)
+    }
+}
+
+#[async_trait]
+impl Vfs for Cached {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        );
+
+        let whole_size = match self.whole_files_below {
+            Some(limit) if !writing => self
+                .stat(path)
+                .await
+                .ok()
+                .and_then(|metadata| metadata.size())
+                .filter(|size| *size <= limit),
+            _ => None,
+        };
+
+        let handle = self.inner.open(path, flags).await?;
+        if writing {
+            self.invalidate(path);
+        }
+
+        self.open_files.lock().insert(
+            handle.clone(),
+            OpenFile {
+                path: normalize(path),
+                writing,
+                whole_size,
+            },
+        );
+
+        Ok(handle)
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let handle = self.inner.open_dir(path).await?;
+        self.open_dirs
+            .lock()
+            .insert(handle.clone(), normalize(path));
+
+        Ok(handle)
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        let file = self.open_files.lock().remove(&handle);
+        self.open_dirs.lock().remove(&handle);
+
+        let result = self.inner.close(handle).await;
+        if let Some(file) = file.filter(|file| file.writing) {
+            self.invalidate(&file.path);
+        }
+
+        result
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = match self.open_files.lock().get(handle) {
+            Some(file) if !file.writing => Some(OpenFile {
+                path: file.path.clone(),
+                writing: false,
+                whole_size: file.whole_size,
+            }),
+            _ => None,
+        };
+        let Some(file) = file else {
+            return self.inner.read(handle, offset, len).await;
+        };
+
+        let end = offset.saturating_add(len as u64);
+        let mut data = Vec::with_capacity(len);
+        let mut position = offset;
+
+        while position < end {
+            let index = position / self.block_size;
+            let block = self.block(handle, &file, index).await?;
+
+            let start = block_len(position - index * self.block_size);
+            if start >= block.len() {
+                break;
+            }
+
+            let taken = (block.len() - start).min(block_len(end - position));
+            data.extend_from_slice(&block[start..start + taken]);
+            position += taken as u64;
+
+            if (block.len() as u64) < self.block_size {
+                break;
+            }
+        }
+
+        Ok((!data.is_empty() || len == 0).then_some(data))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let Some(path) = self.open_dirs.lock().get(handle).cloned() else {
+            return self.inner.read_dir(handle).await;
+        };
+
+        let key = Key::Listing(path.clone());
+        if let Some(Value::Listing(entries)) = self.lookup(&key) {
+            return Ok(entries.as_ref().clone());
+        }
+
+        let entries = self.inner.read_dir(handle).await?;
+
+        let mut cache = self.cache.lock();
+        for (name, metadata) in &entries {
+            cache.insert(Key::Stat(path.join(name)), Value::Stat(*metadata));
+        }
+        cache.insert(key, Value::Listing(Arc::new(entries.clone())));
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let entries = self.inner.read_dir_next(handle, max).await?;
+        let Some(path) = self.open_dirs.lock().get(handle).cloned() else {
+            return Ok(entries);
+        };
+
+        let mut cache = self.cache.lock();
+        for (name, metadata) in &entries {
+            cache.insert(Key::Stat(path.join(name)), Value::Stat(*metadata));
+        }
+
+        Ok(entries)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.inner.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.rename(from, to).await;
+        self.invalidate(from);
+        self.invalidate(to);
+
+        result
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let key = Key::Stat(normalize(path));
+        if let Some(Value::Stat(metadata)) = self.lookup(&key) {
+            return Ok(metadata);
+        }
+
+        let metadata = self.inner.stat(path).await?;
+        self.cache.lock().insert(key, Value::Stat(metadata));
+
+        Ok(metadata)
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.hardlink(path, target).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.symlink(path, target).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.inner
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.mkdir(path).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.remove_file(path).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let result = self.inner.remove_dir(path).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let result = self.inner.set_times(path, atime, mtime).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let result = self.inner.set_times_fd(handle, atime, mtime).await;
+
+        let path = self
+            .open_files
+            .lock()
+            .get(handle)
+            .map(|file| file.path.clone());
+        if let Some(path) = path {
+            self.invalidate(&path);
+        }
+
+        result
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        let result = self.inner.set_permissions(path, mode).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        let result = self.inner.set_permissions_fd(handle, mode).await;
+
+        let path = self
+            .open_files
+            .lock()
+            .get(handle)
+            .map(|file| file.path.clone());
+        if let Some(path) = path {
+            self.invalidate(&path);
+        }
+
+        result
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        let result = self.inner.truncate(path, len).await;
+        self.invalidate(path);
+
+        result
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let result = self.inner.truncate_fd(handle, len).await;
+
+        let path = self
+            .open_files
+            .lock()
+            .get(handle)
+            .map(|file| file.path.clone());
+        if let Some(path) = path {
+            self.invalidate(&path);
+        }
+
+        result
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        self.inner
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        self.inner.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn block_len(len: u64) -> usize {
+    len.min(usize::MAX as u64) as usize
+}
@@ -0,0 +1,786 @@
+use std::{io, num::NonZeroUsize, ops::Range, sync::Arc, time::SystemTime};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use lru::LruCache;
+use parking_lot::Mutex;
+use thiserror_ext::AsReport;
+use tokio::sync::{Mutex as AsyncMutex, broadcast};
+use tracing::{Level, event};
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    CompressionOptions,
+    Error,
+    FsMetadata,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    Vfs,
+    VfsInstance,
+    blocking,
+    hash::BlockHasher,
+    sequential::SequentialBuffer,
+};
+use crate::vfs::error::IntoIoError;
+
+/// The magic number of a zstd skippable frame, which the seek table is kept in.
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+
+/// The magic number at the very end of a seekable zstd file.
+const SEEK_TABLE_MAGIC: u32 = 0x8F92_EAB1;
+
+/// The length of a skippable frame's header: its magic number and size.
+const SKIPPABLE_HEADER_LEN: usize = 8;
+
+/// The length of the seek table's footer: the number of frames, the
+/// descriptor, and the magic number.
+const SEEK_TABLE_FOOTER_LEN: usize = 9;
+
+/// Set in the seek table's descriptor when every entry has a checksum.
+const CHECKSUM_FLAG: u8 = 0x80;
+
+/// How much of a file is read at a time to hash it.
+const HASH_CHUNK_SIZE: usize = 256 * 1024;
+
+/// The skippable frame every file the wrapper writes starts with, which is
+/// how they're told apart from seekable zstd files that were put on the
+/// backend some other way. It's listed in the seek table as a frame that
+/// decompresses to nothing.
+const MARKER: &[u8; MARKER_LEN as usize] = b"\x50\x2A\x4D\x18\x08\x00\x00\x00schlep\x00\x01";
+
+const MARKER_LEN: u32 = 16;
+
+/// How many decompressed sizes are remembered at most.
+const CACHED_SIZES: usize = 10_000;
+
+/// Compresses file contents with zstd before they reach the backend it wraps,
+/// and decompresses them again on the way back, so that clients see plain
+/// files while the backend stores compressed ones.
+///
+/// Files are stored in the [seekable zstd format]: the content is split into
+/// frames that are compressed on their own, followed by a table of where each
+/// frame starts. A read only has to decompress the frames it covers, so random
+/// access stays cheap, and the standard `zstd` tool can still decompress the
+/// files directly.
+///
+/// Files can only be written from start to finish: opening an existing file
+/// for writing without truncating it, or writing to a part of a file that has
+/// already been compressed, fails with [`Error::UnsupportedMethod`]. Files on
+/// the backend that the wrapper didn't write, like ones from before
+/// compression was turned on, are read as they are, even if they happen to be
+/// seekable zstd files themselves.
+///
+/// The decompressed size of each file is remembered along with its
+/// [change token](Metadata::change_token), so that listing a directory only
+/// has to open the files in it that have changed since it was last listed.
+///
+/// [seekable zstd format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+pub struct Compressed {
+    inner: Box<VfsInstance>,
+    level: i32,
+    frame_size: usize,
+    files: Mutex<HashMap<Handle, Arc<AsyncMutex<OpenFile>>>>,
+    dirs: Mutex<HashMap<Handle, Utf8PathBuf>>,
+    /// The change token and decompressed size of files that have been looked
+    /// at, with no size for files the wrapper didn't write.
+    sizes: Mutex<LruCache<Utf8PathBuf, (String, Option<u64>)>>,
+}
+
+/// One compressed frame in a file.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    /// Where the frame's content starts in the decompressed file.
+    offset: u64,
+    len: u32,
+    /// Where the frame starts in the file on the backend.
+    compressed_offset: u64,
+    compressed_len: u32,
+}
+
+impl Frame {
+    fn end(&self) -> u64 {
+        self.offset + u64::from(self.len)
+    }
+}
+
+enum OpenFile {
+    /// A file that isn't in the seekable format, which is read as it is.
+    Plain,
+    Reading(Reader),
+    Writing(Writer),
+}
+
+struct Reader {
+    frames: Vec<Frame>,
+    /// The last frame that was decompressed, since reads usually come in runs
+    /// that are much smaller than a frame.
+    cached: Option<(usize, Arc<Vec<u8>>)>,
+}
+
+impl Reader {
+    fn len(&self) -> u64 {
+        self.frames.last().map_or(0, Frame::end)
+    }
+
+    /// The index of the frame that holds the byte at `offset`, if any does.
+    fn frame_at(&self, offset: u64) -> Option<usize> {
+        let index = self.frames.partition_point(|frame| frame.end() <= offset);
+
+        (index < self.frames.len()).then_some(index)
+    }
+}
+
+struct Writer {
+    frames: Vec<Frame>,
+    /// The part of the file that hasn't been compressed yet.
+    data: SequentialBuffer,
+    /// How much has been written to the backend.
+    compressed_len: u64,
+}
+
+impl Writer {
+    /// A writer for a file that [`MARKER`] has just been written to.
+    fn new() -> Self {
+        let marker = Frame {
+            offset: 0,
+            len: 0,
+            compressed_offset: 0,
+            compressed_len: MARKER_LEN,
+        };
+
+        Self {
+            frames: vec![marker],
+            data: SequentialBuffer::default(),
+            compressed_len: u64::from(MARKER_LEN),
+        }
+    }
+}
+
+impl Compressed {
+    pub fn new(inner: VfsInstance, options: &CompressionOptions) -> Result<Self, Error> {
+        if options.frame_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame_size must be at least 1",
+            ))
+            .into_io_error("invalid compression options");
+        }
+
+        Ok(Self {
+            inner: Box::new(inner),
+            level: options.level,
+            frame_size: options.frame_size as usize,
+            files: Mutex::default(),
+            dirs: Mutex::default(),
+            sizes: Mutex::new(LruCache::new(NonZeroUsize::new(CACHED_SIZES).unwrap())),
+        })
+    }
+
+    fn file(&self, handle: &Handle) -> Option<Arc<AsyncMutex<OpenFile>>> {
+        self.files.lock().get(handle).cloned()
+    }
+
+    /// Reads the seek table of the backend file open as `handle`, or returns
+    /// [`None`] if it isn't a seekable zstd file that the wrapper wrote.
+    async fn read_seek_table(&self, handle: &Handle) -> Result<Option<Vec<Frame>>, Error> {
+        let size = self.inner.stat_fd(handle).await?.size.unwrap_or_default();
+        let Some(footer_offset) = size.checked_sub(SEEK_TABLE_FOOTER_LEN as u64) else {
+            return Ok(None);
+        };
+
+        let footer = self
+            .inner
+            .read(handle, footer_offset, SEEK_TABLE_FOOTER_LEN)
+            .await?
+            .unwrap_or_default();
+        if footer.len() != SEEK_TABLE_FOOTER_LEN || le_u32(&footer[5..]) != SEEK_TABLE_MAGIC {
+            return Ok(None);
+        }
+
+        let frame_count = le_u32(&footer) as usize;
+        let entry_len = if footer[4] & CHECKSUM_FLAG != 0 {
+            12
+        } else {
+            8
+        };
+        let table_len = frame_count * entry_len + SEEK_TABLE_FOOTER_LEN;
+        let Some(table_offset) = size.checked_sub((SKIPPABLE_HEADER_LEN + table_len) as u64) else {
+            return Ok(None);
+        };
+
+        let table = self
+            .inner
+            .read(handle, table_offset, SKIPPABLE_HEADER_LEN + table_len)
+            .await?
+            .unwrap_or_default();
+        if table.len() != SKIPPABLE_HEADER_LEN + table_len
+            || le_u32(&table) != SKIPPABLE_MAGIC
+            || le_u32(&table[4..]) as usize != table_len
+        {
+            return Ok(None);
+        }
+
+        let entries = &table[SKIPPABLE_HEADER_LEN..table.len() - SEEK_TABLE_FOOTER_LEN];
+        let mut frames = Vec::with_capacity(frame_count);
+        let mut offset = 0;
+        let mut compressed_offset = 0;
+        for entry in entries.chunks_exact(entry_len) {
+            let frame = Frame {
+                offset,
+                len: le_u32(&entry[4..]),
+                compressed_offset,
+                compressed_len: le_u32(entry),
+            };
+            offset = frame.end();
+            compressed_offset += u64::from(frame.compressed_len);
+            frames.push(frame);
+        }
+
+        // The frames have to account for everything before the seek table.
+        if compressed_offset != table_offset {
+            return Ok(None);
+        }
+
+        let marker = frames
+            .first()
+            .filter(|frame| frame.len == 0 && frame.compressed_len == MARKER_LEN);
+        if marker.is_none() {
+            return Ok(None);
+        }
+
+        let start = self.inner.read(handle, 0, MARKER.len()).await?;
+        if start.as_deref() != Some(MARKER.as_slice()) {
+            return Ok(None);
+        }
+
+        Ok(Some(frames))
+    }
+
+    /// The decompressed size of the file at `path` on the backend.
+    async fn decompressed_size(&self, path: &Utf8Path) -> Result<Option<u64>, Error> {
+        let handle = self.inner.open(path, OpenFlags::READ).await?;
+        let frames = self.read_seek_table(&handle).await;
+        self.inner.close(handle).await?;
+
+        Ok(frames?.map(|frames| frames.last().map_or(0, Frame::end)))
+    }
+
+    /// Replaces the size in `metadata` of the file at `path` with its
+    /// decompressed size.
+    async fn fix_size(&self, path: &Utf8Path, metadata: &mut Metadata) {
+        if metadata.is_directory {
+            return;
+        }
+
+        let token = metadata.change_token();
+        let cached = self
+            .sizes
+            .lock()
+            .get(path)
+            .filter(|(cached, _)| *cached == token)
+            .map(|(_, size)| *size);
+        if let Some(size) = cached {
+            metadata.size = size.or(metadata.size);
+            return;
+        }
+
+        match self.decompressed_size(path).await {
+            Ok(size) => {
+                metadata.size = size.or(metadata.size);
+                self.sizes.lock().put(path.to_owned(), (token, size));
+            }
+            Err(err) => {
+                event!(
+                    Level::DEBUG,
+                    %path,
+                    err = %err.as_report(),
+                    "failed to read decompressed size"
+                );
+            }
+        }
+    }
+
+    /// The decompressed content of frame `index`.
+    async fn frame(
+        &self,
+        handle: &Handle,
+        reader: &mut Reader,
+        index: usize,
+    ) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some((_, content)) = reader
+            .cached
+            .as_ref()
+            .filter(|(cached, _)| *cached == index)
+        {
+            return Ok(content.clone());
+        }
+
+        let frame = reader.frames[index];
+        let compressed = self
+            .inner
+            .read(
+                handle,
+                frame.compressed_offset,
+                frame.compressed_len as usize,
+            )
+            .await?
+            .unwrap_or_default();
+        if compressed.len() != frame.compressed_len as usize {
+            return corrupt();
+        }
+
+        let content = blocking::run(move |_| {
+            zstd::bulk::decompress(&compressed, frame.len as usize)
+                .into_io_error("failed to decompress frame")
+        })
+        .await?;
+        if content.len() != frame.len as usize {
+            return corrupt();
+        }
+
+        let content = Arc::new(content);
+        reader.cached = Some((index, content.clone()));
+
+        Ok(content)
+    }
+
+    async fn read_frames(
+        &self,
+        handle: &Handle,
+        reader: &mut Reader,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if reader.frame_at(offset).is_none() {
+            return Ok(None);
+        }
+
+        let mut out = Vec::with_capacity(len);
+        let mut position = offset;
+
+        while out.len() < len {
+            let Some(index) = reader.frame_at(position) else {
+                break;
+            };
+
+            let start = reader.frames[index].offset;
+            let content = self.frame(handle, reader, index).await?;
+            let content = &content[usize::try_from(position - start).unwrap()..];
+            let content = &content[..content.len().min(len - out.len())];
+
+            out.extend_from_slice(content);
+            position += content.len() as u64;
+        }
+
+        Ok(Some(out))
+    }
+
+    /// Compresses and writes out every full frame in `writer`'s buffer, along
+    /// with whatever is left over if `all` is set.
+    async fn flush_frames(
+        &self,
+        handle: &Handle,
+        writer: &mut Writer,
+        all: bool,
+    ) -> Result<(), Error> {
+        while writer.data.buffered() >= self.frame_size || (all && writer.data.buffered() > 0) {
+            let offset = writer.data.flushed();
+            let content = writer.data.take(self.frame_size);
+            let len = content.len();
+            let level = self.level;
+
+            let compressed = blocking::run(move |_| {
+                zstd::bulk::compress(&content, level).into_io_error("failed to compress frame")
+            })
+            .await?;
+            self.inner
+                .write(handle, writer.compressed_len, &compressed)
+                .await?;
+
+            let frame = Frame {
+                offset,
+                len: u32::try_from(len).or_else(|_| corrupt())?,
+                compressed_offset: writer.compressed_len,
+                compressed_len: u32::try_from(compressed.len()).or_else(|_| corrupt())?,
+            };
+            writer.compressed_len += u64::from(frame.compressed_len);
+            writer.frames.push(frame);
+        }
+
+        Ok(())
+    }
+
+    /// Writes out the rest of a file that was open for writing, and its seek
+    /// table.
+    async fn finish(&self, handle: &Handle, writer: &mut Writer) -> Result<(), Error> {
+        writer.data.fill_holes();
+        self.flush_frames(handle, writer, true).await?;
+
+        let table = seek_table(&writer.frames);
+        self.inner
+            .write(handle, writer.compressed_len, &table)
+            .await
+    }
+
+    /// Hashes a range of what the file decompresses to, which has to be
+    /// read back through the wrapper.
+    async fn hash_decompressed(
+        &self,
+        path: &Utf8Path,
+        mut offset: u64,
+        mut hasher: BlockHasher,
+    ) -> Result<BlockHasher, Error> {
+        let handle = self.open(path, OpenFlags::READ).await?;
+
+        let result = loop {
+            let wanted = hasher.wanted(HASH_CHUNK_SIZE);
+            if wanted == 0 {
+                break Ok(());
+            }
+
+            match self.read(&handle, offset, wanted).await {
+                Ok(Some(chunk)) => {
+                    hasher.update(&chunk);
+                    offset += chunk.len() as u64;
+                }
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.close(handle).await?;
+        result?;
+
+        Ok(hasher)
+    }
+}
+
+#[async_trait]
+impl Vfs for Compressed {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            let replaces = flags.intersects(OpenFlags::TRUNCATE | OpenFlags::EXCLUDE);
+            let exists = match self.inner.stat(path).await {
+                Ok(_) => true,
+                Err(err) if err.is_not_found() => false,
+                Err(err) => return Err(err),
+            };
+
+            if flags.intersects(OpenFlags::READ | OpenFlags::APPEND) || (exists && !replaces) {
+                return Err(Error::UnsupportedMethod);
+            }
+
+            let handle = self.inner.open(path, flags | OpenFlags::TRUNCATE).await?;
+            if let Err(err) = self.inner.write(&handle, 0, MARKER).await {
+                self.inner.close(handle).await?;
+                return Err(err);
+            }
+
+            self.files.lock().insert(
+                handle.clone(),
+                Arc::new(AsyncMutex::new(OpenFile::Writing(Writer::new()))),
+            );
+
+            return Ok(handle);
+        }
+
+        let handle = self.inner.open(path, flags).await?;
+        let file = match self.read_seek_table(&handle).await {
+            Ok(Some(frames)) => OpenFile::Reading(Reader {
+                frames,
+                cached: None,
+            }),
+            Ok(None) => OpenFile::Plain,
+            Err(err) => {
+                self.inner.close(handle).await?;
+                return Err(err);
+            }
+        };
+        self.files
+            .lock()
+            .insert(handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(handle)
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let handle = self.inner.open_dir(path).await?;
+        self.dirs.lock().insert(handle.clone(), path.to_owned());
+
+        Ok(handle)
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        let result = match handle.handle_type() {
+            HandleType::File => {
+                let file = self.files.lock().remove(&handle);
+                match file {
+                    Some(file) => match &mut *file.lock().await {
+                        OpenFile::Writing(writer) => self.finish(&handle, writer).await,
+                        OpenFile::Plain | OpenFile::Reading(_) => Ok(()),
+                    },
+                    None => Ok(()),
+                }
+            }
+            HandleType::Dir => {
+                self.dirs.lock().remove(&handle);
+                Ok(())
+            }
+        };
+
+        self.inner.close(handle).await?;
+        result
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(file) = self.file(handle) else {
+            return Err(Error::FileNotFound);
+        };
+
+        match &mut *file.lock().await {
+            OpenFile::Plain => self.inner.read(handle, offset, len).await,
+            OpenFile::Reading(reader) => self.read_frames(handle, reader, offset, len).await,
+            OpenFile::Writing(_) => Err(Error::UnsupportedMethod),
+        }
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.inner.read_dir(handle).await?;
+        let Some(dir) = self.dirs.lock().get(handle).cloned() else {
+            return Ok(entries);
+        };
+
+        for (name, metadata) in &mut entries {
+            self.fix_size(&dir.join(&*name), metadata).await;
+        }
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.inner.read_dir_next(handle, max).await?;
+        let Some(dir) = self.dirs.lock().get(handle).cloned() else {
+            return Ok(entries);
+        };
+
+        for (name, metadata) in &mut entries {
+            self.fix_size(&dir.join(&*name), metadata).await;
+        }
+
+        Ok(entries)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let Some(file) = self.file(handle) else {
+            return Err(Error::FileNotFound);
+        };
+
+        match &mut *file.lock().await {
+            OpenFile::Writing(writer) => {
+                writer.data.insert(offset, data)?;
+                self.flush_frames(handle, writer, false).await
+            }
+            OpenFile::Plain | OpenFile::Reading(_) => Err(Error::UnsupportedMethod),
+        }
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        let mut metadata = self.inner.stat_fd(handle).await?;
+
+        if let Some(file) = self.file(handle) {
+            match &*file.lock().await {
+                OpenFile::Plain => (),
+                OpenFile::Reading(reader) => metadata.size = Some(reader.len()),
+                OpenFile::Writing(writer) => metadata.size = Some(writer.data.len()),
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let mut metadata = self.inner.stat(path).await?;
+        self.fix_size(path, &mut metadata).await;
+
+        Ok(metadata)
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner.hardlink(path, target).await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let hasher = BlockHasher::new(algorithm, len, block_size);
+
+        Ok(self.hash_decompressed(path, offset, hasher).await?.finish())
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.remove_file(path).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.remove_dir(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        // Emptying a file is the only change that doesn't need it decompressed.
+        if len != 0 {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        self.inner.truncate(path, 0).await
+    }
+
+    async fn truncate_fd(&self, _handle: &Handle, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+/// The seek table for `frames`, as a skippable frame.
+fn seek_table(frames: &[Frame]) -> Vec<u8> {
+    let table_len = frames.len() * 8 + SEEK_TABLE_FOOTER_LEN;
+    let mut table = Vec::with_capacity(SKIPPABLE_HEADER_LEN + table_len);
+
+    table.extend_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+    table.extend_from_slice(&u32::try_from(table_len).unwrap().to_le_bytes());
+    for frame in frames {
+        table.extend_from_slice(&frame.compressed_len.to_le_bytes());
+        table.extend_from_slice(&frame.len.to_le_bytes());
+    }
+    table.extend_from_slice(&u32::try_from(frames.len()).unwrap().to_le_bytes());
+    table.push(0);
+    table.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+
+    table
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn corrupt<T>() -> Result<T, Error> {
+    Err(io::Error::from(io::ErrorKind::InvalidData)).into_io_error("compressed file is corrupt")
+}
@@ -0,0 +1,1054 @@
+use std::{io, time::Duration};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use url::Url;
+
+use super::{Error, error::IntoIoError};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[repr(transparent)]
+#[serde(transparent)]
+pub struct Config {
+    pub(super) vfs_configs: Vec<VfsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "vfs_config")]
+pub struct VfsConfig {
+    /// The directory root to mount the VFS at within the virtual hierarchy.
+    /// A `{username}` in it, and in `local_dir`, is replaced with the name of
+    /// the user who logged in, which gives every user a mount of their own.
+    #[schemars(with = "String", example = "VfsConfig::example_vfs_root")]
+    pub(super) vfs_root: Utf8PathBuf,
+
+    /// The backend that provides the files for this mount.
+    #[serde(flatten)]
+    pub(super) backend: BackendConfig,
+
+    /// Tunables for the backend, which it checks when the mount is set up.
+    /// Local directories take `io_engine` and `symlinks`, WebDAV takes
+    /// `request_timeout`, WebHDFS takes `request_timeout` and
+    /// `append_size`, FTP takes `connect_timeout`, and PostgreSQL takes
+    /// `max_connections`. The other backends don't take any.
+    #[serde(default, skip_serializing_if = "BackendOptions::is_empty")]
+    #[schemars(with = "Map<String, Value>")]
+    pub(super) backend_options: BackendOptions,
+
+    /// Policies applied to every operation on this mount, independent of the
+    /// backend.
+    #[serde(flatten)]
+    pub(super) options: MountOptions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "backend_config", untagged)]
+pub enum BackendConfig {
+    LocalDir {
+        /// The local directory to expose at the given root.
+        #[schemars(with = "String", example = "BackendConfig::example_local_dir")]
+        local_dir: Utf8PathBuf,
+    },
+    WebDav {
+        /// The URL of the WebDAV collection to expose at the given root.
+        #[schemars(example = "BackendConfig::example_webdav_url")]
+        webdav_url: Url,
+
+        /// The username to log in to the WebDAV server with. Without one,
+        /// requests are sent without credentials.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        webdav_username: Option<String>,
+
+        /// A file containing the password to log in to the WebDAV server with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<String>")]
+        webdav_password_file: Option<Utf8PathBuf>,
+    },
+    SftpProxy {
+        /// The URL of the directory on another SFTP server to expose at the
+        /// given root, like `sftp://user@host:port/path`.
+        #[schemars(example = "BackendConfig::example_sftp_url")]
+        sftp_url: Url,
+
+        /// The private key to log in to the SFTP server with.
+        #[schemars(with = "String")]
+        sftp_private_key_file: Utf8PathBuf,
+
+        /// The host key the SFTP server has to present, in OpenSSH format.
+        #[schemars(example = "BackendConfig::example_sftp_host_key")]
+        sftp_host_key: String,
+    },
+    WebHdfs {
+        /// The URL of the HDFS directory to expose at the given root, on the
+        /// NameNode's HTTP server.
+        #[schemars(example = "BackendConfig::example_webhdfs_url")]
+        webhdfs_url: Url,
+
+        /// How to authenticate to HDFS.
+        webhdfs_auth: WebHdfsAuth,
+    },
+    Postgres {
+        /// The connection string of the PostgreSQL database to keep the files
+        /// exposed at the given root in, either as a URL or as `key=value`
+        /// pairs.
+        #[schemars(example = "BackendConfig::example_postgres_url")]
+        postgres_url: String,
+
+        /// A file containing the password to log in to the database with, if
+        /// it isn't in the connection string.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<String>")]
+        postgres_password_file: Option<Utf8PathBuf>,
+    },
+    Ftp {
+        /// The URL of the directory on an FTP server to expose at the given
+        /// root, like `ftp://user@host:port/path`. With `ftps://`, the
+        /// connection is upgraded to TLS with `AUTH TLS` before logging in.
+        #[schemars(example = "BackendConfig::example_ftp_url")]
+        ftp_url: Url,
+
+        /// A file containing the password to log in to the FTP server with.
+        /// Without one, Schlep logs in anonymously.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<String>")]
+        ftp_password_file: Option<Utf8PathBuf>,
+
+        /// How data connections to the FTP server are made.
+        #[serde(default)]
+        ftp_mode: FtpMode,
+
+        /// How many connections to keep open to the FTP server at most. Each
+        /// file that's being read holds one. The default value is 4.
+        #[serde(
+            default = "BackendConfig::default_ftp_max_connections",
+            skip_serializing_if = "BackendConfig::is_default_ftp_max_connections"
+        )]
+        ftp_max_connections: usize,
+    },
+    Archive {
+        /// The `.zip` or `.tar.zst` file whose contents to expose, read-only,
+        /// at the given root.
+        #[schemars(with = "String", example = "BackendConfig::example_archive_file")]
+        archive_file: Utf8PathBuf,
+    },
+}
+
+/// The `backend_options` of a mount, kept as they were written until the
+/// backend parses them into its own options.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct BackendOptions(Map<String, Value>);
+
+/// What backends without any tunables parse their `backend_options` into, so
+/// that any options they're given are refused.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct NoBackendOptions {}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocalDirOptions {
+    /// How files are read and written.
+    #[serde(default)]
+    pub io_engine: IoEngine,
+
+    /// What clients may do with symlinks.
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IoEngine {
+    /// On the blocking thread pool, like any other file I/O.
+    #[default]
+    Blocking,
+    /// Through `io_uring`, on a thread of its own, which saves handing every
+    /// read and write to the thread pool. Schlep has to be built with the
+    /// `io-uring` feature.
+    IoUring,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Symlinks can't be created or read.
+    Deny,
+    /// Symlinks are created relative to the directory they're in, and only
+    /// ones that lead somewhere inside the mount can be read.
+    #[default]
+    FollowWithinRoot,
+    /// Symlinks are created like with `follow_within_root`, but relative
+    /// links already on disk are read back as they are, wherever they lead.
+    /// Absolute links out of the mount still can't be read, since they'd give
+    /// away where it is on the host.
+    Preserve,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebDavOptions {
+    /// How long to wait for the WebDAV server to answer a request. The
+    /// default value is 60 seconds.
+    #[serde(
+        default = "WebDavOptions::default_request_timeout",
+        with = "humantime_serde"
+    )]
+    pub request_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebHdfsOptions {
+    /// How long to wait for the NameNode or a DataNode to answer a request.
+    /// The default value is 60 seconds.
+    #[serde(
+        default = "WebHdfsOptions::default_request_timeout",
+        with = "humantime_serde"
+    )]
+    pub request_timeout: Duration,
+
+    /// How much is written to a file before it's appended to HDFS, since
+    /// every append is a round trip to the NameNode and a DataNode. The
+    /// default value is 8 MiB.
+    #[serde(default = "WebHdfsOptions::default_append_size")]
+    pub append_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FtpOptions {
+    /// How long to wait for a connection to the FTP server, including logging
+    /// in. The default value is 30 seconds.
+    #[serde(
+        default = "FtpOptions::default_connect_timeout",
+        with = "humantime_serde"
+    )]
+    pub connect_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PostgresOptions {
+    /// How many connections to the database the mount keeps at most. The
+    /// default value is 16.
+    #[serde(default = "PostgresOptions::default_max_connections")]
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "ftp_mode", rename_all = "snake_case")]
+pub enum FtpMode {
+    /// The server listens for data connections, at the address it gives with
+    /// `PASV`.
+    #[default]
+    Passive,
+    /// The server listens for data connections, on the port it gives with
+    /// `EPSV`, which works through NAT and over IPv6.
+    ExtendedPassive,
+    /// Schlep listens for data connections from the server.
+    Active,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "webhdfs_auth", tag = "method", rename_all = "snake_case")]
+pub enum WebHdfsAuth {
+    /// No authentication, for clusters that trust whoever the requests say
+    /// they're from.
+    Simple {
+        /// The HDFS user to make requests as.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+    },
+    /// A delegation token, which has to be renewed before it expires.
+    DelegationToken {
+        /// A file containing the encoded delegation token.
+        #[schemars(with = "String")]
+        token_file: Utf8PathBuf,
+    },
+    /// Kerberos, negotiated with SPNEGO, using the credentials in the default
+    /// credential cache or client keytab. Schlep has to be built with the
+    /// `kerberos` feature.
+    Kerberos {
+        /// The principal of the NameNode's HTTP server. The default value is
+        /// `HTTP/` followed by the host in the URL.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_principal: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "mount_options")]
+pub struct MountOptions {
+    /// Whether clients can change anything in this mount. When it's `false`,
+    /// every attempt to create, write, rename, or remove a file or directory
+    /// fails, whatever the permissions on the backend allow.
+    #[serde(
+        default = "MountOptions::default_writable",
+        skip_serializing_if = "MountOptions::is_default_writable"
+    )]
+    pub writable: bool,
+
+    /// What to do when a file is opened for writing while another client
+    /// already has it open for writing.
+    #[serde(default)]
+    pub exclusive_writes: ExclusiveWrites,
+
+    /// How long to wait for the other writer to close the file when
+    /// `exclusive_writes` is `wait`. The default value is 30 seconds.
+    #[serde(
+        default = "MountOptions::default_exclusive_write_timeout",
+        skip_serializing_if = "MountOptions::is_default_exclusive_write_timeout",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub exclusive_write_timeout: Duration,
+
+    /// How many bytes a file in this mount may hold at most. Uploads that
+    /// announce a bigger size are refused up front, and writes that would
+    /// take a file past it fail. Without one, files can be as big as the
+    /// backend allows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+
+    /// What to do when a client creates a file that already exists without
+    /// asking for it to be truncated. Opening a file to append to it isn't a
+    /// collision.
+    #[serde(default)]
+    pub upload_collisions: UploadCollisions,
+
+    /// Writes uploads under a hidden name in the directory they're going to,
+    /// and only renames them into place once they're closed, so that nothing
+    /// watching the directory sees a file that's only partly there. Uploads
+    /// that are abandoned are removed instead. Files that are appended to, or
+    /// changed in place without being truncated, are still written directly.
+    #[serde(default)]
+    pub atomic_uploads: bool,
+
+    /// Sends every upload to a virus scanner once it's closed. Uploads that
+    /// are infected, or that couldn't be scanned, are moved into a quarantine
+    /// directory and their close fails. With `atomic_uploads`, uploads are
+    /// scanned before they're renamed into place, so that nothing infected is
+    /// ever visible; without it, they're scanned where they were written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub virus_scan: Option<VirusScanOptions>,
+
+    /// The users who can see this mount. A mount without `visible_to_users` or
+    /// `visible_to_group` can be seen by everyone, and one with both by the
+    /// users listed and the members of the group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub visible_to_users: Vec<String>,
+
+    /// The DN of the LDAP group whose members can see this mount. Without one,
+    /// or `visible_to_users`, every user can see it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(example = "MountOptions::example_visible_to_group")]
+    pub visible_to_group: Option<String>,
+
+    /// A file to keep the list of paths in this mount that are under legal
+    /// hold in. Held files can't be deleted, renamed, or written to by anyone
+    /// until the hold is released. Without one, legal holds can't be placed on
+    /// this mount. In a mount for each user, it needs a `{username}` in it, so
+    /// that every user's mount keeps a file of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub legal_hold_file: Option<Utf8PathBuf>,
+
+    /// Makes this mount write-once: files can't be changed once they have been
+    /// written and closed, and can't be deleted until their retention period
+    /// is over.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worm: Option<WormOptions>,
+
+    /// Encrypts the names of the files and directories in this mount before
+    /// they reach the backend, while clients keep seeing the real names. File
+    /// contents aren't encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename_encryption: Option<FilenameEncryptionOptions>,
+
+    /// Compresses the contents of the files in this mount with zstd before
+    /// they reach the backend, while clients keep seeing plain files. Files
+    /// can only be written from start to finish, not changed in place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionOptions>,
+
+    /// Stores the contents of the files in this mount as chunks named by
+    /// their hash, in a chunk directory that other mounts can share, so that
+    /// identical data is only stored once however many files and users it
+    /// turns up in. The backend only keeps a small manifest of each file's
+    /// chunks. Files can only be written from start to finish, not changed in
+    /// place.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deduplication: Option<DeduplicationOptions>,
+
+    /// Shows a read-only file at the root of this mount, like usage
+    /// instructions for the people who connect to it. The file isn't stored on
+    /// the backend, and hides any file there with the same name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readme: Option<ReadmeOptions>,
+
+    /// Moves files and directories that are removed from this mount into a
+    /// `.trash` directory at its root instead of deleting them, so that they
+    /// can be restored by renaming them back out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash: Option<TrashOptions>,
+
+    /// Keeps the previous contents of files in this mount when they are
+    /// overwritten or removed, in a read-only `.versions` directory at its
+    /// root that clients can restore them from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub versioning: Option<VersioningOptions>,
+
+    /// Watermarks for the free space left on this mount's backend, which is
+    /// looked at whenever a file is opened for writing. Without them, uploads
+    /// go ahead until the backend refuses them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub free_space: Option<FreeSpaceOptions>,
+
+    /// How many bytes per second may be read from and written to this mount,
+    /// by everyone together. Without one, transfers are only limited by
+    /// `session_bandwidth_limit` and `user_bandwidth_limit`, if those are set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bandwidth_limit: Option<u64>,
+
+    /// Keeps what was recently read from this mount's backend in memory, so
+    /// that browsing a slow backend doesn't have to wait for it every time.
+    /// Changes made through Schlep show up right away, but changes made to the
+    /// backend by anything else can take until the cached data expires.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheOptions>,
+
+    /// Patterns for the names that files and directories can be created with
+    /// in this mount, like `*.exe` to keep executables out, or `*.csv` to
+    /// take nothing but CSV files. Renames are held to them, too. Without
+    /// them, any name is allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename_filter: Option<FilenameFilterOptions>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "worm_options")]
+pub struct WormOptions {
+    /// How long a file can't be deleted for after it has been written.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String", example = "WormOptions::example_retention")]
+    pub retention: Duration,
+
+    /// A file to keep track of when each file in the mount was written in. In
+    /// a mount for each user, it needs a `{username}` in it, so that every
+    /// user's mount keeps a file of its own.
+    #[schemars(with = "String")]
+    pub state_file: Utf8PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "filename_encryption_options")]
+pub struct FilenameEncryptionOptions {
+    /// A file containing the 64-byte key to encrypt names with, base64-encoded,
+    /// like the output of `openssl rand -base64 64`. Files stored under one key
+    /// can't be found with any other.
+    #[schemars(with = "String")]
+    pub key_file: Utf8PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "compression_options")]
+pub struct CompressionOptions {
+    /// The zstd compression level, from 1 to 22. The default value is 3.
+    #[serde(
+        default = "CompressionOptions::default_level",
+        skip_serializing_if = "CompressionOptions::is_default_level"
+    )]
+    pub level: i32,
+
+    /// How many bytes of a file to compress together. Reads only have to
+    /// decompress the frames they cover, so smaller frames make random access
+    /// cheaper, while larger ones compress better. It can't be 0. The default
+    /// value is 1 MiB.
+    #[serde(
+        default = "CompressionOptions::default_frame_size",
+        skip_serializing_if = "CompressionOptions::is_default_frame_size"
+    )]
+    pub frame_size: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "deduplication_options")]
+pub struct DeduplicationOptions {
+    /// The local directory to keep chunks in. Mounts with the same chunk
+    /// directory share their chunks, which is what lets identical uploads to
+    /// different mounts be stored once. Each chunk is removed along with the
+    /// last file that uses it.
+    #[schemars(with = "String", example = "DeduplicationOptions::example_chunk_dir")]
+    pub chunk_dir: Utf8PathBuf,
+
+    /// How many bytes of a file go in each chunk. Smaller chunks find more
+    /// data in common between files that aren't quite identical, while larger
+    /// ones make for smaller manifests and fewer files in the chunk directory.
+    /// Mounts sharing a chunk directory only share chunks if they use the same
+    /// chunk size. The default value is 4 MiB.
+    #[serde(
+        default = "DeduplicationOptions::default_chunk_size",
+        skip_serializing_if = "DeduplicationOptions::is_default_chunk_size"
+    )]
+    pub chunk_size: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "readme_options")]
+pub struct ReadmeOptions {
+    /// The name of the file. The default value is `README.txt`.
+    #[serde(
+        default = "ReadmeOptions::default_name",
+        skip_serializing_if = "ReadmeOptions::is_default_name"
+    )]
+    pub name: String,
+
+    /// The contents of the file.
+    #[schemars(example = "ReadmeOptions::example_text")]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "trash_options")]
+pub struct TrashOptions {
+    /// How long removed entries are kept in the trash before they are deleted
+    /// for good.
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String", example = "TrashOptions::example_retention")]
+    pub retention: Duration,
+
+    /// How often to look for entries whose retention period is over. The
+    /// default value is 1 hour.
+    #[serde(
+        default = "TrashOptions::default_purge_interval",
+        skip_serializing_if = "TrashOptions::is_default_purge_interval",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub purge_interval: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "versioning_options")]
+pub struct VersioningOptions {
+    /// How many revisions of each file to keep. Once there are more, the
+    /// oldest are deleted. The default value is 10.
+    #[serde(
+        default = "VersioningOptions::default_revisions",
+        skip_serializing_if = "VersioningOptions::is_default_revisions"
+    )]
+    pub revisions: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "free_space_options")]
+pub struct FreeSpaceOptions {
+    /// Below how many bytes of free space a warning is logged, and the mount
+    /// is reported as low on space in the metrics.
+    pub warn_below: u64,
+
+    /// Below how many bytes of free space files can't be opened for writing
+    /// any more. Reading and removing files keeps working, and uploads that
+    /// are already in progress are left to finish.
+    pub reject_below: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "filename_filter_options")]
+pub struct FilenameFilterOptions {
+    /// Names that nothing can be created with, like `.*` for dotfiles.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<String>,
+
+    /// Names that files have to match one of to be created. Directories don't
+    /// have to match them. Without any, every name that isn't denied is
+    /// allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+
+    /// How the patterns are written.
+    #[serde(default)]
+    pub syntax: PatternSyntax,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "virus_scan_options")]
+pub struct VirusScanOptions {
+    /// The scanner to send uploads to.
+    pub scanner: VirusScanner,
+
+    /// The directory, relative to the root of the mount, that uploads are
+    /// moved into when they're infected or couldn't be scanned. Clients can't
+    /// open anything in it. The default value is `.quarantine`.
+    #[serde(
+        default = "VirusScanOptions::default_quarantine_dir",
+        skip_serializing_if = "VirusScanOptions::is_default_quarantine_dir"
+    )]
+    #[schemars(with = "String")]
+    pub quarantine_dir: Utf8PathBuf,
+
+    /// How long scanning an upload can take before it's counted as failed. The
+    /// default value is 2 minutes.
+    #[serde(
+        default = "VirusScanOptions::default_timeout",
+        skip_serializing_if = "VirusScanOptions::is_default_timeout",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "virus_scanner", tag = "type", rename_all = "snake_case")]
+pub enum VirusScanner {
+    /// A clamd daemon, which is sent uploads with its `INSTREAM` command.
+    Clamd {
+        /// Where clamd listens, as `host:port` for TCP, or the path of its
+        /// Unix socket.
+        #[schemars(example = "VirusScanner::example_clamd_address")]
+        address: String,
+    },
+    /// An ICAP server, which is sent uploads as `RESPMOD` requests.
+    Icap {
+        /// The URL of the scanning service.
+        #[schemars(example = "VirusScanner::example_icap_url")]
+        url: Url,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "cache_options")]
+pub struct CacheOptions {
+    /// How long cached data is used before it's read from the backend again.
+    /// The default value is 30 seconds.
+    #[serde(
+        default = "CacheOptions::default_ttl",
+        skip_serializing_if = "CacheOptions::is_default_ttl",
+        with = "humantime_serde"
+    )]
+    #[schemars(with = "String")]
+    pub ttl: Duration,
+
+    /// How many bytes of file contents, metadata, and directory listings to
+    /// keep at most. Once the cache is full, what was used the longest ago is
+    /// dropped first. The default value is 64 MiB.
+    #[serde(
+        default = "CacheOptions::default_max_size",
+        skip_serializing_if = "CacheOptions::is_default_max_size"
+    )]
+    pub max_size: u64,
+
+    /// How many bytes of a file are read from the backend and cached together.
+    /// The default value is 64 KiB.
+    #[serde(
+        default = "CacheOptions::default_block_size",
+        skip_serializing_if = "CacheOptions::is_default_block_size"
+    )]
+    pub block_size: u32,
+
+    /// Files up to this many bytes are read and cached whole as soon as any
+    /// part of them is read. Without it, only the blocks that are read are
+    /// cached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub whole_files_below: Option<u64>,
+}
+
+impl VfsConfig {
+    /// What is replaced with the name of the user who logged in.
+    const USERNAME_PLACEHOLDER: &str = "{username}";
+
+    fn example_vfs_root() -> &'static str {
+        "/shared"
+    }
+
+    /// Whether this is a template for a mount of each user's own.
+    pub(super) fn is_template(&self) -> bool {
+        let local_dir = match &self.backend {
+            BackendConfig::LocalDir { local_dir } => local_dir.as_str(),
+            _ => "",
+        };
+
+        self.vfs_root.as_str().contains(Self::USERNAME_PLACEHOLDER)
+            || local_dir.contains(Self::USERNAME_PLACEHOLDER)
+    }
+
+    /// This template, filled in for `username`.
+    pub(super) fn for_user(&self, username: &str) -> Self {
+        let fill = |path: &Utf8PathBuf| {
+            Utf8PathBuf::from(path.as_str().replace(Self::USERNAME_PLACEHOLDER, username))
+        };
+
+        let mut config = self.clone();
+        config.vfs_root = fill(&self.vfs_root);
+        if let BackendConfig::LocalDir { local_dir } = &mut config.backend {
+            *local_dir = fill(local_dir);
+        }
+        if let Some(legal_hold_file) = &mut config.options.legal_hold_file {
+            *legal_hold_file = fill(legal_hold_file);
+        }
+        if let Some(worm) = &mut config.options.worm {
+            worm.state_file = fill(&worm.state_file);
+        }
+
+        config
+    }
+
+    /// A state file of this template's that every user's mount would share,
+    /// because it doesn't have a `{username}` in it.
+    pub(super) fn shared_state_file(&self) -> Option<&Utf8Path> {
+        let worm_state_file = self
+            .options
+            .worm
+            .as_ref()
+            .map(|worm| worm.state_file.as_path());
+
+        [self.options.legal_hold_file.as_deref(), worm_state_file]
+            .into_iter()
+            .flatten()
+            .find(|path| !path.as_str().contains(Self::USERNAME_PLACEHOLDER))
+    }
+}
+
+impl BackendConfig {
+    fn example_local_dir() -> &'static str {
+        "/srv/schlep/shared"
+    }
+
+    fn example_archive_file() -> &'static str {
+        "/srv/schlep/releases/sdk-2.4.0.zip"
+    }
+
+    fn example_webdav_url() -> &'static str {
+        "https://dav.example.com/remote.php/dav/files/schlep/"
+    }
+
+    fn example_sftp_url() -> &'static str {
+        "sftp://schlep@files.example.com:22/srv/shared"
+    }
+
+    fn example_webhdfs_url() -> &'static str {
+        "https://namenode.example.com:9871/data/exports"
+    }
+
+    fn example_ftp_url() -> &'static str {
+        "ftps://schlep@ftp.example.com/outbound"
+    }
+
+    fn default_ftp_max_connections() -> usize {
+        4
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_ftp_max_connections(max_connections: &usize) -> bool {
+        *max_connections == Self::default_ftp_max_connections()
+    }
+
+    fn example_postgres_url() -> &'static str {
+        "postgresql://schlep@db.example.com/partner_configs"
+    }
+
+    fn example_sftp_host_key() -> &'static str {
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl"
+    }
+}
+
+impl BackendOptions {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// These options, as the ones a backend takes. `vfs_root` is only used to
+    /// say which mount they were wrong for.
+    pub(super) fn parse<T: DeserializeOwned>(&self, vfs_root: &Utf8Path) -> Result<T, Error> {
+        serde_json::from_value(Value::Object(self.0.clone()))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+            .into_io_error(format!("invalid backend_options for {vfs_root}"))
+    }
+}
+
+impl WebDavOptions {
+    fn default_request_timeout() -> Duration {
+        Duration::from_mins(1)
+    }
+}
+
+impl WebHdfsOptions {
+    fn default_request_timeout() -> Duration {
+        Duration::from_mins(1)
+    }
+
+    fn default_append_size() -> usize {
+        8 * 1024 * 1024
+    }
+}
+
+impl FtpOptions {
+    fn default_connect_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl PostgresOptions {
+    fn default_max_connections() -> usize {
+        16
+    }
+}
+
+impl MountOptions {
+    fn example_visible_to_group() -> &'static str {
+        "cn=finance,ou=groups,dc=example,dc=com"
+    }
+
+    fn default_writable() -> bool {
+        true
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_writable(writable: &bool) -> bool {
+        *writable == Self::default_writable()
+    }
+
+    fn default_exclusive_write_timeout() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn is_default_exclusive_write_timeout(timeout: &Duration) -> bool {
+        *timeout == Self::default_exclusive_write_timeout()
+    }
+}
+
+impl WormOptions {
+    fn example_retention() -> &'static str {
+        "7years"
+    }
+}
+
+impl CompressionOptions {
+    fn default_level() -> i32 {
+        3
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_level(level: &i32) -> bool {
+        *level == Self::default_level()
+    }
+
+    fn default_frame_size() -> u32 {
+        1024 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_frame_size(frame_size: &u32) -> bool {
+        *frame_size == Self::default_frame_size()
+    }
+}
+
+impl DeduplicationOptions {
+    fn example_chunk_dir() -> &'static str {
+        "/srv/schlep/chunks"
+    }
+
+    fn default_chunk_size() -> u32 {
+        4 * 1024 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_chunk_size(chunk_size: &u32) -> bool {
+        *chunk_size == Self::default_chunk_size()
+    }
+}
+
+impl ReadmeOptions {
+    fn default_name() -> String {
+        "README.txt".to_string()
+    }
+
+    fn is_default_name(name: &str) -> bool {
+        name == Self::default_name()
+    }
+
+    fn example_text() -> &'static str {
+        "Upload invoices to incoming/. Files are picked up every 15 minutes.\n"
+    }
+}
+
+impl TrashOptions {
+    fn example_retention() -> &'static str {
+        "30days"
+    }
+
+    fn default_purge_interval() -> Duration {
+        Duration::from_hours(1)
+    }
+
+    fn is_default_purge_interval(interval: &Duration) -> bool {
+        *interval == Self::default_purge_interval()
+    }
+}
+
+impl CacheOptions {
+    fn default_ttl() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn is_default_ttl(ttl: &Duration) -> bool {
+        *ttl == Self::default_ttl()
+    }
+
+    fn default_max_size() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_max_size(size: &u64) -> bool {
+        *size == Self::default_max_size()
+    }
+
+    fn default_block_size() -> u32 {
+        64 * 1024
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_block_size(size: &u32) -> bool {
+        *size == Self::default_block_size()
+    }
+}
+
+impl VirusScanOptions {
+    fn default_quarantine_dir() -> Utf8PathBuf {
+        Utf8PathBuf::from(".quarantine")
+    }
+
+    fn is_default_quarantine_dir(dir: &Utf8PathBuf) -> bool {
+        *dir == Self::default_quarantine_dir()
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_mins(2)
+    }
+
+    fn is_default_timeout(timeout: &Duration) -> bool {
+        *timeout == Self::default_timeout()
+    }
+}
+
+impl VirusScanner {
+    fn example_clamd_address() -> &'static str {
+        "/run/clamav/clamd.ctl"
+    }
+
+    fn example_icap_url() -> &'static str {
+        "icap://av.example.com:1344/avscan"
+    }
+}
+
+impl VersioningOptions {
+    fn default_revisions() -> usize {
+        10
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    fn is_default_revisions(revisions: &usize) -> bool {
+        *revisions == Self::default_revisions()
+    }
+}
+
+impl Default for WebDavOptions {
+    fn default() -> Self {
+        Self {
+            request_timeout: Self::default_request_timeout(),
+        }
+    }
+}
+
+impl Default for WebHdfsOptions {
+    fn default() -> Self {
+        Self {
+            request_timeout: Self::default_request_timeout(),
+            append_size: Self::default_append_size(),
+        }
+    }
+}
+
+impl Default for FtpOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Self::default_connect_timeout(),
+        }
+    }
+}
+
+impl Default for PostgresOptions {
+    fn default() -> Self {
+        Self {
+            max_connections: Self::default_max_connections(),
+        }
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            writable: Self::default_writable(),
+            exclusive_writes: ExclusiveWrites::default(),
+            exclusive_write_timeout: Self::default_exclusive_write_timeout(),
+            max_file_size: None,
+            upload_collisions: UploadCollisions::default(),
+            atomic_uploads: false,
+            virus_scan: None,
+            visible_to_users: Vec::new(),
+            visible_to_group: None,
+            legal_hold_file: None,
+            worm: None,
+            filename_encryption: None,
+            compression: None,
+            deduplication: None,
+            readme: None,
+            trash: None,
+            versioning: None,
+            free_space: None,
+            bandwidth_limit: None,
+            cache: None,
+            filename_filter: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "exclusive_writes", rename_all = "snake_case")]
+pub enum ExclusiveWrites {
+    /// Any number of clients may write to the same file at once.
+    #[default]
+    Allow,
+    /// Opening a file that is already open for writing fails immediately.
+    Reject,
+    /// Opening a file that is already open for writing waits for the other
+    /// writer to close it, up to `exclusive_write_timeout`.
+    Wait,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "pattern_syntax", rename_all = "snake_case")]
+pub enum PatternSyntax {
+    /// Shell-style globs, like `*.csv` or `report-[0-9]*.pdf`.
+    #[default]
+    Glob,
+    /// Regular expressions, which have to match the whole name.
+    Regex,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename = "upload_collisions", rename_all = "snake_case")]
+pub enum UploadCollisions {
+    /// The upload writes over the existing file.
+    #[default]
+    Overwrite,
+    /// The upload fails, and the existing file is left alone.
+    Reject,
+    /// The upload is saved with a number added before the extension of its
+    /// name, like `invoice.1.csv`, counting up until the name is free.
+    Rename,
+}
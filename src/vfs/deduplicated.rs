@@ -0,0 +1,965 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    ops::Range,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use rustix::fs::{FlockOperation, flock};
+use sha2::{Digest, Sha256};
+use thiserror_ext::AsReport;
+use tokio::sync::{Mutex as AsyncMutex, broadcast};
+use tracing::{Level, event};
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    DeduplicationOptions,
+    Error,
+    FsMetadata,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    Vfs,
+    VfsInstance,
+    blocking,
+    hash::{BlockHasher, to_hex},
+    sequential::SequentialBuffer,
+    state_file::normalize,
+};
+use crate::vfs::error::IntoIoError;
+
+/// What the first line of every manifest starts with, followed by the size of
+/// the file and its chunk size.
+const MANIFEST_MAGIC: &str = "schlep-dedup-manifest 1 ";
+
+/// The most the first line of a manifest can take up.
+const MAX_HEADER_LEN: usize = 128;
+
+/// The length of a hex-encoded SHA-256, which is what chunks are named by.
+const CHUNK_NAME_LEN: usize = 64;
+
+/// How much of a manifest is read from the backend at a time.
+const MANIFEST_READ_SIZE: usize = 1024 * 1024;
+
+/// How much of a file is read at a time to hash it.
+const HASH_CHUNK_SIZE: usize = 256 * 1024;
+
+/// The file in the chunk directory that's locked while chunks are stored and
+/// removed, so that every mount and process sharing the directory agrees on
+/// how many files use each chunk.
+const LOCK_FILE_NAME: &str = ".lock";
+
+/// Splits file contents into fixed-size chunks and stores each one under its
+/// SHA-256 in a local chunk directory, so that data that's uploaded more than
+/// once, by however many users, is only stored once. The backend it wraps only
+/// gets a manifest for each file, listing its chunks in order.
+///
+/// A chunk is only written if the chunk directory doesn't have it already, and
+/// is checked against its name whenever it's read. Since any number of files
+/// in any number of mounts can share a chunk, the chunk directory keeps count
+/// of the manifests that list each one, and removes it once the last of them
+/// is removed or replaced. Chunks stored before they were counted are kept.
+///
+/// Files can only be written from start to finish: opening an existing file
+/// for writing without truncating it, or writing to a part of a file that has
+/// already been stored, fails with [`Error::UnsupportedMethod`]. Files on the
+/// backend that aren't manifests, like ones from before deduplication was
+/// turned on, are read as they are.
+pub struct Deduplicated {
+    inner: Box<VfsInstance>,
+    chunks: ChunkStore,
+    chunk_size: usize,
+    files: Mutex<HashMap<Handle, Arc<AsyncMutex<OpenFile>>>>,
+    dirs: Mutex<HashMap<Handle, Utf8PathBuf>>,
+}
+
+/// The first line of a manifest.
+struct Header {
+    size: u64,
+    chunk_size: u64,
+    /// How long the line is, including its newline.
+    len: usize,
+}
+
+impl Header {
+    /// Parses the header at the start of `bytes`, or returns [`None`] if they
+    /// don't start with one.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let end = bytes.iter().position(|byte| *byte == b'\n')?;
+        let line = std::str::from_utf8(&bytes[..end]).ok()?;
+        let (size, chunk_size) = line.strip_prefix(MANIFEST_MAGIC)?.split_once(' ')?;
+
+        Some(Self {
+            size: size.parse().ok()?,
+            chunk_size: chunk_size
+                .parse()
+                .ok()
+                .filter(|chunk_size| *chunk_size != 0)?,
+            len: end + 1,
+        })
+    }
+
+    fn chunk_count(&self) -> usize {
+        usize::try_from(self.size.div_ceil(self.chunk_size)).unwrap_or(usize::MAX)
+    }
+}
+
+/// The chunks that make up a file, in order. Every chunk but the last is
+/// `chunk_size` long.
+struct Manifest {
+    size: u64,
+    chunk_size: u64,
+    chunks: Vec<String>,
+}
+
+impl Manifest {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("{MANIFEST_MAGIC}{} {}\n", self.size, self.chunk_size).into_bytes();
+        bytes.reserve(self.chunks.len() * (CHUNK_NAME_LEN + 1));
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(chunk.as_bytes());
+            bytes.push(b'\n');
+        }
+
+        bytes
+    }
+
+    /// The index of the chunk that holds the byte at `offset`, if any does.
+    fn chunk_at(&self, offset: u64) -> Option<usize> {
+        (offset < self.size).then(|| usize::try_from(offset / self.chunk_size).unwrap())
+    }
+
+    /// Where chunk `index` starts in the file, and how long it is.
+    fn chunk_range(&self, index: usize) -> (u64, usize) {
+        let start = index as u64 * self.chunk_size;
+        let len = self.chunk_size.min(self.size - start);
+
+        (start, usize::try_from(len).unwrap())
+    }
+}
+
+enum OpenFile {
+    /// A file that isn't a manifest, which is read as it is.
+    Plain,
+    Reading(Reader),
+    Writing(Writer),
+}
+
+struct Reader {
+    manifest: Manifest,
+    /// The last chunk that was read, since reads usually come in runs that
+    /// are much smaller than a chunk.
+    cached: Option<(usize, Arc<Vec<u8>>)>,
+}
+
+#[derive(Default)]
+struct Writer {
+    /// The names of the chunks that have been stored so far.
+    chunks: Vec<String>,
+    /// The part of the file that hasn't been stored yet.
+    data: SequentialBuffer,
+}
+
+/// The directory that chunks are kept in, each one in a subdirectory named
+/// after the first two characters of its name to keep directories small.
+///
+/// Next to each chunk is a `.refs` file with the number of times manifests
+/// list it. Manifests that couldn't be read when they were removed leave their
+/// chunks counted, so a chunk can outlive its files, but it's never removed
+/// while a manifest still lists it.
+struct ChunkStore {
+    dir: Utf8PathBuf,
+}
+
+impl ChunkStore {
+    fn path(dir: &Utf8Path, name: &str) -> Utf8PathBuf {
+        dir.join(&name[..2]).join(name)
+    }
+
+    fn is_name(name: &str) -> bool {
+        name.len() == CHUNK_NAME_LEN && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+    }
+
+    /// Locks the chunk directory until the returned file is dropped.
+    fn lock(dir: &Utf8Path) -> io::Result<File> {
+        fs::create_dir_all(dir)?;
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE_NAME))?;
+        flock(&file, FlockOperation::LockExclusive)?;
+
+        Ok(file)
+    }
+
+    /// How many times manifests list the chunk at `path`, or [`None`] if it
+    /// isn't counted.
+    fn refs(path: &Utf8Path) -> io::Result<Option<u64>> {
+        match fs::read_to_string(path.with_extension("refs")) {
+            Ok(refs) => refs
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_refs(path: &Utf8Path, refs: u64) -> io::Result<()> {
+        let refs_path = path.with_extension("refs");
+        let temp_path = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+
+        let written = fs::write(&temp_path, refs.to_string())
+            .and_then(|()| fs::rename(&temp_path, refs_path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        written
+    }
+
+    /// Reads the chunk called `name`, making sure it still hashes to it.
+    async fn get(&self, name: &str) -> Result<Vec<u8>, Error> {
+        if !Self::is_name(name) {
+            return corrupt();
+        }
+
+        let path = Self::path(&self.dir, name);
+        let name = name.to_owned();
+
+        blocking::run(move |_| {
+            let content = fs::read(&path).into_io_error(format!("failed to read chunk {name}"))?;
+            if to_hex(&Sha256::digest(&content)) != name {
+                return corrupt();
+            }
+
+            Ok(content)
+        })
+        .await
+    }
+
+    /// Stores `content`, unless a chunk with the same content is already
+    /// stored, and returns the name of its chunk. The chunk is counted as
+    /// listed once more, until it's [released](Self::release).
+    async fn put(&self, content: Vec<u8>) -> Result<String, Error> {
+        let dir = self.dir.clone();
+
+        blocking::run(move |_| {
+            let name = to_hex(&Sha256::digest(&content));
+            let path = Self::path(&dir, &name);
+
+            // New chunks are written to a temporary name before the directory
+            // is locked, so that uploads don't wait on each other's writes,
+            // and so that a chunk is never seen half-written.
+            let temp_path = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+            let write_temp = || {
+                path.parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|()| File::create(&temp_path))
+                    .and_then(|mut file| {
+                        file.write_all(&content)?;
+                        file.sync_all()
+                    })
+            };
+
+            let store = || {
+                let written = !path.exists();
+                if written {
+                    write_temp()?;
+                }
+
+                let _lock = Self::lock(&dir)?;
+                if path.exists() {
+                    return match Self::refs(&path)? {
+                        Some(refs) => Self::set_refs(&path, refs + 1),
+                        None => Ok(()),
+                    };
+                }
+
+                // It was removed since it was checked for.
+                if !written {
+                    write_temp()?;
+                }
+                Self::set_refs(&path, 1)?;
+                fs::rename(&temp_path, &path)
+            };
+
+            let stored = store();
+
+            // Whatever is left at the temporary name wasn't needed.
+            let _ = fs::remove_file(&temp_path);
+            stored.into_io_error(format!("failed to store chunk {name}"))?;
+
+            Ok(name)
+        })
+        .await
+    }
+
+    /// Counts the chunks called `names` as listed once more each, for a
+    /// manifest that's been copied.
+    async fn acquire(&self, names: Vec<String>) -> Result<(), Error> {
+        self.update(names, |path, refs| Self::set_refs(path, refs + 1))
+            .await
+    }
+
+    /// Counts the chunks called `names` as listed once less each, for a
+    /// manifest that's been removed or replaced, and removes those that no
+    /// manifest lists anymore.
+    async fn release(&self, names: Vec<String>) -> Result<(), Error> {
+        self.update(names, |path, refs| {
+            if refs > 1 {
+                return Self::set_refs(path, refs - 1);
+            }
+
+            match fs::remove_file(path) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+            fs::remove_file(path.with_extension("refs"))
+        })
+        .await
+    }
+
+    /// Applies `update` to the path and count of each of the counted chunks
+    /// called `names`, with the chunk directory locked.
+    async fn update(
+        &self,
+        names: Vec<String>,
+        update: fn(&Utf8Path, u64) -> io::Result<()>,
+    ) -> Result<(), Error> {
+        let dir = self.dir.clone();
+
+        blocking::run(move |_| {
+            let _lock = Self::lock(&dir).into_io_error("failed to lock chunk directory")?;
+
+            for name in names.iter().filter(|name| Self::is_name(name)) {
+                let path = Self::path(&dir, name);
+                if let Some(refs) =
+                    Self::refs(&path).into_io_error(format!("failed to count chunk {name}"))?
+                {
+                    update(&path, refs).into_io_error(format!("failed to count chunk {name}"))?;
+                }
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+impl Deduplicated {
+    #[must_use]
+    pub fn new(inner: VfsInstance, options: &DeduplicationOptions) -> Self {
+        Self {
+            inner: Box::new(inner),
+            chunks: ChunkStore {
+                dir: options.chunk_dir.clone(),
+            },
+            chunk_size: options.chunk_size.max(1) as usize,
+            files: Mutex::default(),
+            dirs: Mutex::default(),
+        }
+    }
+
+    fn file(&self, handle: &Handle) -> Option<Arc<AsyncMutex<OpenFile>>> {
+        self.files.lock().get(handle).cloned()
+    }
+
+    /// Reads the header of the backend file open as `handle`, or returns
+    /// [`None`] if it isn't a manifest.
+    async fn read_header(&self, handle: &Handle) -> Result<Option<Header>, Error> {
+        let bytes = self
+            .inner
+            .read(handle, 0, MAX_HEADER_LEN)
+            .await?
+            .unwrap_or_default();
+
+        Ok(Header::parse(&bytes))
+    }
+
+    /// Reads the manifest in the backend file open as `handle`, or returns
+    /// [`None`] if it isn't one.
+    async fn read_manifest(&self, handle: &Handle) -> Result<Option<Manifest>, Error> {
+        let Some(header) = self.read_header(handle).await? else {
+            return Ok(None);
+        };
+
+        let Some(wanted) = header.chunk_count().checked_mul(CHUNK_NAME_LEN + 1) else {
+            return corrupt();
+        };
+        let mut body = Vec::with_capacity(wanted.min(MANIFEST_READ_SIZE));
+        let mut offset = header.len as u64;
+
+        while body.len() < wanted {
+            let len = (wanted - body.len()).min(MANIFEST_READ_SIZE);
+            match self.inner.read(handle, offset, len).await? {
+                Some(bytes) if !bytes.is_empty() => {
+                    offset += bytes.len() as u64;
+                    body.extend_from_slice(&bytes);
+                }
+                _ => break,
+            }
+        }
+
+        if body.len() != wanted {
+            return corrupt();
+        }
+
+        let chunks = body
+            .chunks_exact(CHUNK_NAME_LEN + 1)
+            .map(|line| {
+                let (name, newline) = line.split_at(CHUNK_NAME_LEN);
+                if newline != b"\n" {
+                    return corrupt();
+                }
+
+                String::from_utf8(name.to_vec()).or_else(|_| corrupt())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(Manifest {
+            size: header.size,
+            chunk_size: header.chunk_size,
+            chunks,
+        }))
+    }
+
+    /// The size of the file whose manifest is at `path` on the backend.
+    async fn manifest_size(&self, path: &Utf8Path) -> Result<Option<u64>, Error> {
+        let handle = self.inner.open(path, OpenFlags::READ).await?;
+        let header = self.read_header(&handle).await;
+        self.inner.close(handle).await?;
+
+        Ok(header?.map(|header| header.size))
+    }
+
+    /// The manifest of the file at `path` on the backend, if there is one.
+    ///
+    /// It's read to let go of its chunks when the file is removed or replaced,
+    /// which a manifest that can't be read shouldn't stop, so failing to read
+    /// it is only logged.
+    async fn manifest_at(&self, path: &Utf8Path) -> Option<Manifest> {
+        let manifest = async {
+            // A symlink to a manifest doesn't count towards its chunks.
+            if self.inner.readlink(path).await.is_ok() {
+                return Ok(None);
+            }
+
+            match self.inner.stat(path).await {
+                Ok(metadata) if !metadata.is_directory => (),
+                Ok(_) => return Ok(None),
+                Err(err) if err.is_not_found() => return Ok(None),
+                Err(err) => return Err(err),
+            }
+
+            let handle = self.inner.open(path, OpenFlags::READ).await?;
+            let manifest = self.read_manifest(&handle).await;
+            self.inner.close(handle).await?;
+
+            manifest
+        };
+
+        match manifest.await {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                event!(
+                    Level::WARN,
+                    %path,
+                    err = %err.as_report(),
+                    "failed to read manifest, so its chunks are kept"
+                );
+                None
+            }
+        }
+    }
+
+    /// Lets go of the chunks of a manifest that's been removed or replaced.
+    async fn release(&self, manifest: Option<Manifest>) {
+        let Some(manifest) = manifest else {
+            return;
+        };
+
+        if let Err(err) = self.chunks.release(manifest.chunks).await {
+            event!(Level::WARN, err = %err.as_report(), "failed to release chunks");
+        }
+    }
+
+    /// Replaces the size in `metadata` of the file at `path` with the size of
+    /// the file its manifest describes.
+    async fn fix_size(&self, path: &Utf8Path, metadata: &mut Metadata) {
+        if metadata.is_directory {
+            return;
+        }
+
+        match self.manifest_size(path).await {
+            Ok(Some(size)) => metadata.size = Some(size),
+            Ok(None) => (),
+            Err(err) => {
+                event!(
+                    Level::DEBUG,
+                    %path,
+                    err = %err.as_report(),
+                    "failed to read manifest size"
+                );
+            }
+        }
+    }
+
+    /// The content of chunk `index`.
+    async fn chunk(&self, reader: &mut Reader, index: usize) -> Result<Arc<Vec<u8>>, Error> {
+        if let Some((_, content)) = reader
+            .cached
+            .as_ref()
+            .filter(|(cached, _)| *cached == index)
+        {
+            return Ok(content.clone());
+        }
+
+        let content = self.chunks.get(&reader.manifest.chunks[index]).await?;
+        if content.len() != reader.manifest.chunk_range(index).1 {
+            return corrupt();
+        }
+
+        let content = Arc::new(content);
+        reader.cached = Some((index, content.clone()));
+
+        Ok(content)
+    }
+
+    async fn read_chunks(
+        &self,
+        reader: &mut Reader,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if reader.manifest.chunk_at(offset).is_none() {
+            return Ok(None);
+        }
+
+        let mut out = Vec::with_capacity(len);
+        let mut position = offset;
+
+        while out.len() < len {
+            let Some(index) = reader.manifest.chunk_at(position) else {
+                break;
+            };
+
+            let (start, _) = reader.manifest.chunk_range(index);
+            let content = self.chunk(reader, index).await?;
+            let content = &content[usize::try_from(position - start).unwrap()..];
+            let content = &content[..content.len().min(len - out.len())];
+
+            out.extend_from_slice(content);
+            position += content.len() as u64;
+        }
+
+        Ok(Some(out))
+    }
+
+    /// Stores every full chunk in `writer`'s buffer, along with whatever is
+    /// left over if `all` is set.
+    async fn flush_chunks(&self, writer: &mut Writer, all: bool) -> Result<(), Error> {
+        while writer.data.buffered() >= self.chunk_size || (all && writer.data.buffered() > 0) {
+            let content = writer.data.take(self.chunk_size);
+            let name = self.chunks.put(content).await?;
+            writer.chunks.push(name);
+        }
+
+        Ok(())
+    }
+
+    /// Stores the rest of a file that was open for writing, and writes its
+    /// manifest to the backend.
+    async fn finish(&self, handle: &Handle, writer: &mut Writer) -> Result<(), Error> {
+        writer.data.fill_holes();
+        self.flush_chunks(writer, true).await?;
+
+        let manifest = Manifest {
+            size: writer.data.flushed(),
+            chunk_size: self.chunk_size as u64,
+            chunks: std::mem::take(&mut writer.chunks),
+        };
+        let result = self.inner.write(handle, 0, &manifest.to_bytes()).await;
+        if result.is_err() {
+            self.release(Some(manifest)).await;
+        }
+
+        result
+    }
+
+    /// Hashes a range of the file's content, which has to be read back
+    /// through the wrapper.
+    async fn hash_content(
+        &self,
+        path: &Utf8Path,
+        mut offset: u64,
+        mut hasher: BlockHasher,
+    ) -> Result<BlockHasher, Error> {
+        let handle = self.open(path, OpenFlags::READ).await?;
+
+        let result = loop {
+            let wanted = hasher.wanted(HASH_CHUNK_SIZE);
+            if wanted == 0 {
+                break Ok(());
+            }
+
+            match self.read(&handle, offset, wanted).await {
+                Ok(Some(chunk)) => {
+                    hasher.update(&chunk);
+                    offset += chunk.len() as u64;
+                }
+                Ok(None) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.close(handle).await?;
+        result?;
+
+        Ok(hasher)
+    }
+}
+
+#[async_trait]
+impl Vfs for Deduplicated {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            let truncates = flags.intersects(OpenFlags::TRUNCATE | OpenFlags::EXCLUDE);
+            let exists = match self.inner.stat(path).await {
+                Ok(_) => true,
+                Err(err) if err.is_not_found() => false,
+                Err(err) => return Err(err),
+            };
+
+            if flags.intersects(OpenFlags::READ | OpenFlags::APPEND) || (exists && !truncates) {
+                return Err(Error::UnsupportedMethod);
+            }
+
+            let replaced = if exists {
+                self.manifest_at(path).await
+            } else {
+                None
+            };
+            let handle = self.inner.open(path, flags | OpenFlags::TRUNCATE).await?;
+            self.release(replaced).await;
+            self.files.lock().insert(
+                handle.clone(),
+                Arc::new(AsyncMutex::new(OpenFile::Writing(Writer::default()))),
+            );
+
+            return Ok(handle);
+        }
+
+        let handle = self.inner.open(path, flags).await?;
+        let file = match self.read_manifest(&handle).await {
+            Ok(Some(manifest)) => OpenFile::Reading(Reader {
+                manifest,
+                cached: None,
+            }),
+            Ok(None) => OpenFile::Plain,
+            Err(err) => {
+                self.inner.close(handle).await?;
+                return Err(err);
+            }
+        };
+        self.files
+            .lock()
+            .insert(handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(handle)
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let handle = self.inner.open_dir(path).await?;
+        self.dirs.lock().insert(handle.clone(), path.to_owned());
+
+        Ok(handle)
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        let result = match handle.handle_type() {
+            HandleType::File => {
+                let file = self.files.lock().remove(&handle);
+                match file {
+                    Some(file) => match &mut *file.lock().await {
+                        OpenFile::Writing(writer) => self.finish(&handle, writer).await,
+                        OpenFile::Plain | OpenFile::Reading(_) => Ok(()),
+                    },
+                    None => Ok(()),
+                }
+            }
+            HandleType::Dir => {
+                self.dirs.lock().remove(&handle);
+                Ok(())
+            }
+        };
+
+        self.inner.close(handle).await?;
+        result
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(file) = self.file(handle) else {
+            return Err(Error::FileNotFound);
+        };
+
+        match &mut *file.lock().await {
+            OpenFile::Plain => self.inner.read(handle, offset, len).await,
+            OpenFile::Reading(reader) => self.read_chunks(reader, offset, len).await,
+            OpenFile::Writing(_) => Err(Error::UnsupportedMethod),
+        }
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.inner.read_dir(handle).await?;
+        let Some(dir) = self.dirs.lock().get(handle).cloned() else {
+            return Ok(entries);
+        };
+
+        for (name, metadata) in &mut entries {
+            self.fix_size(&dir.join(&*name), metadata).await;
+        }
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.inner.read_dir_next(handle, max).await?;
+        let Some(dir) = self.dirs.lock().get(handle).cloned() else {
+            return Ok(entries);
+        };
+
+        for (name, metadata) in &mut entries {
+            self.fix_size(&dir.join(&*name), metadata).await;
+        }
+
+        Ok(entries)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let Some(file) = self.file(handle) else {
+            return Err(Error::FileNotFound);
+        };
+
+        match &mut *file.lock().await {
+            OpenFile::Writing(writer) => {
+                writer.data.insert(offset, data)?;
+                self.flush_chunks(writer, false).await
+            }
+            OpenFile::Plain | OpenFile::Reading(_) => Err(Error::UnsupportedMethod),
+        }
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        let mut metadata = self.inner.stat_fd(handle).await?;
+
+        if let Some(file) = self.file(handle) {
+            match &*file.lock().await {
+                OpenFile::Plain => (),
+                OpenFile::Reading(reader) => metadata.size = Some(reader.manifest.size),
+                OpenFile::Writing(writer) => metadata.size = Some(writer.data.len()),
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let replaced = if normalize(from) == normalize(to) {
+            None
+        } else {
+            self.manifest_at(to).await
+        };
+        self.inner.rename(from, to).await?;
+        self.release(replaced).await;
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let mut metadata = self.inner.stat(path).await?;
+        self.fix_size(path, &mut metadata).await;
+
+        Ok(metadata)
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        // The new link is counted before it's made, so that the chunks can't
+        // be removed along with the old one in the meantime.
+        let linked = self.manifest_at(path).await;
+        if let Some(manifest) = &linked {
+            self.chunks.acquire(manifest.chunks.clone()).await?;
+        }
+
+        let result = self.inner.hardlink(path, target).await;
+        if result.is_err() {
+            self.release(linked).await;
+        }
+
+        result
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let hasher = BlockHasher::new(algorithm, len, block_size);
+
+        Ok(self.hash_content(path, offset, hasher).await?.finish())
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let removed = self.manifest_at(path).await;
+        self.inner.remove_file(path).await?;
+        self.release(removed).await;
+
+        Ok(())
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.remove_dir(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        // An empty file doesn't need a manifest, so emptying one is the only
+        // change that can be made without rewriting it.
+        if len != 0 {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        let emptied = self.manifest_at(path).await;
+        self.inner.truncate(path, 0).await?;
+        self.release(emptied).await;
+
+        Ok(())
+    }
+
+    async fn truncate_fd(&self, _handle: &Handle, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
+
+fn corrupt<T>() -> Result<T, Error> {
+    Err(io::Error::from(io::ErrorKind::InvalidData)).into_io_error("deduplicated file is corrupt")
+}
@@ -0,0 +1,360 @@
+use std::{io, ops::Range, path::PathBuf, time::SystemTime};
+
+use aes_siv::{KeyInit, siv::Aes256Siv};
+use async_trait::async_trait;
+use base32ct::{Base32Unpadded, Encoding as _};
+use base64ct::{Base64, Encoding as _};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tracing::{Level, event};
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FilenameEncryptionOptions,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    Vfs,
+    VfsInstance,
+};
+use crate::vfs::error::IntoIoError;
+
+/// Bound into every encrypted name, so that names can't be passed off as any
+/// other kind of ciphertext made with the same key.
+const ASSOCIATED_DATA: &[u8] = b"filename@schlep";
+
+/// The longest name most filesystems will store.
+const MAX_NAME_LEN: usize = 255;
+
+/// Encrypts the name of every file and directory before it reaches the backend
+/// it wraps, and decrypts them again on the way back, so that the names on the
+/// backend give nothing away while clients see the real ones.
+///
+/// Each name is encrypted on its own with AES-SIV and encoded with lowercase
+/// base32. The encryption is deterministic, so that a path can be looked up
+/// without listing its directory, but that also means that two entries with
+/// the same name have the same encrypted name, wherever they are. File contents
+/// and metadata are left alone.
+///
+/// Entries on the backend whose names don't decrypt, like ones that were put
+/// there by something other than Schlep, are left out of directory listings.
+pub struct EncryptedNames {
+    inner: Box<VfsInstance>,
+    cipher: Mutex<Aes256Siv>,
+}
+
+impl EncryptedNames {
+    pub fn new(inner: VfsInstance, options: &FilenameEncryptionOptions) -> Result<Self, Error> {
+        let key = std::fs::read_to_string(&options.key_file)
+            .into_io_error("failed to read filename encryption key")?;
+        let cipher = Base64::decode_vec(key.trim())
+            .ok()
+            .and_then(|key| Aes256Siv::new_from_slice(&key).ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected 64 base64-encoded bytes",
+                )
+            })
+            .into_io_error("invalid filename encryption key")?;
+
+        Ok(Self {
+            inner: Box::new(inner),
+            cipher: Mutex::new(cipher),
+        })
+    }
+
+    fn encrypt_name(&self, name: &str) -> Result<String, Error> {
+        let ciphertext = self
+            .cipher
+            .lock()
+            .encrypt([ASSOCIATED_DATA], name.as_bytes())
+            .map_err(|_| Error::InvalidPath(PathBuf::from(name)))?;
+        let encrypted = Base32Unpadded::encode_string(&ciphertext);
+
+        if encrypted.len() > MAX_NAME_LEN {
+            return Err(Error::InvalidPath(PathBuf::from(name)));
+        }
+
+        Ok(encrypted)
+    }
+
+    fn decrypt_name(&self, encrypted: &str) -> Option<String> {
+        let ciphertext = Base32Unpadded::decode_vec(encrypted).ok()?;
+        let name = self
+            .cipher
+            .lock()
+            .decrypt([ASSOCIATED_DATA], &ciphertext)
+            .ok()?;
+
+        String::from_utf8(name).ok()
+    }
+
+    /// The path on the backend of `path`.
+    fn encrypt_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let mut encrypted = Utf8PathBuf::new();
+
+        for component in path.components() {
+            match component {
+                Utf8Component::Normal(name) => encrypted.push(self.encrypt_name(name)?),
+                component => encrypted.push(component),
+            }
+        }
+
+        Ok(encrypted)
+    }
+
+    /// The path that clients see of the path on the backend `encrypted`.
+    fn decrypt_path(&self, encrypted: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let mut path = Utf8PathBuf::new();
+
+        for component in encrypted.components() {
+            match component {
+                Utf8Component::Normal(name) => path.push(
+                    self.decrypt_name(name)
+                        .ok_or_else(|| Error::InvalidPath(PathBuf::from(encrypted)))?,
+                ),
+                component => path.push(component),
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl Vfs for EncryptedNames {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        self.inner.open(&self.encrypt_path(path)?, flags).await
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        self.inner.open_dir(&self.encrypt_path(path)?).await
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        self.inner.close(handle).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // Changes would be reported under the encrypted names.
+        self.inner.capabilities() - Capabilities::WATCH
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.read(handle, offset, len).await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let entries = self.inner.read_dir(handle).await?;
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|(encrypted, metadata)| {
+                let Some(name) = self.decrypt_name(encrypted.as_str()) else {
+                    event!(Level::DEBUG, name = %encrypted, "skipping undecryptable name");
+                    return None;
+                };
+
+                Some((Utf8PathBuf::from(name), metadata))
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        // A batch of nothing but undecryptable names would look like the end
+        // of the listing, so those are skipped until something's left.
+        loop {
+            let entries = self.inner.read_dir_next(handle, max).await?;
+            if entries.is_empty() {
+                return Ok(entries);
+            }
+
+            let entries: Vec<_> = entries
+                .into_iter()
+                .filter_map(|(encrypted, metadata)| {
+                    let Some(name) = self.decrypt_name(encrypted.as_str()) else {
+                        event!(Level::DEBUG, name = %encrypted, "skipping undecryptable name");
+                        return None;
+                    };
+
+                    Some((Utf8PathBuf::from(name), metadata))
+                })
+                .collect();
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+        }
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.inner.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.inner
+            .rename(&self.encrypt_path(from)?, &self.encrypt_path(to)?)
+            .await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat(&self.encrypt_path(path)?).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(&self.encrypt_path(path)?).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(&self.encrypt_path(path)?).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner
+            .hardlink(&self.encrypt_path(path)?, &self.encrypt_path(target)?)
+            .await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner
+            .symlink(&self.encrypt_path(path)?, &self.encrypt_path(target)?)
+            .await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.inner
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let target = self.inner.readlink(&self.encrypt_path(path)?).await?;
+
+        self.decrypt_path(&target)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.mkdir(&self.encrypt_path(path)?).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.remove_file(&self.encrypt_path(path)?).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.remove_dir(&self.encrypt_path(path)?).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner
+            .set_times(&self.encrypt_path(path)?, atime, mtime)
+            .await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.inner
+            .set_permissions(&self.encrypt_path(path)?, mode)
+            .await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.inner.truncate(&self.encrypt_path(path)?, len).await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        self.inner.truncate_fd(handle, len).await
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        self.inner
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        self.inner.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 
+use crate::error_code::ErrorCode;
+
 #[derive(thiserror::Error, thiserror_ext::ContextInto, Debug)]
 #[non_exhaustive]
 pub enum Error {
@@ -20,4 +22,75 @@ pub enum Error {
     FileNotFound,
     #[error("would escape VFS root")]
     WouldEscape,
+    #[error("file is already open for writing")]
+    OpenForWrite,
+    #[error("file was modified by someone else")]
+    ChangeTokenMismatch,
+    #[error("operation was cancelled")]
+    Cancelled,
+    #[error("operation panicked: {0}")]
+    TaskPanicked(String),
+    #[error("filesystem is read-only")]
+    ReadOnly,
+    #[error("file is under legal hold")]
+    LegalHold,
+    #[error("file is write-once and can't be changed")]
+    Immutable,
+    #[error("file is still within its retention period")]
+    Retained,
+    #[error("not enough free space left")]
+    NoSpace,
+    #[error("file is locked by someone else")]
+    RangeLocked,
+    #[error("no lock on that range of the file")]
+    NoMatchingLock,
+    #[error("symlinks aren't allowed here")]
+    SymlinkDenied,
+    #[error("file name isn't allowed here")]
+    FilenameDenied,
+    #[error("file is infected with {0}")]
+    Infected(String),
+    #[error("file is in quarantine")]
+    Quarantined,
+}
+
+impl Error {
+    /// Whether the error means there's nothing at the path, whether the
+    /// backend said so itself or the OS did.
+    #[must_use]
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Self::FileNotFound => true,
+            Self::IoError { source, .. } => source.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// The stable code for this error.
+    #[must_use]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::IoError { .. } => ErrorCode::Io,
+            Self::InvalidPath(_) => ErrorCode::InvalidPath,
+            Self::UnsupportedMethod => ErrorCode::Unsupported,
+            Self::NotAFile => ErrorCode::NotAFile,
+            Self::NotADirectory => ErrorCode::NotADirectory,
+            Self::FileNotFound => ErrorCode::NotFound,
+            Self::WouldEscape => ErrorCode::OutsideRoot,
+            Self::OpenForWrite | Self::RangeLocked => ErrorCode::Locked,
+            Self::ChangeTokenMismatch => ErrorCode::Conflict,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::TaskPanicked(_) => ErrorCode::Internal,
+            Self::ReadOnly => ErrorCode::ReadOnly,
+            Self::LegalHold => ErrorCode::LegalHold,
+            Self::Immutable => ErrorCode::Immutable,
+            Self::Retained => ErrorCode::Retained,
+            Self::NoSpace => ErrorCode::NoSpace,
+            Self::NoMatchingLock => ErrorCode::BadRequest,
+            Self::SymlinkDenied | Self::FilenameDenied | Self::Quarantined => {
+                ErrorCode::PermissionDenied
+            }
+            Self::Infected(_) => ErrorCode::Infected,
+        }
+    }
 }
@@ -0,0 +1,101 @@
+use std::io;
+
+use camino::Utf8Path;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+
+use super::{Error, FilenameFilterOptions, PatternSyntax, error::IntoIoError};
+
+/// Enforces
+/// [`MountOptions::filename_filter`](super::MountOptions::filename_filter) for
+/// a mount.
+///
+/// Only the last component of a path is matched, so the patterns apply in
+/// every directory of the mount alike.
+pub(super) struct FilenameFilter {
+    deny: Patterns,
+    allow: Option<Patterns>,
+}
+
+enum Patterns {
+    Glob(GlobSet),
+    Regex(RegexSet),
+}
+
+impl FilenameFilter {
+    pub(super) fn new(options: Option<&FilenameFilterOptions>) -> Result<Option<Self>, Error> {
+        let Some(options) = options else {
+            return Ok(None);
+        };
+
+        let allow = if options.allow.is_empty() {
+            None
+        } else {
+            Some(Patterns::new(&options.allow, options.syntax)?)
+        };
+
+        Ok(Some(Self {
+            deny: Patterns::new(&options.deny, options.syntax)?,
+            allow,
+        }))
+    }
+
+    /// Fails with [`Error::FilenameDenied`] if a file can't be created at
+    /// `path`.
+    pub(super) fn check_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_dir(path)?;
+
+        match (&self.allow, path.file_name()) {
+            (Some(allow), Some(name)) if !allow.is_match(name) => Err(Error::FilenameDenied),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with [`Error::FilenameDenied`] if a directory can't be created at
+    /// `path`. Directories only have to stay clear of the denied patterns.
+    pub(super) fn check_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        match path.file_name() {
+            Some(name) if self.deny.is_match(name) => Err(Error::FilenameDenied),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Patterns {
+    fn new(patterns: &[String], syntax: PatternSyntax) -> Result<Self, Error> {
+        match syntax {
+            PatternSyntax::Glob => {
+                let mut builder = GlobSetBuilder::new();
+                for pattern in patterns {
+                    builder.add(
+                        Glob::new(pattern)
+                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+                            .into_io_error(format!("invalid filename pattern {pattern:?}"))?,
+                    );
+                }
+
+                let set = builder
+                    .build()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+                    .into_io_error("invalid filename patterns")?;
+
+                Ok(Self::Glob(set))
+            }
+            PatternSyntax::Regex => {
+                // Patterns have to match the whole name, like globs do.
+                let set = RegexSet::new(patterns.iter().map(|pattern| format!("^(?:{pattern})$")))
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+                    .into_io_error("invalid filename patterns")?;
+
+                Ok(Self::Regex(set))
+            }
+        }
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(set) => set.is_match(name),
+            Self::Regex(set) => set.is_match(name),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use camino::Utf8Path;
+use metrics::gauge;
+use tracing::{Level, event};
+
+use super::{Error, FreeSpaceOptions, VfsInstance};
+use crate::metrics::Metrics;
+
+/// Enforces [`MountOptions::free_space`](super::MountOptions::free_space) for
+/// a mount.
+///
+/// The free space is only looked at when a file is opened for writing, so
+/// uploads that are already in progress when it runs low are left to finish.
+pub(super) struct FreeSpace {
+    options: Option<FreeSpaceOptions>,
+    /// Whether the mount was below the warning watermark the last time it was
+    /// looked at, so that the warning is only logged when that changes.
+    low: AtomicBool,
+}
+
+impl FreeSpace {
+    pub(super) fn new(options: Option<FreeSpaceOptions>) -> Self {
+        Self {
+            options,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Fails with [`Error::NoSpace`] if `vfs` has less free space than a new
+    /// upload needs.
+    #[allow(clippy::cast_precision_loss)]
+    pub(super) async fn check_writable(&self, vfs: &VfsInstance) -> Result<(), Error> {
+        let Some(options) = &self.options else {
+            return Ok(());
+        };
+
+        let metadata = match vfs.statvfs(Utf8Path::new(".")).await {
+            Ok(metadata) => metadata,
+            // There's nothing to go by, so the backend will have to refuse the
+            // upload itself if it runs out.
+            Err(Error::UnsupportedMethod) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let free = metadata.free_blocks.saturating_mul(metadata.block_size);
+        let low = free < options.warn_below;
+        let vfs_root = vfs.vfs_root().to_string();
+
+        gauge!(Metrics::MOUNT_FREE_BYTES, "mount" => vfs_root.clone()).set(free as f64);
+        gauge!(Metrics::MOUNT_LOW_SPACE, "mount" => vfs_root.clone()).set(f64::from(u8::from(low)));
+
+        if self.low.swap(low, Ordering::Relaxed) != low {
+            if low {
+                event!(Level::WARN, %vfs_root, free, "mount is running low on space");
+            } else {
+                event!(Level::INFO, %vfs_root, free, "mount has enough free space again");
+            }
+        }
+
+        if free < options.reject_below {
+            Err(Error::NoSpace)
+        } else {
+            Ok(())
+        }
+    }
+}
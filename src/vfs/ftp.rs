@@ -0,0 +1,831 @@
+use std::{io, io::SeekFrom, ops::Range, path::PathBuf, sync::Arc, time::SystemTime};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use deadpool::{Runtime, managed};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use suppaftp::{
+    FtpError,
+    Status,
+    list::File as ListEntry,
+    tokio::{AsyncRustlsConnector, AsyncRustlsFtpStream},
+    types::{FileType, Mode},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex as AsyncMutex, broadcast},
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{ClientConfig, RootCertStore},
+};
+use tracing::{Level, event};
+use url::Url;
+
+use super::{
+    ChangeEvent,
+    Error,
+    FtpMode,
+    FtpOptions,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// A backend that re-exports a directory on an FTP server, so that the server
+/// doesn't have to be exposed itself.
+///
+/// `ftps://` URLs upgrade the connection with `AUTH TLS` before logging in,
+/// and protect the data connections too. Implicit FTPS isn't supported.
+///
+/// Connections are pooled, since an FTP connection can only carry one transfer
+/// at a time. A file that's being read keeps a connection to itself for as
+/// long as it's read in order, and jumping around in it starts a new transfer.
+/// Files that are open for writing are spooled to a local temporary file,
+/// which is uploaded when the file is synced or closed. Links and setting file
+/// times aren't supported.
+pub struct FtpDir {
+    vfs_path: Utf8PathBuf,
+    root: Utf8PathBuf,
+    pool: managed::Pool<FtpConnectionManager>,
+    open_files: Mutex<HashMap<String, Arc<AsyncMutex<OpenFile>>>>,
+    open_dirs: Mutex<HashMap<String, Utf8PathBuf>>,
+}
+
+type Connection = managed::Object<FtpConnectionManager>;
+
+struct OpenFile {
+    path: Utf8PathBuf,
+    /// The local copy of a file that's open for writing.
+    spool: Option<File>,
+    /// Whether the spool has changes that haven't been uploaded yet.
+    dirty: bool,
+    /// The transfer of a file that's being read, if one is in progress.
+    reader: Option<Reader>,
+}
+
+struct Reader {
+    connection: Connection,
+    stream: Box<dyn AsyncRead + Send + Unpin>,
+    /// How far into the file the transfer is.
+    position: u64,
+}
+
+pub(super) struct FtpConnectionManager {
+    addr: String,
+    host: String,
+    tls: Option<Arc<ClientConfig>>,
+    username: String,
+    password: String,
+    mode: Mode,
+}
+
+impl managed::Manager for FtpConnectionManager {
+    type Type = AsyncRustlsFtpStream;
+    type Error = FtpError;
+
+    async fn create(&self) -> Result<AsyncRustlsFtpStream, FtpError> {
+        let mut ftp = AsyncRustlsFtpStream::connect(&self.addr).await?;
+
+        if let Some(tls) = &self.tls {
+            let connector = AsyncRustlsConnector::from(TlsConnector::from(tls.clone()));
+            ftp = ftp.into_secure(connector, &self.host).await?;
+        }
+
+        ftp.login(&self.username, &self.password).await?;
+        ftp.set_mode(self.mode);
+        ftp.transfer_type(FileType::Binary).await?;
+
+        Ok(ftp)
+    }
+
+    async fn recycle(
+        &self,
+        ftp: &mut AsyncRustlsFtpStream,
+        _: &managed::Metrics,
+    ) -> managed::RecycleResult<FtpError> {
+        ftp.noop().await?;
+
+        Ok(())
+    }
+}
+
+impl FtpDir {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        url: &Url,
+        password_file: Option<&Utf8Path>,
+        mode: FtpMode,
+        max_connections: usize,
+        options: &FtpOptions,
+    ) -> Result<Self, Error> {
+        let tls = match url.scheme() {
+            "ftp" => None,
+            "ftps" => {
+                let mut roots = RootCertStore::empty();
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+                Some(Arc::new(
+                    ClientConfig::builder()
+                        .with_root_certificates(roots)
+                        .with_no_client_auth(),
+                ))
+            }
+            _ => return Err(Error::InvalidPath(PathBuf::from(url.as_str()))),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::InvalidPath(PathBuf::from(url.as_str())))?
+            .to_owned();
+        let root = match percent_decode_str(url.path()).decode_utf8() {
+            Ok(path) if path.is_empty() || path == "/" => Utf8PathBuf::from("."),
+            Ok(path) => Utf8PathBuf::from(&*path),
+            Err(_) => return Err(Error::InvalidPath(PathBuf::from(url.path()))),
+        };
+
+        let username = match url.username() {
+            "" => "anonymous".to_owned(),
+            username => percent_decode_str(username)
+                .decode_utf8_lossy()
+                .into_owned(),
+        };
+        let password = match password_file {
+            Some(password_file) => std::fs::read_to_string(password_file)
+                .map(|password| password.trim_end().to_owned())
+                .into_io_error("failed to read FTP password")?,
+            None => "anonymous@".to_owned(),
+        };
+
+        let manager = FtpConnectionManager {
+            addr: format!("{host}:{}", url.port().unwrap_or(21)),
+            host,
+            tls,
+            username,
+            password,
+            mode: match mode {
+                FtpMode::Passive => Mode::Passive,
+                FtpMode::ExtendedPassive => Mode::ExtendedPassive,
+                FtpMode::Active => Mode::Active,
+            },
+        };
+        let pool = managed::Pool::builder(manager)
+            .runtime(Runtime::Tokio1)
+            .create_timeout(Some(options.connect_timeout))
+            .max_size(max_connections)
+            .build()
+            .map_err(io::Error::other)
+            .into_io_error("failed to create FTP connection pool")?;
+
+        Ok(Self {
+            vfs_path,
+            root,
+            pool,
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        })
+    }
+
+    async fn connection(&self) -> Result<Connection, Error> {
+        self.pool
+            .get()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to connect to FTP server")
+    }
+
+    /// The path on the FTP server of `path`.
+    fn remote_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let mut remote_path = self.root.clone();
+
+        for component in path.components() {
+            match component {
+                Utf8Component::Normal(name) => remote_path.push(name),
+                Utf8Component::CurDir => (),
+                _ => return Err(Error::InvalidPath(PathBuf::from(path))),
+            }
+        }
+
+        Ok(remote_path)
+    }
+
+    /// The entries in the directory at `remote_path`, from `LIST`.
+    async fn list(&self, remote_path: &Utf8Path) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut connection = self.connection().await?;
+        let lines = connection
+            .list(Some(remote_path.as_str()))
+            .await
+            .map_err(|err| ftp_error(&err, "failed to list directory"))?;
+
+        Ok(lines
+            .iter()
+            .filter_map(|line| {
+                let Ok(entry) = line.parse::<ListEntry>() else {
+                    event!(Level::DEBUG, line, "skipping unparseable FTP listing line");
+                    return None;
+                };
+                if entry.name() == "." || entry.name() == ".." {
+                    return None;
+                }
+
+                let metadata = Metadata {
+                    size: (!entry.is_directory()).then_some(entry.size() as u64),
+                    mtime: Some(entry.modified()),
+                    is_directory: entry.is_directory(),
+                    ..Metadata::default()
+                };
+
+                Some((Utf8PathBuf::from(entry.name()), metadata))
+            })
+            .collect())
+    }
+
+    /// The metadata of the file at `remote_path`. FTP has no command that
+    /// every server supports for this, so it comes from listing the directory
+    /// the file is in.
+    async fn stat_remote(&self, remote_path: &Utf8Path) -> Result<Metadata, Error> {
+        let (Some(parent), Some(name)) = (remote_path.parent(), remote_path.file_name()) else {
+            // The root of the server, or the directory logged in to.
+            return Ok(Metadata {
+                is_directory: true,
+                ..Metadata::default()
+            });
+        };
+        if remote_path == self.root {
+            return Ok(Metadata {
+                is_directory: true,
+                ..Metadata::default()
+            });
+        }
+
+        let parent = if parent.as_str().is_empty() {
+            Utf8Path::new(".")
+        } else {
+            parent
+        };
+
+        self.list(parent)
+            .await?
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, metadata)| metadata)
+            .ok_or(Error::FileNotFound)
+    }
+
+    /// Starts a transfer of the file at `remote_path`, from `offset` onwards.
+    async fn start_read(&self, remote_path: &Utf8Path, offset: u64) -> Result<Reader, Error> {
+        let mut connection = self.connection().await?;
+
+        if offset > 0 {
+            connection
+                .resume_transfer(usize::try_from(offset).unwrap_or(usize::MAX))
+                .await
+                .map_err(|err| ftp_error(&err, "failed to read file"))?;
+        }
+        let stream = connection
+            .retr_as_stream(remote_path.as_str())
+            .await
+            .map_err(|err| ftp_error(&err, "failed to read file"))?;
+
+        Ok(Reader {
+            connection,
+            stream: Box::new(stream),
+            position: offset,
+        })
+    }
+
+    /// Fetches the whole file at `remote_path` into `spool`.
+    async fn download(&self, remote_path: &Utf8Path, spool: &mut File) -> Result<(), Error> {
+        let mut reader = self.start_read(remote_path, 0).await?;
+
+        if let Err(err) = tokio::io::copy(&mut reader.stream, spool).await {
+            discard(reader);
+            return Err(err).into_io_error("failed to download file");
+        }
+
+        finish(reader).await
+    }
+
+    /// Uploads the spool of `file`, if it has changed since it was last
+    /// uploaded.
+    async fn upload(&self, file: &mut OpenFile) -> Result<(), Error> {
+        let Some(spool) = &file.spool else {
+            return Ok(());
+        };
+        if !file.dirty {
+            return Ok(());
+        }
+
+        let mut body = spool
+            .try_clone()
+            .await
+            .into_io_error("failed to read spool file")?;
+        body.seek(SeekFrom::Start(0))
+            .await
+            .into_io_error("failed to read spool file")?;
+
+        let mut connection = self.connection().await?;
+        connection
+            .put_file(file.path.as_str(), &mut body)
+            .await
+            .map_err(|err| ftp_error(&err, "failed to upload file"))?;
+        file.dirty = false;
+
+        Ok(())
+    }
+
+    fn get_file(&self, handle: &Handle) -> Result<Arc<AsyncMutex<OpenFile>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+#[async_trait]
+impl Vfs for FtpDir {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+
+        let exists = match self.stat(path).await {
+            Ok(metadata) if metadata.is_directory => return Err(Error::NotAFile),
+            Ok(_) => true,
+            Err(Error::FileNotFound) if writing && flags.contains(OpenFlags::CREATE) => false,
+            Err(err) => return Err(err),
+        };
+
+        if exists && flags.contains(OpenFlags::EXCLUDE) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error(format!("couldn't open file {path}"));
+        }
+
+        let remote_path = self.remote_path(path)?;
+        let spool = if writing {
+            let mut spool =
+                File::from_std(tempfile::tempfile().into_io_error("failed to create spool file")?);
+
+            if exists && !flags.contains(OpenFlags::TRUNCATE) {
+                self.download(&remote_path, &mut spool).await?;
+            }
+
+            Some(spool)
+        } else {
+            None
+        };
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        let mut file = OpenFile {
+            path: remote_path,
+            spool,
+            // New and truncated files have to be created on the server even if
+            // nothing is ever written to them.
+            dirty: writing && (!exists || flags.contains(OpenFlags::TRUNCATE)),
+            reader: None,
+        };
+
+        if file.dirty {
+            self.upload(&mut file).await?;
+        }
+
+        self.open_files
+            .lock()
+            .insert(vfs_handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        if !self.stat(path).await?.is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs
+            .lock()
+            .insert(vfs_handle.clone(), self.remote_path(path)?);
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => {
+                let file = self.open_files.lock().remove(handle.vfs_handle());
+
+                if let Some(file) = file {
+                    let mut file = file.lock().await;
+
+                    if let Some(reader) = file.reader.take() {
+                        discard(reader);
+                    }
+                    self.upload(&mut file).await?;
+                }
+            }
+            HandleType::Dir => {
+                self.open_dirs.lock().remove(handle.vfs_handle());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::empty()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        if let Some(spool) = &mut file.spool {
+            let mut buf = Vec::with_capacity(len);
+
+            spool
+                .seek(SeekFrom::Start(offset))
+                .await
+                .into_io_error("failed to seek spool file")?;
+            let bytes_read = spool
+                .take(len as u64)
+                .read_to_end(&mut buf)
+                .await
+                .into_io_error("failed to read spool file")?;
+
+            return Ok((bytes_read != 0 || len == 0).then_some(buf));
+        }
+
+        if len == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        // A transfer can only go forwards, so anywhere else needs a new one.
+        let mut reader = match file.reader.take() {
+            Some(reader) if reader.position == offset => reader,
+            reader => {
+                if let Some(reader) = reader {
+                    discard(reader);
+                }
+
+                self.start_read(&file.path, offset).await?
+            }
+        };
+
+        let mut buf = Vec::with_capacity(len);
+        if let Err(err) = (&mut reader.stream)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .await
+        {
+            discard(reader);
+            return Err(err).into_io_error("failed to read file");
+        }
+        reader.position += buf.len() as u64;
+
+        if buf.len() < len {
+            finish(reader).await?;
+        } else {
+            file.reader = Some(reader);
+        }
+
+        Ok((!buf.is_empty()).then_some(buf))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self
+            .open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)?;
+
+        self.list(&path).await
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .seek(SeekFrom::Start(offset))
+            .await
+            .into_io_error("failed to seek spool file")?;
+        spool
+            .write_all(data)
+            .await
+            .into_io_error("failed to write spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        if handle.handle_type() == HandleType::Dir {
+            if !self.open_dirs.lock().contains_key(handle.vfs_handle()) {
+                return Err(Error::FileNotFound);
+            }
+
+            return Ok(Metadata {
+                is_directory: true,
+                ..Metadata::default()
+            });
+        }
+
+        let file = self.get_file(handle)?;
+        let file = file.lock().await;
+
+        match &file.spool {
+            Some(spool) => {
+                let metadata = spool
+                    .metadata()
+                    .await
+                    .into_io_error("failed to get spool file metadata")?;
+
+                Ok(Metadata {
+                    size: Some(metadata.len()),
+                    mtime: metadata.modified().ok(),
+                    ..Metadata::default()
+                })
+            }
+            None => self.stat_remote(&file.path).await,
+        }
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        self.upload(&mut file).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+
+        connection
+            .rename(
+                self.remote_path(from)?.as_str(),
+                self.remote_path(to)?.as_str(),
+            )
+            .await
+            .map_err(|err| ftp_error(&err, "failed to rename file"))
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.stat_remote(&self.remote_path(path)?).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // Links on the server are served as what they point to.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, _path: &Utf8Path) -> Result<FsMetadata, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn statvfs_fd(&self, _handle: &Handle) -> Result<FsMetadata, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let mut reader = self.start_read(&self.remote_path(path)?, offset).await?;
+        let mut hasher = BlockHasher::new(algorithm, len, block_size);
+        let mut buf = vec![0u8; 64 * 1024];
+
+        loop {
+            let wanted = hasher.wanted(buf.len());
+            if wanted == 0 {
+                // The rest of the file is never read, so the transfer has to
+                // be abandoned.
+                discard(reader);
+                return Ok(hasher.finish());
+            }
+
+            match reader.stream.read(&mut buf[..wanted]).await {
+                Ok(0) => break,
+                Ok(bytes_read) => hasher.update(&buf[..bytes_read]),
+                Err(err) => {
+                    discard(reader);
+                    return Err(err).into_io_error("failed to read file");
+                }
+            }
+        }
+
+        finish(reader).await?;
+
+        Ok(hasher.finish())
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+
+        connection
+            .mkdir(self.remote_path(path)?.as_str())
+            .await
+            .map_err(|err| ftp_error(&err, "failed to create directory"))
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+
+        connection
+            .rm(self.remote_path(path)?.as_str())
+            .await
+            .map_err(|err| ftp_error(&err, "failed to remove file"))
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+
+        connection
+            .rmdir(self.remote_path(path)?.as_str())
+            .await
+            .map_err(|err| ftp_error(&err, "failed to remove directory"))
+    }
+
+    async fn set_times(
+        &self,
+        _path: &Utf8Path,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_times_fd(
+        &self,
+        _handle: &Handle,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        let handle = self.open(path, OpenFlags::WRITE).await?;
+        let result = self.truncate_fd(&handle, len).await;
+        let closed = self.close(handle).await;
+
+        result.and(closed)
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .set_len(len)
+            .await
+            .into_io_error("failed to truncate spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.stat(Utf8Path::new(".")).await.map(drop)
+    }
+}
+
+/// Ends a transfer that has been read to the end, and puts its connection
+/// back in the pool.
+async fn finish(mut reader: Reader) -> Result<(), Error> {
+    reader
+        .connection
+        .finalize_retr_stream(reader.stream)
+        .await
+        .map_err(|err| ftp_error(&err, "failed to read file"))
+}
+
+/// Abandons a transfer. The server still has to answer for it, so its
+/// connection is closed instead of going back to the pool.
+fn discard(reader: Reader) {
+    drop(reader.stream);
+    drop(managed::Object::take(reader.connection));
+}
+
+/// The error for an FTP command that failed.
+fn ftp_error(err: &FtpError, context: &str) -> Error {
+    let kind = match err {
+        FtpError::UnexpectedResponse(response) => match response.status {
+            Status::FileUnavailable => return Error::FileNotFound,
+            Status::BadFilename => io::ErrorKind::InvalidInput,
+            Status::NotLoggedIn | Status::InvalidCredentials => io::ErrorKind::PermissionDenied,
+            Status::ExceededStorage => io::ErrorKind::StorageFull,
+            _ => io::ErrorKind::Other,
+        },
+        FtpError::ConnectionError(err) => err.kind(),
+        _ => io::ErrorKind::Other,
+    };
+
+    Error::IoError {
+        source: io::Error::new(kind, err.to_string()),
+        from: context.to_owned(),
+    }
+}
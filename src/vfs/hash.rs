@@ -0,0 +1,207 @@
+//! Hashing for [`Vfs::hash_range`](super::Vfs::hash_range).
+//!
+//! Backends read the range however suits them and feed it to a
+//! [`BlockHasher`], which cuts it into blocks and keeps the digest of each.
+//! Files that are already open are hashed through their handle with
+//! [`hash_handle`].
+
+use std::fmt::Write;
+
+use digest::DynDigest;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use super::{Error, Handle, Vfs};
+
+/// How much of a file [`hash_handle`] reads at a time.
+const HANDLE_CHUNK_SIZE: usize = 256 * 1024;
+
+/// A hash algorithm that files can be hashed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The algorithm that the `md5sum`-style command `command` uses.
+    #[must_use]
+    pub fn for_command(command: &str) -> Option<Self> {
+        match command {
+            "md5sum" => Some(Self::Md5),
+            "sha1sum" => Some(Self::Sha1),
+            "sha256sum" => Some(Self::Sha256),
+            "sha512sum" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The algorithm the `check-file` extension calls `name`.
+    #[must_use]
+    pub fn for_name(name: &str) -> Option<Self> {
+        match name {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha256" => Some(Self::Sha256),
+            "sha512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// The algorithm's name, as the `check-file` extension spells it.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            Self::Md5 => Box::new(Md5::default()),
+            Self::Sha1 => Box::new(Sha1::default()),
+            Self::Sha256 => Box::new(Sha256::default()),
+            Self::Sha512 => Box::new(Sha512::default()),
+        }
+    }
+}
+
+/// Hex-encodes `digest`, the way checksums are usually printed.
+#[must_use]
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Hashes the bytes of a range as they're read, finishing a digest at the end
+/// of every block.
+pub(super) struct BlockHasher {
+    hasher: Box<dyn DynDigest + Send>,
+    block_size: u64,
+    in_block: u64,
+    remaining: Option<u64>,
+    digests: Vec<Vec<u8>>,
+}
+
+impl BlockHasher {
+    /// A hasher for `len` bytes, or up to the end of the file if it's `0`, in
+    /// blocks of `block_size` bytes, or all in one if it's `0`.
+    pub(super) fn new(algorithm: HashAlgorithm, len: u64, block_size: u64) -> Self {
+        Self {
+            hasher: algorithm.hasher(),
+            block_size: if block_size == 0 {
+                u64::MAX
+            } else {
+                block_size
+            },
+            in_block: 0,
+            remaining: (len != 0).then_some(len),
+            digests: Vec::new(),
+        }
+    }
+
+    /// Hashes the next bytes of the range. Anything past the end of the range
+    /// is ignored.
+    pub(super) fn update(&mut self, mut data: &[u8]) {
+        if let Some(remaining) = &mut self.remaining {
+            let len = usize::try_from(*remaining).map_or(data.len(), |r| r.min(data.len()));
+            data = &data[..len];
+            *remaining -= len as u64;
+        }
+
+        while !data.is_empty() {
+            let left_in_block = self.block_size - self.in_block;
+            let take = usize::try_from(left_in_block).map_or(data.len(), |n| n.min(data.len()));
+
+            self.hasher.update(&data[..take]);
+            self.in_block += take as u64;
+            data = &data[take..];
+
+            if self.in_block == self.block_size {
+                self.digests.push(self.hasher.finalize_reset().into_vec());
+                self.in_block = 0;
+            }
+        }
+    }
+
+    /// How many bytes to read next, at most `max`, or `0` once the range has
+    /// all been hashed.
+    pub(super) fn wanted(&self, max: usize) -> usize {
+        match self.remaining {
+            Some(remaining) => usize::try_from(remaining).map_or(max, |r| r.min(max)),
+            None => max,
+        }
+    }
+
+    /// Whether the whole range has been hashed. A range that runs to the end of
+    /// the file is only done when the reader says so.
+    pub(super) fn is_done(&self) -> bool {
+        self.remaining == Some(0)
+    }
+
+    /// The digests of every block, including the last one if the range ended
+    /// part of the way through it. An empty range has the digest of nothing.
+    pub(super) fn finish(mut self) -> Vec<Vec<u8>> {
+        if self.in_block > 0 || self.digests.is_empty() {
+            self.digests.push(self.hasher.finalize_reset().into_vec());
+        }
+
+        self.digests
+    }
+}
+
+/// Hashes a range of the file open at `handle` like [`Vfs::hash_range`] does
+/// for a path, by reading it through `vfs`. This sees what has been written
+/// through the handle even where the path would lead somewhere else, like to
+/// the previous version of an upload that hasn't been renamed into place yet.
+pub async fn hash_handle(
+    vfs: &(impl Vfs + ?Sized),
+    handle: &Handle,
+    algorithm: HashAlgorithm,
+    mut offset: u64,
+    len: u64,
+    block_size: u64,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut hasher = BlockHasher::new(algorithm, len, block_size);
+
+    loop {
+        let wanted = hasher.wanted(HANDLE_CHUNK_SIZE);
+        if wanted == 0 {
+            break;
+        }
+
+        match vfs.read(handle, offset, wanted).await? {
+            Some(chunk) if !chunk.is_empty() => {
+                hasher.update(&chunk);
+                offset += chunk.len() as u64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Hashes a range of `data` that's already in memory, for backends that keep
+/// files there.
+pub(super) fn hash_bytes(
+    data: &[u8],
+    algorithm: HashAlgorithm,
+    offset: u64,
+    len: u64,
+    block_size: u64,
+) -> Vec<Vec<u8>> {
+    let start = usize::try_from(offset).map_or(data.len(), |offset| offset.min(data.len()));
+    let mut hasher = BlockHasher::new(algorithm, len, block_size);
+    hasher.update(&data[start..]);
+
+    hasher.finish()
+}
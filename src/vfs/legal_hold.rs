@@ -0,0 +1,106 @@
+use std::collections::BTreeSet;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{
+    Error,
+    state_file::{self, normalize},
+};
+
+/// The paths in a mount that are under legal hold, see
+/// [`MountOptions::legal_hold_file`](super::MountOptions::legal_hold_file).
+///
+/// Holding a path holds everything beneath it, too. Paths are relative to the
+/// root of the mount.
+pub(super) struct LegalHolds {
+    file: Option<Utf8PathBuf>,
+    paths: Mutex<BTreeSet<Utf8PathBuf>>,
+    save: AsyncMutex<()>,
+}
+
+impl LegalHolds {
+    pub(super) fn load(file: Option<Utf8PathBuf>) -> Result<Self, Error> {
+        let paths = match &file {
+            Some(file) => state_file::load(file)?,
+            None => BTreeSet::new(),
+        };
+
+        Ok(Self {
+            file,
+            paths: Mutex::new(paths),
+            save: AsyncMutex::new(()),
+        })
+    }
+
+    /// Whether `path`, or a directory containing it, is under legal hold.
+    pub(super) fn is_held(&self, path: &Utf8Path) -> bool {
+        let path = normalize(path);
+
+        self.paths.lock().iter().any(|held| path.starts_with(held))
+    }
+
+    /// Fails with [`Error::LegalHold`] if `path` is under legal hold.
+    pub(super) fn check(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.is_held(path) {
+            Err(Error::LegalHold)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`Error::LegalHold`] if `path`, or anything beneath it, is
+    /// under legal hold. This is the check for moving a whole directory.
+    pub(super) fn check_tree(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path);
+        let held = self
+            .paths
+            .lock()
+            .iter()
+            .any(|held| path.starts_with(held) || held.starts_with(&path));
+
+        if held { Err(Error::LegalHold) } else { Ok(()) }
+    }
+
+    /// The held paths.
+    pub(super) fn list(&self) -> Vec<Utf8PathBuf> {
+        self.paths.lock().iter().cloned().collect()
+    }
+
+    /// Places `path` under legal hold. Returns `false` if it already was.
+    pub(super) async fn hold(&self, path: &Utf8Path) -> Result<bool, Error> {
+        self.update(|paths| paths.insert(normalize(path))).await
+    }
+
+    /// Releases the legal hold on `path`. Returns `false` if there wasn't one.
+    pub(super) async fn release(&self, path: &Utf8Path) -> Result<bool, Error> {
+        self.update(|paths| paths.remove(&normalize(path))).await
+    }
+
+    async fn update(
+        &self,
+        change: impl FnOnce(&mut BTreeSet<Utf8PathBuf>) -> bool,
+    ) -> Result<bool, Error> {
+        let Some(file) = &self.file else {
+            return Err(Error::UnsupportedMethod);
+        };
+
+        // Holds the save lock across the change, so saves land in the same order
+        // as the changes they save.
+        let _guard = self.save.lock().await;
+
+        let (changed, paths) = {
+            let mut paths = self.paths.lock();
+            let changed = change(&mut paths);
+
+            (changed, paths.clone())
+        };
+
+        if changed {
+            state_file::save(file, &paths).await?;
+        }
+
+        Ok(changed)
+    }
+}
@@ -1,6 +1,11 @@
 use std::{
     io,
-    io::{SeekFrom, Write},
+    io::SeekFrom,
+    ops::Range,
+    os::{
+        fd::AsFd,
+        unix::fs::{FileExt, PermissionsExt},
+    },
     path::PathBuf,
     sync::Arc,
     time::SystemTime,
@@ -9,51 +14,121 @@ use std::{
 use ahash::RandomState;
 use async_trait::async_trait;
 use base64ct::{Base64, Encoding};
-use camino::{Utf8Path, Utf8PathBuf};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use cap_fs_ext::DirExtUtf8;
 use cap_std::{
     ambient_authority,
-    fs_utf8::{Dir, File},
+    fs::{OpenOptions, Permissions},
+    fs_utf8::{Dir, File, ReadDir},
 };
-use digest::OutputSizeUser;
-use generic_array::GenericArray;
-use md5::Md5;
+use parking_lot::Mutex;
 use rand::Rng;
-use sha1::Sha1;
+use rustix::{
+    fs::{FallocateFlags, FlockOperation},
+    io::Errno,
+};
 use sha2::{Digest, Sha256};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{OnceCell, broadcast},
+};
+use tokio_util::sync::CancellationToken;
 use whirlwind::ShardMap;
 
+#[cfg(feature = "io-uring")]
+use super::uring::Uring;
 use super::{
+    ChangeEvent,
     Error,
     Handle,
     HandleType,
+    HashAlgorithm,
+    IoEngine,
+    LocalDirOptions,
+    SymlinkPolicy,
     Vfs,
-    options::{FsMetadata, Metadata, OpenFlags},
+    blocking,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags, STAT_BLOCK_SIZE},
+    watch::Watcher,
 };
 use crate::vfs::error::IntoIoError;
 
+/// How much of a file is read at a time while hashing it.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How much of a file one blocking task hashes before handing the rest on to
+/// the next.
+const HASH_SLICE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// How much of a file `copy_file_range` is asked to copy at a time, so that a
+/// cancelled copy stops before long.
+const COPY_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// How much of a file is read at a time when it has to be copied by hand.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How big a run of zeroes in a write has to be to be left as a hole. It's
+/// the smallest block size filesystems use, and holes can't be any smaller
+/// than a block.
+const HOLE_BLOCK_SIZE: u64 = 4096;
+
+/// A backend that exposes a directory on the local filesystem.
+///
+/// Every path is opened relative to the root directory through `cap-std`,
+/// which refuses `..`, absolute symlinks, and any other way out of it. On Linux
+/// 5.6 and later, it does so with `openat2(RESOLVE_BENEATH)`, so that escaping
+/// the root is prevented by the kernel rather than by checks in userspace.
 pub struct LocalDir {
     vfs_path: Utf8PathBuf,
     root_path: Utf8PathBuf,
     root_dir: Arc<Dir>,
     open_files: ShardMap<String, File, ahash::RandomState>,
     open_dirs: ShardMap<String, Dir, ahash::RandomState>,
+    /// How far [`Vfs::read_dir_next`] has got through each open directory,
+    /// started the first time it's called on the handle.
+    dir_cursors: ShardMap<String, Arc<Mutex<Option<ReadDir>>>, ahash::RandomState>,
+    symlinks: SymlinkPolicy,
+    /// Watches paths for [`Vfs::watch`], started the first time it's needed.
+    watcher: OnceCell<Watcher>,
+    /// Where reads and writes go when the mount's `io_engine` is `io_uring`.
+    #[cfg(feature = "io-uring")]
+    uring: Option<&'static Uring>,
 }
 
 impl LocalDir {
-    pub fn new(vfs_path: Utf8PathBuf, root_path: Utf8PathBuf) -> Result<Self, Error> {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        root_path: Utf8PathBuf,
+        options: &LocalDirOptions,
+    ) -> Result<Self, Error> {
         let root_dir = Arc::new(
             Dir::open_ambient_dir(root_path.as_path(), ambient_authority())
                 .into_io_error("failed to open LocalDir root")?,
         );
 
+        #[cfg(feature = "io-uring")]
+        let uring = match options.io_engine {
+            IoEngine::Blocking => None,
+            IoEngine::IoUring => Some(Uring::get().into_io_error("failed to start io_uring")?),
+        };
+        #[cfg(not(feature = "io-uring"))]
+        if options.io_engine == IoEngine::IoUring {
+            return Err(io::Error::from(io::ErrorKind::Unsupported))
+                .into_io_error("Schlep was built without io_uring support");
+        }
+
         Ok(Self {
             vfs_path,
             root_path,
             root_dir,
             open_files: ShardMap::with_hasher(RandomState::default()),
             open_dirs: ShardMap::with_hasher(RandomState::default()),
+            dir_cursors: ShardMap::with_hasher(RandomState::default()),
+            symlinks: options.symlinks,
+            watcher: OnceCell::new(),
+            #[cfg(feature = "io-uring")]
+            uring,
         })
     }
 
@@ -84,32 +159,81 @@ impl LocalDir {
         }
     }
 
-    async fn hash<Hash: Digest + Write>(
+    /// Applies `operation` to the whole file open at `handle` with `flock`.
+    /// Unlike `fcntl` locks, those belong to the open file rather than the
+    /// process, so the handles of different clients don't share their locks,
+    /// and closing a duplicate of the file doesn't release them.
+    async fn lock_file(&self, handle: &Handle, operation: FlockOperation) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let file = self.get_file(handle).await?;
+
+        match rustix::fs::flock(&file, operation) {
+            Ok(()) => Ok(()),
+            Err(Errno::WOULDBLOCK) => Err(Error::RangeLocked),
+            Err(err) => Err(io::Error::from(err)).into_io_error("failed to lock file"),
+        }
+    }
+
+    /// Hashes the range a slice at a time, each on its own blocking task, so
+    /// that a huge file doesn't hold a thread until it's done.
+    async fn hash_slices(
         &self,
         path: &Utf8Path,
-    ) -> Result<GenericArray<u8, <Hash as OutputSizeUser>::OutputSize>, Error> {
+        mut offset: u64,
+        mut hasher: BlockHasher,
+    ) -> Result<BlockHasher, Error> {
         let root_dir = self
             .root_dir
             .try_clone()
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        let hash = tokio::task::spawn_blocking(move || {
-            let mut file = root_dir.open(path).into_io_error("failed opening file")?;
-            let mut hasher = Hash::new();
-            io::copy(&mut file, &mut hasher).into_io_error("failed to hash file")?;
-            Ok(hasher.finalize())
+        let mut file = blocking::run(move |_| {
+            Ok(root_dir
+                .open(path)
+                .into_io_error("failed opening file")?
+                .into_std())
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
+        .await?;
+
+        loop {
+            let done;
+            (file, hasher, offset, done) = blocking::run(move |cancel| {
+                let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+                let slice_end = offset.saturating_add(HASH_SLICE_SIZE);
+
+                while offset < slice_end {
+                    if cancel.is_cancelled() {
+                        return Err(Error::Cancelled);
+                    }
+
+                    let wanted = hasher.wanted(buf.len());
+                    if wanted == 0 {
+                        return Ok((file, hasher, offset, true));
+                    }
+
+                    let bytes_read = file
+                        .read_at(&mut buf[..wanted], offset)
+                        .into_io_error("failed to hash file")?;
+                    if bytes_read == 0 {
+                        return Ok((file, hasher, offset, true));
+                    }
+
+                    hasher.update(&buf[..bytes_read]);
+                    offset += bytes_read as u64;
+                }
 
-            panic!("task failed: {e}");
-        })?;
+                Ok((file, hasher, offset, false))
+            })
+            .await?;
 
-        Ok(hash)
+            if done {
+                return Ok(hasher);
+            }
+        }
     }
 }
 
@@ -119,19 +243,12 @@ impl Vfs for LocalDir {
         let root_dir = self.root_dir.clone();
         let path_buf = Utf8PathBuf::from(path);
 
-        let file = tokio::task::spawn_blocking(move || {
+        let file = blocking::run(move |_| {
             root_dir
                 .open_with(&path_buf, &OpenFlags::into(flags))
                 .into_io_error(format!("couldn't open file {path_buf}"))
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         let mut hasher = Sha256::new();
         let mut salt = [0u8; 32];
@@ -152,19 +269,12 @@ impl Vfs for LocalDir {
         let root_dir = self.root_dir.clone();
         let path_buf = Utf8PathBuf::from(path);
 
-        let dir = tokio::task::spawn_blocking(move || {
+        let dir = blocking::run(move |_| {
             root_dir
                 .open_dir(&path_buf)
                 .into_io_error("couldn't open directory {path_buf}")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         let mut hasher = Sha256::new();
         let mut salt = [0u8; 32];
@@ -189,9 +299,9 @@ impl Vfs for LocalDir {
                     .await;
             }
             HandleType::Dir => {
-                self.open_dirs
-                    .remove(&String::from(handle.vfs_handle()))
-                    .await;
+                let vfs_handle = String::from(handle.vfs_handle());
+                self.open_dirs.remove(&vfs_handle).await;
+                self.dir_cursors.remove(&vfs_handle).await;
             }
         }
 
@@ -217,6 +327,16 @@ impl Vfs for LocalDir {
         self.vfs_path.as_path()
     }
 
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::SYMLINKS
+            | Capabilities::HARDLINKS
+            | Capabilities::STATVFS
+            | Capabilities::LOCKS
+            | Capabilities::PERMISSIONS
+            | Capabilities::WATCH
+            | Capabilities::ATOMIC_RENAME
+    }
+
     async fn read(
         &self,
         handle: &Handle,
@@ -224,9 +344,30 @@ impl Vfs for LocalDir {
         len: usize,
     ) -> Result<Option<Vec<u8>>, Error> {
         if handle.handle_type() == HandleType::File {
-            let mut buf: Vec<u8> = Vec::with_capacity(len);
             let mut file = self.get_file(handle).await?;
 
+            // Holes read as zeroes, so only the parts of a sparse file that
+            // have data in them are read from disk.
+            if is_sparse(&file) {
+                let file = file.into_std().await;
+                let buf = blocking::run(move |_| read_sparse(&file, offset, len)).await?;
+
+                return Ok((!buf.is_empty() || len == 0).then_some(buf));
+            }
+
+            #[cfg(feature = "io-uring")]
+            if let Some(uring) = self.uring {
+                let file = file.into_std().await;
+                let buf = uring
+                    .read(file, offset, len)
+                    .await
+                    .into_io_error("failed to read file")?;
+
+                return Ok((!buf.is_empty() || len == 0).then_some(buf));
+            }
+
+            let mut buf: Vec<u8> = Vec::with_capacity(len);
+
             file.seek(SeekFrom::Start(offset))
                 .await
                 .into_io_error("failed to seek file")?;
@@ -251,7 +392,7 @@ impl Vfs for LocalDir {
         if handle.handle_type() == HandleType::Dir {
             let dir = self.get_dir(handle).await?;
 
-            let entries = tokio::task::spawn_blocking(move || {
+            let entries = blocking::run(move |_| {
                 let mut files = Vec::new();
 
                 for entry in dir
@@ -270,14 +411,7 @@ impl Vfs for LocalDir {
 
                 Ok(files)
             })
-            .await
-            .unwrap_or_else(|e| {
-                if e.is_panic() {
-                    std::panic::resume_unwind(e.into_panic());
-                }
-
-                panic!("task failed: {e}");
-            })?;
+            .await?;
 
             Ok(entries)
         } else {
@@ -285,10 +419,83 @@ impl Vfs for LocalDir {
         }
     }
 
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let dir = self.get_dir(handle).await?;
+        let vfs_handle = String::from(handle.vfs_handle());
+        let existing = self
+            .dir_cursors
+            .get(&vfs_handle)
+            .await
+            .map(|cursor| cursor.value().clone());
+        let cursor = if let Some(cursor) = existing {
+            cursor
+        } else {
+            let cursor = Arc::new(Mutex::new(None));
+            self.dir_cursors.insert(vfs_handle, cursor.clone()).await;
+            cursor
+        };
+
+        blocking::run(move |_| {
+            let mut cursor = cursor.lock();
+            let mut entries = match cursor.take() {
+                Some(entries) => entries,
+                None => dir
+                    .entries()
+                    .into_io_error("couldn't get directory entries")?,
+            };
+
+            let mut files = Vec::new();
+            while files.len() < max {
+                let Some(entry) = entries.next() else {
+                    break;
+                };
+                let entry = entry.into_io_error("couldn't get directory entry")?;
+
+                let file_name = entry.file_name().into_io_error("couldn't get file name")?;
+                let metadata = dir
+                    .metadata(&file_name)
+                    .into_io_error("couldn't get file metadata")?;
+
+                files.push((Utf8PathBuf::from(file_name), Metadata::from(metadata)));
+            }
+
+            *cursor = Some(entries);
+            Ok(files)
+        })
+        .await
+    }
+
     async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
         if handle.handle_type() == HandleType::File {
             let mut file = self.get_file(handle).await?;
 
+            // Blocks of zeroes are left as holes instead of being written, so
+            // that sparse files like VM images stay sparse when uploaded.
+            let runs = sparse_runs(offset, data);
+            if runs.iter().any(|(_, hole)| *hole) {
+                let file = file.into_std().await;
+                let data = data.to_vec();
+
+                return blocking::run(move |_| write_sparse(&file, offset, &data, &runs)).await;
+            }
+
+            #[cfg(feature = "io-uring")]
+            if let Some(uring) = self.uring {
+                let file = file.into_std().await;
+                return uring
+                    .write(file, offset, data.to_vec())
+                    .await
+                    .into_io_error("failed to write file");
+            }
+
             file.seek(SeekFrom::Start(offset))
                 .await
                 .into_io_error("failed to seek file")?;
@@ -316,18 +523,11 @@ impl Vfs for LocalDir {
         } else {
             let dir = self.get_dir(handle).await?;
 
-            let metadata = tokio::task::spawn_blocking(move || {
+            let metadata = blocking::run(move |_| {
                 dir.dir_metadata()
                     .into_io_error("failed to get directory metadata")
             })
-            .await
-            .unwrap_or_else(|e| {
-                if e.is_panic() {
-                    std::panic::resume_unwind(e.into_panic());
-                }
-
-                panic!("task failed: {e}");
-            })?;
+            .await?;
 
             Ok(Metadata::from(metadata))
         }
@@ -353,19 +553,12 @@ impl Vfs for LocalDir {
         let from = from.to_owned();
         let to = to.to_owned();
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .rename(from, &root_dir, to)
                 .into_io_error("failed to rename file")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -377,19 +570,12 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        let metadata = tokio::task::spawn_blocking(move || {
+        let metadata = blocking::run(move |_| {
             root_dir
                 .metadata(path)
                 .into_io_error("failed to get symlink metadata")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(Metadata::from(metadata))
     }
@@ -401,19 +587,12 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        let metadata = tokio::task::spawn_blocking(move || {
+        let metadata = blocking::run(move |_| {
             root_dir
                 .symlink_metadata(path)
                 .into_io_error("failed to get symlink metadata")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(Metadata::from(metadata))
     }
@@ -425,21 +604,36 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        let fs_metadata = tokio::task::spawn_blocking(move || {
+        let fs_metadata = blocking::run(move |_| {
             let file = root_dir.open(&path).into_io_error("failed to open file")?;
             let fs_metadata = rustix::fs::fstatvfs(&file).map_err(|err| {
                 io::Error::from(err).into_io_error("failed to get filesystem metadata")
             })?;
             Ok(fs_metadata)
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
+        .await?;
 
-            panic!("task failed: {e}");
-        })?;
+        Ok(fs_metadata.into())
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        let fs_metadata = if handle.handle_type() == HandleType::File {
+            let file = self.get_file(handle).await?;
+            blocking::run(move |_| {
+                rustix::fs::fstatvfs(&file).map_err(|err| {
+                    io::Error::from(err).into_io_error("failed to get filesystem metadata")
+                })
+            })
+            .await?
+        } else {
+            let dir = self.get_dir(handle).await?;
+            blocking::run(move |_| {
+                rustix::fs::fstatvfs(&dir).map_err(|err| {
+                    io::Error::from(err).into_io_error("failed to get filesystem metadata")
+                })
+            })
+            .await?
+        };
 
         Ok(fs_metadata.into())
     }
@@ -452,19 +646,12 @@ impl Vfs for LocalDir {
         let source = source.to_owned();
         let target = target.to_owned();
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .hard_link(source, &root_dir, target)
                 .into_io_error("failed to create hardlink")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -474,39 +661,36 @@ impl Vfs for LocalDir {
             .root_dir
             .try_clone()
             .into_io_error("failed to get root directory handle")?;
+        if self.symlinks == SymlinkPolicy::Deny {
+            return Err(Error::SymlinkDenied);
+        }
+
         let path = path.to_owned();
-        let relative_target = pathdiff::diff_utf8_paths(target, &path)
+        let link_dir = path.parent().unwrap_or(Utf8Path::new(""));
+        let target = pathdiff::diff_utf8_paths(target, link_dir)
             .ok_or_else(|| Error::InvalidPath(PathBuf::from(target)))?;
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
-                .symlink(path, relative_target)
-                .into_io_error("failed to remove file")
+                .symlink(target, path)
+                .into_io_error("failed to create symlink")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
 
-    async fn md5sum(
+    async fn hash_range(
         &self,
         path: &Utf8Path,
-    ) -> Result<GenericArray<u8, <Md5 as OutputSizeUser>::OutputSize>, Error> {
-        self.hash::<Md5>(path).await
-    }
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let hasher = BlockHasher::new(algorithm, len, block_size);
 
-    async fn sha1sum(
-        &self,
-        path: &Utf8Path,
-    ) -> Result<GenericArray<u8, <Sha1 as OutputSizeUser>::OutputSize>, Error> {
-        self.hash::<Sha1>(path).await
+        Ok(self.hash_slices(path, offset, hasher).await?.finish())
     }
 
     async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
@@ -514,35 +698,37 @@ impl Vfs for LocalDir {
             .root_dir
             .try_clone()
             .into_io_error("failed to get root directory handle")?;
+        if self.symlinks == SymlinkPolicy::Deny {
+            return Err(Error::SymlinkDenied);
+        }
+
         let root_path = self.root_path.clone();
+        let vfs_path = self.vfs_path.clone();
+        let symlinks = self.symlinks;
         let path = path.to_owned();
 
-        let link_contents = tokio::task::spawn_blocking(move || {
+        let link_contents = blocking::run(move |_| {
             let link_contents = root_dir
-                .read_link_contents(path)
+                .read_link_contents(&path)
                 .into_io_error("failed to read symlink")?;
 
             if link_contents.is_absolute() {
-                if link_contents.starts_with(&root_path) {
-                    let relative_path =
-                        pathdiff::diff_utf8_paths(&link_contents, &root_path).unwrap();
-
-                    Ok(relative_path)
-                } else {
-                    Err(Error::WouldEscape)
+                // Absolute links into the root are rewritten to where the
+                // client sees that place, and ones out of it aren't shown at
+                // all, since they'd give away where the root is on the host.
+                match link_contents.strip_prefix(&root_path) {
+                    Ok(relative_path) => Ok(vfs_path.join(relative_path)),
+                    Err(_) => Err(Error::WouldEscape),
                 }
-            } else {
+            } else if symlinks == SymlinkPolicy::Preserve
+                || stays_within_root(&path, &link_contents)
+            {
                 Ok(link_contents)
+            } else {
+                Err(Error::WouldEscape)
             }
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(link_contents)
     }
@@ -554,19 +740,12 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .create_dir(path)
                 .into_io_error("failed to create directory")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -578,19 +757,12 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .remove_file(path)
                 .into_io_error("failed to remove file")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -602,19 +774,12 @@ impl Vfs for LocalDir {
             .into_io_error("failed to get root directory handle")?;
         let path = path.to_owned();
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .remove_dir(path)
                 .into_io_error("failed to remove directory")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -639,19 +804,12 @@ impl Vfs for LocalDir {
         let atime = atime.map(convert_system_time);
         let mtime = mtime.map(convert_system_time);
 
-        tokio::task::spawn_blocking(move || {
+        blocking::run(move |_| {
             root_dir
                 .set_times(path, atime, mtime)
                 .into_io_error("failed to set times")
         })
-        .await
-        .unwrap_or_else(|e| {
-            if e.is_panic() {
-                std::panic::resume_unwind(e.into_panic());
-            }
-
-            panic!("task failed: {e}");
-        })?;
+        .await?;
 
         Ok(())
     }
@@ -669,22 +827,410 @@ impl Vfs for LocalDir {
             let atime = atime.map(SystemTimeSpec::Absolute);
             let mtime = mtime.map(SystemTimeSpec::Absolute);
 
-            tokio::task::spawn_blocking(move || {
+            blocking::run(move |_| {
                 file.set_times(atime, mtime)
                     .into_io_error("failed to set times")
             })
-            .await
-            .unwrap_or_else(|e| {
-                if e.is_panic() {
-                    std::panic::resume_unwind(e.into_panic());
-                }
-
-                panic!("task failed: {e}");
-            })?;
+            .await?;
 
             Ok(())
         } else {
             Err(Error::NotAFile)
         }
     }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        let root_dir = self
+            .root_dir
+            .try_clone()
+            .into_io_error("failed to get root directory handle")?;
+        let path = path.to_owned();
+        let permissions = Permissions::from_std(std::fs::Permissions::from_mode(mode));
+
+        blocking::run(move |_| {
+            root_dir
+                .set_permissions(path, permissions)
+                .into_io_error("failed to set permissions")
+        })
+        .await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.get_file(handle)
+            .await?
+            .set_permissions(std::fs::Permissions::from_mode(mode))
+            .await
+            .into_io_error("failed to set permissions")
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        let root_dir = self.root_dir.clone();
+        let path = path.to_owned();
+
+        blocking::run(move |_| {
+            root_dir
+                .open_with(&path, OpenOptions::new().write(true))
+                .into_io_error(format!("couldn't open file {path}"))?
+                .set_len(len)
+                .into_io_error("failed to truncate file")
+        })
+        .await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.get_file(handle)
+            .await?
+            .set_len(len)
+            .await
+            .into_io_error("failed to truncate file")
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        // The OS locks whole files only, so byte ranges are left to the mount.
+        if range != (0..u64::MAX) {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        let operation = match mode {
+            LockMode::Shared => FlockOperation::NonBlockingLockShared,
+            LockMode::Exclusive => FlockOperation::NonBlockingLockExclusive,
+        };
+
+        self.lock_file(handle, operation).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        if range != (0..u64::MAX) {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        self.lock_file(handle, FlockOperation::NonBlockingUnlock)
+            .await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        if src.handle_type() != HandleType::File || dst.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let src = self.get_file(src).await?.into_std().await;
+        let dst = self.get_file(dst).await?.into_std().await;
+
+        blocking::run(move |token| copy_file_range(token, &src, src_offset, &dst, dst_offset, len))
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let file = self.get_file(handle).await?;
+
+        blocking::run(move |_| {
+            match rustix::fs::fallocate(&file, FallocateFlags::KEEP_SIZE, offset, len) {
+                Ok(()) => Ok(()),
+                Err(Errno::NOSPC) => Err(Error::NoSpace),
+                Err(Errno::OPNOTSUPP) => Err(Error::UnsupportedMethod),
+                Err(err) => {
+                    Err(io::Error::from(err)).into_io_error("failed to allocate file space")
+                }
+            }
+        })
+        .await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        // inotify is given the path by name, so it's looked up through the
+        // root first to make sure it doesn't lead out of it.
+        let root_dir = self.root_dir.clone();
+        let path_buf = path.to_owned();
+        blocking::run(move |_| {
+            root_dir
+                .symlink_metadata(&path_buf)
+                .into_io_error(format!("failed to watch {path_buf}"))
+        })
+        .await?;
+
+        let watcher = self
+            .watcher
+            .get_or_try_init(|| async { Watcher::new(self.root_path.clone()) })
+            .await?;
+
+        watcher.watch(path)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        // The root is looked up again, since the directory that's open stays
+        // usable even if it's removed or the filesystem it was on goes away.
+        let root_path = self.root_path.clone();
+
+        blocking::run(move |_| {
+            if std::fs::metadata(&root_path)
+                .into_io_error("failed to find LocalDir root")?
+                .is_dir()
+            {
+                Ok(())
+            } else {
+                Err(Error::NotADirectory)
+            }
+        })
+        .await
+    }
+}
+
+/// Copies up to `len` bytes from `src` to `dst` with `copy_file_range`, so
+/// that the kernel or the filesystem does it. Where they can't, like between
+/// filesystems on older kernels, it falls back to reading and writing.
+fn copy_file_range(
+    token: &CancellationToken,
+    src: &std::fs::File,
+    mut src_offset: u64,
+    dst: &std::fs::File,
+    mut dst_offset: u64,
+    len: u64,
+) -> Result<u64, Error> {
+    let mut copied = 0;
+
+    while copied < len {
+        if token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let chunk = usize::try_from(len - copied)
+            .unwrap_or(usize::MAX)
+            .min(COPY_CHUNK_SIZE);
+        let n = match rustix::fs::copy_file_range(
+            src,
+            Some(&mut src_offset),
+            dst,
+            Some(&mut dst_offset),
+            chunk,
+        ) {
+            Ok(n) => n,
+            Err(Errno::XDEV | Errno::NOSYS | Errno::INVAL | Errno::OPNOTSUPP) => {
+                let rest = copy_by_reading(token, src, src_offset, dst, dst_offset, len - copied)?;
+                return Ok(copied + rest);
+            }
+            Err(err) => {
+                return Err(io::Error::from(err)).into_io_error("failed to copy file range");
+            }
+        };
+
+        if n == 0 {
+            break;
+        }
+        copied += n as u64;
+    }
+
+    Ok(copied)
+}
+
+/// Copies up to `len` bytes from `src` to `dst` by reading and writing them.
+fn copy_by_reading(
+    token: &CancellationToken,
+    src: &std::fs::File,
+    mut src_offset: u64,
+    dst: &std::fs::File,
+    mut dst_offset: u64,
+    len: u64,
+) -> Result<u64, Error> {
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut copied = 0;
+
+    while copied < len {
+        if token.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        let chunk = usize::try_from(len - copied)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        let n = src
+            .read_at(&mut buf[..chunk], src_offset)
+            .into_io_error("failed to read file")?;
+        if n == 0 {
+            break;
+        }
+
+        dst.write_all_at(&buf[..n], dst_offset)
+            .into_io_error("failed to write file")?;
+
+        src_offset += n as u64;
+        dst_offset += n as u64;
+        copied += n as u64;
+    }
+
+    Ok(copied)
+}
+
+/// Whether the file has holes in it, going by how much of it is allocated.
+/// `fstat` on an open file only looks at its inode, which is already in
+/// memory, so it's cheap enough to do before every read.
+fn is_sparse(file: &impl AsFd) -> bool {
+    rustix::fs::fstat(file).is_ok_and(|stat| {
+        let allocated = u64::try_from(stat.st_blocks).unwrap_or_default() * STAT_BLOCK_SIZE;
+
+        allocated < u64::try_from(stat.st_size).unwrap_or_default()
+    })
+}
+
+/// Reads up to `len` bytes of `file` at `offset`, filling in the holes with
+/// zeroes instead of reading them.
+fn read_sparse(file: &std::fs::File, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let size = file
+        .metadata()
+        .into_io_error("failed to get file metadata")?
+        .len();
+    let end = offset.saturating_add(len as u64).min(size);
+    let mut buf = vec![0u8; usize::try_from(end.saturating_sub(offset)).unwrap()];
+    let mut position = offset;
+
+    while position < end {
+        let data_start = match seek_sparse(file, rustix::fs::SeekFrom::Data, position)? {
+            Some(data_start) if data_start < end => data_start,
+            _ => break,
+        };
+        let data_end = seek_sparse(file, rustix::fs::SeekFrom::Hole, data_start)?
+            .unwrap_or(end)
+            .min(end);
+
+        let start = usize::try_from(data_start - offset).unwrap();
+        let stop = usize::try_from(data_end - offset).unwrap();
+        file.read_exact_at(&mut buf[start..stop], data_start)
+            .into_io_error("failed to read file")?;
+
+        position = data_end;
+    }
+
+    Ok(buf)
+}
+
+/// Finds the start of the next run of data or hole in `file` from `position`
+/// with `lseek`, or returns [`None`] if there isn't one before the end.
+fn seek_sparse(
+    file: &std::fs::File,
+    whence: fn(i64) -> rustix::fs::SeekFrom,
+    position: u64,
+) -> Result<Option<u64>, Error> {
+    let position = i64::try_from(position).unwrap_or(i64::MAX);
+
+    match rustix::fs::seek(file, whence(position)) {
+        Ok(found) => Ok(Some(found)),
+        Err(Errno::NXIO) => Ok(None),
+        Err(err) => Err(io::Error::from(err)).into_io_error("failed to find holes in file"),
+    }
+}
+
+/// Splits a write of `data` at `offset` into runs that are either whole
+/// blocks of zeroes, which can be left as holes, or have to be written.
+fn sparse_runs(offset: u64, data: &[u8]) -> Vec<(Range<usize>, bool)> {
+    let mut runs: Vec<(Range<usize>, bool)> = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        // Blocks line up with the file's, so a write that doesn't start on a
+        // boundary starts with part of one.
+        let block_end = ((offset + start as u64) / HOLE_BLOCK_SIZE + 1) * HOLE_BLOCK_SIZE;
+        let end = usize::try_from(block_end - offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let block = &data[start..end];
+        let hole = block.len() as u64 == HOLE_BLOCK_SIZE && block.iter().all(|byte| *byte == 0);
+
+        match runs.last_mut() {
+            Some((range, last_hole)) if *last_hole == hole => range.end = end,
+            _ => runs.push((start..end, hole)),
+        }
+        start = end;
+    }
+
+    runs
+}
+
+/// Writes `data` to `file` at `offset`, punching holes for the `runs` that
+/// are holes. Where the filesystem can't punch holes, they're written as
+/// zeroes.
+fn write_sparse(
+    file: &std::fs::File,
+    offset: u64,
+    data: &[u8],
+    runs: &[(Range<usize>, bool)],
+) -> Result<(), Error> {
+    for (range, hole) in runs {
+        let run_offset = offset + range.start as u64;
+        if *hole && punch_hole(file, run_offset, range.len() as u64)? {
+            continue;
+        }
+
+        file.write_all_at(&data[range.clone()], run_offset)
+            .into_io_error("failed to write file")?;
+    }
+
+    // Punching a hole doesn't change the size of the file, so a write that
+    // ends in one has to extend the file itself. Each session's requests are
+    // handled one at a time, so this can't cut off a write that's further
+    // along.
+    let end = offset + data.len() as u64;
+    let size = file
+        .metadata()
+        .into_io_error("failed to get file metadata")?
+        .len();
+    if size < end {
+        file.set_len(end).into_io_error("failed to extend file")?;
+    }
+
+    Ok(())
+}
+
+/// Deallocates `len` bytes of `file` at `offset`, which then read as zeroes.
+/// Returns `false` if the filesystem can't.
+fn punch_hole(file: &std::fs::File, offset: u64, len: u64) -> Result<bool, Error> {
+    match rustix::fs::fallocate(
+        file,
+        FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE,
+        offset,
+        len,
+    ) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP) => Ok(false),
+        Err(err) => Err(io::Error::from(err)).into_io_error("failed to punch hole in file"),
+    }
+}
+
+/// Whether the relative `target` of the link at `path` leads somewhere inside
+/// the root once its `..`s have been followed.
+fn stays_within_root(path: &Utf8Path, target: &Utf8Path) -> bool {
+    let mut depth = path.parent().map_or(0, |link_dir| {
+        link_dir
+            .components()
+            .filter(|component| matches!(component, Utf8Component::Normal(_)))
+            .count()
+    });
+
+    for component in target.components() {
+        match component {
+            Utf8Component::Normal(_) => depth += 1,
+            Utf8Component::ParentDir if depth == 0 => return false,
+            Utf8Component::ParentDir => depth -= 1,
+            _ => {}
+        }
+    }
+
+    true
 }
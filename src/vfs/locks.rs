@@ -0,0 +1,176 @@
+use std::ops::Range;
+
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+
+use super::{Error, Handle, LockMode, state_file::normalize};
+
+/// The advisory locks that clients of a mount hold on its files, see
+/// [`Vfs::lock`](super::Vfs::lock).
+///
+/// Locks are kept per path, so they're enforced between every handle the
+/// mount opened on a file, whether or not the backend has locks of its own.
+/// Reads and writes through other handles are refused while they conflict with
+/// a lock, and so are truncating, removing, and renaming a locked file.
+#[derive(Default)]
+pub(super) struct Locks {
+    /// The path every open file handle was opened with.
+    handles: Mutex<HashMap<Handle, Utf8PathBuf>>,
+    held: Mutex<HashMap<Utf8PathBuf, Vec<Lock>>>,
+}
+
+struct Lock {
+    handle: Handle,
+    range: Range<u64>,
+    mode: LockMode,
+}
+
+impl Lock {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+}
+
+impl Locks {
+    pub(super) fn opened(&self, handle: &Handle, path: &Utf8Path) {
+        self.handles.lock().insert(handle.clone(), normalize(path));
+    }
+
+    /// Forgets `handle`, releasing all of its locks.
+    pub(super) fn closed(&self, handle: &Handle) {
+        let Some(path) = self.handles.lock().remove(handle) else {
+            return;
+        };
+
+        let mut held = self.held.lock();
+        if let Some(locks) = held.get_mut(&path) {
+            locks.retain(|lock| lock.handle != *handle);
+            if locks.is_empty() {
+                held.remove(&path);
+            }
+        }
+    }
+
+    /// Locks `range` for `handle`, returning the mode the handle had it locked
+    /// with before, if it did.
+    pub(super) fn lock(
+        &self,
+        handle: &Handle,
+        range: Range<u64>,
+        mode: LockMode,
+    ) -> Result<Option<LockMode>, Error> {
+        let path = self.path(handle)?;
+        let mut held = self.held.lock();
+        let locks = held.entry(path).or_default();
+
+        let conflicting = locks.iter().any(|lock| {
+            lock.handle != *handle
+                && lock.overlaps(&range)
+                && (mode == LockMode::Exclusive || lock.mode == LockMode::Exclusive)
+        });
+        if conflicting {
+            return Err(Error::RangeLocked);
+        }
+
+        let existing = locks
+            .iter_mut()
+            .find(|lock| lock.handle == *handle && lock.range == range);
+        if let Some(existing) = existing {
+            return Ok(Some(std::mem::replace(&mut existing.mode, mode)));
+        }
+
+        locks.push(Lock {
+            handle: handle.clone(),
+            range,
+            mode,
+        });
+
+        Ok(None)
+    }
+
+    /// Releases the lock `handle` has on exactly `range`.
+    pub(super) fn unlock(&self, handle: &Handle, range: &Range<u64>) -> Result<(), Error> {
+        let path = self.path(handle)?;
+        let mut held = self.held.lock();
+        let locks = held.get_mut(&path).ok_or(Error::NoMatchingLock)?;
+
+        let position = locks
+            .iter()
+            .position(|lock| lock.handle == *handle && lock.range == *range)
+            .ok_or(Error::NoMatchingLock)?;
+        locks.remove(position);
+        if locks.is_empty() {
+            held.remove(&path);
+        }
+
+        Ok(())
+    }
+
+    /// Puts the lock `handle` had on `range` back the way it was before
+    /// [`Locks::lock`] returned `previous`, after the backend refused it.
+    pub(super) fn restore(&self, handle: &Handle, range: &Range<u64>, previous: Option<LockMode>) {
+        match previous {
+            Some(mode) => {
+                let _ = self.lock(handle, range.clone(), mode);
+            }
+            None => {
+                let _ = self.unlock(handle, range);
+            }
+        }
+    }
+
+    /// Fails with [`Error::RangeLocked`] if reading `range` through `handle`
+    /// would go against someone else's exclusive lock.
+    pub(super) fn check_read(&self, handle: &Handle, range: &Range<u64>) -> Result<(), Error> {
+        self.check(handle, range, |mode| mode == LockMode::Exclusive)
+    }
+
+    /// Fails with [`Error::RangeLocked`] if writing `range` through `handle`
+    /// would go against someone else's lock.
+    pub(super) fn check_write(&self, handle: &Handle, range: &Range<u64>) -> Result<(), Error> {
+        self.check(handle, range, |_| true)
+    }
+
+    /// Fails with [`Error::RangeLocked`] if anyone holds a lock on `path`.
+    pub(super) fn check_unlocked(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.held.lock().contains_key(&normalize(path)) {
+            Err(Error::RangeLocked)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check(
+        &self,
+        handle: &Handle,
+        range: &Range<u64>,
+        conflicts: impl Fn(LockMode) -> bool,
+    ) -> Result<(), Error> {
+        let Some(path) = self.handles.lock().get(handle).cloned() else {
+            return Ok(());
+        };
+
+        let held = self.held.lock();
+        let conflicting = held.get(&path).is_some_and(|locks| {
+            locks
+                .iter()
+                .any(|lock| lock.handle != *handle && lock.overlaps(range) && conflicts(lock.mode))
+        });
+
+        if conflicting {
+            Err(Error::RangeLocked)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The path the file open at `handle` was opened with.
+    pub(super) fn path(&self, handle: &Handle) -> Result<Utf8PathBuf, Error> {
+        self.handles
+            .lock()
+            .get(handle)
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+}
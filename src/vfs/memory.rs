@@ -0,0 +1,669 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::{Bound, Range},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    hash::hash_bytes,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// A backend that keeps its files in memory, and forgets them when the
+/// process exits.
+///
+/// It's meant for trying Schlep out and for tests, where there's no
+/// directory worth setting aside for the files. Symlinks aren't supported,
+/// and hard links only work within the same `MemoryDir`.
+pub struct MemoryDir {
+    vfs_path: Utf8PathBuf,
+    /// Every file and directory, keyed by its normalized path. The root is the
+    /// empty path.
+    entries: Mutex<BTreeMap<Utf8PathBuf, Arc<Mutex<Inode>>>>,
+    open_files: Mutex<HashMap<String, OpenFile>>,
+    open_dirs: Mutex<HashMap<String, Utf8PathBuf>>,
+    /// The last entry [`Vfs::read_dir_next`] listed from each open directory.
+    listed: Mutex<HashMap<String, Utf8PathBuf>>,
+}
+
+struct Inode {
+    is_directory: bool,
+    data: Vec<u8>,
+    atime: SystemTime,
+    mtime: SystemTime,
+    /// Bumped on every change, for [`Metadata::change_token`].
+    generation: u64,
+}
+
+impl Inode {
+    fn new(is_directory: bool) -> Self {
+        let now = SystemTime::now();
+
+        Self {
+            is_directory,
+            data: Vec::new(),
+            atime: now,
+            mtime: now,
+            generation: 0,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            size: (!self.is_directory).then_some(self.data.len() as u64),
+            atime: Some(self.atime),
+            mtime: Some(self.mtime),
+            is_directory: self.is_directory,
+            generation: Some(self.generation),
+            ..Metadata::default()
+        }
+    }
+
+    fn modified(&mut self) {
+        self.mtime = SystemTime::now();
+        self.generation += 1;
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        if self.is_directory {
+            return Err(Error::NotAFile);
+        }
+
+        self.data
+            .resize(usize::try_from(len).map_err(|_| Error::NoSpace)?, 0);
+        self.modified();
+
+        Ok(())
+    }
+}
+
+/// A file that is open, which keeps its contents around even if it's removed
+/// in the meantime.
+struct OpenFile {
+    inode: Arc<Mutex<Inode>>,
+    flags: OpenFlags,
+}
+
+impl MemoryDir {
+    #[must_use]
+    pub fn new(vfs_path: Utf8PathBuf) -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(Utf8PathBuf::new(), Arc::new(Mutex::new(Inode::new(true))));
+
+        Self {
+            vfs_path,
+            entries: Mutex::new(entries),
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+            listed: Mutex::default(),
+        }
+    }
+
+    fn inode(&self, path: &Utf8Path) -> Result<Arc<Mutex<Inode>>, Error> {
+        self.entries
+            .lock()
+            .get(&normalize(path)?)
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    fn open_file(&self, handle: &Handle) -> Result<(Arc<Mutex<Inode>>, OpenFlags), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .map(|file| (file.inode.clone(), file.flags))
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+/// Fails unless the parent of `path` is a directory in `entries`.
+fn check_parent(
+    entries: &BTreeMap<Utf8PathBuf, Arc<Mutex<Inode>>>,
+    path: &Utf8Path,
+) -> Result<(), Error> {
+    let parent = path.parent().ok_or(Error::InvalidPath(path.into()))?;
+
+    match entries.get(parent) {
+        Some(inode) if inode.lock().is_directory => Ok(()),
+        Some(_) => Err(Error::NotADirectory),
+        None => Err(Error::FileNotFound),
+    }
+}
+
+/// Fails because `path` is already taken.
+fn already_exists(path: &Utf8Path) -> Error {
+    io::Error::from(io::ErrorKind::AlreadyExists).into_io_error(format!("{path} already exists"))
+}
+
+#[async_trait]
+impl Vfs for MemoryDir {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let path = normalize(path)?;
+        let mut entries = self.entries.lock();
+
+        let inode = match entries.get(&path) {
+            Some(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUDE) => {
+                return Err(already_exists(&path));
+            }
+            Some(inode) => {
+                let mut locked = inode.lock();
+                if locked.is_directory {
+                    return Err(Error::NotAFile);
+                }
+
+                if flags.contains(OpenFlags::TRUNCATE) {
+                    locked.data.clear();
+                    locked.modified();
+                }
+                drop(locked);
+
+                inode.clone()
+            }
+            None if flags.contains(OpenFlags::CREATE) => {
+                check_parent(&entries, &path)?;
+
+                let inode = Arc::new(Mutex::new(Inode::new(false)));
+                entries.insert(path, inode.clone());
+
+                inode
+            }
+            None => return Err(Error::FileNotFound),
+        };
+        drop(entries);
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_files
+            .lock()
+            .insert(vfs_handle.clone(), OpenFile { inode, flags });
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let path = normalize(path)?;
+        if !self.inode(&path)?.lock().is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs.lock().insert(vfs_handle.clone(), path);
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().remove(handle.vfs_handle()).map(drop),
+            HandleType::Dir => {
+                self.listed.lock().remove(handle.vfs_handle());
+                self.open_dirs.lock().remove(handle.vfs_handle()).map(drop)
+            }
+        };
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::HARDLINKS | Capabilities::ATOMIC_RENAME
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (inode, _) = self.open_file(handle)?;
+        let mut inode = inode.lock();
+        inode.atime = SystemTime::now();
+
+        let Some(data) = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| inode.data.get(offset..))
+            .filter(|data| !data.is_empty() || len == 0)
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(data[..len.min(data.len())].to_vec()))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self
+            .open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)?;
+
+        Ok(self
+            .entries
+            .lock()
+            .range(path.clone()..)
+            .take_while(|(child, _)| child.starts_with(&path))
+            .filter(|(child, _)| child.parent() == Some(&path))
+            .filter_map(|(child, inode)| {
+                Some((
+                    Utf8PathBuf::from(child.file_name()?),
+                    inode.lock().metadata(),
+                ))
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self
+            .open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)?;
+
+        let mut listed = self.listed.lock();
+        let after = listed
+            .get(handle.vfs_handle())
+            .cloned()
+            .unwrap_or_else(|| path.clone());
+
+        let children: Vec<_> = self
+            .entries
+            .lock()
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take_while(|(child, _)| child.starts_with(&path))
+            .filter(|(child, _)| child.parent() == Some(&path))
+            .take(max)
+            .map(|(child, inode)| (child.clone(), inode.lock().metadata()))
+            .collect();
+
+        if let Some((last, _)) = children.last() {
+            listed.insert(String::from(handle.vfs_handle()), last.clone());
+        }
+
+        Ok(children
+            .into_iter()
+            .filter_map(|(child, metadata)| Some((Utf8PathBuf::from(child.file_name()?), metadata)))
+            .collect())
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let (inode, flags) = self.open_file(handle)?;
+        if !flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied)
+                .into_io_error("file isn't open for writing"));
+        }
+
+        let mut inode = inode.lock();
+        let start = if flags.contains(OpenFlags::APPEND) {
+            inode.data.len()
+        } else {
+            usize::try_from(offset).map_err(|_| Error::NoSpace)?
+        };
+        let end = start.checked_add(data.len()).ok_or(Error::NoSpace)?;
+
+        if inode.data.len() < end {
+            inode.data.resize(end, 0);
+        }
+        inode.data[start..end].copy_from_slice(data);
+        inode.modified();
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        match handle.handle_type() {
+            HandleType::File => Ok(self.open_file(handle)?.0.lock().metadata()),
+            HandleType::Dir => {
+                let path = self
+                    .open_dirs
+                    .lock()
+                    .get(handle.vfs_handle())
+                    .cloned()
+                    .ok_or(Error::FileNotFound)?;
+
+                self.stat(&path).await
+            }
+        }
+    }
+
+    async fn sync_fd(&self, _handle: &Handle) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let from = normalize(from)?;
+        let to = normalize(to)?;
+        if from.as_str().is_empty() || to.starts_with(&from) {
+            return Err(Error::InvalidPath(to.into()));
+        }
+
+        let mut entries = self.entries.lock();
+        if !entries.contains_key(&from) {
+            return Err(Error::FileNotFound);
+        }
+        check_parent(&entries, &to)?;
+
+        if let Some(existing) = entries.get(&to)
+            && existing.lock().is_directory
+        {
+            return Err(already_exists(&to));
+        }
+
+        // A directory takes everything in it along.
+        let moved = entries
+            .range(from.clone()..)
+            .take_while(|(path, _)| path.starts_with(&from))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        for path in moved {
+            if let Some(inode) = entries.remove(&path) {
+                let relative = path.strip_prefix(&from).unwrap_or(&path);
+                entries.insert(to.join(relative), inode);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        Ok(self.inode(path)?.lock().metadata())
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // There are no links to follow.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, _path: &Utf8Path) -> Result<FsMetadata, Error> {
+        // Memory isn't a filesystem, so there's nothing meaningful to report.
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn statvfs_fd(&self, _handle: &Handle) -> Result<FsMetadata, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hardlink(&self, source: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        let source = normalize(source)?;
+        let target = normalize(target)?;
+
+        let mut entries = self.entries.lock();
+        let inode = entries.get(&source).cloned().ok_or(Error::FileNotFound)?;
+        if inode.lock().is_directory {
+            return Err(Error::NotAFile);
+        }
+        if entries.contains_key(&target) {
+            return Err(already_exists(&target));
+        }
+        check_parent(&entries, &target)?;
+
+        entries.insert(target, inode);
+
+        Ok(())
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let inode = self.inode(path)?;
+        let inode = inode.lock();
+        if inode.is_directory {
+            return Err(Error::NotAFile);
+        }
+
+        Ok(hash_bytes(&inode.data, algorithm, offset, len, block_size))
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path)?;
+
+        let mut entries = self.entries.lock();
+        if entries.contains_key(&path) {
+            return Err(already_exists(&path));
+        }
+        check_parent(&entries, &path)?;
+
+        entries.insert(path, Arc::new(Mutex::new(Inode::new(true))));
+
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path)?;
+
+        let mut entries = self.entries.lock();
+        match entries.get(&path) {
+            Some(inode) if inode.lock().is_directory => Err(Error::NotAFile),
+            Some(_) => {
+                entries.remove(&path);
+                Ok(())
+            }
+            None => Err(Error::FileNotFound),
+        }
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path)?;
+        if path.as_str().is_empty() {
+            return Err(Error::InvalidPath(path.into()));
+        }
+
+        let mut entries = self.entries.lock();
+        match entries.get(&path) {
+            Some(inode) if !inode.lock().is_directory => return Err(Error::NotADirectory),
+            Some(_) => (),
+            None => return Err(Error::FileNotFound),
+        }
+
+        let has_children = entries
+            .range(path.clone()..)
+            .nth(1)
+            .is_some_and(|(child, _)| child.starts_with(&path));
+        if has_children {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty)
+                .into_io_error(format!("{path} isn't empty")));
+        }
+
+        entries.remove(&path);
+
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let inode = self.inode(path)?;
+        let mut inode = inode.lock();
+
+        if let Some(atime) = atime {
+            inode.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            inode.mtime = mtime;
+        }
+
+        Ok(())
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let (inode, _) = self.open_file(handle)?;
+        let mut inode = inode.lock();
+
+        if let Some(atime) = atime {
+            inode.atime = atime;
+        }
+        if let Some(mtime) = mtime {
+            inode.mtime = mtime;
+        }
+
+        Ok(())
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.inode(path)?.lock().truncate(len)
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let (inode, flags) = self.open_file(handle)?;
+        if !flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied)
+                .into_io_error("file isn't open for writing"));
+        }
+
+        inode.lock().truncate(len)
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        let (inode, _) = self.open_file(src)?;
+        let data = {
+            let inode = inode.lock();
+            let start = usize::try_from(src_offset)
+                .unwrap_or(usize::MAX)
+                .min(inode.data.len());
+            let len = usize::try_from(len)
+                .unwrap_or(usize::MAX)
+                .min(inode.data.len() - start);
+
+            inode.data[start..start + len].to_vec()
+        };
+
+        self.write(dst, dst_offset, &data).await?;
+
+        Ok(data.len() as u64)
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        let (inode, flags) = self.open_file(handle)?;
+        if !flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied)
+                .into_io_error("file isn't open for writing"));
+        }
+
+        let end = offset
+            .checked_add(len)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or(Error::NoSpace)?;
+        let mut inode = inode.lock();
+        let additional = end.saturating_sub(inode.data.len());
+
+        inode
+            .data
+            .try_reserve(additional)
+            .map_err(|_| Error::NoSpace)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Turns `path` into the key it has in [`MemoryDir::entries`], refusing paths
+/// that would leave the root.
+fn normalize(path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+    let mut normalized = Utf8PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(name) => normalized.push(name),
+            Utf8Component::CurDir | Utf8Component::RootDir => (),
+            _ => return Err(Error::InvalidPath(path.into())),
+        }
+    }
+
+    Ok(normalized)
+}
@@ -10,13 +10,69 @@
 //! `#[async_trait]`, the actual structure is simpler. It simply provides a
 //! uniform interface using uniform types for the servers and filesystem
 //! backends to communicate with each other.
+//!
+//! # Mounts
+//! Each [`Vfs`] in a [`VfsSet`] is wrapped in a [`Mount`], which applies the
+//! per-mount policies from the `fs` configuration (see [`MountOptions`]) before
+//! handing the operation to the backend. This keeps policy out of the
+//! individual backends, so every backend gets it for free.
 
+mod archive_dir;
+mod atomic_upload;
+mod audit_log;
+mod blocking;
+mod cached;
+mod compressed;
+mod config;
+mod deduplicated;
+mod encrypted_names;
 mod error;
+mod filename_filter;
+mod free_space;
+mod ftp;
+mod hash;
+mod legal_hold;
 mod local_dir;
+mod locks;
+mod memory;
+mod mount;
 mod options;
+mod postgres;
+mod readme;
+mod sequential;
+mod sftp_proxy;
+mod state_file;
+mod trash;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod versioned;
 mod vfs_trait;
+mod virus_scan;
+mod watch;
+mod webdav;
+mod webhdfs;
+mod worm;
 
+pub use archive_dir::ArchiveDir;
+pub use audit_log::AuditLog;
+pub use cached::Cached;
+pub use compressed::Compressed;
+pub use config::*;
+pub use deduplicated::Deduplicated;
+pub use encrypted_names::EncryptedNames;
 pub use error::Error;
+pub use ftp::FtpDir;
+pub use hash::{HashAlgorithm, hash_handle, to_hex};
 pub use local_dir::*;
+pub use memory::MemoryDir;
+pub use mount::Mount;
 pub use options::*;
+pub use postgres::Postgres;
+pub use readme::Readme;
+pub use sftp_proxy::SftpProxyDir;
+pub use trash::Trash;
+pub use versioned::Versioned;
 pub use vfs_trait::*;
+pub use watch::{ChangeEvent, ChangeKind};
+pub use webdav::WebDav;
+pub use webhdfs::WebHdfs;
@@ -0,0 +1,1451 @@
+use std::{io, ops::Range, sync::Arc, time::SystemTime};
+
+use ahash::{HashMap, HashSet};
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::{
+    sync::{Mutex as AsyncMutex, Notify, broadcast},
+    time::Instant,
+};
+use tracing::{Level, event};
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    ExclusiveWrites,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    MountOptions,
+    OpenFlags,
+    UploadCollisions,
+    Vfs,
+    VfsInstance,
+    atomic_upload::AtomicUploads,
+    error::IntoIoError,
+    filename_filter::FilenameFilter,
+    free_space::FreeSpace,
+    legal_hold::LegalHolds,
+    locks::Locks,
+    virus_scan::VirusScan,
+    worm::Worm,
+};
+use crate::throttle::Throttle;
+
+/// How many numbered names are tried for an upload under
+/// [`UploadCollisions::Rename`] before it fails.
+const MAX_RENAMES: u32 = 1000;
+
+/// A [`VfsInstance`] as it is mounted into a [`VfsSet`](super::VfsSet),
+/// together with the per-mount policies that apply regardless of which backend
+/// is providing the files.
+///
+/// A `Mount` is shared between every session that uses the same [`VfsSet`],
+/// so any state it keeps is visible across sessions.
+pub struct Mount {
+    id: u32,
+    vfs: Arc<VfsInstance>,
+    options: MountOptions,
+    writers: Writers,
+    appends: Appends,
+    uploads: AtomicUploads,
+    conditional_writes: AsyncMutex<()>,
+    legal_holds: LegalHolds,
+    locks: Locks,
+    worm: Worm,
+    virus_scan: VirusScan,
+    free_space: FreeSpace,
+    filename_filter: Option<FilenameFilter>,
+    bandwidth: Option<Throttle>,
+}
+
+/// Tracks which paths are currently open for writing, so that
+/// [`MountOptions::exclusive_writes`] can be enforced.
+#[derive(Default)]
+struct Writers {
+    paths: Mutex<HashSet<Utf8PathBuf>>,
+    handles: Mutex<HashMap<Handle, Utf8PathBuf>>,
+    released: Notify,
+}
+
+impl Writers {
+    fn try_claim(&self, path: &Utf8Path) -> bool {
+        self.paths.lock().insert(path.to_owned())
+    }
+
+    fn release_path(&self, path: &Utf8Path) {
+        self.paths.lock().remove(path);
+        self.released.notify_waiters();
+    }
+
+    fn release_handle(&self, handle: &Handle) {
+        let path = self.handles.lock().remove(handle);
+
+        if let Some(path) = path {
+            self.release_path(&path);
+        }
+    }
+}
+
+/// A path claimed from [`Writers`] by an open that isn't done yet. The claim
+/// is released when this is dropped, unless it has been handed over to the
+/// handle the open returned, so that an open that fails or is given up on
+/// part way doesn't keep the path claimed.
+struct Claim<'a> {
+    writers: &'a Writers,
+    path: Option<Utf8PathBuf>,
+}
+
+impl Claim<'_> {
+    /// Hands the claim over to `handle`, which releases it when it's closed.
+    fn keep(mut self, handle: &Handle) {
+        if let Some(path) = self.path.take() {
+            self.writers.handles.lock().insert(handle.clone(), path);
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            self.writers.release_path(&path);
+        }
+    }
+}
+
+/// The handles opened with [`OpenFlags::APPEND`].
+///
+/// Writes to an appended file go to its end, whatever offset the client sent
+/// with them. Clients don't agree on what to send: OpenSSH's `put -a` sends
+/// offsets from where the file ended when it was opened, and others send 0. The
+/// end is looked up again for every write, so that it follows truncation and
+/// other writers.
+#[derive(Default)]
+struct Appends {
+    handles: Mutex<HashSet<Handle>>,
+    /// Held from looking up where a file ends until the write there is done,
+    /// so that two appends can't both land at the same place.
+    writes: AsyncMutex<()>,
+}
+
+impl Appends {
+    fn opened(&self, handle: &Handle) {
+        self.handles.lock().insert(handle.clone());
+    }
+
+    fn contains(&self, handle: &Handle) -> bool {
+        self.handles.lock().contains(handle)
+    }
+
+    fn closed(&self, handle: &Handle) {
+        self.handles.lock().remove(handle);
+    }
+}
+
+impl Mount {
+    pub(super) fn new(id: u32, vfs: VfsInstance, options: MountOptions) -> Result<Self, Error> {
+        let legal_holds = LegalHolds::load(options.legal_hold_file.clone())?;
+        let worm = Worm::load(options.worm.clone())?;
+        let virus_scan = VirusScan::new(options.virus_scan.clone());
+        let free_space = FreeSpace::new(options.free_space.clone());
+        let filename_filter = FilenameFilter::new(options.filename_filter.as_ref())?;
+        let bandwidth = options.bandwidth_limit.map(Throttle::new);
+
+        Ok(Self {
+            id,
+            vfs: Arc::new(vfs),
+            options,
+            writers: Writers::default(),
+            appends: Appends::default(),
+            uploads: AtomicUploads::default(),
+            conditional_writes: AsyncMutex::new(()),
+            legal_holds,
+            locks: Locks::default(),
+            worm,
+            virus_scan,
+            free_space,
+            filename_filter,
+            bandwidth,
+        })
+    }
+
+    /// Identifies the mount in the handles it opens, see [`Handle::mount`].
+    #[must_use]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The policies that apply to this mount.
+    #[must_use]
+    pub fn options(&self) -> &MountOptions {
+        &self.options
+    }
+
+    /// Fails with [`Error::FilenameDenied`] if the mount's
+    /// [`MountOptions::filename_filter`] doesn't let a file be created at
+    /// `path`.
+    pub fn check_file_name(&self, path: &Utf8Path) -> Result<(), Error> {
+        match &self.filename_filter {
+            Some(filter) => filter.check_file(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with [`Error::FilenameDenied`] if the mount's
+    /// [`MountOptions::filename_filter`] doesn't let a directory be created
+    /// at `path`.
+    pub fn check_dir_name(&self, path: &Utf8Path) -> Result<(), Error> {
+        match &self.filename_filter {
+            Some(filter) => filter.check_dir(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Places `path` under legal hold, so that it can't be deleted, renamed,
+    /// or written to until the hold is released. Returns `false` if it already
+    /// was.
+    ///
+    /// Fails with [`Error::UnsupportedMethod`] if the mount has no
+    /// [`MountOptions::legal_hold_file`].
+    pub async fn hold(&self, path: &Utf8Path) -> Result<bool, Error> {
+        self.legal_holds.hold(path).await
+    }
+
+    /// Releases the legal hold on `path`. Returns `false` if there wasn't one.
+    pub async fn release_hold(&self, path: &Utf8Path) -> Result<bool, Error> {
+        self.legal_holds.release(path).await
+    }
+
+    /// The paths in this mount that are under legal hold.
+    #[must_use]
+    pub fn holds(&self) -> Vec<Utf8PathBuf> {
+        self.legal_holds.list()
+    }
+
+    /// Every file at or below `path`, along with its metadata. The paths are
+    /// relative to `path`, so if `path` is a file, it comes back by itself with
+    /// an empty path.
+    pub async fn files_under(
+        &self,
+        path: &Utf8Path,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let metadata = self.stat(path).await?;
+        if !metadata.is_directory() {
+            return Ok(vec![(Utf8PathBuf::new(), metadata)]);
+        }
+
+        let mut files = Vec::new();
+        let mut dirs = vec![Utf8PathBuf::new()];
+
+        while let Some(dir) = dirs.pop() {
+            let dir_path = if dir.as_str().is_empty() {
+                path.to_owned()
+            } else {
+                path.join(&dir)
+            };
+
+            let handle = self.open_dir(&dir_path).await?;
+            let entries = self.read_dir(&handle).await;
+            self.close(handle).await?;
+
+            for (name, metadata) in entries? {
+                if metadata.is_directory() {
+                    dirs.push(dir.join(name));
+                } else {
+                    files.push((dir.join(name), metadata));
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Writes to the file like [`Vfs::write`], but only if its
+    /// [change token](Metadata::change_token) still matches `token`.
+    ///
+    /// Conditional writes on a mount are serialized with each other, so two
+    /// clients racing to update the same file with the same token can't both
+    /// succeed. Plain writes don't take part in this, though.
+    pub async fn write_if_match(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        data: &[u8],
+        token: &str,
+    ) -> Result<(), Error> {
+        let _guard = self.conditional_writes.lock().await;
+
+        if self.stat_fd(handle).await?.change_token() != token {
+            return Err(Error::ChangeTokenMismatch);
+        }
+
+        self.write(handle, offset, data).await
+    }
+
+    /// Where a write to `handle` that asked for `offset` goes, which is the
+    /// current end of the file if it was opened with [`OpenFlags::APPEND`].
+    pub async fn write_offset(&self, handle: &Handle, offset: u64) -> Result<u64, Error> {
+        if !self.appends.contains(handle) {
+            return Ok(offset);
+        }
+
+        self.vfs
+            .stat_fd(handle)
+            .await?
+            .size()
+            .ok_or(Error::UnsupportedMethod)
+    }
+
+    /// Waits for whoever has `path` open for writing to close it, if the
+    /// mount's [`ExclusiveWrites`] policy is to wait, for up to
+    /// [`MountOptions::exclusive_write_timeout`].
+    ///
+    /// Opening the file waits by itself, but callers that limit how long an
+    /// open may take can wait here first, so that the other writer's time
+    /// isn't counted against the open. Nothing is claimed, so another writer
+    /// can still get there first, and then the open waits again.
+    pub async fn wait_for_writer(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.options.exclusive_writes != ExclusiveWrites::Wait {
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + self.options.exclusive_write_timeout;
+
+        loop {
+            let released = self.writers.released.notified();
+
+            if !self.writers.paths.lock().contains(path) {
+                return Ok(());
+            }
+
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                return Err(Error::OpenForWrite);
+            }
+        }
+    }
+
+    /// Accounts for `bytes` having been read from or written to the mount, and
+    /// waits until it's back under its [`MountOptions::bandwidth_limit`].
+    ///
+    /// Reads and writes don't wait for this themselves, so that the wait isn't
+    /// counted against the time they're given to finish.
+    pub async fn transferred(&self, bytes: u64) {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.consume(bytes).await;
+        }
+    }
+
+    /// Closes `handle` like [`Vfs::close`], but throws away what it wrote if
+    /// it was an upload that [`MountOptions::atomic_uploads`] held back, for
+    /// when the client went away without closing it.
+    pub async fn abort(&self, handle: Handle) -> Result<(), Error> {
+        self.close_handle(handle, false).await
+    }
+
+    /// Leaves the uploads that are still being written out of a directory
+    /// listing, when the mount has
+    /// [atomic uploads](MountOptions::atomic_uploads).
+    fn hide_uploads(&self, entries: &mut Vec<(Utf8PathBuf, Metadata)>) {
+        if self.options.atomic_uploads {
+            entries.retain(|(name, _)| !AtomicUploads::is_temp_name(name.as_str()));
+        }
+    }
+
+    /// Fails with [`Error::ReadOnly`] unless the mount is
+    /// [writable](MountOptions::writable).
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.options.writable {
+            Ok(())
+        } else {
+            Err(Error::ReadOnly)
+        }
+    }
+
+    /// Fails with [`Error::LegalHold`] if the file open at `handle` is under
+    /// legal hold, or with [`Error::Immutable`] if it has been sealed.
+    /// Directory handles aren't checked.
+    fn check_changeable_fd(&self, handle: &Handle) -> Result<(), Error> {
+        let Ok(path) = self.locks.path(handle) else {
+            return Ok(());
+        };
+
+        self.legal_holds.check(&path)?;
+        self.worm.check_writable(&path)
+    }
+
+    /// Claims `path` for writing according to the mount's
+    /// [`ExclusiveWrites`] policy.
+    async fn claim_for_write(&self, path: &Utf8Path) -> Result<Claim<'_>, Error> {
+        let claim = |claimed: bool| Claim {
+            writers: &self.writers,
+            path: claimed.then(|| path.to_owned()),
+        };
+
+        match self.options.exclusive_writes {
+            ExclusiveWrites::Allow => Ok(claim(false)),
+            ExclusiveWrites::Reject => {
+                if self.writers.try_claim(path) {
+                    Ok(claim(true))
+                } else {
+                    Err(Error::OpenForWrite)
+                }
+            }
+            ExclusiveWrites::Wait => {
+                let deadline = Instant::now() + self.options.exclusive_write_timeout;
+
+                loop {
+                    // This has to be created before we try to claim the path, or a release
+                    // that happens in between would be missed.
+                    let released = self.writers.released.notified();
+
+                    if self.writers.try_claim(path) {
+                        return Ok(claim(true));
+                    }
+
+                    event!(Level::DEBUG, %path, "waiting for other writer to close file");
+
+                    if tokio::time::timeout_at(deadline, released).await.is_err() {
+                        return Err(Error::OpenForWrite);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The hidden name to write the file opened at `path` with `flags` under,
+    /// if it's an upload that [`MountOptions::atomic_uploads`] applies to.
+    async fn upload_path(
+        &self,
+        path: &Utf8Path,
+        flags: OpenFlags,
+    ) -> Result<Option<Utf8PathBuf>, Error> {
+        if !self.options.atomic_uploads
+            || !flags.contains(OpenFlags::CREATE | OpenFlags::WRITE)
+            || flags.contains(OpenFlags::APPEND)
+        {
+            return Ok(None);
+        }
+
+        let exists = match self.vfs.stat(path).await {
+            Ok(_) => true,
+            Err(err) if err.is_not_found() => false,
+            Err(err) => return Err(err),
+        };
+
+        if exists && flags.contains(OpenFlags::EXCLUDE) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error(format!("couldn't open file {path}"));
+        }
+
+        // Changing part of a file that's already there has to happen in place.
+        if exists && !flags.contains(OpenFlags::TRUNCATE) {
+            return Ok(None);
+        }
+
+        Ok(Some(AtomicUploads::temp_path(path)))
+    }
+
+    /// Closes `handle`, putting an upload that was held back in place if
+    /// `keep` is set, and throwing it away otherwise. Uploads are scanned for
+    /// viruses before they're put in place, if the mount asks for it.
+    async fn close_handle(&self, handle: Handle, keep: bool) -> Result<(), Error> {
+        self.locks.closed(&handle);
+        self.appends.closed(&handle);
+        let upload = self.uploads.take(&handle);
+
+        let closed = self.vfs.close(handle.clone()).await;
+        let scanned = match self.virus_scan.take(&handle) {
+            Some(path) if keep && closed.is_ok() => {
+                let written = upload
+                    .as_ref()
+                    .map_or(path.as_path(), |upload| upload.temp_path());
+
+                self.virus_scan.scan(&self.vfs, written, &path).await
+            }
+            _ => Ok(()),
+        };
+
+        let placed = match upload {
+            Some(upload) if keep && closed.is_ok() && scanned.is_ok() => {
+                upload.commit(&self.vfs).await
+            }
+            Some(upload) => {
+                upload.discard(&self.vfs).await;
+                self.worm.abandoned(&handle);
+
+                Ok(())
+            }
+            None => Ok(()),
+        };
+
+        // What wasn't scanned clean has been quarantined, so there's nothing to
+        // seal.
+        if scanned.is_err() {
+            self.worm.abandoned(&handle);
+        }
+
+        // The path is only released once the upload is in place, so that a
+        // writer that was waiting for it can't be overtaken by the rename.
+        self.writers.release_handle(&handle);
+        let sealed = self.worm.closed(&handle).await;
+
+        closed?;
+        scanned?;
+        placed?;
+        sealed
+    }
+
+    /// Opens `path` like [`Vfs::open`], once the mount's
+    /// [`UploadCollisions`] policy has been applied.
+    async fn open_path(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::TRUNCATE);
+        self.virus_scan.check_accessible(path)?;
+
+        if writing {
+            self.legal_holds.check(path)?;
+            self.worm.check_writable(path)?;
+        }
+
+        if flags.contains(OpenFlags::TRUNCATE) {
+            self.locks.check_unlocked(path)?;
+        }
+
+        let claim = if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            self.free_space.check_writable(&self.vfs).await?;
+
+            Some(self.claim_for_write(path).await?)
+        } else {
+            None
+        };
+
+        let upload_path = self.upload_path(path, flags).await?;
+
+        let handle = match &upload_path {
+            Some(upload_path) => {
+                let flags = (flags - OpenFlags::TRUNCATE) | OpenFlags::EXCLUDE;
+                self.vfs.open(upload_path, flags).await?
+            }
+            None => self.vfs.open(path, flags).await?,
+        };
+        let handle = handle.with_mount(self.id);
+
+        if flags.contains(OpenFlags::APPEND) {
+            self.appends.opened(&handle);
+
+            // A file whose end can't be found can't be appended to.
+            if let Err(err) = self.write_offset(&handle, 0).await {
+                self.appends.closed(&handle);
+                let _ = self.vfs.close(handle).await;
+
+                return Err(err);
+            }
+        }
+
+        self.locks.opened(&handle, path);
+
+        if let Some(upload_path) = upload_path {
+            self.uploads.started(&handle, upload_path, path);
+        }
+
+        if writing {
+            self.worm.opened_for_write(&handle, path);
+            self.virus_scan.opened_for_write(&handle, path);
+        }
+
+        if let Some(claim) = claim {
+            claim.keep(&handle);
+        }
+
+        Ok(handle)
+    }
+
+    /// Creates `path`, or the first free name with a number added to it, for
+    /// [`UploadCollisions::Rename`].
+    async fn open_renamed(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let flags = flags | OpenFlags::EXCLUDE;
+        let mut candidate = path.to_owned();
+        let mut n = 0;
+
+        loop {
+            let err = match self.open_path(&candidate, flags).await {
+                Ok(handle) => {
+                    if n > 0 {
+                        event!(
+                            Level::INFO,
+                            %path,
+                            renamed = %candidate,
+                            "upload renamed to keep an existing file"
+                        );
+                    }
+
+                    return Ok(handle);
+                }
+                Err(err) => err,
+            };
+
+            // Only a file that's in the way is worth trying another name for.
+            if n >= MAX_RENAMES || self.vfs.stat(&candidate).await.is_err() {
+                return Err(err);
+            }
+
+            n += 1;
+            candidate = numbered(path, n);
+        }
+    }
+}
+
+/// `path` with `n` added before the extension of its name.
+fn numbered(path: &Utf8Path, n: u32) -> Utf8PathBuf {
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => path.with_file_name(format!("{stem}.{n}.{extension}")),
+        _ => path.with_file_name(format!("{}.{n}", path.file_name().unwrap_or_default())),
+    }
+}
+
+#[async_trait]
+impl Vfs for Mount {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let creating = flags.contains(OpenFlags::CREATE | OpenFlags::WRITE)
+            && !flags.intersects(OpenFlags::APPEND | OpenFlags::TRUNCATE | OpenFlags::EXCLUDE);
+
+        if flags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        ) {
+            self.check_writable()?;
+        }
+
+        match self.options.upload_collisions {
+            UploadCollisions::Reject if creating => {
+                self.open_path(path, flags | OpenFlags::EXCLUDE).await
+            }
+            UploadCollisions::Rename if creating => self.open_renamed(path, flags).await,
+            _ => self.open_path(path, flags).await,
+        }
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        self.virus_scan.check_accessible(path)?;
+
+        Ok(self.vfs.open_dir(path).await?.with_mount(self.id))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        self.close_handle(handle, true).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.mount() {
+            Some(id) if id != self.id => false,
+            _ => self.vfs.owns_handle(handle).await,
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.vfs.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.locks
+            .check_read(handle, &(offset..offset.saturating_add(len as u64)))?;
+
+        self.vfs.read(handle, offset, len).await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.vfs.read_dir(handle).await?;
+        self.hide_uploads(&mut entries);
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        // A batch of nothing but uploads would look like the end of the
+        // listing, so those are skipped until something's left.
+        loop {
+            let mut entries = self.vfs.read_dir_next(handle, max).await?;
+            if entries.is_empty() {
+                return Ok(entries);
+            }
+
+            self.hide_uploads(&mut entries);
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+        }
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        // Another handle may have sealed the file, or it may have been placed
+        // under legal hold, since this one was opened.
+        self.check_changeable_fd(handle)?;
+        let _append = if self.appends.contains(handle) {
+            Some(self.appends.writes.lock().await)
+        } else {
+            None
+        };
+        let offset = self.write_offset(handle, offset).await?;
+        self.locks
+            .check_write(handle, &(offset..offset.saturating_add(data.len() as u64)))?;
+
+        self.vfs.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.vfs.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.vfs.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check_tree(from)?;
+        self.legal_holds.check(to)?;
+        self.locks.check_unlocked(from)?;
+        self.locks.check_unlocked(to)?;
+        self.worm.check_tree_writable(from)?;
+        self.worm.check_tree_writable(to)?;
+        self.virus_scan.check_accessible(from)?;
+        self.virus_scan.check_accessible(to)?;
+
+        self.vfs.rename(from, to).await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.vfs.stat(path).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.vfs.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        let mut metadata = self.vfs.statvfs(path).await?;
+        metadata.read_only |= !self.options.writable;
+
+        Ok(metadata)
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        let mut metadata = self.vfs.statvfs_fd(handle).await?;
+        metadata.read_only |= !self.options.writable;
+
+        Ok(metadata)
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+        // Holds and seals are kept by path, so another name for the file would
+        // be a way around them.
+        self.legal_holds.check(path)?;
+        self.worm.check_writable(path)?;
+        self.virus_scan.check_accessible(path)?;
+
+        self.vfs.hardlink(path, target).await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+
+        self.vfs.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.vfs
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.vfs.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+
+        self.vfs.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check(path)?;
+        self.locks.check_unlocked(path)?;
+        self.worm.check_removable(path)?;
+
+        self.vfs.remove_file(path).await?;
+        self.worm.removed(path).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check(path)?;
+        self.worm.check_tree_removable(path)?;
+
+        self.vfs.remove_dir(path).await?;
+        self.worm.removed(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check(path)?;
+        self.worm.check_writable(path)?;
+
+        self.vfs.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_changeable_fd(handle)?;
+
+        self.vfs.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check(path)?;
+        self.worm.check_writable(path)?;
+
+        self.vfs.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_changeable_fd(handle)?;
+
+        self.vfs.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        self.legal_holds.check(path)?;
+        self.worm.check_writable(path)?;
+        self.locks.check_unlocked(path)?;
+
+        self.vfs.truncate(path, len).await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_changeable_fd(handle)?;
+        self.locks.check_write(handle, &(len..u64::MAX))?;
+
+        self.vfs.truncate_fd(handle, len).await
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        let previous = self.locks.lock(handle, range.clone(), mode)?;
+
+        match self.vfs.lock(handle, range.clone(), mode).await {
+            Ok(()) | Err(Error::UnsupportedMethod) => Ok(()),
+            Err(err) => {
+                self.locks.restore(handle, &range, previous);
+
+                Err(err)
+            }
+        }
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.locks.unlock(handle, &range)?;
+
+        match self.vfs.unlock(handle, range).await {
+            Ok(()) | Err(Error::UnsupportedMethod) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        // Copying between mounts is up to the caller, by reading and writing.
+        if [src, dst]
+            .iter()
+            .any(|handle| handle.mount().is_some_and(|id| id != self.id))
+        {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        self.check_writable()?;
+        self.check_changeable_fd(dst)?;
+        let _append = if self.appends.contains(dst) {
+            Some(self.appends.writes.lock().await)
+        } else {
+            None
+        };
+        let dst_offset = self.write_offset(dst, dst_offset).await?;
+        self.locks
+            .check_read(src, &(src_offset..src_offset.saturating_add(len)))?;
+        self.locks
+            .check_write(dst, &(dst_offset..dst_offset.saturating_add(len)))?;
+
+        self.vfs
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        self.check_writable()?;
+        self.check_changeable_fd(handle)?;
+
+        self.vfs.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.vfs.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.vfs.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{task::Poll, time::Duration};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::vfs::{
+        FilenameFilterOptions,
+        LocalDir,
+        LocalDirOptions,
+        MemoryDir,
+        PatternSyntax,
+        WormOptions,
+    };
+
+    fn mount() -> Mount {
+        mount_with(MountOptions::default())
+    }
+
+    fn mount_with(options: MountOptions) -> Mount {
+        let vfs = VfsInstance::MemoryDir(MemoryDir::new(Utf8PathBuf::from("/")));
+
+        Mount::new(0, vfs, options).unwrap()
+    }
+
+    /// A mount that seals what's written to it, keeping its state in `state`.
+    fn worm_mount(state: &TempDir) -> Mount {
+        let state_file = Utf8Path::from_path(state.path()).unwrap().join("worm.json");
+
+        mount_with(MountOptions {
+            worm: Some(WormOptions {
+                retention: Duration::from_hours(1),
+                state_file,
+            }),
+            ..MountOptions::default()
+        })
+    }
+
+    async fn create(mount: &Mount, path: &Utf8Path, data: &[u8]) {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let handle = mount.open(path, flags).await.unwrap();
+        mount.write(&handle, 0, data).await.unwrap();
+        mount.close(handle).await.unwrap();
+    }
+
+    async fn contents(mount: &Mount, path: &Utf8Path) -> Vec<u8> {
+        let handle = mount.open(path, OpenFlags::READ).await.unwrap();
+        let data = mount
+            .read(&handle, 0, 1024)
+            .await
+            .unwrap()
+            .unwrap_or_default();
+        mount.close(handle).await.unwrap();
+
+        data
+    }
+
+    /// OpenSSH's `put -a` opens the file for appending and sends offsets
+    /// counted from where the file ended when it was opened.
+    #[tokio::test]
+    async fn append_with_openssh_offsets() {
+        let mount = mount();
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"hello").await;
+
+        let handle = mount
+            .open(path, OpenFlags::WRITE | OpenFlags::APPEND)
+            .await
+            .unwrap();
+        assert_eq!(mount.write_offset(&handle, 5).await.unwrap(), 5);
+        mount.write(&handle, 5, b" wor").await.unwrap();
+        assert_eq!(mount.write_offset(&handle, 9).await.unwrap(), 9);
+        mount.write(&handle, 9, b"ld").await.unwrap();
+        mount.close(handle).await.unwrap();
+
+        assert_eq!(contents(&mount, path).await, b"hello world");
+    }
+
+    /// Clients that send 0 with every write still append.
+    #[tokio::test]
+    async fn append_ignores_offsets() {
+        let mount = mount();
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"hello").await;
+
+        let handle = mount
+            .open(path, OpenFlags::WRITE | OpenFlags::APPEND)
+            .await
+            .unwrap();
+        assert_eq!(mount.write_offset(&handle, 0).await.unwrap(), 5);
+        mount.write(&handle, 0, b" world").await.unwrap();
+        mount.close(handle).await.unwrap();
+
+        assert_eq!(contents(&mount, path).await, b"hello world");
+    }
+
+    /// The end of an appended file follows truncation and other appenders.
+    #[tokio::test]
+    async fn append_follows_end() {
+        let mount = mount();
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"hello").await;
+
+        let flags = OpenFlags::WRITE | OpenFlags::APPEND;
+        let first = mount.open(path, flags).await.unwrap();
+        let second = mount.open(path, flags).await.unwrap();
+
+        mount.write(&first, 5, b" world").await.unwrap();
+        assert_eq!(mount.write_offset(&second, 5).await.unwrap(), 11);
+        mount.write(&second, 5, b"!").await.unwrap();
+
+        mount.truncate_fd(&first, 5).await.unwrap();
+        assert_eq!(mount.write_offset(&second, 12).await.unwrap(), 5);
+        mount.write(&second, 12, b"?").await.unwrap();
+
+        mount.close(first).await.unwrap();
+        mount.close(second).await.unwrap();
+
+        assert_eq!(contents(&mount, path).await, b"hello?");
+    }
+
+    /// Writes to handles that weren't opened for appending go where they're
+    /// asked to.
+    #[tokio::test]
+    async fn write_offset_without_append() {
+        let mount = mount();
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"hello").await;
+
+        let handle = mount.open(path, OpenFlags::WRITE).await.unwrap();
+        assert_eq!(mount.write_offset(&handle, 2).await.unwrap(), 2);
+        mount.write(&handle, 0, b"J").await.unwrap();
+        mount.close(handle).await.unwrap();
+
+        assert_eq!(contents(&mount, path).await, b"Jello");
+    }
+
+    /// Listing a directory a batch at a time carries on from where the last
+    /// batch stopped, and ends with an empty one.
+    #[tokio::test]
+    async fn read_dir_next_pages() {
+        let mount = mount();
+        for name in ["a", "b", "c"] {
+            create(&mount, Utf8Path::new(name), b"").await;
+        }
+
+        let handle = mount.open_dir(Utf8Path::new("")).await.unwrap();
+        let mut batches = Vec::new();
+        loop {
+            let entries = mount.read_dir_next(&handle, 2).await.unwrap();
+            if entries.is_empty() {
+                break;
+            }
+            batches.push(
+                entries
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        mount.close(handle).await.unwrap();
+
+        assert_eq!(batches, [vec!["a", "b"], vec!["c"]]);
+    }
+
+    /// Uploads that are still being written under their hidden names are left
+    /// out of listings, whole or a batch at a time.
+    #[tokio::test]
+    async fn read_dir_hides_uploads() {
+        let mount = mount_with(MountOptions {
+            atomic_uploads: true,
+            ..MountOptions::default()
+        });
+        create(&mount, Utf8Path::new("a"), b"").await;
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let upload = mount.open(Utf8Path::new("b"), flags).await.unwrap();
+        mount.write(&upload, 0, b"partial").await.unwrap();
+
+        let handle = mount.open_dir(Utf8Path::new("")).await.unwrap();
+        let names = |entries: Vec<(Utf8PathBuf, Metadata)>| {
+            entries
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(mount.read_dir(&handle).await.unwrap()), ["a"]);
+        mount.close(handle).await.unwrap();
+
+        let handle = mount.open_dir(Utf8Path::new("")).await.unwrap();
+        assert_eq!(names(mount.read_dir_next(&handle, 1).await.unwrap()), ["a"]);
+        assert!(mount.read_dir_next(&handle, 1).await.unwrap().is_empty());
+        mount.close(handle).await.unwrap();
+
+        mount.close(upload).await.unwrap();
+        let handle = mount.open_dir(Utf8Path::new("")).await.unwrap();
+        assert_eq!(names(mount.read_dir(&handle).await.unwrap()), ["a", "b"]);
+        mount.close(handle).await.unwrap();
+    }
+
+    /// A file under legal hold or sealed can't be given a second name, which
+    /// could be written to or removed in its place.
+    #[tokio::test]
+    async fn hardlink_refuses_held_and_sealed_files() {
+        let state = tempfile::tempdir().unwrap();
+        let state_dir = Utf8Path::from_path(state.path()).unwrap();
+        let mount = mount_with(MountOptions {
+            legal_hold_file: Some(state_dir.join("holds.json")),
+            ..MountOptions::default()
+        });
+        create(&mount, Utf8Path::new("held"), b"").await;
+        create(&mount, Utf8Path::new("free"), b"").await;
+        mount.hold(Utf8Path::new("held")).await.unwrap();
+
+        assert!(matches!(
+            mount
+                .hardlink(Utf8Path::new("held"), Utf8Path::new("link"))
+                .await,
+            Err(Error::LegalHold)
+        ));
+        mount
+            .hardlink(Utf8Path::new("free"), Utf8Path::new("link"))
+            .await
+            .unwrap();
+
+        let mount = worm_mount(&state);
+        create(&mount, Utf8Path::new("sealed"), b"").await;
+        assert!(matches!(
+            mount
+                .hardlink(Utf8Path::new("sealed"), Utf8Path::new("link"))
+                .await,
+            Err(Error::Immutable)
+        ));
+    }
+
+    /// An open that's given up on part way, like one that took too long,
+    /// doesn't keep the path claimed for writing.
+    #[tokio::test]
+    async fn abandoned_open_releases_claim() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap().to_owned();
+        let vfs = LocalDir::new(Utf8PathBuf::from("/"), root, &LocalDirOptions::default());
+        let options = MountOptions {
+            exclusive_writes: ExclusiveWrites::Reject,
+            ..MountOptions::default()
+        };
+        let mount = Mount::new(0, VfsInstance::LocalDir(vfs.unwrap()), options).unwrap();
+        let path = Utf8Path::new("file");
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+
+        // The backend opens files on the blocking thread pool, so the open is
+        // still waiting for it after being polled once.
+        let mut open = Box::pin(mount.open(path, flags));
+        if let Poll::Ready(handle) = futures_util::poll!(open.as_mut()) {
+            mount.close(handle.unwrap()).await.unwrap();
+        }
+        drop(open);
+
+        let handle = mount.open(path, flags).await.unwrap();
+        mount.close(handle).await.unwrap();
+    }
+
+    /// A file that's sealed while another handle has it open for writing can't
+    /// be changed through that handle any more.
+    #[tokio::test]
+    async fn worm_seal_applies_to_open_handles() {
+        let state = tempfile::tempdir().unwrap();
+        let mount = worm_mount(&state);
+        let path = Utf8Path::new("file");
+
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+        let first = mount.open(path, flags).await.unwrap();
+        let second = mount.open(path, flags).await.unwrap();
+        mount.write(&second, 0, b"sealed").await.unwrap();
+        mount.close(second).await.unwrap();
+
+        assert!(matches!(
+            mount.write(&first, 0, b"changed").await,
+            Err(Error::Immutable)
+        ));
+        assert!(matches!(
+            mount.truncate_fd(&first, 0).await,
+            Err(Error::Immutable)
+        ));
+        assert!(matches!(
+            mount.set_permissions_fd(&first, 0o600).await,
+            Err(Error::Immutable)
+        ));
+        mount.close(first).await.unwrap();
+
+        assert_eq!(contents(&mount, path).await, b"sealed");
+    }
+
+    /// A sealed file can't be moved, by itself or along with the directory
+    /// it's in, and nothing can be moved over it.
+    #[tokio::test]
+    async fn worm_rename_keeps_sealed_files() {
+        let state = tempfile::tempdir().unwrap();
+        let mount = worm_mount(&state);
+        mount.mkdir(Utf8Path::new("dir")).await.unwrap();
+        mount.mkdir(Utf8Path::new("di")).await.unwrap();
+        create(&mount, Utf8Path::new("dir/file"), b"sealed").await;
+        create(&mount, Utf8Path::new("other"), b"").await;
+
+        for (from, to) in [
+            ("dir/file", "dir/moved"),
+            ("dir", "moved"),
+            ("other", "dir/file"),
+        ] {
+            assert!(
+                matches!(
+                    mount.rename(Utf8Path::new(from), Utf8Path::new(to)).await,
+                    Err(Error::Immutable)
+                ),
+                "{from} -> {to}"
+            );
+        }
+
+        mount
+            .rename(Utf8Path::new("di"), Utf8Path::new("moved"))
+            .await
+            .unwrap();
+        assert_eq!(contents(&mount, Utf8Path::new("dir/file")).await, b"sealed");
+    }
+
+    /// Nothing can change or remove a file under legal hold until the hold is
+    /// released.
+    #[tokio::test]
+    async fn legal_hold_blocks_changes() {
+        let state = tempfile::tempdir().unwrap();
+        let state_dir = Utf8Path::from_path(state.path()).unwrap();
+        let mount = mount_with(MountOptions {
+            legal_hold_file: Some(state_dir.join("holds.json")),
+            ..MountOptions::default()
+        });
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"held").await;
+        assert!(mount.hold(path).await.unwrap());
+
+        assert!(matches!(
+            mount.open(path, OpenFlags::WRITE).await,
+            Err(Error::LegalHold)
+        ));
+        assert!(matches!(
+            mount.truncate(path, 0).await,
+            Err(Error::LegalHold)
+        ));
+        assert!(matches!(
+            mount.rename(path, Utf8Path::new("moved")).await,
+            Err(Error::LegalHold)
+        ));
+        assert!(matches!(
+            mount.remove_file(path).await,
+            Err(Error::LegalHold)
+        ));
+        assert_eq!(mount.holds(), [path]);
+
+        assert!(mount.release_hold(path).await.unwrap());
+        create(&mount, path, b"released").await;
+        assert_eq!(contents(&mount, path).await, b"released");
+        mount.remove_file(path).await.unwrap();
+    }
+
+    /// A sealed file can't be written to or removed until its retention is
+    /// over.
+    #[tokio::test]
+    async fn worm_retains_sealed_files() {
+        let state = tempfile::tempdir().unwrap();
+        let mount = worm_mount(&state);
+        let path = Utf8Path::new("file");
+        create(&mount, path, b"sealed").await;
+
+        assert!(matches!(
+            mount.open(path, OpenFlags::WRITE).await,
+            Err(Error::Immutable)
+        ));
+        assert!(matches!(
+            mount.truncate(path, 0).await,
+            Err(Error::Immutable)
+        ));
+        assert!(matches!(
+            mount.remove_file(path).await,
+            Err(Error::Retained)
+        ));
+        assert_eq!(contents(&mount, path).await, b"sealed");
+
+        let state_dir = Utf8Path::from_path(state.path()).unwrap();
+        let mount = mount_with(MountOptions {
+            worm: Some(WormOptions {
+                retention: Duration::ZERO,
+                state_file: state_dir.join("expired.json"),
+            }),
+            ..MountOptions::default()
+        });
+        create(&mount, path, b"sealed").await;
+        mount.remove_file(path).await.unwrap();
+    }
+
+    /// With [`ExclusiveWrites::Reject`], a second writer is turned away while
+    /// the first has the file open, but readers aren't.
+    #[tokio::test]
+    async fn exclusive_writes_reject() {
+        let mount = mount_with(MountOptions {
+            exclusive_writes: ExclusiveWrites::Reject,
+            ..MountOptions::default()
+        });
+        let path = Utf8Path::new("file");
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+
+        let first = mount.open(path, flags).await.unwrap();
+        assert!(matches!(
+            mount.open(path, flags).await,
+            Err(Error::OpenForWrite)
+        ));
+        let reader = mount.open(path, OpenFlags::READ).await.unwrap();
+        mount.close(reader).await.unwrap();
+        mount.close(first).await.unwrap();
+
+        let second = mount.open(path, flags).await.unwrap();
+        mount.close(second).await.unwrap();
+    }
+
+    /// With [`ExclusiveWrites::Wait`], a second writer gets the file once the
+    /// first closes it, or gives up after the timeout.
+    #[tokio::test]
+    async fn exclusive_writes_wait() {
+        let mount = mount_with(MountOptions {
+            exclusive_writes: ExclusiveWrites::Wait,
+            exclusive_write_timeout: Duration::from_millis(50),
+            ..MountOptions::default()
+        });
+        let path = Utf8Path::new("file");
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE;
+
+        let first = mount.open(path, flags).await.unwrap();
+        assert!(matches!(
+            mount.open(path, flags).await,
+            Err(Error::OpenForWrite)
+        ));
+
+        let (second, ()) = tokio::join!(mount.open(path, flags), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            mount.close(first).await.unwrap();
+        });
+        mount.close(second.unwrap()).await.unwrap();
+    }
+
+    /// An atomic upload only shows up under its name once it's closed, and
+    /// one that's aborted leaves the file it would have replaced alone.
+    #[tokio::test]
+    async fn atomic_uploads_replace_on_close() {
+        let mount = mount_with(MountOptions {
+            atomic_uploads: true,
+            ..MountOptions::default()
+        });
+        let path = Utf8Path::new("file");
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+
+        let upload = mount.open(path, flags).await.unwrap();
+        mount.write(&upload, 0, b"first").await.unwrap();
+        assert!(mount.stat(path).await.is_err());
+        mount.close(upload).await.unwrap();
+        assert_eq!(contents(&mount, path).await, b"first");
+
+        let upload = mount.open(path, flags).await.unwrap();
+        mount.write(&upload, 0, b"second").await.unwrap();
+        assert_eq!(contents(&mount, path).await, b"first");
+        mount.abort(upload).await.unwrap();
+        assert_eq!(contents(&mount, path).await, b"first");
+
+        let handle = mount.open_dir(Utf8Path::new("")).await.unwrap();
+        let entries = mount.read_dir(&handle).await.unwrap();
+        mount.close(handle).await.unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+
+    /// Denied names are refused for files and directories alike, while the
+    /// allowed names only limit files.
+    #[test]
+    fn filename_filter() {
+        let mount = mount_with(MountOptions {
+            filename_filter: Some(FilenameFilterOptions {
+                deny: vec![".*".to_owned()],
+                allow: vec!["*.csv".to_owned()],
+                syntax: PatternSyntax::Glob,
+            }),
+            ..MountOptions::default()
+        });
+
+        mount
+            .check_file_name(Utf8Path::new("dir/report.csv"))
+            .unwrap();
+        mount.check_dir_name(Utf8Path::new("dir")).unwrap();
+        for path in [".hidden.csv", "dir/report.txt"] {
+            assert!(
+                matches!(
+                    mount.check_file_name(Utf8Path::new(path)),
+                    Err(Error::FilenameDenied)
+                ),
+                "{path}"
+            );
+        }
+        assert!(matches!(
+            mount.check_dir_name(Utf8Path::new(".git")),
+            Err(Error::FilenameDenied)
+        ));
+    }
+}
@@ -6,19 +6,39 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use base64ct::{Base64Unpadded, Encoding};
 use bitflags::bitflags;
 use cap_primitives::fs::MetadataExt as _;
 use cap_std::fs::OpenOptions;
-use russh_sftp::protocol::FileAttributes;
+use russh_sftp::protocol::{FileAttributes, OpenFlags as SftpOpenFlags};
 use rustix::fs::{StatVfs, StatVfsMountFlags};
+use sha2::{Digest, Sha256};
 use tracing::{Level, event};
 
+/// The bits of a file mode that are permissions, rather than the file type.
+pub const PERMISSION_BITS: u32 = 0o7777;
+
+/// The unit that `st_blocks` counts in, whatever the filesystem's block size.
+pub(super) const STAT_BLOCK_SIZE: u64 = 512;
+
 #[derive(Debug, Default, Copy, Clone)]
 pub struct Metadata {
     pub(super) size: Option<u64>,
     pub(super) atime: Option<SystemTime>,
     pub(super) mtime: Option<SystemTime>,
     pub(super) is_directory: bool,
+    /// A backend-provided generation number that changes on every
+    /// modification, for backends that track one.
+    pub(super) generation: Option<u64>,
+    /// The permission bits, for backends that keep them.
+    pub(super) mode: Option<u32>,
+    pub(super) uid: Option<u32>,
+    pub(super) gid: Option<u32>,
+    /// The number of hard links to the file, for backends that have them.
+    pub(super) nlink: Option<u64>,
+    /// How much space the file takes up on disk, in bytes, for backends that
+    /// know. It's less than `size` for sparse files.
+    pub(super) allocated_size: Option<u64>,
 }
 
 impl Metadata {
@@ -42,17 +62,81 @@ impl Metadata {
         self.is_directory
     }
 
+    #[must_use]
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    #[must_use]
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    #[must_use]
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    #[must_use]
+    pub fn nlink(&self) -> Option<u64> {
+        self.nlink
+    }
+
+    #[must_use]
+    pub fn allocated_size(&self) -> Option<u64> {
+        self.allocated_size
+    }
+
+    /// Whether the file has holes, which take up less space on disk than the
+    /// zeroes they read as.
+    #[must_use]
+    pub fn is_sparse(&self) -> bool {
+        match (self.size, self.allocated_size) {
+            (Some(size), Some(allocated_size)) => allocated_size < size,
+            _ => false,
+        }
+    }
+
+    /// An opaque token that changes whenever the file is modified.
+    ///
+    /// This is derived from the backend's generation number when it has one,
+    /// and from the size and modification time otherwise. Clients can use it to
+    /// detect that someone else has modified a file since they last looked at
+    /// it.
+    #[must_use]
+    pub fn change_token(&self) -> String {
+        let mut hasher = Sha256::new();
+
+        if let Some(generation) = self.generation {
+            hasher.update(b"generation");
+            hasher.update(generation.to_be_bytes());
+        } else {
+            hasher.update(self.size.unwrap_or_default().to_be_bytes());
+
+            if let Some(mtime) = self.mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+                hasher.update(mtime.as_nanos().to_be_bytes());
+            }
+        }
+
+        Base64Unpadded::encode_string(&hasher.finalize()[..12])
+    }
+
+    /// The attributes to report over SFTP. `file_mode` and `dir_mode` are the
+    /// permissions shown for files and directories whose backend doesn't keep
+    /// any.
     pub fn file_attrs(&self, file_mode: u32, dir_mode: u32) -> FileAttributes {
         let mut attrs = FileAttributes::default();
 
         attrs.size = self.size;
         attrs.atime = self.atime.and_then(from_system_time);
         attrs.mtime = self.mtime.and_then(from_system_time);
+        attrs.uid = self.uid;
+        attrs.gid = self.gid;
 
         if self.is_directory {
-            attrs.permissions = Some((0o004 << 12) | dir_mode);
+            attrs.permissions = Some((0o004 << 12) | self.mode.unwrap_or(dir_mode));
         } else {
-            attrs.permissions = Some((0o010 << 12) | file_mode);
+            attrs.permissions = Some((0o010 << 12) | self.mode.unwrap_or(file_mode));
         }
 
         attrs
@@ -70,6 +154,12 @@ impl From<FileAttributes> for Metadata {
         out.size = value.size;
         out.atime = value.atime.map(to_system_time);
         out.mtime = value.mtime.map(to_system_time);
+        out.is_directory = value.is_dir();
+        out.mode = value
+            .permissions
+            .map(|permissions| permissions & PERMISSION_BITS);
+        out.uid = value.uid;
+        out.gid = value.gid;
 
         out
     }
@@ -80,9 +170,14 @@ impl From<std::fs::Metadata> for Metadata {
         let mut out = Metadata::default();
 
         out.size = Some(value.size());
-        out.atime = Some(to_system_time(value.atime()));
-        out.mtime = Some(to_system_time(value.mtime()));
+        out.atime = Some(to_system_time(value.atime(), value.atime_nsec()));
+        out.mtime = Some(to_system_time(value.mtime(), value.mtime_nsec()));
         out.is_directory = value.is_dir();
+        out.mode = Some(value.mode() & PERMISSION_BITS);
+        out.uid = Some(value.uid());
+        out.gid = Some(value.gid());
+        out.nlink = Some(value.nlink());
+        out.allocated_size = Some(value.blocks() * STAT_BLOCK_SIZE);
 
         out
     }
@@ -93,15 +188,20 @@ impl From<cap_std::fs::Metadata> for Metadata {
         let mut out = Metadata::default();
 
         out.size = Some(value.size());
-        out.atime = Some(to_system_time(value.atime()));
-        out.mtime = Some(to_system_time(value.mtime()));
+        out.atime = Some(to_system_time(value.atime(), value.atime_nsec()));
+        out.mtime = Some(to_system_time(value.mtime(), value.mtime_nsec()));
         out.is_directory = value.is_dir();
+        out.mode = Some(value.mode() & PERMISSION_BITS);
+        out.uid = Some(value.uid());
+        out.gid = Some(value.gid());
+        out.nlink = Some(value.nlink());
+        out.allocated_size = Some(value.blocks() * STAT_BLOCK_SIZE);
 
         out
     }
 }
 
-fn from_system_time(system_time: SystemTime) -> Option<u32> {
+pub(super) fn from_system_time(system_time: SystemTime) -> Option<u32> {
     if let Ok(duration) = system_time.duration_since(UNIX_EPOCH) {
         if let Ok(duration) = duration.as_secs().try_into() {
             Some(duration)
@@ -115,13 +215,15 @@ fn from_system_time(system_time: SystemTime) -> Option<u32> {
     }
 }
 
-fn to_system_time(x: i64) -> SystemTime {
-    #[allow(clippy::cast_sign_loss)]
-    if x < 0 {
-        SystemTime::UNIX_EPOCH - Duration::from_secs(-x as u64)
+#[allow(clippy::cast_sign_loss)]
+fn to_system_time(secs: i64, nsecs: i64) -> SystemTime {
+    let time = if secs < 0 {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(-secs as u64)
     } else {
-        SystemTime::UNIX_EPOCH + Duration::from_secs(x as u64)
-    }
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64)
+    };
+
+    time + Duration::from_nanos(nsecs as u64)
 }
 
 /// The core filesystem metadata that VFSes need to report if they implement
@@ -151,6 +253,34 @@ impl From<StatVfs> for FsMetadata {
     }
 }
 
+/// The optional operations a [`Vfs`](super::Vfs) backend can do. The SFTP
+/// server refuses the ones a mount's backend lacks up front, and only
+/// advertises the protocol extensions that some mount can serve.
+#[repr(transparent)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Capabilities(u32);
+
+bitflags! {
+    impl Capabilities: u32 {
+        /// Creating and reading symbolic links.
+        const SYMLINKS  = 0x0000_0001;
+        /// Creating hard links.
+        const HARDLINKS = 0x0000_0002;
+        /// Reporting free space with [`Vfs::statvfs`](super::Vfs::statvfs).
+        const STATVFS   = 0x0000_0004;
+        /// Taking locks that other programs using the backend can see. Mounts
+        /// enforce locks between their own clients either way.
+        const LOCKS     = 0x0000_0008;
+        /// Changing permissions, with [`Vfs::set_permissions`](super::Vfs::set_permissions).
+        const PERMISSIONS = 0x0000_0010;
+        /// Reporting changes as they happen, with [`Vfs::watch`](super::Vfs::watch).
+        const WATCH     = 0x0000_0020;
+        /// Renaming over an existing file, which is replaced in one step, so
+        /// that nobody ever finds the destination missing.
+        const ATOMIC_RENAME = 0x0000_0040;
+    }
+}
+
 /// The simplified lowest-common-denominator of file-opening types that the VFS
 /// needs to support.
 #[repr(transparent)]
@@ -175,21 +305,19 @@ impl OpenFlags {
     }
 }
 
-impl From<russh_sftp::protocol::OpenFlags> for OpenFlags {
-    fn from(pflags: russh_sftp::protocol::OpenFlags) -> Self {
-        use russh_sftp::protocol::OpenFlags as SftpOpenFlags;
-
-        static FLAGS: LazyLock<Vec<(SftpOpenFlags, OpenFlags)>> = LazyLock::new(|| {
-            vec![
-                (SftpOpenFlags::READ, OpenFlags::READ),
-                (SftpOpenFlags::WRITE, OpenFlags::WRITE),
-                (SftpOpenFlags::APPEND, OpenFlags::APPEND),
-                (SftpOpenFlags::CREATE, OpenFlags::CREATE),
-                (SftpOpenFlags::TRUNCATE, OpenFlags::TRUNCATE),
-                (SftpOpenFlags::EXCLUDE, OpenFlags::EXCLUDE),
-            ]
-        });
-
+static FLAGS: LazyLock<Vec<(SftpOpenFlags, OpenFlags)>> = LazyLock::new(|| {
+    vec![
+        (SftpOpenFlags::READ, OpenFlags::READ),
+        (SftpOpenFlags::WRITE, OpenFlags::WRITE),
+        (SftpOpenFlags::APPEND, OpenFlags::APPEND),
+        (SftpOpenFlags::CREATE, OpenFlags::CREATE),
+        (SftpOpenFlags::TRUNCATE, OpenFlags::TRUNCATE),
+        (SftpOpenFlags::EXCLUDE, OpenFlags::EXCLUDE),
+    ]
+});
+
+impl From<SftpOpenFlags> for OpenFlags {
+    fn from(pflags: SftpOpenFlags) -> Self {
         let mut out = Self::empty();
 
         for (src, dst) in FLAGS.iter() {
@@ -202,6 +330,30 @@ impl From<russh_sftp::protocol::OpenFlags> for OpenFlags {
     }
 }
 
+impl From<OpenFlags> for SftpOpenFlags {
+    fn from(flags: OpenFlags) -> Self {
+        let mut out = Self::empty();
+
+        for (src, dst) in FLAGS.iter() {
+            if flags.contains(*dst) {
+                out |= *src;
+            }
+        }
+
+        out
+    }
+}
+
+/// How a byte range of a file is locked, see [`Vfs::lock`](super::Vfs::lock).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockMode {
+    /// Others can still read the range and take shared locks on it, but
+    /// can't write to it.
+    Shared,
+    /// Others can neither read nor write the range, nor lock any of it.
+    Exclusive,
+}
+
 macro_rules! convert_flags {
     ( $input:ident, $output:ident, [ $(( $src:expr , $dst:ident ), )* ] ) => {
         {
@@ -0,0 +1,977 @@
+use std::{
+    io,
+    io::SeekFrom,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use deadpool_postgres::{GenericClient, Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+use parking_lot::Mutex;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex as AsyncMutex, OnceCell, broadcast},
+};
+use tokio_postgres::{NoTls, Row};
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    PostgresOptions,
+    Vfs,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// How long to wait for a connection to the database.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many bytes of a file are stored in each row of `schlep_chunks`.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// How many chunks are fetched at a time when a whole file is read.
+const CHUNKS_PER_QUERY: i64 = 16;
+
+/// The tables that files are kept in, created when the mount is first used.
+///
+/// Paths are relative to the root of the mount, which is the empty string, and
+/// have no leading or trailing slashes.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS schlep_files (
+    path text PRIMARY KEY,
+    parent text,
+    is_directory boolean NOT NULL,
+    size bigint NOT NULL DEFAULT 0,
+    atime timestamptz,
+    mtime timestamptz NOT NULL DEFAULT now(),
+    generation bigint NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS schlep_files_parent ON schlep_files (parent);
+CREATE TABLE IF NOT EXISTS schlep_chunks (
+    path text NOT NULL REFERENCES schlep_files (path) ON DELETE CASCADE ON UPDATE CASCADE,
+    chunk bigint NOT NULL,
+    data bytea NOT NULL,
+    PRIMARY KEY (path, chunk)
+);
+INSERT INTO schlep_files (path, parent, is_directory) VALUES ('', NULL, true)
+ON CONFLICT DO NOTHING;
+";
+
+/// A backend that keeps files and their metadata in PostgreSQL, for small
+/// files that need to be backed up and replicated along with everything else
+/// in the database.
+///
+/// Every file is a row in `schlep_files`, and its contents are split into
+/// rows of `schlep_chunks`. Files that are open for writing are spooled to a
+/// local temporary file, which replaces the contents in the database in a
+/// single transaction when the file is synced or closed, so nobody ever reads
+/// a half-written file. Links aren't supported.
+///
+/// The tables are created in the default schema of the connection, so mounts
+/// that share a database need to use different schemas, which can be chosen
+/// with `options=-csearch_path=...` in the connection string. Connections
+/// aren't encrypted, so the database should only be reached over a trusted
+/// network or a local socket.
+pub struct Postgres {
+    vfs_path: Utf8PathBuf,
+    pool: Pool,
+    schema: OnceCell<()>,
+    open_files: Mutex<HashMap<String, Arc<AsyncMutex<OpenFile>>>>,
+    open_dirs: Mutex<HashMap<String, String>>,
+}
+
+struct OpenFile {
+    path: String,
+    /// The local copy of a file that's open for writing.
+    spool: Option<File>,
+    /// Whether the spool has changes that haven't been stored yet.
+    dirty: bool,
+}
+
+impl Postgres {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        connection_string: &str,
+        password_file: Option<&Utf8Path>,
+        options: &PostgresOptions,
+    ) -> Result<Self, Error> {
+        let mut config = connection_string
+            .parse::<tokio_postgres::Config>()
+            .map_err(io::Error::other)
+            .into_io_error("invalid PostgreSQL connection string")?;
+
+        if let Some(password_file) = password_file {
+            let password = std::fs::read_to_string(password_file)
+                .into_io_error("failed to read PostgreSQL password")?;
+            config.password(password.trim_end());
+        }
+        if config.get_connect_timeout().is_none() {
+            config.connect_timeout(CONNECT_TIMEOUT);
+        }
+
+        let manager = Manager::from_config(
+            config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(options.max_connections)
+            .build()
+            .map_err(io::Error::other)
+            .into_io_error("failed to create PostgreSQL pool")?;
+
+        Ok(Self {
+            vfs_path,
+            pool,
+            schema: OnceCell::new(),
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        })
+    }
+
+    /// A connection to the database, which has the tables in it.
+    async fn client(&self) -> Result<Object, Error> {
+        let client = self
+            .pool
+            .get()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to connect to PostgreSQL")?;
+
+        self.schema
+            .get_or_try_init(async || {
+                client
+                    .batch_execute(SCHEMA)
+                    .await
+                    .map_err(io::Error::other)
+                    .into_io_error("failed to create PostgreSQL tables")
+            })
+            .await?;
+
+        Ok(client)
+    }
+
+    /// Fetches the whole file at `path` into `spool`.
+    async fn download(&self, path: &str, spool: &mut File) -> Result<(), Error> {
+        let client = self.client().await?;
+        let mut first = 0;
+
+        loop {
+            let chunks = read_chunks(&client, path, first, first + CHUNKS_PER_QUERY - 1).await?;
+
+            for row in &chunks {
+                spool
+                    .write_all(row.get::<_, &[u8]>("data"))
+                    .await
+                    .into_io_error("failed to write spool file")?;
+            }
+
+            if chunks.len() < usize::try_from(CHUNKS_PER_QUERY).unwrap_or(usize::MAX) {
+                return Ok(());
+            }
+            first += CHUNKS_PER_QUERY;
+        }
+    }
+
+    /// Replaces the contents of the file with its spool, if it has changed
+    /// since it was last stored.
+    async fn upload(&self, file: &mut OpenFile) -> Result<(), Error> {
+        let Some(spool) = &file.spool else {
+            return Ok(());
+        };
+        if !file.dirty {
+            return Ok(());
+        }
+
+        let mut body = spool
+            .try_clone()
+            .await
+            .into_io_error("failed to read spool file")?;
+        body.seek(SeekFrom::Start(0))
+            .await
+            .into_io_error("failed to read spool file")?;
+
+        let mut client = self.client().await?;
+        let transaction = client
+            .transaction()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to store file")?;
+
+        transaction
+            .execute("DELETE FROM schlep_chunks WHERE path = $1", &[&file.path])
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to store file")?;
+
+        let mut size = 0i64;
+        let mut chunk = 0i64;
+        loop {
+            let mut data = Vec::with_capacity(CHUNK_SIZE);
+            (&mut body)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut data)
+                .await
+                .into_io_error("failed to read spool file")?;
+            if data.is_empty() {
+                break;
+            }
+
+            transaction
+                .execute(
+                    "INSERT INTO schlep_chunks (path, chunk, data) VALUES ($1, $2, $3)",
+                    &[&file.path, &chunk, &data],
+                )
+                .await
+                .map_err(io::Error::other)
+                .into_io_error("failed to store file")?;
+
+            size += i64::try_from(data.len()).unwrap_or(i64::MAX);
+            chunk += 1;
+        }
+
+        let updated = transaction
+            .execute(
+                "UPDATE schlep_files SET size = $2, mtime = now(), generation = generation + 1 \
+                 WHERE path = $1",
+                &[&file.path, &size],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to store file")?;
+        if updated == 0 {
+            return Err(Error::FileNotFound);
+        }
+
+        transaction
+            .commit()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to store file")?;
+        file.dirty = false;
+
+        Ok(())
+    }
+
+    fn get_file(&self, handle: &Handle) -> Result<Arc<AsyncMutex<OpenFile>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    fn get_dir(&self, handle: &Handle) -> Result<String, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        self.open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    /// The metadata of the file at `path`, which is already a key.
+    async fn stat_key(&self, path: &str) -> Result<Metadata, Error> {
+        let client = self.client().await?;
+
+        stat_row(&client, path)
+            .await?
+            .as_ref()
+            .map(metadata)
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+#[async_trait]
+impl Vfs for Postgres {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+        let key = key(path)?;
+
+        let exists = match self.stat_key(&key).await {
+            Ok(metadata) if metadata.is_directory => return Err(Error::NotAFile),
+            Ok(_) => true,
+            Err(Error::FileNotFound) if writing && flags.contains(OpenFlags::CREATE) => false,
+            Err(err) => return Err(err),
+        };
+
+        if exists && flags.contains(OpenFlags::EXCLUDE) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error(format!("couldn't open file {path}"));
+        }
+
+        if !exists {
+            let client = self.client().await?;
+            let parent = parent_key(&key).ok_or_else(|| Error::InvalidPath(path.into()))?;
+            check_parent(&client, &parent).await?;
+
+            client
+                .execute(
+                    "INSERT INTO schlep_files (path, parent, is_directory) VALUES ($1, $2, false) \
+                     ON CONFLICT DO NOTHING",
+                    &[&key, &parent],
+                )
+                .await
+                .map_err(io::Error::other)
+                .into_io_error(format!("couldn't create file {path}"))?;
+        }
+
+        let spool = if writing {
+            let mut spool =
+                File::from_std(tempfile::tempfile().into_io_error("failed to create spool file")?);
+
+            if exists && !flags.contains(OpenFlags::TRUNCATE) {
+                self.download(&key, &mut spool).await?;
+            }
+
+            Some(spool)
+        } else {
+            None
+        };
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        let mut file = OpenFile {
+            path: key,
+            spool,
+            // Truncated files have to be emptied even if nothing is ever
+            // written to them.
+            dirty: writing && exists && flags.contains(OpenFlags::TRUNCATE),
+        };
+
+        if file.dirty {
+            self.upload(&mut file).await?;
+        }
+
+        self.open_files
+            .lock()
+            .insert(vfs_handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let key = key(path)?;
+        if !self.stat_key(&key).await?.is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs.lock().insert(vfs_handle.clone(), key);
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => {
+                let file = self.open_files.lock().remove(handle.vfs_handle());
+
+                if let Some(file) = file {
+                    self.upload(&mut *file.lock().await).await?;
+                }
+            }
+            HandleType::Dir => {
+                self.open_dirs.lock().remove(handle.vfs_handle());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::ATOMIC_RENAME
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        if let Some(spool) = &mut file.spool {
+            let mut buf = Vec::with_capacity(len);
+
+            spool
+                .seek(SeekFrom::Start(offset))
+                .await
+                .into_io_error("failed to seek spool file")?;
+            let bytes_read = spool
+                .take(len as u64)
+                .read_to_end(&mut buf)
+                .await
+                .into_io_error("failed to read spool file")?;
+
+            return Ok((bytes_read != 0 || len == 0).then_some(buf));
+        }
+
+        if len == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        let chunk_size = CHUNK_SIZE as u64;
+        let first = offset / chunk_size;
+        let last = (offset + len as u64 - 1) / chunk_size;
+
+        let client = self.client().await?;
+        let chunks = read_chunks(
+            &client,
+            &file.path,
+            i64::try_from(first).unwrap_or(i64::MAX),
+            i64::try_from(last).unwrap_or(i64::MAX),
+        )
+        .await?;
+
+        let mut data = Vec::with_capacity(chunks.len() * CHUNK_SIZE);
+        for row in &chunks {
+            data.extend_from_slice(row.get::<_, &[u8]>("data"));
+        }
+
+        let start = usize::try_from(offset - first * chunk_size)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let data = &data[start..data.len().min(start + len)];
+
+        Ok((!data.is_empty()).then(|| data.to_vec()))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let path = self.get_dir(handle)?;
+        let client = self.client().await?;
+
+        let rows = client
+            .query(
+                "SELECT path, is_directory, size, atime, mtime, generation FROM schlep_files \
+                 WHERE parent = $1",
+                &[&path],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to list directory")?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let name = Utf8Path::new(row.get::<_, &str>("path")).file_name()?;
+
+                Some((Utf8PathBuf::from(name), metadata(row)))
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .seek(SeekFrom::Start(offset))
+            .await
+            .into_io_error("failed to seek spool file")?;
+        spool
+            .write_all(data)
+            .await
+            .into_io_error("failed to write spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        if handle.handle_type() == HandleType::Dir {
+            return self.stat_key(&self.get_dir(handle)?).await;
+        }
+
+        let file = self.get_file(handle)?;
+        let file = file.lock().await;
+
+        match &file.spool {
+            Some(spool) => {
+                let metadata = spool
+                    .metadata()
+                    .await
+                    .into_io_error("failed to get spool file metadata")?;
+
+                Ok(Metadata {
+                    size: Some(metadata.len()),
+                    mtime: metadata.modified().ok(),
+                    ..Metadata::default()
+                })
+            }
+            None => self.stat_key(&file.path).await,
+        }
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        self.upload(&mut file).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let from_key = key(from)?;
+        let to_key = key(to)?;
+        if from_key.is_empty() || to_key.starts_with(&format!("{from_key}/")) {
+            return Err(Error::InvalidPath(to.into()));
+        }
+        if from_key == to_key {
+            return Ok(());
+        }
+        let parent = parent_key(&to_key).ok_or_else(|| Error::InvalidPath(to.into()))?;
+
+        let mut client = self.client().await?;
+        let transaction = client
+            .transaction()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to rename file")?;
+
+        let source = stat_row(&transaction, &from_key)
+            .await?
+            .ok_or(Error::FileNotFound)?;
+        check_parent(&transaction, &parent).await?;
+
+        // Like on a local filesystem, whatever is at the destination is
+        // replaced, as long as it isn't a directory with something in it.
+        if let Some(destination) = stat_row(&transaction, &to_key).await? {
+            let source_is_directory = source.get::<_, bool>("is_directory");
+            match (
+                source_is_directory,
+                destination.get::<_, bool>("is_directory"),
+            ) {
+                (true, false) => return Err(Error::NotADirectory),
+                (false, true) => return Err(Error::NotAFile),
+                (true, true) if has_children(&transaction, &to_key).await? => {
+                    return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty))
+                        .into_io_error("failed to rename file");
+                }
+                _ => (),
+            }
+
+            transaction
+                .execute("DELETE FROM schlep_files WHERE path = $1", &[&to_key])
+                .await
+                .map_err(io::Error::other)
+                .into_io_error("failed to rename file")?;
+        }
+
+        // Everything under a directory moves with it. Their chunks follow
+        // along through the foreign key.
+        transaction
+            .execute(
+                "UPDATE schlep_files SET \
+                 path = $2 || substr(path, length($1) + 1), \
+                 parent = CASE WHEN path = $1 THEN $3 \
+                 ELSE $2 || substr(parent, length($1) + 1) END \
+                 WHERE path = $1 OR starts_with(path, $1 || '/')",
+                &[&from_key, &to_key, &parent],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to rename file")?;
+
+        transaction
+            .commit()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to rename file")?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.stat_key(&key(path)?).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // There are no links in the database, so nothing needs to be followed.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, _path: &Utf8Path) -> Result<FsMetadata, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn statvfs_fd(&self, _handle: &Handle) -> Result<FsMetadata, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let path = key(path)?;
+        if self.stat_key(&path).await?.is_directory {
+            return Err(Error::NotAFile);
+        }
+
+        let client = self.client().await?;
+        let mut hasher = BlockHasher::new(algorithm, len, block_size);
+        let chunk_size = CHUNK_SIZE as u64;
+        let mut first = i64::try_from(offset / chunk_size).unwrap_or(i64::MAX);
+        let mut skip = usize::try_from(offset % chunk_size).unwrap_or(0);
+
+        loop {
+            let chunks = read_chunks(&client, &path, first, first + CHUNKS_PER_QUERY - 1).await?;
+
+            for row in &chunks {
+                let data = row.get::<_, &[u8]>("data");
+                hasher.update(&data[skip.min(data.len())..]);
+                skip = 0;
+            }
+
+            if hasher.is_done()
+                || chunks.len() < usize::try_from(CHUNKS_PER_QUERY).unwrap_or(usize::MAX)
+            {
+                return Ok(hasher.finish());
+            }
+            first += CHUNKS_PER_QUERY;
+        }
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let key = key(path)?;
+        let parent = parent_key(&key).ok_or_else(|| Error::InvalidPath(path.into()))?;
+        let client = self.client().await?;
+        check_parent(&client, &parent).await?;
+
+        let created = client
+            .execute(
+                "INSERT INTO schlep_files (path, parent, is_directory) VALUES ($1, $2, true) \
+                 ON CONFLICT DO NOTHING",
+                &[&key, &parent],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to create directory")?;
+
+        if created == 0 {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error("failed to create directory");
+        }
+
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        let key = key(path)?;
+        let client = self.client().await?;
+
+        let removed = client
+            .execute(
+                "DELETE FROM schlep_files WHERE path = $1 AND NOT is_directory",
+                &[&key],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to remove file")?;
+
+        if removed == 0 {
+            return match stat_row(&client, &key).await? {
+                Some(_) => Err(Error::NotAFile),
+                None => Err(Error::FileNotFound),
+            };
+        }
+
+        Ok(())
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        let key = key(path)?;
+        if key.is_empty() {
+            return Err(Error::InvalidPath(path.into()));
+        }
+
+        let mut client = self.client().await?;
+        let transaction = client
+            .transaction()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to remove directory")?;
+
+        let row = stat_row(&transaction, &key)
+            .await?
+            .ok_or(Error::FileNotFound)?;
+        if !row.get::<_, bool>("is_directory") {
+            return Err(Error::NotADirectory);
+        }
+        if has_children(&transaction, &key).await? {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty))
+                .into_io_error("failed to remove directory");
+        }
+
+        transaction
+            .execute("DELETE FROM schlep_files WHERE path = $1", &[&key])
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to remove directory")?;
+        transaction
+            .commit()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to remove directory")?;
+
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let key = key(path)?;
+        let client = self.client().await?;
+
+        let updated = client
+            .execute(
+                "UPDATE schlep_files SET atime = coalesce($2, atime), mtime = coalesce($3, mtime) \
+                 WHERE path = $1",
+                &[&key, &atime, &mtime],
+            )
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to set file times")?;
+
+        if updated == 0 {
+            return Err(Error::FileNotFound);
+        }
+
+        Ok(())
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let path = match handle.handle_type() {
+            HandleType::File => self.get_file(handle)?.lock().await.path.clone(),
+            HandleType::Dir => self.get_dir(handle)?,
+        };
+
+        self.set_times(Utf8Path::new(&path), atime, mtime).await
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        let handle = self.open(path, OpenFlags::WRITE).await?;
+        let result = self.truncate_fd(&handle, len).await;
+        let closed = self.close(handle).await;
+
+        result.and(closed)
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .set_len(len)
+            .await
+            .into_io_error("failed to truncate spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.stat(Utf8Path::new(".")).await.map(drop)
+    }
+}
+
+/// The key of `path` in `schlep_files`.
+fn key(path: &Utf8Path) -> Result<String, Error> {
+    let mut names = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(name) => names.push(name),
+            Utf8Component::CurDir | Utf8Component::RootDir => (),
+            _ => return Err(Error::InvalidPath(path.into())),
+        }
+    }
+
+    Ok(names.join("/"))
+}
+
+/// The key of the directory that the file at `key` is in, unless it's the
+/// root.
+fn parent_key(key: &str) -> Option<String> {
+    if key.is_empty() {
+        return None;
+    }
+
+    Some(
+        key.rsplit_once('/')
+            .map_or("", |(parent, _)| parent)
+            .to_owned(),
+    )
+}
+
+fn metadata(row: &Row) -> Metadata {
+    Metadata {
+        size: u64::try_from(row.get::<_, i64>("size")).ok(),
+        atime: row.get("atime"),
+        mtime: row.get("mtime"),
+        is_directory: row.get("is_directory"),
+        generation: u64::try_from(row.get::<_, i64>("generation")).ok(),
+        ..Metadata::default()
+    }
+}
+
+async fn stat_row(client: &impl GenericClient, key: &str) -> Result<Option<Row>, Error> {
+    client
+        .query_opt(
+            "SELECT path, is_directory, size, atime, mtime, generation FROM schlep_files \
+             WHERE path = $1",
+            &[&key],
+        )
+        .await
+        .map_err(io::Error::other)
+        .into_io_error("failed to get file metadata")
+}
+
+/// Fails unless there's a directory at `parent` to make something in.
+async fn check_parent(client: &impl GenericClient, parent: &str) -> Result<(), Error> {
+    match stat_row(client, parent).await? {
+        Some(row) if row.get::<_, bool>("is_directory") => Ok(()),
+        Some(_) => Err(Error::NotADirectory),
+        None => Err(Error::FileNotFound),
+    }
+}
+
+async fn has_children(client: &impl GenericClient, key: &str) -> Result<bool, Error> {
+    let row = client
+        .query_one(
+            "SELECT EXISTS (SELECT 1 FROM schlep_files WHERE parent = $1)",
+            &[&key],
+        )
+        .await
+        .map_err(io::Error::other)
+        .into_io_error("failed to list directory")?;
+
+    Ok(row.get(0))
+}
+
+/// The chunks of the file at `key` from `first` to `last`, in order.
+async fn read_chunks(
+    client: &impl GenericClient,
+    key: &str,
+    first: i64,
+    last: i64,
+) -> Result<Vec<Row>, Error> {
+    client
+        .query(
+            "SELECT data FROM schlep_chunks WHERE path = $1 AND chunk BETWEEN $2 AND $3 \
+             ORDER BY chunk",
+            &[&key, &first, &last],
+        )
+        .await
+        .map_err(io::Error::other)
+        .into_io_error("failed to read file")
+}
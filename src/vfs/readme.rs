@@ -0,0 +1,419 @@
+use std::{ops::Range, sync::Arc, time::SystemTime};
+
+use ahash::HashSet;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FsMetadata,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    ReadmeOptions,
+    Vfs,
+    VfsInstance,
+    hash::hash_bytes,
+};
+
+/// Shows a read-only file with text from the configuration at the root of the
+/// backend it wraps, without storing it there.
+///
+/// The file hides any file on the backend with the same name, and can't be
+/// written to, renamed, or removed. Everything else is passed through.
+pub struct Readme {
+    inner: Box<VfsInstance>,
+    name: String,
+    contents: Arc<[u8]>,
+    /// When the file was made, which is when the mount was.
+    mtime: SystemTime,
+    files: Mutex<HashSet<Handle>>,
+    /// The open handles of the root directory, whose listings the file is
+    /// added to.
+    root_dirs: Mutex<HashSet<Handle>>,
+}
+
+impl Readme {
+    pub fn new(inner: VfsInstance, options: &ReadmeOptions) -> Result<Self, Error> {
+        let mut components = Utf8Path::new(&options.name).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Utf8Component::Normal(_)), None)
+        ) {
+            return Err(Error::InvalidPath(options.name.clone().into()));
+        }
+
+        Ok(Self {
+            inner: Box::new(inner),
+            name: options.name.clone(),
+            contents: options.text.as_bytes().into(),
+            mtime: SystemTime::now(),
+            files: Mutex::default(),
+            root_dirs: Mutex::default(),
+        })
+    }
+
+    /// Whether `path` is the file.
+    fn is_readme(&self, path: &Utf8Path) -> bool {
+        let mut components = path
+            .components()
+            .filter(|component| *component != Utf8Component::CurDir);
+
+        components.next() == Some(Utf8Component::Normal(&self.name)) && components.next().is_none()
+    }
+
+    /// Whether `path` is the root of the backend.
+    fn is_root(path: &Utf8Path) -> bool {
+        path.components()
+            .all(|component| matches!(component, Utf8Component::CurDir | Utf8Component::RootDir))
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            size: Some(self.contents.len() as u64),
+            mtime: Some(self.mtime),
+            ..Metadata::default()
+        }
+    }
+
+    fn owns_file(&self, handle: &Handle) -> bool {
+        self.files.lock().contains(handle)
+    }
+
+    /// Fails with [`Error::ReadOnly`] if any of `paths` is the file.
+    fn check_writable(&self, paths: &[&Utf8Path]) -> Result<(), Error> {
+        if paths.iter().any(|path| self.is_readme(path)) {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl Vfs for Readme {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if !self.is_readme(path) {
+            return self.inner.open(path, flags).await;
+        }
+
+        if flags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        ) {
+            return Err(Error::ReadOnly);
+        }
+
+        let handle = Handle::file(format!("{:032x}", rand::random::<u128>()));
+        self.files.lock().insert(handle.clone());
+
+        Ok(handle)
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        if self.is_readme(path) {
+            return Err(Error::NotADirectory);
+        }
+
+        let handle = self.inner.open_dir(path).await?;
+        if Self::is_root(path) {
+            self.root_dirs.lock().insert(handle.clone());
+        }
+
+        Ok(handle)
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        if self.files.lock().remove(&handle) {
+            return Ok(());
+        }
+
+        self.root_dirs.lock().remove(&handle);
+        self.inner.close(handle).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.owns_file(handle) || self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if !self.owns_file(handle) {
+            return self.inner.read(handle, offset, len).await;
+        }
+
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        if start >= self.contents.len() {
+            return Ok(None);
+        }
+
+        let end = start.saturating_add(len).min(self.contents.len());
+
+        Ok(Some(self.contents[start..end].to_vec()))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        let mut entries = self.inner.read_dir(handle).await?;
+
+        if handle.handle_type() == HandleType::Dir && self.root_dirs.lock().contains(handle) {
+            entries.retain(|(name, _)| name.as_str() != self.name);
+            entries.push((Utf8PathBuf::from(&self.name), self.metadata()));
+        }
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        // The file has to be listed exactly once, which is easier with the
+        // whole listing of the root in hand.
+        if handle.handle_type() == HandleType::Dir && self.root_dirs.lock().contains(handle) {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        self.inner.read_dir_next(handle, max).await
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        if self.owns_file(handle) {
+            return Ok(self.metadata());
+        }
+
+        self.inner.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Ok(());
+        }
+
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.check_writable(&[from, to])?;
+
+        self.inner.rename(from, to).await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        if self.is_readme(path) {
+            return Ok(self.metadata());
+        }
+
+        self.inner.stat(path).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        if self.is_readme(path) {
+            return Ok(self.metadata());
+        }
+
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        if self.is_readme(path) {
+            return self.inner.statvfs(Utf8Path::new(".")).await;
+        }
+
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        if self.owns_file(handle) {
+            return self.inner.statvfs(Utf8Path::new(".")).await;
+        }
+
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.check_writable(&[path, target])?;
+
+        self.inner.hardlink(path, target).await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        if self.is_readme(path) {
+            return Ok(hash_bytes(
+                &self.contents,
+                algorithm,
+                offset,
+                len,
+                block_size,
+            ));
+        }
+
+        self.inner
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        if self.is_readme(path) {
+            return Err(Error::InvalidPath(path.into()));
+        }
+
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.remove_file(path).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.is_readme(path) {
+            return Err(Error::NotADirectory);
+        }
+
+        self.inner.remove_dir(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.check_writable(&[path])?;
+
+        self.inner.truncate(path, len).await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.truncate_fd(handle, len).await
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        if self.owns_file(dst) {
+            return Err(Error::ReadOnly);
+        }
+        // The README isn't on the backend, so it can't be copied from there.
+        if self.owns_file(src) {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        self.inner
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        if self.owns_file(handle) {
+            return Err(Error::ReadOnly);
+        }
+
+        self.inner.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
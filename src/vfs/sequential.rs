@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use super::Error;
+
+/// The part of a file being written that hasn't been written out yet, for
+/// wrappers that can only write files from start to finish.
+///
+/// Clients pipeline their writes, so they can arrive out of order. Writes that
+/// arrive ahead of the ones before them are held back until the gap is filled.
+#[derive(Default)]
+pub(super) struct SequentialBuffer {
+    /// Where `buffer` starts in the file. Everything before it has been taken
+    /// out with [`SequentialBuffer::take`].
+    flushed: u64,
+    buffer: Vec<u8>,
+    /// Writes that arrived ahead of the ones before them, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl SequentialBuffer {
+    /// Where the buffer starts in the file.
+    pub(super) fn flushed(&self) -> u64 {
+        self.flushed
+    }
+
+    /// How many bytes are buffered in order, ready to be taken.
+    pub(super) fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn end(&self) -> u64 {
+        self.flushed + self.buffer.len() as u64
+    }
+
+    /// How long the file is so far, counting writes that are still pending.
+    pub(super) fn len(&self) -> u64 {
+        let pending_end = self
+            .pending
+            .last_key_value()
+            .map_or(0, |(offset, data)| offset + data.len() as u64);
+
+        self.end().max(pending_end)
+    }
+
+    /// Writes `data` at `offset`, which fails with [`Error::UnsupportedMethod`]
+    /// if that part of the file has already been taken.
+    pub(super) fn insert(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if offset < self.flushed {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        if offset > self.end() {
+            self.pending.insert(offset, data.to_vec());
+            return Ok(());
+        }
+
+        self.splice(offset, data);
+
+        while self
+            .pending
+            .first_key_value()
+            .is_some_and(|(offset, _)| *offset <= self.end())
+        {
+            if let Some((offset, data)) = self.pending.pop_first() {
+                self.splice(offset, &data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes up to `max` bytes from the start of the buffer.
+    pub(super) fn take(&mut self, max: usize) -> Vec<u8> {
+        let taken = self
+            .buffer
+            .drain(..self.buffer.len().min(max))
+            .collect::<Vec<_>>();
+        self.flushed += taken.len() as u64;
+
+        taken
+    }
+
+    /// Puts `data` into the buffer at `offset`, which must be within or right
+    /// at the end of the buffer.
+    fn splice(&mut self, offset: u64, data: &[u8]) {
+        // The buffer is in memory, so anything in it fits in a usize.
+        let start = usize::try_from(offset - self.flushed).unwrap();
+        let overlap = (self.buffer.len() - start).min(data.len());
+
+        self.buffer[start..start + overlap].copy_from_slice(&data[..overlap]);
+        self.buffer.extend_from_slice(&data[overlap..]);
+    }
+
+    /// Fills in the holes left by writes that never arrived with zeroes, like
+    /// a sparse file would.
+    pub(super) fn fill_holes(&mut self) {
+        for (offset, data) in std::mem::take(&mut self.pending) {
+            let start = usize::try_from(offset - self.flushed).unwrap();
+            if start > self.buffer.len() {
+                self.buffer.resize(start, 0);
+            }
+
+            self.splice(offset, &data);
+        }
+    }
+}
@@ -0,0 +1,716 @@
+use std::{io, ops::Range, path::PathBuf, sync::Arc, time::SystemTime};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use russh::keys::PublicKey;
+use russh_sftp::{
+    client::{RawSftpSession, error::Error as SftpError},
+    protocol::{FileAttributes, StatusCode},
+};
+use tokio::sync::{Mutex as AsyncMutex, broadcast};
+use tracing::{Level, event};
+use url::Url;
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags, from_system_time},
+};
+use crate::{ssh_client::SshClient, vfs::error::IntoIoError};
+
+/// How much of a file is read at a time while hashing it.
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A backend that re-exports a directory on another SFTP server, so that
+/// Schlep can put its own authentication in front of SFTP servers that can't
+/// be integrated with it directly.
+///
+/// Every client of the mount shares one connection to the remote server,
+/// which is made the first time it's needed, and made again if it drops.
+/// Handles that were opened on a connection that has dropped stop working.
+pub struct SftpProxyDir {
+    vfs_path: Utf8PathBuf,
+    url: Url,
+    root: Utf8PathBuf,
+    private_key_file: Utf8PathBuf,
+    host_key: PublicKey,
+    connection: AsyncMutex<Option<Arc<Connection>>>,
+    handles: Mutex<HashMap<String, RemoteHandle>>,
+}
+
+struct Connection {
+    session: RawSftpSession,
+    client: SshClient,
+}
+
+#[derive(Clone)]
+struct RemoteHandle {
+    connection: Arc<Connection>,
+    handle: String,
+}
+
+impl SftpProxyDir {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        url: Url,
+        private_key_file: Utf8PathBuf,
+        host_key: &str,
+    ) -> Result<Self, Error> {
+        let host_key = PublicKey::from_openssh(host_key)
+            .map_err(io::Error::other)
+            .into_io_error("invalid host key for SFTP proxy")?;
+        let root = match percent_decode_str(url.path()).decode_utf8() {
+            Ok(path) if path.is_empty() || path == "/" => Utf8PathBuf::from("."),
+            Ok(path) => Utf8PathBuf::from(&*path),
+            Err(_) => return Err(Error::InvalidPath(PathBuf::from(url.path()))),
+        };
+
+        Ok(Self {
+            vfs_path,
+            url,
+            root,
+            private_key_file,
+            host_key,
+            connection: AsyncMutex::new(None),
+            handles: Mutex::default(),
+        })
+    }
+
+    /// The connection to the remote server, which is made first if there
+    /// isn't a live one.
+    async fn connection(&self) -> Result<Arc<Connection>, Error> {
+        let mut connection = self.connection.lock().await;
+
+        if let Some(connection) = &*connection {
+            if !connection.client.is_closed() {
+                return Ok(connection.clone());
+            }
+
+            event!(Level::INFO, url = %self.url, "reconnecting to SFTP server");
+        }
+
+        let client = SshClient::connect(
+            &self.url,
+            self.private_key_file.as_std_path(),
+            Some(self.host_key.clone()),
+        )
+        .await
+        .map_err(io::Error::other)
+        .into_io_error("failed to connect to SFTP server")?;
+        let session = RawSftpSession::new(
+            client
+                .sftp()
+                .await
+                .map_err(io::Error::other)
+                .into_io_error("failed to connect to SFTP server")?,
+        );
+        session
+            .init()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to start SFTP session")?;
+
+        let new_connection = Arc::new(Connection { session, client });
+        *connection = Some(new_connection.clone());
+
+        Ok(new_connection)
+    }
+
+    /// The path on the remote server of `path`.
+    fn remote_path(&self, path: &Utf8Path) -> Result<String, Error> {
+        let mut remote_path = self.root.clone();
+
+        for component in path.components() {
+            match component {
+                Utf8Component::Normal(name) => remote_path.push(name),
+                Utf8Component::CurDir => (),
+                _ => return Err(Error::InvalidPath(PathBuf::from(path))),
+            }
+        }
+
+        Ok(remote_path.into_string())
+    }
+
+    fn remote_handle(&self, handle: &Handle) -> Result<RemoteHandle, Error> {
+        self.handles
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    fn add_handle(&self, connection: Arc<Connection>, handle: String) -> String {
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.handles
+            .lock()
+            .insert(vfs_handle.clone(), RemoteHandle { connection, handle });
+
+        vfs_handle
+    }
+
+    async fn hash_slices(
+        &self,
+        path: &Utf8Path,
+        mut offset: u64,
+        mut hasher: BlockHasher,
+    ) -> Result<BlockHasher, Error> {
+        let connection = self.connection().await?;
+        let session = &connection.session;
+        let handle = session
+            .open(
+                self.remote_path(path)?,
+                russh_sftp::protocol::OpenFlags::READ,
+                FileAttributes::empty(),
+            )
+            .await
+            .map_err(|err| sftp_error(err, "failed to open file"))?
+            .handle;
+
+        let result = loop {
+            let wanted = hasher.wanted(HASH_BUFFER_SIZE);
+            if wanted == 0 {
+                break Ok(());
+            }
+
+            match session
+                .read(&handle, offset, u32::try_from(wanted).unwrap_or(u32::MAX))
+                .await
+            {
+                Ok(data) => {
+                    hasher.update(&data.data);
+                    offset += data.data.len() as u64;
+                }
+                Err(err) if is_eof(&err) => break Ok(()),
+                Err(err) => break Err(sftp_error(err, "failed to read file")),
+            }
+        };
+
+        session
+            .close(handle)
+            .await
+            .map_err(|err| sftp_error(err, "failed to close file"))?;
+        result?;
+
+        Ok(hasher)
+    }
+}
+
+#[async_trait]
+impl Vfs for SftpProxyDir {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let connection = self.connection().await?;
+        let handle = connection
+            .session
+            .open(
+                self.remote_path(path)?,
+                flags.into(),
+                FileAttributes::empty(),
+            )
+            .await
+            .map_err(|err| sftp_error(err, format!("couldn't open file {path}")))?
+            .handle;
+
+        Ok(Handle::file(self.add_handle(connection, handle)))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let connection = self.connection().await?;
+        let handle = connection
+            .session
+            .opendir(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, format!("couldn't open directory {path}")))?
+            .handle;
+
+        Ok(Handle::dir(self.add_handle(connection, handle)))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        let Some(remote) = self.handles.lock().remove(handle.vfs_handle()) else {
+            return Ok(());
+        };
+
+        remote
+            .connection
+            .session
+            .close(remote.handle)
+            .await
+            .map_err(|err| sftp_error(err, "failed to close handle"))?;
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.handles.lock().contains_key(handle.vfs_handle())
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::SYMLINKS
+            | Capabilities::HARDLINKS
+            | Capabilities::STATVFS
+            | Capabilities::PERMISSIONS
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let remote = self.remote_handle(handle)?;
+        let mut buf = Vec::with_capacity(len);
+
+        // Servers may send less than was asked for, so keep asking until the
+        // buffer is full or the file ends.
+        while buf.len() < len {
+            let remaining = u32::try_from(len - buf.len()).unwrap_or(u32::MAX);
+
+            match remote
+                .connection
+                .session
+                .read(&remote.handle, offset + buf.len() as u64, remaining)
+                .await
+            {
+                Ok(data) if data.data.is_empty() => break,
+                Ok(data) => buf.extend_from_slice(&data.data),
+                Err(err) if is_eof(&err) => break,
+                Err(err) => return Err(sftp_error(err, "failed to read file")),
+            }
+        }
+
+        if buf.is_empty() && len != 0 {
+            Ok(None)
+        } else {
+            Ok(Some(buf))
+        }
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let remote = self.remote_handle(handle)?;
+        let mut entries = Vec::new();
+
+        loop {
+            match remote.connection.session.readdir(&remote.handle).await {
+                Ok(name) => entries.extend(
+                    name.files
+                        .into_iter()
+                        .filter(|file| file.filename != "." && file.filename != "..")
+                        .map(|file| (Utf8PathBuf::from(file.filename), Metadata::from(file.attrs))),
+                ),
+                Err(err) if is_eof(&err) => break,
+                Err(err) => return Err(sftp_error(err, "couldn't get directory entries")),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let remote = self.remote_handle(handle)?;
+        remote
+            .connection
+            .session
+            .write(&remote.handle, offset, data.to_vec())
+            .await
+            .map_err(|err| sftp_error(err, "failed to write file"))?;
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        let remote = self.remote_handle(handle)?;
+        let attrs = remote
+            .connection
+            .session
+            .fstat(&remote.handle)
+            .await
+            .map_err(|err| sftp_error(err, "failed to get file metadata"))?
+            .attrs;
+
+        Ok(Metadata::from(attrs))
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        let remote = self.remote_handle(handle)?;
+        remote
+            .connection
+            .session
+            .fsync(&remote.handle)
+            .await
+            .map_err(|err| sftp_error(err, "failed to sync file"))?;
+
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .rename(self.remote_path(from)?, self.remote_path(to)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to rename file"))?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let attrs = self
+            .connection()
+            .await?
+            .session
+            .stat(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to get metadata"))?
+            .attrs;
+
+        Ok(Metadata::from(attrs))
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let attrs = self
+            .connection()
+            .await?
+            .session
+            .lstat(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to get symlink metadata"))?
+            .attrs;
+
+        Ok(Metadata::from(attrs))
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        let stat = self
+            .connection()
+            .await?
+            .session
+            .statvfs(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to get filesystem metadata"))?;
+
+        Ok(FsMetadata {
+            block_size: stat.fragment_size,
+            num_blocks: stat.blocks,
+            free_blocks: stat.blocks_free,
+            num_files: stat.inodes,
+            free_files: stat.inodes_free,
+            read_only: stat.flags & 0x1 != 0,
+            max_length: stat.name_max,
+        })
+    }
+
+    async fn statvfs_fd(&self, _handle: &Handle) -> Result<FsMetadata, Error> {
+        // The client can't send `fstatvfs@openssh.com`, so this goes by the
+        // root instead, which everything on the server usually shares a
+        // filesystem with.
+        self.statvfs(Utf8Path::new("")).await
+    }
+
+    async fn hardlink(&self, source: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .hardlink(self.remote_path(source)?, self.remote_path(target)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to create hardlink"))?;
+
+        Ok(())
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        let link_dir = path.parent().unwrap_or(Utf8Path::new(""));
+        let relative_target = pathdiff::diff_utf8_paths(target, link_dir)
+            .ok_or_else(|| Error::InvalidPath(PathBuf::from(target)))?;
+
+        self.connection()
+            .await?
+            .session
+            .symlink(self.remote_path(path)?, relative_target.into_string())
+            .await
+            .map_err(|err| sftp_error(err, "failed to create symlink"))?;
+
+        Ok(())
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let hasher = BlockHasher::new(algorithm, len, block_size);
+
+        Ok(self.hash_slices(path, offset, hasher).await?.finish())
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let name = self
+            .connection()
+            .await?
+            .session
+            .readlink(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to read symlink"))?;
+        let Some(file) = name.files.into_iter().next() else {
+            return Err(Error::FileNotFound);
+        };
+
+        let link_contents = Utf8PathBuf::from(file.filename);
+        if link_contents.is_absolute() {
+            link_contents
+                .strip_prefix(&self.root)
+                .map(Utf8Path::to_path_buf)
+                .map_err(|_| Error::WouldEscape)
+        } else {
+            Ok(link_contents)
+        }
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .mkdir(self.remote_path(path)?, FileAttributes::empty())
+            .await
+            .map_err(|err| sftp_error(err, "failed to create directory"))?;
+
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .remove(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to remove file"))?;
+
+        Ok(())
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .rmdir(self.remote_path(path)?)
+            .await
+            .map_err(|err| sftp_error(err, "failed to remove directory"))?;
+
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let Some(attrs) = time_attrs(self.stat(path).await?, atime, mtime) else {
+            return Ok(());
+        };
+
+        self.connection()
+            .await?
+            .session
+            .setstat(self.remote_path(path)?, attrs)
+            .await
+            .map_err(|err| sftp_error(err, "failed to set file times"))?;
+
+        Ok(())
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let Some(attrs) = time_attrs(self.stat_fd(handle).await?, atime, mtime) else {
+            return Ok(());
+        };
+
+        let remote = self.remote_handle(handle)?;
+        remote
+            .connection
+            .session
+            .fsetstat(&remote.handle, attrs)
+            .await
+            .map_err(|err| sftp_error(err, "failed to set file times"))?;
+
+        Ok(())
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .setstat(self.remote_path(path)?, permission_attrs(mode))
+            .await
+            .map_err(|err| sftp_error(err, "failed to set permissions"))?;
+
+        Ok(())
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        let remote = self.remote_handle(handle)?;
+        remote
+            .connection
+            .session
+            .fsetstat(&remote.handle, permission_attrs(mode))
+            .await
+            .map_err(|err| sftp_error(err, "failed to set permissions"))?;
+
+        Ok(())
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.connection()
+            .await?
+            .session
+            .setstat(self.remote_path(path)?, size_attrs(len))
+            .await
+            .map_err(|err| sftp_error(err, "failed to truncate file"))?;
+
+        Ok(())
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let remote = self.remote_handle(handle)?;
+        remote
+            .connection
+            .session
+            .fsetstat(&remote.handle, size_attrs(len))
+            .await
+            .map_err(|err| sftp_error(err, "failed to truncate file"))?;
+
+        Ok(())
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.stat(Utf8Path::new(".")).await.map(drop)
+    }
+}
+
+/// The attributes to set `atime` and `mtime` with. SFTP can only set both at
+/// once, so the one that isn't being changed is taken from `current`.
+fn time_attrs(
+    current: Metadata,
+    atime: Option<SystemTime>,
+    mtime: Option<SystemTime>,
+) -> Option<FileAttributes> {
+    if atime.is_none() && mtime.is_none() {
+        return None;
+    }
+
+    let mut attrs = FileAttributes::empty();
+    attrs.atime = atime.or(current.atime).and_then(from_system_time);
+    attrs.mtime = mtime.or(current.mtime).and_then(from_system_time);
+
+    Some(attrs)
+}
+
+fn permission_attrs(mode: u32) -> FileAttributes {
+    let mut attrs = FileAttributes::empty();
+    attrs.permissions = Some(mode);
+
+    attrs
+}
+
+fn size_attrs(len: u64) -> FileAttributes {
+    let mut attrs = FileAttributes::empty();
+    attrs.size = Some(len);
+
+    attrs
+}
+
+fn is_eof(err: &SftpError) -> bool {
+    matches!(err, SftpError::Status(status) if status.status_code == StatusCode::Eof)
+}
+
+fn sftp_error(err: SftpError, context: impl Into<String>) -> Error {
+    let kind = match &err {
+        SftpError::Status(status) => match status.status_code {
+            StatusCode::NoSuchFile => return Error::FileNotFound,
+            StatusCode::OpUnsupported => return Error::UnsupportedMethod,
+            StatusCode::PermissionDenied => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        },
+        SftpError::Timeout => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+
+    Error::IoError {
+        source: io::Error::new(kind, err),
+        from: context.into(),
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers for per-mount state that is kept in a JSON file outside of the
+//! mount, like legal holds and WORM seals.
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Error, error::IntoIoError};
+
+/// Reads the state from `file`, or returns the default state if the file
+/// doesn't exist yet.
+pub(super) fn load<T: DeserializeOwned + Default>(file: &Utf8Path) -> Result<T, Error> {
+    match std::fs::read(file) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+            .into_io_error(format!("failed to parse state file {file}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into_io_error(format!("failed to read state file {file}"))),
+    }
+}
+
+/// Replaces the contents of `file` with `state`.
+///
+/// The state is written to a temporary file first, so that a crash can't leave
+/// a truncated file behind.
+pub(super) async fn save<T: Serialize>(file: &Utf8Path, state: &T) -> Result<(), Error> {
+    let contents = serde_json::to_vec_pretty(state)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+        .into_io_error("failed to serialize state")?;
+
+    let temp_file = file.with_extension("tmp");
+    tokio::fs::write(&temp_file, contents)
+        .await
+        .into_io_error(format!("failed to write state file {temp_file}"))?;
+    tokio::fs::rename(&temp_file, file)
+        .await
+        .into_io_error(format!("failed to replace state file {file}"))
+}
+
+/// Drops `.` components from a path relative to the root of a mount, so that
+/// the root itself is the empty path and every path has a single spelling.
+pub(super) fn normalize(path: &Utf8Path) -> Utf8PathBuf {
+    path.components()
+        .filter(|component| *component != Utf8Component::CurDir)
+        .collect()
+}
@@ -0,0 +1,389 @@
+use std::{
+    ops::Range,
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use thiserror_ext::AsReport;
+use tokio::{sync::broadcast, time::MissedTickBehavior};
+use tracing::{Level, event};
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    TrashOptions,
+    Vfs,
+    VfsInstance,
+    error::IntoIoError,
+    state_file::normalize,
+};
+
+/// The directory at the root of the backend that deleted entries are moved to.
+pub(super) const TRASH_DIR: &str = ".trash";
+
+/// Moves files and directories into a `.trash` directory at the root of the
+/// backend it wraps when they're removed, instead of deleting them.
+///
+/// Each removal goes into a directory of its own below `.trash`, named after
+/// when it happened in milliseconds since the Unix epoch, at the same path it
+/// had in the mount. Entries are restored by renaming them back out, and
+/// removing anything inside `.trash` deletes it for good. A background task
+/// deletes removals once they're older than the retention period.
+pub struct Trash {
+    inner: Arc<VfsInstance>,
+}
+
+impl Trash {
+    pub fn new(inner: VfsInstance, options: &TrashOptions) -> Self {
+        let inner = Arc::new(inner);
+        tokio::spawn(purge_periodically(
+            Arc::downgrade(&inner),
+            options.retention,
+            options.purge_interval,
+        ));
+
+        Self { inner }
+    }
+
+    /// Whether `path` is in the trash, or is the trash itself.
+    fn in_trash(path: &Utf8Path) -> bool {
+        normalize(path).starts_with(TRASH_DIR)
+    }
+
+    /// Moves `path` into a new directory in the trash.
+    async fn move_to_trash(&self, path: &Utf8Path) -> Result<(), Error> {
+        let deleted_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis());
+        let destination = Utf8Path::new(TRASH_DIR)
+            .join(deleted_at.to_string())
+            .join(normalize(path));
+
+        if let Some(parent) = destination.parent() {
+            create_dir_all(&self.inner, parent).await?;
+        }
+
+        self.inner.rename(path, &destination).await
+    }
+}
+
+/// Creates `path` in `vfs`, along with every missing directory above it.
+pub(super) async fn create_dir_all(vfs: &VfsInstance, path: &Utf8Path) -> Result<(), Error> {
+    for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+        if dir.as_str().is_empty() {
+            continue;
+        }
+
+        if let Err(err) = vfs.mkdir(dir).await {
+            // Someone else may have made it in the meantime.
+            match vfs.stat(dir).await {
+                Ok(metadata) if metadata.is_directory() => (),
+                _ => return Err(err),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Purges `inner`'s trash every `interval`, until the mount is gone.
+async fn purge_periodically(inner: Weak<VfsInstance>, retention: Duration, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+
+        if let Err(err) = purge(&inner, retention).await {
+            event!(
+                Level::WARN,
+                vfs_root = %inner.vfs_root(),
+                err = %err.as_report(),
+                "failed to purge trash"
+            );
+        }
+    }
+}
+
+/// Deletes the removals in `inner`'s trash that are older than `retention`.
+async fn purge(inner: &VfsInstance, retention: Duration) -> Result<(), Error> {
+    let trash = Utf8Path::new(TRASH_DIR);
+    let handle = match inner.open_dir(trash).await {
+        Ok(handle) => handle,
+        Err(Error::FileNotFound) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let entries = inner.read_dir(&handle).await;
+    inner.close(handle).await?;
+
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .and_then(|cutoff| cutoff.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_millis());
+
+    for (name, _) in entries? {
+        // Anything that isn't named like a removal was put there by hand, and
+        // is left alone.
+        let Ok(deleted_at) = name.as_str().parse::<u128>() else {
+            continue;
+        };
+
+        if deleted_at < cutoff {
+            remove_tree(inner, &trash.join(name)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the directory at `path` and everything in it.
+async fn remove_tree(inner: &VfsInstance, path: &Utf8Path) -> Result<(), Error> {
+    let mut dirs = Vec::new();
+    let mut pending = vec![path.to_owned()];
+
+    while let Some(dir) = pending.pop() {
+        let handle = inner.open_dir(&dir).await?;
+        let entries = inner.read_dir(&handle).await;
+        inner.close(handle).await?;
+
+        for (name, metadata) in entries? {
+            if metadata.is_directory() {
+                pending.push(dir.join(name));
+            } else {
+                inner.remove_file(&dir.join(name)).await?;
+            }
+        }
+
+        dirs.push(dir);
+    }
+
+    // Every directory was found after the one it's in.
+    for dir in dirs.iter().rev() {
+        inner.remove_dir(dir).await?;
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Vfs for Trash {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        self.inner.open(path, flags).await
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        self.inner.open_dir(path).await
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        self.inner.close(handle).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.read(handle, offset, len).await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.inner.read_dir(handle).await
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.inner.read_dir_next(handle, max).await
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.inner.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat(path).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner.hardlink(path, target).await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        self.inner.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.inner
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        if Self::in_trash(path) {
+            return self.inner.remove_file(path).await;
+        }
+
+        if self.inner.stat_link(path).await?.is_directory() {
+            return Err(Error::NotAFile);
+        }
+
+        self.move_to_trash(path).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        if Self::in_trash(path) {
+            return self.inner.remove_dir(path).await;
+        }
+
+        if !self.inner.stat_link(path).await?.is_directory() {
+            return Err(Error::NotADirectory);
+        }
+
+        // Removing a directory only ever removes an empty one, and moving a
+        // full one into the trash mustn't change that.
+        let handle = self.inner.open_dir(path).await?;
+        let entries = self.inner.read_dir(&handle).await;
+        self.inner.close(handle).await?;
+        if !entries?.is_empty() {
+            return Err(std::io::Error::from(std::io::ErrorKind::DirectoryNotEmpty)
+                .into_io_error(format!("failed to remove directory {path}")));
+        }
+
+        self.move_to_trash(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        self.inner.truncate(path, len).await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        self.inner.truncate_fd(handle, len).await
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        self.inner
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        self.inner.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
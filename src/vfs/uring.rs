@@ -0,0 +1,160 @@
+//! File I/O through io_uring, for [`LocalDir`](super::LocalDir) mounts whose
+//! `io_engine` is `io_uring`.
+//!
+//! `tokio-uring` needs a runtime of its own, so reads and writes are sent to a
+//! thread that runs one for every such mount in the process. Handing them over
+//! costs a channel send rather than a trip through the blocking thread pool,
+//! and no thread is tied up while the kernel does the I/O.
+
+use std::{fs::File, io, sync::OnceLock, thread};
+
+use tokio::sync::{mpsc, oneshot};
+
+/// The thread that runs the io_uring runtime, started by the first mount that
+/// asks for it.
+static URING: OnceLock<io::Result<Uring>> = OnceLock::new();
+
+pub(super) struct Uring {
+    ops: mpsc::UnboundedSender<Op>,
+}
+
+enum Op {
+    Read {
+        file: File,
+        offset: u64,
+        len: usize,
+        done: oneshot::Sender<io::Result<Vec<u8>>>,
+    },
+    Write {
+        file: File,
+        offset: u64,
+        data: Vec<u8>,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+impl Uring {
+    /// The io_uring thread, which is started if it isn't running yet. Fails if
+    /// the kernel doesn't support io_uring.
+    pub(super) fn get() -> io::Result<&'static Self> {
+        URING
+            .get_or_init(Self::start)
+            .as_ref()
+            .map_err(|err| io::Error::new(err.kind(), err.to_string()))
+    }
+
+    fn start() -> io::Result<Self> {
+        let (ops, mut receiver) = mpsc::unbounded_channel::<Op>();
+        let (started_sender, started) = std::sync::mpsc::channel();
+
+        thread::Builder::new()
+            .name("schlep-uring".to_owned())
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(err) => {
+                        let _ = started_sender.send(Err(err));
+                        return;
+                    }
+                };
+                let _ = started_sender.send(Ok(()));
+
+                runtime.block_on(async move {
+                    while let Some(op) = receiver.recv().await {
+                        tokio_uring::spawn(op.run());
+                    }
+                });
+            })?;
+
+        started.recv().map_err(|_| thread_exited())??;
+
+        Ok(Self { ops })
+    }
+
+    /// Reads up to `len` bytes from `file` at `offset`, stopping short only at
+    /// the end of the file.
+    pub(super) async fn read(&self, file: File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let (done, result) = oneshot::channel();
+        self.send(Op::Read {
+            file,
+            offset,
+            len,
+            done,
+        })?;
+
+        result.await.map_err(|_| thread_exited())?
+    }
+
+    /// Writes all of `data` to `file` at `offset`.
+    pub(super) async fn write(&self, file: File, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.send(Op::Write {
+            file,
+            offset,
+            data,
+            done,
+        })?;
+
+        result.await.map_err(|_| thread_exited())?
+    }
+
+    fn send(&self, op: Op) -> io::Result<()> {
+        self.ops.send(op).map_err(|_| thread_exited())
+    }
+}
+
+impl Op {
+    async fn run(self) {
+        match self {
+            Self::Read {
+                file,
+                offset,
+                len,
+                done,
+            } => {
+                let _ = done.send(read(file, offset, len).await);
+            }
+            Self::Write {
+                file,
+                offset,
+                data,
+                done,
+            } => {
+                let _ = done.send(write(file, offset, data).await);
+            }
+        }
+    }
+}
+
+async fn read(file: File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let file = tokio_uring::fs::File::from_std(file);
+    let mut data = Vec::with_capacity(len);
+
+    let result = loop {
+        if data.len() == len {
+            break Ok(());
+        }
+
+        let buf = Vec::with_capacity(len - data.len());
+        match file.read_at(buf, offset + data.len() as u64).await {
+            (Ok(0), _) => break Ok(()),
+            (Ok(_), buf) => data.extend_from_slice(&buf),
+            (Err(err), _) => break Err(err),
+        }
+    };
+
+    file.close().await?;
+    result.map(|()| data)
+}
+
+async fn write(file: File, offset: u64, data: Vec<u8>) -> io::Result<()> {
+    let file = tokio_uring::fs::File::from_std(file);
+    let (result, _) = file.write_all_at(data, offset).await;
+
+    file.close().await?;
+    result
+}
+
+fn thread_exited() -> io::Error {
+    io::Error::other("io_uring thread exited")
+}
@@ -0,0 +1,373 @@
+use std::{ops::Range, time::SystemTime};
+
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::sync::broadcast;
+
+use super::{
+    Capabilities,
+    ChangeEvent,
+    Error,
+    FsMetadata,
+    Handle,
+    HashAlgorithm,
+    LockMode,
+    Metadata,
+    OpenFlags,
+    VersioningOptions,
+    Vfs,
+    VfsInstance,
+    state_file::normalize,
+    trash::{TRASH_DIR, create_dir_all},
+};
+
+/// The directory at the root of the backend that old revisions are kept in.
+const VERSIONS_DIR: &str = ".versions";
+
+/// How many bytes to copy at a time when taking a snapshot.
+const COPY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Keeps the previous contents of files in the backend it wraps when they are
+/// overwritten or removed.
+///
+/// The revisions of a file are kept in a directory at the same path below
+/// `.versions`, each named after when it was taken in milliseconds since the
+/// Unix epoch, and only the newest few are kept. Clients can browse and read
+/// `.versions`, and restore a revision by copying it back out, but nothing in
+/// it can be changed.
+pub struct Versioned {
+    inner: Box<VfsInstance>,
+    revisions: usize,
+}
+
+impl Versioned {
+    pub fn new(inner: VfsInstance, options: &VersioningOptions) -> Self {
+        Self {
+            inner: Box::new(inner),
+            revisions: options.revisions,
+        }
+    }
+
+    /// Whether `path` is among the revisions, or is where they're kept.
+    fn in_versions(path: &Utf8Path) -> bool {
+        normalize(path).starts_with(VERSIONS_DIR)
+    }
+
+    /// Fails with [`Error::ReadOnly`] if any of `paths` is among the revisions.
+    fn check_writable(paths: &[&Utf8Path]) -> Result<(), Error> {
+        if paths.iter().any(|path| Self::in_versions(path)) {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes a revision of the file at `path`, if there is one worth keeping,
+    /// by copying it if `keep` is set and moving it otherwise. Returns whether
+    /// a revision was taken.
+    async fn snapshot(&self, path: &Utf8Path, keep: bool) -> Result<bool, Error> {
+        // Whatever is in the trash was already removed once, and keeping it
+        // around again would outlast the trash's retention period.
+        if normalize(path).starts_with(TRASH_DIR) {
+            return Ok(false);
+        }
+
+        match self.inner.stat_link(path).await {
+            Ok(metadata) if !metadata.is_directory() => (),
+            Ok(_) | Err(Error::FileNotFound) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+
+        let taken_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis());
+        let dir = Utf8Path::new(VERSIONS_DIR).join(normalize(path));
+        let revision = dir.join(taken_at.to_string());
+
+        create_dir_all(&self.inner, &dir).await?;
+        if keep {
+            self.copy(path, &revision).await?;
+        } else {
+            self.inner.rename(path, &revision).await?;
+        }
+
+        self.prune(&dir).await?;
+
+        Ok(true)
+    }
+
+    /// Copies the contents of the file at `from` to a new file at `to`.
+    async fn copy(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let source = self.inner.open(from, OpenFlags::READ).await?;
+
+        let copied = async {
+            let target = self
+                .inner
+                .open(
+                    to,
+                    OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                )
+                .await?;
+
+            let written = async {
+                let mut offset = 0;
+                while let Some(data) = self.inner.read(&source, offset, COPY_CHUNK_SIZE).await? {
+                    self.inner.write(&target, offset, &data).await?;
+                    offset += data.len() as u64;
+                }
+
+                Ok(())
+            }
+            .await;
+
+            self.inner.close(target).await?;
+            written
+        }
+        .await;
+
+        self.inner.close(source).await?;
+        copied
+    }
+
+    /// Removes the oldest revisions in `dir` until only as many as should be
+    /// kept are left.
+    async fn prune(&self, dir: &Utf8Path) -> Result<(), Error> {
+        let handle = self.inner.open_dir(dir).await?;
+        let entries = self.inner.read_dir(&handle).await;
+        self.inner.close(handle).await?;
+
+        let mut revisions = entries?
+            .into_iter()
+            .filter(|(_, metadata)| !metadata.is_directory())
+            .filter_map(|(name, _)| Some((name.as_str().parse::<u128>().ok()?, name)))
+            .collect::<Vec<_>>();
+        revisions.sort_unstable();
+
+        let excess = revisions.len().saturating_sub(self.revisions);
+        for (_, name) in revisions.drain(..excess) {
+            self.inner.remove_file(&dir.join(name)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Vfs for Versioned {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::TRUNCATE) {
+            Self::check_writable(&[path])?;
+            self.snapshot(path, true).await?;
+        }
+
+        self.inner.open(path, flags).await
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        self.inner.open_dir(path).await
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        self.inner.close(handle).await
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        self.inner.owns_handle(handle).await
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.inner.vfs_root()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.inner.read(handle, offset, len).await
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.inner.read_dir(handle).await
+    }
+
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        self.inner.read_dir_next(handle, max).await
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.inner.write(handle, offset, data).await
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        self.inner.stat_fd(handle).await
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        self.inner.sync_fd(handle).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[from, to])?;
+
+        // The file being replaced is copied rather than moved, so that it's
+        // still there if the rename fails.
+        if normalize(from) != normalize(to) {
+            self.snapshot(to, true).await?;
+        }
+
+        self.inner.rename(from, to).await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat(path).await
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.inner.stat_link(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        self.inner.statvfs(path).await
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        self.inner.statvfs_fd(handle).await
+    }
+
+    async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[path, target])?;
+
+        self.inner.hardlink(path, target).await
+    }
+
+    async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        self.inner.symlink(path, target).await
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        self.inner
+            .hash_range(path, algorithm, offset, len, block_size)
+            .await
+    }
+
+    async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        self.inner.readlink(path).await
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        // Taking the revision moves the file out of the way, which is all that
+        // removing it has to do.
+        if self.snapshot(path, false).await? {
+            return Ok(());
+        }
+
+        self.inner.remove_file(path).await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        self.inner.remove_dir(path).await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        self.inner.set_times(path, atime, mtime).await
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        self.inner.set_times_fd(handle, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+
+        self.inner.set_permissions(path, mode).await
+    }
+
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error> {
+        self.inner.set_permissions_fd(handle, mode).await
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        Self::check_writable(&[path])?;
+        self.snapshot(path, true).await?;
+
+        self.inner.truncate(path, len).await
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        self.inner.truncate_fd(handle, len).await
+    }
+
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error> {
+        self.inner.lock(handle, range, mode).await
+    }
+
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error> {
+        self.inner.unlock(handle, range).await
+    }
+
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error> {
+        self.inner
+            .copy_range(src, src_offset, dst, dst_offset, len)
+            .await
+    }
+
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error> {
+        self.inner.allocate(handle, offset, len).await
+    }
+
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        self.inner.watch(path).await
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.inner.health_check().await
+    }
+}
@@ -1,23 +1,64 @@
 use std::{
     fmt::{Display, Formatter},
-    ops::Deref,
+    hash::{Hash, Hasher},
+    ops::{Deref, Range},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        Arc,
+        Weak,
+        atomic::{AtomicU32, Ordering},
+    },
     time::SystemTime,
 };
 
 use ahash::HashMap;
 use async_trait::async_trait;
-use camino::{Utf8Path, Utf8PathBuf};
-use digest::OutputSizeUser;
-use generic_array::GenericArray;
-use md5::Md5;
-use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
-use sha1::Sha1;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::broadcast;
 use trait_enum::trait_enum;
+use url::Url;
 
-use super::{Error, FsMetadata, Metadata, OpenFlags, local_dir::LocalDir};
+use super::{
+    BackendConfig,
+    Capabilities,
+    ChangeEvent,
+    Config,
+    Error,
+    FsMetadata,
+    FtpMode,
+    FtpOptions,
+    HashAlgorithm,
+    LocalDirOptions,
+    LockMode,
+    Metadata,
+    MountOptions,
+    NoBackendOptions,
+    OpenFlags,
+    PostgresOptions,
+    VfsConfig,
+    WebDavOptions,
+    WebHdfsAuth,
+    WebHdfsOptions,
+    archive_dir::ArchiveDir,
+    audit_log::AuditLog,
+    cached::Cached,
+    compressed::Compressed,
+    deduplicated::Deduplicated,
+    encrypted_names::EncryptedNames,
+    ftp::FtpDir,
+    local_dir::LocalDir,
+    memory::MemoryDir,
+    mount::Mount,
+    postgres::Postgres,
+    readme::Readme,
+    sftp_proxy::SftpProxyDir,
+    trash::Trash,
+    versioned::Versioned,
+    webdav::WebDav,
+    webhdfs::WebHdfs,
+};
+use crate::vfs::error::IntoIoError;
 
 /// A virtual filesystem backend suitable for exposing over the network using
 /// Schlep.
@@ -32,6 +73,10 @@ pub trait Vfs: Send {
     async fn owns_handle(&self, handle: &Handle) -> bool;
     fn vfs_root(&self) -> &Utf8Path;
 
+    /// The optional operations this backend can do. Wrappers report what the
+    /// backend they wrap can do.
+    fn capabilities(&self) -> Capabilities;
+
     /// Reads `len` bytes from the file represented by the given `handle`,
     /// starting at `offset` bytes from the start of the file.
     ///
@@ -52,6 +97,20 @@ pub trait Vfs: Send {
     ) -> Result<Option<Vec<u8>>, Error>;
 
     async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error>;
+
+    /// Lists up to `max` more entries of the directory open at `handle`,
+    /// carrying on from where the last call on the handle stopped, and
+    /// returning none once they've all been listed.
+    ///
+    /// Backends that can only list a directory all at once fail with
+    /// [`Error::UnsupportedMethod`], and are listed with [`Vfs::read_dir`]
+    /// instead.
+    async fn read_dir_next(
+        &self,
+        handle: &Handle,
+        max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error>;
+
     async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error>;
     async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error>;
     async fn sync_fd(&self, handle: &Handle) -> Result<(), Error>;
@@ -62,18 +121,29 @@ pub trait Vfs: Send {
     async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error>;
     async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error>;
 
+    /// [`Vfs::statvfs`] for the filesystem that an open file or directory is
+    /// on.
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error>;
+
     async fn hardlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error>;
 
     async fn symlink(&self, path: &Utf8Path, target: &Utf8Path) -> Result<(), Error>;
 
-    async fn md5sum(
-        &self,
-        path: &Utf8Path,
-    ) -> Result<GenericArray<u8, <Md5 as OutputSizeUser>::OutputSize>, Error>;
-    async fn sha1sum(
+    /// Hashes `len` bytes of the file at `path` starting at `offset`, or up
+    /// to the end of the file if `len` is `0`, with a separate digest for
+    /// every `block_size` bytes, or one for the whole range if `block_size` is
+    /// `0`. A range that runs past the end of the file stops there.
+    ///
+    /// Backends should hash the range a piece at a time rather than tying up a
+    /// blocking task until the whole of it is done, since it can be terabytes.
+    async fn hash_range(
         &self,
         path: &Utf8Path,
-    ) -> Result<GenericArray<u8, <Sha1 as OutputSizeUser>::OutputSize>, Error>;
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error>;
 
     async fn readlink(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error>;
     async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error>;
@@ -93,6 +163,69 @@ pub trait Vfs: Send {
         atime: Option<SystemTime>,
         mtime: Option<SystemTime>,
     ) -> Result<(), Error>;
+
+    /// Sets the permission bits of the file at `path` to `mode`. Backends that
+    /// don't keep permissions fail with [`Error::UnsupportedMethod`].
+    async fn set_permissions(&self, path: &Utf8Path, mode: u32) -> Result<(), Error>;
+
+    /// Sets the permission bits of the file open at `handle` to `mode`.
+    async fn set_permissions_fd(&self, handle: &Handle, mode: u32) -> Result<(), Error>;
+
+    /// Truncates or extends the file at `path` to `len` bytes. Extending it
+    /// fills it with zeroes, like a sparse file.
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error>;
+
+    /// Truncates or extends the file open at `handle` to `len` bytes, which
+    /// has to be open for writing.
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error>;
+
+    /// Takes an advisory lock on `range` of the file open at `handle`, where
+    /// other programs using the backend can see it. Locking a range the handle
+    /// already has locked changes the mode of the lock. A range that ends at
+    /// [`u64::MAX`] covers the rest of the file, however much it grows.
+    ///
+    /// Fails with [`Error::RangeLocked`] if someone else holds a conflicting
+    /// lock. Backends that have no locks of their own fail with
+    /// [`Error::UnsupportedMethod`], and [`Mount`] then only enforces the lock
+    /// between its own clients.
+    async fn lock(&self, handle: &Handle, range: Range<u64>, mode: LockMode) -> Result<(), Error>;
+
+    /// Releases a lock taken with [`Vfs::lock`] on exactly `range`. Closing
+    /// the handle releases all of its locks.
+    async fn unlock(&self, handle: &Handle, range: Range<u64>) -> Result<(), Error>;
+
+    /// Copies up to `len` bytes of the file open at `src`, from `src_offset`,
+    /// into the file open at `dst` at `dst_offset`, without the data passing
+    /// through Schlep if the backend can help it. Both files have to be open
+    /// on this backend, and `dst` has to be open for writing. Returns how many
+    /// bytes were copied, which is less than `len` only if `src` ended first.
+    async fn copy_range(
+        &self,
+        src: &Handle,
+        src_offset: u64,
+        dst: &Handle,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<u64, Error>;
+
+    /// Reserves space for `len` bytes of the file open at `handle` from
+    /// `offset`, without changing its size, so that writing them later can't
+    /// run out of space. Fails with [`Error::NoSpace`] if there isn't enough.
+    async fn allocate(&self, handle: &Handle, offset: u64, len: u64) -> Result<(), Error>;
+
+    /// Starts watching `path` for changes, and returns a receiver for the
+    /// changes to everything that's watched on the backend, so the receiver
+    /// should pick out the paths it cares about. Watching a directory only
+    /// reports changes to what's directly inside it.
+    ///
+    /// Backends without [`Capabilities::WATCH`] fail with
+    /// [`Error::UnsupportedMethod`].
+    async fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error>;
+
+    /// Checks that the files behind the backend are still there, like that
+    /// its root directory still exists or its server still answers, so that
+    /// readiness checks can tell when they're gone.
+    async fn health_check(&self) -> Result<(), Error>;
 }
 
 /// A trait representing a file handle within the VFS implementation.
@@ -113,10 +246,32 @@ pub trait Vfs: Send {
 ///
 /// It is essential that these raw `vfs_handle`s must be 250 bytes or less, due
 /// to limits in the underlying SFTP protocol.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Handles opened through a [`Mount`] also carry the [`Mount::id`] of the
+/// mount, so that [`VfsSet::resolve_handle`] can find it without asking every
+/// mount. It's left out of comparisons, since the `vfs_handle` already tells
+/// handles apart.
+#[derive(Debug, Clone)]
+#[allow(clippy::struct_field_names)]
 pub struct Handle {
     handle_ty: HandleType,
     vfs_handle: String,
+    mount: Option<u32>,
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Self) -> bool {
+        self.handle_ty == other.handle_ty && self.vfs_handle == other.vfs_handle
+    }
+}
+
+impl Eq for Handle {}
+
+impl Hash for Handle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.handle_ty.hash(state);
+        self.vfs_handle.hash(state);
+    }
 }
 
 impl Handle {
@@ -126,6 +281,7 @@ impl Handle {
         Handle {
             handle_ty: HandleType::File,
             vfs_handle,
+            mount: None,
         }
     }
 
@@ -136,6 +292,7 @@ impl Handle {
         Handle {
             handle_ty: HandleType::Dir,
             vfs_handle,
+            mount: None,
         }
     }
 
@@ -150,30 +307,66 @@ impl Handle {
     pub fn vfs_handle(&self) -> &str {
         self.vfs_handle.as_str()
     }
+
+    /// The [`Mount::id`] of the mount that opened the handle, if it was opened
+    /// through one.
+    #[must_use]
+    pub fn mount(&self) -> Option<u32> {
+        self.mount
+    }
+
+    /// This handle, marked as opened by the mount `id`.
+    #[must_use]
+    pub(super) fn with_mount(self, id: u32) -> Self {
+        Self {
+            mount: Some(id),
+            ..self
+        }
+    }
 }
 
 impl FromStr for Handle {
     type Err = HandleParseError;
 
     fn from_str(handle: &str) -> Result<Self, Self::Err> {
-        if handle.starts_with("dir") {
-            let handle = handle[4..].to_string();
-            Ok(Handle::dir(handle))
-        } else if handle.starts_with("file") {
-            let handle = handle[5..].to_string();
-            Ok(Handle::file(handle))
+        let invalid = || HandleParseError::InvalidHandle(handle.to_string());
+
+        let (handle_ty, rest) = if let Some(rest) = handle.strip_prefix("dir") {
+            (HandleType::Dir, rest)
+        } else if let Some(rest) = handle.strip_prefix("file") {
+            (HandleType::File, rest)
         } else {
-            Err(HandleParseError::InvalidHandle(handle.to_string()))
-        }
+            return Err(invalid());
+        };
+
+        let (mount, vfs_handle) = match rest.strip_prefix('@') {
+            Some(rest) => {
+                let (mount, vfs_handle) = rest.split_once('_').ok_or_else(invalid)?;
+                (Some(mount.parse().map_err(|_| invalid())?), vfs_handle)
+            }
+            None => (None, rest.strip_prefix('_').ok_or_else(invalid)?),
+        };
+
+        Ok(Handle {
+            handle_ty,
+            vfs_handle: vfs_handle.to_string(),
+            mount,
+        })
     }
 }
 
 impl Display for Handle {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.handle_ty {
-            HandleType::File => write!(f, "file_{}", self.vfs_handle),
-            HandleType::Dir => write!(f, "dir_{}", self.vfs_handle),
+            HandleType::File => write!(f, "file")?,
+            HandleType::Dir => write!(f, "dir")?,
         }
+
+        if let Some(mount) = self.mount {
+            write!(f, "@{mount}")?;
+        }
+
+        write!(f, "_{}", self.vfs_handle)
     }
 }
 
@@ -200,7 +393,20 @@ pub enum HandleType {
 /// A `VfsSet` tracks the tree of configured virtual file systems,
 #[derive(Clone)]
 pub struct VfsSet {
-    vfs_map: HashMap<Utf8PathBuf, (usize, Arc<VfsInstance>)>,
+    vfs_map: HashMap<Utf8PathBuf, (usize, Arc<Mount>)>,
+    /// The same mounts, by [`Mount::id`].
+    mount_ids: HashMap<u32, Arc<Mount>>,
+    user_mounts: Arc<UserMounts>,
+}
+
+/// The mounts that every user gets one of their own of, see
+/// [`VfsSet::for_user`].
+#[derive(Default)]
+struct UserMounts {
+    templates: Vec<VfsConfig>,
+    /// The mounts made from the templates, keyed by user and root, so that
+    /// all of a user's sessions share them for as long as any is connected.
+    mounts: Mutex<HashMap<(String, Utf8PathBuf), Weak<Mount>>>,
 }
 
 /// An opaque wrapper for an implementor of [`Vfs`].
@@ -216,6 +422,111 @@ impl VfsInstance {
             inner: VfsInstanceInner::LocalDir(local_dir),
         }
     }
+
+    #[allow(non_snake_case)]
+    pub(super) fn WebDav(webdav: WebDav) -> Self {
+        Self {
+            inner: VfsInstanceInner::WebDav(webdav),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn WebHdfs(webhdfs: WebHdfs) -> Self {
+        Self {
+            inner: VfsInstanceInner::WebHdfs(webhdfs),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn SftpProxyDir(sftp_proxy_dir: SftpProxyDir) -> Self {
+        Self {
+            inner: VfsInstanceInner::SftpProxyDir(sftp_proxy_dir),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn FtpDir(ftp_dir: FtpDir) -> Self {
+        Self {
+            inner: VfsInstanceInner::FtpDir(ftp_dir),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Postgres(postgres: Postgres) -> Self {
+        Self {
+            inner: VfsInstanceInner::Postgres(postgres),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn ArchiveDir(archive_dir: ArchiveDir) -> Self {
+        Self {
+            inner: VfsInstanceInner::ArchiveDir(archive_dir),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn MemoryDir(memory_dir: MemoryDir) -> Self {
+        Self {
+            inner: VfsInstanceInner::MemoryDir(memory_dir),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn AuditLog(audit_log: AuditLog) -> Self {
+        Self {
+            inner: VfsInstanceInner::AuditLog(audit_log),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Cached(cached: Cached) -> Self {
+        Self {
+            inner: VfsInstanceInner::Cached(cached),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Compressed(compressed: Compressed) -> Self {
+        Self {
+            inner: VfsInstanceInner::Compressed(compressed),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Deduplicated(deduplicated: Deduplicated) -> Self {
+        Self {
+            inner: VfsInstanceInner::Deduplicated(deduplicated),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn EncryptedNames(encrypted_names: EncryptedNames) -> Self {
+        Self {
+            inner: VfsInstanceInner::EncryptedNames(encrypted_names),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Readme(readme: Readme) -> Self {
+        Self {
+            inner: VfsInstanceInner::Readme(readme),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Trash(trash: Trash) -> Self {
+        Self {
+            inner: VfsInstanceInner::Trash(trash),
+        }
+    }
+
+    #[allow(non_snake_case)]
+    pub(super) fn Versioned(versioned: Versioned) -> Self {
+        Self {
+            inner: VfsInstanceInner::Versioned(versioned),
+        }
+    }
 }
 
 impl Deref for VfsInstance {
@@ -228,7 +539,22 @@ impl Deref for VfsInstance {
 
 trait_enum! {
     enum VfsInstanceInner: Vfs {
-            LocalDir
+            LocalDir,
+            WebDav,
+            WebHdfs,
+            SftpProxyDir,
+            FtpDir,
+            Postgres,
+            ArchiveDir,
+            MemoryDir,
+            AuditLog,
+            Cached,
+            Compressed,
+            Deduplicated,
+            EncryptedNames,
+            Readme,
+            Trash,
+            Versioned
         }
 }
 
@@ -237,20 +563,99 @@ trait_enum! {
 pub struct PathMatch {
     /// The path, relative to the root of the VFS.
     pub relative_path: Utf8PathBuf,
-    /// The matching VFS itself.
-    pub vfs: Arc<VfsInstance>,
+    /// The mount containing the matching VFS.
+    pub vfs: Arc<Mount>,
 }
 
 impl VfsSet {
-    fn new(vfs_map: HashMap<Utf8PathBuf, (usize, Arc<VfsInstance>)>) -> Self {
-        Self { vfs_map }
+    fn new(
+        vfs_map: HashMap<Utf8PathBuf, (usize, Arc<Mount>)>,
+        user_mounts: Arc<UserMounts>,
+    ) -> Self {
+        let mount_ids = vfs_map
+            .values()
+            .map(|(_, mount)| (mount.id(), mount.clone()))
+            .collect();
+
+        Self {
+            vfs_map,
+            mount_ids,
+            user_mounts,
+        }
+    }
+
+    /// A copy of this set with the mounts that have a `{username}` in their
+    /// configuration added for `username`. Each user's local directory is
+    /// created if it doesn't exist yet.
+    pub async fn for_user(&self, username: &str) -> Result<Self, Error> {
+        if self.user_mounts.templates.is_empty() {
+            return Ok(self.clone());
+        }
+
+        // The name ends up in paths, where it mustn't be able to point
+        // anywhere but a directory of its own.
+        let mut components = Utf8Path::new(username).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Utf8Component::Normal(_)), None)
+        ) {
+            return Err(Error::InvalidPath(username.into()));
+        }
+
+        let mut vfs_map = self.vfs_map.clone();
+
+        for template in &self.user_mounts.templates {
+            let config = template.for_user(username);
+            let key = (username.to_owned(), config.vfs_root.clone());
+
+            let existing = self
+                .user_mounts
+                .mounts
+                .lock()
+                .get(&key)
+                .and_then(Weak::upgrade);
+            let mount = if let Some(mount) = existing {
+                mount
+            } else {
+                // The mount is made without holding the lock, since that
+                // can mean creating the user's directory. If another of
+                // the user's sessions made it in the meantime, theirs is
+                // used instead.
+                if let BackendConfig::LocalDir { local_dir } = &config.backend {
+                    tokio::fs::create_dir_all(local_dir)
+                        .await
+                        .into_io_error("failed to create user directory")?;
+                }
+
+                let (_, (_, made)) = VfsSetBuilder::new()
+                    .add(config)?
+                    .vfs_map
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::FileNotFound)?;
+
+                let mut mounts = self.user_mounts.mounts.lock();
+                mounts.retain(|_, mount| mount.strong_count() > 0);
+                if let Some(mount) = mounts.get(&key).and_then(Weak::upgrade) {
+                    mount
+                } else {
+                    mounts.insert(key.clone(), Arc::downgrade(&made));
+                    made
+                }
+            };
+
+            let root = key.1;
+            vfs_map.insert(root.clone(), (root.components().count(), mount));
+        }
+
+        Ok(Self::new(vfs_map, self.user_mounts.clone()))
     }
 
     #[must_use]
     pub fn resolve_path(&self, path: &Utf8Path) -> Option<PathMatch> {
         use pathdiff::diff_utf8_paths;
 
-        let mut best_match: Option<(Utf8PathBuf, &Arc<VfsInstance>)> = None;
+        let mut best_match: Option<(Utf8PathBuf, &Arc<Mount>)> = None;
         let mut longest_match: usize = 0;
 
         for (prefix, (len, vfs)) in &self.vfs_map {
@@ -277,7 +682,88 @@ impl VfsSet {
         })
     }
 
-    pub async fn resolve_handle(&self, handle: &Handle) -> Option<Arc<VfsInstance>> {
+    /// A copy of this set with the mounts configured in `old` replaced by
+    /// those in `new`. See [`LiveVfsSet::reload`].
+    fn reload(&self, old: &Config, new: &Config) -> Result<Self, Error> {
+        let unchanged = |config: &VfsConfig| {
+            let config = serde_json::to_value(config).ok();
+            old.vfs_configs
+                .iter()
+                .any(|old| serde_json::to_value(old).ok() == config)
+        };
+
+        let mut vfs_map = self.vfs_map.clone();
+        for config in &old.vfs_configs {
+            vfs_map.remove(&config.vfs_root);
+        }
+
+        let mut builder = VfsSetBuilder::new();
+        for config in &new.vfs_configs {
+            if !config.is_template()
+                && unchanged(config)
+                && let Some(mount) = self.vfs_map.get(&config.vfs_root)
+            {
+                vfs_map.insert(config.vfs_root.clone(), mount.clone());
+                continue;
+            }
+
+            builder = builder.add(config.clone())?;
+        }
+        vfs_map.extend(builder.vfs_map);
+
+        // Users keep the mounts they have from the templates unless those
+        // changed.
+        let user_mounts = if builder.templates.iter().all(unchanged)
+            && builder.templates.len() == self.user_mounts.templates.len()
+        {
+            self.user_mounts.clone()
+        } else {
+            Arc::new(UserMounts {
+                templates: builder.templates,
+                mounts: Mutex::default(),
+            })
+        };
+
+        Ok(Self::new(vfs_map, user_mounts))
+    }
+
+    /// The mounts in this set, along with the roots they are mounted at.
+    pub fn mounts(&self) -> impl Iterator<Item = (&Utf8Path, &Arc<Mount>)> {
+        self.vfs_map
+            .iter()
+            .map(|(root, (_, mount))| (root.as_path(), mount))
+    }
+
+    /// Everything that at least one of the mounts in this set can do.
+    #[must_use]
+    pub fn capabilities(&self) -> Capabilities {
+        self.vfs_map
+            .values()
+            .fold(Capabilities::empty(), |capabilities, (_, mount)| {
+                capabilities | mount.capabilities()
+            })
+    }
+
+    /// A copy of this set without the mounts at `roots`.
+    #[must_use]
+    pub fn without(&self, roots: &[Utf8PathBuf]) -> Self {
+        let mut vfs_map = self.vfs_map.clone();
+        for root in roots {
+            vfs_map.remove(root);
+        }
+
+        Self::new(vfs_map, self.user_mounts.clone())
+    }
+
+    /// The mount that `handle` belongs to, if it's one of this set's.
+    pub async fn resolve_handle(&self, handle: &Handle) -> Option<Arc<Mount>> {
+        // The mount ID only says where the handle was opened, so the mount is
+        // asked whether it's still open there.
+        if let Some(id) = handle.mount() {
+            let mount = self.mount_ids.get(&id)?;
+            return mount.owns_handle(handle).await.then(|| mount.clone());
+        }
+
         for (_, vfs) in self.vfs_map.values() {
             if vfs.owns_handle(handle).await {
                 return Some(Arc::clone(vfs));
@@ -288,9 +774,63 @@ impl VfsSet {
     }
 }
 
+/// A [`VfsSet`] whose configured mounts can be changed while the server is
+/// running, with [`LiveVfsSet::reload`].
+///
+/// Sessions take a [`snapshot`](Self::snapshot) of it when they start, so
+/// changes only reach the sessions that start after them. Sessions that are
+/// already connected keep the mounts they started with, even ones that have
+/// since been removed, until they disconnect.
+#[derive(Clone)]
+pub struct LiveVfsSet {
+    inner: Arc<RwLock<(VfsSet, Config)>>,
+}
+
+impl LiveVfsSet {
+    /// A live set that starts out as `vfs_set`, which was built from
+    /// `config`. Mounts that were added to it some other way, like the audit
+    /// log, are left alone by reloads.
+    #[must_use]
+    pub fn new(vfs_set: VfsSet, config: Config) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new((vfs_set, config))),
+        }
+    }
+
+    /// The mounts as they are right now.
+    #[must_use]
+    pub fn snapshot(&self) -> VfsSet {
+        self.inner.read().0.clone()
+    }
+
+    /// [`VfsSet::resolve_path`] against the mounts as they are right now, for
+    /// background work that outlives any one configuration.
+    #[must_use]
+    pub fn resolve_path(&self, path: &Utf8Path) -> Option<PathMatch> {
+        self.inner.read().0.resolve_path(path)
+    }
+
+    /// Replaces the mounts from the last configuration with the ones in
+    /// `config`. Mounts whose configuration hasn't changed are kept as they
+    /// are, along with the state they keep, like which files are open for
+    /// writing. If any new mount can't be set up, nothing changes.
+    pub fn reload(&self, config: Config) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        let vfs_set = inner.0.reload(&inner.1, &config)?;
+        *inner = (vfs_set, config);
+
+        Ok(())
+    }
+}
+
+/// The [`Mount::id`] of the next mount to be made. Mounts are made by more
+/// than one builder, see [`VfsSet::for_user`], so their IDs come from here.
+static NEXT_MOUNT_ID: AtomicU32 = AtomicU32::new(0);
+
 /// A builder for creating an immutable [`VfsSet`].
 pub struct VfsSetBuilder {
-    vfs_map: HashMap<Utf8PathBuf, (usize, Arc<VfsInstance>)>,
+    vfs_map: HashMap<Utf8PathBuf, (usize, Arc<Mount>)>,
+    templates: Vec<VfsConfig>,
 }
 
 impl VfsSetBuilder {
@@ -299,48 +839,340 @@ impl VfsSetBuilder {
     pub fn new() -> Self {
         Self {
             vfs_map: HashMap::default(),
+            templates: Vec::new(),
         }
     }
 
-    /// Add a new [`LocalDir`] to the VFS set.
-    pub fn local_dir(
+    fn mount(
         mut self,
         vfs_root: Utf8PathBuf,
-        local_dir: Utf8PathBuf,
+        vfs: VfsInstance,
+        options: MountOptions,
     ) -> Result<Self, Error> {
         let num_components = vfs_root.components().count();
+        let vfs = match &options.cache {
+            Some(cache) => VfsInstance::Cached(Cached::new(vfs, cache)),
+            None => vfs,
+        };
+        let vfs = match &options.deduplication {
+            Some(deduplication) => VfsInstance::Deduplicated(Deduplicated::new(vfs, deduplication)),
+            None => vfs,
+        };
+        let vfs = match &options.compression {
+            Some(compression) => VfsInstance::Compressed(Compressed::new(vfs, compression)?),
+            None => vfs,
+        };
+        let vfs = match &options.filename_encryption {
+            Some(encryption) => VfsInstance::EncryptedNames(EncryptedNames::new(vfs, encryption)?),
+            None => vfs,
+        };
+        let vfs = match &options.versioning {
+            Some(versioning) => VfsInstance::Versioned(Versioned::new(vfs, versioning)),
+            None => vfs,
+        };
+        let vfs = match &options.trash {
+            Some(trash) => VfsInstance::Trash(Trash::new(vfs, trash)),
+            None => vfs,
+        };
+        let vfs = match &options.readme {
+            Some(readme) => VfsInstance::Readme(Readme::new(vfs, readme)?),
+            None => vfs,
+        };
 
         self.vfs_map.insert(
-            vfs_root.clone(),
+            vfs_root,
             (
                 num_components,
-                Arc::new(VfsInstance::LocalDir(LocalDir::new(vfs_root, local_dir)?)),
+                Arc::new(Mount::new(
+                    NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed),
+                    vfs,
+                    options,
+                )?),
             ),
         );
 
         Ok(self)
     }
 
+    /// Add a new [`LocalDir`] to the VFS set.
+    pub fn local_dir(
+        self,
+        vfs_root: Utf8PathBuf,
+        local_dir: Utf8PathBuf,
+        local_dir_options: &LocalDirOptions,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::LocalDir(LocalDir::new(
+            vfs_root.clone(),
+            local_dir,
+            local_dir_options,
+        )?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add a [`WebDav`] to the VFS set.
+    pub fn webdav(
+        self,
+        vfs_root: Utf8PathBuf,
+        url: Url,
+        username: Option<String>,
+        password_file: Option<&Utf8Path>,
+        webdav_options: &WebDavOptions,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::WebDav(WebDav::new(
+            vfs_root.clone(),
+            url,
+            username,
+            password_file,
+            webdav_options,
+        )?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add a [`WebHdfs`] to the VFS set.
+    pub fn webhdfs(
+        self,
+        vfs_root: Utf8PathBuf,
+        url: &Url,
+        auth: &WebHdfsAuth,
+        webhdfs_options: &WebHdfsOptions,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::WebHdfs(WebHdfs::new(vfs_root.clone(), url, auth, webhdfs_options)?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add an [`SftpProxyDir`] to the VFS set.
+    pub fn sftp_proxy(
+        self,
+        vfs_root: Utf8PathBuf,
+        url: Url,
+        private_key_file: Utf8PathBuf,
+        host_key: &str,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::SftpProxyDir(SftpProxyDir::new(
+            vfs_root.clone(),
+            url,
+            private_key_file,
+            host_key,
+        )?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add an [`FtpDir`] to the VFS set.
+    #[allow(clippy::too_many_arguments)]
+    pub fn ftp(
+        self,
+        vfs_root: Utf8PathBuf,
+        url: &Url,
+        password_file: Option<&Utf8Path>,
+        mode: FtpMode,
+        max_connections: usize,
+        ftp_options: &FtpOptions,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::FtpDir(FtpDir::new(
+            vfs_root.clone(),
+            url,
+            password_file,
+            mode,
+            max_connections,
+            ftp_options,
+        )?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add a [`Postgres`] to the VFS set.
+    pub fn postgres(
+        self,
+        vfs_root: Utf8PathBuf,
+        connection_string: &str,
+        password_file: Option<&Utf8Path>,
+        postgres_options: &PostgresOptions,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::Postgres(Postgres::new(
+            vfs_root.clone(),
+            connection_string,
+            password_file,
+            postgres_options,
+        )?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add an [`ArchiveDir`] to the VFS set.
+    pub fn archive(
+        self,
+        vfs_root: Utf8PathBuf,
+        archive_file: Utf8PathBuf,
+        options: MountOptions,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::ArchiveDir(ArchiveDir::new(vfs_root.clone(), archive_file)?);
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add a [`MemoryDir`] to the VFS set.
+    pub fn memory(self, vfs_root: Utf8PathBuf, options: MountOptions) -> Result<Self, Error> {
+        let vfs = VfsInstance::MemoryDir(MemoryDir::new(vfs_root.clone()));
+
+        self.mount(vfs_root, vfs, options)
+    }
+
+    /// Add an [`AuditLog`] to the VFS set, visible only to members of
+    /// `auditor_group`.
+    pub fn audit_log(
+        self,
+        vfs_root: Utf8PathBuf,
+        log_dir: Utf8PathBuf,
+        auditor_group: String,
+    ) -> Result<Self, Error> {
+        let vfs = VfsInstance::AuditLog(AuditLog::new(vfs_root.clone(), log_dir)?);
+        let options = MountOptions {
+            visible_to_group: Some(auditor_group),
+            ..MountOptions::default()
+        };
+
+        self.mount(vfs_root, vfs, options)
+    }
+
     pub fn from_config(config: Config) -> Result<Self, Error> {
         let mut out = Self::new();
 
         for config in config.vfs_configs {
-            match config {
-                VfsConfig::LocalDir {
-                    vfs_root,
-                    local_dir,
-                } => out = out.local_dir(vfs_root, local_dir)?,
-            }
+            out = out.add(config)?;
         }
 
         Ok(out)
     }
 
+    /// Add the mount described by `config` to the VFS set, or keep it as a
+    /// template for [`VfsSet::for_user`] if it has a `{username}` in it.
+    fn add(mut self, config: VfsConfig) -> Result<Self, Error> {
+        if config.is_template() {
+            if !matches!(config.backend, BackendConfig::LocalDir { .. }) {
+                return Err(Error::InvalidPath(config.vfs_root.into()));
+            }
+
+            // Users' mounts can't keep their holds and seals in the same file.
+            if let Some(path) = config.shared_state_file() {
+                return Err(Error::InvalidPath(path.into()));
+            }
+
+            self.templates.push(config);
+
+            return Ok(self);
+        }
+
+        let backend_options = &config.backend_options;
+        match config.backend {
+            BackendConfig::LocalDir { local_dir } => {
+                let local_dir_options = backend_options.parse(&config.vfs_root)?;
+                self.local_dir(
+                    config.vfs_root,
+                    local_dir,
+                    &local_dir_options,
+                    config.options,
+                )
+            }
+            BackendConfig::WebDav {
+                webdav_url,
+                webdav_username,
+                webdav_password_file,
+            } => {
+                let webdav_options = backend_options.parse(&config.vfs_root)?;
+                self.webdav(
+                    config.vfs_root,
+                    webdav_url,
+                    webdav_username,
+                    webdav_password_file.as_deref(),
+                    &webdav_options,
+                    config.options,
+                )
+            }
+            BackendConfig::WebHdfs {
+                webhdfs_url,
+                webhdfs_auth,
+            } => {
+                let webhdfs_options = backend_options.parse(&config.vfs_root)?;
+                self.webhdfs(
+                    config.vfs_root,
+                    &webhdfs_url,
+                    &webhdfs_auth,
+                    &webhdfs_options,
+                    config.options,
+                )
+            }
+            BackendConfig::SftpProxy {
+                sftp_url,
+                sftp_private_key_file,
+                sftp_host_key,
+            } => {
+                backend_options.parse::<NoBackendOptions>(&config.vfs_root)?;
+                self.sftp_proxy(
+                    config.vfs_root,
+                    sftp_url,
+                    sftp_private_key_file,
+                    &sftp_host_key,
+                    config.options,
+                )
+            }
+            BackendConfig::Ftp {
+                ftp_url,
+                ftp_password_file,
+                ftp_mode,
+                ftp_max_connections,
+            } => {
+                let ftp_options = backend_options.parse(&config.vfs_root)?;
+                self.ftp(
+                    config.vfs_root,
+                    &ftp_url,
+                    ftp_password_file.as_deref(),
+                    ftp_mode,
+                    ftp_max_connections,
+                    &ftp_options,
+                    config.options,
+                )
+            }
+            BackendConfig::Postgres {
+                postgres_url,
+                postgres_password_file,
+            } => {
+                let postgres_options = backend_options.parse(&config.vfs_root)?;
+                self.postgres(
+                    config.vfs_root,
+                    &postgres_url,
+                    postgres_password_file.as_deref(),
+                    &postgres_options,
+                    config.options,
+                )
+            }
+            BackendConfig::Archive { archive_file } => {
+                backend_options.parse::<NoBackendOptions>(&config.vfs_root)?;
+                self.archive(config.vfs_root, archive_file, config.options)
+            }
+        }
+    }
+
     /// Build a [`VfsSet`] that can be provided to a sftp that uses the VFS
     /// interface.
     #[must_use]
     pub fn build(&self) -> VfsSet {
-        VfsSet::new(self.vfs_map.clone())
+        VfsSet::new(
+            self.vfs_map.clone(),
+            Arc::new(UserMounts {
+                templates: self.templates.clone(),
+                mounts: Mutex::default(),
+            }),
+        )
     }
 }
 
@@ -349,24 +1181,3 @@ impl Default for VfsSetBuilder {
         Self::new()
     }
 }
-
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[repr(transparent)]
-#[serde(transparent)]
-pub struct Config {
-    vfs_configs: Vec<VfsConfig>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename = "vfs_config", untagged)]
-pub enum VfsConfig {
-    LocalDir {
-        /// The directory root to mount the VFS at within the virtual hierarchy.
-        #[schemars(with = "String")]
-        vfs_root: Utf8PathBuf,
-
-        /// The local directory to expose at the given root.
-        #[schemars(with = "String")]
-        local_dir: Utf8PathBuf,
-    },
-}
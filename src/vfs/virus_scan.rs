@@ -0,0 +1,357 @@
+use std::io;
+
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use thiserror_ext::AsReport;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpStream, UnixStream},
+};
+use tracing::{Level, event};
+use url::Url;
+
+use super::{
+    Error,
+    Handle,
+    OpenFlags,
+    VfsInstance,
+    VirusScanOptions,
+    VirusScanner,
+    error::IntoIoError,
+    state_file::normalize,
+};
+use crate::audit;
+
+/// How much of an upload is read from the backend and sent to the scanner at
+/// a time. clamd refuses chunks bigger than its `StreamMaxLength`, which is
+/// far more than this.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The port ICAP servers listen on when their URL doesn't say.
+const ICAP_PORT: u16 = 1344;
+
+/// Enforces [`MountOptions::virus_scan`](super::MountOptions::virus_scan) for
+/// a mount.
+///
+/// A file is scanned when a handle that was opened for writing it is closed,
+/// which with atomic uploads is before it's renamed into place.
+pub(super) struct VirusScan {
+    options: Option<VirusScanOptions>,
+    writers: Mutex<HashMap<Handle, Utf8PathBuf>>,
+}
+
+/// What the scanner made of an upload.
+enum Verdict {
+    Clean,
+    /// Infected with the named threat.
+    Infected(String),
+}
+
+impl VirusScan {
+    pub(super) fn new(options: Option<VirusScanOptions>) -> Self {
+        Self {
+            options,
+            writers: Mutex::default(),
+        }
+    }
+
+    /// Fails with [`Error::Quarantined`] if `path` is in the quarantine
+    /// directory.
+    pub(super) fn check_accessible(&self, path: &Utf8Path) -> Result<(), Error> {
+        match &self.options {
+            Some(options) if normalize(path).starts_with(normalize(&options.quarantine_dir)) => {
+                Err(Error::Quarantined)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remembers that `handle` was opened for writing to `path`, so that the
+    /// file can be scanned when it is closed.
+    pub(super) fn opened_for_write(&self, handle: &Handle, path: &Utf8Path) {
+        if self.options.is_some() {
+            self.writers.lock().insert(handle.clone(), path.to_owned());
+        }
+    }
+
+    /// The path `handle` was writing to, if it's to be scanned.
+    pub(super) fn take(&self, handle: &Handle) -> Option<Utf8PathBuf> {
+        self.writers.lock().remove(handle)
+    }
+
+    /// Scans the upload to `path`, which was written at `written`. If it's
+    /// infected, or can't be scanned, it's moved into quarantine and the
+    /// reason is returned.
+    pub(super) async fn scan(
+        &self,
+        vfs: &VfsInstance,
+        written: &Utf8Path,
+        path: &Utf8Path,
+    ) -> Result<(), Error> {
+        let Some(options) = &self.options else {
+            return Ok(());
+        };
+
+        let scanned =
+            tokio::time::timeout(options.timeout, scan_file(&options.scanner, vfs, written))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::from(io::ErrorKind::TimedOut))
+                        .into_io_error(format!("timed out scanning {path}"))
+                });
+
+        let err = match scanned {
+            Ok(Verdict::Clean) => return Ok(()),
+            Ok(Verdict::Infected(threat)) => Error::Infected(threat),
+            Err(err) => err,
+        };
+
+        match quarantine(options, vfs, written, path).await {
+            Ok(quarantined) => event!(
+                target: audit::TARGET,
+                Level::WARN,
+                %path,
+                %quarantined,
+                err = %err.as_report(),
+                code = %err.code(),
+                "upload quarantined"
+            ),
+            Err(quarantine_err) => {
+                event!(
+                    target: audit::TARGET,
+                    Level::ERROR,
+                    %path,
+                    err = %err.as_report(),
+                    code = %err.code(),
+                    quarantine_err = %quarantine_err.as_report(),
+                    "failed to quarantine upload, removing it"
+                );
+
+                if let Err(remove_err) = vfs.remove_file(written).await {
+                    event!(
+                        Level::ERROR,
+                        %path,
+                        err = %remove_err.as_report(),
+                        "failed to remove upload that couldn't be quarantined"
+                    );
+                }
+            }
+        }
+
+        Err(err)
+    }
+}
+
+/// Moves the file at `written` into the quarantine directory, returning where
+/// it ended up. Its name is kept, with something random in front of it so that
+/// quarantined files don't replace each other.
+async fn quarantine(
+    options: &VirusScanOptions,
+    vfs: &VfsInstance,
+    written: &Utf8Path,
+    path: &Utf8Path,
+) -> Result<Utf8PathBuf, Error> {
+    if let Err(err) = vfs.mkdir(&options.quarantine_dir).await
+        && vfs.stat(&options.quarantine_dir).await.is_err()
+    {
+        return Err(err);
+    }
+
+    let quarantined = options.quarantine_dir.join(format!(
+        "{:016x}-{}",
+        rand::random::<u64>(),
+        path.file_name().unwrap_or_default()
+    ));
+    vfs.rename(written, &quarantined).await?;
+
+    Ok(quarantined)
+}
+
+/// Reads the file at `path` and sends it to `scanner`.
+async fn scan_file(
+    scanner: &VirusScanner,
+    vfs: &VfsInstance,
+    path: &Utf8Path,
+) -> Result<Verdict, Error> {
+    let mut connection = Connection::open(scanner)
+        .await
+        .into_io_error("failed to connect to virus scanner")?;
+    let handle = vfs.open(path, OpenFlags::READ).await?;
+
+    let sent = async {
+        let mut offset = 0;
+
+        while let Some(chunk) = vfs.read(&handle, offset, CHUNK_SIZE).await? {
+            if chunk.is_empty() {
+                break;
+            }
+
+            connection
+                .send(&chunk)
+                .await
+                .into_io_error("failed to send upload to virus scanner")?;
+            offset += chunk.len() as u64;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    vfs.close(handle).await?;
+    sent?;
+
+    connection
+        .finish()
+        .await
+        .into_io_error("virus scanner didn't answer")
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A scan in progress, which the upload is sent to in chunks.
+enum Connection {
+    Clamd(Box<dyn Stream>),
+    Icap(Box<dyn Stream>),
+}
+
+impl Connection {
+    async fn open(scanner: &VirusScanner) -> io::Result<Self> {
+        match scanner {
+            VirusScanner::Clamd { address } => {
+                let mut stream: Box<dyn Stream> = if address.starts_with('/') {
+                    Box::new(UnixStream::connect(address).await?)
+                } else {
+                    Box::new(TcpStream::connect(address).await?)
+                };
+
+                stream.write_all(b"zINSTREAM\0").await?;
+
+                Ok(Self::Clamd(stream))
+            }
+            VirusScanner::Icap { url } => {
+                let host = url.host_str().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "ICAP URL has no host")
+                })?;
+                let mut stream =
+                    TcpStream::connect((host, url.port().unwrap_or(ICAP_PORT))).await?;
+
+                stream.write_all(icap_request(url, host).as_bytes()).await?;
+
+                Ok(Self::Icap(Box::new(stream)))
+            }
+        }
+    }
+
+    async fn send(&mut self, chunk: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Clamd(stream) => {
+                let len = u32::try_from(chunk.len())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+                stream.write_u32(len).await?;
+                stream.write_all(chunk).await
+            }
+            Self::Icap(stream) => {
+                stream
+                    .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                    .await?;
+                stream.write_all(chunk).await?;
+                stream.write_all(b"\r\n").await
+            }
+        }
+    }
+
+    /// Tells the scanner that the whole upload has been sent, and waits for
+    /// its verdict.
+    async fn finish(self) -> io::Result<Verdict> {
+        match self {
+            Self::Clamd(mut stream) => {
+                stream.write_u32(0).await?;
+
+                let mut reply = Vec::new();
+                BufReader::new(stream).read_until(b'\0', &mut reply).await?;
+                let reply = String::from_utf8_lossy(&reply);
+                let reply = reply.trim_end_matches('\0').trim();
+
+                // Replies look like `stream: OK` or `stream: Eicar-Signature
+                // FOUND`, and anything else is an error.
+                match reply.strip_prefix("stream: ") {
+                    Some("OK") => Ok(Verdict::Clean),
+                    Some(found) if found.ends_with(" FOUND") => Ok(Verdict::Infected(
+                        found.trim_end_matches(" FOUND").to_owned(),
+                    )),
+                    _ => Err(io::Error::other(format!("clamd replied {reply:?}"))),
+                }
+            }
+            Self::Icap(mut stream) => {
+                stream.write_all(b"0\r\n\r\n").await?;
+                icap_verdict(BufReader::new(stream)).await
+            }
+        }
+    }
+}
+
+/// The headers of an ICAP `RESPMOD` request for `url`, which the upload
+/// follows as the chunked body of an HTTP response. Servers answer `204` for
+/// clean files, since there's nothing to modify.
+fn icap_request(url: &Url, host: &str) -> String {
+    let http_headers = "HTTP/1.1 200 OK\r\n\r\n";
+
+    format!(
+        "RESPMOD {url} ICAP/1.0\r\n\
+         Host: {host}\r\n\
+         Allow: 204\r\n\
+         Encapsulated: res-hdr=0, res-body={}\r\n\
+         \r\n\
+         {http_headers}",
+        http_headers.len()
+    )
+}
+
+/// Reads an ICAP server's reply to a `RESPMOD` request. Anything but `204`
+/// means the server changed the file, which scanning services only do when
+/// it's infected.
+async fn icap_verdict(mut reader: impl AsyncBufReadExt + Unpin) -> io::Result<Verdict> {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).await?;
+
+    let status = status_line
+        .strip_prefix("ICAP/1.0 ")
+        .and_then(|rest| rest.get(..3))
+        .ok_or_else(|| io::Error::other(format!("ICAP server replied {status_line:?}")))?;
+
+    match status {
+        "204" => return Ok(Verdict::Clean),
+        "200" => {}
+        _ => {
+            return Err(io::Error::other(format!(
+                "ICAP server replied {:?}",
+                status_line.trim_end()
+            )));
+        }
+    }
+
+    let mut threat = None;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':')
+            && (name.eq_ignore_ascii_case("X-Virus-ID")
+                || (threat.is_none() && name.eq_ignore_ascii_case("X-Infection-Found")))
+        {
+            threat = Some(value.trim().to_owned());
+        }
+    }
+
+    Ok(Verdict::Infected(
+        threat.unwrap_or_else(|| "an unknown threat".to_owned()),
+    ))
+}
@@ -0,0 +1,182 @@
+//! Change notifications for [`Vfs::watch`](super::Vfs::watch), and the
+//! inotify watcher that [`LocalDir`](super::LocalDir) delivers them with.
+
+use std::{ffi::OsString, sync::Arc};
+
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use futures_util::StreamExt;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{Level, event};
+
+use super::{Error, error::IntoIoError};
+
+/// How many changes a receiver can fall behind by before it starts missing
+/// them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// How much room the kernel has to hand over events in at a time.
+const EVENT_BUFFER_SIZE: usize = 16 * 1024;
+
+/// What happened to a watched path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// A file or directory was created, or moved in.
+    Created,
+    /// A file that was open for writing was closed. Files are only reported
+    /// once they're closed, rather than on every write.
+    Modified,
+    /// A file or directory was removed, or moved out.
+    Removed,
+    /// Changes were lost, because too many happened at once, so anything
+    /// that's been watched could have changed.
+    Rescan,
+}
+
+/// A change to something in a backend, as delivered by
+/// [`Vfs::watch`](super::Vfs::watch).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The path that changed, relative to the root of the VFS. It's empty for
+    /// [`ChangeKind::Rescan`].
+    pub path: Utf8PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// Watches paths in a directory with inotify, and sends what changes to
+/// every receiver.
+///
+/// inotify only watches the path it's given and, for directories, what's
+/// directly inside, so subdirectories have to be watched on their own.
+pub(super) struct Watcher {
+    root_path: Utf8PathBuf,
+    watches: Mutex<Watches>,
+    paths: Arc<Mutex<HashMap<WatchDescriptor, Utf8PathBuf>>>,
+    sender: broadcast::Sender<ChangeEvent>,
+    _stop: DropGuard,
+}
+
+impl Watcher {
+    /// Starts a watcher for the directory at `root_path`, which only has any
+    /// watches once [`Watcher::watch`] is called.
+    pub(super) fn new(root_path: Utf8PathBuf) -> Result<Self, Error> {
+        let inotify = Inotify::init().into_io_error("failed to start inotify")?;
+        let watches = inotify.watches();
+        let stream = inotify
+            .into_event_stream(vec![0u8; EVENT_BUFFER_SIZE])
+            .into_io_error("failed to start inotify")?;
+
+        let paths = Arc::new(Mutex::new(HashMap::default()));
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let stop = CancellationToken::new();
+
+        tokio::spawn(deliver(stream, paths.clone(), sender.clone(), stop.clone()));
+
+        Ok(Self {
+            root_path,
+            watches: Mutex::new(watches),
+            paths,
+            sender,
+            _stop: stop.drop_guard(),
+        })
+    }
+
+    /// Watches `path`, relative to the root, and returns a receiver for the
+    /// changes to everything that's watched.
+    pub(super) fn watch(&self, path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        let mask = WatchMask::CREATE
+            | WatchMask::MOVED_TO
+            | WatchMask::CLOSE_WRITE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF
+            | WatchMask::DONT_FOLLOW;
+
+        // Subscribing first means nothing that happens once the watch is
+        // added is missed.
+        let receiver = self.sender.subscribe();
+        let descriptor = self
+            .watches
+            .lock()
+            .add(self.root_path.join(path), mask)
+            .into_io_error(format!("failed to watch {path}"))?;
+        self.paths.lock().insert(descriptor, path.to_owned());
+
+        Ok(receiver)
+    }
+}
+
+/// Turns inotify events into [`ChangeEvent`]s until the watcher is dropped.
+async fn deliver(
+    mut stream: inotify::EventStream<Vec<u8>>,
+    paths: Arc<Mutex<HashMap<WatchDescriptor, Utf8PathBuf>>>,
+    sender: broadcast::Sender<ChangeEvent>,
+    stop: CancellationToken,
+) {
+    loop {
+        let inotify_event = tokio::select! {
+            () = stop.cancelled() => return,
+            inotify_event = stream.next() => inotify_event,
+        };
+
+        let inotify_event = match inotify_event {
+            Some(Ok(inotify_event)) => inotify_event,
+            Some(Err(err)) => {
+                event!(Level::WARN, %err, "failed to read inotify events");
+                return;
+            }
+            None => return,
+        };
+
+        if inotify_event.mask.contains(EventMask::Q_OVERFLOW) {
+            let _ = sender.send(ChangeEvent {
+                path: Utf8PathBuf::new(),
+                kind: ChangeKind::Rescan,
+            });
+            continue;
+        }
+
+        if inotify_event.mask.contains(EventMask::IGNORED) {
+            paths.lock().remove(&inotify_event.wd);
+            continue;
+        }
+
+        let Some(path) = event_path(&paths, &inotify_event.wd, inotify_event.name) else {
+            continue;
+        };
+
+        let kind = if inotify_event
+            .mask
+            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+        {
+            ChangeKind::Created
+        } else if inotify_event.mask.contains(EventMask::CLOSE_WRITE) {
+            ChangeKind::Modified
+        } else {
+            ChangeKind::Removed
+        };
+
+        // Nobody listening isn't a problem; the next receiver only wants what
+        // happens from then on.
+        let _ = sender.send(ChangeEvent { path, kind });
+    }
+}
+
+/// The path, relative to the root, that an event about `name` in the watch
+/// `descriptor` is about, or `None` if it can't be told.
+fn event_path(
+    paths: &Mutex<HashMap<WatchDescriptor, Utf8PathBuf>>,
+    descriptor: &WatchDescriptor,
+    name: Option<OsString>,
+) -> Option<Utf8PathBuf> {
+    let watched = paths.lock().get(descriptor)?.clone();
+
+    match name {
+        Some(name) => Some(watched.join(name.into_string().ok()?)),
+        None => Some(watched),
+    }
+}
@@ -0,0 +1,879 @@
+use std::{io, io::SeekFrom, ops::Range, sync::Arc, time::SystemTime};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use futures_util::StreamExt;
+use http::{HeaderValue, Method, StatusCode, header};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+use reqwest::{Body, Client, RequestBuilder, Response};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex as AsyncMutex, broadcast},
+};
+use tokio_util::io::ReaderStream;
+use url::Url;
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    WebDavOptions,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// The properties asked for in every `PROPFIND`.
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<propfind xmlns="DAV:">
+  <prop>
+    <resourcetype/>
+    <getcontentlength/>
+    <getlastmodified/>
+    <quota-available-bytes/>
+    <quota-used-bytes/>
+  </prop>
+</propfind>"#;
+
+/// A backend that proxies to a remote WebDAV server, so that Schlep can put
+/// SFTP, and its authentication, in front of an existing WebDAV store.
+///
+/// Metadata comes from `PROPFIND`, and files are read with ranged `GET`s. HTTP
+/// doesn't allow writing part of a file with a `PUT`, so files that are open
+/// for writing are spooled to a local temporary file, which is uploaded in
+/// one `PUT` when the file is synced or closed. Links and setting file times
+/// aren't supported.
+pub struct WebDav {
+    vfs_path: Utf8PathBuf,
+    base_url: Url,
+    client: Client,
+    credentials: Option<(String, Option<String>)>,
+    open_files: Mutex<HashMap<String, Arc<AsyncMutex<OpenFile>>>>,
+    open_dirs: Mutex<HashMap<String, Utf8PathBuf>>,
+}
+
+struct OpenFile {
+    path: Utf8PathBuf,
+    /// The local copy of a file that's open for writing.
+    spool: Option<File>,
+    /// Whether the spool has changes that haven't been uploaded yet.
+    dirty: bool,
+}
+
+/// What a `PROPFIND` says about one resource.
+#[derive(Default)]
+struct PropEntry {
+    href: String,
+    metadata: Metadata,
+    quota_available: Option<u64>,
+    quota_used: Option<u64>,
+}
+
+impl WebDav {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        mut base_url: Url,
+        username: Option<String>,
+        password_file: Option<&Utf8Path>,
+        options: &WebDavOptions,
+    ) -> Result<Self, Error> {
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let password = password_file
+            .map(|password_file| {
+                std::fs::read_to_string(password_file)
+                    .map(|password| password.trim_end().to_owned())
+                    .into_io_error("failed to read WebDAV password")
+            })
+            .transpose()?;
+
+        let client = Client::builder()
+            .timeout(options.request_timeout)
+            .build()
+            .map_err(io::Error::other)
+            .into_io_error("failed to create WebDAV client")?;
+
+        Ok(Self {
+            vfs_path,
+            base_url,
+            client,
+            credentials: username.map(|username| (username, password)),
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        })
+    }
+
+    /// The URL of `path`, with a trailing slash if it's a `collection`.
+    fn url(&self, path: &Utf8Path, collection: bool) -> Result<Url, Error> {
+        let mut url = self.base_url.clone();
+
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|()| Error::InvalidPath(path.into()))?;
+            segments.pop_if_empty();
+
+            for component in path.components() {
+                match component {
+                    Utf8Component::Normal(name) => {
+                        segments.push(name);
+                    }
+                    Utf8Component::CurDir | Utf8Component::RootDir => (),
+                    _ => return Err(Error::InvalidPath(path.into())),
+                }
+            }
+
+            if collection {
+                segments.push("");
+            }
+        }
+
+        Ok(url)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+
+        match &self.credentials {
+            Some((username, password)) => request.basic_auth(username, password.as_ref()),
+            None => request,
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder, context: &str) -> Result<Response, Error> {
+        let response = request
+            .send()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error(context)?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            Err(status_error(status, context))
+        }
+    }
+
+    async fn propfind(&self, path: &Utf8Path, depth: u8) -> Result<Vec<PropEntry>, Error> {
+        let propfind = Method::from_bytes(b"PROPFIND").unwrap();
+        let url = self.url(path, false)?;
+
+        let response = self
+            .send(
+                self.request(propfind, url)
+                    .header("Depth", depth.to_string())
+                    .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+                    .body(PROPFIND_BODY),
+                "failed to get WebDAV properties",
+            )
+            .await?;
+        let body = response
+            .text()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to get WebDAV properties")?;
+
+        parse_multistatus(&body)
+    }
+
+    /// Fetches the whole file at `path` into `spool`.
+    async fn download(&self, path: &Utf8Path, spool: &mut File) -> Result<(), Error> {
+        let response = self
+            .send(
+                self.request(Method::GET, self.url(path, false)?),
+                "failed to download file",
+            )
+            .await?;
+
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map_err(io::Error::other)
+                .into_io_error("failed to download file")?;
+            spool
+                .write_all(&chunk)
+                .await
+                .into_io_error("failed to write spool file")?;
+        }
+
+        Ok(())
+    }
+
+    /// Uploads the spool of `file`, if it has changed since it was last
+    /// uploaded.
+    async fn upload(&self, file: &mut OpenFile) -> Result<(), Error> {
+        let Some(spool) = &file.spool else {
+            return Ok(());
+        };
+        if !file.dirty {
+            return Ok(());
+        }
+
+        let mut body = spool
+            .try_clone()
+            .await
+            .into_io_error("failed to read spool file")?;
+        let len = body
+            .seek(SeekFrom::End(0))
+            .await
+            .into_io_error("failed to read spool file")?;
+        body.seek(SeekFrom::Start(0))
+            .await
+            .into_io_error("failed to read spool file")?;
+
+        self.send(
+            self.request(Method::PUT, self.url(&file.path, false)?)
+                .header(header::CONTENT_LENGTH, len)
+                .body(Body::wrap_stream(ReaderStream::new(body))),
+            "failed to upload file",
+        )
+        .await?;
+        file.dirty = false;
+
+        Ok(())
+    }
+
+    fn get_file(&self, handle: &Handle) -> Result<Arc<AsyncMutex<OpenFile>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+#[async_trait]
+impl Vfs for WebDav {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+
+        let exists = match self.propfind(path, 0).await {
+            Ok(entries) => {
+                if entries
+                    .first()
+                    .is_some_and(|entry| entry.metadata.is_directory)
+                {
+                    return Err(Error::NotAFile);
+                }
+                true
+            }
+            Err(Error::FileNotFound) if writing && flags.contains(OpenFlags::CREATE) => false,
+            Err(err) => return Err(err),
+        };
+
+        if exists && flags.contains(OpenFlags::EXCLUDE) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error(format!("couldn't open file {path}"));
+        }
+
+        let spool = if writing {
+            let mut spool =
+                File::from_std(tempfile::tempfile().into_io_error("failed to create spool file")?);
+
+            if exists && !flags.contains(OpenFlags::TRUNCATE) {
+                self.download(path, &mut spool).await?;
+            }
+
+            Some(spool)
+        } else {
+            None
+        };
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        let mut file = OpenFile {
+            path: path.to_owned(),
+            spool,
+            // New and truncated files have to be created on the server even if
+            // nothing is ever written to them.
+            dirty: writing && (!exists || flags.contains(OpenFlags::TRUNCATE)),
+        };
+
+        if file.dirty {
+            self.upload(&mut file).await?;
+        }
+
+        self.open_files
+            .lock()
+            .insert(vfs_handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        let entries = self.propfind(path, 0).await?;
+        if !entries
+            .first()
+            .is_some_and(|entry| entry.metadata.is_directory)
+        {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs
+            .lock()
+            .insert(vfs_handle.clone(), path.to_owned());
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => {
+                let file = self.open_files.lock().remove(handle.vfs_handle());
+
+                if let Some(file) = file {
+                    self.upload(&mut *file.lock().await).await?;
+                }
+            }
+            HandleType::Dir => {
+                self.open_dirs.lock().remove(handle.vfs_handle());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::STATVFS
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        if let Some(spool) = &mut file.spool {
+            let mut buf = Vec::with_capacity(len);
+
+            spool
+                .seek(SeekFrom::Start(offset))
+                .await
+                .into_io_error("failed to seek spool file")?;
+            let bytes_read = spool
+                .take(len as u64)
+                .read_to_end(&mut buf)
+                .await
+                .into_io_error("failed to read spool file")?;
+
+            return Ok((bytes_read != 0 || len == 0).then_some(buf));
+        }
+
+        if len == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        let range = format!("bytes={offset}-{}", offset + len as u64 - 1);
+        let response = self
+            .request(Method::GET, self.url(&file.path, false)?)
+            .header(header::RANGE, range)
+            .send()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to read file")?;
+
+        let status = response.status();
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(status_error(status, "failed to read file"));
+        }
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to read file")?;
+
+        // A server that doesn't support ranges sends the whole file instead.
+        let data = if status == StatusCode::PARTIAL_CONTENT {
+            &body[..body.len().min(len)]
+        } else {
+            let start = usize::try_from(offset)
+                .unwrap_or(usize::MAX)
+                .min(body.len());
+            &body[start..body.len().min(start + len)]
+        };
+
+        Ok((!data.is_empty()).then(|| data.to_vec()))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self
+            .open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)?;
+        let dir_url = self.url(&path, true)?;
+
+        let entries = self.propfind(&path, 1).await?;
+
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                let url = dir_url.join(&entry.href).ok()?;
+                if url.path().trim_end_matches('/') == dir_url.path().trim_end_matches('/') {
+                    return None;
+                }
+
+                let name = url.path_segments()?.rev().find(|name| !name.is_empty())?;
+                let name = percent_decode_str(name).decode_utf8().ok()?;
+
+                Some((Utf8PathBuf::from(name.as_ref()), entry.metadata))
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .seek(SeekFrom::Start(offset))
+            .await
+            .into_io_error("failed to seek spool file")?;
+        spool
+            .write_all(data)
+            .await
+            .into_io_error("failed to write spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        if handle.handle_type() == HandleType::Dir {
+            let path = self
+                .open_dirs
+                .lock()
+                .get(handle.vfs_handle())
+                .cloned()
+                .ok_or(Error::FileNotFound)?;
+
+            return self.stat(&path).await;
+        }
+
+        let file = self.get_file(handle)?;
+        let file = file.lock().await;
+
+        match &file.spool {
+            Some(spool) => {
+                let metadata = spool
+                    .metadata()
+                    .await
+                    .into_io_error("failed to get spool file metadata")?;
+
+                Ok(Metadata {
+                    size: Some(metadata.len()),
+                    mtime: metadata.modified().ok(),
+                    ..Metadata::default()
+                })
+            }
+            None => self.stat(&file.path).await,
+        }
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        self.upload(&mut file).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let is_directory = self.stat(from).await?.is_directory;
+        let destination = self.url(to, is_directory)?;
+
+        self.send(
+            self.request(
+                Method::from_bytes(b"MOVE").unwrap(),
+                self.url(from, is_directory)?,
+            )
+            .header(
+                "Destination",
+                HeaderValue::from_str(destination.as_str())
+                    .map_err(|_| Error::InvalidPath(to.into()))?,
+            )
+            .header("Overwrite", "T"),
+            "failed to rename file",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        self.propfind(path, 0)
+            .await?
+            .into_iter()
+            .next()
+            .map(|entry| entry.metadata)
+            .ok_or(Error::FileNotFound)
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // WebDAV has no links, so nothing needs to be followed.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        let entry = self
+            .propfind(path, 0)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::FileNotFound)?;
+
+        let (Some(available), Some(used)) = (entry.quota_available, entry.quota_used) else {
+            return Err(Error::UnsupportedMethod);
+        };
+
+        Ok(FsMetadata {
+            block_size: 1,
+            num_blocks: available + used,
+            free_blocks: available,
+            num_files: 0,
+            free_files: 0,
+            read_only: false,
+            max_length: 255,
+        })
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        let path = if handle.handle_type() == HandleType::Dir {
+            self.open_dirs
+                .lock()
+                .get(handle.vfs_handle())
+                .cloned()
+                .ok_or(Error::FileNotFound)?
+        } else {
+            self.get_file(handle)?.lock().await.path.clone()
+        };
+
+        self.statvfs(&path).await
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let mut hasher = BlockHasher::new(algorithm, len, block_size);
+
+        let range = if len == 0 {
+            format!("bytes={offset}-")
+        } else {
+            format!("bytes={offset}-{}", offset.saturating_add(len) - 1)
+        };
+        let response = self
+            .request(Method::GET, self.url(path, false)?)
+            .header(header::RANGE, range)
+            .send()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to download file")?;
+
+        let status = response.status();
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(hasher.finish());
+        }
+        if !status.is_success() {
+            return Err(status_error(status, "failed to download file"));
+        }
+
+        // A server that doesn't support ranges sends the whole file instead.
+        let mut skip = if status == StatusCode::PARTIAL_CONTENT {
+            0
+        } else {
+            offset
+        };
+
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map_err(io::Error::other)
+                .into_io_error("failed to download file")?;
+            let start = usize::try_from(skip).map_or(chunk.len(), |skip| skip.min(chunk.len()));
+            skip -= start as u64;
+
+            hasher.update(&chunk[start..]);
+            if hasher.is_done() {
+                break;
+            }
+        }
+
+        Ok(hasher.finish())
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.send(
+            self.request(Method::from_bytes(b"MKCOL").unwrap(), self.url(path, true)?),
+            "failed to create directory",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.stat(path).await?.is_directory {
+            return Err(Error::NotAFile);
+        }
+
+        self.send(
+            self.request(Method::DELETE, self.url(path, false)?),
+            "failed to remove file",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        // A DELETE on a collection removes everything in it, but removing a
+        // directory that isn't empty has to fail.
+        let entries = self.propfind(path, 1).await?;
+        if !entries
+            .first()
+            .is_some_and(|entry| entry.metadata.is_directory)
+        {
+            return Err(Error::NotADirectory);
+        }
+        if entries.len() > 1 {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty))
+                .into_io_error("failed to remove directory");
+        }
+
+        self.send(
+            self.request(Method::DELETE, self.url(path, true)?),
+            "failed to remove directory",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &Utf8Path,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_times_fd(
+        &self,
+        _handle: &Handle,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        let handle = self.open(path, OpenFlags::WRITE).await?;
+        let result = self.truncate_fd(&handle, len).await;
+        let closed = self.close(handle).await;
+
+        result.and(closed)
+    }
+
+    async fn truncate_fd(&self, handle: &Handle, len: u64) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(spool) = &mut file.spool else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        spool
+            .set_len(len)
+            .await
+            .into_io_error("failed to truncate spool file")?;
+        file.dirty = true;
+
+        Ok(())
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.stat(Utf8Path::new(".")).await.map(drop)
+    }
+}
+
+/// The error for a request that failed with `status`.
+fn status_error(status: StatusCode, context: &str) -> Error {
+    let kind = match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => return Error::FileNotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+        StatusCode::METHOD_NOT_ALLOWED | StatusCode::PRECONDITION_FAILED => {
+            io::ErrorKind::AlreadyExists
+        }
+        StatusCode::CONFLICT => io::ErrorKind::NotFound,
+        StatusCode::INSUFFICIENT_STORAGE => io::ErrorKind::StorageFull,
+        _ => io::ErrorKind::Other,
+    };
+
+    Error::IoError {
+        source: io::Error::new(kind, format!("WebDAV server answered {status}")),
+        from: context.to_owned(),
+    }
+}
+
+/// Reads the entries out of a `207 Multi-Status` response to a `PROPFIND`.
+fn parse_multistatus(body: &str) -> Result<Vec<PropEntry>, Error> {
+    let mut reader = quick_xml::Reader::from_str(body);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = Vec::new();
+    let mut entry = PropEntry::default();
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(io::Error::other)
+            .into_io_error("invalid WebDAV response")?;
+
+        match event {
+            Event::Start(element) => {
+                let name = element.local_name().as_ref().to_vec();
+                if name == b"collection" {
+                    entry.metadata.is_directory = true;
+                }
+                stack.push(name);
+            }
+            Event::Empty(element) if element.local_name().as_ref() == b"collection" => {
+                entry.metadata.is_directory = true;
+            }
+            Event::Text(text) => {
+                let text = text
+                    .unescape()
+                    .map_err(io::Error::other)
+                    .into_io_error("invalid WebDAV response")?;
+                let text = text.trim();
+
+                match stack.last().map(Vec::as_slice) {
+                    Some(b"href") => text.clone_into(&mut entry.href),
+                    Some(b"getcontentlength") => entry.metadata.size = text.parse().ok(),
+                    Some(b"getlastmodified") => {
+                        entry.metadata.mtime = httpdate::parse_http_date(text).ok();
+                    }
+                    Some(b"quota-available-bytes") => entry.quota_available = text.parse().ok(),
+                    Some(b"quota-used-bytes") => entry.quota_used = text.parse().ok(),
+                    _ => (),
+                }
+            }
+            Event::End(_) if stack.pop().as_deref() == Some(b"response".as_slice()) => {
+                entries.push(std::mem::take(&mut entry));
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Ok(entries)
+}
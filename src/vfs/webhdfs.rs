@@ -0,0 +1,980 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ahash::HashMap;
+use async_trait::async_trait;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use futures_util::StreamExt;
+use http::{Method, StatusCode, header};
+use parking_lot::Mutex;
+use reqwest::{Client, RequestBuilder, Response, redirect};
+use serde::{Deserialize, de::DeserializeOwned};
+use tokio::sync::{Mutex as AsyncMutex, broadcast};
+use url::Url;
+
+use super::{
+    ChangeEvent,
+    Error,
+    Handle,
+    HandleType,
+    HashAlgorithm,
+    Vfs,
+    WebHdfsAuth,
+    WebHdfsOptions,
+    hash::BlockHasher,
+    options::{Capabilities, FsMetadata, LockMode, Metadata, OpenFlags},
+};
+use crate::vfs::error::IntoIoError;
+
+/// A backend that exposes a directory in HDFS through the WebHDFS REST API, so
+/// that Hadoop data can be shared over SFTP.
+///
+/// HDFS files can only be appended to, so files that are open for writing
+/// have to be written from the start, or from their end, in order. Writes are
+/// buffered and appended in large pieces. Links aren't supported.
+pub struct WebHdfs {
+    vfs_path: Utf8PathBuf,
+    /// The WebHDFS URL of the directory that's exposed.
+    base_url: Url,
+    /// The HDFS path of the directory that's exposed.
+    root: Utf8PathBuf,
+    client: Client,
+    auth: Auth,
+    /// How much is written to a file before it's appended to HDFS.
+    append_size: usize,
+    open_files: Mutex<HashMap<String, Arc<AsyncMutex<OpenFile>>>>,
+    open_dirs: Mutex<HashMap<String, Utf8PathBuf>>,
+}
+
+enum Auth {
+    Simple(Option<String>),
+    DelegationToken(String),
+    #[cfg(feature = "kerberos")]
+    Kerberos(String),
+}
+
+struct OpenFile {
+    path: Utf8PathBuf,
+    /// How long the file was when it was opened.
+    len: u64,
+    /// The writes that haven't been appended yet, for a file that's open for
+    /// writing.
+    writer: Option<Appender>,
+}
+
+struct Appender {
+    /// How much of the file is in HDFS. `buffer` starts here.
+    flushed: u64,
+    /// What's been written after `flushed`, in order.
+    buffer: Vec<u8>,
+    /// Writes that arrived ahead of the ones before them, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Appender {
+    fn end(&self) -> u64 {
+        self.flushed + self.buffer.len() as u64
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        if offset < self.flushed {
+            return Err(Error::UnsupportedMethod);
+        }
+
+        if offset > self.end() {
+            self.pending.insert(offset, data.to_vec());
+            return Ok(());
+        }
+
+        self.splice(offset, data);
+
+        while self
+            .pending
+            .first_key_value()
+            .is_some_and(|(offset, _)| *offset <= self.end())
+        {
+            if let Some((offset, data)) = self.pending.pop_first() {
+                self.splice(offset, &data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Puts `data` into the buffer at `offset`, which must be within or right
+    /// at the end of the buffer.
+    fn splice(&mut self, offset: u64, data: &[u8]) {
+        // The buffer is in memory, so anything in it fits in a usize.
+        let start = usize::try_from(offset - self.flushed).unwrap();
+        let overlap = (self.buffer.len() - start).min(data.len());
+
+        self.buffer[start..start + overlap].copy_from_slice(&data[..overlap]);
+        self.buffer.extend_from_slice(&data[overlap..]);
+    }
+
+    /// Fills in the holes left by writes that never arrived with zeroes, like
+    /// a sparse file would.
+    fn fill_holes(&mut self) {
+        for (offset, data) in std::mem::take(&mut self.pending) {
+            let start = usize::try_from(offset - self.flushed).unwrap();
+            if start > self.buffer.len() {
+                self.buffer.resize(start, 0);
+            }
+
+            self.splice(offset, &data);
+        }
+    }
+}
+
+/// The `FileStatus` object that WebHDFS describes files with.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileStatus {
+    #[serde(default)]
+    path_suffix: String,
+    length: u64,
+    modification_time: u64,
+    access_time: u64,
+    #[serde(rename = "type")]
+    file_type: String,
+}
+
+impl From<FileStatus> for Metadata {
+    fn from(status: FileStatus) -> Self {
+        Metadata {
+            size: Some(status.length),
+            atime: Some(UNIX_EPOCH + Duration::from_millis(status.access_time)),
+            mtime: Some(UNIX_EPOCH + Duration::from_millis(status.modification_time)),
+            is_directory: status.file_type == "DIRECTORY",
+            ..Metadata::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FileStatusResponse {
+    file_status: FileStatus,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListStatusResponse {
+    file_statuses: FileStatuses,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FileStatuses {
+    file_status: Vec<FileStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FsStatusResponse {
+    fs_status: FsStatus,
+}
+
+#[derive(Deserialize)]
+struct FsStatus {
+    capacity: u64,
+    remaining: u64,
+}
+
+#[derive(Deserialize)]
+struct BooleanResponse {
+    boolean: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RemoteExceptionResponse {
+    remote_exception: RemoteException,
+}
+
+#[derive(Deserialize)]
+struct RemoteException {
+    exception: String,
+    message: String,
+}
+
+impl WebHdfs {
+    pub fn new(
+        vfs_path: Utf8PathBuf,
+        url: &Url,
+        auth: &WebHdfsAuth,
+        options: &WebHdfsOptions,
+    ) -> Result<Self, Error> {
+        let root = percent_encoding::percent_decode_str(url.path())
+            .decode_utf8()
+            .map_err(|_| Error::InvalidPath(url.path().into()))?;
+        let root = match root.trim_end_matches('/') {
+            "" => Utf8PathBuf::from("/"),
+            root => Utf8PathBuf::from(root),
+        };
+
+        let mut base_url = url.clone();
+        base_url.set_path(&format!("/webhdfs/v1{}", url.path().trim_end_matches('/')));
+        base_url.set_query(None);
+
+        let auth = match auth {
+            WebHdfsAuth::Simple { user } => Auth::Simple(user.clone()),
+            WebHdfsAuth::DelegationToken { token_file } => Auth::DelegationToken(
+                std::fs::read_to_string(token_file)
+                    .map(|token| token.trim().to_owned())
+                    .into_io_error("failed to read HDFS delegation token")?,
+            ),
+            #[cfg(feature = "kerberos")]
+            WebHdfsAuth::Kerberos { service_principal } => Auth::Kerberos(
+                service_principal
+                    .clone()
+                    .unwrap_or_else(|| format!("HTTP/{}", url.host_str().unwrap_or_default())),
+            ),
+            #[cfg(not(feature = "kerberos"))]
+            WebHdfsAuth::Kerberos { .. } => {
+                return Err(io::Error::from(io::ErrorKind::Unsupported))
+                    .into_io_error("Schlep was built without Kerberos support");
+            }
+        };
+
+        // Data is read and written through a DataNode that the NameNode
+        // redirects to, and the redirect has to be followed by hand so that
+        // the body is sent to the right place.
+        let client = Client::builder()
+            .timeout(options.request_timeout)
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(io::Error::other)
+            .into_io_error("failed to create WebHDFS client")?;
+
+        Ok(Self {
+            vfs_path,
+            base_url,
+            root,
+            client,
+            auth,
+            append_size: options.append_size,
+            open_files: Mutex::default(),
+            open_dirs: Mutex::default(),
+        })
+    }
+
+    /// The HDFS path of `path`.
+    fn hdfs_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        let mut hdfs_path = self.root.clone();
+
+        for component in path.components() {
+            match component {
+                Utf8Component::Normal(name) => hdfs_path.push(name),
+                Utf8Component::CurDir | Utf8Component::RootDir => (),
+                _ => return Err(Error::InvalidPath(path.into())),
+            }
+        }
+
+        Ok(hdfs_path)
+    }
+
+    /// The URL for `op` on `path`, with the parameters in `params`.
+    fn url(&self, path: &Utf8Path, op: &str, params: &[(&str, &str)]) -> Result<Url, Error> {
+        let mut url = self.base_url.clone();
+
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|()| Error::InvalidPath(path.into()))?;
+
+            for component in path.components() {
+                match component {
+                    Utf8Component::Normal(name) => {
+                        segments.push(name);
+                    }
+                    Utf8Component::CurDir | Utf8Component::RootDir => (),
+                    _ => return Err(Error::InvalidPath(path.into())),
+                }
+            }
+        }
+
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("op", op);
+            query.extend_pairs(params);
+
+            match &self.auth {
+                Auth::Simple(Some(user)) => {
+                    query.append_pair("user.name", user);
+                }
+                Auth::DelegationToken(token) => {
+                    query.append_pair("delegation", token);
+                }
+                Auth::Simple(None) => (),
+                #[cfg(feature = "kerberos")]
+                Auth::Kerberos(_) => (),
+            }
+        }
+
+        Ok(url)
+    }
+
+    #[cfg_attr(not(feature = "kerberos"), allow(clippy::unused_async))]
+    async fn request(&self, method: Method, url: Url) -> Result<RequestBuilder, Error> {
+        let request = self.client.request(method, url);
+
+        #[cfg(feature = "kerberos")]
+        if let Auth::Kerberos(service_principal) = &self.auth {
+            let authorization = negotiate(service_principal.clone()).await?;
+            return Ok(request.header(header::AUTHORIZATION, authorization));
+        }
+
+        Ok(request)
+    }
+
+    async fn send(&self, request: RequestBuilder, context: &str) -> Result<Response, Error> {
+        let response = request
+            .send()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error(context)?;
+
+        let status = response.status();
+        if status.is_success() || status.is_redirection() {
+            Ok(response)
+        } else {
+            Err(remote_error(response, context).await)
+        }
+    }
+
+    /// Sends a request to the NameNode and parses the JSON it answers with.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        url: Url,
+        context: &str,
+    ) -> Result<T, Error> {
+        self.send(self.request(method, url).await?, context)
+            .await?
+            .json()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error(context)
+    }
+
+    /// Like [`Self::call`], for operations that answer with whether they did
+    /// anything.
+    async fn call_boolean(&self, method: Method, url: Url, context: &str) -> Result<(), Error> {
+        let response: BooleanResponse = self.call(method, url, context).await?;
+
+        if response.boolean {
+            Ok(())
+        } else {
+            Err(io::Error::other("HDFS refused")).into_io_error(context)
+        }
+    }
+
+    /// Sends a data operation to the NameNode, and returns the DataNode URL
+    /// that it redirects to.
+    async fn datanode_url(&self, method: Method, url: Url, context: &str) -> Result<Url, Error> {
+        let response = self.send(self.request(method, url).await?, context).await?;
+
+        response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| Url::parse(location).ok())
+            .ok_or_else(|| io::Error::other("the NameNode didn't redirect to a DataNode"))
+            .into_io_error(context)
+    }
+
+    /// Starts reading `path` from `offset`, for at most `len` bytes if a
+    /// length is given.
+    async fn open_stream(
+        &self,
+        path: &Utf8Path,
+        offset: u64,
+        len: Option<usize>,
+    ) -> Result<Response, Error> {
+        let offset = offset.to_string();
+        let len = len.map(|len| len.to_string());
+        let mut params = vec![("offset", offset.as_str())];
+        if let Some(len) = &len {
+            params.push(("length", len));
+        }
+
+        let url = self.url(path, "OPEN", &params)?;
+        let datanode_url = self
+            .datanode_url(Method::GET, url, "failed to read file")
+            .await?;
+
+        self.send(
+            self.client.request(Method::GET, datanode_url),
+            "failed to read file",
+        )
+        .await
+    }
+
+    /// Creates the file at `path`, replacing it if it exists and `overwrite`
+    /// is set.
+    async fn create(&self, path: &Utf8Path, overwrite: bool) -> Result<(), Error> {
+        let overwrite = if overwrite { "true" } else { "false" };
+        let url = self.url(path, "CREATE", &[("overwrite", overwrite)])?;
+        let datanode_url = self
+            .datanode_url(Method::PUT, url, "failed to create file")
+            .await?;
+
+        self.send(
+            self.client.request(Method::PUT, datanode_url),
+            "failed to create file",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Appends the buffered writes of `file` to it.
+    async fn flush(&self, file: &mut OpenFile) -> Result<(), Error> {
+        let Some(writer) = &mut file.writer else {
+            return Ok(());
+        };
+        if writer.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let url = self.url(&file.path, "APPEND", &[])?;
+        let datanode_url = self
+            .datanode_url(Method::POST, url, "failed to write file")
+            .await?;
+        let data = std::mem::take(&mut writer.buffer);
+        let len = data.len() as u64;
+
+        self.send(
+            self.client.request(Method::POST, datanode_url).body(data),
+            "failed to write file",
+        )
+        .await?;
+        writer.flushed += len;
+        file.len = file.len.max(writer.flushed);
+
+        Ok(())
+    }
+
+    fn get_file(&self, handle: &Handle) -> Result<Arc<AsyncMutex<OpenFile>>, Error> {
+        if handle.handle_type() != HandleType::File {
+            return Err(Error::NotAFile);
+        }
+
+        self.open_files
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+
+    fn get_dir(&self, handle: &Handle) -> Result<Utf8PathBuf, Error> {
+        self.open_dirs
+            .lock()
+            .get(handle.vfs_handle())
+            .cloned()
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+#[async_trait]
+impl Vfs for WebHdfs {
+    async fn open(&self, path: &Utf8Path, flags: OpenFlags) -> Result<Handle, Error> {
+        let writing = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+
+        let existing = match self.stat(path).await {
+            Ok(metadata) if metadata.is_directory => return Err(Error::NotAFile),
+            Ok(metadata) => Some(metadata),
+            Err(Error::FileNotFound) if writing && flags.contains(OpenFlags::CREATE) => None,
+            Err(err) => return Err(err),
+        };
+
+        if existing.is_some() && writing && flags.contains(OpenFlags::EXCLUDE) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                .into_io_error(format!("couldn't open file {path}"));
+        }
+
+        let mut len = existing.and_then(|metadata| metadata.size).unwrap_or(0);
+        let writer = if writing {
+            if existing.is_none() || flags.contains(OpenFlags::TRUNCATE) {
+                self.create(path, existing.is_some()).await?;
+                len = 0;
+            }
+
+            Some(Appender {
+                flushed: len,
+                buffer: Vec::new(),
+                pending: BTreeMap::new(),
+            })
+        } else {
+            None
+        };
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        let file = OpenFile {
+            path: path.to_owned(),
+            len,
+            writer,
+        };
+        self.open_files
+            .lock()
+            .insert(vfs_handle.clone(), Arc::new(AsyncMutex::new(file)));
+
+        Ok(Handle::file(vfs_handle))
+    }
+
+    async fn open_dir(&self, path: &Utf8Path) -> Result<Handle, Error> {
+        if !self.stat(path).await?.is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        let vfs_handle = format!("{:032x}", rand::random::<u128>());
+        self.open_dirs
+            .lock()
+            .insert(vfs_handle.clone(), path.to_owned());
+
+        Ok(Handle::dir(vfs_handle))
+    }
+
+    async fn close(&self, handle: Handle) -> Result<(), Error> {
+        match handle.handle_type() {
+            HandleType::File => {
+                let file = self.open_files.lock().remove(handle.vfs_handle());
+
+                if let Some(file) = file {
+                    let mut file = file.lock().await;
+                    if let Some(writer) = &mut file.writer {
+                        writer.fill_holes();
+                    }
+
+                    self.flush(&mut file).await?;
+                }
+            }
+            HandleType::Dir => {
+                self.open_dirs.lock().remove(handle.vfs_handle());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn owns_handle(&self, handle: &Handle) -> bool {
+        match handle.handle_type() {
+            HandleType::File => self.open_files.lock().contains_key(handle.vfs_handle()),
+            HandleType::Dir => self.open_dirs.lock().contains_key(handle.vfs_handle()),
+        }
+    }
+
+    fn vfs_root(&self) -> &Utf8Path {
+        self.vfs_path.as_path()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::STATVFS
+    }
+
+    async fn read(
+        &self,
+        handle: &Handle,
+        offset: u64,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        // Whatever has been written has to be in HDFS before it can be read
+        // back.
+        self.flush(&mut file).await?;
+
+        if offset >= file.len {
+            return Ok(None);
+        }
+        if len == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        let data = self
+            .open_stream(&file.path, offset, Some(len))
+            .await?
+            .bytes()
+            .await
+            .map_err(io::Error::other)
+            .into_io_error("failed to read file")?;
+
+        Ok((!data.is_empty()).then(|| data.to_vec()))
+    }
+
+    async fn read_dir(&self, handle: &Handle) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        if handle.handle_type() != HandleType::Dir {
+            return Err(Error::NotADirectory);
+        }
+
+        let path = self.get_dir(handle)?;
+        let response: ListStatusResponse = self
+            .call(
+                Method::GET,
+                self.url(&path, "LISTSTATUS", &[])?,
+                "couldn't get directory entries",
+            )
+            .await?;
+
+        Ok(response
+            .file_statuses
+            .file_status
+            .into_iter()
+            .map(|status| {
+                (
+                    Utf8PathBuf::from(&status.path_suffix),
+                    Metadata::from(status),
+                )
+            })
+            .collect())
+    }
+
+    async fn read_dir_next(
+        &self,
+        _handle: &Handle,
+        _max: usize,
+    ) -> Result<Vec<(Utf8PathBuf, Metadata)>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn write(&self, handle: &Handle, offset: u64, data: &[u8]) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        let Some(writer) = &mut file.writer else {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied))
+                .into_io_error("file isn't open for writing");
+        };
+
+        writer.insert(offset, data)?;
+
+        if writer.buffer.len() >= self.append_size {
+            self.flush(&mut file).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn stat_fd(&self, handle: &Handle) -> Result<Metadata, Error> {
+        if handle.handle_type() == HandleType::Dir {
+            return self.stat(&self.get_dir(handle)?).await;
+        }
+
+        let file = self.get_file(handle)?;
+        let file = file.lock().await;
+        let mut metadata = self.stat(&file.path).await?;
+
+        if let Some(writer) = &file.writer {
+            metadata.size = Some(writer.end().max(metadata.size.unwrap_or(0)));
+        }
+
+        Ok(metadata)
+    }
+
+    async fn sync_fd(&self, handle: &Handle) -> Result<(), Error> {
+        let file = self.get_file(handle)?;
+        let mut file = file.lock().await;
+
+        self.flush(&mut file).await
+    }
+
+    async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), Error> {
+        let destination = self.hdfs_path(to)?;
+
+        self.call_boolean(
+            Method::PUT,
+            self.url(from, "RENAME", &[("destination", destination.as_str())])?,
+            "failed to rename file",
+        )
+        .await
+    }
+
+    async fn stat(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        let response: FileStatusResponse = self
+            .call(
+                Method::GET,
+                self.url(path, "GETFILESTATUS", &[])?,
+                "failed to get metadata",
+            )
+            .await?;
+
+        Ok(Metadata::from(response.file_status))
+    }
+
+    async fn stat_link(&self, path: &Utf8Path) -> Result<Metadata, Error> {
+        // Links aren't supported, so nothing needs to be followed.
+        self.stat(path).await
+    }
+
+    async fn statvfs(&self, path: &Utf8Path) -> Result<FsMetadata, Error> {
+        let response: FsStatusResponse = self
+            .call(
+                Method::GET,
+                self.url(path, "GETSTATUS", &[])?,
+                "failed to get filesystem metadata",
+            )
+            .await?;
+
+        Ok(FsMetadata {
+            block_size: 1,
+            num_blocks: response.fs_status.capacity,
+            free_blocks: response.fs_status.remaining,
+            num_files: 0,
+            free_files: 0,
+            read_only: false,
+            max_length: 255,
+        })
+    }
+
+    async fn statvfs_fd(&self, handle: &Handle) -> Result<FsMetadata, Error> {
+        if handle.handle_type() == HandleType::Dir {
+            return self.statvfs(&self.get_dir(handle)?).await;
+        }
+
+        let path = self.get_file(handle)?.lock().await.path.clone();
+        self.statvfs(&path).await
+    }
+
+    async fn hardlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn symlink(&self, _path: &Utf8Path, _target: &Utf8Path) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn hash_range(
+        &self,
+        path: &Utf8Path,
+        algorithm: HashAlgorithm,
+        offset: u64,
+        len: u64,
+        block_size: u64,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let mut hasher = BlockHasher::new(algorithm, len, block_size);
+        let stream_len = (len != 0).then(|| usize::try_from(len).unwrap_or(usize::MAX));
+        let response = self.open_stream(path, offset, stream_len).await?;
+
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk
+                .map_err(io::Error::other)
+                .into_io_error("failed to read file")?;
+
+            hasher.update(&chunk);
+            if hasher.is_done() {
+                break;
+            }
+        }
+
+        Ok(hasher.finish())
+    }
+
+    async fn readlink(&self, _path: &Utf8Path) -> Result<Utf8PathBuf, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn mkdir(&self, path: &Utf8Path) -> Result<(), Error> {
+        self.call_boolean(
+            Method::PUT,
+            self.url(path, "MKDIRS", &[])?,
+            "failed to create directory",
+        )
+        .await
+    }
+
+    async fn remove_file(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.stat(path).await?.is_directory {
+            return Err(Error::NotAFile);
+        }
+
+        self.call_boolean(
+            Method::DELETE,
+            self.url(path, "DELETE", &[("recursive", "false")])?,
+            "failed to remove file",
+        )
+        .await
+    }
+
+    async fn remove_dir(&self, path: &Utf8Path) -> Result<(), Error> {
+        if !self.stat(path).await?.is_directory {
+            return Err(Error::NotADirectory);
+        }
+
+        // HDFS refuses to remove a directory that isn't empty unless it's
+        // asked to remove it recursively.
+        self.call_boolean(
+            Method::DELETE,
+            self.url(path, "DELETE", &[("recursive", "false")])?,
+            "failed to remove directory",
+        )
+        .await
+    }
+
+    async fn set_times(
+        &self,
+        path: &Utf8Path,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        // HDFS leaves times set to -1 alone.
+        let millis = |time: Option<SystemTime>| {
+            time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or_else(|| "-1".to_string(), |time| time.as_millis().to_string())
+        };
+        let atime = millis(atime);
+        let mtime = millis(mtime);
+
+        self.send(
+            self.request(
+                Method::PUT,
+                self.url(
+                    path,
+                    "SETTIMES",
+                    &[("accesstime", &atime), ("modificationtime", &mtime)],
+                )?,
+            )
+            .await?,
+            "failed to set file times",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_times_fd(
+        &self,
+        handle: &Handle,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<(), Error> {
+        let path = match handle.handle_type() {
+            HandleType::File => self.get_file(handle)?.lock().await.path.clone(),
+            HandleType::Dir => self.get_dir(handle)?,
+        };
+
+        self.set_times(&path, atime, mtime).await
+    }
+
+    async fn set_permissions(&self, _path: &Utf8Path, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn set_permissions_fd(&self, _handle: &Handle, _mode: u32) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn truncate(&self, path: &Utf8Path, len: u64) -> Result<(), Error> {
+        // HDFS can only shrink files. It answers false when the last block
+        // still has to be recovered, which it finishes on its own.
+        let _: BooleanResponse = self
+            .call(
+                Method::POST,
+                self.url(path, "TRUNCATE", &[("newlength", &len.to_string())])?,
+                "failed to truncate file",
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn truncate_fd(&self, _handle: &Handle, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn lock(
+        &self,
+        _handle: &Handle,
+        _range: Range<u64>,
+        _mode: LockMode,
+    ) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn unlock(&self, _handle: &Handle, _range: Range<u64>) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn copy_range(
+        &self,
+        _src: &Handle,
+        _src_offset: u64,
+        _dst: &Handle,
+        _dst_offset: u64,
+        _len: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn allocate(&self, _handle: &Handle, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn watch(&self, _path: &Utf8Path) -> Result<broadcast::Receiver<ChangeEvent>, Error> {
+        Err(Error::UnsupportedMethod)
+    }
+
+    async fn health_check(&self) -> Result<(), Error> {
+        self.stat(Utf8Path::new(".")).await.map(drop)
+    }
+}
+
+/// The error for a request that failed, from the `RemoteException` that
+/// WebHDFS describes it with if there is one.
+async fn remote_error(response: Response, context: &str) -> Error {
+    let status = response.status();
+    let exception = response
+        .json::<RemoteExceptionResponse>()
+        .await
+        .ok()
+        .map(|response| response.remote_exception);
+
+    let kind = match exception
+        .as_ref()
+        .map(|exception| exception.exception.as_str())
+    {
+        Some("FileNotFoundException") => return Error::FileNotFound,
+        Some("FileAlreadyExistsException") => io::ErrorKind::AlreadyExists,
+        Some("PathIsNotEmptyDirectoryException") => io::ErrorKind::DirectoryNotEmpty,
+        Some("AccessControlException" | "SecurityException") => io::ErrorKind::PermissionDenied,
+        Some("DSQuotaExceededException" | "NSQuotaExceededException") => io::ErrorKind::StorageFull,
+        _ => match status {
+            StatusCode::NOT_FOUND => return Error::FileNotFound,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        },
+    };
+    let message = match exception {
+        Some(exception) => format!("{}: {}", exception.exception, exception.message),
+        None => format!("WebHDFS answered {status}"),
+    };
+
+    Error::IoError {
+        source: io::Error::new(kind, message),
+        from: context.to_owned(),
+    }
+}
+
+/// A SPNEGO `Authorization` header for `service_principal`, from the Kerberos
+/// credentials in the default credential cache or client keytab.
+#[cfg(feature = "kerberos")]
+async fn negotiate(service_principal: String) -> Result<String, Error> {
+    use base64ct::{Base64, Encoding};
+    use cross_krb5::{ClientCtx, InitiateFlags};
+
+    super::blocking::run(move |_| {
+        let (_, token) = ClientCtx::new(InitiateFlags::empty(), None, &service_principal, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, format!("{err:#}")))
+            .into_io_error("failed to get a Kerberos ticket for WebHDFS")?;
+
+        Ok(format!("Negotiate {}", Base64::encode_string(&token)))
+    })
+    .await
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use ahash::HashMap;
+use camino::{Utf8Path, Utf8PathBuf};
+use parking_lot::Mutex;
+use tokio::sync::Mutex as AsyncMutex;
+
+use super::{
+    Error,
+    Handle,
+    WormOptions,
+    state_file::{self, normalize},
+};
+
+/// Enforces [`MountOptions::worm`](super::MountOptions::worm) for a mount.
+///
+/// A file is sealed when a handle that was opened for writing it is closed.
+/// From then on it can't be written to, renamed (by itself or along with a
+/// directory it's in), or have its times changed, and it can only be deleted
+/// once its retention period is over.
+pub(super) struct Worm {
+    options: Option<WormOptions>,
+    /// When each file was sealed, in seconds since the Unix epoch.
+    sealed: Mutex<BTreeMap<Utf8PathBuf, u64>>,
+    writers: Mutex<HashMap<Handle, Utf8PathBuf>>,
+    save: AsyncMutex<()>,
+}
+
+impl Worm {
+    pub(super) fn load(options: Option<WormOptions>) -> Result<Self, Error> {
+        let sealed = match &options {
+            Some(options) => state_file::load(&options.state_file)?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            options,
+            sealed: Mutex::new(sealed),
+            writers: Mutex::default(),
+            save: AsyncMutex::new(()),
+        })
+    }
+
+    /// Fails with [`Error::Immutable`] if `path` has been sealed.
+    pub(super) fn check_writable(&self, path: &Utf8Path) -> Result<(), Error> {
+        if self.sealed.lock().contains_key(&normalize(path)) {
+            Err(Error::Immutable)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`Error::Immutable`] if `path` or anything beneath it has
+    /// been sealed. This is the check for renaming, which moves everything in
+    /// a directory along with it.
+    pub(super) fn check_tree_writable(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path);
+        let sealed = self
+            .sealed
+            .lock()
+            .range(path.clone()..)
+            .next()
+            .is_some_and(|(sealed, _)| sealed.starts_with(&path));
+
+        if sealed {
+            Err(Error::Immutable)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`Error::Retained`] if `path` has been sealed and its
+    /// retention period isn't over yet.
+    pub(super) fn check_removable(&self, path: &Utf8Path) -> Result<(), Error> {
+        let Some(options) = &self.options else {
+            return Ok(());
+        };
+
+        let Some(sealed_at) = self.sealed.lock().get(&normalize(path)).copied() else {
+            return Ok(());
+        };
+
+        if is_retained(options, sealed_at) {
+            Err(Error::Retained)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`Error::Retained`] if anything beneath `path` has been
+    /// sealed and its retention period isn't over yet. This is the check for
+    /// removing a directory, which some backends remove along with everything
+    /// in it.
+    pub(super) fn check_tree_removable(&self, path: &Utf8Path) -> Result<(), Error> {
+        let Some(options) = &self.options else {
+            return Ok(());
+        };
+
+        let path = normalize(path);
+        let retained = self
+            .sealed
+            .lock()
+            .range(path.clone()..)
+            .take_while(|(sealed, _)| sealed.starts_with(&path))
+            .any(|(_, sealed_at)| is_retained(options, *sealed_at));
+
+        if retained {
+            Err(Error::Retained)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remembers that `handle` was opened for writing to `path`, so that the
+    /// file can be sealed when it is closed.
+    pub(super) fn opened_for_write(&self, handle: &Handle, path: &Utf8Path) {
+        if self.options.is_some() {
+            self.writers.lock().insert(handle.clone(), normalize(path));
+        }
+    }
+
+    /// Seals the file `handle` was writing to, if any.
+    pub(super) async fn closed(&self, handle: &Handle) -> Result<(), Error> {
+        let Some(path) = self.writers.lock().remove(handle) else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+
+        self.update(|sealed| {
+            sealed.entry(path).or_insert(now);
+        })
+        .await
+    }
+
+    /// Forgets that `handle` was writing, without sealing its file, because
+    /// what it wrote was thrown away.
+    pub(super) fn abandoned(&self, handle: &Handle) {
+        self.writers.lock().remove(handle);
+    }
+
+    /// Forgets the seals on `path` and everything beneath it after it has
+    /// been deleted.
+    pub(super) async fn removed(&self, path: &Utf8Path) -> Result<(), Error> {
+        let path = normalize(path);
+        let beneath = |sealed: &Utf8PathBuf| sealed.starts_with(&path);
+
+        if !self.sealed.lock().keys().any(beneath) {
+            return Ok(());
+        }
+
+        self.update(|sealed| {
+            sealed.retain(|sealed, _| !beneath(sealed));
+        })
+        .await
+    }
+
+    async fn update(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<Utf8PathBuf, u64>),
+    ) -> Result<(), Error> {
+        let Some(options) = &self.options else {
+            return Ok(());
+        };
+
+        let _guard = self.save.lock().await;
+
+        let sealed = {
+            let mut sealed = self.sealed.lock();
+            change(&mut sealed);
+
+            sealed.clone()
+        };
+
+        state_file::save(&options.state_file, &sealed).await
+    }
+}
+
+/// Whether a file sealed at `sealed_at`, in seconds since the Unix epoch, is
+/// still in its retention period.
+fn is_retained(options: &WormOptions, sealed_at: u64) -> bool {
+    SystemTime::now() < SystemTime::UNIX_EPOCH + Duration::from_secs(sealed_at) + options.retention
+}